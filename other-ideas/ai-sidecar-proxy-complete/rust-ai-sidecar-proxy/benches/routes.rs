@@ -0,0 +1,46 @@
+use ai_sidecar_proxy::routes::{PathPattern, RouteRule, Routes};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hyper::Uri;
+
+// A third each of prefixes, wildcards and regexes, none of which take the
+// request, so every rule is tried before the catch-all at the end.
+fn rules(count: usize) -> Vec<RouteRule> {
+    let mut rules: Vec<RouteRule> = (0..count - 1)
+        .map(|index| {
+            let path_pattern = match index % 3 {
+                0 => None,
+                1 => Some(PathPattern::Wildcard(format!("/svc{}/*/items/**", index))),
+                _ => Some(PathPattern::Regex(format!("/svc{}/v[0-9]+/items/\\d+", index))),
+            };
+            RouteRule {
+                path_prefix: if path_pattern.is_none() { format!("/svc{}", index) } else { String::new() },
+                path_pattern,
+                service: format!("service-{}", index),
+                ..RouteRule::default()
+            }
+        })
+        .collect();
+    rules.push(RouteRule {
+        path_pattern: Some(PathPattern::Regex("/.*".to_string())),
+        priority: -1,
+        service: "fallback".to_string(),
+        ..RouteRule::default()
+    });
+    rules
+}
+
+fn resolve(c: &mut Criterion) {
+    let uri: Uri = "/other/v2/items/42?expand=lines".parse().unwrap();
+    let mut group = c.benchmark_group("resolve");
+    for count in [10, 100, 1000] {
+        let routes = Routes::new(&rules(count)).unwrap();
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &routes, |b, routes| {
+            b.iter(|| routes.resolve(None, black_box(&uri)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, resolve);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+use ai_sidecar_proxy::{
+    config::Config,
+    egress::Direction,
+    metrics::MetricsCollector,
+    mock_upstream::{MockResponse, MockUpstream},
+    upstream_client::{self, ClientCache, UpstreamClient, UpstreamPoolConfig},
+};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use http_body_util::BodyExt;
+use hyper::Request;
+use std::{sync::Arc, time::Duration};
+
+async fn get(client: &UpstreamClient, url: &str, timeout: Duration) -> Bytes {
+    let request = Request::get(url).body(upstream_client::full_body(Bytes::new())).unwrap();
+    let response = client.send(request, timeout).await.unwrap();
+    response.into_body().collect().await.unwrap().to_bytes()
+}
+
+// One GET to a local upstream with a fresh client each time, as the proxy
+// once did, against the pooled client it now takes from its cache. The
+// fresh client pays for TLS setup and a new connection on every request.
+fn request(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let upstream = runtime.block_on(MockUpstream::start(MockResponse::default())).unwrap();
+    let url = format!("{}/items", upstream.url());
+    let service = Config::new().upstream_services["service-a"].clone();
+    let timeout = Duration::from_millis(service.timeout_ms);
+    let cache = ClientCache::new(UpstreamPoolConfig::default(), Arc::new(MetricsCollector::new()));
+
+    let mut group = c.benchmark_group("upstream_request");
+    group.bench_function("client_per_request", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let client = UpstreamClient::new(&service, &UpstreamPoolConfig::default()).unwrap();
+                get(&client, &url, timeout).await
+            })
+        })
+    });
+    group.bench_function("pooled_client", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let client = cache.get(Direction::Ingress, &service).unwrap();
+                get(&client, &url, timeout).await
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, request);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Runs the proxy inside an existing tokio application: the application binds
+//! the socket, shares its Prometheus registry and decides when to stop.
+//!
+//!     cargo run --example embedded
+
+use ai_sidecar_proxy::{
+    config::Config,
+    middleware::{Middleware, RequestContext},
+    proxy::ProxyServer,
+};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::Response;
+use prometheus::{IntCounter, Registry};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+// Tags every response so callers can tell which application served them.
+struct ServedBy(&'static str);
+
+impl Middleware for ServedBy {
+    fn on_response(&self, response: &mut Response<BoxBody<Bytes, hyper::Error>>, _context: &RequestContext) {
+        response
+            .headers_mut()
+            .insert("x-served-by", hyper::header::HeaderValue::from_static(self.0));
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_env_filter("info").init();
+
+    // Metrics the application already had; the proxy's join them.
+    let registry = Registry::new();
+    let started = IntCounter::new("app_starts_total", "Times the application started")?;
+    registry.register(Box::new(started.clone()))?;
+    started.inc();
+
+    let shutdown = CancellationToken::new();
+    let proxy = ProxyServer::builder()
+        .config(Config::new())
+        .registry(registry.clone())
+        .middleware(ServedBy("embedded-example"))
+        .shutdown_token(shutdown.clone())
+        .build()?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let handle = proxy.run(listener)?;
+    println!("proxy listening on http://{}", handle.local_addr());
+    println!("press ctrl-c to stop");
+
+    tokio::signal::ctrl_c().await?;
+    shutdown.cancel();
+    handle.await_terminated().await?;
+
+    println!("{} metric families registered", registry.gather().len());
+    Ok(())
+}
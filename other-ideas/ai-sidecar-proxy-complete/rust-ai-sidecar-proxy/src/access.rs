@@ -0,0 +1,354 @@
+use anyhow::{Context, Result};
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRulesConfig {
+    #[serde(default)]
+    pub default_action: RuleAction,
+    #[serde(default)]
+    pub rules: Vec<AccessRuleConfig>,
+}
+
+// Every matcher that is set must match; unset matchers match anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRuleConfig {
+    pub name: String,
+    #[serde(default)]
+    pub path_glob: Option<String>,
+    #[serde(default)]
+    pub path_regex: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub source_cidrs: Vec<String>,
+    #[serde(default)]
+    pub excluded_source_cidrs: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<HeaderMatcherConfig>,
+    pub action: RuleAction,
+}
+
+// Without a regex the header only has to be present.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderMatcherConfig {
+    pub name: String,
+    #[serde(default)]
+    pub regex: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum RuleAction {
+    #[default]
+    Allow,
+    Deny { status: u16 },
+    // Recorded and logged, then evaluation continues with the next rule.
+    LogOnly,
+}
+
+impl RuleAction {
+    pub fn label(&self) -> &'static str {
+        match self {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny { .. } => "deny",
+            RuleAction::LogOnly => "log_only",
+        }
+    }
+}
+
+pub struct AccessRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub source: IpAddr,
+    pub headers: &'a HeaderMap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessDecision {
+    // None when no rule matched and the default action applied.
+    pub rule: Option<String>,
+    pub action: RuleAction,
+    pub logged_by: Vec<String>,
+}
+
+struct HeaderMatcher {
+    name: String,
+    regex: Option<Regex>,
+}
+
+struct AccessRule {
+    name: String,
+    path: Vec<Regex>,
+    methods: Vec<String>,
+    source_cidrs: Vec<IpNet>,
+    excluded_source_cidrs: Vec<IpNet>,
+    headers: Vec<HeaderMatcher>,
+    action: RuleAction,
+}
+
+impl AccessRule {
+    fn compile(config: &AccessRuleConfig) -> Result<Self> {
+        let mut path = Vec::new();
+        if let Some(glob) = &config.path_glob {
+            path.push(Regex::new(&glob_to_regex(glob)).context("invalid path_glob")?);
+        }
+        if let Some(pattern) = &config.path_regex {
+            path.push(Regex::new(pattern).context("invalid path_regex")?);
+        }
+
+        let parse_cidrs = |cidrs: &[String]| {
+            cidrs
+                .iter()
+                .map(|cidr| cidr.parse::<IpNet>().with_context(|| format!("invalid CIDR {:?}", cidr)))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let headers = config
+            .headers
+            .iter()
+            .map(|header| {
+                let regex = header
+                    .regex
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .with_context(|| format!("invalid regex for header {:?}", header.name))?;
+                Ok(HeaderMatcher {
+                    name: header.name.to_ascii_lowercase(),
+                    regex,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if let RuleAction::Deny { status } = config.action {
+            anyhow::ensure!((400..=599).contains(&status), "deny status {} is not an error status", status);
+        }
+
+        Ok(Self {
+            name: config.name.clone(),
+            path,
+            methods: config.methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+            source_cidrs: parse_cidrs(&config.source_cidrs)?,
+            excluded_source_cidrs: parse_cidrs(&config.excluded_source_cidrs)?,
+            headers,
+            action: config.action,
+        })
+    }
+
+    fn matches(&self, req: &AccessRequest<'_>) -> bool {
+        let source = canonical(req.source);
+        self.path.iter().all(|re| re.is_match(req.path))
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(req.method)))
+            && (self.source_cidrs.is_empty() || self.source_cidrs.iter().any(|net| net.contains(&source)))
+            && !self.excluded_source_cidrs.iter().any(|net| net.contains(&source))
+            && self.headers.iter().all(|matcher| {
+                req.headers.get_all(matcher.name.as_str()).iter().any(|value| {
+                    match (&matcher.regex, value.to_str()) {
+                        (None, _) => true,
+                        (Some(re), Ok(value)) => re.is_match(value),
+                        (Some(_), Err(_)) => false,
+                    }
+                })
+            })
+    }
+}
+
+// IPv4 clients accepted on a dual-stack socket show up as ::ffff:a.b.c.d.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+// `*` stays within one path segment and `**` crosses segments.
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+// Ordered, first-match-wins rule list.
+#[derive(Default)]
+pub struct AccessRules {
+    config: AccessRulesConfig,
+    rules: Vec<AccessRule>,
+}
+
+impl AccessRules {
+    pub fn compile(config: &AccessRulesConfig) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| AccessRule::compile(rule).with_context(|| format!("access rule {:?}", rule.name)))
+            .collect::<Result<Vec<_>>>()?;
+        match config.default_action {
+            RuleAction::LogOnly => anyhow::bail!("default action must be allow or deny"),
+            RuleAction::Deny { status } => {
+                anyhow::ensure!((400..=599).contains(&status), "default deny status {} is not an error status", status)
+            }
+            RuleAction::Allow => {}
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            rules,
+        })
+    }
+
+    pub fn config(&self) -> &AccessRulesConfig {
+        &self.config
+    }
+
+    pub fn evaluate(&self, req: &AccessRequest<'_>) -> AccessDecision {
+        let mut logged_by = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.matches(req)) {
+            if rule.action == RuleAction::LogOnly {
+                logged_by.push(rule.name.clone());
+                continue;
+            }
+            return AccessDecision {
+                rule: Some(rule.name.clone()),
+                action: rule.action,
+                logged_by,
+            };
+        }
+
+        AccessDecision {
+            rule: None,
+            action: self.config.default_action,
+            logged_by,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, action: RuleAction) -> AccessRuleConfig {
+        AccessRuleConfig {
+            name: name.to_string(),
+            path_glob: None,
+            path_regex: None,
+            methods: Vec::new(),
+            source_cidrs: Vec::new(),
+            excluded_source_cidrs: Vec::new(),
+            headers: Vec::new(),
+            action,
+        }
+    }
+
+    fn evaluate(rules: &AccessRules, method: &str, path: &str, source: &str) -> AccessDecision {
+        rules.evaluate(&AccessRequest {
+            method,
+            path,
+            source: source.parse().unwrap(),
+            headers: &HeaderMap::new(),
+        })
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let rules = AccessRules::compile(&AccessRulesConfig {
+            default_action: RuleAction::Allow,
+            rules: vec![
+                AccessRuleConfig {
+                    path_glob: Some("/api/*/admin/**".to_string()),
+                    methods: vec!["put".to_string()],
+                    excluded_source_cidrs: vec!["10.0.0.0/8".to_string()],
+                    ..rule("block-external-admin-writes", RuleAction::Deny { status: 403 })
+                },
+                AccessRuleConfig {
+                    path_glob: Some("/api/**".to_string()),
+                    ..rule("audit-api", RuleAction::LogOnly)
+                },
+                AccessRuleConfig {
+                    path_regex: Some("^/api/a/".to_string()),
+                    ..rule("deny-a", RuleAction::Deny { status: 451 })
+                },
+            ],
+        })
+        .unwrap();
+
+        let decision = evaluate(&rules, "PUT", "/api/b/admin/users/1", "203.0.113.9");
+        assert_eq!(decision.rule.as_deref(), Some("block-external-admin-writes"));
+        assert_eq!(decision.action, RuleAction::Deny { status: 403 });
+        assert!(decision.logged_by.is_empty());
+
+        let decision = evaluate(&rules, "PUT", "/api/b/admin/users/1", "::ffff:10.1.2.3");
+        assert_eq!(decision.rule, None);
+        assert_eq!(decision.logged_by, vec!["audit-api".to_string()]);
+
+        let decision = evaluate(&rules, "PUT", "/api/a/admin/users/1", "10.1.2.3");
+        assert_eq!(decision.rule.as_deref(), Some("deny-a"));
+        assert_eq!(decision.action, RuleAction::Deny { status: 451 });
+        assert_eq!(decision.logged_by, vec!["audit-api".to_string()]);
+
+        assert_eq!(evaluate(&rules, "GET", "/api/b/admin/x", "203.0.113.9").rule, None);
+    }
+
+    #[test]
+    fn test_default_action_applies_when_nothing_matches() {
+        let rules = AccessRules::compile(&AccessRulesConfig {
+            default_action: RuleAction::Deny { status: 403 },
+            rules: vec![AccessRuleConfig {
+                source_cidrs: vec!["10.0.0.0/8".to_string()],
+                headers: vec![HeaderMatcherConfig {
+                    name: "X-Mesh-Identity".to_string(),
+                    regex: Some("^spiffe://".to_string()),
+                }],
+                ..rule("mesh-internal", RuleAction::Allow)
+            }],
+        })
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-mesh-identity", "spiffe://cluster/ns/a".parse().unwrap());
+        let decision = rules.evaluate(&AccessRequest {
+            method: "GET",
+            path: "/",
+            source: "10.0.0.5".parse().unwrap(),
+            headers: &headers,
+        });
+        assert_eq!(decision.action, RuleAction::Allow);
+        assert_eq!(decision.rule.as_deref(), Some("mesh-internal"));
+
+        let decision = evaluate(&rules, "GET", "/", "10.0.0.5");
+        assert_eq!(decision.rule, None);
+        assert_eq!(decision.action, RuleAction::Deny { status: 403 });
+    }
+
+    #[test]
+    fn test_invalid_rules_name_the_rule() {
+        let error = AccessRules::compile(&AccessRulesConfig {
+            default_action: RuleAction::Allow,
+            rules: vec![AccessRuleConfig {
+                source_cidrs: vec!["10.0.0.0/33".to_string()],
+                ..rule("bad-cidr", RuleAction::Allow)
+            }],
+        })
+        .err()
+        .unwrap();
+        assert!(format!("{:#}", error).contains("access rule \"bad-cidr\""));
+    }
+}
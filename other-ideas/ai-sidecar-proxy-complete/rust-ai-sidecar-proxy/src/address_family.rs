@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{io, net::SocketAddr};
+use tokio::net::TcpListener;
+
+// Which resolved addresses an upstream connection may use. The client's
+// connector races the families itself: it tries the family of the first
+// address, and after a 300ms head start (RFC 8305 suggests 250ms) starts on
+// the other family in parallel, keeping whichever connects first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    // Both families, IPv6 first.
+    #[default]
+    Auto,
+    V4Only,
+    V6Only,
+}
+
+impl AddressFamily {
+    // Orders resolved addresses for the connector. Auto interleaves the
+    // families starting with IPv6, as in RFC 8305 section 4, which also hands
+    // the head start to IPv6.
+    pub fn arrange(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+        match self {
+            AddressFamily::V4Only => v4,
+            AddressFamily::V6Only => v6,
+            AddressFamily::Auto => {
+                let mut arranged = Vec::with_capacity(v6.len() + v4.len());
+                let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+                loop {
+                    match (v6.next(), v4.next()) {
+                        (None, None) => return arranged,
+                        (first, second) => arranged.extend(first.into_iter().chain(second)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn label(addr: &SocketAddr) -> &'static str {
+    // An IPv4 peer on a dual-stack socket shows up as a mapped IPv6 address.
+    match addr {
+        SocketAddr::V6(v6) if v6.ip().to_ipv4_mapped().is_none() => "ipv6",
+        _ => "ipv4",
+    }
+}
+
+// Binds the listener with the dual-stack behaviour set explicitly instead of
+// left to the host's default (net.ipv6.bindv6only on Linux). An IPv6 wildcard
+// address also accepts IPv4 clients unless `ipv6_only` is set; the flag has no
+// effect on IPv4 addresses.
+pub fn bind_listener(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Matches what tokio's own bind does, so restarts don't trip over TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_arrange_prefers_ipv6_and_filters() {
+        let resolved = addrs(&["10.0.0.1:80", "10.0.0.2:80", "[fd00::1]:80"]);
+
+        assert_eq!(
+            AddressFamily::Auto.arrange(resolved.clone()),
+            addrs(&["[fd00::1]:80", "10.0.0.1:80", "10.0.0.2:80"])
+        );
+        assert_eq!(
+            AddressFamily::V4Only.arrange(resolved.clone()),
+            addrs(&["10.0.0.1:80", "10.0.0.2:80"])
+        );
+        assert_eq!(AddressFamily::V6Only.arrange(resolved), addrs(&["[fd00::1]:80"]));
+    }
+
+    #[test]
+    fn test_mapped_ipv4_peer_labelled_ipv4() {
+        assert_eq!(label(&"[::ffff:127.0.0.1]:80".parse().unwrap()), "ipv4");
+        assert_eq!(label(&"[::1]:80".parse().unwrap()), "ipv6");
+        assert_eq!(label(&"127.0.0.1:80".parse().unwrap()), "ipv4");
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_wildcard_dual_stack_is_explicit() {
+        let listener = bind_listener("[::]:0".parse().unwrap(), false).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(label(&peer), "ipv4");
+
+        let listener = bind_listener("[::]:0".parse().unwrap(), true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+        TcpStream::connect(("::1", port)).await.unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{ensure, Result};
+use hyper::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// Bearer tokens for the admin API, keyed by who holds them. With none
+// configured the admin API is open, as it always was.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminAuthConfig {
+    pub tokens: HashMap<String, AdminTokenConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminTokenConfig {
+    pub token: String,
+    // Services this token may see and change; every service when unset.
+    #[serde(default)]
+    pub services: Option<Vec<String>>,
+}
+
+// What one admin request may see. Output is filtered to it server-side, and
+// changes outside it are refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminScope {
+    All,
+    Services(HashSet<String>),
+}
+
+impl AdminScope {
+    pub fn allows(&self, service: &str) -> bool {
+        match self {
+            AdminScope::All => true,
+            AdminScope::Services(services) => services.contains(service),
+        }
+    }
+
+    pub fn is_all(&self) -> bool {
+        matches!(self, AdminScope::All)
+    }
+}
+
+pub struct AdminAuth {
+    tokens: Vec<(String, AdminScope)>,
+}
+
+impl AdminAuth {
+    pub fn from_config(config: &AdminAuthConfig) -> Result<Self> {
+        let mut tokens = Vec::new();
+        for (holder, token) in &config.tokens {
+            ensure!(!token.token.is_empty(), "admin_auth.tokens.{}: token must not be empty", holder);
+            let scope = match &token.services {
+                Some(services) => AdminScope::Services(services.iter().cloned().collect()),
+                None => AdminScope::All,
+            };
+            tokens.push((token.token.clone(), scope));
+        }
+        Ok(Self { tokens })
+    }
+
+    // None when tokens are configured and the request carries none of them.
+    pub fn scope(&self, headers: &HeaderMap) -> Option<AdminScope> {
+        if self.tokens.is_empty() {
+            return Some(AdminScope::All);
+        }
+        let presented = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        self.tokens
+            .iter()
+            .find(|(token, _)| constant_time_eq(token.as_bytes(), presented.as_bytes()))
+            .map(|(_, scope)| scope.clone())
+    }
+}
+
+// Compares secrets in time that depends only on their lengths, so a token
+// cannot be guessed byte by byte from how fast it is refused.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_scope_follows_token() {
+        let mut config = AdminAuthConfig::default();
+        config.tokens.insert(
+            "ops".to_string(),
+            AdminTokenConfig {
+                token: "all-access".to_string(),
+                services: None,
+            },
+        );
+        config.tokens.insert(
+            "checkout".to_string(),
+            AdminTokenConfig {
+                token: "checkout-only".to_string(),
+                services: Some(vec!["service-a".to_string()]),
+            },
+        );
+        let auth = AdminAuth::from_config(&config).unwrap();
+
+        assert_eq!(auth.scope(&bearer("all-access")), Some(AdminScope::All));
+        let scoped = auth.scope(&bearer("checkout-only")).unwrap();
+        assert!(scoped.allows("service-a") && !scoped.allows("service-b"));
+        assert_eq!(auth.scope(&bearer("guess")), None);
+        assert_eq!(auth.scope(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_open_without_tokens() {
+        let auth = AdminAuth::from_config(&AdminAuthConfig::default()).unwrap();
+        assert_eq!(auth.scope(&HeaderMap::new()), Some(AdminScope::All));
+    }
+}
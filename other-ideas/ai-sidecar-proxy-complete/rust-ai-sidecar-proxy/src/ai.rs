@@ -1,8 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, Notify, RwLock};
 use serde::{Deserialize, Serialize};
-use tracing::{info, debug, warn};
+use tracing::{debug, info, warn};
+use crate::config::AIConfig;
+use crate::endpoint_url;
+use crate::storage::{self, KeyValueStore, Slot};
+use crate::supervisor::TaskSupervisor;
+use crate::upstream_timing::UpstreamPhases;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestMetrics {
@@ -11,6 +17,9 @@ pub struct RequestMetrics {
     pub endpoint: String,
     pub timestamp: u64,
     pub success: bool,
+    // Unset for probes and for calls that never got a response.
+    #[serde(default)]
+    pub phases: Option<UpstreamPhases>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,12 +38,114 @@ pub struct AIDecision {
     pub confidence: f64,
     pub reasoning: String,
     pub fallback_endpoints: Vec<String>,
+    #[serde(default)]
+    pub mode: SelectionMode,
+}
+
+// Who made a decision.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMode {
+    #[default]
+    Ai,
+    // The engine was asked but was not confident enough.
+    Balancer,
+    // The engine is switched off and was not asked.
+    Bypassed,
+}
+
+impl SelectionMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            SelectionMode::Ai => "ai",
+            SelectionMode::Balancer => "balancer",
+            SelectionMode::Bypassed => "bypassed",
+        }
+    }
+}
+
+// Turns an endpoint's passive health into a score from 0 to 1; higher is
+// better. Only asked about endpoints with at least one recorded request.
+pub trait EndpointScorer: Send + Sync {
+    fn score(&self, health: &ServiceHealth) -> f64;
+}
+
+// Success rate weighted 0.6 and latency 0.4.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultScorer;
+
+impl EndpointScorer for DefaultScorer {
+    fn score(&self, health: &ServiceHealth) -> f64 {
+        0.6 * health.success_rate + 0.4 * latency_score(health)
+    }
+}
+
+// Latency alone, for services where a slow answer is as bad as a failed one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyPriorityScorer;
+
+impl EndpointScorer for LatencyPriorityScorer {
+    fn score(&self, health: &ServiceHealth) -> f64 {
+        latency_score(health)
+    }
+}
+
+// 1 with no latency recorded, halving by one second.
+fn latency_score(health: &ServiceHealth) -> f64 {
+    if health.avg_latency_ms > 0.0 {
+        1.0 / (1.0 + health.avg_latency_ms / 1000.0)
+    } else {
+        1.0
+    }
+}
+
+// What survives a restart; request history is rebuilt from live traffic.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    service_metrics: HashMap<String, ServiceHealth>,
+    learning_weights: HashMap<String, f64>,
+}
+
+struct Persistence {
+    slot: Slot,
+    interval: u32,
+    updates: AtomicU32,
+    // Wakes the writer; signals that arrive while it is busy make one flush.
+    due: Notify,
+    // One flush at a time, so an older snapshot never lands after a newer one.
+    writing: Mutex<()>,
 }
 
 pub struct AIEngine {
     service_metrics: Arc<RwLock<HashMap<String, ServiceHealth>>>,
     request_history: Arc<RwLock<Vec<RequestMetrics>>>,
     learning_weights: Arc<RwLock<HashMap<String, f64>>>,
+    persistence: Option<Persistence>,
+    // Health entries created so far, seeded or on first use.
+    created: AtomicU64,
+    // The runtime switch. Off, nothing is recorded and no lock is taken on
+    // the request path; what was learned is kept for when it comes back.
+    enabled: AtomicBool,
+    // Configured endpoint weights by service. Each endpoint's score is scaled
+    // by its weight over the heaviest candidate's; unlisted endpoints weigh 1.
+    endpoint_weights: std::sync::RwLock<HashMap<String, HashMap<String, u32>>>,
+    // Endpoints whose last probe was slow, kept by the health checker. Their
+    // scores are scaled by `degraded_penalty`.
+    degraded: std::sync::RwLock<HashSet<String>>,
+    degraded_penalty: f64,
+    // Endpoints left out of selection after failing too often, each with the
+    // probes it has passed in a row since. It returns once that reaches
+    // `rehabilitation_successes`.
+    evicted_endpoints: std::sync::RwLock<HashMap<String, u32>>,
+    eviction_threshold: u32,
+    rehabilitation_successes: u32,
+    scorer: Arc<dyn EndpointScorer>,
+}
+
+impl Default for AIEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AIEngine {
@@ -43,10 +154,235 @@ impl AIEngine {
             service_metrics: Arc::new(RwLock::new(HashMap::new())),
             request_history: Arc::new(RwLock::new(Vec::new())),
             learning_weights: Arc::new(RwLock::new(HashMap::new())),
+            persistence: None,
+            created: AtomicU64::new(0),
+            enabled: AtomicBool::new(true),
+            endpoint_weights: std::sync::RwLock::new(HashMap::new()),
+            degraded: std::sync::RwLock::new(HashSet::new()),
+            degraded_penalty: AIConfig::default().degraded_penalty,
+            evicted_endpoints: std::sync::RwLock::new(HashMap::new()),
+            eviction_threshold: AIConfig::default().eviction_threshold,
+            rehabilitation_successes: AIConfig::default().rehabilitation_successes,
+            scorer: Arc::new(DefaultScorer),
+        }
+    }
+
+    // Scores endpoints with `scorer` instead of the default.
+    pub fn with_scorer(mut self, scorer: Arc<dyn EndpointScorer>) -> Self {
+        self.scorer = scorer;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    // Returns the previous setting.
+    pub fn set_enabled(&self, enabled: bool) -> bool {
+        self.enabled.swap(enabled, Ordering::Relaxed)
+    }
+
+    // Replaces every service's weights, as after a reload.
+    pub fn set_endpoint_weights(&self, weights: HashMap<String, HashMap<String, u32>>) {
+        *self.endpoint_weights.write().unwrap() = weights;
+    }
+
+    pub fn set_degraded(&self, endpoint: &str, degraded: bool) {
+        if degraded {
+            self.degraded.write().unwrap().insert(endpoint.to_string());
+        } else {
+            self.degraded.write().unwrap().remove(endpoint);
+        }
+    }
+
+    // Leaves `endpoint` out of selection and forgets its health, so it starts
+    // afresh once rehabilitated. False when it was already evicted.
+    pub async fn evict_endpoint(&self, endpoint: &str) -> bool {
+        self.service_metrics.write().await.remove(endpoint);
+        self.mark_evicted(endpoint)
+    }
+
+    fn mark_evicted(&self, endpoint: &str) -> bool {
+        let evicted = self.evicted_endpoints.write().unwrap().insert(endpoint.to_string(), 0).is_none();
+        if evicted {
+            warn!(endpoint, "endpoint evicted from selection");
+        }
+        evicted
+    }
+
+    // False when `endpoint` was not evicted.
+    pub fn rehabilitate_endpoint(&self, endpoint: &str) -> bool {
+        let rehabilitated = self.evicted_endpoints.write().unwrap().remove(endpoint).is_some();
+        if rehabilitated {
+            info!(endpoint, "evicted endpoint rehabilitated");
         }
+        rehabilitated
+    }
+
+    // Counts a health probe of `endpoint`. Only probes since its eviction
+    // count towards its return, so a long healthy run before the failures
+    // does not bring it straight back. True when this probe rehabilitated it.
+    pub fn record_probe(&self, endpoint: &str, passed: bool) -> bool {
+        let mut evicted = self.evicted_endpoints.write().unwrap();
+        let Some(passes) = evicted.get_mut(endpoint) else {
+            return false;
+        };
+        *passes = if passed { *passes + 1 } else { 0 };
+        if *passes < self.rehabilitation_successes {
+            return false;
+        }
+        evicted.remove(endpoint);
+        info!(endpoint, "evicted endpoint rehabilitated");
+        true
+    }
+
+    pub fn is_evicted(&self, endpoint: &str) -> bool {
+        self.evicted_endpoints.read().unwrap().contains_key(endpoint)
+    }
+
+    // `endpoints` less the evicted ones, unless that would leave none: then
+    // all of them, as when every probe fails.
+    pub fn without_evicted(&self, endpoints: &[String]) -> Vec<String> {
+        let evicted = self.evicted_endpoints.read().unwrap();
+        let kept: Vec<String> = endpoints.iter().filter(|endpoint| !evicted.contains_key(*endpoint)).cloned().collect();
+        if kept.is_empty() {
+            endpoints.to_vec()
+        } else {
+            kept
+        }
+    }
+
+    // Gives each endpoint a health entry with no requests behind it, which
+    // scores the same as no entry at all; returns how many were new.
+    pub async fn seed_endpoints(&self, endpoints: &[String]) -> usize {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut service_metrics = self.service_metrics.write().await;
+        let mut seeded = 0;
+        for endpoint in endpoints {
+            if !service_metrics.contains_key(endpoint) {
+                service_metrics.insert(endpoint.clone(), Self::initial_health(endpoint, timestamp));
+                seeded += 1;
+            }
+        }
+        self.created.fetch_add(seeded as u64, Ordering::Relaxed);
+        seeded
+    }
+
+    pub fn health_entries_created(&self) -> u64 {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    fn initial_health(endpoint: &str, timestamp: u64) -> ServiceHealth {
+        ServiceHealth {
+            endpoint: endpoint.to_string(),
+            success_rate: 1.0,
+            avg_latency_ms: 0.0,
+            error_count: 0,
+            total_requests: 0,
+            last_updated: timestamp,
+        }
+    }
+
+    // Starts from the snapshot at `persist_path` when there is one. A missing
+    // file is a first run; an unreadable one is logged and ignored.
+    pub async fn with_config(ai_config: &AIConfig) -> Self {
+        Self::with_storage(ai_config, None).await
+    }
+
+    // As `with_config`, but keeps the snapshot in `store` when one is given.
+    pub async fn with_storage(ai_config: &AIConfig, store: Option<Arc<dyn KeyValueStore>>) -> Self {
+        let mut engine = Self::new();
+        engine.set_enabled(ai_config.enabled);
+        engine.degraded_penalty = ai_config.degraded_penalty;
+        engine.eviction_threshold = ai_config.eviction_threshold;
+        engine.rehabilitation_successes = ai_config.rehabilitation_successes;
+        let slot = match (store, &ai_config.persist_path) {
+            (Some(store), _) => Slot::Stored { store, namespace: storage::AI_NAMESPACE, key: "snapshot" },
+            (None, Some(path)) => Slot::File(path.clone()),
+            (None, None) => return engine,
+        };
+        match load_snapshot(&slot).await {
+            Ok(Some(snapshot)) => {
+                info!(location = %slot.location(), endpoints = snapshot.service_metrics.len(), "restored AI engine state");
+                engine.service_metrics = Arc::new(RwLock::new(merge_equivalent(snapshot.service_metrics)));
+                engine.learning_weights = Arc::new(RwLock::new(snapshot.learning_weights));
+            }
+            Ok(None) => {}
+            Err(e) => warn!(location = %slot.location(), error = %e, "ignoring unreadable AI engine state"),
+        }
+        engine.persistence = Some(Persistence {
+            slot,
+            interval: ai_config.persist_interval.max(1),
+            updates: AtomicU32::new(0),
+            due: Notify::new(),
+            writing: Mutex::new(()),
+        });
+        engine
+    }
+
+    // Flushes a snapshot every `persist_interval` recorded requests, off the
+    // request path. Until this runs, snapshots are only written by `persist`.
+    pub fn start_persistence(self: &Arc<Self>, supervisor: &Arc<TaskSupervisor>) {
+        if self.persistence.is_none() {
+            return;
+        }
+        let engine = self.clone();
+        supervisor.spawn("ai_persistence", false, move |heartbeat| {
+            let engine = engine.clone();
+            async move {
+                let Some(persistence) = &engine.persistence else {
+                    return;
+                };
+                let mut idle = tokio::time::interval(std::time::Duration::from_secs(10));
+                loop {
+                    tokio::select! {
+                        _ = persistence.due.notified() => {
+                            if let Err(e) = engine.persist().await {
+                                warn!(location = %persistence.slot.location(), error = %e, "failed to persist AI engine state");
+                            }
+                        }
+                        _ = idle.tick() => {}
+                    }
+                    heartbeat.beat();
+                }
+            }
+        });
+    }
+
+    // Writes the current state now, whatever the update count.
+    pub async fn persist(&self) -> std::io::Result<()> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(());
+        };
+        let _writing = persistence.writing.lock().await;
+        let snapshot = Snapshot {
+            service_metrics: self.service_metrics.read().await.clone(),
+            learning_weights: self.learning_weights.read().await.clone(),
+        };
+        let json = serde_json::to_vec(&snapshot).expect("AI engine state serializes");
+        persistence.slot.save(&json).await
+    }
+
+    // Forgets everything learned, on disk and in memory.
+    pub async fn clear_persisted_state(&self) -> std::io::Result<()> {
+        if let Some(persistence) = &self.persistence {
+            let _writing = persistence.writing.lock().await;
+            persistence.slot.delete().await?;
+            persistence.updates.store(0, Ordering::Relaxed);
+        }
+        self.service_metrics.write().await.clear();
+        self.learning_weights.write().await.clear();
+        self.request_history.write().await.clear();
+        Ok(())
     }
 
     pub async fn record_request(&self, metrics: RequestMetrics) {
+        if !self.is_enabled() {
+            return;
+        }
         let mut history = self.request_history.write().await;
         history.push(metrics.clone());
         
@@ -54,23 +390,26 @@ impl AIEngine {
             history.drain(0..1000);
         }
 
+        drop(history);
+
         self.update_service_health(&metrics).await;
-        debug!("Recorded request metrics for endpoint: {}", metrics.endpoint);
+        debug!(endpoint = %metrics.endpoint, "recorded request metrics");
+
+        if let Some(persistence) = &self.persistence {
+            let updates = persistence.updates.fetch_add(1, Ordering::Relaxed) + 1;
+            if updates % persistence.interval == 0 {
+                persistence.due.notify_one();
+            }
+        }
     }
 
     async fn update_service_health(&self, metrics: &RequestMetrics) {
         let mut service_metrics = self.service_metrics.write().await;
         
-        let health = service_metrics
-            .entry(metrics.endpoint.clone())
-            .or_insert(ServiceHealth {
-                endpoint: metrics.endpoint.clone(),
-                success_rate: 1.0,
-                avg_latency_ms: 0.0,
-                error_count: 0,
-                total_requests: 0,
-                last_updated: metrics.timestamp,
-            });
+        let health = service_metrics.entry(metrics.endpoint.clone()).or_insert_with(|| {
+            self.created.fetch_add(1, Ordering::Relaxed);
+            Self::initial_health(&metrics.endpoint, metrics.timestamp)
+        });
 
         health.total_requests += 1;
         
@@ -83,74 +422,129 @@ impl AIEngine {
         let alpha = 0.1;
         health.avg_latency_ms = alpha * metrics.latency_ms as f64 + (1.0 - alpha) * health.avg_latency_ms;
         health.last_updated = metrics.timestamp;
+
+        if self.eviction_threshold > 0 && is_failing(health, self.eviction_threshold) {
+            service_metrics.remove(&metrics.endpoint);
+            self.mark_evicted(&metrics.endpoint);
+        }
     }
 
+    #[cfg(feature = "ai")]
     pub async fn select_endpoint(&self, service_name: &str, available_endpoints: &[String]) -> AIDecision {
+        let available_endpoints = &self.without_evicted(available_endpoints)[..];
         if available_endpoints.is_empty() {
             return AIDecision {
                 selected_endpoint: "".to_string(),
                 confidence: 0.0,
                 reasoning: "No available endpoints".to_string(),
                 fallback_endpoints: vec![],
+                mode: SelectionMode::Ai,
+            };
+        }
+
+        let weights: Vec<u32> = {
+            let weights = self.endpoint_weights.read().unwrap();
+            let weights = weights.get(service_name);
+            available_endpoints
+                .iter()
+                .map(|endpoint| weights.and_then(|weights| weights.get(endpoint)).copied().unwrap_or(1))
+                .collect()
+        };
+        let heaviest = weights.iter().copied().max().unwrap_or(1);
+        // Weight 0 is never picked, unless it is the only endpoint left.
+        if heaviest == 0 && available_endpoints.len() > 1 {
+            return AIDecision {
+                selected_endpoint: "".to_string(),
+                confidence: 0.0,
+                reasoning: "No endpoint with a weight above 0".to_string(),
+                fallback_endpoints: vec![],
+                mode: SelectionMode::Ai,
             };
         }
 
         let service_metrics = self.service_metrics.read().await;
-        let mut endpoint_scores = HashMap::new();
+        let mut endpoint_scores = Vec::with_capacity(available_endpoints.len());
 
-        for endpoint in available_endpoints {
-            let score = if let Some(health) = service_metrics.get(endpoint) {
-                self.calculate_endpoint_score(health).await
-            } else {
-                0.5
-            };
-            endpoint_scores.insert(endpoint.clone(), score);
+        for (endpoint, weight) in available_endpoints.iter().zip(&weights) {
+            let mut score = self.selection_score(endpoint, service_metrics.get(endpoint));
+            if heaviest > 0 {
+                score *= *weight as f64 / heaviest as f64;
+            }
+            endpoint_scores.push((endpoint.clone(), score, *weight));
         }
 
-        let best_endpoint = endpoint_scores
+        // Ties go to the endpoint listed first, so equal scores route the same
+        // way every run.
+        let best_index = endpoint_scores
             .iter()
-            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-            .map(|(endpoint, score)| (endpoint.clone(), *score))
-            .unwrap_or_else(|| (available_endpoints[0].clone(), 0.5));
+            .enumerate()
+            .fold(0, |best, (index, (_, score, _))| if *score > endpoint_scores[best].1 { index } else { best });
+        let best_endpoint = endpoint_scores.remove(best_index);
 
-        let mut fallback_with_scores: Vec<(String, f64)> = endpoint_scores
-            .iter()
-            .filter(|(endpoint, _)| *endpoint != &best_endpoint.0)
-            .map(|(endpoint, score)| (endpoint.clone(), *score))
-            .collect::<Vec<_>>();
-        
+        // Weight 0 endpoints are left out of the fallbacks too.
+        let mut fallback_with_scores = endpoint_scores;
+        fallback_with_scores.retain(|(_, _, weight)| *weight > 0);
         fallback_with_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-        let fallback_endpoints: Vec<String> = fallback_with_scores.into_iter().map(|(endpoint, _)| endpoint).collect();
+        let fallback_endpoints: Vec<String> = fallback_with_scores.into_iter().map(|(endpoint, _, _)| endpoint).collect();
 
         let reasoning = format!(
             "Selected {} with score {:.3} based on success rate and latency analysis",
             best_endpoint.0, best_endpoint.1
         );
 
-        info!("AI decision for {}: {} (confidence: {:.3})", service_name, best_endpoint.0, best_endpoint.1);
+        debug!(
+            service = service_name,
+            endpoint = %best_endpoint.0,
+            score = best_endpoint.1,
+            candidates = available_endpoints.len(),
+            "AI decision"
+        );
 
         AIDecision {
             selected_endpoint: best_endpoint.0,
             confidence: best_endpoint.1,
             reasoning,
             fallback_endpoints,
+            mode: SelectionMode::Ai,
         }
     }
 
-    async fn calculate_endpoint_score(&self, health: &ServiceHealth) -> f64 {
-        let success_weight = 0.6;
-        let latency_weight = 0.4;
-        
-        let success_score = health.success_rate;
-        
-        let normalized_latency = if health.avg_latency_ms > 0.0 {
-            1.0 / (1.0 + health.avg_latency_ms / 1000.0)
-        } else {
-            1.0
+    // Without the `ai` feature there is no scoring; the engine only keeps
+    // passive health, and every pick is left to the balancer.
+    #[cfg(not(feature = "ai"))]
+    pub async fn select_endpoint(&self, _service_name: &str, _available_endpoints: &[String]) -> AIDecision {
+        AIDecision {
+            selected_endpoint: String::new(),
+            confidence: 0.0,
+            reasoning: "This build has no AI engine".to_string(),
+            fallback_endpoints: vec![],
+            mode: SelectionMode::Bypassed,
+        }
+    }
+
+    // Endpoints without passive stats yet score a neutral 0.5.
+    pub fn endpoint_score(&self, health: Option<&ServiceHealth>) -> f64 {
+        let Some(health) = health.filter(|health| health.total_requests > 0) else {
+            return 0.5;
         };
-        
-        let score = success_weight * success_score + latency_weight * normalized_latency;
-        score.min(1.0).max(0.0)
+        let score = self.scorer.score(health);
+        // NaN from a custom scorer cannot be ranked.
+        if score.is_nan() {
+            0.0
+        } else {
+            score.clamp(0.0, 1.0)
+        }
+    }
+
+    // The score selection starts from, before weights: a degraded endpoint's
+    // is scaled down rather than the endpoint being left out.
+    pub fn selection_score(&self, endpoint: &str, health: Option<&ServiceHealth>) -> f64 {
+        let score = self.endpoint_score(health);
+        if self.degraded.read().unwrap().contains(endpoint) {
+            score * self.degraded_penalty
+        } else {
+            score
+        }
     }
 
     pub async fn get_service_health(&self, endpoint: &str) -> Option<ServiceHealth> {
@@ -163,15 +557,43 @@ impl AIEngine {
         service_metrics.clone()
     }
 
+    // Drops everything learned about endpoints that are gone for good; returns
+    // how many health entries went with them.
+    pub async fn forget_endpoints(&self, endpoints: &[String]) -> usize {
+        self.request_history
+            .write()
+            .await
+            .retain(|metrics| !endpoints.contains(&metrics.endpoint));
+        self.degraded.write().unwrap().retain(|endpoint| !endpoints.contains(endpoint));
+        self.evicted_endpoints.write().unwrap().retain(|endpoint, _| !endpoints.contains(endpoint));
+        let mut service_metrics = self.service_metrics.write().await;
+        endpoints.iter().filter(|endpoint| service_metrics.remove(*endpoint).is_some()).count()
+    }
+
     pub async fn should_circuit_break(&self, endpoint: &str, threshold: u32) -> bool {
         if let Some(health) = self.get_service_health(endpoint).await {
-            health.error_count >= threshold && health.success_rate < 0.5
+            is_failing(&health, threshold)
         } else {
             false
         }
     }
 
+    // Every lock the engine has, for tests showing that something does not
+    // take any of them.
+    #[cfg(test)]
+    pub(crate) async fn hold_locks(&self) -> impl Send {
+        (
+            self.request_history.clone().write_owned().await,
+            self.service_metrics.clone().write_owned().await,
+            self.learning_weights.clone().write_owned().await,
+        )
+    }
+
+    // The base timeout while switched off, as for an endpoint not seen yet.
     pub async fn adaptive_timeout(&self, endpoint: &str) -> u64 {
+        if !self.is_enabled() {
+            return 5000;
+        }
         if let Some(health) = self.get_service_health(endpoint).await {
             let base_timeout = 5000u64;
             let adaptive_factor = if health.avg_latency_ms > 0.0 {
@@ -185,3 +607,342 @@ impl AIEngine {
         }
     }
 }
+
+fn is_failing(health: &ServiceHealth, threshold: u32) -> bool {
+    health.error_count >= threshold && health.success_rate < 0.5
+}
+
+async fn load_snapshot(slot: &Slot) -> std::io::Result<Option<Snapshot>> {
+    let Some(bytes) = slot.load().await? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+// Snapshots written before endpoints were canonicalized can hold one
+// endpoint under several spellings. Those become one entry under the
+// canonical key, with counts summed and latency weighted by requests.
+fn merge_equivalent(service_metrics: HashMap<String, ServiceHealth>) -> HashMap<String, ServiceHealth> {
+    let mut merged: HashMap<String, ServiceHealth> = HashMap::with_capacity(service_metrics.len());
+    for (endpoint, health) in service_metrics {
+        let key = endpoint_url::canonicalize(&endpoint).unwrap_or(endpoint);
+        match merged.get_mut(&key) {
+            None => {
+                merged.insert(key.clone(), ServiceHealth { endpoint: key, ..health });
+            }
+            Some(entry) => {
+                let total = entry.total_requests + health.total_requests;
+                if total > 0 {
+                    entry.avg_latency_ms = (entry.avg_latency_ms * entry.total_requests as f64
+                        + health.avg_latency_ms * health.total_requests as f64)
+                        / total as f64;
+                }
+                entry.total_requests = total;
+                entry.error_count += health.error_count;
+                entry.success_rate = if total > 0 { 1.0 - entry.error_count as f64 / total as f64 } else { 1.0 };
+                entry.last_updated = entry.last_updated.max(health.last_updated);
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(endpoint: &str, success: bool) -> RequestMetrics {
+        RequestMetrics {
+            latency_ms: 40,
+            status_code: if success { 200 } else { 503 },
+            endpoint: endpoint.to_string(),
+            timestamp: 1,
+            success,
+            phases: None,
+        }
+    }
+
+    fn persisted_config(name: &str) -> AIConfig {
+        let dir = std::env::temp_dir().join(format!("ai-engine-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let _ = std::fs::remove_file(&path);
+        AIConfig {
+            persist_path: Some(path),
+            persist_interval: 2,
+            ..AIConfig::default()
+        }
+    }
+
+    fn start_writer(engine: &Arc<AIEngine>) {
+        let metrics = Arc::new(crate::metrics::MetricsCollector::new());
+        let supervisor = Arc::new(TaskSupervisor::new(crate::config::SupervisorConfig::default(), metrics));
+        engine.start_persistence(&supervisor);
+    }
+
+    async fn wait_for<F: std::future::Future<Output = bool>>(written: impl Fn() -> F) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !written().await {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("snapshot never written");
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_weights_scale_endpoint_scores() {
+        let engine = AIEngine::new();
+        engine.record_request(request("http://a", true)).await;
+        engine.record_request(request("http://b", false)).await;
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+        let decision = engine.select_endpoint("svc", &endpoints).await;
+        assert_eq!(decision.selected_endpoint, "http://a");
+
+        // a scores about 0.98 and b about 0.4; a third of a's score is less than b's.
+        let weights = |pairs: &[(&str, u32)]| {
+            let weights = pairs.iter().map(|(endpoint, weight)| (endpoint.to_string(), *weight)).collect();
+            HashMap::from([("svc".to_string(), weights)])
+        };
+        engine.set_endpoint_weights(weights(&[("http://b", 3)]));
+        let decision = engine.select_endpoint("svc", &endpoints).await;
+        assert_eq!(decision.selected_endpoint, "http://b");
+        assert!(decision.confidence < 0.5, "{}", decision.confidence);
+        assert_eq!(engine.select_endpoint("other", &endpoints).await.selected_endpoint, "http://a");
+
+        engine.set_endpoint_weights(weights(&[("http://a", 0)]));
+        for _ in 0..1_000 {
+            let decision = engine.select_endpoint("svc", &endpoints).await;
+            assert_eq!(decision.selected_endpoint, "http://b");
+            assert!(decision.fallback_endpoints.is_empty());
+        }
+        let alone = ["http://a".to_string()];
+        assert_eq!(engine.select_endpoint("svc", &alone).await.selected_endpoint, "http://a");
+
+        engine.set_endpoint_weights(weights(&[("http://a", 0), ("http://b", 0)]));
+        assert_eq!(engine.select_endpoint("svc", &endpoints).await.selected_endpoint, "");
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_scorer_decides_the_ranking() {
+        let timed = |endpoint: &str, latency_ms, success| RequestMetrics {
+            latency_ms,
+            ..request(endpoint, success)
+        };
+        let endpoints = vec!["http://steady".to_string(), "http://fast".to_string()];
+        let engines = [
+            AIEngine::new(),
+            AIEngine::new().with_scorer(Arc::new(LatencyPriorityScorer)),
+        ];
+        for engine in &engines {
+            for _ in 0..20 {
+                engine.record_request(timed("http://steady", 2_000, true)).await;
+                engine.record_request(timed("http://fast", 10, false)).await;
+            }
+        }
+
+        // Every call to fast fails, which only the default scorer minds.
+        assert_eq!(engines[0].select_endpoint("svc", &endpoints).await.selected_endpoint, "http://steady");
+        assert_eq!(engines[1].select_endpoint("svc", &endpoints).await.selected_endpoint, "http://fast");
+
+        struct Broken;
+        impl EndpointScorer for Broken {
+            fn score(&self, _health: &ServiceHealth) -> f64 {
+                f64::NAN
+            }
+        }
+        let engine = AIEngine::new().with_scorer(Arc::new(Broken));
+        engine.record_request(request("http://steady", true)).await;
+        assert_eq!(engine.endpoint_score(engine.get_service_health("http://steady").await.as_ref()), 0.0);
+        assert_eq!(engine.endpoint_score(None), 0.5);
+        assert_eq!(engine.select_endpoint("svc", &endpoints).await.selected_endpoint, "http://fast");
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_failing_endpoint_is_evicted_until_rehabilitated() {
+        let config = AIConfig { eviction_threshold: 3, ..AIConfig::default() };
+        let engine = AIEngine::with_config(&config).await;
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+        engine.record_request(request("http://a", true)).await;
+        for _ in 0..2 {
+            engine.record_request(request("http://a", false)).await;
+        }
+        assert!(!engine.is_evicted("http://a"));
+
+        // The third error, with one success in four, crosses the threshold.
+        engine.record_request(request("http://a", false)).await;
+        assert!(engine.is_evicted("http://a"));
+        assert!(engine.get_service_health("http://a").await.is_none());
+        let decision = engine.select_endpoint("svc", &endpoints).await;
+        assert_eq!(decision.selected_endpoint, "http://b");
+        assert!(decision.fallback_endpoints.is_empty());
+        // With nothing else left, it is still picked.
+        assert_eq!(engine.select_endpoint("svc", &endpoints[..1]).await.selected_endpoint, "http://a");
+
+        assert!(engine.rehabilitate_endpoint("http://a"));
+        assert!(!engine.rehabilitate_endpoint("http://a"));
+        assert_eq!(engine.select_endpoint("svc", &endpoints).await.fallback_endpoints.len(), 1);
+
+        // Evicted by hand, whatever its record.
+        assert!(engine.evict_endpoint("http://b").await);
+        assert!(!engine.evict_endpoint("http://b").await);
+        assert_eq!(engine.select_endpoint("svc", &endpoints).await.selected_endpoint, "http://a");
+    }
+
+    #[tokio::test]
+    async fn test_rehabilitation_counts_passes_in_a_row_since_eviction() {
+        let config = AIConfig { rehabilitation_successes: 2, ..AIConfig::default() };
+        let engine = AIEngine::with_config(&config).await;
+        assert!(!engine.record_probe("http://a", true));
+
+        engine.evict_endpoint("http://a").await;
+        assert!(!engine.record_probe("http://a", true));
+        assert!(!engine.record_probe("http://a", false));
+        assert!(!engine.record_probe("http://a", true));
+        assert!(engine.is_evicted("http://a"));
+        assert!(engine.record_probe("http://a", true));
+        assert!(!engine.is_evicted("http://a"));
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_degraded_endpoints_are_scored_down_not_dropped() {
+        let engine = AIEngine::new();
+        engine.record_request(request("http://a", true)).await;
+        engine.record_request(request("http://b", true)).await;
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+
+        engine.set_degraded("http://a", true);
+        let decision = engine.select_endpoint("svc", &endpoints).await;
+        assert_eq!(decision.selected_endpoint, "http://b");
+        assert_eq!(decision.fallback_endpoints, vec!["http://a".to_string()]);
+        let health = engine.get_service_health("http://a").await;
+        assert_eq!(engine.selection_score("http://a", health.as_ref()), engine.endpoint_score(health.as_ref()) * 0.5);
+
+        let alone = ["http://a".to_string()];
+        assert_eq!(engine.select_endpoint("svc", &alone).await.selected_endpoint, "http://a");
+
+        engine.set_degraded("http://a", false);
+        assert_eq!(engine.select_endpoint("svc", &endpoints).await.selected_endpoint, "http://a");
+    }
+
+    #[tokio::test]
+    async fn test_switched_off_engine_takes_no_locks() {
+        let engine = AIEngine::new();
+        engine.record_request(request("http://a", true)).await;
+        assert!(engine.set_enabled(false));
+
+        let locks = engine.hold_locks().await;
+        let switched_off = async {
+            engine.record_request(request("http://a", false)).await;
+            engine.adaptive_timeout("http://a").await
+        };
+        let timeout = tokio::time::timeout(std::time::Duration::from_secs(1), switched_off).await;
+        assert_eq!(timeout.expect("switched-off engine waited on a lock"), 5000);
+        drop(locks);
+
+        let health = engine.get_service_health("http://a").await.unwrap();
+        assert_eq!((health.total_requests, health.error_count), (1, 0));
+        assert!(!engine.set_enabled(true));
+        engine.record_request(request("http://a", false)).await;
+        assert_eq!(engine.get_service_health("http://a").await.unwrap().error_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_state_survives_restart_every_interval() {
+        let config = persisted_config("restart");
+        let path = config.persist_path.clone().unwrap();
+
+        let engine = Arc::new(AIEngine::with_config(&config).await);
+        start_writer(&engine);
+        engine.record_request(request("http://a", true)).await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!path.exists());
+        engine.record_request(request("http://a", false)).await;
+        wait_for(|| std::future::ready(path.exists())).await;
+        // Not yet flushed; lost on restart.
+        engine.record_request(request("http://b", true)).await;
+
+        let restarted = AIEngine::with_config(&config).await;
+        let health = restarted.get_all_service_health().await;
+        assert_eq!(health.len(), 1);
+        assert_eq!(health["http://a"].total_requests, 2);
+        assert_eq!(health["http://a"].error_count, 1);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_kept_in_shared_store() {
+        let dir = std::env::temp_dir().join(format!("ai-engine-store-{}", std::process::id()));
+        let store: Arc<dyn KeyValueStore> =
+            Arc::new(storage::FileStore::new(dir.clone(), Arc::new(crate::clock::SystemClock)).unwrap());
+        let config = AIConfig { persist_interval: 1, ..AIConfig::default() };
+
+        let engine = Arc::new(AIEngine::with_storage(&config, Some(store.clone())).await);
+        start_writer(&engine);
+        engine.record_request(request("http://a", true)).await;
+        wait_for(|| async { store.get(storage::AI_NAMESPACE, "snapshot").await.unwrap().is_some() }).await;
+        let restarted = AIEngine::with_storage(&config, Some(store.clone())).await;
+        assert_eq!(restarted.get_service_health("http://a").await.unwrap().total_requests, 1);
+
+        restarted.clear_persisted_state().await.unwrap();
+        assert_eq!(store.get(storage::AI_NAMESPACE, "snapshot").await.unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clear_removes_file_and_memory() {
+        let config = persisted_config("clear");
+        let path = config.persist_path.clone().unwrap();
+
+        let engine = AIEngine::with_config(&config).await;
+        engine.record_request(request("http://a", true)).await;
+        engine.persist().await.unwrap();
+        engine.clear_persisted_state().await.unwrap();
+        assert!(!path.exists());
+        assert!(engine.get_service_health("http://a").await.is_none());
+
+        std::fs::write(&path, "not json").unwrap();
+        let engine = AIEngine::with_config(&config).await;
+        assert!(engine.get_all_service_health().await.is_empty());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_spellings_of_one_endpoint_merge() {
+        let config = persisted_config("merge");
+        let path = config.persist_path.clone().unwrap();
+        let health = |endpoint: &str, total: u32, errors: u32, latency: f64, updated: u64| ServiceHealth {
+            endpoint: endpoint.to_string(),
+            success_rate: 1.0 - errors as f64 / total as f64,
+            avg_latency_ms: latency,
+            error_count: errors,
+            total_requests: total,
+            last_updated: updated,
+        };
+        let snapshot = Snapshot {
+            service_metrics: HashMap::from([
+                ("http://api.internal".to_string(), health("http://api.internal", 10, 1, 20.0, 5)),
+                ("HTTP://API.internal:80/".to_string(), health("HTTP://API.internal:80/", 30, 3, 60.0, 9)),
+                ("http://api.internal/".to_string(), health("http://api.internal/", 10, 6, 10.0, 7)),
+            ]),
+            learning_weights: HashMap::new(),
+        };
+        std::fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let engine = AIEngine::with_config(&config).await;
+        let all = engine.get_all_service_health().await;
+        assert_eq!(all.len(), 1);
+        let merged = &all["http://api.internal"];
+        assert_eq!(merged.endpoint, "http://api.internal");
+        assert_eq!(merged.total_requests, 50);
+        assert_eq!(merged.error_count, 10);
+        assert!((merged.success_rate - 0.8).abs() < 1e-9);
+        assert!((merged.avg_latency_ms - 42.0).abs() < 1e-9);
+        assert_eq!(merged.last_updated, 9);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
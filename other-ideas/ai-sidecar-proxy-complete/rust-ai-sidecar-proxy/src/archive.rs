@@ -0,0 +1,440 @@
+use crate::{metrics::MetricsCollector, supervisor::TaskSupervisor};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hyper::{header::HeaderName, HeaderMap};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{mpsc, Mutex},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub spool_dir: String,
+    // A spool file is closed and a new one started once it reaches this size.
+    pub max_file_bytes: u64,
+    // The oldest spool files are deleted beyond this many.
+    pub max_files: usize,
+    // Records waiting for the writer. When it is full new records are dropped.
+    pub queue_capacity: usize,
+    // Header values replaced with "[redacted]" in records; case-insensitive.
+    pub redact_headers: Vec<String>,
+    // Keyed by route, e.g. "/api/payments". Routes not listed are never archived.
+    pub routes: HashMap<String, RouteArchiveConfig>,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            spool_dir: "archive-spool".to_string(),
+            max_file_bytes: 64 * 1024 * 1024,
+            max_files: 16,
+            queue_capacity: 1024,
+            redact_headers: ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"]
+                .map(str::to_string)
+                .to_vec(),
+            routes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteArchiveConfig {
+    // Fraction of requests archived, 0.0 to 1.0.
+    pub sample_rate: f64,
+    // Bytes kept of each body; the record notes the full size.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+// A body as archived: at most the route's cap, copied out of the proxy's
+// buffer so a queued record never pins the whole body in memory.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchivedBody {
+    pub size: usize,
+    pub truncated: bool,
+    #[serde(serialize_with = "as_base64")]
+    pub data: Bytes,
+}
+
+impl ArchivedBody {
+    pub fn capture(body: &[u8], cap: usize) -> Self {
+        Self::prefix(body, body.len(), cap)
+    }
+
+    // For a body relayed as it arrived, of which only the first bytes were
+    // kept; `size` is how much of it there was in all.
+    pub fn prefix(kept: &[u8], size: usize, cap: usize) -> Self {
+        let kept = &kept[..kept.len().min(cap)];
+        Self {
+            size,
+            truncated: kept.len() < size,
+            data: Bytes::copy_from_slice(kept),
+        }
+    }
+
+    // For a body streamed to the upstream, of which only the part read
+    // ahead was held. Its size is the declared one, when there was one.
+    pub fn streamed(read_ahead: &[u8], declared: Option<u64>, cap: usize) -> Self {
+        let mut body = Self::capture(read_ahead, cap);
+        body.size = declared.map_or(body.size, |length| length as usize);
+        body.truncated = true;
+        body
+    }
+}
+
+fn as_base64<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+// One NDJSON line in the spool.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveRecord {
+    pub timestamp_ms: u64,
+    pub direction: &'static str,
+    pub route: String,
+    pub method: String,
+    pub path: String,
+    pub endpoint: String,
+    pub status: u16,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: ArchivedBody,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: ArchivedBody,
+}
+
+// Decides which requests are archived and hands their records to the writer
+// without ever waiting on it.
+pub struct Archiver {
+    routes: HashMap<String, RouteArchiveConfig>,
+    redact: Vec<HeaderName>,
+    sender: mpsc::Sender<ArchiveRecord>,
+    metrics: Arc<MetricsCollector>,
+    // Records queued or being written.
+    pending: Arc<AtomicUsize>,
+}
+
+impl Archiver {
+    // Returns None when no route is archived.
+    pub fn start(
+        config: &ArchiveConfig,
+        metrics: Arc<MetricsCollector>,
+        supervisor: &Arc<TaskSupervisor>,
+    ) -> Result<Option<Self>> {
+        if config.routes.is_empty() {
+            return Ok(None);
+        }
+        let (archiver, receiver) = Self::new(config, metrics.clone())?;
+        let receiver = Arc::new(Mutex::new(receiver));
+        let config = config.clone();
+        let pending = archiver.pending.clone();
+
+        supervisor.spawn("archive_writer", false, move |heartbeat| {
+            let receiver = receiver.clone();
+            let metrics = metrics.clone();
+            let config = config.clone();
+            let pending = pending.clone();
+            async move {
+                let mut spool = Spool::new(&config);
+                let mut receiver = receiver.lock().await;
+                let mut idle = tokio::time::interval(Duration::from_secs(10));
+                loop {
+                    tokio::select! {
+                        record = receiver.recv() => {
+                            let Some(record) = record else { return };
+                            match spool.write(&record).await {
+                                Ok(()) => metrics.record_archive("written"),
+                                Err(e) => {
+                                    metrics.record_archive("write_error");
+                                    warn!(error = format!("{:#}", e), "failed to write archive record");
+                                }
+                            }
+                            pending.fetch_sub(1, Ordering::Relaxed);
+                        }
+                        _ = idle.tick() => {}
+                    }
+                    heartbeat.beat();
+                }
+            }
+        });
+        Ok(Some(archiver))
+    }
+
+    pub fn new(config: &ArchiveConfig, metrics: Arc<MetricsCollector>) -> Result<(Self, mpsc::Receiver<ArchiveRecord>)> {
+        let redact = config
+            .redact_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes())
+                    .with_context(|| format!("invalid header name {:?} in archive redact_headers", name))
+            })
+            .collect::<Result<_>>()?;
+        for (route, archive) in &config.routes {
+            if !(0.0..=1.0).contains(&archive.sample_rate) {
+                anyhow::bail!("archive sample_rate for {} must be between 0 and 1", route);
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let archiver = Self {
+            routes: config.routes.clone(),
+            redact,
+            sender,
+            metrics,
+            pending: Arc::new(AtomicUsize::new(0)),
+        };
+        Ok((archiver, receiver))
+    }
+
+    // The body cap to archive this request with, if it was sampled.
+    pub fn sample(&self, route: &str) -> Option<usize> {
+        let archive = self.routes.get(route)?;
+        (rand::random::<f64>() < archive.sample_rate).then_some(archive.max_body_bytes)
+    }
+
+    pub fn headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        redacted_headers(headers, &self.redact)
+    }
+
+    // Drops the record rather than wait when the writer is behind.
+    pub fn submit(&self, record: ArchiveRecord) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(record) {
+            Ok(()) => self.metrics.record_archive("queued"),
+            Err(_) => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+                self.metrics.record_archive("dropped");
+            }
+        }
+    }
+
+    // Resolves once every record queued so far has been written, or has
+    // failed to be.
+    pub async fn flush(&self) {
+        while self.pending.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+pub fn redacted_headers(headers: &HeaderMap, redact: &[HeaderName]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if redact.contains(name) {
+                "[redacted]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+struct Spool {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+    current: Option<(File, u64)>,
+    sequence: u64,
+}
+
+impl Spool {
+    fn new(config: &ArchiveConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.spool_dir),
+            max_file_bytes: config.max_file_bytes.max(1),
+            max_files: config.max_files.max(1),
+            current: None,
+            sequence: 0,
+        }
+    }
+
+    async fn write(&mut self, record: &ArchiveRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let full = self
+            .current
+            .as_ref()
+            .is_some_and(|(_, written)| *written > 0 && written + line.len() as u64 > self.max_file_bytes);
+        if full || self.current.is_none() {
+            self.rotate().await?;
+        }
+        let (file, written) = self.current.as_mut().expect("rotate opened a file");
+        file.write_all(&line).await?;
+        file.flush().await?;
+        *written += line.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> Result<()> {
+        if let Some((mut file, _)) = self.current.take() {
+            file.flush().await?;
+        }
+        fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("creating spool directory {}", self.dir.display()))?;
+
+        // Zero-padded so that name order is age order.
+        self.sequence += 1;
+        let path = self.dir.join(format!("archive-{:013}-{:06}.ndjson", now_ms(), self.sequence));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("opening spool file {}", path.display()))?;
+        self.current = Some((file, 0));
+        prune(&self.dir, self.max_files).await
+    }
+}
+
+async fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("archive-") && name.ends_with(".ndjson") {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    let excess = files.len().saturating_sub(keep);
+    for path in &files[..excess] {
+        fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ai-sidecar-proxy-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn config(dir: &Path, sample_rate: f64) -> ArchiveConfig {
+        ArchiveConfig {
+            spool_dir: dir.display().to_string(),
+            routes: HashMap::from([(
+                "/api/a".to_string(),
+                RouteArchiveConfig {
+                    sample_rate,
+                    max_body_bytes: 4,
+                },
+            )]),
+            ..ArchiveConfig::default()
+        }
+    }
+
+    fn record(body: &[u8]) -> ArchiveRecord {
+        ArchiveRecord {
+            timestamp_ms: now_ms(),
+            direction: "ingress",
+            route: "/api/a".to_string(),
+            method: "POST".to_string(),
+            path: "/api/a/items".to_string(),
+            endpoint: "http://upstream".to_string(),
+            status: 200,
+            request_headers: Vec::new(),
+            request_body: ArchivedBody::capture(body, 4),
+            response_headers: Vec::new(),
+            response_body: ArchivedBody::capture(b"", 4),
+        }
+    }
+
+    #[test]
+    fn test_sampling_rate_approximately_holds() {
+        let dir = spool_dir();
+        let (archiver, _receiver) = Archiver::new(&config(&dir, 0.25), Arc::new(MetricsCollector::new())).unwrap();
+
+        let sampled = (0..10_000).filter(|_| archiver.sample("/api/a").is_some()).count();
+        assert!((2_200..=2_800).contains(&sampled), "sampled {} of 10000", sampled);
+        assert_eq!(archiver.sample("/api/b"), None);
+    }
+
+    #[test]
+    fn test_full_queue_drops_without_waiting() {
+        let dir = spool_dir();
+        let mut config = config(&dir, 1.0);
+        config.queue_capacity = 2;
+        let metrics = Arc::new(MetricsCollector::new());
+        // Nothing drains the receiver.
+        let (archiver, _receiver) = Archiver::new(&config, metrics.clone()).unwrap();
+
+        for _ in 0..100 {
+            archiver.submit(record(b"body"));
+        }
+        assert_eq!(metrics.archive_count("queued"), 2);
+        assert_eq!(metrics.archive_count("dropped"), 98);
+    }
+
+    #[test]
+    fn test_headers_redacted_and_body_capped() {
+        let dir = spool_dir();
+        let mut config = config(&dir, 1.0);
+        config.redact_headers.push("X-Session".to_string());
+        let (archiver, _receiver) = Archiver::new(&config, Arc::new(MetricsCollector::new())).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("x-session", "abc".parse().unwrap());
+        headers.insert("accept", "*/*".parse().unwrap());
+        let archived: HashMap<_, _> = archiver.headers(&headers).into_iter().collect();
+        assert_eq!(archived["authorization"], "[redacted]");
+        assert_eq!(archived["x-session"], "[redacted]");
+        assert_eq!(archived["accept"], "*/*");
+
+        let body = ArchivedBody::capture(b"0123456789", 4);
+        assert_eq!((body.size, body.truncated, body.data.as_ref()), (10, true, &b"0123"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_spool_rotates_and_prunes() {
+        let dir = spool_dir();
+        let mut config = config(&dir, 1.0);
+        config.max_file_bytes = 1;
+        config.max_files = 3;
+        let mut spool = Spool::new(&config);
+
+        for _ in 0..5 {
+            spool.write(&record(b"body")).await.unwrap();
+        }
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert!(names[2].ends_with("-000005.ndjson"), "{:?}", names);
+
+        let line = std::fs::read_to_string(dir.join(&names[2])).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(parsed["request_body"]["data"], STANDARD.encode(b"body"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
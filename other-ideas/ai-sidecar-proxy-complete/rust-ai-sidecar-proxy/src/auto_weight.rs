@@ -0,0 +1,205 @@
+use crate::{ai::AIEngine, load_balancer::LoadBalancer};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+// A target one unit away from the current weight is rounding noise, not a
+// reason to move.
+const DEADBAND: u32 = 1;
+
+// Turns the AI engine's endpoint scores into load balancer weights, so
+// weight-aware strategies route by learned health too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoWeightConfig {
+    pub interval_ms: u64,
+    pub min_weight: u32,
+    pub max_weight: u32,
+    // Largest change to one endpoint's weight per interval.
+    pub max_step: u32,
+    // Endpoints with fewer recorded requests keep the weight they have.
+    pub min_samples: u32,
+    // How far below the best endpoint's score an endpoint has to fall to be
+    // down at `min_weight`; weights are linear in between.
+    pub score_spread: f64,
+    // Share of the newest score in each endpoint's running average.
+    pub smoothing: f64,
+}
+
+impl Default for AutoWeightConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 10_000,
+            min_weight: 1,
+            max_weight: 100,
+            max_step: 10,
+            min_samples: 20,
+            score_spread: 0.3,
+            smoothing: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WeightChange {
+    pub endpoint: String,
+    pub from: u32,
+    pub to: u32,
+    pub score: f64,
+}
+
+// One per auto-weighted service; keeps the smoothed scores between rounds.
+pub struct AutoWeigher {
+    config: AutoWeightConfig,
+    scores: HashMap<String, f64>,
+}
+
+impl AutoWeigher {
+    pub fn new(config: AutoWeightConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AutoWeightConfig {
+        &self.config
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.config.interval_ms.max(1))
+    }
+
+    // One round: every endpoint with enough samples moves up to `max_step`
+    // towards the weight its smoothed score calls for. Moving towards a
+    // target that only follows the average never overshoots it, so weights
+    // settle instead of swinging.
+    pub async fn tune(
+        &mut self,
+        service_name: &str,
+        endpoints: &[String],
+        ai_engine: &AIEngine,
+        balancer: &LoadBalancer,
+    ) -> Vec<WeightChange> {
+        self.scores.retain(|endpoint, _| endpoints.contains(endpoint));
+        let mut sampled = Vec::new();
+        for endpoint in endpoints {
+            let health = ai_engine.get_service_health(endpoint).await;
+            if health.as_ref().map_or(0, |health| health.total_requests) < self.config.min_samples {
+                continue;
+            }
+            let score = ai_engine.endpoint_score(health.as_ref());
+            let alpha = self.config.smoothing;
+            let smoothed = self
+                .scores
+                .get(endpoint)
+                .map_or(score, |previous| alpha * score + (1.0 - alpha) * previous);
+            self.scores.insert(endpoint.clone(), smoothed);
+            sampled.push((endpoint, smoothed));
+        }
+        // Scores only mean something next to each other.
+        if sampled.len() < 2 {
+            return Vec::new();
+        }
+
+        let best = sampled.iter().map(|(_, score)| *score).fold(f64::MIN, f64::max);
+        let current = balancer.explain(service_name, endpoints).await;
+        let mut changes = Vec::new();
+        for (endpoint, score) in sampled {
+            let from = current
+                .endpoints
+                .iter()
+                .find(|explained| explained.endpoint == *endpoint)
+                .map_or(self.config.min_weight, |explained| explained.weight);
+            let to = self.next_weight(from, self.target(best - score));
+            if to != from {
+                balancer.set_auto_weight(service_name, endpoint, to).await;
+                changes.push(WeightChange {
+                    endpoint: endpoint.clone(),
+                    from,
+                    to,
+                    score,
+                });
+            }
+        }
+        changes
+    }
+
+    fn target(&self, gap: f64) -> u32 {
+        let fraction = (gap / self.config.score_spread).clamp(0.0, 1.0);
+        let range = self.config.max_weight.saturating_sub(self.config.min_weight);
+        self.config.max_weight - (range as f64 * fraction).round() as u32
+    }
+
+    fn next_weight(&self, from: u32, target: u32) -> u32 {
+        let from_clamped = from.clamp(self.config.min_weight, self.config.max_weight);
+        if from == from_clamped && from.abs_diff(target) <= DEADBAND {
+            return from;
+        }
+        if target > from_clamped {
+            from_clamped.saturating_add(self.config.max_step).min(target)
+        } else {
+            from_clamped.saturating_sub(self.config.max_step).max(target)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ai::RequestMetrics, load_balancer::WeightSource};
+
+    async fn record(ai_engine: &AIEngine, endpoint: &str, requests: u32, failures: u32) {
+        for index in 0..requests {
+            ai_engine
+                .record_request(RequestMetrics {
+                    latency_ms: 10,
+                    status_code: 200,
+                    endpoint: endpoint.to_string(),
+                    timestamp: 0,
+                    success: index >= failures,
+                    phases: None,
+                })
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_steps_are_bounded_and_settle() {
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+        let ai_engine = AIEngine::new();
+        let balancer = LoadBalancer::new()
+            .with_service_weights("svc", &HashMap::from([("http://a".to_string(), 50), ("http://b".to_string(), 50)]));
+        record(&ai_engine, "http://a", 40, 0).await;
+        // Half failing scores 0.3 below `a`, which is all of `score_spread`.
+        record(&ai_engine, "http://b", 40, 20).await;
+        let mut weigher = AutoWeigher::new(AutoWeightConfig::default());
+
+        let mut history = Vec::new();
+        for _ in 0..15 {
+            weigher.tune("svc", &endpoints, &ai_engine, &balancer).await;
+            let explained = balancer.explain("svc", &endpoints).await;
+            history.push((explained.endpoints[0].weight, explained.endpoints[1].weight));
+        }
+
+        assert_eq!(history[..6], [(60, 40), (70, 30), (80, 20), (90, 10), (100, 1), (100, 1)]);
+        assert!(history[6..].iter().all(|weights| *weights == (100, 1)));
+        let explained = balancer.explain("svc", &endpoints).await;
+        assert_eq!(explained.endpoints[0].weight_source, WeightSource::Auto);
+    }
+
+    #[tokio::test]
+    async fn test_frozen_without_samples() {
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+        let ai_engine = AIEngine::new();
+        let balancer = LoadBalancer::new();
+        balancer.set_endpoint_weight("svc", "http://a", 7).await;
+        record(&ai_engine, "http://a", 40, 0).await;
+        record(&ai_engine, "http://b", 5, 5).await;
+        let mut weigher = AutoWeigher::new(AutoWeightConfig::default());
+
+        assert!(weigher.tune("svc", &endpoints, &ai_engine, &balancer).await.is_empty());
+        let explained = balancer.explain("svc", &endpoints).await;
+        assert_eq!(explained.endpoints[0].weight, 7);
+        assert_eq!(explained.endpoints[0].weight_source, WeightSource::Static);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::{
+    ai::AIEngine,
+    config::Config,
+    metrics::MetricsCollector,
+    mock_upstream::{MockResponse, MockUpstream},
+    proxy::ProxyServer,
+};
+use anyhow::{bail, Context, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpListener, sync::mpsc, time::interval};
+
+const TICK: Duration = Duration::from_millis(10);
+const BREAKER_OPEN_MESSAGE: &str = "Service temporarily unavailable";
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub rps: u32,
+    pub duration: Duration,
+    pub upstreams: usize,
+    pub upstream_p99: Duration,
+    pub error_rate: f64,
+    pub post_ratio: f64,
+    pub body_bytes: usize,
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            rps: 1000,
+            duration: Duration::from_secs(10),
+            upstreams: 3,
+            upstream_p99: Duration::from_millis(50),
+            error_rate: 0.0,
+            post_ratio: 0.0,
+            body_bytes: 1024,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Response { status: u16, endpoint: Option<String>, breaker_open: bool },
+    Transport(&'static str),
+}
+
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    pub sent: usize,
+    pub elapsed: Duration,
+    pub latencies_us: Vec<u64>,
+    pub statuses: BTreeMap<u16, usize>,
+    pub errors: BTreeMap<String, usize>,
+    pub endpoint_decisions: BTreeMap<String, usize>,
+    pub breaker_rejections: usize,
+}
+
+impl BenchReport {
+    pub fn achieved_rps(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies_us.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0) * (self.latencies_us.len() - 1) as f64).round() as usize;
+        Duration::from_micros(self.latencies_us[rank])
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "requests:      {} in {:.2}s", self.sent, self.elapsed.as_secs_f64())?;
+        writeln!(f, "achieved rps:  {:.1}", self.achieved_rps())?;
+        writeln!(
+            f,
+            "latency:       p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        )?;
+        writeln!(f, "status codes:")?;
+        for (status, count) in &self.statuses {
+            writeln!(f, "  {:<12} {}", status, count)?;
+        }
+        writeln!(f, "errors:")?;
+        for (kind, count) in &self.errors {
+            writeln!(f, "  {:<12} {}", kind, count)?;
+        }
+        writeln!(f, "breaker rejections: {}", self.breaker_rejections)?;
+        writeln!(f, "AI decisions:")?;
+        for (endpoint, count) in &self.endpoint_decisions {
+            let share = *count as f64 * 100.0 / self.sent.max(1) as f64;
+            writeln!(f, "  {:<28} {:>8} ({:.1}%)", endpoint, count, share)?;
+        }
+        Ok(())
+    }
+}
+
+// Accepts "250ms", "30s", "2m" or a bare number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let (number, unit) = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .map_or((value, ""), |i| value.split_at(i));
+    let number: f64 = number.parse().with_context(|| format!("invalid duration {:?}", value))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => bail!("unknown duration unit {:?} in {:?}", unit, value),
+    };
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+// Exponential latencies scaled so that 99% of samples land under `p99`.
+fn sample_latency(rng: &mut StdRng, p99: Duration) -> Duration {
+    let mean = p99.as_secs_f64() / 100f64.ln();
+    let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+    Duration::from_secs_f64(-mean * u.ln())
+}
+
+pub async fn run(options: BenchOptions) -> Result<BenchReport> {
+    if options.rps == 0 {
+        bail!("--rps must be greater than zero");
+    }
+    if options.upstreams == 0 {
+        bail!("--upstreams must be greater than zero");
+    }
+
+    let mut upstreams = Vec::with_capacity(options.upstreams);
+    for index in 0..options.upstreams {
+        let rng = Mutex::new(StdRng::seed_from_u64(options.seed.wrapping_add(index as u64)));
+        let p99 = options.upstream_p99;
+        let error_rate = options.error_rate;
+        let upstream = MockUpstream::start_with(move || {
+            let mut rng = rng.lock().unwrap();
+            let latency = sample_latency(&mut rng, p99);
+            let status = if rng.gen_bool(error_rate.clamp(0.0, 1.0)) { 500 } else { 200 };
+            MockResponse {
+                status,
+                latency,
+                ..MockResponse::default()
+            }
+        })
+        .await?;
+        upstreams.push(upstream);
+    }
+
+    let mut config = Config::new();
+    config.upstream_services.retain(|name, _| name == "service-a");
+    let service = config.upstream_services.get_mut("service-a").unwrap();
+    service.endpoints = upstreams.iter().map(MockUpstream::url).collect();
+
+    let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new()))?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_url = format!("http://{}/api/a/bench", listener.local_addr()?);
+    let proxy_task = tokio::spawn(async move { proxy.serve(listener).await });
+
+    let client = reqwest::Client::new();
+    let body = bytes::Bytes::from(vec![b'x'; options.body_bytes]);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut mix_rng = StdRng::seed_from_u64(options.seed);
+
+    let per_tick = options.rps as f64 * TICK.as_secs_f64();
+    let mut owed = 0.0;
+    let mut sent = 0;
+    let started = Instant::now();
+    let mut ticker = interval(TICK);
+
+    while started.elapsed() < options.duration {
+        ticker.tick().await;
+        owed += per_tick;
+        while owed >= 1.0 {
+            owed -= 1.0;
+            sent += 1;
+
+            let request = if mix_rng.gen_bool(options.post_ratio.clamp(0.0, 1.0)) {
+                client.post(&proxy_url).body(body.clone())
+            } else {
+                client.get(&proxy_url)
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let start = Instant::now();
+                let outcome = match request.send().await {
+                    Ok(response) => {
+                        let status = response.status().as_u16();
+                        let endpoint = response
+                            .headers()
+                            .get("x-proxy-endpoint")
+                            .and_then(|v| v.to_str().ok())
+                            .map(str::to_string);
+                        let body = response.bytes().await.unwrap_or_default();
+                        let breaker_open = status == 503
+                            && std::str::from_utf8(&body).is_ok_and(|b| b.contains(BREAKER_OPEN_MESSAGE));
+                        Outcome::Response { status, endpoint, breaker_open }
+                    }
+                    Err(e) if e.is_timeout() => Outcome::Transport("timeout"),
+                    Err(e) if e.is_connect() => Outcome::Transport("connect"),
+                    Err(_) => Outcome::Transport("other"),
+                };
+                let _ = tx.send((start.elapsed(), outcome));
+            });
+        }
+    }
+    drop(tx);
+
+    let mut report = BenchReport {
+        sent,
+        ..BenchReport::default()
+    };
+    while let Some((latency, outcome)) = rx.recv().await {
+        report.latencies_us.push(latency.as_micros() as u64);
+        match outcome {
+            Outcome::Response { status, endpoint, breaker_open } => {
+                *report.statuses.entry(status).or_default() += 1;
+                if let Some(endpoint) = endpoint {
+                    *report.endpoint_decisions.entry(endpoint).or_default() += 1;
+                }
+                if breaker_open {
+                    report.breaker_rejections += 1;
+                }
+            }
+            Outcome::Transport(kind) => *report.errors.entry(kind.to_string()).or_default() += 1,
+        }
+    }
+    report.elapsed = started.elapsed();
+    report.latencies_us.sort_unstable();
+
+    proxy_task.abort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("80ms").unwrap(), Duration::from_millis(80));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("fast").is_err());
+    }
+
+    #[test]
+    fn test_latency_sampling_matches_p99() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let p99 = Duration::from_millis(80);
+        let mut samples: Vec<Duration> = (0..10_000).map(|_| sample_latency(&mut rng, p99)).collect();
+        samples.sort();
+
+        let observed = samples[9_900];
+        assert!(observed > Duration::from_millis(70) && observed < Duration::from_millis(90));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_short_bench_produces_report() {
+        let report = run(BenchOptions {
+            rps: 200,
+            duration: Duration::from_millis(500),
+            upstreams: 2,
+            upstream_p99: Duration::from_millis(5),
+            error_rate: 0.0,
+            post_ratio: 0.5,
+            body_bytes: 64,
+            seed: 9,
+        })
+        .await
+        .unwrap();
+
+        assert!(report.sent >= 90);
+        assert_eq!(report.latencies_us.len(), report.sent);
+        assert_eq!(report.statuses.get(&200).copied().unwrap_or(0), report.sent);
+        assert_eq!(report.endpoint_decisions.values().sum::<usize>(), report.sent);
+        assert!(report.to_string().contains("achieved rps"));
+    }
+}
@@ -0,0 +1,272 @@
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use hyper::{
+    body::{Body, Bytes},
+    header::{self, HeaderMap, HeaderValue},
+    Method, Response, StatusCode,
+};
+use std::fmt;
+
+// Decides how a response body is framed on the way to the client. Anything
+// that changes a body after it left the upstream (decoding, middleware
+// rewrites) records that here, and `finish` runs once on the final response,
+// so Content-Length always describes the bytes actually sent.
+#[derive(Debug, Clone)]
+pub struct ResponseBodyPipeline {
+    head: bool,
+    // Length the upstream declared, kept while the body is still exactly what
+    // it sent. Only HEAD and 304 responses rely on it, since they carry no body
+    // to measure.
+    declared: Option<u64>,
+    mutations: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramingError {
+    InvalidLength,
+    ConflictingLength,
+    // RFC 9112 section 6.3: a message with both is a smuggling vector.
+    LengthWithTransferEncoding,
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FramingError::InvalidLength => "invalid content-length",
+            FramingError::ConflictingLength => "conflicting content-length values",
+            FramingError::LengthWithTransferEncoding => "both content-length and transfer-encoding",
+        })
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+// How `finish` framed the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Length(u64),
+    // No length header; chunked on HTTP/1.1, end of stream on HTTP/2.
+    Streamed,
+    NoBody,
+    // No body sent, but the response had one, which was dropped.
+    Suppressed,
+}
+
+// Names the reason a response must not carry a body, if it must not: the
+// request was HEAD, or the status is 1xx, 204 or 304.
+pub fn bodiless_kind(head: bool, status: StatusCode) -> Option<&'static str> {
+    if status.is_informational() {
+        Some("1xx")
+    } else if status == StatusCode::NO_CONTENT {
+        Some("204")
+    } else if status == StatusCode::NOT_MODIFIED {
+        Some("304")
+    } else if head {
+        Some("head")
+    } else {
+        None
+    }
+}
+
+impl ResponseBodyPipeline {
+    pub fn from_upstream(method: &Method, headers: &HeaderMap) -> Result<Self, FramingError> {
+        let mut declared = None;
+        for value in headers.get_all(header::CONTENT_LENGTH) {
+            // A single header may also hold a comma-separated list of copies.
+            for part in value.to_str().map_err(|_| FramingError::InvalidLength)?.split(',') {
+                let part = part.trim();
+                if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(FramingError::InvalidLength);
+                }
+                let length: u64 = part.parse().map_err(|_| FramingError::InvalidLength)?;
+                if declared.is_some_and(|seen| seen != length) {
+                    return Err(FramingError::ConflictingLength);
+                }
+                declared = Some(length);
+            }
+        }
+        if declared.is_some() && headers.contains_key(header::TRANSFER_ENCODING) {
+            return Err(FramingError::LengthWithTransferEncoding);
+        }
+
+        Ok(Self {
+            head: method == Method::HEAD,
+            declared,
+            mutations: Vec::new(),
+        })
+    }
+
+    // For responses the proxy produced itself.
+    pub fn local(method: &Method) -> Self {
+        Self {
+            head: method == Method::HEAD,
+            declared: None,
+            mutations: Vec::new(),
+        }
+    }
+
+    pub fn mutate(&mut self, feature: &'static str) {
+        self.declared = None;
+        if !self.mutations.contains(&feature) {
+            self.mutations.push(feature);
+        }
+    }
+
+    pub fn mutations(&self) -> &[&'static str] {
+        &self.mutations
+    }
+
+    // Marks the body of a response in flight as changed by `feature`. Used by
+    // middleware, which only sees the response.
+    pub fn mark_mutated<B>(response: &mut Response<B>, feature: &'static str) {
+        if let Some(pipeline) = response.extensions_mut().get_mut::<Self>() {
+            pipeline.mutate(feature);
+        }
+    }
+
+    pub fn finish(&self, response: &mut Response<BoxBody<Bytes, hyper::Error>>) -> Framing {
+        let status = response.status();
+        let exact = response.body().size_hint().exact();
+
+        let headers = response.headers_mut();
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.remove(header::CONTENT_LENGTH);
+
+        if bodiless_kind(self.head, status).is_none() {
+            return match exact {
+                Some(length) => {
+                    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+                    Framing::Length(length)
+                }
+                None => Framing::Streamed,
+            };
+        }
+
+        // No body follows, but for HEAD and 304 the length of the one that
+        // would have is still meaningful to the client and only the upstream
+        // knows it. 1xx and 204 responses cannot carry one.
+        if self.head || status == StatusCode::NOT_MODIFIED {
+            if let Some(length) = self.declared {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+            }
+        }
+        // HTTP/2 would send whatever is left here as DATA frames.
+        if exact == Some(0) {
+            return Framing::NoBody;
+        }
+        *response.body_mut() = Empty::new().map_err(|never| match never {}).boxed();
+        Framing::Suppressed
+    }
+
+    // Runs the pipeline attached to the response, or a local one.
+    pub fn finish_response(method: &Method, response: &mut Response<BoxBody<Bytes, hyper::Error>>) -> Framing {
+        let pipeline = response
+            .extensions_mut()
+            .remove::<Self>()
+            .unwrap_or_else(|| Self::local(method));
+        pipeline.finish(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Frame;
+
+    fn upstream(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn full(body: &'static [u8]) -> BoxBody<Bytes, hyper::Error> {
+        Full::new(Bytes::from_static(body)).map_err(|never| match never {}).boxed()
+    }
+
+    fn streamed() -> BoxBody<Bytes, hyper::Error> {
+        let frames = futures::stream::iter(vec![Ok::<_, hyper::Error>(Frame::data(Bytes::from_static(b"abc")))]);
+        StreamBody::new(frames).boxed()
+    }
+
+    #[test]
+    fn test_rejects_illegal_upstream_framing() {
+        let parse = |pairs: &[(&str, &str)]| ResponseBodyPipeline::from_upstream(&Method::GET, &upstream(pairs)).err();
+
+        assert_eq!(
+            parse(&[("content-length", "3"), ("transfer-encoding", "chunked")]),
+            Some(FramingError::LengthWithTransferEncoding)
+        );
+        assert_eq!(parse(&[("content-length", "3, 4")]), Some(FramingError::ConflictingLength));
+        assert_eq!(parse(&[("content-length", "+3")]), Some(FramingError::InvalidLength));
+        assert_eq!(parse(&[("content-length", "3, 3")]), None);
+        assert_eq!(parse(&[("transfer-encoding", "chunked")]), None);
+    }
+
+    #[test]
+    fn test_length_follows_the_final_body() {
+        let pipeline = ResponseBodyPipeline::from_upstream(&Method::GET, &upstream(&[("content-length", "10")])).unwrap();
+
+        let mut response = Response::new(full(b"abc"));
+        response.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(10));
+        assert_eq!(pipeline.finish(&mut response), Framing::Length(3));
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "3");
+
+        let mut response = Response::new(streamed());
+        response.headers_mut().insert(header::TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        assert_eq!(pipeline.finish(&mut response), Framing::Streamed);
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert!(!response.headers().contains_key(header::TRANSFER_ENCODING));
+    }
+
+    #[test]
+    fn test_head_keeps_declared_length_until_mutated() {
+        let headers = upstream(&[("content-length", "1234")]);
+        let mut pipeline = ResponseBodyPipeline::from_upstream(&Method::HEAD, &headers).unwrap();
+
+        let mut response = Response::new(full(b""));
+        assert_eq!(pipeline.finish(&mut response), Framing::NoBody);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "1234");
+
+        pipeline.mutate("decompress");
+        let mut response = Response::new(full(b""));
+        pipeline.finish(&mut response);
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(pipeline.mutations(), ["decompress"]);
+    }
+
+    #[tokio::test]
+    async fn test_bodiless_responses_are_emptied() {
+        let pipeline = ResponseBodyPipeline::local(&Method::GET);
+        let mut response = Response::new(full(b"x"));
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        assert_eq!(pipeline.finish(&mut response), Framing::Suppressed);
+        assert!(response.body().is_end_stream());
+
+        let mut response = Response::new(full(b""));
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        assert_eq!(pipeline.finish(&mut response), Framing::NoBody);
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+
+        // A HEAD or 304 body is dropped, the upstream's declared length kept.
+        let headers = upstream(&[("content-length", "1234")]);
+        for (method, status) in [(Method::HEAD, StatusCode::OK), (Method::GET, StatusCode::NOT_MODIFIED)] {
+            let pipeline = ResponseBodyPipeline::from_upstream(&method, &headers).unwrap();
+            let mut response = Response::new(streamed());
+            *response.status_mut() = status;
+            assert_eq!(pipeline.finish(&mut response), Framing::Suppressed, "{} {}", method, status);
+            assert_eq!(response.headers()[header::CONTENT_LENGTH], "1234");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_bodiless_kind() {
+        assert_eq!(bodiless_kind(true, StatusCode::OK), Some("head"));
+        assert_eq!(bodiless_kind(true, StatusCode::NO_CONTENT), Some("204"));
+        assert_eq!(bodiless_kind(false, StatusCode::NOT_MODIFIED), Some("304"));
+        assert_eq!(bodiless_kind(false, StatusCode::CONTINUE), Some("1xx"));
+        assert_eq!(bodiless_kind(false, StatusCode::OK), None);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::metrics::MetricsCollector;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::Serialize;
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+#[derive(Debug)]
+pub enum BufferError<E> {
+    Exhausted,
+    // Longer than the caller's own limit, whatever the budget has left.
+    TooLarge,
+    Body(E),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BufferBudgetSnapshot {
+    pub limit_bytes: usize,
+    pub used_bytes: usize,
+    pub high_water_bytes: usize,
+}
+
+// Global accounting for every body the proxy holds fully in memory.
+pub struct BufferBudget {
+    limit: usize,
+    used: AtomicUsize,
+    high_water: AtomicUsize,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl BufferBudget {
+    pub fn new(limit: usize, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    pub fn permit(self: &Arc<Self>) -> BufferPermit {
+        BufferPermit {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let reserved = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(bytes).filter(|total| *total <= self.limit)
+        });
+
+        match reserved {
+            Ok(previous) => {
+                let used = previous + bytes;
+                let high_water = self.high_water.fetch_max(used, Ordering::AcqRel).max(used);
+                self.metrics.set_buffered_bytes(used, high_water);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        let used = self.used.fetch_sub(bytes, Ordering::AcqRel) - bytes;
+        self.metrics.set_buffered_bytes(used, self.high_water.load(Ordering::Acquire));
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Acquire)
+    }
+
+    pub fn snapshot(&self) -> BufferBudgetSnapshot {
+        BufferBudgetSnapshot {
+            limit_bytes: self.limit,
+            used_bytes: self.used(),
+            high_water_bytes: self.high_water(),
+        }
+    }
+}
+
+pub struct BufferPermit {
+    budget: Arc<BufferBudget>,
+    bytes: usize,
+}
+
+impl BufferPermit {
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        if self.budget.try_reserve(bytes) {
+            self.bytes += bytes;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for BufferPermit {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.budget.release(self.bytes);
+        }
+    }
+}
+
+// Stops reading as soon as the body passes `max_bytes`; the rest is never
+// pulled off the connection.
+pub async fn collect_body<B>(mut body: B, permit: &mut BufferPermit, max_bytes: usize) -> Result<Bytes, BufferError<B::Error>>
+where
+    B: Body + Unpin,
+{
+    let mut buf = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(BufferError::Body)?;
+        if let Ok(data) = frame.into_data() {
+            if buf.len().saturating_add(data.remaining()) > max_bytes {
+                return Err(BufferError::TooLarge);
+            }
+            if !permit.grow(data.remaining()) {
+                return Err(BufferError::Exhausted);
+            }
+            buf.put(data);
+        }
+    }
+    Ok(buf.freeze())
+}
+
+// Reads until the body ends or `limit` bytes are in, leaving the rest on
+// the connection. Says whether the body ended; the frame that crosses
+// `limit` is kept whole.
+pub async fn read_ahead<B>(body: &mut B, permit: &mut BufferPermit, limit: usize) -> Result<(Bytes, bool), BufferError<B::Error>>
+where
+    B: Body + Unpin,
+{
+    match read_frames(body, permit, limit).await.map_err(BufferError::Body)? {
+        (read, Some(ended)) => Ok((read, ended)),
+        (_, None) => Err(BufferError::Exhausted),
+    }
+}
+
+// As `read_ahead`, but a frame the budget has no room for stops the reading
+// instead of failing it. That frame comes back with the rest, outside the
+// budget, and the body counts as unfinished.
+pub async fn read_ahead_or_stop<B>(body: &mut B, permit: &mut BufferPermit, limit: usize) -> Result<(Bytes, bool), B::Error>
+where
+    B: Body + Unpin,
+{
+    let (read, ended) = read_frames(body, permit, limit).await?;
+    Ok((read, ended.unwrap_or(false)))
+}
+
+// None in place of whether the body ended when the budget ran out.
+async fn read_frames<B>(body: &mut B, permit: &mut BufferPermit, limit: usize) -> Result<(Bytes, Option<bool>), B::Error>
+where
+    B: Body + Unpin,
+{
+    let mut buf = BytesMut::new();
+    while buf.len() < limit {
+        let Some(frame) = body.frame().await else {
+            return Ok((buf.freeze(), Some(true)));
+        };
+        if let Ok(data) = frame?.into_data() {
+            let room = permit.grow(data.remaining());
+            buf.put(data);
+            if !room {
+                return Ok((buf.freeze(), None));
+            }
+        }
+    }
+    Ok((buf.freeze(), Some(body.is_end_stream())))
+}
+
+// As `collect_body`, with no limit but the budget.
+pub async fn collect_response<B>(body: B, permit: &mut BufferPermit) -> Result<Bytes, BufferError<B::Error>>
+where
+    B: Body + Unpin,
+{
+    collect_body(body, permit, usize::MAX).await
+}
+
+// A fully buffered body that gives its bytes back to the budget once hyper drops it.
+pub struct BudgetedBody {
+    data: Option<Bytes>,
+    _permit: BufferPermit,
+}
+
+impl BudgetedBody {
+    pub fn new(data: Bytes, permit: BufferPermit) -> Self {
+        Self {
+            data: (!data.is_empty()).then_some(data),
+            _permit: permit,
+        }
+    }
+}
+
+impl Body for BudgetedBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(self.data.take().map(|data| Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.data.as_ref().map_or(0, |data| data.len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use http_body_util::{Full, StreamBody};
+
+    fn budget(limit: usize) -> Arc<BufferBudget> {
+        Arc::new(BufferBudget::new(limit, Arc::new(MetricsCollector::new())))
+    }
+
+    #[test]
+    fn test_permits_release_on_drop() {
+        let budget = budget(100);
+
+        let mut first = budget.permit();
+        assert!(first.grow(60));
+        let mut second = budget.permit();
+        assert!(!second.grow(60));
+        assert!(second.grow(40));
+        assert_eq!(budget.used(), 100);
+
+        drop(first);
+        assert_eq!(budget.used(), 40);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.high_water(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_collect_body_stops_at_budget() {
+        let budget = budget(10);
+
+        let mut permit = budget.permit();
+        let body = collect_body(Full::new(Bytes::from_static(b"0123456789")), &mut permit, usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 10);
+
+        let mut other = budget.permit();
+        let result = collect_body(Full::new(Bytes::from_static(b"x")), &mut other, usize::MAX).await;
+        assert!(matches!(result, Err(BufferError::Exhausted)));
+    }
+
+    #[tokio::test]
+    async fn test_collect_body_stops_at_max_bytes_without_reading_on() {
+        let budget = budget(1024);
+        let chunks = futures::stream::iter([b"012345".as_slice(), b"6789".as_slice()])
+            .map(|chunk| Ok::<_, Infallible>(Frame::data(Bytes::from_static(chunk))))
+            // Reached only if the collector keeps reading past the limit.
+            .chain(futures::stream::pending());
+
+        let mut permit = budget.permit();
+        let result = collect_body(StreamBody::new(chunks), &mut permit, 8).await;
+        assert!(matches!(result, Err(BufferError::TooLarge)));
+        assert_eq!(permit.bytes(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_leaves_the_rest_unread() {
+        let budget = budget(1024);
+        let chunks = futures::stream::iter([b"012345".as_slice(), b"6789".as_slice(), b"ab".as_slice()])
+            .map(|chunk| Ok::<_, Infallible>(Frame::data(Bytes::from_static(chunk))));
+        let mut body = StreamBody::new(chunks);
+
+        let mut permit = budget.permit();
+        let (ahead, ended) = read_ahead(&mut body, &mut permit, 8).await.unwrap();
+        assert_eq!((ahead.as_ref(), ended), (b"0123456789".as_slice(), false));
+        assert_eq!(BodyExt::collect(body).await.unwrap().to_bytes(), "ab");
+
+        let (ahead, ended) = read_ahead(&mut Full::new(Bytes::from_static(b"short")), &mut permit, 8).await.unwrap();
+        assert_eq!((ahead.as_ref(), ended), (b"short".as_slice(), true));
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_or_stop_keeps_what_the_budget_refused() {
+        let budget = budget(8);
+        let chunks = futures::stream::iter([b"012345".as_slice(), b"6789".as_slice(), b"ab".as_slice()])
+            .map(|chunk| Ok::<_, Infallible>(Frame::data(Bytes::from_static(chunk))));
+        let mut body = StreamBody::new(chunks);
+
+        let mut permit = budget.permit();
+        let (ahead, ended) = read_ahead_or_stop(&mut body, &mut permit, 16).await.unwrap();
+        assert_eq!((ahead.as_ref(), ended), (b"0123456789".as_slice(), false));
+        assert_eq!(permit.bytes(), 6);
+        assert_eq!(BodyExt::collect(body).await.unwrap().to_bytes(), "ab");
+    }
+}
@@ -0,0 +1,477 @@
+use crate::{
+    admin_auth::constant_time_eq,
+    archive::{self, ArchivedBody},
+    clock::Clock,
+};
+use anyhow::{Context, Result};
+use hyper::{
+    header::{self, HeaderName},
+    HeaderMap, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+// On-demand capture of live exchanges for debugging, started and read through
+// /admin/capture. Everything about it is capped: how many captures run at
+// once, how many records each keeps, how long it records and how long its
+// records are kept afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    // Off unless set; /admin/capture answers 404 while it is.
+    pub enabled: bool,
+    // Bearer tokens allowed to use /admin/capture, keyed by the operator name
+    // written to the audit log. With `admin_auth` tokens configured, each must
+    // also be an admin token limited to no services.
+    pub admin_tokens: HashMap<String, String>,
+    // Captures held at once, whether still recording or only retained.
+    pub max_captures: usize,
+    // Per capture.
+    pub max_records: usize,
+    pub max_duration_s: u64,
+    // Bytes kept of each body when a capture includes them.
+    pub max_body_bytes: usize,
+    // How long a capture stays readable after it stops recording.
+    pub retention_s: u64,
+    // Header values replaced with "[redacted]"; case-insensitive.
+    pub redact_headers: Vec<String>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            admin_tokens: HashMap::new(),
+            max_captures: 4,
+            max_records: 500,
+            max_duration_s: 900,
+            max_body_bytes: 4096,
+            retention_s: 600,
+            redact_headers: ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+// Body of POST /admin/capture. Exactly one of `route` and `service` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureRequest {
+    #[serde(default)]
+    pub route: Option<String>,
+    #[serde(default)]
+    pub service: Option<String>,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    pub max_records: usize,
+    pub duration_s: u64,
+    #[serde(default)]
+    pub include_bodies: bool,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedExchange {
+    pub timestamp_ms: u64,
+    pub route: String,
+    pub service: String,
+    pub method: String,
+    pub path: String,
+    pub endpoint: String,
+    pub status: u16,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Option<ArchivedBody>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Option<ArchivedBody>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureView {
+    pub id: String,
+    pub operator: String,
+    pub request: CaptureRequest,
+    // "recording" or "complete".
+    pub state: &'static str,
+    pub remaining_s: u64,
+    pub records: Vec<CapturedExchange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureError {
+    Invalid(String),
+    TooMany,
+}
+
+impl CaptureError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            CaptureError::Invalid(_) => StatusCode::BAD_REQUEST,
+            CaptureError::TooMany => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::Invalid(reason) => write!(f, "{}", reason),
+            CaptureError::TooMany => write!(f, "too many captures held; delete one or wait for it to expire"),
+        }
+    }
+}
+
+struct Capture {
+    operator: String,
+    request: CaptureRequest,
+    recording_until: Instant,
+    // Set once the window has closed and `recording` no longer counts it.
+    stopped: bool,
+    records: Vec<CapturedExchange>,
+}
+
+// Which captures want the current request, decided before it is forwarded.
+pub struct Sampled {
+    ids: Vec<String>,
+    pub body_cap: Option<usize>,
+}
+
+pub struct CaptureStore {
+    config: CaptureConfig,
+    redact: Vec<HeaderName>,
+    clock: Arc<dyn Clock>,
+    captures: Mutex<HashMap<String, Capture>>,
+    // Captures still recording, so requests skip the lock when there are none.
+    recording: AtomicUsize,
+}
+
+impl CaptureStore {
+    pub fn new(config: &CaptureConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        let redact = config
+            .redact_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes())
+                    .with_context(|| format!("invalid header name {:?} in capture redact_headers", name))
+            })
+            .collect::<Result<_>>()?;
+        anyhow::ensure!(
+            config.admin_tokens.values().all(|token| !token.is_empty()),
+            "capture admin tokens must not be empty"
+        );
+        Ok(Self {
+            config: config.clone(),
+            redact,
+            clock,
+            captures: Mutex::new(HashMap::new()),
+            recording: AtomicUsize::new(0),
+        })
+    }
+
+    // The operator named by the request's bearer token, if any.
+    pub fn authorize(&self, headers: &HeaderMap) -> Option<&str> {
+        let token = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        self.config
+            .admin_tokens
+            .iter()
+            .find(|(_, expected)| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+            .map(|(operator, _)| operator.as_str())
+    }
+
+    pub fn start(&self, operator: &str, request: CaptureRequest) -> Result<String, CaptureError> {
+        let invalid = |reason: String| Err(CaptureError::Invalid(reason));
+        if request.route.is_some() == request.service.is_some() {
+            return invalid("exactly one of route and service must be set".to_string());
+        }
+        if !(0.0..=1.0).contains(&request.sample_rate) {
+            return invalid("sample_rate must be between 0 and 1".to_string());
+        }
+        if request.max_records == 0 || request.max_records > self.config.max_records {
+            return invalid(format!("max_records must be between 1 and {}", self.config.max_records));
+        }
+        if request.duration_s == 0 || request.duration_s > self.config.max_duration_s {
+            return invalid(format!("duration_s must be between 1 and {}", self.config.max_duration_s));
+        }
+
+        let now = self.clock.now();
+        let mut captures = self.expire(now);
+        if captures.len() >= self.config.max_captures {
+            return Err(CaptureError::TooMany);
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        info!(
+            target: "audit",
+            action = "capture.start",
+            operator,
+            capture_id = %id,
+            route = request.route.as_deref().unwrap_or(""),
+            service = request.service.as_deref().unwrap_or(""),
+            sample_rate = request.sample_rate,
+            max_records = request.max_records,
+            duration_s = request.duration_s,
+            include_bodies = request.include_bodies,
+            "capture started"
+        );
+        captures.insert(
+            id.clone(),
+            Capture {
+                operator: operator.to_string(),
+                recording_until: now + Duration::from_secs(request.duration_s),
+                stopped: false,
+                request,
+                records: Vec::new(),
+            },
+        );
+        self.recording.fetch_add(1, Ordering::SeqCst);
+        Ok(id)
+    }
+
+    pub fn get(&self, operator: &str, id: &str) -> Option<CaptureView> {
+        let now = self.clock.now();
+        let captures = self.expire(now);
+        let capture = captures.get(id);
+        info!(target: "audit", action = "capture.read", operator, capture_id = %id, found = capture.is_some(), "capture read");
+        let capture = capture?;
+        let retained_until = capture.recording_until + Duration::from_secs(self.config.retention_s);
+        Some(CaptureView {
+            id: id.to_string(),
+            operator: capture.operator.clone(),
+            request: capture.request.clone(),
+            state: if now < capture.recording_until { "recording" } else { "complete" },
+            remaining_s: retained_until.saturating_duration_since(now).as_secs(),
+            records: capture.records.clone(),
+        })
+    }
+
+    pub fn delete(&self, operator: &str, id: &str) -> bool {
+        let now = self.clock.now();
+        let mut captures = self.expire(now);
+        let removed = captures.remove(id);
+        if removed.as_ref().is_some_and(|capture| !capture.stopped) {
+            self.recording.fetch_sub(1, Ordering::SeqCst);
+        }
+        info!(target: "audit", action = "capture.delete", operator, capture_id = %id, found = removed.is_some(), "capture deleted");
+        removed.is_some()
+    }
+
+    pub fn sample(&self, route: &str, service: &str) -> Option<Sampled> {
+        if self.recording.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let now = self.clock.now();
+        let captures = self.expire(now);
+        let mut sampled = Sampled {
+            ids: Vec::new(),
+            body_cap: None,
+        };
+        for (id, capture) in captures.iter() {
+            let request = &capture.request;
+            let matches = request.route.as_deref() == Some(route) || request.service.as_deref() == Some(service);
+            if !matches
+                || now >= capture.recording_until
+                || capture.records.len() >= request.max_records
+                || rand::random::<f64>() >= request.sample_rate
+            {
+                continue;
+            }
+            sampled.ids.push(id.clone());
+            if request.include_bodies {
+                sampled.body_cap = Some(self.config.max_body_bytes);
+            }
+        }
+        (!sampled.ids.is_empty()).then_some(sampled)
+    }
+
+    pub fn headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        archive::redacted_headers(headers, &self.redact)
+    }
+
+    // Stored once per capture that sampled the request, without bodies for
+    // captures that did not ask for them, and never past a capture's cap.
+    pub fn record(&self, sampled: Sampled, exchange: CapturedExchange) {
+        let mut captures = self.captures.lock().unwrap();
+        for id in sampled.ids {
+            let Some(capture) = captures.get_mut(&id) else {
+                continue;
+            };
+            if capture.records.len() >= capture.request.max_records {
+                continue;
+            }
+            let mut exchange = exchange.clone();
+            if !capture.request.include_bodies {
+                exchange.request_body = None;
+                exchange.response_body = None;
+            }
+            capture.records.push(exchange);
+        }
+    }
+
+    // Drops captures past their retention, logging each, and hands back the
+    // locked map.
+    fn expire(&self, now: Instant) -> std::sync::MutexGuard<'_, HashMap<String, Capture>> {
+        let mut captures = self.captures.lock().unwrap();
+        let retention = Duration::from_secs(self.config.retention_s);
+        let mut stopped = 0;
+        captures.retain(|id, capture| {
+            if now >= capture.recording_until && !capture.stopped {
+                capture.stopped = true;
+                stopped += 1;
+            }
+            if now < capture.recording_until + retention {
+                return true;
+            }
+            info!(
+                target: "audit",
+                action = "capture.expire",
+                operator = %capture.operator,
+                capture_id = %id,
+                records = capture.records.len(),
+                "capture expired"
+            );
+            false
+        });
+        if stopped > 0 {
+            self.recording.fetch_sub(stopped, Ordering::SeqCst);
+        }
+        captures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn store(clock: Arc<MockClock>) -> CaptureStore {
+        let mut config = CaptureConfig {
+            enabled: true,
+            max_captures: 2,
+            max_records: 10,
+            retention_s: 60,
+            ..CaptureConfig::default()
+        };
+        config.admin_tokens.insert("alice".to_string(), "s3cret".to_string());
+        CaptureStore::new(&config, clock).unwrap()
+    }
+
+    fn request(max_records: usize, duration_s: u64) -> CaptureRequest {
+        serde_json::from_value(serde_json::json!({
+            "service": "service-a",
+            "max_records": max_records,
+            "duration_s": duration_s,
+        }))
+        .unwrap()
+    }
+
+    fn exchange(headers: &HeaderMap, store: &CaptureStore) -> CapturedExchange {
+        CapturedExchange {
+            timestamp_ms: 0,
+            route: "/api/a".to_string(),
+            service: "service-a".to_string(),
+            method: "GET".to_string(),
+            path: "/api/a/items".to_string(),
+            endpoint: "http://a".to_string(),
+            status: 200,
+            request_headers: store.headers(headers),
+            request_body: Some(ArchivedBody::capture(b"body", 4096)),
+            response_headers: Vec::new(),
+            response_body: None,
+        }
+    }
+
+    fn capture_one(store: &CaptureStore) -> bool {
+        match store.sample("/api/a", "service-a") {
+            Some(sampled) => {
+                store.record(sampled, exchange(&HeaderMap::new(), store));
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn test_only_configured_tokens_authorize() {
+        let store = store(Arc::new(MockClock::new()));
+        let mut headers = HeaderMap::new();
+        assert_eq!(store.authorize(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert_eq!(store.authorize(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        assert_eq!(store.authorize(&headers), Some("alice"));
+    }
+
+    #[test]
+    fn test_request_limits_enforced() {
+        let store = store(Arc::new(MockClock::new()));
+        assert!(matches!(store.start("alice", request(11, 60)), Err(CaptureError::Invalid(_))));
+        assert!(matches!(store.start("alice", request(1, 901)), Err(CaptureError::Invalid(_))));
+        let mut both = request(1, 60);
+        both.route = Some("/api/a".to_string());
+        assert!(matches!(store.start("alice", both), Err(CaptureError::Invalid(_))));
+
+        store.start("alice", request(1, 60)).unwrap();
+        store.start("alice", request(1, 60)).unwrap();
+        assert_eq!(store.start("alice", request(1, 60)), Err(CaptureError::TooMany));
+    }
+
+    #[test]
+    fn test_records_capped_and_bodies_only_when_asked() {
+        let store = store(Arc::new(MockClock::new()));
+        let id = store.start("alice", request(3, 60)).unwrap();
+        let captured = (0..5).filter(|_| capture_one(&store)).count();
+        assert_eq!(captured, 3);
+
+        let view = store.get("alice", &id).unwrap();
+        assert_eq!(view.records.len(), 3);
+        assert!(view.records.iter().all(|record| record.request_body.is_none()));
+    }
+
+    #[test]
+    fn test_sensitive_headers_redacted() {
+        let store = store(Arc::new(MockClock::new()));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer client-token".parse().unwrap());
+        headers.insert(header::COOKIE, "session=abc".parse().unwrap());
+        headers.insert("x-request-id", "req-1".parse().unwrap());
+        let captured: HashMap<String, String> = store.headers(&headers).into_iter().collect();
+        assert_eq!(captured["authorization"], "[redacted]");
+        assert_eq!(captured["cookie"], "[redacted]");
+        assert_eq!(captured["x-request-id"], "req-1");
+    }
+
+    #[test]
+    fn test_stops_recording_then_expires() {
+        let clock = Arc::new(MockClock::new());
+        let store = store(clock.clone());
+        let id = store.start("alice", request(10, 30)).unwrap();
+        assert!(capture_one(&store));
+        assert_eq!(store.get("alice", &id).unwrap().state, "recording");
+
+        clock.advance(Duration::from_secs(30));
+        assert!(!capture_one(&store));
+        let view = store.get("alice", &id).unwrap();
+        assert_eq!((view.state, view.remaining_s, view.records.len()), ("complete", 60, 1));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(store.get("alice", &id).is_none());
+        // The expired capture no longer counts against the limit.
+        store.start("alice", request(1, 60)).unwrap();
+        store.start("alice", request(1, 60)).unwrap();
+    }
+}
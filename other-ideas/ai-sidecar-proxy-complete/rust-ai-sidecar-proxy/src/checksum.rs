@@ -0,0 +1,284 @@
+use anyhow::{Context as _, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Buf, Bytes};
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// Values are the base64 of the raw digest, as in x-amz-checksum-* headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    fn default_header(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32 => "x-amz-checksum-crc32",
+            ChecksumAlgorithm::Sha1 => "x-amz-checksum-sha1",
+            ChecksumAlgorithm::Sha256 => "x-amz-checksum-sha256",
+        }
+    }
+
+    pub fn hasher(self) -> ChecksumHasher {
+        match self {
+            ChecksumAlgorithm::Crc32 => ChecksumHasher::Crc32(crc32fast::Hasher::new()),
+            ChecksumAlgorithm::Sha1 => {
+                ChecksumHasher::Digest(Box::new(digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY)))
+            }
+            ChecksumAlgorithm::Sha256 => ChecksumHasher::Digest(Box::new(digest::Context::new(&digest::SHA256))),
+        }
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(bytes);
+        hasher.finish()
+    }
+}
+
+pub enum ChecksumHasher {
+    Crc32(crc32fast::Hasher),
+    Digest(Box<digest::Context>),
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            ChecksumHasher::Crc32(hasher) => hasher.update(bytes),
+            ChecksumHasher::Digest(context) => context.update(bytes),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            ChecksumHasher::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            ChecksumHasher::Digest(context) => context.finish().as_ref().to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchAction {
+    // Replace the response with a 502.
+    #[default]
+    Reject,
+    // Forward it anyway; the mismatch is only logged and counted.
+    Log,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BodyChecksumConfig {
+    // Keyed by route, e.g. "/api/storage".
+    pub routes: HashMap<String, RouteChecksumConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteChecksumConfig {
+    pub algorithm: ChecksumAlgorithm,
+    // Defaults to the x-amz-checksum-* header for the algorithm.
+    #[serde(default)]
+    pub header: Option<String>,
+    // Reject request bodies that disagree with the client's header (400).
+    #[serde(default)]
+    pub verify_request: bool,
+    // Add the header to requests that arrive without one.
+    #[serde(default)]
+    pub generate_request: bool,
+    #[serde(default)]
+    pub verify_response: bool,
+    #[serde(default)]
+    pub on_response_mismatch: MismatchAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Match,
+    Mismatch,
+    // No header, or one that is not valid base64.
+    Absent,
+}
+
+impl Verdict {
+    pub fn label(self) -> &'static str {
+        match self {
+            Verdict::Match => "match",
+            Verdict::Mismatch => "mismatch",
+            Verdict::Absent => "absent",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub header: HeaderName,
+    pub verify_request: bool,
+    pub generate_request: bool,
+    pub verify_response: bool,
+    pub on_response_mismatch: MismatchAction,
+}
+
+impl RouteChecksum {
+    pub fn hashes_request(&self) -> bool {
+        self.verify_request || self.generate_request
+    }
+
+    pub fn check(&self, headers: &HeaderMap, digest: &[u8]) -> Verdict {
+        let claimed = headers
+            .get(&self.header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| STANDARD.decode(value.trim()).ok());
+        match claimed {
+            Some(claimed) if claimed == digest => Verdict::Match,
+            Some(_) => Verdict::Mismatch,
+            None => Verdict::Absent,
+        }
+    }
+
+    pub fn header_value(digest: &[u8]) -> HeaderValue {
+        HeaderValue::from_str(&STANDARD.encode(digest)).expect("base64 is a valid header value")
+    }
+}
+
+// Per-route policies with validated header names, built once at startup.
+#[derive(Debug, Clone, Default)]
+pub struct BodyChecksums {
+    routes: HashMap<String, RouteChecksum>,
+}
+
+impl BodyChecksums {
+    pub fn from_config(config: &BodyChecksumConfig) -> Result<Self> {
+        let mut routes = HashMap::new();
+        for (route, policy) in &config.routes {
+            let header = policy.header.as_deref().unwrap_or(policy.algorithm.default_header());
+            let header = HeaderName::from_bytes(header.as_bytes())
+                .with_context(|| format!("invalid checksum header {:?} for route {}", header, route))?;
+            routes.insert(
+                route.clone(),
+                RouteChecksum {
+                    algorithm: policy.algorithm,
+                    header,
+                    verify_request: policy.verify_request,
+                    generate_request: policy.generate_request,
+                    verify_response: policy.verify_response,
+                    on_response_mismatch: policy.on_response_mismatch,
+                },
+            );
+        }
+        Ok(Self { routes })
+    }
+
+    pub fn for_route(&self, route: &str) -> Option<&RouteChecksum> {
+        self.routes.get(route)
+    }
+}
+
+// Passes a body through while feeding its data frames to a hasher, so the
+// digest is ready as soon as the last frame has been read.
+pub struct TeeHash<'a, B> {
+    inner: B,
+    hasher: Option<&'a mut ChecksumHasher>,
+}
+
+impl<'a, B> TeeHash<'a, B> {
+    pub fn new(inner: B, hasher: Option<&'a mut ChecksumHasher>) -> Self {
+        Self { inner, hasher }
+    }
+}
+
+impl<B> Body for TeeHash<'_, B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.inner).poll_frame(cx);
+        if let (Poll::Ready(Some(Ok(frame))), Some(hasher)) = (&polled, this.hasher.as_mut()) {
+            if let Some(data) = frame.data_ref() {
+                hasher.update(data.chunk());
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, StreamBody};
+
+    #[test]
+    fn test_known_digests() {
+        let encode = |algorithm: ChecksumAlgorithm| STANDARD.encode(algorithm.digest(b"Hello world"));
+        assert_eq!(encode(ChecksumAlgorithm::Crc32), "i9aeUg==");
+        assert_eq!(encode(ChecksumAlgorithm::Sha256), "ZOyIygCyaOW6GjVnihtTFtIS9PNmskdyMlNKiuyjfzw=");
+    }
+
+    #[tokio::test]
+    async fn test_tee_hash_sees_every_frame() {
+        let frames = ["Hello", " ", "world"].map(|chunk| Ok::<_, std::convert::Infallible>(Frame::data(Bytes::from(chunk))));
+        let mut hasher = ChecksumAlgorithm::Sha256.hasher();
+        let body = TeeHash::new(StreamBody::new(futures::stream::iter(frames)), Some(&mut hasher));
+        let collected = body.collect().await.unwrap().to_bytes();
+
+        assert_eq!(collected, "Hello world");
+        assert_eq!(hasher.finish(), ChecksumAlgorithm::Sha256.digest(b"Hello world"));
+    }
+
+    #[test]
+    fn test_check_compares_decoded_values() {
+        let config = BodyChecksumConfig {
+            routes: HashMap::from([(
+                "/api/storage".to_string(),
+                RouteChecksumConfig {
+                    algorithm: ChecksumAlgorithm::Crc32,
+                    header: None,
+                    verify_request: true,
+                    generate_request: false,
+                    verify_response: false,
+                    on_response_mismatch: MismatchAction::Reject,
+                },
+            )]),
+        };
+        let checksums = BodyChecksums::from_config(&config).unwrap();
+        let policy = checksums.for_route("/api/storage").unwrap();
+        let digest = policy.algorithm.digest(b"Hello world");
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(policy.check(&headers, &digest), Verdict::Absent);
+        headers.insert("x-amz-checksum-crc32", " i9aeUg== ".parse().unwrap());
+        assert_eq!(policy.check(&headers, &digest), Verdict::Match);
+        headers.insert("x-amz-checksum-crc32", "AAAAAA==".parse().unwrap());
+        assert_eq!(policy.check(&headers, &digest), Verdict::Mismatch);
+    }
+}
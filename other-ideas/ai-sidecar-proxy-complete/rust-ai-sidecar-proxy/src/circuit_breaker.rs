@@ -1,8 +1,141 @@
-use std::sync::atomic::{AtomicU32, AtomicBool, Ordering};
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tracing::{info, warn};
 
+// Which requests find out whether a half-open breaker can close.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HalfOpenProbeMode {
+    // Any live request may be a probe.
+    #[default]
+    Live,
+    // Only live requests with idempotent methods may be probes.
+    Idempotent,
+    // No live request is a probe; the service's health check is sent instead.
+    Synthetic,
+}
+
+impl HalfOpenProbeMode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            HalfOpenProbeMode::Live => "live",
+            HalfOpenProbeMode::Idempotent => "idempotent",
+            HalfOpenProbeMode::Synthetic => "synthetic",
+        }
+    }
+
+    fn admits_live(&self, method: &Method) -> bool {
+        match self {
+            HalfOpenProbeMode::Live => true,
+            HalfOpenProbeMode::Idempotent => is_idempotent(method),
+            HalfOpenProbeMode::Synthetic => false,
+        }
+    }
+}
+
+// Safe to send twice: the second has the same effect as the first.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerProbeConfig {
+    pub mode: HalfOpenProbeMode,
+    // How long the breaker stays open before it goes half-open.
+    pub open_ms: u64,
+    // Probes allowed in flight at once while half-open.
+    pub max_in_flight: u32,
+    // Probe successes that close the breaker; any probe failure reopens it.
+    pub successes_to_close: u32,
+    // Between synthetic probes.
+    pub synthetic_interval_ms: u64,
+}
+
+impl Default for BreakerProbeConfig {
+    fn default() -> Self {
+        Self {
+            mode: HalfOpenProbeMode::Live,
+            open_ms: 60_000,
+            max_in_flight: 5,
+            successes_to_close: 3,
+            synthetic_interval_ms: 1_000,
+        }
+    }
+}
+
+// Whether a live request may go upstream, decided before it is sent.
+pub enum Admission<'a> {
+    Closed,
+    Probe(ProbePermit<'a>),
+    Open,
+    // Half-open, but not picked as a probe.
+    Refused,
+}
+
+impl Admission<'_> {
+    pub fn state(&self) -> CircuitBreakerState {
+        match self {
+            Admission::Closed => CircuitBreakerState::Closed,
+            Admission::Probe(_) => CircuitBreakerState::HalfOpen,
+            Admission::Open | Admission::Refused => CircuitBreakerState::Open,
+        }
+    }
+}
+
+// A probe slot, given back when dropped whether or not an outcome was
+// recorded. Only to the half-open period it was taken in; a later period
+// starts with every slot free.
+pub struct ProbePermit<'a> {
+    breaker: &'a CircuitBreaker,
+    generation: u32,
+}
+
+impl ProbePermit<'_> {
+    pub async fn succeeded(self) {
+        self.breaker.probes_succeeded.fetch_add(1, Ordering::Relaxed);
+        // The slot goes back when the permit drops.
+        self.breaker.succeeded().await;
+    }
+
+    pub async fn failed(self) {
+        self.breaker.probes_failed.fetch_add(1, Ordering::Relaxed);
+        self.breaker.failed().await;
+    }
+}
+
+impl Drop for ProbePermit<'_> {
+    fn drop(&mut self) {
+        self.breaker.release_slot(self.generation);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeStats {
+    pub mode: HalfOpenProbeMode,
+    pub in_flight: u32,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub refused: u64,
+}
+
+// Sent on every state change; slow subscribers miss the oldest events
+// rather than holding the breaker up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitBreakerEvent {
+    OpenedAfterFailures { failure_count: u32 },
+    HalfOpened,
+    Closed,
+}
+
+const EVENT_CAPACITY: usize = 16;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CircuitBreakerState {
     Closed,
@@ -19,6 +152,15 @@ pub struct CircuitBreaker {
     timeout: Duration,
     half_open_max_calls: u32,
     half_open_success_threshold: u32,
+    probe_mode: HalfOpenProbeMode,
+    // The half-open period in the high half and the probe slots taken in it
+    // in the low half, swapped together so a slot cannot be claimed in one
+    // period and given back in another.
+    probe_slots: AtomicU64,
+    probes_succeeded: AtomicU64,
+    probes_failed: AtomicU64,
+    probes_refused: AtomicU64,
+    events: broadcast::Sender<CircuitBreakerEvent>,
 }
 
 impl CircuitBreaker {
@@ -32,6 +174,12 @@ impl CircuitBreaker {
             timeout: Duration::from_secs(60),
             half_open_max_calls: 5,
             half_open_success_threshold: 3,
+            probe_mode: HalfOpenProbeMode::Live,
+            probe_slots: AtomicU64::new(0),
+            probes_succeeded: AtomicU64::new(0),
+            probes_failed: AtomicU64::new(0),
+            probes_refused: AtomicU64::new(0),
+            events: broadcast::channel(EVENT_CAPACITY).0,
         }
     }
 
@@ -40,24 +188,110 @@ impl CircuitBreaker {
         self
     }
 
-    pub async fn is_open(&self) -> bool {
-        let state = *self.state.read().await;
-        
-        match state {
-            CircuitBreakerState::Open => {
-                if self.should_attempt_reset().await {
-                    self.transition_to_half_open().await;
-                    false
-                } else {
-                    true
+    pub fn with_probe_config(mut self, config: &BreakerProbeConfig) -> Self {
+        self.timeout = Duration::from_millis(config.open_ms);
+        self.half_open_max_calls = config.max_in_flight.max(1);
+        self.half_open_success_threshold = config.successes_to_close.max(1);
+        self.probe_mode = config.mode;
+        self
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CircuitBreakerEvent> {
+        self.events.subscribe()
+    }
+
+    // Nobody listening is not an error.
+    fn emit(&self, event: CircuitBreakerEvent) {
+        let _ = self.events.send(event);
+    }
+
+    pub fn probe_mode(&self) -> HalfOpenProbeMode {
+        self.probe_mode
+    }
+
+    pub async fn admit(&self, method: &Method) -> Admission<'_> {
+        match self.current_state().await {
+            CircuitBreakerState::Closed => Admission::Closed,
+            CircuitBreakerState::Open => Admission::Open,
+            CircuitBreakerState::HalfOpen => {
+                let permit = self.probe_mode.admits_live(method).then(|| self.try_probe()).flatten();
+                match permit {
+                    Some(permit) => Admission::Probe(permit),
+                    None => {
+                        self.probes_refused.fetch_add(1, Ordering::Relaxed);
+                        Admission::Refused
+                    }
                 }
             }
-            CircuitBreakerState::HalfOpen => false,
+        }
+    }
+
+    // Takes a probe slot if one is free; for synthetic probes, which skip
+    // `admit`. The caller checks that the breaker is half-open.
+    pub fn try_probe(&self) -> Option<ProbePermit<'_>> {
+        let slots = self
+            .probe_slots
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slots| {
+                (in_flight(slots) < self.half_open_max_calls).then_some(slots + 1)
+            })
+            .ok()?;
+        Some(ProbePermit {
+            breaker: self,
+            generation: generation(slots),
+        })
+    }
+
+    // A permit from an earlier half-open period has nothing to give back.
+    fn release_slot(&self, claimed_in: u32) {
+        let _ = self.probe_slots.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slots| {
+            (generation(slots) == claimed_in && in_flight(slots) > 0).then(|| slots - 1)
+        });
+    }
+
+    pub fn probe_stats(&self) -> ProbeStats {
+        ProbeStats {
+            mode: self.probe_mode,
+            in_flight: in_flight(self.probe_slots.load(Ordering::Relaxed)),
+            succeeded: self.probes_succeeded.load(Ordering::Relaxed),
+            failed: self.probes_failed.load(Ordering::Relaxed),
+            refused: self.probes_refused.load(Ordering::Relaxed),
+        }
+    }
+
+    // Half-open counts as open once all `half_open_max_calls` probe slots
+    // are taken. Only `admit` and `try_probe` take one.
+    pub async fn is_open(&self) -> bool {
+        match self.current_state().await {
+            CircuitBreakerState::Open => true,
+            CircuitBreakerState::HalfOpen => {
+                in_flight(self.probe_slots.load(Ordering::Relaxed)) >= self.half_open_max_calls
+            }
             CircuitBreakerState::Closed => false,
         }
     }
 
+    // Applies a due Open -> HalfOpen transition before reporting the state.
+    pub async fn current_state(&self) -> CircuitBreakerState {
+        let state = *self.state.read().await;
+        if state == CircuitBreakerState::Open && self.should_attempt_reset().await {
+            self.transition_to_half_open().await;
+            return *self.state.read().await;
+        }
+        state
+    }
+
+    // For calls that took no probe slot: those admitted while closed, even
+    // if the breaker has gone half-open since. Probes report through their
+    // permit.
     pub async fn record_success(&self) {
+        self.succeeded().await;
+    }
+
+    pub async fn record_failure(&self) {
+        self.failed().await;
+    }
+
+    async fn succeeded(&self) {
         let current_state = *self.state.read().await;
         
         match current_state {
@@ -76,7 +310,7 @@ impl CircuitBreaker {
         }
     }
 
-    pub async fn record_failure(&self) {
+    async fn failed(&self) {
         let current_state = *self.state.read().await;
         
         match current_state {
@@ -89,6 +323,9 @@ impl CircuitBreaker {
                 }
             }
             CircuitBreakerState::HalfOpen => {
+                // Restarts the open period rather than going straight back
+                // to half-open.
+                *self.last_failure_time.write().await = Some(Instant::now());
                 self.transition_to_open().await;
             }
             CircuitBreakerState::Open => {
@@ -110,6 +347,9 @@ impl CircuitBreaker {
         if *state != CircuitBreakerState::Open {
             *state = CircuitBreakerState::Open;
             warn!("Circuit breaker transitioned to OPEN state");
+            self.emit(CircuitBreakerEvent::OpenedAfterFailures {
+                failure_count: self.failure_count.load(Ordering::Relaxed),
+            });
         }
     }
 
@@ -118,16 +358,22 @@ impl CircuitBreaker {
         if *state == CircuitBreakerState::Open {
             *state = CircuitBreakerState::HalfOpen;
             self.success_count.store(0, Ordering::Relaxed);
+            let next = u64::from(generation(self.probe_slots.load(Ordering::Relaxed)).wrapping_add(1));
+            self.probe_slots.store(next << 32, Ordering::Relaxed);
             info!("Circuit breaker transitioned to HALF-OPEN state");
+            self.emit(CircuitBreakerEvent::HalfOpened);
         }
     }
 
     async fn transition_to_closed(&self) {
         let mut state = self.state.write().await;
-        *state = CircuitBreakerState::Closed;
+        let was = std::mem::replace(&mut *state, CircuitBreakerState::Closed);
         self.failure_count.store(0, Ordering::Relaxed);
         self.success_count.store(0, Ordering::Relaxed);
-        info!("Circuit breaker transitioned to CLOSED state");
+        if was != CircuitBreakerState::Closed {
+            info!("Circuit breaker transitioned to CLOSED state");
+            self.emit(CircuitBreakerEvent::Closed);
+        }
     }
 
     pub async fn get_state(&self) -> CircuitBreakerState {
@@ -141,4 +387,167 @@ impl CircuitBreaker {
     pub fn get_success_count(&self) -> u32 {
         self.success_count.load(Ordering::Relaxed)
     }
+
+    pub fn get_failure_threshold(&self) -> u32 {
+        self.failure_threshold
+    }
+}
+
+fn generation(slots: u64) -> u32 {
+    (slots >> 32) as u32
+}
+
+fn in_flight(slots: u64) -> u32 {
+    slots as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn half_open(mode: HalfOpenProbeMode, max_in_flight: u32) -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(1).with_probe_config(&BreakerProbeConfig {
+            mode,
+            open_ms: 0,
+            max_in_flight,
+            successes_to_close: 1,
+            ..BreakerProbeConfig::default()
+        });
+        breaker.record_failure().await;
+        assert_eq!(breaker.current_state().await, CircuitBreakerState::HalfOpen);
+        breaker
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_mode_probes_only_safe_methods() {
+        let breaker = half_open(HalfOpenProbeMode::Idempotent, 5).await;
+
+        assert!(matches!(breaker.admit(&Method::POST).await, Admission::Refused));
+        assert!(matches!(breaker.admit(&Method::PATCH).await, Admission::Refused));
+        match breaker.admit(&Method::GET).await {
+            Admission::Probe(permit) => permit.succeeded().await,
+            _ => panic!("GET was not admitted as a probe"),
+        }
+
+        assert_eq!(breaker.get_state().await, CircuitBreakerState::Closed);
+        let stats = breaker.probe_stats();
+        assert_eq!((stats.in_flight, stats.succeeded, stats.refused), (0, 1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_probe_slots_are_bounded_and_returned() {
+        let breaker = half_open(HalfOpenProbeMode::Live, 1).await;
+
+        let first = breaker.admit(&Method::POST).await;
+        assert!(matches!(first, Admission::Probe(_)));
+        assert!(matches!(breaker.admit(&Method::GET).await, Admission::Refused));
+        drop(first);
+        assert!(matches!(breaker.admit(&Method::GET).await, Admission::Probe(_)));
+        assert_eq!(breaker.probe_stats().in_flight, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_half_open_callers_beyond_the_limit_see_it_open() {
+        let max_calls = 3;
+        let callers = max_calls + 5;
+        let breaker = std::sync::Arc::new(half_open(HalfOpenProbeMode::Live, max_calls).await);
+        // Every caller holds what it was given until all have been answered.
+        let answered = std::sync::Arc::new(tokio::sync::Barrier::new(callers as usize + 1));
+
+        let tasks: Vec<_> = (0..callers)
+            .map(|_| {
+                let (breaker, answered) = (breaker.clone(), answered.clone());
+                tokio::spawn(async move {
+                    let admission = breaker.admit(&Method::GET).await;
+                    let probe = matches!(admission, Admission::Probe(_));
+                    answered.wait().await;
+                    probe
+                })
+            })
+            .collect();
+        answered.wait().await;
+        let mut let_through = 0;
+        for task in tasks {
+            let_through += task.await.unwrap() as u32;
+        }
+        assert_eq!(let_through, max_calls);
+        assert_eq!(breaker.probe_stats().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_only_probes_of_the_current_period_give_slots_back() {
+        let breaker = CircuitBreaker::new(1).with_probe_config(&BreakerProbeConfig {
+            open_ms: 0,
+            max_in_flight: 1,
+            successes_to_close: 2,
+            ..BreakerProbeConfig::default()
+        });
+        // Admitted while closed, so it holds no slot.
+        assert!(matches!(breaker.admit(&Method::GET).await, Admission::Closed));
+        breaker.record_failure().await;
+        assert_eq!(breaker.current_state().await, CircuitBreakerState::HalfOpen);
+        let stale = breaker.try_probe().unwrap();
+
+        // The closed-era call finishing frees nothing.
+        breaker.record_success().await;
+        assert_eq!(breaker.get_state().await, CircuitBreakerState::HalfOpen);
+        assert!(matches!(breaker.admit(&Method::GET).await, Admission::Refused));
+        assert!(breaker.is_open().await);
+
+        // Neither does a permit from the previous half-open period.
+        breaker.record_failure().await;
+        assert_eq!(breaker.current_state().await, CircuitBreakerState::HalfOpen);
+        let current = breaker.try_probe().unwrap();
+        drop(stale);
+        assert_eq!(breaker.probe_stats().in_flight, 1);
+        assert!(breaker.try_probe().is_none());
+        drop(current);
+        assert_eq!(breaker.probe_stats().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_synthetic_mode_refuses_all_live_traffic() {
+        let breaker = half_open(HalfOpenProbeMode::Synthetic, 5).await;
+
+        assert!(matches!(breaker.admit(&Method::GET).await, Admission::Refused));
+        breaker.try_probe().unwrap().failed().await;
+        assert_eq!(breaker.get_state().await, CircuitBreakerState::Open);
+        assert_eq!(breaker.probe_stats().failed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_transitions_are_broadcast() {
+        let breaker = CircuitBreaker::new(2).with_timeout(Duration::ZERO);
+        let mut events = breaker.subscribe();
+
+        breaker.record_failure().await;
+        assert!(events.try_recv().is_err());
+        breaker.record_failure().await;
+        assert_eq!(events.recv().await.unwrap(), CircuitBreakerEvent::OpenedAfterFailures { failure_count: 2 });
+
+        assert_eq!(breaker.current_state().await, CircuitBreakerState::HalfOpen);
+        assert_eq!(events.recv().await.unwrap(), CircuitBreakerEvent::HalfOpened);
+        for _ in 0..3 {
+            breaker.record_success().await;
+        }
+        assert_eq!(events.recv().await.unwrap(), CircuitBreakerEvent::Closed);
+
+        // Successes while already closed announce nothing.
+        breaker.record_success().await;
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_skips_oldest_events() {
+        let breaker = CircuitBreaker::new(1).with_timeout(Duration::ZERO);
+        let mut events = breaker.subscribe();
+        for _ in 0..EVENT_CAPACITY {
+            breaker.record_failure().await;
+            breaker.current_state().await;
+            breaker.record_failure().await;
+        }
+
+        assert!(matches!(events.recv().await, Err(broadcast::error::RecvError::Lagged(_))));
+        assert!(events.recv().await.is_ok());
+    }
 }
@@ -0,0 +1,229 @@
+use crate::metrics::MetricsCollector;
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+// How long the proxy waits on a slow client before giving up on it. Zero
+// disables a timeout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTimeoutsConfig {
+    // Until a request's headers are complete. Covers a new connection's first
+    // bytes and the wait for the next request on a kept-alive one.
+    pub header_read_ms: u64,
+    // Longest gap between chunks of a request body.
+    pub body_read_idle_ms: u64,
+    // Longest a response write may wait on a client that is not reading.
+    pub response_write_idle_ms: u64,
+}
+
+impl Default for ClientTimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            header_read_ms: 10_000,
+            body_read_idle_ms: 30_000,
+            response_write_idle_ms: 30_000,
+        }
+    }
+}
+
+impl ClientTimeoutsConfig {
+    pub fn header_read(&self) -> Option<Duration> {
+        enabled(self.header_read_ms)
+    }
+
+    pub fn body_read_idle(&self) -> Option<Duration> {
+        enabled(self.body_read_idle_ms)
+    }
+
+    pub fn response_write_idle(&self) -> Option<Duration> {
+        enabled(self.response_write_idle_ms)
+    }
+}
+
+fn enabled(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+#[derive(Debug)]
+pub enum BodyReadError<E> {
+    Body(E),
+    Idle,
+}
+
+// A request body that fails once no frame has arrived for `timeout`.
+pub struct IdleTimeoutBody<B> {
+    inner: B,
+    timeout: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> IdleTimeoutBody<B> {
+    pub fn new(inner: B, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            idle: None,
+        }
+    }
+}
+
+impl<B> Body for IdleTimeoutBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = BodyReadError<B::Error>;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                this.idle = None;
+                Poll::Ready(frame.map(|frame| frame.map_err(BodyReadError::Body)))
+            }
+            Poll::Pending => match this.timeout {
+                Some(timeout) => {
+                    let idle = this.idle.get_or_insert_with(|| Box::pin(sleep(timeout)));
+                    match idle.as_mut().poll(cx) {
+                        Poll::Ready(()) => Poll::Ready(Some(Err(BodyReadError::Idle))),
+                        Poll::Pending => Poll::Pending,
+                    }
+                }
+                None => Poll::Pending,
+            },
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// A client connection whose writes fail once the client has taken nothing for
+// `timeout`. Only a write that cannot make progress starts the clock, so an
+// idle connection never trips it.
+pub struct WriteTimeoutIo<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    stalled: Option<Pin<Box<Sleep>>>,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl<S> WriteTimeoutIo<S> {
+    pub fn new(inner: S, timeout: Option<Duration>, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            inner,
+            timeout,
+            stalled: None,
+            metrics,
+        }
+    }
+
+    fn track<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let Some(timeout) = self.timeout else {
+            return poll;
+        };
+        if poll.is_ready() {
+            self.stalled = None;
+            return poll;
+        }
+        let stalled = self.stalled.get_or_insert_with(|| Box::pin(sleep(timeout)));
+        if stalled.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.stalled = None;
+        self.metrics.record_client_timeout("response_write");
+        Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading the response")))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WriteTimeoutIo<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeoutIo<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.track(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{channel::mpsc, SinkExt};
+    use http_body_util::{BodyExt, StreamBody};
+    use std::convert::Infallible;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_body_fails_only_after_idle_gap() {
+        let (mut tx, rx) = mpsc::channel::<Result<Frame<Bytes>, Infallible>>(4);
+        let mut body = IdleTimeoutBody::new(StreamBody::new(rx), Some(Duration::from_millis(200)));
+
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                tx.send(Ok(Frame::data(Bytes::from_static(b"chunk")))).await.unwrap();
+            }
+            // Held open, but silent.
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+        for _ in 0..3 {
+            assert!(body.frame().await.unwrap().is_ok());
+        }
+        assert!(matches!(body.frame().await, Some(Err(BodyReadError::Idle))));
+    }
+
+    #[tokio::test]
+    async fn test_write_fails_when_peer_stops_reading() {
+        let metrics = Arc::new(MetricsCollector::new());
+        let (client, _server) = tokio::io::duplex(64);
+        let mut io = WriteTimeoutIo::new(client, Some(Duration::from_millis(100)), metrics.clone());
+
+        let error = io.write_all(&[0; 1024]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(metrics.client_timeout_count("response_write"), 1);
+    }
+}
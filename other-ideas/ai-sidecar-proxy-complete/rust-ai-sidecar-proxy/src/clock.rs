@@ -0,0 +1,67 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+// Time source for state that ages out, so tests can skip ahead instead of
+// sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    // Wall time, for state that outlives the process or follows the calendar.
+    fn wall(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Stands still until advanced.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    wall_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    pub fn at(wall: SystemTime) -> Self {
+        Self {
+            start: Instant::now(),
+            wall_start: wall,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall_start + *self.elapsed.lock().unwrap()
+    }
+}
@@ -0,0 +1,111 @@
+use hyper::{
+    header::{self, HeaderName},
+    HeaderMap, StatusCode,
+};
+
+// A 304 carries these from the response it stands in for (RFC 9110 section
+// 15.4.5), plus Last-Modified so caches can update their stored copy.
+const NOT_MODIFIED_HEADERS: [HeaderName; 7] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::ETAG,
+    header::EXPIRES,
+    header::VARY,
+    header::LAST_MODIFIED,
+];
+
+// The validators of a conditional GET, checked by the proxy against the
+// upstream's answer to a HEAD so an unchanged body is never transferred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl Validators {
+    // None for unconditional requests.
+    pub fn from_request(headers: &HeaderMap) -> Option<Self> {
+        let get = |name| headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let validators = Self {
+            if_none_match: get(header::IF_NONE_MATCH),
+            if_modified_since: get(header::IF_MODIFIED_SINCE),
+        };
+        (validators.if_none_match.is_some() || validators.if_modified_since.is_some()).then_some(validators)
+    }
+
+    // Whether the client's copy is current. If-None-Match takes precedence
+    // and uses weak comparison; If-Modified-Since must equal Last-Modified
+    // exactly, which is what a cache echoing the stored value sends. Anything
+    // else counts as modified and costs only the full GET.
+    pub fn matches(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if !status.is_success() {
+            return false;
+        }
+        let get = |name| headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+        if let Some(if_none_match) = &self.if_none_match {
+            let Some(etag) = get(header::ETAG) else {
+                return if_none_match.trim() == "*";
+            };
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak(tag) == weak(etag));
+        }
+        match (&self.if_modified_since, get(header::LAST_MODIFIED)) {
+            (Some(since), Some(modified)) => since.trim() == modified,
+            _ => false,
+        }
+    }
+}
+
+// Headers for a 304 sent in place of a matching response.
+pub fn not_modified_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut kept = HeaderMap::new();
+    for name in NOT_MODIFIED_HEADERS {
+        for value in headers.get_all(&name) {
+            kept.append(name.clone(), value.clone());
+        }
+    }
+    kept
+}
+
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_if_none_match_uses_weak_comparison() {
+        let validators = Validators::from_request(&headers(&[("if-none-match", "\"a\", W/\"b\"")])).unwrap();
+
+        assert!(validators.matches(StatusCode::OK, &headers(&[("etag", "\"b\"")])));
+        assert!(validators.matches(StatusCode::OK, &headers(&[("etag", "W/\"a\"")])));
+        assert!(!validators.matches(StatusCode::OK, &headers(&[("etag", "\"c\"")])));
+        assert!(!validators.matches(StatusCode::NOT_FOUND, &headers(&[("etag", "\"a\"")])));
+        // Without an ETag only "*" matches.
+        assert!(!validators.matches(StatusCode::OK, &headers(&[("last-modified", "x")])));
+        let any = Validators::from_request(&headers(&[("if-none-match", "*")])).unwrap();
+        assert!(any.matches(StatusCode::OK, &HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_if_modified_since_needs_exact_date() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let validators = Validators::from_request(&headers(&[("if-modified-since", date)])).unwrap();
+
+        assert!(validators.matches(StatusCode::OK, &headers(&[("last-modified", date)])));
+        assert!(!validators.matches(StatusCode::OK, &headers(&[("last-modified", "Thu, 22 Oct 2015 07:28:00 GMT")])));
+        assert_eq!(Validators::from_request(&headers(&[("accept", "*/*")])), None);
+    }
+}
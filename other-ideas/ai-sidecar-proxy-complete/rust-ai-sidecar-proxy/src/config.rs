@@ -1,88 +1,1961 @@
+use crate::access::AccessRulesConfig;
+use crate::admin_auth::AdminAuthConfig;
+use crate::archive::ArchiveConfig;
+use crate::auto_weight::AutoWeightConfig;
+use crate::capture::CaptureConfig;
+use crate::checksum::BodyChecksumConfig;
+use crate::circuit_breaker::BreakerProbeConfig;
+use crate::client_timeouts::ClientTimeoutsConfig;
+use crate::config_history::ConfigRollbackConfig;
+use crate::config_migration::{self, Upgraded, CURRENT_CONFIG_VERSION};
+use crate::config_toml;
+use crate::config_env::EnvOverrides;
+use crate::consul::ConsulDiscoveryConfig;
+use crate::config_yaml;
+use crate::address_family::AddressFamily;
+use crate::content_coding::ContentCodingMode;
+use crate::drain::DrainConfig;
+use crate::eager_init::EagerInitConfig;
+use crate::server_timing::ServerTimingConfig;
+use crate::overload::OverloadConfig;
+use crate::stats_history::StatsHistoryConfig;
+use crate::egress::EgressConfig;
+use crate::endpoint_gc::EndpointGcConfig;
+use crate::endpoint_url;
+use crate::header_rules::HeaderRule;
+use crate::experiments::ExperimentsConfig;
+use crate::kubernetes::KubernetesDiscoveryConfig;
+use crate::load_balancer::LoadBalancingStrategy;
+use crate::mesh_metadata::MeshMetadataConfig;
+use crate::policy_templates::PolicyTemplate;
+use crate::routes::{self, ConflictKind, ConflictStrictness, PathPattern, RouteRule};
+use crate::prewarm::PrewarmConfig;
+use crate::quota::QuotaConfig;
+use crate::storage::StorageConfig;
+use crate::rate_limiter::{RateLimitAlgorithm, RateLimitConfig};
+use crate::response_headers::ResponseHeadersConfig;
+use crate::upstream_client::UpstreamPoolConfig;
+use crate::upstream_response::{self, UpstreamResponseConfig};
+use crate::sniff::TlsOnPlaintext;
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    // Files without one predate versioning and are read as version 1; see
+    // `config_migration`.
+    pub config_version: u32,
     pub upstream_services: HashMap<String, UpstreamService>,
+    // Path prefixes and patterns and the services they go to; see `routes`.
+    // Paths no rule matches fall back to `/api/<x>` -> `service-<x>`.
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    // Whether duplicate and unreachable routes stop the config loading or
+    // are only warned about.
+    #[serde(default)]
+    pub route_conflicts: ConflictStrictness,
+    // Takes the paths neither a rule nor `/api/<x>` matches. Unset answers
+    // them 404.
+    #[serde(default)]
+    pub default_service: Option<String>,
+    #[serde(default)]
     pub ai_config: AIConfig,
+    #[serde(default)]
     pub proxy_config: ProxyConfig,
+    #[serde(default)]
     pub metrics_config: MetricsConfig,
+    #[serde(default)]
+    pub access_rules: AccessRulesConfig,
+    #[serde(default)]
+    pub mesh_metadata: MeshMetadataConfig,
+    #[serde(default)]
+    pub body_checksums: BodyChecksumConfig,
+    // Outbound calls the application sends through the sidecar.
+    #[serde(default)]
+    pub egress: EgressConfig,
+    // Sampled request/response bodies written to a local spool.
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    // Route-level A/B splits between upstream services.
+    #[serde(default)]
+    pub experiments: ExperimentsConfig,
+    // Operator-started captures of live exchanges, read back over the admin API.
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    // Tokens for the admin API, optionally limited to some services.
+    #[serde(default)]
+    pub admin_auth: AdminAuthConfig,
+    // Per client IP, on the ingress listener; /health and /metrics are never
+    // limited. Unset means no limit.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    // Per-period request counts by API key, kept across restarts.
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+    // One backend for the AI engine's snapshot and quota usage, replacing
+    // their `persist_path` files; see `storage`.
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
+    // Upstream response headers kept from, or stripped before, each listener's
+    // clients.
+    #[serde(default)]
+    pub response_headers: ResponseHeadersConfig,
+    // Limits on upstream header blocks, and what stands in for statuses with
+    // no standard meaning.
+    #[serde(default)]
+    pub upstream_responses: UpstreamResponseConfig,
+    // Named policy settings that services pull in with `policy`; applied when
+    // the file is loaded.
+    #[serde(default)]
+    pub policy_templates: HashMap<String, PolicyTemplate>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UpstreamService {
     pub name: String,
+    // The policy template this service's settings were filled from.
+    #[serde(default)]
+    pub policy: Option<String>,
     pub endpoints: Vec<String>,
+    #[serde(default = "default_health_check_path")]
     pub health_check_path: String,
+    #[serde(default = "default_timeout_ms")]
     pub timeout_ms: u64,
+    // Caps the AI engine's adaptive per-request timeout, which can reach 30
+    // seconds; the admin API can override it while running.
+    #[serde(default)]
+    pub max_timeout_ms: Option<u64>,
+    #[serde(default = "default_max_retries")]
     pub max_retries: u32,
+    #[serde(default = "default_circuit_breaker_threshold")]
     pub circuit_breaker_threshold: u32,
+    #[serde(default)]
+    pub health_check_timeout_ms: Option<u64>,
+    // A probe that succeeds but takes longer than this marks the endpoint
+    // degraded: still routable, with its AI score scaled down. Unset, probes
+    // are only healthy or unhealthy.
+    #[serde(default)]
+    pub health_check_warn_latency_ms: Option<u64>,
+    // Replaces proxy_config.max_body_bytes for requests to this service.
+    #[serde(default)]
+    pub max_body_bytes: Option<usize>,
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+    #[serde(default)]
+    pub auth: Option<UpstreamAuthConfig>,
+    #[serde(default)]
+    pub content_coding: ContentCodingMode,
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    // Fixed addresses for endpoint hostnames, used instead of DNS.
+    #[serde(default)]
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    // Conditional GETs are checked with a HEAD first, and answered 304 without
+    // fetching the body when the client's copy is current.
+    #[serde(default)]
+    pub validate_with_head: bool,
+    #[serde(default)]
+    pub prewarm: Option<PrewarmConfig>,
+    // Every request goes out on a connection of its own that opens with a
+    // PROXY protocol v1 header naming the client, for upstreams behind
+    // HAProxy or nginx that take the client address from it. Such
+    // connections are never pooled.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    // How a half-open breaker picks the requests that decide whether it closes.
+    #[serde(default)]
+    pub breaker_probe: BreakerProbeConfig,
+    // Load balancer weights by endpoint; unlisted endpoints weigh 1.
+    #[serde(default)]
+    pub endpoint_weights: Option<HashMap<String, u32>>,
+    // Priority tiers by endpoint; unlisted endpoints are tier 0. Only the
+    // lowest tier with a healthy endpoint is routed to, so higher tiers
+    // stand by until it fails its probes.
+    #[serde(default)]
+    pub endpoint_priorities: Option<HashMap<String, u32>>,
+    // How the service picks an endpoint when the AI engine is disabled or
+    // below its decision threshold: round_robin (the default),
+    // weighted_round_robin, least_connections, random, least_request or
+    // weighted_random.
+    #[serde(default)]
+    pub load_balancing_strategy: Option<String>,
+    // Weights learned from AI endpoint scores, replacing the static ones as
+    // samples come in. Needs a strategy that uses weights.
+    #[serde(default)]
+    pub auto_weight: Option<AutoWeightConfig>,
+    // Endpoints kept in step with a Kubernetes Service instead of listed
+    // here; those listed are used until the first answer.
+    #[serde(default)]
+    pub kubernetes: Option<KubernetesDiscoveryConfig>,
+    // Endpoints kept in step with the instances passing their Consul health
+    // checks, the same way.
+    #[serde(default)]
+    pub consul: Option<ConsulDiscoveryConfig>,
+    // Headers injected into or removed from requests to the service and its
+    // responses, in order.
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRule>,
+    // Endpoints as they were written, by canonical form, where the two
+    // differ. Only for showing back to whoever wrote them.
+    #[serde(skip)]
+    pub endpoint_originals: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl UpstreamService {
+    // Endpoints come from a discovery backend rather than the config.
+    pub fn discovered(&self) -> bool {
+        self.kubernetes.is_some() || self.consul.is_some()
+    }
+
+    pub fn canonicalize_endpoints(&mut self) {
+        // Kept from earlier passes, so a second one still knows what the
+        // first was given.
+        let mut originals = std::mem::take(&mut self.endpoint_originals);
+        let mut endpoints: Vec<String> = Vec::with_capacity(self.endpoints.len());
+        for endpoint in std::mem::take(&mut self.endpoints) {
+            let canonical = endpoint_url::canonicalize(&endpoint).unwrap_or_else(|_| endpoint.clone());
+            if canonical != endpoint {
+                originals.entry(canonical.clone()).or_insert(endpoint);
+            }
+            if !endpoints.contains(&canonical) {
+                endpoints.push(canonical);
+            }
+        }
+        originals.retain(|canonical, _| endpoints.contains(canonical));
+        self.endpoints = endpoints;
+        self.endpoint_originals = originals;
+        self.endpoint_weights = self.endpoint_weights.take().map(canonical_keys);
+        self.endpoint_priorities = self.endpoint_priorities.take().map(canonical_keys);
+    }
+}
+
+// Copies the value at `path`, keys joined by '.', from `overlay` into
+// `base`. Where `base` has no table on the way, the overlay's whole table is
+// taken.
+fn override_path(base: &mut serde_json::Value, overlay: &serde_json::Value, path: &str) {
+    let (mut base, mut overlay) = (base, overlay);
+    for key in path.split('.') {
+        overlay = &overlay[key];
+        let serde_json::Value::Object(table) = base else {
+            break;
+        };
+        if !table.contains_key(key) {
+            table.insert(key.to_string(), overlay.clone());
+            return;
+        }
+        base = table.get_mut(key).expect("key was just checked");
+    }
+    *base = overlay.clone();
+}
+
+fn canonical_keys(by_endpoint: HashMap<String, u32>) -> HashMap<String, u32> {
+    by_endpoint
+        .into_iter()
+        .map(|(endpoint, value)| (endpoint_url::canonicalize(&endpoint).unwrap_or(endpoint), value))
+        .collect()
+}
+
+fn default_health_check_path() -> String {
+    "/health".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_circuit_breaker_threshold() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    pub ca_bundle_path: Option<String>,
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamAuthConfig {
+    pub header: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AIConfig {
     pub enabled: bool,
     pub decision_threshold: f64,
     pub learning_rate: f64,
     pub model_update_interval_ms: u64,
+    // Where learned endpoint health is kept across restarts; unset keeps it
+    // in memory only.
+    pub persist_path: Option<PathBuf>,
+    // Recorded requests between snapshots.
+    pub persist_interval: u32,
+    // Scales the score of endpoints whose last probe was slow.
+    pub degraded_penalty: f64,
+    // Failed requests, with under half succeeding, that take an endpoint out
+    // of selection; 0 never does.
+    pub eviction_threshold: u32,
+    // Passed health probes in a row that bring an evicted endpoint back.
+    pub rehabilitation_successes: u32,
+}
+
+impl Default for AIConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            decision_threshold: 0.7,
+            learning_rate: 0.01,
+            model_update_interval_ms: 60000,
+            persist_path: None,
+            persist_interval: 100,
+            degraded_penalty: 0.5,
+            eviction_threshold: 0,
+            rehabilitation_successes: 3,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ProxyConfig {
     pub max_connections: usize,
     pub connection_timeout_ms: u64,
     pub request_timeout_ms: u64,
     pub buffer_size: usize,
+    pub sniff_protocol: bool,
+    pub tls_on_plaintext: TlsOnPlaintext,
+    pub fd_monitor: FdMonitorConfig,
+    pub max_buffered_bytes: usize,
+    // Per request; larger bodies are answered 413.
+    pub max_body_bytes: usize,
+    // A request body that may have to be sent again, for a retry or after a
+    // HEAD validation, is buffered when it fits in this many bytes. Longer
+    // ones, and every body of a request that will not be resent, stream to
+    // the upstream as they arrive and are never retried.
+    #[serde(default = "default_replay_buffer_bytes")]
+    pub replay_buffer_bytes: usize,
+    // Longer values the proxy writes itself are truncated.
+    pub max_header_value_bytes: usize,
+    pub supervisor: SupervisorConfig,
+    pub tls: Option<ListenerTlsConfig>,
+    #[serde(default)]
+    pub drain: DrainConfig,
+    #[serde(default)]
+    pub endpoint_gc: EndpointGcConfig,
+    // Limits on slow clients, separate from the upstream timeouts.
+    #[serde(default)]
+    pub client_timeouts: ClientTimeoutsConfig,
+    // Answers 400 to HTTP/1 requests with ambiguous framing or malformed
+    // header lines instead of leaving them to hyper.
+    #[serde(default)]
+    pub strict_http: bool,
+    // Passes an upstream's 103 Early Hints on to HTTP/1.1 and HTTP/2 clients
+    // ahead of the final response. Off, they are only counted.
+    #[serde(default = "default_relay_early_hints")]
+    pub relay_early_hints: bool,
+    // Clients, DNS and AI engine entries set up before the first request.
+    #[serde(default)]
+    pub eager_init: EagerInitConfig,
+    // Per-hop phase timings in a Server-Timing response header.
+    #[serde(default)]
+    pub server_timing: ServerTimingConfig,
+    // Reverts a reload whose error rate climbs past a guardrail.
+    #[serde(default)]
+    pub config_rollback: ConfigRollbackConfig,
+    // Sheds low-priority requests while the proxy's own scheduler lags.
+    #[serde(default)]
+    pub overload: OverloadConfig,
+    // Idle connections and keepalives of the pooled upstream clients.
+    #[serde(default)]
+    pub upstream_pool: UpstreamPoolConfig,
+    // Wait before retrying a failed upstream call, doubled on each retry
+    // up to retry_max_delay_ms. A service's max_retries sets how many.
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    // Upstream statuses worth another attempt; connection errors and
+    // timeouts always are. Only requests with idempotent methods, or that
+    // carry an Idempotency-Key, are retried at all.
+    #[serde(default = "default_retry_on_status")]
+    pub retry_on_status: Vec<u16>,
+    // Identical GET and HEAD requests to a service share one upstream call
+    // while it is in flight, and its answer for request_dedupe_window_ms
+    // after. Requests carrying any of request_dedupe_excluded_headers, or
+    // the quota's key header, are never shared.
+    #[serde(default)]
+    pub enable_request_dedupe: bool,
+    #[serde(default = "default_request_dedupe_window_ms")]
+    pub request_dedupe_window_ms: u64,
+    // Headers that make an answer depend on who asks, or on which part of
+    // the body they want.
+    #[serde(default = "default_request_dedupe_excluded_headers")]
+    pub request_dedupe_excluded_headers: Vec<String>,
+    // Answers with longer bodies are streamed to the request that made the
+    // call, and the others make their own.
+    #[serde(default = "default_request_dedupe_max_body_bytes")]
+    pub request_dedupe_max_body_bytes: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerTlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    pub session_cache_size: usize,
+    pub ticket_rotation_secs: u64,
+    // Hex-encoded 32-byte key shared by every instance behind the same name, so
+    // a ticket issued by one instance resumes on another. Re-read on rotation.
+    pub ticket_key_path: Option<String>,
+    // How often cert_path and key_path are checked for a rotated pair.
+    #[serde(default = "default_cert_reload_secs")]
+    pub cert_reload_secs: u64,
+    // Oldest protocol version a client may negotiate.
+    #[serde(default)]
+    pub min_version: TlsVersion,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl ProxyConfig {
+    // Before retry `retry` (from 0): the base delay doubled that many
+    // times, capped.
+    pub fn retry_delay(&self, retry: u32) -> Duration {
+        let delay = self.retry_base_delay_ms.saturating_mul(1u64.checked_shl(retry).unwrap_or(u64::MAX));
+        Duration::from_millis(delay.min(self.retry_max_delay_ms))
+    }
+
+    // Somewhere in the upper half of `retry_delay`, so clients that failed
+    // together do not all retry together.
+    pub fn jittered_retry_delay(&self, retry: u32) -> Duration {
+        let delay = self.retry_delay(retry);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10000,
+            connection_timeout_ms: 30000,
+            request_timeout_ms: 30000,
+            buffer_size: 8192,
+            sniff_protocol: true,
+            tls_on_plaintext: TlsOnPlaintext::Close,
+            fd_monitor: FdMonitorConfig::default(),
+            max_buffered_bytes: 256 * 1024 * 1024,
+            max_body_bytes: 10 * 1024 * 1024,
+            replay_buffer_bytes: default_replay_buffer_bytes(),
+            max_header_value_bytes: 4096,
+            supervisor: SupervisorConfig::default(),
+            tls: None,
+            drain: DrainConfig::default(),
+            endpoint_gc: EndpointGcConfig::default(),
+            client_timeouts: ClientTimeoutsConfig::default(),
+            strict_http: false,
+            relay_early_hints: default_relay_early_hints(),
+            eager_init: EagerInitConfig::default(),
+            server_timing: ServerTimingConfig::default(),
+            config_rollback: ConfigRollbackConfig::default(),
+            overload: OverloadConfig::default(),
+            upstream_pool: UpstreamPoolConfig::default(),
+            retry_base_delay_ms: default_retry_base_delay_ms(),
+            retry_max_delay_ms: default_retry_max_delay_ms(),
+            retry_on_status: default_retry_on_status(),
+            enable_request_dedupe: false,
+            request_dedupe_window_ms: default_request_dedupe_window_ms(),
+            request_dedupe_excluded_headers: default_request_dedupe_excluded_headers(),
+            request_dedupe_max_body_bytes: default_request_dedupe_max_body_bytes(),
+        }
+    }
+}
+
+fn default_cert_reload_secs() -> u64 {
+    10
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    100
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    2000
+}
+
+fn default_retry_on_status() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_replay_buffer_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_relay_early_hints() -> bool {
+    true
+}
+
+fn default_request_dedupe_window_ms() -> u64 {
+    50
+}
+
+fn default_request_dedupe_excluded_headers() -> Vec<String> {
+    ["authorization", "proxy-authorization", "cookie", "range"].map(String::from).to_vec()
+}
+
+fn default_request_dedupe_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FdMonitorConfig {
+    pub safe_fraction: f64,
+    pub high_water_fraction: f64,
+    pub shed_on_pressure: bool,
+    pub check_interval_ms: u64,
+}
+
+impl Default for FdMonitorConfig {
+    fn default() -> Self {
+        Self {
+            safe_fraction: 0.8,
+            high_water_fraction: 0.9,
+            shed_on_pressure: true,
+            check_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SupervisorConfig {
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub stall_after_ms: u64,
+    pub critical_dead_after_ms: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            stall_after_ms: 90_000,
+            critical_dead_after_ms: 60_000,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MetricsConfig {
     pub enabled: bool,
     pub port: u16,
     pub path: String,
+    // Per-minute endpoint stats for GET /admin/stats/export.
+    pub history: StatsHistoryConfig,
+    // Distinct route and operation pairs labelled on metrics; any more are
+    // counted as "__other__". See `path_template` on routes.
+    pub max_operations: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            port: 9090,
+            path: "/metrics".to_string(),
+            history: StatsHistoryConfig::default(),
+            max_operations: 200,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Every format goes through the same upgrade into the same `Config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    // By extension; anything unrecognised is read as JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    pub fn upgrade(&self, text: &str) -> Result<Upgraded> {
+        match self {
+            ConfigFormat::Json => config_migration::upgrade(text),
+            ConfigFormat::Toml => config_toml::upgrade(text),
+            ConfigFormat::Yaml => config_yaml::upgrade(text),
+        }
+    }
+
+    // Output that `upgrade` reads back as the same config.
+    pub fn write(&self, config: &Config) -> String {
+        match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config).expect("config serializes") + "\n",
+            ConfigFormat::Toml => config_toml::to_toml(config),
+            ConfigFormat::Yaml => config_yaml::to_yaml(config),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    // The config file could not be read.
+    Io { file: String, kind: std::io::ErrorKind, message: String },
+    // The file is not a config in its format, or is one this build cannot
+    // upgrade.
+    Parse { file: String, message: String },
+    // One setting that would make the proxy fail at request time rather than
+    // at startup. `path` is in `config_migration`'s "a.b[1].c" form.
+    Invalid { path: String, message: String },
+}
+
+impl ConfigError {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    // The file for `Io` and `Parse`, the setting for `Invalid`.
+    pub fn path(&self) -> &str {
+        match self {
+            ConfigError::Io { file, .. } | ConfigError::Parse { file, .. } => file,
+            ConfigError::Invalid { path, .. } => path,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ConfigError::Io { message, .. } | ConfigError::Parse { message, .. } | ConfigError::Invalid { message, .. } => message,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { file, message, .. } => write!(f, "reading config {}: {}", file, message),
+            ConfigError::Parse { file, message } => write!(f, "loading config {}: {}", file, message),
+            ConfigError::Invalid { path, message } => write!(f, "at `{}`: {}", path, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn validate_kubernetes(kubernetes: &KubernetesDiscoveryConfig, path: &str, errors: &mut Vec<ConfigError>) {
+    if !cfg!(feature = "kubernetes") {
+        errors.push(ConfigError::new(path, "this build has no Kubernetes discovery; rebuild with --features kubernetes"));
+    }
+    if kubernetes.service.is_empty() || kubernetes.namespace.is_empty() {
+        errors.push(ConfigError::new(path, "needs the namespace and service to watch"));
+    }
+    if !matches!(kubernetes.scheme.as_str(), "http" | "https") {
+        errors.push(ConfigError::new(
+            format!("{}.scheme", path),
+            format!("must be http or https, got {:?}", kubernetes.scheme),
+        ));
+    }
+    if kubernetes.retry_initial_ms == 0 || kubernetes.retry_initial_ms > kubernetes.retry_max_ms {
+        errors.push(ConfigError::new(
+            format!("{}.retry_initial_ms", path),
+            format!("must be between 1 and retry_max_ms ({})", kubernetes.retry_max_ms),
+        ));
+    }
+}
+
+const NO_TLS: &str = "this build has no TLS; rebuild with --features tls";
+
+// Without the `tls` feature nothing can be reached over HTTPS.
+fn validate_plaintext(service: &UpstreamService, path: &str, errors: &mut Vec<ConfigError>) {
+    if cfg!(feature = "tls") {
+        return;
+    }
+    let https = |url: &str| url.get(..8).is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"));
+    if service.tls.is_some() {
+        errors.push(ConfigError::new(format!("{}.tls", path), NO_TLS));
+    }
+    for (index, endpoint) in service.endpoints.iter().enumerate() {
+        if https(endpoint) {
+            errors.push(ConfigError::new(format!("{}.endpoints[{}]", path, index), NO_TLS));
+        }
+    }
+    if service.kubernetes.as_ref().is_some_and(|kubernetes| kubernetes.scheme == "https") {
+        errors.push(ConfigError::new(format!("{}.kubernetes.scheme", path), NO_TLS));
+    }
+    if let Some(consul) = &service.consul {
+        if consul.scheme == "https" {
+            errors.push(ConfigError::new(format!("{}.consul.scheme", path), NO_TLS));
+        }
+        if https(&consul.address) {
+            errors.push(ConfigError::new(format!("{}.consul.address", path), NO_TLS));
+        }
+    }
+}
+
+fn validate_consul(consul: &ConsulDiscoveryConfig, path: &str, errors: &mut Vec<ConfigError>) {
+    if consul.service.is_empty() {
+        errors.push(ConfigError::new(path, "needs the service to look up"));
+    }
+    if let Err(message) = endpoint_url::canonicalize(&consul.address) {
+        errors.push(ConfigError::new(format!("{}.address", path), message));
+    }
+    if !matches!(consul.scheme.as_str(), "http" | "https") {
+        errors.push(ConfigError::new(
+            format!("{}.scheme", path),
+            format!("must be http or https, got {:?}", consul.scheme),
+        ));
+    }
+    // Consul caps a blocking query's wait at ten minutes.
+    if consul.poll_interval_ms == 0 || consul.poll_interval_ms > 600_000 {
+        errors.push(ConfigError::new(format!("{}.poll_interval_ms", path), "must be between 1 and 600000"));
+    }
+    if consul.retry_initial_ms == 0 || consul.retry_initial_ms > consul.retry_max_ms {
+        errors.push(ConfigError::new(
+            format!("{}.retry_initial_ms", path),
+            format!("must be between 1 and retry_max_ms ({})", consul.retry_max_ms),
+        ));
+    }
+}
+
+// What `Config::validation` found. Only errors stop a config loading.
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub errors: Vec<ConfigError>,
+    pub warnings: Vec<ConfigError>,
+}
+
+// Every error on its own line, for refusing to start.
+pub fn invalid_config(errors: &[ConfigError]) -> anyhow::Error {
+    let lines: Vec<String> = errors.iter().map(|error| format!("  {}", error)).collect();
+    anyhow::anyhow!("invalid config:\n{}", lines.join("\n"))
 }
 
 impl Config {
+    // Reads a config file, TOML when the name ends in ".toml" and JSON
+    // otherwise, upgrading it from an older version if needed.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        Self::from_file_as(path, ConfigFormat::from_path(path))
+    }
+
+    pub fn from_file_as(path: &Path, format: ConfigFormat) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io {
+            file: path.display().to_string(),
+            kind: e.kind(),
+            message: e.to_string(),
+        })?;
+        let upgraded = format.upgrade(&text).map_err(|e| ConfigError::Parse {
+            file: path.display().to_string(),
+            message: format!("{:#}", e),
+        })?;
+        Self::warn_if_migrated(path, &upgraded);
+        Ok(upgraded.config)
+    }
+
+    // Reads a JSON config file, upgrading it from an older version if needed.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::from_file_as(path, ConfigFormat::Json)
+    }
+
+    fn warn_if_migrated(path: &Path, upgraded: &Upgraded) {
+        if !upgraded.changes.is_empty() {
+            warn!(
+                path = %path.display(),
+                from_version = upgraded.from_version,
+                to_version = CURRENT_CONFIG_VERSION,
+                changes = ?upgraded.changes,
+                "migrated config from an older version; run migrate-config to update the file"
+            );
+        }
+    }
+
+    // Checks everything that can be checked without knowing the listen port,
+    // and reports all of it rather than stopping at the first problem.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let validation = self.validation();
+        if validation.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(validation.errors)
+        }
+    }
+
+    // `validate`'s checks, with the findings that do not stop a config
+    // loading.
+    pub fn validation(&self) -> Validation {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+
+        let mut names: Vec<&String> = self.upstream_services.keys().collect();
+        names.sort();
+        for name in names {
+            let service = &self.upstream_services[name];
+            let path = format!("upstream_services.{}", name);
+            if service.endpoints.is_empty() && !service.discovered() {
+                errors.push(ConfigError::new(format!("{}.endpoints", path), "needs at least one endpoint"));
+            }
+            if let Some(kubernetes) = &service.kubernetes {
+                validate_kubernetes(kubernetes, &format!("{}.kubernetes", path), &mut errors);
+            }
+            if let Some(consul) = &service.consul {
+                if service.kubernetes.is_some() {
+                    errors.push(ConfigError::new(format!("{}.consul", path), "cannot be set along with kubernetes"));
+                }
+                validate_consul(consul, &format!("{}.consul", path), &mut errors);
+            }
+            for (index, endpoint) in service.endpoints.iter().enumerate() {
+                let endpoint_path = format!("{}.endpoints[{}]", path, index);
+                if let Err(message) = endpoint_url::canonicalize(endpoint) {
+                    errors.push(ConfigError::new(endpoint_path, message));
+                }
+            }
+            validate_plaintext(service, &path, &mut errors);
+            if service.proxy_protocol && service.prewarm.is_some() {
+                errors.push(ConfigError::new(
+                    format!("{}.prewarm", path),
+                    "cannot be used with proxy_protocol; a connection opened ahead of time has no client to announce",
+                ));
+            }
+            if !service.health_check_path.starts_with('/') {
+                errors.push(ConfigError::new(
+                    format!("{}.health_check_path", path),
+                    format!("must start with '/', as in {:?}", format!("/{}", service.health_check_path)),
+                ));
+            }
+            for (field, by_endpoint) in [
+                ("endpoint_weights", &service.endpoint_weights),
+                ("endpoint_priorities", &service.endpoint_priorities),
+            ] {
+                for endpoint in by_endpoint.iter().flat_map(|by_endpoint| by_endpoint.keys()) {
+                    if !service.endpoints.contains(endpoint) {
+                        errors.push(ConfigError::new(
+                            format!("{}.{}", path, field),
+                            format!("{:?} is not one of the service's endpoints", endpoint),
+                        ));
+                    }
+                }
+            }
+            let strategy = service.load_balancing_strategy.as_deref().map(str::parse::<LoadBalancingStrategy>);
+            if let Some(Err(e)) = &strategy {
+                errors.push(ConfigError::new(format!("{}.load_balancing_strategy", path), e.clone()));
+            }
+            if let Some(auto_weight) = &service.auto_weight {
+                let weighted = matches!(&strategy, Some(Ok(strategy)) if strategy.uses_weights());
+                if !weighted {
+                    errors.push(ConfigError::new(
+                        format!("{}.auto_weight", path),
+                        "needs a load_balancing_strategy that uses weights: weighted_round_robin, weighted_random or least_request",
+                    ));
+                }
+                if auto_weight.min_weight == 0 || auto_weight.min_weight > auto_weight.max_weight {
+                    errors.push(ConfigError::new(
+                        format!("{}.auto_weight.min_weight", path),
+                        format!("must be between 1 and max_weight ({})", auto_weight.max_weight),
+                    ));
+                }
+                if auto_weight.max_step == 0 || auto_weight.interval_ms == 0 {
+                    errors.push(ConfigError::new(
+                        format!("{}.auto_weight", path),
+                        "max_step and interval_ms must be greater than 0",
+                    ));
+                }
+                if auto_weight.score_spread <= 0.0 || !(auto_weight.smoothing > 0.0 && auto_weight.smoothing <= 1.0) {
+                    errors.push(ConfigError::new(
+                        format!("{}.auto_weight", path),
+                        "score_spread must be greater than 0 and smoothing between 0 (exclusive) and 1",
+                    ));
+                }
+            }
+            for (index, rule) in service.header_rules.iter().enumerate() {
+                if let Some(problem) = rule.problem() {
+                    errors.push(ConfigError::new(format!("{}.header_rules[{}].name", path, index), problem));
+                }
+                if let Some((source, error)) = rule.value_error() {
+                    errors.push(ConfigError::new(
+                        format!("{}.header_rules[{}].value", path, index),
+                        format!("{:?} is not a valid template: {}", source, error),
+                    ));
+                }
+            }
+            if service.timeout_ms == 0 {
+                errors.push(ConfigError::new(format!("{}.timeout_ms", path), "must be greater than 0"));
+            }
+            if service.max_timeout_ms == Some(0) {
+                errors.push(ConfigError::new(format!("{}.max_timeout_ms", path), "must be greater than 0"));
+            }
+            if service.health_check_warn_latency_ms == Some(0) {
+                errors.push(ConfigError::new(
+                    format!("{}.health_check_warn_latency_ms", path),
+                    "must be greater than 0",
+                ));
+            }
+            if service.max_body_bytes == Some(0) {
+                errors.push(ConfigError::new(format!("{}.max_body_bytes", path), "must be greater than 0"));
+            }
+            if service.circuit_breaker_threshold == 0 {
+                errors.push(ConfigError::new(
+                    format!("{}.circuit_breaker_threshold", path),
+                    "must be greater than 0; a breaker that trips at 0 failures never closes",
+                ));
+            }
+        }
+
+        let mut conflicts = routes::conflicts(&self.routes).into_iter().peekable();
+        for (index, route) in self.routes.iter().enumerate() {
+            let path = format!("routes[{}]", index);
+            if let Some(pattern) = &route.path_pattern {
+                if !route.path_prefix.is_empty() {
+                    errors.push(ConfigError::new(
+                        format!("{}.path_pattern", path),
+                        "set path_prefix or path_pattern, not both",
+                    ));
+                } else if let PathPattern::Wildcard(wildcard) = pattern {
+                    if !wildcard.starts_with('/') {
+                        errors.push(ConfigError::new(
+                            format!("{}.path_pattern", path),
+                            format!("must start with '/', as in {:?}", format!("/{}", wildcard)),
+                        ));
+                    }
+                }
+                if let Err(e) = pattern.compile() {
+                    errors.push(ConfigError::new(format!("{}.path_pattern", path), format!("invalid pattern: {}", e)));
+                }
+                if route.strip_prefix {
+                    errors.push(ConfigError::new(
+                        format!("{}.strip_prefix", path),
+                        "only applies to path_prefix; strip with a rewrite instead",
+                    ));
+                }
+            } else if !route.path_prefix.starts_with('/') {
+                errors.push(ConfigError::new(
+                    format!("{}.path_prefix", path),
+                    format!("must start with '/', as in {:?}", format!("/{}", route.path_prefix)),
+                ));
+            }
+            while let Some(conflict) = conflicts.next_if(|conflict| conflict.rule == index) {
+                let matcher = if route.path_pattern.is_some() { "path_pattern" } else { "path_prefix" };
+                let finding = ConfigError::new(format!("{}.{}", path, matcher), conflict.message);
+                match (conflict.kind, self.route_conflicts) {
+                    (ConflictKind::CatchAll | ConflictKind::Ambiguous, _) | (_, ConflictStrictness::Warn) => {
+                        warnings.push(finding)
+                    }
+                    (_, ConflictStrictness::Error) => errors.push(finding),
+                }
+            }
+            if !self.upstream_services.contains_key(&route.service) {
+                errors.push(ConfigError::new(
+                    format!("{}.service", path),
+                    format!("{:?} is not a configured upstream service", route.service),
+                ));
+            }
+            if let Some(rewrite) = &route.rewrite {
+                if route.strip_prefix {
+                    errors.push(ConfigError::new(
+                        format!("{}.rewrite", path),
+                        "cannot be combined with strip_prefix; strip in the pattern instead",
+                    ));
+                }
+                if let Err(e) = rewrite.compile() {
+                    errors.push(ConfigError::new(format!("{}.rewrite.pattern", path), format!("invalid regex: {}", e)));
+                }
+            }
+            if let Some(Err((pattern, e))) = route.path_template.as_ref().map(|template| template.compile()) {
+                errors.push(ConfigError::new(
+                    format!("{}.path_template.patterns[{}].pattern", path, pattern),
+                    format!("invalid regex: {}", e),
+                ));
+            }
+        }
+
+        if let Some(service) = &self.default_service {
+            if !self.upstream_services.contains_key(service) {
+                errors.push(ConfigError::new(
+                    "default_service",
+                    format!("{:?} is not a configured upstream service", service),
+                ));
+            }
+        }
+
+        let mut holders: Vec<&String> = self.admin_auth.tokens.keys().collect();
+        holders.sort();
+        for holder in holders {
+            for service in self.admin_auth.tokens[holder].services.iter().flatten() {
+                if !self.upstream_services.contains_key(service) && !self.egress.services.contains_key(service) {
+                    errors.push(ConfigError::new(
+                        format!("admin_auth.tokens.{}.services", holder),
+                        format!("{:?} is not a configured service", service),
+                    ));
+                }
+            }
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.requests_per_second == 0 {
+                errors.push(ConfigError::new(
+                    "rate_limit.requests_per_second",
+                    "must be greater than 0; set no rate_limit to turn limiting off",
+                ));
+            }
+            match rate_limit.algorithm {
+                RateLimitAlgorithm::TokenBucket if rate_limit.burst_size == 0 => errors.push(ConfigError::new(
+                    "rate_limit.burst_size",
+                    "must be greater than 0, or no request is ever allowed",
+                )),
+                RateLimitAlgorithm::SlidingWindow
+                    if rate_limit.requests_per_second as u64 * rate_limit.window_size_ms < 1000 =>
+                {
+                    errors.push(ConfigError::new(
+                        "rate_limit.window_size_ms",
+                        "must fit at least one request at requests_per_second",
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        let mut egress: Vec<&String> = self.egress.services.keys().collect();
+        egress.sort();
+        for name in egress {
+            let service = &self.egress.services[name];
+            if service.kubernetes.is_some() {
+                errors.push(ConfigError::new(
+                    format!("egress.services.{}.kubernetes", name),
+                    "only upstream services can be discovered from Kubernetes",
+                ));
+            }
+            if service.consul.is_some() {
+                errors.push(ConfigError::new(
+                    format!("egress.services.{}.consul", name),
+                    "only upstream services can be discovered from Consul",
+                ));
+            }
+            validate_plaintext(service, &format!("egress.services.{}", name), &mut errors);
+        }
+
+        let responses = &self.upstream_responses;
+        if responses.max_header_bytes == 0 || responses.max_header_count == 0 {
+            errors.push(ConfigError::new("upstream_responses", "header limits must be above 0"));
+        }
+        if let Some(status) = responses.nonstandard_status.filter(|status| !upstream_response::is_standard_status(*status)) {
+            errors.push(ConfigError::new(
+                "upstream_responses.nonstandard_status",
+                format!("{} is not a standard status itself", status),
+            ));
+        }
+
+        let history = &self.metrics_config.history;
+        if history.enabled && (history.retention_hours == 0 || history.max_endpoints == 0 || history.tick_ms == 0) {
+            errors.push(ConfigError::new(
+                "metrics_config.history",
+                "retention_hours, max_endpoints and tick_ms must be above 0",
+            ));
+        }
+        if self.proxy_config.tls.is_some() && !cfg!(feature = "tls") {
+            errors.push(ConfigError::new("proxy_config.tls", NO_TLS));
+        }
+        if let Some(problem) = self.proxy_config.overload.problem() {
+            errors.push(ConfigError::new("proxy_config.overload", problem));
+        }
+        if let Some(problem) = self.proxy_config.upstream_pool.problem() {
+            errors.push(ConfigError::new("proxy_config.upstream_pool", problem));
+        }
+        if self.metrics_config.max_operations == 0 {
+            errors.push(ConfigError::new("metrics_config.max_operations", "must be greater than 0"));
+        }
+
+        let rollback = &self.proxy_config.config_rollback;
+        if rollback.enabled {
+            if !(0.0..1.0).contains(&rollback.max_error_rate_increase) {
+                errors.push(ConfigError::new(
+                    "proxy_config.config_rollback.max_error_rate_increase",
+                    format!("must be at least 0 and below 1, got {}", rollback.max_error_rate_increase),
+                ));
+            }
+            if rollback.observation_window_ms == 0 {
+                errors.push(ConfigError::new(
+                    "proxy_config.config_rollback.observation_window_ms",
+                    "must be greater than 0",
+                ));
+            }
+        }
+
+        let proxy = &self.proxy_config;
+        if proxy.retry_base_delay_ms > proxy.retry_max_delay_ms {
+            errors.push(ConfigError::new(
+                "proxy_config.retry_base_delay_ms",
+                format!("must not exceed retry_max_delay_ms ({})", proxy.retry_max_delay_ms),
+            ));
+        }
+        for (index, status) in proxy.retry_on_status.iter().enumerate() {
+            if !(400..=599).contains(status) {
+                errors.push(ConfigError::new(
+                    format!("proxy_config.retry_on_status[{}]", index),
+                    format!("{} is not an error status (400-599)", status),
+                ));
+            }
+        }
+        for (index, name) in proxy.request_dedupe_excluded_headers.iter().enumerate() {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                errors.push(ConfigError::new(
+                    format!("proxy_config.request_dedupe_excluded_headers[{}]", index),
+                    format!("{:?} is not a valid header name", name),
+                ));
+            }
+        }
+        let fd_monitor = &proxy.fd_monitor;
+        if fd_monitor.check_interval_ms == 0 {
+            errors.push(ConfigError::new("proxy_config.fd_monitor.check_interval_ms", "must be greater than 0"));
+        }
+        // At 0 the budget is always exceeded and shedding always on; above 1,
+        // never.
+        for (name, fraction) in [
+            ("safe_fraction", fd_monitor.safe_fraction),
+            ("high_water_fraction", fd_monitor.high_water_fraction),
+        ] {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                errors.push(ConfigError::new(
+                    format!("proxy_config.fd_monitor.{}", name),
+                    format!("must be greater than 0 and at most 1, got {}", fraction),
+                ));
+            }
+        }
+
+        if let Some(quota) = &self.quota {
+            if hyper::header::HeaderName::from_bytes(quota.key_header.as_bytes()).is_err() {
+                errors.push(ConfigError::new(
+                    "quota.key_header",
+                    format!("{:?} is not a valid header name", quota.key_header),
+                ));
+            }
+            if quota.persist_interval_ms == 0 {
+                errors.push(ConfigError::new("quota.persist_interval_ms", "must be greater than 0"));
+            }
+        }
+
+        if let Some(storage) = &self.storage {
+            if let Some(problem) = storage.problem() {
+                errors.push(ConfigError::new("storage", problem));
+            }
+            if self.ai_config.persist_path.is_some() {
+                errors.push(ConfigError::new("ai_config.persist_path", "unused with a storage section; remove it"));
+            }
+            if self.quota.as_ref().is_some_and(|quota| quota.persist_path.is_some()) {
+                errors.push(ConfigError::new("quota.persist_path", "unused with a storage section; remove it"));
+            }
+        }
+
+        let ai = &self.ai_config;
+        if !(ai.learning_rate > 0.0 && ai.learning_rate <= 1.0) {
+            errors.push(ConfigError::new(
+                "ai_config.learning_rate",
+                format!("must be greater than 0 and at most 1, got {}", ai.learning_rate),
+            ));
+        }
+        if !(0.0..=1.0).contains(&ai.decision_threshold) {
+            errors.push(ConfigError::new(
+                "ai_config.decision_threshold",
+                format!("must be between 0 and 1, got {}", ai.decision_threshold),
+            ));
+        }
+        if !(0.0..=1.0).contains(&ai.degraded_penalty) {
+            errors.push(ConfigError::new(
+                "ai_config.degraded_penalty",
+                format!("must be between 0 and 1, got {}", ai.degraded_penalty),
+            ));
+        }
+        if ai.rehabilitation_successes == 0 {
+            errors.push(ConfigError::new(
+                "ai_config.rehabilitation_successes",
+                "must be greater than 0; an evicted endpoint needs at least one passed probe to return",
+            ));
+        }
+        // Without the engine, switching it off is the only setting that means anything.
+        if !cfg!(feature = "ai") && *ai != (AIConfig { enabled: ai.enabled, ..AIConfig::default() }) {
+            errors.push(ConfigError::new("ai_config", "this build has no AI engine; rebuild with --features ai"));
+        }
+
+        Validation { errors, warnings }
+    }
+
+    // Rewrites every upstream and egress endpoint, and the endpoint weight
+    // keys, to their canonical form, dropping endpoints a service lists
+    // twice. Endpoints that do not canonicalize are left for `validate`.
+    pub fn canonicalize_endpoints(&mut self) {
+        for service in self.upstream_services.values_mut().chain(self.egress.services.values_mut()) {
+            service.canonicalize_endpoints();
+        }
+    }
+
+    // `base`, such as a file config, with every setting the environment set,
+    // including back to its `Config::new` value. Lists are replaced whole.
+    pub fn merge(base: Config, overrides: EnvOverrides) -> Config {
+        let EnvOverrides { config: overlay, paths } = overrides;
+        let mut merged = serde_json::to_value(&base).expect("config serializes");
+        let overlay_json = serde_json::to_value(&overlay).expect("config serializes");
+        for path in &paths {
+            override_path(&mut merged, &overlay_json, path);
+        }
+        let mut config: Config = serde_json::from_value(merged).expect("merged config deserializes");
+
+        for (services, from) in [
+            (&mut config.upstream_services, [&base.upstream_services, &overlay.upstream_services]),
+            (&mut config.egress.services, [&base.egress.services, &overlay.egress.services]),
+        ] {
+            for (name, service) in services.iter_mut() {
+                for originals in from.iter().filter_map(|services| services.get(name)) {
+                    service.endpoint_originals.extend(originals.endpoint_originals.clone());
+                }
+            }
+        }
+        config.canonicalize_endpoints();
+        config
+    }
+
+    // `validate`, plus the checks that need the port the proxy listens on.
+    pub fn validate_for_port(&self, proxy_port: u16) -> Result<(), Vec<ConfigError>> {
+        let mut errors = self.validate().err().unwrap_or_default();
+        if self.metrics_config.enabled && self.metrics_config.port == proxy_port {
+            errors.push(ConfigError::new(
+                "metrics_config.port",
+                format!("{} is also the proxy port; pick another or disable metrics", proxy_port),
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     pub fn new() -> Self {
         let mut upstream_services = HashMap::new();
         
         upstream_services.insert("service-a".to_string(), UpstreamService {
             name: "service-a".to_string(),
+            policy: None,
             endpoints: vec!["http://localhost:3001".to_string()],
-            health_check_path: "/health".to_string(),
-            timeout_ms: 5000,
-            max_retries: 3,
-            circuit_breaker_threshold: 5,
+            health_check_path: default_health_check_path(),
+            timeout_ms: default_timeout_ms(),
+            max_timeout_ms: None,
+            max_retries: default_max_retries(),
+            circuit_breaker_threshold: default_circuit_breaker_threshold(),
+            health_check_timeout_ms: None,
+            health_check_warn_latency_ms: None,
+            max_body_bytes: None,
+            tls: None,
+            auth: None,
+            content_coding: ContentCodingMode::default(),
+            address_family: AddressFamily::Auto,
+            hosts: HashMap::new(),
+            validate_with_head: false,
+            prewarm: None,
+            proxy_protocol: false,
+            breaker_probe: BreakerProbeConfig::default(),
+            endpoint_weights: None,
+            endpoint_priorities: None,
+            load_balancing_strategy: None,
+            auto_weight: None,
+            kubernetes: None,
+            consul: None,
+            header_rules: Vec::new(),
+            endpoint_originals: HashMap::new(),
         });
         
         upstream_services.insert("service-b".to_string(), UpstreamService {
             name: "service-b".to_string(),
+            policy: None,
             endpoints: vec!["http://localhost:3002".to_string()],
-            health_check_path: "/health".to_string(),
-            timeout_ms: 5000,
-            max_retries: 3,
-            circuit_breaker_threshold: 5,
+            health_check_path: default_health_check_path(),
+            timeout_ms: default_timeout_ms(),
+            max_timeout_ms: None,
+            max_retries: default_max_retries(),
+            circuit_breaker_threshold: default_circuit_breaker_threshold(),
+            health_check_timeout_ms: None,
+            health_check_warn_latency_ms: None,
+            max_body_bytes: None,
+            tls: None,
+            auth: None,
+            content_coding: ContentCodingMode::default(),
+            address_family: AddressFamily::Auto,
+            hosts: HashMap::new(),
+            validate_with_head: false,
+            prewarm: None,
+            proxy_protocol: false,
+            breaker_probe: BreakerProbeConfig::default(),
+            endpoint_weights: None,
+            endpoint_priorities: None,
+            load_balancing_strategy: None,
+            auto_weight: None,
+            kubernetes: None,
+            consul: None,
+            header_rules: Vec::new(),
+            endpoint_originals: HashMap::new(),
         });
 
         Self {
+            config_version: CURRENT_CONFIG_VERSION,
             upstream_services,
-            ai_config: AIConfig {
-                enabled: true,
-                decision_threshold: 0.7,
-                learning_rate: 0.01,
-                model_update_interval_ms: 60000,
+            ai_config: AIConfig::default(),
+            proxy_config: ProxyConfig::default(),
+            metrics_config: MetricsConfig::default(),
+            access_rules: AccessRulesConfig::default(),
+            mesh_metadata: MeshMetadataConfig::default(),
+            body_checksums: BodyChecksumConfig::default(),
+            egress: EgressConfig::default(),
+            archive: ArchiveConfig::default(),
+            experiments: ExperimentsConfig::default(),
+            capture: CaptureConfig::default(),
+            admin_auth: AdminAuthConfig::default(),
+            rate_limit: None,
+            quota: None,
+            storage: None,
+            routes: Vec::new(),
+            route_conflicts: ConflictStrictness::default(),
+            default_service: None,
+            response_headers: ResponseHeadersConfig::default(),
+            upstream_responses: UpstreamResponseConfig::default(),
+            policy_templates: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin_auth::AdminTokenConfig;
+    use crate::interpolate::ConfigTemplate;
+
+    fn paths(config: &Config) -> Vec<String> {
+        config.validate().unwrap_err().into_iter().map(|error| error.path().to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_config_is_valid() {
+        assert!(Config::new().validate().is_ok());
+        assert!(Config::new().validate_for_port(8080).is_ok());
+    }
+
+    #[test]
+    fn test_header_rules_need_rewritable_names() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().header_rules = vec![
+            HeaderRule::RemoveRequest { name: "authorization".to_string() },
+            HeaderRule::InjectRequest { name: "x token".to_string(), value: ConfigTemplate::parse("1") },
+            HeaderRule::RemoveResponse { name: "Transfer-Encoding".to_string() },
+        ];
+
+        assert_eq!(
+            paths(&config),
+            vec![
+                "upstream_services.service-a.header_rules[1].name",
+                "upstream_services.service-a.header_rules[2].name",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_rule_templates_checked() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().header_rules = serde_json::from_value(serde_json::json!([
+            {"type": "inject_request", "name": "x-tenant", "value": "${header:x-tenant|anonymous}"},
+            {"type": "inject_response", "name": "x-served-by", "value": "${service} ${nope}"},
+        ]))
+        .unwrap();
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "upstream_services.service-a.header_rules[1].value");
+        assert!(errors[0].message().contains("at byte 13: unknown variable `nope`"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_endpoints_must_be_present_and_http_urls() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoints.clear();
+        config.upstream_services.get_mut("service-b").unwrap().endpoints =
+            vec!["http://ok:80".to_string(), "localhost:3002".to_string(), "not a url".to_string()];
+
+        assert_eq!(
+            paths(&config),
+            vec![
+                "upstream_services.service-a.endpoints",
+                "upstream_services.service-b.endpoints[1]",
+                "upstream_services.service-b.endpoints[2]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_service_limits_and_health_path() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.timeout_ms = 0;
+        service.max_timeout_ms = Some(0);
+        service.health_check_warn_latency_ms = Some(0);
+        service.max_body_bytes = Some(0);
+        service.circuit_breaker_threshold = 0;
+        service.health_check_path = "health".to_string();
+
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|error| error.path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "upstream_services.service-a.health_check_path",
+                "upstream_services.service-a.timeout_ms",
+                "upstream_services.service-a.max_timeout_ms",
+                "upstream_services.service-a.health_check_warn_latency_ms",
+                "upstream_services.service-a.max_body_bytes",
+                "upstream_services.service-a.circuit_breaker_threshold",
+            ]
+        );
+        assert!(errors[0].to_string().contains("\"/health\""), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_endpoint_weights_name_known_endpoints() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoint_weights =
+            Some(HashMap::from([("http://localhost:3001".to_string(), 3), ("http://gone:3001".to_string(), 1)]));
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "upstream_services.service-a.endpoint_weights");
+        assert!(errors[0].message().contains("http://gone:3001"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_proxy_protocol_cannot_prewarm() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.proxy_protocol = true;
+        assert!(config.validate().is_ok());
+
+        config.upstream_services.get_mut("service-a").unwrap().prewarm =
+            Some(serde_json::from_value(serde_json::json!({"connections": 2})).unwrap());
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "upstream_services.service-a.prewarm");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_loaded_endpoints_are_canonical() {
+        let mut value = serde_json::to_value(Config::new()).unwrap();
+        let service = &mut value["upstream_services"]["service-a"];
+        service["endpoints"] =
+            serde_json::json!(["HTTP://LocalHost:3001/", "http://localhost:3001", "https://api.internal:443"]);
+        service["endpoint_weights"] = serde_json::json!({"http://LOCALHOST:3001/": 4});
+        let config = config_migration::upgrade(&value.to_string()).unwrap().config;
+
+        let service = &config.upstream_services["service-a"];
+        assert_eq!(service.endpoints, vec!["http://localhost:3001", "https://api.internal"]);
+        assert_eq!(service.endpoint_originals["http://localhost:3001"], "HTTP://LocalHost:3001/");
+        assert_eq!(service.endpoint_originals["https://api.internal"], "https://api.internal:443");
+        assert_eq!(service.endpoint_weights, Some(HashMap::from([("http://localhost:3001".to_string(), 4)])));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_without_scheme_says_so() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoints = vec!["localhost:3001".to_string()];
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message().contains("has no scheme"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_load_balancing_strategy_must_be_known() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().load_balancing_strategy = Some("least_connections".to_string());
+        config.upstream_services.get_mut("service-b").unwrap().load_balancing_strategy = Some("fastest".to_string());
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "upstream_services.service-b.load_balancing_strategy");
+        assert!(errors[0].message().contains("\"fastest\""), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_auto_weight_needs_weighted_strategy() {
+        let mut config = Config::new();
+        let service_a = config.upstream_services.get_mut("service-a").unwrap();
+        service_a.auto_weight = Some(AutoWeightConfig::default());
+        service_a.load_balancing_strategy = Some("weighted_round_robin".to_string());
+        assert!(config.validate().is_ok());
+
+        let service_b = config.upstream_services.get_mut("service-b").unwrap();
+        service_b.auto_weight = Some(AutoWeightConfig {
+            min_weight: 0,
+            ..AutoWeightConfig::default()
+        });
+        assert_eq!(
+            paths(&config),
+            vec!["upstream_services.service-b.auto_weight", "upstream_services.service-b.auto_weight.min_weight"]
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_must_admit_requests() {
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "config_version": CURRENT_CONFIG_VERSION,
+            "upstream_services": {},
+            "rate_limit": { "requests_per_second": 5, "burst_size": 0 },
+        }))
+        .unwrap();
+        assert_eq!(paths(&config), vec!["rate_limit.burst_size"]);
+
+        config.rate_limit = Some(RateLimitConfig {
+            requests_per_second: 2,
+            window_size_ms: 100,
+            algorithm: RateLimitAlgorithm::SlidingWindow,
+            ..RateLimitConfig::default()
+        });
+        assert_eq!(paths(&config), vec!["rate_limit.window_size_ms"]);
+    }
+
+    #[test]
+    fn test_config_rollback_guardrail_in_range() {
+        let mut config = Config::new();
+        config.proxy_config.config_rollback = ConfigRollbackConfig {
+            enabled: true,
+            max_error_rate_increase: 1.0,
+            observation_window_ms: 0,
+            ..ConfigRollbackConfig::default()
+        };
+        assert_eq!(
+            paths(&config),
+            vec![
+                "proxy_config.config_rollback.max_error_rate_increase",
+                "proxy_config.config_rollback.observation_window_ms"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_within_its_cap() {
+        let mut config = Config::new();
+        config.proxy_config.retry_base_delay_ms = 500;
+        config.proxy_config.retry_max_delay_ms = 200;
+        assert_eq!(paths(&config), vec!["proxy_config.retry_base_delay_ms"]);
+
+        config.proxy_config.retry_base_delay_ms = 100;
+        config.proxy_config.retry_max_delay_ms = 350;
+        let delays: Vec<u64> = (0..4).map(|retry| config.proxy_config.retry_delay(retry).as_millis() as u64).collect();
+        assert_eq!(delays, vec![100, 200, 350, 350]);
+        assert_eq!(config.proxy_config.retry_delay(200), Duration::from_millis(350));
+        for retry in 0..4 {
+            let jittered = config.proxy_config.jittered_retry_delay(retry);
+            let delay = config.proxy_config.retry_delay(retry);
+            assert!(jittered >= delay / 2 && jittered <= delay, "{:?} outside {:?}", jittered, delay);
+        }
+
+        config.proxy_config.retry_on_status = vec![503, 200];
+        assert_eq!(paths(&config), vec!["proxy_config.retry_on_status[1]"]);
+    }
+
+    #[test]
+    fn test_fd_monitor_settings_in_range() {
+        let mut config = Config::new();
+        config.proxy_config.fd_monitor = FdMonitorConfig {
+            check_interval_ms: 0,
+            safe_fraction: 0.0,
+            high_water_fraction: 1.5,
+            ..FdMonitorConfig::default()
+        };
+        assert_eq!(
+            paths(&config),
+            vec![
+                "proxy_config.fd_monitor.check_interval_ms",
+                "proxy_config.fd_monitor.safe_fraction",
+                "proxy_config.fd_monitor.high_water_fraction"
+            ]
+        );
+
+        config.proxy_config.fd_monitor = FdMonitorConfig {
+            safe_fraction: 1.0,
+            high_water_fraction: f64::NAN,
+            ..FdMonitorConfig::default()
+        };
+        assert_eq!(paths(&config), vec!["proxy_config.fd_monitor.high_water_fraction"]);
+    }
+
+    #[test]
+    fn test_kubernetes_discovery_replaces_endpoints() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.clear();
+        service.kubernetes = Some(KubernetesDiscoveryConfig {
+            service: "orders".to_string(),
+            ..KubernetesDiscoveryConfig::default()
+        });
+        let service = config.upstream_services.get_mut("service-b").unwrap();
+        service.kubernetes = Some(KubernetesDiscoveryConfig {
+            scheme: "grpc".to_string(),
+            retry_initial_ms: 0,
+            ..KubernetesDiscoveryConfig::default()
+        });
+
+        let mut expected = vec![
+            "upstream_services.service-b.kubernetes",
+            "upstream_services.service-b.kubernetes.scheme",
+            "upstream_services.service-b.kubernetes.retry_initial_ms",
+        ];
+        if !cfg!(feature = "kubernetes") {
+            expected.insert(0, "upstream_services.service-a.kubernetes");
+            expected.insert(1, "upstream_services.service-b.kubernetes");
+        }
+        assert_eq!(paths(&config), expected);
+    }
+
+    #[test]
+    fn test_consul_discovery_replaces_endpoints() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.clear();
+        service.consul = Some(ConsulDiscoveryConfig {
+            service: "orders".to_string(),
+            tag: Some("v2".to_string()),
+            ..ConsulDiscoveryConfig::default()
+        });
+        assert!(config.validate().is_ok());
+
+        let service = config.upstream_services.get_mut("service-b").unwrap();
+        service.consul = Some(ConsulDiscoveryConfig {
+            address: "127.0.0.1:8500".to_string(),
+            poll_interval_ms: 0,
+            ..ConsulDiscoveryConfig::default()
+        });
+        assert_eq!(
+            paths(&config),
+            vec![
+                "upstream_services.service-b.consul",
+                "upstream_services.service-b.consul.address",
+                "upstream_services.service-b.consul.poll_interval_ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nonstandard_status_replacement_must_be_standard() {
+        let mut config = Config::new();
+        config.upstream_responses.nonstandard_status = Some(599);
+        assert_eq!(paths(&config), vec!["upstream_responses.nonstandard_status"]);
+        config.upstream_responses.nonstandard_status = None;
+        config.upstream_responses.max_header_count = 0;
+        assert_eq!(paths(&config), vec!["upstream_responses"]);
+    }
+
+    #[test]
+    fn test_stats_history_bounds_must_be_set() {
+        let mut config = Config::new();
+        config.metrics_config.history.max_endpoints = 0;
+        assert_eq!(paths(&config), vec!["metrics_config.history"]);
+        config.metrics_config.history.enabled = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_quota_needs_a_header_name() {
+        let mut config = Config::new();
+        config.quota = Some(QuotaConfig {
+            key_header: "x api key".to_string(),
+            persist_interval_ms: 0,
+            ..QuotaConfig::default()
+        });
+        assert_eq!(paths(&config), vec!["quota.key_header", "quota.persist_interval_ms"]);
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn test_storage_replaces_persist_paths() {
+        let mut config = Config::new();
+        config.ai_config.persist_path = Some(PathBuf::from("ai.json"));
+        config.quota = Some(QuotaConfig { persist_path: Some(PathBuf::from("quotas.json")), ..QuotaConfig::default() });
+        assert!(config.validate().is_ok());
+
+        config.storage = Some(StorageConfig::default());
+        assert_eq!(paths(&config), vec!["storage", "ai_config.persist_path", "quota.persist_path"]);
+        config.ai_config.persist_path = None;
+        config.quota = Some(QuotaConfig::default());
+        config.storage = Some(StorageConfig { path: Some(PathBuf::from("state")), ..StorageConfig::default() });
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_route_rewrite_must_compile() {
+        let mut config = Config::new();
+        config.routes = serde_json::from_value(serde_json::json!([
+            { "path_prefix": "/api", "service": "service-a", "rewrite": { "pattern": "^/api/(.*)", "replacement": "/v2/$1" } },
+            { "path_prefix": "/old", "service": "service-a", "rewrite": { "pattern": "^/old/(.*", "replacement": "/$1" } },
+            { "path_prefix": "/new", "service": "service-b", "strip_prefix": true, "rewrite": { "pattern": "^/new", "replacement": "" } },
+        ]))
+        .unwrap();
+
+        assert_eq!(paths(&config), vec!["routes[1].rewrite.pattern", "routes[2].rewrite"]);
+    }
+
+    #[test]
+    fn test_routes_name_known_services_once() {
+        let mut config = Config::new();
+        config.routes = vec![
+            RouteRule {
+                path_prefix: "/orders".to_string(),
+                service: "service-a".to_string(),
+                strip_prefix: true,
+                ..RouteRule::default()
             },
-            proxy_config: ProxyConfig {
-                max_connections: 10000,
-                connection_timeout_ms: 30000,
-                request_timeout_ms: 30000,
-                buffer_size: 8192,
+            RouteRule {
+                path_prefix: "/orders/".to_string(),
+                service: "service-b".to_string(),
+                strip_prefix: false,
+                ..RouteRule::default()
             },
-            metrics_config: MetricsConfig {
-                enabled: true,
-                port: 9090,
-                path: "/metrics".to_string(),
+            RouteRule {
+                path_prefix: "billing".to_string(),
+                service: "billing".to_string(),
+                strip_prefix: false,
+                ..RouteRule::default()
             },
-        }
+        ];
+
+        assert_eq!(paths(&config), vec!["routes[1].path_prefix", "routes[2].path_prefix", "routes[2].service"]);
+    }
+
+    #[test]
+    fn test_shadowed_host_route_is_reported() {
+        let mut config = Config::new();
+        config.routes = serde_json::from_value(serde_json::json!([
+            {"host": "shop.example", "path_prefix": "/cart", "service": "service-a", "priority": 10},
+            {"host": "Shop.Example", "path_prefix": "/cart/items", "service": "service-b"},
+            {"host": "admin.example", "path_prefix": "/cart/items", "service": "service-b"},
+        ]))
+        .unwrap();
+        assert_eq!(paths(&config), vec!["routes[1].path_prefix"]);
+
+        config.route_conflicts = ConflictStrictness::Warn;
+        let validation = config.validation();
+        assert!(validation.errors.is_empty());
+        assert_eq!(validation.warnings.len(), 1);
+        assert_eq!(validation.warnings[0].path(), "routes[1].path_prefix");
+        assert!(validation.warnings[0].message().contains("routes[0]"), "{}", validation.warnings[0]);
+    }
+
+    #[test]
+    fn test_catch_all_route_only_warns() {
+        let mut config = Config::new();
+        config.routes = serde_json::from_value(serde_json::json!([
+            {"path_prefix": "/", "service": "service-a"},
+            {"path_prefix": "/orders", "service": "service-b"},
+        ]))
+        .unwrap();
+
+        let validation = config.validation();
+        assert!(validation.errors.is_empty(), "{:?}", validation.errors);
+        assert_eq!(validation.warnings.len(), 1);
+        assert_eq!(validation.warnings[0].path(), "routes[0].path_prefix");
+        assert!(validation.warnings[0].message().contains("every path"), "{}", validation.warnings[0]);
+    }
+
+    #[test]
+    fn test_path_templates_checked() {
+        let mut config = Config::new();
+        config.routes = serde_json::from_value(serde_json::json!([
+            {"path_prefix": "/users", "service": "service-a", "path_template": {"collapse": ["numeric"]}},
+            {"path_prefix": "/files", "service": "service-a", "path_template": {"patterns": [
+                {"pattern": "/files/[^/]+", "template": "/files/{name}"},
+                {"pattern": "/files/(", "template": "/files"},
+            ]}},
+        ]))
+        .unwrap();
+        config.metrics_config.max_operations = 0;
+
+        assert_eq!(
+            paths(&config),
+            vec!["routes[1].path_template.patterns[1].pattern", "metrics_config.max_operations"]
+        );
+    }
+
+    #[test]
+    fn test_default_service_must_be_configured() {
+        let mut config = Config::new();
+        config.default_service = Some("service-b".to_string());
+        assert!(config.validate().is_ok());
+        config.default_service = Some("service-a ".to_string());
+        assert_eq!(paths(&config), vec!["default_service"]);
+    }
+
+    #[test]
+    fn test_route_patterns_checked() {
+        let mut config = Config::new();
+        config.routes = serde_json::from_value(serde_json::json!([
+            {"path_pattern": {"wildcard": "/api/*/export"}, "service": "service-a"},
+            {"path_pattern": {"wildcard": "/api/*/export"}, "service": "service-b", "priority": 5},
+            {"path_pattern": {"regex": "/items/(\\d+"}, "service": "service-a"},
+            {"path_prefix": "/x", "path_pattern": {"regex": "/x/.*"}, "service": "service-a"},
+            {"path_pattern": {"wildcard": "reports/**"}, "service": "service-a", "strip_prefix": true},
+            {"path_prefix": "/api", "service": "service-a"},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            paths(&config),
+            vec![
+                "routes[1].path_pattern",
+                "routes[2].path_pattern",
+                "routes[3].path_pattern",
+                "routes[4].path_pattern",
+                "routes[4].strip_prefix",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_admin_token_scopes_name_known_services() {
+        let mut config = Config::new();
+        config.admin_auth.tokens.insert(
+            "team".to_string(),
+            AdminTokenConfig {
+                token: "t".to_string(),
+                services: Some(vec!["service-a".to_string(), "service-z".to_string()]),
+            },
+        );
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "admin_auth.tokens.team.services");
+        assert!(errors[0].message().contains("service-z"), "{}", errors[0]);
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn test_ai_ranges() {
+        let mut config = Config::new();
+        config.ai_config.learning_rate = 1.0;
+        config.ai_config.decision_threshold = 0.0;
+        assert!(config.validate().is_ok());
+
+        config.ai_config.learning_rate = 0.0;
+        config.ai_config.decision_threshold = 1.5;
+        assert_eq!(paths(&config), vec!["ai_config.learning_rate", "ai_config.decision_threshold"]);
+
+        config.ai_config.learning_rate = f64::NAN;
+        config.ai_config.decision_threshold = -0.1;
+        assert_eq!(paths(&config), vec!["ai_config.learning_rate", "ai_config.decision_threshold"]);
+
+        config.ai_config.learning_rate = 0.5;
+        config.ai_config.decision_threshold = 0.5;
+        config.ai_config.degraded_penalty = 1.1;
+        assert_eq!(paths(&config), vec!["ai_config.degraded_penalty"]);
+        config.ai_config.degraded_penalty = 0.5;
+        config.ai_config.rehabilitation_successes = 0;
+        assert_eq!(paths(&config), vec!["ai_config.rehabilitation_successes"]);
+    }
+
+    #[cfg(not(feature = "ai"))]
+    #[test]
+    fn test_ai_settings_need_the_ai_feature() {
+        let mut config = Config::new();
+        config.ai_config.enabled = false;
+        assert!(config.validate().is_ok());
+
+        config.ai_config.decision_threshold = 0.5;
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "ai_config");
+        assert!(errors[0].message().contains("--features ai"), "{}", errors[0]);
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn test_https_needs_the_tls_feature() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.push("HTTPS://api.internal".to_string());
+        service.tls = Some(UpstreamTlsConfig::default());
+        config.proxy_config.tls = Some(ListenerTlsConfig {
+            cert_path: "cert.pem".to_string(),
+            key_path: "key.pem".to_string(),
+            session_cache_size: 256,
+            ticket_rotation_secs: 3600,
+            ticket_key_path: None,
+            cert_reload_secs: 60,
+            min_version: TlsVersion::Tls12,
+        });
+        let errors = config.validate().unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|error| error.path()).collect();
+        assert_eq!(
+            paths,
+            vec!["upstream_services.service-a.tls", "upstream_services.service-a.endpoints[1]", "proxy_config.tls"]
+        );
+        assert!(errors.iter().all(|error| error.message().contains("--features tls")));
+    }
+
+    #[test]
+    fn test_metrics_port_must_differ_from_proxy_port() {
+        let mut config = Config::new();
+        let errors = config.validate_for_port(9090).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "metrics_config.port");
+
+        config.metrics_config.enabled = false;
+        assert!(config.validate_for_port(9090).is_ok());
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn test_all_errors_reported_together() {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().timeout_ms = 0;
+        config.ai_config.learning_rate = 2.0;
+
+        let errors = config.validate_for_port(9090).unwrap_err();
+        assert_eq!(
+            invalid_config(&errors).to_string(),
+            "invalid config:\n  at `upstream_services.service-a.timeout_ms`: must be greater than 0\n  \
+             at `ai_config.learning_rate`: must be greater than 0 and at most 1, got 2\n  \
+             at `metrics_config.port`: 9090 is also the proxy port; pick another or disable metrics"
+        );
     }
 }
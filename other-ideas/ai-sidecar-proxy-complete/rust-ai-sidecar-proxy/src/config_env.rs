@@ -0,0 +1,243 @@
+use crate::config::Config;
+use crate::config_migration::{self, KeyError};
+use serde_json::Value;
+use std::{collections::HashMap, fmt};
+
+// Settings read from `{PREFIX}_{PATH}` variables, where PATH is the field's
+// key path in upper case with `_` between keys and `_config` dropped from the
+// top-level section: `proxy_config.max_connections` is
+// `{PREFIX}_PROXY_MAX_CONNECTIONS`. Fields nobody set keep their
+// `Config::new` value. Lists take JSON or a comma-separated string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    // The variable's text is not a value of the field's type.
+    Invalid { variable: String, message: String },
+    // Carries the prefix but names no field.
+    Unknown { variable: String },
+    // Every variable parsed but the config as a whole was rejected.
+    Rejected { message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Invalid { variable, message } => write!(f, "{}: {}", variable, message),
+            ConfigError::Unknown { variable } => write!(f, "{}: no such setting", variable),
+            ConfigError::Rejected { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// The environment a config is read from; the process's own, or one built up
+// in code.
+#[derive(Debug, Clone, Default)]
+pub struct EnvLoader(HashMap<String, String>);
+
+impl EnvLoader {
+    pub fn from_process() -> Self {
+        Self(std::env::vars().collect())
+    }
+
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.0.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn load(&self, prefix: &str) -> Result<Config, ConfigError> {
+        self.overrides(prefix).map(|overrides| overrides.config)
+    }
+
+    // The config `load` reads, with the key paths of the variables that were
+    // set, for merging over a file.
+    pub fn overrides(&self, prefix: &str) -> Result<EnvOverrides, ConfigError> {
+        let mut value = serde_json::to_value(Config::new()).expect("default config serializes");
+        let mut fields = Vec::new();
+        collect_fields(&value, "", prefix, &mut fields);
+
+        let mut by_path = HashMap::new();
+        for (path, variable) in &fields {
+            let Some(text) = self.0.get(variable) else {
+                continue;
+            };
+            let slot = slot(&mut value, path);
+            *slot = parse_value(slot, text).map_err(|message| ConfigError::Invalid {
+                variable: variable.clone(),
+                message,
+            })?;
+            by_path.insert(path.as_str(), variable.as_str());
+        }
+
+        let marker = format!("{}_", prefix);
+        let mut unknown: Vec<&String> = self
+            .0
+            .keys()
+            .filter(|name| name.starts_with(&marker) && !fields.iter().any(|(_, variable)| variable == *name))
+            .collect();
+        unknown.sort();
+        if let Some(variable) = unknown.first() {
+            return Err(ConfigError::Unknown {
+                variable: variable.to_string(),
+            });
+        }
+
+        let json = serde_json::to_string_pretty(&value).expect("JSON value serializes");
+        let mut paths: Vec<String> = by_path.keys().map(|path| path.to_string()).collect();
+        paths.sort();
+        let config = config_migration::upgrade(&json).map(|upgraded| upgraded.config).map_err(|e| {
+            let key_error = e.chain().find_map(|cause| cause.downcast_ref::<KeyError>());
+            match key_error.and_then(|key_error| variable_for(&by_path, &key_error.path).map(|variable| (key_error, variable))) {
+                Some((key_error, variable)) => ConfigError::Invalid {
+                    variable: variable.to_string(),
+                    message: key_error.message(),
+                },
+                None => ConfigError::Rejected {
+                    message: format!("{:#}", e),
+                },
+            }
+        })?;
+        Ok(EnvOverrides { config, paths })
+    }
+}
+
+// Settings read from the environment. Only the fields at `paths` were set;
+// the rest of `config` is `Config::new`.
+#[derive(Debug, Clone)]
+pub struct EnvOverrides {
+    pub config: Config,
+    pub paths: Vec<String>,
+}
+
+impl Config {
+    pub fn from_env(prefix: &str) -> Result<Self, ConfigError> {
+        EnvLoader::from_process().load(prefix)
+    }
+
+    pub fn env_overrides(prefix: &str) -> Result<EnvOverrides, ConfigError> {
+        EnvLoader::from_process().overrides(prefix)
+    }
+}
+
+// Every settable leaf of the default config, with its variable name. Empty
+// maps have no leaves, so entries cannot be added to them this way.
+fn collect_fields(value: &Value, path: &str, variable: &str, out: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                let name = if path.is_empty() { key.strip_suffix("_config").unwrap_or(key) } else { key };
+                let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                collect_fields(child, &path, &format!("{}_{}", variable, env_name(name)), out);
+            }
+        }
+        _ => out.push((path.to_string(), variable.to_string())),
+    }
+}
+
+fn env_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+fn slot<'a>(value: &'a mut Value, path: &str) -> &'a mut Value {
+    path.split('.').fold(value, |value, key| &mut value[key])
+}
+
+// Read as the type the default has; an unset optional field takes JSON, and
+// plain text when that does not parse.
+fn parse_value(default: &Value, text: &str) -> Result<Value, String> {
+    match default {
+        Value::Bool(_) => text.parse::<bool>().map(Value::Bool).map_err(|e| e.to_string()),
+        Value::Number(number) if number.is_f64() => text
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| format!("expected a number, got {:?}", text)),
+        Value::Number(_) => text
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|e| format!("expected an integer, got {:?}: {}", text, e)),
+        Value::String(_) => Ok(Value::String(text.to_string())),
+        Value::Array(_) if text.trim_start().starts_with('[') => {
+            serde_json::from_str(text).map_err(|e| format!("invalid JSON list: {}", e))
+        }
+        Value::Array(_) => Ok(Value::Array(
+            text.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        )),
+        Value::Null | Value::Object(_) => Ok(serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))),
+    }
+}
+
+// The variable behind an error path like "a.b[1].c", or behind its nearest
+// parent that was set.
+fn variable_for<'a>(by_path: &HashMap<&str, &'a str>, path: &str) -> Option<&'a str> {
+    let mut path = path;
+    loop {
+        if let Some(variable) = by_path.get(path) {
+            return Some(variable);
+        }
+        path = &path[..path.rfind(['.', '['])?];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unset_variables_keep_defaults() {
+        let config = EnvLoader::empty().var("OTHER_PROXY_MAX_CONNECTIONS", "1").load("SIDECAR").unwrap();
+        assert_eq!(serde_json::to_value(config).unwrap(), serde_json::to_value(Config::new()).unwrap());
+    }
+
+    #[test]
+    fn test_variables_override_nested_fields() {
+        let config = EnvLoader::empty()
+            .var("SIDECAR_PROXY_MAX_CONNECTIONS", "2048")
+            .var("SIDECAR_AI_LEARNING_RATE", "0.25")
+            .var("SIDECAR_AI_ENABLED", "false")
+            .var("SIDECAR_PROXY_CLIENT_TIMEOUTS_HEADER_READ_MS", "1500")
+            .var("SIDECAR_UPSTREAM_SERVICES_SERVICE_A_ENDPOINTS", "http://a-1:80, http://a-2:80")
+            .var("SIDECAR_METRICS_PATH", "/stats")
+            .load("SIDECAR")
+            .unwrap();
+
+        assert_eq!(config.proxy_config.max_connections, 2048);
+        assert_eq!(config.ai_config.learning_rate, 0.25);
+        assert!(!config.ai_config.enabled);
+        assert_eq!(config.proxy_config.client_timeouts.header_read_ms, 1500);
+        assert_eq!(config.upstream_services["service-a"].endpoints, vec!["http://a-1", "http://a-2"]);
+        assert_eq!(config.metrics_config.path, "/stats");
+    }
+
+    #[test]
+    fn test_errors_name_the_variable() {
+        let error = EnvLoader::empty().var("SIDECAR_PROXY_MAX_CONNECTIONS", "lots").load("SIDECAR").unwrap_err();
+        assert!(
+            matches!(&error, ConfigError::Invalid { variable, .. } if variable == "SIDECAR_PROXY_MAX_CONNECTIONS"),
+            "{}",
+            error
+        );
+
+        let error = EnvLoader::empty().var("SIDECAR_PROXY_TLS_ON_PLAINTEXT", "shout").load("SIDECAR").unwrap_err();
+        assert!(error.to_string().starts_with("SIDECAR_PROXY_TLS_ON_PLAINTEXT: unknown variant"), "{}", error);
+
+        let error = EnvLoader::empty().var("SIDECAR_PROXY_MAX_CONECTIONS", "10").load("SIDECAR").unwrap_err();
+        assert_eq!(
+            error,
+            ConfigError::Unknown {
+                variable: "SIDECAR_PROXY_MAX_CONECTIONS".to_string()
+            }
+        );
+    }
+}
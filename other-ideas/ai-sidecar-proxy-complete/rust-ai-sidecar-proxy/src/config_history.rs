@@ -0,0 +1,386 @@
+use crate::{clock::Clock, config::UpstreamService, config_reload::ReloadDiff};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+// Older entries are dropped from /admin/config/history.
+const MAX_ENTRIES: usize = 20;
+
+// Guardrail on reloads: a reload whose error rate climbs too far above the
+// rate before it is reverted to the services it replaced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigRollbackConfig {
+    pub enabled: bool,
+    // How long after a reload its requests are watched. A reload that makes
+    // it through is kept.
+    pub observation_window_ms: u64,
+    // The error rate a reload is compared with is measured over this long
+    // before it.
+    pub baseline_window_ms: u64,
+    // Reverted once the share of 5xx and unroutable requests since the reload
+    // is more than this above the baseline.
+    pub max_error_rate_increase: f64,
+    // Requests since the reload before it is judged at all.
+    pub min_requests: u64,
+    // POSTed a JSON description of every automatic rollback.
+    pub webhook_url: Option<String>,
+}
+
+impl Default for ConfigRollbackConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            observation_window_ms: 60_000,
+            baseline_window_ms: 300_000,
+            max_error_rate_increase: 0.2,
+            min_requests: 20,
+            webhook_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Startup,
+    Reload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigStatus {
+    // Running, and still inside its observation window.
+    Observing,
+    Active,
+    // Replaced by a later reload, or by a rollback to an earlier config.
+    Superseded,
+    // Rolled back, automatically or by an operator; never chosen as a
+    // rollback target.
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub version: u64,
+    // Unix seconds.
+    pub applied_at: u64,
+    pub source: ConfigSource,
+    pub status: ConfigStatus,
+    pub diff: ReloadDiff,
+    // Why it was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip)]
+    pub services: HashMap<String, UpstreamService>,
+}
+
+// What to put back, and why.
+#[derive(Debug, Clone)]
+pub struct Rollback {
+    pub rejected: u64,
+    pub restore: u64,
+    pub services: HashMap<String, UpstreamService>,
+    pub reason: String,
+}
+
+struct Observation {
+    version: u64,
+    until: Instant,
+    baseline: f64,
+    requests: u64,
+    errors: u64,
+}
+
+struct History {
+    entries: VecDeque<HistoryEntry>,
+    next_version: u64,
+    observation: Option<Observation>,
+    // Requests and errors per second, for the baseline.
+    buckets: VecDeque<(u64, u64, u64)>,
+}
+
+pub struct ConfigHistory {
+    config: ConfigRollbackConfig,
+    clock: Arc<dyn Clock>,
+    origin: Instant,
+    history: Mutex<History>,
+}
+
+impl ConfigHistory {
+    pub fn new(config: ConfigRollbackConfig, clock: Arc<dyn Clock>, services: HashMap<String, UpstreamService>) -> Self {
+        let origin = clock.now();
+        let history = Self {
+            config,
+            clock,
+            origin,
+            history: Mutex::new(History {
+                entries: VecDeque::new(),
+                next_version: 1,
+                observation: None,
+                buckets: VecDeque::new(),
+            }),
+        };
+        history.push(ConfigSource::Startup, ReloadDiff::default(), services);
+        history
+    }
+
+    pub fn config(&self) -> &ConfigRollbackConfig {
+        &self.config
+    }
+
+    // Records reloaded services, watched when rollback is enabled.
+    pub fn reloaded(&self, diff: ReloadDiff, services: HashMap<String, UpstreamService>) -> u64 {
+        self.push(ConfigSource::Reload, diff, services)
+    }
+
+    fn push(&self, source: ConfigSource, diff: ReloadDiff, services: HashMap<String, UpstreamService>) -> u64 {
+        let now = self.clock.now();
+        let mut history = self.history.lock().unwrap();
+        let version = history.next_version;
+        history.next_version += 1;
+        for entry in history.entries.iter_mut() {
+            if matches!(entry.status, ConfigStatus::Active | ConfigStatus::Observing) {
+                entry.status = ConfigStatus::Superseded;
+            }
+        }
+        let observe = self.config.enabled && source == ConfigSource::Reload;
+        let baseline = self.baseline(&mut history, now);
+        history.observation = observe.then(|| Observation {
+            version,
+            until: now + Duration::from_millis(self.config.observation_window_ms),
+            baseline,
+            requests: 0,
+            errors: 0,
+        });
+        history.entries.push_back(HistoryEntry {
+            version,
+            applied_at: self.clock.wall().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            source,
+            status: if observe { ConfigStatus::Observing } else { ConfigStatus::Active },
+            diff,
+            reason: None,
+            services,
+        });
+        while history.entries.len() > MAX_ENTRIES {
+            history.entries.pop_front();
+        }
+        version
+    }
+
+    // The error rate over `baseline_window_ms`; 0 with too few requests to
+    // say.
+    fn baseline(&self, history: &mut History, now: Instant) -> f64 {
+        self.expire_buckets(history, now);
+        let (requests, errors) = history
+            .buckets
+            .iter()
+            .fold((0, 0), |(requests, errors), (_, r, e)| (requests + r, errors + e));
+        if requests < self.config.min_requests.max(1) {
+            return 0.0;
+        }
+        errors as f64 / requests as f64
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.duration_since(self.origin).as_secs()
+    }
+
+    fn expire_buckets(&self, history: &mut History, now: Instant) {
+        let oldest = self
+            .second(now)
+            .saturating_sub(Duration::from_millis(self.config.baseline_window_ms).as_secs());
+        while history.buckets.front().is_some_and(|(second, _, _)| *second < oldest) {
+            history.buckets.pop_front();
+        }
+    }
+
+    // Counts one routed request. Returns the rollback to carry out when this
+    // request pushed a watched reload over the guardrail.
+    pub fn record(&self, error: bool) -> Option<Rollback> {
+        if !self.config.enabled {
+            return None;
+        }
+        let now = self.clock.now();
+        let second = self.second(now);
+        let mut history = self.history.lock().unwrap();
+        match history.buckets.back_mut() {
+            Some((last, requests, errors)) if *last == second => {
+                *requests += 1;
+                *errors += u64::from(error);
+            }
+            _ => history.buckets.push_back((second, 1, u64::from(error))),
+        }
+        self.expire_buckets(&mut history, now);
+
+        self.conclude_if_over(&mut history, now);
+        let observation = history.observation.as_mut()?;
+        observation.requests += 1;
+        observation.errors += u64::from(error);
+        if observation.requests < self.config.min_requests.max(1) {
+            return None;
+        }
+        let rate = observation.errors as f64 / observation.requests as f64;
+        if rate <= observation.baseline + self.config.max_error_rate_increase {
+            return None;
+        }
+        let reason = format!(
+            "error rate {:.2} over {} requests since the reload, against {:.2} before it",
+            rate, observation.requests, observation.baseline
+        );
+        let version = observation.version;
+        history.observation = None;
+        self.reject(&mut history, version, reason)
+    }
+
+    // Ends an observation whose window has passed; the reload is kept.
+    fn conclude_if_over(&self, history: &mut History, now: Instant) {
+        let Some(observation) = &history.observation else {
+            return;
+        };
+        if now < observation.until {
+            return;
+        }
+        let version = observation.version;
+        history.observation = None;
+        if let Some(entry) = history.entries.iter_mut().find(|entry| entry.version == version) {
+            entry.status = ConfigStatus::Active;
+        }
+    }
+
+    // Rejects the running config in favour of the last one before it that
+    // was not rejected. None when there is nothing to go back to.
+    pub fn rollback(&self, reason: String) -> Option<Rollback> {
+        let mut history = self.history.lock().unwrap();
+        let version = history
+            .entries
+            .iter()
+            .find(|entry| matches!(entry.status, ConfigStatus::Active | ConfigStatus::Observing))?
+            .version;
+        history.observation = None;
+        self.reject(&mut history, version, reason)
+    }
+
+    // The caller applies the returned services; the restored entry is marked
+    // running here, so a second rollback goes further back.
+    fn reject(&self, history: &mut History, version: u64, reason: String) -> Option<Rollback> {
+        let current = history.entries.iter().position(|entry| entry.version == version)?;
+        let target = history.entries.range(..current).rposition(|entry| entry.status != ConfigStatus::Rejected)?;
+        let restored = &mut history.entries[target];
+        restored.status = ConfigStatus::Active;
+        let rollback = Rollback {
+            rejected: version,
+            restore: restored.version,
+            services: restored.services.clone(),
+            reason: reason.clone(),
+        };
+        let rejected = &mut history.entries[current];
+        rejected.status = ConfigStatus::Rejected;
+        rejected.reason = Some(reason);
+        Some(rollback)
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        let mut history = self.history.lock().unwrap();
+        self.conclude_if_over(&mut history, self.clock.now());
+        history.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, config::Config};
+
+    fn enabled() -> ConfigRollbackConfig {
+        ConfigRollbackConfig {
+            enabled: true,
+            min_requests: 10,
+            ..ConfigRollbackConfig::default()
+        }
+    }
+
+    fn services(endpoint: &str) -> HashMap<String, UpstreamService> {
+        let mut services = Config::new().upstream_services;
+        services.get_mut("service-a").unwrap().endpoints = vec![endpoint.to_string()];
+        services
+    }
+
+    fn statuses(history: &ConfigHistory) -> Vec<(u64, ConfigStatus)> {
+        history.entries().iter().map(|entry| (entry.version, entry.status)).collect()
+    }
+
+    #[test]
+    fn test_rolls_back_reload_above_baseline() {
+        let clock = Arc::new(MockClock::new());
+        let history = ConfigHistory::new(enabled(), clock.clone(), services("http://good"));
+        // A 10% baseline.
+        for index in 0..50 {
+            assert!(history.record(index % 10 == 0).is_none());
+        }
+        clock.advance(Duration::from_secs(1));
+        history.reloaded(ReloadDiff::default(), services("http://bad"));
+        assert_eq!(statuses(&history), [(1, ConfigStatus::Superseded), (2, ConfigStatus::Observing)]);
+
+        // 30% is within 0.2 of the baseline; judged only from 10 requests.
+        for index in 0..9 {
+            assert!(history.record(index < 5).is_none());
+        }
+        let rollback = history.record(false).unwrap();
+        assert_eq!((rollback.rejected, rollback.restore), (2, 1));
+        assert_eq!(rollback.services["service-a"].endpoints, ["http://good"]);
+        assert!(rollback.reason.contains("0.50 over 10 requests"), "{}", rollback.reason);
+        assert_eq!(statuses(&history), [(1, ConfigStatus::Active), (2, ConfigStatus::Rejected)]);
+        // Decided once.
+        assert!(history.record(true).is_none());
+    }
+
+    #[test]
+    fn test_reload_kept_after_window() {
+        let clock = Arc::new(MockClock::new());
+        let history = ConfigHistory::new(enabled(), clock.clone(), services("http://good"));
+        history.reloaded(ReloadDiff::default(), services("http://next"));
+        for _ in 0..5 {
+            history.record(true);
+        }
+        clock.advance(Duration::from_millis(enabled().observation_window_ms));
+        assert_eq!(statuses(&history)[1], (2, ConfigStatus::Active));
+        for _ in 0..20 {
+            assert!(history.record(true).is_none());
+        }
+    }
+
+    #[test]
+    fn test_manual_rollback_skips_rejected() {
+        let clock = Arc::new(MockClock::new());
+        let history = ConfigHistory::new(ConfigRollbackConfig::default(), clock, services("http://one"));
+        history.reloaded(ReloadDiff::default(), services("http://two"));
+        history.reloaded(ReloadDiff::default(), services("http://three"));
+        assert!(history.record(true).is_none());
+
+        let rollback = history.rollback("manual".to_string()).unwrap();
+        assert_eq!((rollback.rejected, rollback.restore), (3, 2));
+        let rollback = history.rollback("manual".to_string()).unwrap();
+        assert_eq!((rollback.rejected, rollback.restore), (2, 1));
+        assert_eq!(rollback.services["service-a"].endpoints, ["http://one"]);
+        assert_eq!(
+            statuses(&history),
+            [(1, ConfigStatus::Active), (2, ConfigStatus::Rejected), (3, ConfigStatus::Rejected)]
+        );
+        assert!(history.rollback("manual".to_string()).is_none());
+
+        // A reload after a rollback supersedes the restored config.
+        history.reloaded(ReloadDiff::default(), services("http://four"));
+        assert_eq!(statuses(&history)[0], (1, ConfigStatus::Superseded));
+    }
+
+    #[test]
+    fn test_nothing_to_roll_back_to() {
+        let history = ConfigHistory::new(enabled(), Arc::new(MockClock::new()), services("http://one"));
+        assert!(history.rollback("manual".to_string()).is_none());
+        assert_eq!(statuses(&history), [(1, ConfigStatus::Active)]);
+    }
+}
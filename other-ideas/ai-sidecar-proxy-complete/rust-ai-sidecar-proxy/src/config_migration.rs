@@ -0,0 +1,396 @@
+use crate::config::Config;
+use crate::policy_templates;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+// One edit to the raw JSON. Paths are dot-separated keys; `*` stands for every
+// entry of a map, e.g. "upstream_services.*.timeout_ms".
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    Rename { path: &'static str, to: &'static str },
+    // Fills a missing field with its value in `Config::new`.
+    Default { path: &'static str },
+}
+
+// Upgrades a file at `from_version` to the next version.
+#[derive(Debug)]
+pub struct Migration {
+    pub from_version: u32,
+    pub steps: &'static [Step],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    // Version 1 is every file written before config_version existed. These
+    // listener settings were added as required fields.
+    Migration {
+        from_version: 1,
+        steps: &[
+            Step::Default { path: "proxy_config.sniff_protocol" },
+            Step::Default { path: "proxy_config.tls_on_plaintext" },
+            Step::Default { path: "proxy_config.fd_monitor" },
+            Step::Default { path: "proxy_config.max_buffered_bytes" },
+            Step::Default { path: "proxy_config.supervisor" },
+        ],
+    },
+];
+
+#[derive(Debug)]
+pub struct Upgraded {
+    pub config: Config,
+    pub from_version: u32,
+    // One line per edit, e.g. "proxy_config.supervisor: added default".
+    pub changes: Vec<String>,
+}
+
+pub fn upgrade(text: &str) -> Result<Upgraded> {
+    let mut value: Value = serde_json::from_str(text).context("config is not valid JSON")?;
+    let root = value.as_object_mut().context("config must be a JSON object")?;
+
+    let from_version = match root.get("config_version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= 1)
+            .with_context(|| format!("config_version must be a positive integer, got {}", version))?,
+    };
+    if from_version > CURRENT_CONFIG_VERSION {
+        bail!(
+            "config_version {} is newer than this build supports ({})",
+            from_version,
+            CURRENT_CONFIG_VERSION
+        );
+    }
+
+    let defaults = serde_json::to_value(Config::new()).expect("default config serializes");
+    let mut changes = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|migration| migration.from_version >= from_version) {
+        for step in migration.steps {
+            apply(root, &defaults, *step, &mut changes);
+        }
+    }
+
+    let templated = policy_templates::resolve(root)?;
+    let expanded = expand_endpoint_tables(root)?;
+    if from_version == CURRENT_CONFIG_VERSION && !templated && !expanded {
+        // Checked against the original text so line numbers match the file.
+        let config = parse(text)?;
+        return Ok(Upgraded {
+            config,
+            from_version,
+            changes,
+        });
+    }
+
+    root.insert("config_version".to_string(), Value::from(CURRENT_CONFIG_VERSION));
+    let upgraded = serde_json::to_string_pretty(&value).expect("JSON value serializes");
+    let config = parse(&upgraded).context(if templated {
+        "config is invalid once policy templates are applied"
+    } else {
+        "config is invalid after migration"
+    })?;
+    Ok(Upgraded {
+        config,
+        from_version,
+        changes,
+    })
+}
+
+// A config value serde rejected, with the key it sits under. Kept as a type
+// so loaders of other formats can point at their own line instead.
+#[derive(Debug)]
+pub struct KeyError {
+    pub path: String,
+    pub error: serde_json::Error,
+}
+
+impl KeyError {
+    // The error without serde_json's position in the JSON text.
+    pub fn message(&self) -> String {
+        let text = self.error.to_string();
+        let position = format!(" at line {} column {}", self.error.line(), self.error.column());
+        text.strip_suffix(&position).unwrap_or(&text).to_string()
+    }
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at `{}`: {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+// Every loader ends here, so endpoints are canonical whatever the format.
+fn parse(text: &str) -> Result<Config> {
+    let mut config: Config = serde_json::from_str(text).map_err(|e| {
+        let path = key_path_at(text, e.line(), e.column());
+        if path.is_empty() {
+            anyhow!("{}", e)
+        } else {
+            KeyError { path, error: e }.into()
+        }
+    })?;
+    config.canonicalize_endpoints();
+    Ok(config)
+}
+
+fn apply(root: &mut Map<String, Value>, defaults: &Value, step: Step, changes: &mut Vec<String>) {
+    let path = match step {
+        Step::Rename { path, .. } | Step::Default { path } => path,
+    };
+    let segments: Vec<&str> = path.split('.').collect();
+    let (field, parents) = segments.split_last().expect("step path is not empty");
+
+    let mut found = Vec::new();
+    collect_parents(root, parents, String::new(), &mut found);
+    for (prefix, parent) in found {
+        let at = format!("{}{}", prefix, field);
+        match step {
+            Step::Rename { to, .. } => {
+                if parent.contains_key(to) {
+                    continue;
+                }
+                if let Some(value) = parent.remove(*field) {
+                    parent.insert(to.to_string(), value);
+                    changes.push(format!("{}: renamed to {}", at, to));
+                }
+            }
+            Step::Default { .. } => {
+                if parent.contains_key(*field) {
+                    continue;
+                }
+                if let Some(value) = lookup(defaults, &segments) {
+                    parent.insert(field.to_string(), value.clone());
+                    changes.push(format!("{}: added default {}", at, value));
+                }
+            }
+        }
+    }
+}
+
+// Every object reached by following `parents` from `object`, with its path.
+fn collect_parents<'a>(
+    object: &'a mut Map<String, Value>,
+    parents: &[&str],
+    prefix: String,
+    found: &mut Vec<(String, &'a mut Map<String, Value>)>,
+) {
+    let Some((first, rest)) = parents.split_first() else {
+        found.push((prefix, object));
+        return;
+    };
+    for (key, child) in object.iter_mut() {
+        if *first != "*" && key != first {
+            continue;
+        }
+        if let Value::Object(child) = child {
+            collect_parents(child, rest, format!("{}{}.", prefix, key), found);
+        }
+    }
+}
+
+// Endpoints may be written as tables, `{ url = "...", weight = 3, priority = 1 }`,
+// instead of bare URLs. Each becomes its URL, with the weight and priority
+// moved into the service's `endpoint_weights` and `endpoint_priorities`.
+// Returns whether any table was found.
+fn expand_endpoint_tables(root: &mut Map<String, Value>) -> Result<bool> {
+    let mut expanded = false;
+    for parents in [&["upstream_services", "*"][..], &["egress", "services", "*"]] {
+        let mut found = Vec::new();
+        collect_parents(root, parents, String::new(), &mut found);
+        for (prefix, service) in found {
+            let Some(Value::Array(endpoints)) = service.get_mut("endpoints") else {
+                continue;
+            };
+            let mut by_field: Vec<(&str, Vec<(String, Value)>)> =
+                vec![("endpoint_weights", Vec::new()), ("endpoint_priorities", Vec::new())];
+            for (index, endpoint) in endpoints.iter_mut().enumerate() {
+                let Value::Object(table) = endpoint else {
+                    continue;
+                };
+                let at = format!("{}endpoints[{}]", prefix, index);
+                if let Some(key) = table.keys().find(|key| !matches!(key.as_str(), "url" | "weight" | "priority")) {
+                    bail!("{}: unknown field `{}`, expected `url`, `weight` or `priority`", at, key);
+                }
+                let url = match table.get("url") {
+                    Some(Value::String(url)) => url.clone(),
+                    _ => bail!("{}: needs a `url` string", at),
+                };
+                for (key, (_, values)) in ["weight", "priority"].into_iter().zip(by_field.iter_mut()) {
+                    if let Some(value) = table.get(key) {
+                        values.push((url.clone(), value.clone()));
+                    }
+                }
+                *endpoint = Value::String(url);
+                expanded = true;
+            }
+            for (field, values) in by_field {
+                if values.is_empty() {
+                    continue;
+                }
+                let map = service.entry(field).or_insert_with(|| Value::Object(Map::new()));
+                if map.is_null() {
+                    *map = Value::Object(Map::new());
+                }
+                let Value::Object(map) = map else {
+                    bail!("{}{} must be a table", prefix, field);
+                };
+                for (url, value) in values {
+                    if map.insert(url.clone(), value).is_some() {
+                        bail!("{}{}: {:?} is also given in its endpoint table", prefix, field, url);
+                    }
+                }
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+// For `*`, any entry of the default map will do; they share a shape.
+fn lookup<'a>(value: &'a Value, segments: &[&str]) -> Option<&'a Value> {
+    segments.iter().try_fold(value, |value, segment| match *segment {
+        "*" => value.as_object()?.values().next(),
+        key => value.get(key),
+    })
+}
+
+// Names the key serde was looking at when it failed, such as
+// "upstream_services.service-a.tls.ca_bundle". Scans the JSON text up to the
+// error position, tracking which key or index each open container is at.
+fn key_path_at(text: &str, line: usize, column: usize) -> String {
+    enum Frame {
+        Object { key: Option<String>, expect_key: bool },
+        Array { index: usize },
+    }
+
+    let offset = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>()
+        + column;
+    let scanned = &text.as_bytes()[..offset.min(text.len())];
+
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut i = 0;
+    while i < scanned.len() {
+        match scanned[i] {
+            b'{' => stack.push(Frame::Object { key: None, expect_key: true }),
+            b'[' => stack.push(Frame::Array { index: 0 }),
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => match stack.last_mut() {
+                Some(Frame::Object { expect_key, .. }) => *expect_key = true,
+                Some(Frame::Array { index }) => *index += 1,
+                None => {}
+            },
+            b':' => {
+                if let Some(Frame::Object { expect_key, .. }) = stack.last_mut() {
+                    *expect_key = false;
+                }
+            }
+            b'"' => {
+                let start = i + 1;
+                i = start;
+                while i < scanned.len() && scanned[i] != b'"' {
+                    i += if scanned[i] == b'\\' { 2 } else { 1 };
+                }
+                if let Some(Frame::Object { key, expect_key: true }) = stack.last_mut() {
+                    let raw = &scanned[start..i.min(scanned.len())];
+                    *key = Some(String::from_utf8_lossy(raw).into_owned());
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let mut path = String::new();
+    for frame in &stack {
+        match frame {
+            Frame::Object { key: Some(key), .. } => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+            }
+            Frame::Array { index } => path.push_str(&format!("[{}]", index)),
+            Frame::Object { key: None, .. } => {}
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_path_follows_nesting() {
+        let text = "{\n  \"a\": {\"b\": [1, {\"c\": 2}]},\n  \"d\": {\"e\": true}\n}";
+        // Just past "c" on line 2, and just past "e" on line 3.
+        assert_eq!(key_path_at(text, 2, 24), "a.b[1].c");
+        assert_eq!(key_path_at(text, 3, 13), "d.e");
+    }
+
+    #[test]
+    fn test_rename_applies_to_every_map_entry() {
+        let mut value = serde_json::json!({
+            "upstream_services": {
+                "a": {"timeout": 5},
+                "b": {"timeout": 6, "timeout_ms": 7},
+            }
+        });
+        let mut changes = Vec::new();
+        apply(
+            value.as_object_mut().unwrap(),
+            &Value::Null,
+            Step::Rename {
+                path: "upstream_services.*.timeout",
+                to: "timeout_ms",
+            },
+            &mut changes,
+        );
+
+        assert_eq!(value["upstream_services"]["a"], serde_json::json!({"timeout_ms": 5}));
+        // An explicit new name wins; the old one is left for the unknown-field check.
+        assert_eq!(value["upstream_services"]["b"]["timeout_ms"], 7);
+        assert_eq!(changes, vec!["upstream_services.a.timeout: renamed to timeout_ms"]);
+    }
+
+    #[test]
+    fn test_endpoint_tables_expand_into_weights_and_priorities() {
+        let upgraded = upgrade(
+            r#"{"config_version": 2, "upstream_services": {"a": {"name": "a", "endpoints": [
+                {"url": "http://a-1:8080", "weight": 3},
+                {"url": "http://a-2:8080", "priority": 1},
+                "http://a-3:8080"
+            ]}}}"#,
+        )
+        .unwrap();
+        let service = &upgraded.config.upstream_services["a"];
+        assert_eq!(service.endpoints, vec!["http://a-1:8080", "http://a-2:8080", "http://a-3:8080"]);
+        assert_eq!(service.endpoint_weights, Some([("http://a-1:8080".to_string(), 3)].into()));
+        assert_eq!(service.endpoint_priorities, Some([("http://a-2:8080".to_string(), 1)].into()));
+
+        let error = upgrade(
+            r#"{"upstream_services": {"a": {"name": "a",
+                "endpoints": [{"url": "http://a-1:8080", "weight": 3}],
+                "endpoint_weights": {"http://a-1:8080": 2}}}}"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("also given in its endpoint table"), "{}", error);
+        let error = upgrade(r#"{"upstream_services": {"a": {"name": "a", "endpoints": [{"weight": 3}]}}}"#).unwrap_err();
+        assert!(error.to_string().contains("upstream_services.a.endpoints[0]: needs a `url`"), "{}", error);
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let error = upgrade(r#"{"config_version": 99}"#).unwrap_err();
+        assert!(error.to_string().contains("newer than this build supports"), "{}", error);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::config::{Config, UpstreamService};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Sections a reload applies; the rest of the file is read at startup only.
+const RELOADED: [&str; 3] = ["config_version", "upstream_services", "policy_templates"];
+
+// What a reload changes, by service name, sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ReloadDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // Present before and after with any setting different, endpoints included.
+    pub changed: Vec<String>,
+    // Top-level sections that differ from the running config and only take
+    // effect after a restart.
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadDiff {
+    // `services` is what is running now, which may differ from `running` after
+    // endpoint updates through the admin API.
+    pub fn new(services: &HashMap<String, UpstreamService>, running: &Config, reloaded: &Config) -> Self {
+        let mut diff = Self::default();
+        for (name, service) in &reloaded.upstream_services {
+            match services.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(current) if !same(current, service) => diff.changed.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = services
+            .keys()
+            .filter(|name| !reloaded.upstream_services.contains_key(*name))
+            .cloned()
+            .collect();
+
+        let running = serde_json::to_value(running).expect("config serializes");
+        let reloaded = serde_json::to_value(reloaded).expect("config serializes");
+        if let (Some(running), Some(reloaded)) = (running.as_object(), reloaded.as_object()) {
+            diff.restart_required = running
+                .keys()
+                .chain(reloaded.keys())
+                .filter(|section| !RELOADED.contains(&section.as_str()))
+                .filter(|section| running.get(*section) != reloaded.get(*section))
+                .cloned()
+                .collect();
+        }
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff.restart_required.sort();
+        diff.restart_required.dedup();
+        diff
+    }
+}
+
+fn same(a: &UpstreamService, b: &UpstreamService) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_names_services_and_restart_sections() {
+        let running = Config::new();
+        let mut reloaded = Config::new();
+        reloaded.upstream_services.remove("service-b");
+        let mut orders = reloaded.upstream_services["service-a"].clone();
+        orders.name = "orders".to_string();
+        reloaded.upstream_services.insert("orders".to_string(), orders);
+        reloaded.upstream_services.get_mut("service-a").unwrap().timeout_ms = 100;
+        reloaded.proxy_config.max_connections = 5;
+
+        let diff = ReloadDiff::new(&running.upstream_services, &running, &reloaded);
+
+        assert_eq!(diff.added, vec!["orders"]);
+        assert_eq!(diff.removed, vec!["service-b"]);
+        assert_eq!(diff.changed, vec!["service-a"]);
+        assert_eq!(diff.restart_required, vec!["proxy_config"]);
+        assert_eq!(ReloadDiff::new(&running.upstream_services, &running, &running), ReloadDiff::default());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::config_migration::{self, KeyError, Upgraded};
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::ops::Range;
+use toml_edit::{Document, Item, Table};
+
+// Reads a TOML config through the same upgrade as JSON, so versioning and
+// policy templates behave the same; errors name the TOML line instead.
+pub fn upgrade(text: &str) -> Result<Upgraded> {
+    let document = Document::parse(text).map_err(|e| anyhow!("{}", e.to_string().trim_end()))?;
+    let mut lines = Lines {
+        text,
+        by_path: HashMap::new(),
+    };
+    let root = table(document.as_table(), "", &mut lines)?;
+    let json = serde_json::to_string_pretty(&Value::Object(root)).expect("JSON value serializes");
+    config_migration::upgrade(&json).map_err(|e| lines.locate(e))
+}
+
+// Writes the config as TOML that `upgrade` reads back unchanged. Unset
+// optional fields are left out, since TOML has no null.
+pub fn to_toml(config: &Config) -> String {
+    let value = serde_json::to_value(config).expect("config serializes");
+    let mut out = String::new();
+    write_table(&mut out, &[], value.as_object().expect("config is an object"));
+    out
+}
+
+// The line each key path, in `config_migration`'s "a.b[1].c" form, was
+// written on.
+struct Lines<'a> {
+    text: &'a str,
+    by_path: HashMap<String, usize>,
+}
+
+impl Lines<'_> {
+    fn record(&mut self, path: &str, span: Option<Range<usize>>) {
+        if let Some(span) = span {
+            let line = self.text[..span.start.min(self.text.len())].matches('\n').count() + 1;
+            self.by_path.insert(path.to_string(), line);
+        }
+    }
+
+    fn line_of(&self, path: &str) -> Option<usize> {
+        let mut path = path;
+        loop {
+            if let Some(line) = self.by_path.get(path) {
+                return Some(*line);
+            }
+            path = &path[..path.rfind(['.', '['])?];
+        }
+    }
+
+    // Swaps the JSON position in a rejected value's error for its TOML line.
+    fn locate(&self, error: anyhow::Error) -> anyhow::Error {
+        let Some(key_error) = error.chain().find_map(|cause| cause.downcast_ref::<KeyError>()) else {
+            return error;
+        };
+        let located = match self.line_of(&key_error.path) {
+            Some(line) => anyhow!("line {}: at `{}`: {}", line, key_error.path, key_error.message()),
+            None => anyhow!("at `{}`: {}", key_error.path, key_error.message()),
+        };
+        if error.chain().count() > 1 {
+            located.context(error.to_string())
+        } else {
+            located
+        }
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn table(table: &Table, path: &str, lines: &mut Lines) -> Result<Map<String, Value>> {
+    let mut object = Map::new();
+    for (key, item) in table.iter() {
+        let path = child_path(path, key);
+        lines.record(&path, table.key(key).and_then(|key| key.span()).or_else(|| item.span()));
+        let value = match item {
+            Item::None => continue,
+            Item::Value(value) => toml_value(value, &path, lines)?,
+            Item::Table(child) => Value::Object(self::table(child, &path, lines)?),
+            Item::ArrayOfTables(tables) => {
+                let mut array = Vec::new();
+                for (index, child) in tables.iter().enumerate() {
+                    let path = format!("{}[{}]", path, index);
+                    lines.record(&path, child.span());
+                    array.push(Value::Object(self::table(child, &path, lines)?));
+                }
+                Value::Array(array)
+            }
+        };
+        object.insert(key.to_string(), value);
+    }
+    Ok(object)
+}
+
+fn toml_value(value: &toml_edit::Value, path: &str, lines: &mut Lines) -> Result<Value> {
+    use toml_edit::Value as Toml;
+    Ok(match value {
+        Toml::String(text) => Value::String(text.value().clone()),
+        Toml::Integer(number) => Value::from(*number.value()),
+        Toml::Float(number) => match Number::from_f64(*number.value()) {
+            Some(number) => Value::Number(number),
+            None => bail!("line {}: at `{}`: nan and inf are not valid settings", lines.line_of(path).unwrap_or(0), path),
+        },
+        Toml::Boolean(flag) => Value::Bool(*flag.value()),
+        Toml::Datetime(datetime) => Value::String(datetime.value().to_string()),
+        Toml::Array(array) => {
+            let mut items = Vec::new();
+            for (index, item) in array.iter().enumerate() {
+                let path = format!("{}[{}]", path, index);
+                lines.record(&path, item.span());
+                items.push(toml_value(item, &path, lines)?);
+            }
+            Value::Array(items)
+        }
+        Toml::InlineTable(inline) => {
+            let mut object = Map::new();
+            for (key, item) in inline.iter() {
+                let path = child_path(path, key);
+                lines.record(&path, inline.key(key).and_then(|key| key.span()).or_else(|| item.span()));
+                object.insert(key.to_string(), toml_value(item, &path, lines)?);
+            }
+            Value::Object(object)
+        }
+    })
+}
+
+// Plain values first, then each nested object as its own [section].
+fn write_table(out: &mut String, path: &[String], object: &Map<String, Value>) {
+    if !path.is_empty() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let header: Vec<String> = path.iter().map(|key| toml_key(key)).collect();
+        out.push_str(&format!("[{}]\n", header.join(".")));
+    }
+    for (key, value) in object {
+        if !value.is_null() && !value.is_object() {
+            out.push_str(&format!("{} = {}\n", toml_key(key), inline(value)));
+        }
+    }
+    for (key, value) in object {
+        if let Value::Object(child) = value {
+            let mut child_path = path.to_vec();
+            child_path.push(key.clone());
+            write_table(out, &child_path, child);
+        }
+    }
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty() && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if bare {
+        key.to_string()
+    } else {
+        inline(&Value::String(key.to_string()))
+    }
+}
+
+fn inline(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        // JSON's string escapes are valid TOML; DEL is the one control
+        // character JSON leaves raw and TOML does not.
+        Value::String(_) => value.to_string().replace('\u{7f}', "\\u007F"),
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().filter(|item| !item.is_null()).map(inline).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(object) => {
+            let fields: Vec<String> = object
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| format!("{} = {}", toml_key(key), inline(value)))
+                .collect();
+            if fields.is_empty() {
+                "{}".to_string()
+            } else {
+                format!("{{ {} }}", fields.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{UpstreamAuthConfig, UpstreamTlsConfig};
+    use crate::prewarm::PrewarmConfig;
+
+    fn round_trip(config: &Config) {
+        let text = to_toml(config);
+        let parsed = upgrade(&text).unwrap_or_else(|e| panic!("{:#}\n{}", e, text)).config;
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::to_value(config).unwrap(),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_default_config_round_trips() {
+        round_trip(&Config::new());
+    }
+
+    #[test]
+    fn test_nested_and_optional_fields_round_trip() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.push("http://10.0.0.7:3001".to_string());
+        service.health_check_timeout_ms = Some(250);
+        service.tls = Some(UpstreamTlsConfig {
+            ca_bundle_path: Some("/etc/ssl/ca \"internal\".pem".to_string()),
+            client_cert_path: None,
+            client_key_path: None,
+        });
+        service.auth = Some(UpstreamAuthConfig {
+            header: "authorization".to_string(),
+            value: "Bearer ${TOKEN}".to_string(),
+        });
+        service.hosts.insert("api.internal".to_string(), vec!["10.0.0.7".parse().unwrap()]);
+        service.prewarm = Some(PrewarmConfig {
+            connections: 2,
+            method: "OPTIONS".to_string(),
+            path: None,
+            parallelism: 4,
+            refresh_interval_ms: 0,
+        });
+        config.ai_config.decision_threshold = 0.25;
+
+        round_trip(&config);
+    }
+
+    #[test]
+    fn test_minimal_service_gets_defaults() {
+        let config = upgrade(
+            r#"
+config_version = 2
+
+[upstream_services.orders]
+name = "orders"
+endpoints = ["http://orders:8080"]
+"#,
+        )
+        .unwrap()
+        .config;
+
+        let orders = &config.upstream_services["orders"];
+        assert_eq!(orders.max_retries, 3);
+        assert_eq!(orders.circuit_breaker_threshold, 5);
+        assert_eq!(orders.timeout_ms, 5000);
+        assert_eq!(orders.health_check_path, "/health");
+        assert_eq!(config.upstream_services.len(), 1);
+    }
+
+    #[test]
+    fn test_errors_name_key_and_line() {
+        let error = upgrade(
+            r#"config_version = 2
+
+[upstream_services.orders]
+name = "orders"
+endpoints = ["http://orders:8080"]
+max_retries = "three"
+"#,
+        )
+        .unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("line 6: at `upstream_services.orders.max_retries`: invalid type"), "{}", message);
+
+        let error = upgrade("[upstream_services\nname = 1\n").unwrap_err();
+        assert!(error.to_string().contains("line 1"), "{}", error);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config::Config;
+use crate::config_migration::{self, KeyError, Upgraded};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+// Reads a YAML config through the same upgrade as JSON and TOML. A rejected
+// value is reported by its key path; the JSON position it was found at means
+// nothing to someone looking at the YAML file.
+pub fn upgrade(text: &str) -> Result<Upgraded> {
+    let value: Value = serde_yaml::from_str(text).map_err(|e| anyhow!("{}", e))?;
+    let json = serde_json::to_string_pretty(&value).expect("JSON value serializes");
+    config_migration::upgrade(&json).map_err(locate)
+}
+
+pub fn to_yaml(config: &Config) -> String {
+    serde_yaml::to_string(config).expect("config serializes")
+}
+
+fn locate(error: anyhow::Error) -> anyhow::Error {
+    let Some(key_error) = error.chain().find_map(|cause| cause.downcast_ref::<KeyError>()) else {
+        return error;
+    };
+    let located = anyhow!("at `{}`: {}", key_error.path, key_error.message());
+    if error.chain().count() > 1 {
+        located.context(error.to_string())
+    } else {
+        located
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_round_trips() {
+        let config = Config::new();
+        let text = to_yaml(&config);
+        let parsed = upgrade(&text).unwrap_or_else(|e| panic!("{:#}\n{}", e, text)).config;
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), serde_json::to_value(&config).unwrap());
+    }
+
+    #[test]
+    fn test_errors_name_key_path() {
+        let error = upgrade(
+            "config_version: 2\nupstream_services:\n  orders:\n    name: orders\n    endpoints: [\"http://orders:8080\"]\n    max_retries: three\n",
+        )
+        .unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("at `upstream_services.orders.max_retries`: invalid type"), "{}", message);
+        assert!(!message.contains("column"), "{}", message);
+
+        let error = upgrade("upstream_services: [\n").unwrap_err();
+        assert!(error.to_string().contains("line"), "{}", error);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{egress::Direction, metrics::MetricsCollector, supervisor::panic_message};
+use futures::FutureExt;
+use serde::Serialize;
+use std::{
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio_util::task::TaskTracker;
+use tracing::error;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionTasksSnapshot {
+    pub running: usize,
+    pub limit: usize,
+}
+
+// The tasks serving accepted connections, capped at `max_connections` so a
+// flood of accepts cannot grow the task count without bound.
+pub struct ConnectionTasks {
+    tracker: TaskTracker,
+    running: Arc<AtomicUsize>,
+    limit: usize,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl ConnectionTasks {
+    pub fn new(limit: usize, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            tracker: TaskTracker::new(),
+            running: Arc::new(AtomicUsize::new(0)),
+            limit,
+            metrics,
+        }
+    }
+
+    // Spawns the connection's task, or returns false without spawning once
+    // `limit` tasks are running. Only the accept loop spawns, so the check
+    // cannot race another spawn.
+    pub fn try_spawn<F>(&self, direction: Direction, task: F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.running.load(Ordering::Acquire) >= self.limit {
+            return false;
+        }
+        let running = Running::start(self.running.clone(), self.metrics.clone());
+        let metrics = self.metrics.clone();
+        self.tracker.spawn(async move {
+            let _running = running;
+            if let Err(payload) = AssertUnwindSafe(task).catch_unwind().await {
+                metrics.record_connection_task_panic(direction);
+                error!(
+                    direction = direction.label(),
+                    panic = %panic_message(payload.as_ref()),
+                    "connection task panicked"
+                );
+            }
+        });
+        true
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Acquire)
+    }
+
+    pub fn snapshot(&self) -> ConnectionTasksSnapshot {
+        ConnectionTasksSnapshot {
+            running: self.running(),
+            limit: self.limit,
+        }
+    }
+
+    // Resolves once every spawned task has finished; no more may be spawned
+    // after this is called.
+    pub async fn wait(&self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+// Counts one running task, including while it unwinds.
+struct Running {
+    running: Arc<AtomicUsize>,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl Running {
+    fn start(running: Arc<AtomicUsize>, metrics: Arc<MetricsCollector>) -> Self {
+        let now = running.fetch_add(1, Ordering::AcqRel) + 1;
+        metrics.set_connection_tasks(now);
+        Self { running, metrics }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let now = self.running.fetch_sub(1, Ordering::AcqRel) - 1;
+        self.metrics.set_connection_tasks(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refuses_spawns_over_limit() {
+        let metrics = Arc::new(MetricsCollector::new());
+        let tasks = ConnectionTasks::new(2, metrics.clone());
+        let (release, released) = tokio::sync::watch::channel(false);
+
+        for _ in 0..2 {
+            let mut released = released.clone();
+            assert!(tasks.try_spawn(Direction::Ingress, async move {
+                let _ = released.wait_for(|released| *released).await;
+            }));
+        }
+        assert!(!tasks.try_spawn(Direction::Ingress, async {}));
+        assert_eq!(metrics.connection_tasks(), 2.0);
+
+        release.send(true).unwrap();
+        tasks.wait().await;
+        assert_eq!(tasks.running(), 0);
+        assert_eq!(metrics.connection_tasks(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_is_counted() {
+        let metrics = Arc::new(MetricsCollector::new());
+        let tasks = ConnectionTasks::new(1, metrics.clone());
+
+        assert!(tasks.try_spawn(Direction::Egress, async { panic!("bad connection") }));
+        tasks.wait().await;
+
+        assert_eq!(metrics.connection_task_panic_count(Direction::Egress), 1);
+        assert_eq!(metrics.connection_task_panic_count(Direction::Ingress), 0);
+        assert_eq!(tasks.running(), 0);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::endpoint_url;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Takes a service's endpoints from the Consul catalog instead of the config.
+// Only instances passing all their Consul health checks are listed; the
+// health checker probes them as it would listed ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsulDiscoveryConfig {
+    // The Consul agent's HTTP API.
+    pub address: String,
+    pub service: String,
+    // Only instances registered with this tag.
+    pub tag: Option<String>,
+    pub datacenter: Option<String>,
+    // Sent as X-Consul-Token when the agent has ACLs enabled.
+    pub token: Option<String>,
+    pub scheme: String,
+    // With blocking queries, the longest the agent holds a query open
+    // waiting for a change; without them, the wait between queries.
+    pub poll_interval_ms: u64,
+    pub blocking: bool,
+    // Wait before asking again after a failure, doubled on each one in a
+    // row up to retry_max_ms.
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+}
+
+impl Default for ConsulDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            address: "http://127.0.0.1:8500".to_string(),
+            service: String::new(),
+            tag: None,
+            datacenter: None,
+            token: None,
+            scheme: "http".to_string(),
+            poll_interval_ms: 30_000,
+            blocking: true,
+            retry_initial_ms: 500,
+            retry_max_ms: 30_000,
+        }
+    }
+}
+
+impl ConsulDiscoveryConfig {
+    pub fn retry_delay(&self, failures: u32) -> Duration {
+        let delay = self.retry_initial_ms.saturating_mul(1u64.checked_shl(failures).unwrap_or(u64::MAX));
+        Duration::from_millis(delay.min(self.retry_max_ms))
+    }
+}
+
+// The parts of a /v1/health/service entry the proxy reads.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct ServiceEntry {
+    pub node: CatalogNode,
+    pub service: CatalogService,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct CatalogNode {
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct CatalogService {
+    // Empty when the instance listens on its node's address.
+    pub address: String,
+    pub port: u16,
+}
+
+// One answer from the agent, with the X-Consul-Index to block on next.
+#[derive(Debug, Clone, Default)]
+pub struct HealthAnswer {
+    pub index: u64,
+    pub entries: Vec<ServiceEntry>,
+}
+
+impl HealthAnswer {
+    // The instances as endpoint URLs, in a stable order.
+    pub fn endpoints(&self, config: &ConsulDiscoveryConfig) -> Vec<String> {
+        let endpoints: BTreeSet<String> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.service.port != 0)
+            .filter_map(|entry| {
+                let address = if entry.service.address.is_empty() { &entry.node.address } else { &entry.service.address };
+                if address.is_empty() {
+                    return None;
+                }
+                let host = if address.contains(':') { format!("[{}]", address) } else { address.clone() };
+                endpoint_url::canonicalize(&format!("{}://{}:{}", config.scheme, host, entry.service.port)).ok()
+            })
+            .collect();
+        endpoints.into_iter().collect()
+    }
+}
+
+// Where the passing instances come from: the agent, or a fake in tests.
+#[async_trait]
+pub trait CatalogSource: Send + Sync {
+    // Blocks until the catalog moves past `index` when the config asks for
+    // blocking queries and `index` is not 0.
+    async fn passing(&self, index: u64) -> Result<HealthAnswer>;
+}
+
+// Asks the catalog for the service's passing instances until `publish`
+// returns false, handing it the endpoints whenever they change. A failed
+// query is retried with backoff; nothing is published meanwhile, so the
+// last endpoints stay in use.
+pub async fn follow<P, F>(source: &dyn CatalogSource, config: &ConsulDiscoveryConfig, mut publish: P)
+where
+    P: FnMut(Vec<String>) -> F + Send,
+    F: Future<Output = bool> + Send,
+{
+    let mut published: Option<Vec<String>> = None;
+    let mut index = 0;
+    let mut failures = 0u32;
+    loop {
+        let answer = match source.passing(index).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                let delay = config.retry_delay(failures);
+                failures = failures.saturating_add(1);
+                warn!(
+                    service = %config.service,
+                    error = format!("{:#}", e),
+                    retry_ms = delay.as_millis() as u64,
+                    "consul discovery failed, keeping the last endpoints"
+                );
+                // Start over without blocking, in case the agent's index
+                // moved on while it was away.
+                index = 0;
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
+        failures = 0;
+        // An index that goes backwards means the agent's state was reset,
+        // so the next query must not block on the old one.
+        index = if answer.index < index { 0 } else { answer.index };
+
+        let endpoints = answer.endpoints(config);
+        if published.as_ref() != Some(&endpoints) {
+            info!(service = %config.service, endpoints = ?endpoints, "consul endpoints changed");
+            published = Some(endpoints.clone());
+            if !publish(endpoints).await {
+                return;
+            }
+        } else {
+            debug!(service = %config.service, index, "consul endpoints unchanged");
+        }
+        if !config.blocking || index == 0 {
+            tokio::time::sleep(Duration::from_millis(config.poll_interval_ms)).await;
+        }
+    }
+}
+
+// Queries /v1/health/service over the agent's HTTP API.
+pub struct Agent {
+    client: reqwest::Client,
+    config: ConsulDiscoveryConfig,
+}
+
+impl Agent {
+    pub fn new(config: &ConsulDiscoveryConfig) -> Result<Self> {
+        // Long enough for a blocking query, which the agent may hold up to
+        // 1/16 past the wait it was given.
+        let timeout = Duration::from_millis(config.poll_interval_ms + config.poll_interval_ms / 16) + Duration::from_secs(10);
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(timeout)
+            .build()?;
+        Ok(Self {
+            client,
+            config: config.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl CatalogSource for Agent {
+    async fn passing(&self, index: u64) -> Result<HealthAnswer> {
+        let url = format!("{}/v1/health/service/{}", self.config.address.trim_end_matches('/'), self.config.service);
+        let mut query = vec![("passing", "true".to_string())];
+        if let Some(tag) = &self.config.tag {
+            query.push(("tag", tag.clone()));
+        }
+        if let Some(datacenter) = &self.config.datacenter {
+            query.push(("dc", datacenter.clone()));
+        }
+        if self.config.blocking && index > 0 {
+            query.push(("index", index.to_string()));
+            query.push(("wait", format!("{}ms", self.config.poll_interval_ms)));
+        }
+        let mut request = self.client.get(url).query(&query);
+        if let Some(token) = &self.config.token {
+            request = request.header("x-consul-token", token);
+        }
+        let response = request.send().await?.error_for_status()?;
+        let index = match response.headers().get("x-consul-index") {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .with_context(|| format!("malformed X-Consul-Index {:?}", value))?,
+            None => 0,
+        };
+        let entries = response.json().await.context("malformed health answer")?;
+        Ok(HealthAnswer { index, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    fn config() -> ConsulDiscoveryConfig {
+        ConsulDiscoveryConfig {
+            service: "orders".to_string(),
+            poll_interval_ms: 1,
+            retry_initial_ms: 1,
+            retry_max_ms: 4,
+            ..ConsulDiscoveryConfig::default()
+        }
+    }
+
+    fn answer(index: u64, entries: serde_json::Value) -> HealthAnswer {
+        HealthAnswer {
+            index,
+            entries: serde_json::from_value(entries).unwrap(),
+        }
+    }
+
+    // Plays back one scripted answer per query, logging the index asked for.
+    #[derive(Default)]
+    struct Scripted {
+        answers: Mutex<Vec<Result<HealthAnswer>>>,
+        indexes: Mutex<Vec<u64>>,
+    }
+
+    #[async_trait]
+    impl CatalogSource for Scripted {
+        async fn passing(&self, index: u64) -> Result<HealthAnswer> {
+            self.indexes.lock().unwrap().push(index);
+            self.answers.lock().unwrap().remove(0)
+        }
+    }
+
+    #[test]
+    fn test_service_address_falls_back_to_the_node() {
+        let answer = answer(
+            1,
+            json!([
+                {"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "", "Port": 8080}},
+                {"Node": {"Address": "10.0.0.2"}, "Service": {"Address": "10.0.1.2", "Port": 8080}},
+                {"Node": {"Address": "10.0.0.3"}, "Service": {"Address": "fd00::3", "Port": 9090}},
+                {"Node": {"Address": "10.0.0.4"}, "Service": {"Address": "", "Port": 0}},
+            ]),
+        );
+        assert_eq!(
+            answer.endpoints(&config()),
+            vec!["http://10.0.0.1:8080", "http://10.0.1.2:8080", "http://[fd00::3]:9090"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocks_on_the_last_index_and_keeps_endpoints_through_failures() {
+        let instance = |address: &str| json!([{"Node": {"Address": address}, "Service": {"Port": 8080}}]);
+        let source = Scripted::default();
+        *source.answers.lock().unwrap() = vec![
+            Ok(answer(10, instance("10.0.0.1"))),
+            // The wait ran out with nothing new.
+            Ok(answer(10, instance("10.0.0.1"))),
+            Err(anyhow::anyhow!("connection refused")),
+            Ok(answer(12, instance("10.0.0.2"))),
+            // The agent restarted and its index with it.
+            Ok(answer(3, instance("10.0.0.3"))),
+        ];
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let seen = published.clone();
+        follow(&source, &config(), move |endpoints| {
+            let seen = seen.clone();
+            async move {
+                let mut seen = seen.lock().unwrap();
+                seen.push(endpoints);
+                seen.len() < 3
+            }
+        })
+        .await;
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![
+                vec!["http://10.0.0.1:8080"],
+                // The failed query in between published nothing.
+                vec!["http://10.0.0.2:8080"],
+                vec!["http://10.0.0.3:8080"],
+            ]
+        );
+        assert_eq!(*source.indexes.lock().unwrap(), vec![0, 10, 10, 0, 12]);
+    }
+
+    #[tokio::test]
+    async fn test_agent_sends_a_blocking_health_query() {
+        let (addr, requests) = crate::test_support::spawn_fake_consul(|_| {
+            let body = json!([{"Node": {"Address": "10.0.0.9"}, "Service": {"Port": 8080}}]).to_string();
+            Some((42, body))
+        })
+        .await;
+
+        let agent = Agent::new(&ConsulDiscoveryConfig {
+            address: format!("http://{}/", addr),
+            tag: Some("v2".to_string()),
+            token: Some("s3cret".to_string()),
+            poll_interval_ms: 5000,
+            ..config()
+        })
+        .unwrap();
+
+        let first = agent.passing(0).await.unwrap();
+        assert_eq!(first.index, 42);
+        assert_eq!(first.endpoints(&config()), vec!["http://10.0.0.9:8080"]);
+        agent.passing(42).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let path = "/v1/health/service/orders?passing=true&tag=v2";
+        assert_eq!(requests[0], format!("{} s3cret", path));
+        assert_eq!(requests[1], format!("{}&index=42&wait=5000ms s3cret", path));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::buffer_budget::BufferPermit;
+use bytes::Bytes;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+const DECODABLE: &str = "gzip, deflate";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentCodingMode {
+    // Bytes and encoding headers are relayed untouched, even to a client that
+    // cannot read them.
+    Passthrough,
+    // Responses reach the client decoded regardless of what it offered.
+    Decompress,
+    // Decode only when the client did not offer the upstream's encoding.
+    #[default]
+    Negotiate,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Exhausted,
+    Corrupt(std::io::Error),
+}
+
+// Whether an Accept-Encoding value admits `coding`, honouring q=0 and `*`.
+pub fn accepts(accept_encoding: Option<&str>, coding: &str) -> bool {
+    let Some(header) = accept_encoding else {
+        return coding.eq_ignore_ascii_case("identity");
+    };
+
+    let mut wildcard = None;
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let token = params.next().unwrap_or("").trim();
+        let acceptable = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .all(|q| q.trim().parse::<f32>().map_or(true, |q| q > 0.0));
+
+        if token.eq_ignore_ascii_case(coding) {
+            return acceptable;
+        }
+        if token == "*" {
+            wildcard = Some(acceptable);
+        }
+    }
+
+    wildcard.unwrap_or_else(|| coding.eq_ignore_ascii_case("identity"))
+}
+
+pub fn can_decode(content_encoding: &str) -> bool {
+    matches!(content_encoding.trim().to_ascii_lowercase().as_str(), "gzip" | "x-gzip" | "deflate")
+}
+
+// The Accept-Encoding to send upstream, or None to forward the client's as-is.
+pub fn upstream_accept_encoding(mode: ContentCodingMode, client_accept: Option<&str>) -> Option<String> {
+    match mode {
+        ContentCodingMode::Passthrough => None,
+        ContentCodingMode::Decompress => Some(DECODABLE.to_string()),
+        ContentCodingMode::Negotiate => Some(match client_accept {
+            Some(client) if !client.trim().is_empty() => format!("{}, {}", client, DECODABLE),
+            _ => DECODABLE.to_string(),
+        }),
+    }
+}
+
+pub fn should_decode(
+    mode: ContentCodingMode,
+    client_accept: Option<&str>,
+    content_encoding: Option<&str>,
+) -> bool {
+    let Some(encoding) = content_encoding else {
+        return false;
+    };
+    if !can_decode(encoding) {
+        return false;
+    }
+
+    match mode {
+        ContentCodingMode::Passthrough => false,
+        ContentCodingMode::Decompress => true,
+        ContentCodingMode::Negotiate => !accepts(client_accept, encoding.trim()),
+    }
+}
+
+// Decoded output is charged to `permit` so a small compressed body cannot
+// expand past the buffer budget.
+pub fn decode(content_encoding: &str, body: &[u8], permit: &mut BufferPermit) -> Result<Bytes, DecodeError> {
+    let mut reader: Box<dyn Read + '_> = match content_encoding.trim().to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(body)),
+        "deflate" if is_zlib(body) => Box::new(ZlibDecoder::new(body)),
+        // Some servers send a bare deflate stream without the zlib wrapper.
+        "deflate" => Box::new(DeflateDecoder::new(body)),
+        _ => return Ok(Bytes::copy_from_slice(body)),
+    };
+
+    let mut decoded = Vec::new();
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        let n = reader.read(&mut chunk).map_err(DecodeError::Corrupt)?;
+        if n == 0 {
+            break;
+        }
+        if !permit.grow(n) {
+            return Err(DecodeError::Exhausted);
+        }
+        decoded.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Bytes::from(decoded))
+}
+
+// HTTP's deflate is a zlib stream (RFC 1950): a header naming deflate whose
+// first two bytes are a multiple of 31.
+fn is_zlib(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer_budget::BufferBudget, metrics::MetricsCollector};
+    use std::sync::Arc;
+
+    const PLAIN: &[u8] = include_bytes!("../tests/fixtures/content_coding/body.json");
+
+    fn permit(limit: usize) -> BufferPermit {
+        Arc::new(BufferBudget::new(limit, Arc::new(MetricsCollector::new()))).permit()
+    }
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts(Some("gzip, br"), "gzip"));
+        assert!(accepts(Some("GZIP;q=0.5"), "gzip"));
+        assert!(!accepts(Some("gzip;q=0, br"), "gzip"));
+        assert!(!accepts(Some("br"), "gzip"));
+        assert!(accepts(Some("*"), "gzip"));
+        assert!(!accepts(Some("*;q=0"), "gzip"));
+        assert!(!accepts(None, "gzip"));
+        assert!(accepts(None, "identity"));
+    }
+
+    #[test]
+    fn test_should_decode() {
+        use ContentCodingMode::*;
+
+        assert!(!should_decode(Passthrough, None, Some("gzip")));
+        assert!(should_decode(Decompress, Some("gzip"), Some("gzip")));
+        assert!(!should_decode(Decompress, None, Some("br")));
+        assert!(should_decode(Negotiate, None, Some("gzip")));
+        assert!(!should_decode(Negotiate, Some("gzip"), Some("gzip")));
+        assert!(!should_decode(Negotiate, None, None));
+    }
+
+    #[test]
+    fn test_upstream_accept_encoding() {
+        use ContentCodingMode::*;
+
+        assert_eq!(upstream_accept_encoding(Passthrough, Some("br")), None);
+        assert_eq!(upstream_accept_encoding(Decompress, Some("br")).unwrap(), "gzip, deflate");
+        assert_eq!(upstream_accept_encoding(Negotiate, Some("br")).unwrap(), "br, gzip, deflate");
+        assert_eq!(upstream_accept_encoding(Negotiate, None).unwrap(), "gzip, deflate");
+    }
+
+    #[test]
+    fn test_decodes_fixtures() {
+        let fixtures: [(&str, &[u8]); 4] = [
+            ("gzip", include_bytes!("../tests/fixtures/content_coding/body.json.gz")),
+            ("x-gzip", include_bytes!("../tests/fixtures/content_coding/body.json.gz")),
+            ("deflate", include_bytes!("../tests/fixtures/content_coding/body.json.deflate")),
+            ("Deflate", include_bytes!("../tests/fixtures/content_coding/body.json.raw-deflate")),
+        ];
+        for (encoding, body) in fixtures {
+            let decoded = decode(encoding, body, &mut permit(1 << 20)).unwrap_or_else(|e| panic!("{}: {:?}", encoding, e));
+            assert_eq!(decoded, PLAIN, "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let gzip = include_bytes!("../tests/fixtures/content_coding/body.json.gz");
+        assert!(matches!(decode("gzip", &gzip[..gzip.len() / 2], &mut permit(1 << 20)), Err(DecodeError::Corrupt(_))));
+        assert!(matches!(decode("gzip", gzip, &mut permit(PLAIN.len() / 2)), Err(DecodeError::Exhausted)));
+        assert_eq!(decode("br", b"opaque", &mut permit(1 << 20)).unwrap(), &b"opaque"[..]);
+    }
+}
@@ -0,0 +1,243 @@
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
+use hyper::{header, header::HeaderName, HeaderMap, Method, StatusCode, Uri};
+use std::{fmt::Write, sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+type Slot = Arc<watch::Receiver<Option<CachedResponse>>>;
+
+// Request headers that choose between representations of the same resource.
+// They are part of the key, so a gzip answer never reaches a client that
+// asked for identity.
+const NEGOTIATED: [HeaderName; 3] = [header::ACCEPT, header::ACCEPT_ENCODING, header::ACCEPT_LANGUAGE];
+
+// An upstream answer shared by every request with the same key.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+// Identical GET and HEAD requests that arrive while one of them is with the
+// upstream, or within `window` of its answer, wait for that answer instead of
+// each making the call.
+pub struct DedupeCache {
+    slots: Arc<DashMap<String, Slot>>,
+    window: Duration,
+    // Requests with any of these are never shared.
+    excluded: Vec<HeaderName>,
+}
+
+pub enum Dedupe {
+    // Makes the upstream call and publishes what came back.
+    Leader(Leader),
+    // Waits for the leader's answer.
+    Follower(Slot),
+}
+
+impl DedupeCache {
+    pub fn new(window: Duration, excluded: Vec<HeaderName>) -> Self {
+        Self {
+            slots: Arc::new(DashMap::new()),
+            window,
+            excluded,
+        }
+    }
+
+    // None for requests that must not share an answer: other methods, and
+    // requests with an excluded header.
+    pub fn key(&self, service: &str, method: &Method, uri: &Uri, headers: &HeaderMap) -> Option<String> {
+        if method != Method::GET && method != Method::HEAD {
+            return None;
+        }
+        if self.excluded.iter().any(|name| headers.contains_key(name)) {
+            return None;
+        }
+        let target = uri.path_and_query().map_or("/", |target| target.as_str());
+        let mut key = format!("{} {} {}", service, method, target);
+        for name in &NEGOTIATED {
+            for value in headers.get_all(name) {
+                let _ = write!(key, " {}={}", name, String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        Some(key)
+    }
+
+    pub fn acquire(&self, key: String) -> Dedupe {
+        match self.slots.entry(key.clone()) {
+            Entry::Occupied(slot) => Dedupe::Follower(slot.get().clone()),
+            Entry::Vacant(vacant) => {
+                let (sender, receiver) = watch::channel(None);
+                let slot = Arc::new(receiver);
+                vacant.insert(slot.clone());
+                Dedupe::Leader(Leader {
+                    key,
+                    slot,
+                    sender: Some(sender),
+                    slots: self.slots.clone(),
+                    window: self.window,
+                })
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+// Whether an answer may go to requests other than the one that asked for it.
+// Server errors are not shared, nor answers that set cookies, that the
+// upstream marks private or no-store, or that vary on a request header the
+// key leaves out.
+pub fn shareable(status: StatusCode, headers: &HeaderMap) -> bool {
+    if status.is_server_error() || headers.contains_key(header::SET_COOKIE) {
+        return false;
+    }
+    let directives = |name| {
+        headers
+            .get_all(name)
+            .into_iter()
+            .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+            .map(|directive| directive.trim().to_ascii_lowercase())
+    };
+    let uncacheable = directives(header::CACHE_CONTROL).any(|directive| {
+        let name = directive.split('=').next().unwrap_or_default().trim();
+        name == "private" || name == "no-store"
+    });
+    let varies_elsewhere = directives(header::VARY)
+        .filter(|name| !name.is_empty())
+        .any(|name| !NEGOTIATED.iter().any(|keyed| keyed.as_str() == name));
+    !uncacheable && !varies_elsewhere
+}
+
+// None when the leader gave up without an answer to share, as when its
+// client went away or the response was a stream; the follower then makes the
+// call itself.
+pub async fn wait(slot: Slot) -> Option<CachedResponse> {
+    let mut receiver = (*slot).clone();
+    let answer = receiver.wait_for(Option::is_some).await.ok()?;
+    answer.clone()
+}
+
+pub struct Leader {
+    key: String,
+    slot: Slot,
+    sender: Option<watch::Sender<Option<CachedResponse>>>,
+    slots: Arc<DashMap<String, Slot>>,
+    window: Duration,
+}
+
+impl Leader {
+    // Answers the followers, and anyone asking within the window.
+    pub fn publish(mut self, response: CachedResponse) {
+        if let Some(sender) = self.sender.take() {
+            sender.send_replace(Some(response));
+        }
+        let (slots, key, slot, window) = (self.slots.clone(), self.key.clone(), self.slot.clone(), self.window);
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            slots.remove_if(&key, |_, current| Arc::ptr_eq(current, &slot));
+        });
+    }
+}
+
+// Unpublished: followers are woken to make their own calls, and the next
+// request leads afresh.
+impl Drop for Leader {
+    fn drop(&mut self) {
+        if self.sender.take().is_some() {
+            self.slots.remove_if(&self.key, |_, current| Arc::ptr_eq(current, &self.slot));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(method: Method, uri: &str, headers: &HeaderMap) -> Option<String> {
+        let cache = DedupeCache::new(Duration::ZERO, vec![header::AUTHORIZATION, HeaderName::from_static("x-api-key")]);
+        cache.key("svc", &method, &uri.parse().unwrap(), headers)
+    }
+
+    fn answer(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_only_anonymous_reads_are_keyed() {
+        let plain = HeaderMap::new();
+        assert_eq!(key(Method::GET, "/a?x=1", &plain).as_deref(), Some("svc GET /a?x=1"));
+        assert_ne!(key(Method::GET, "/a?x=1", &plain), key(Method::GET, "/a?x=2", &plain));
+        assert_ne!(key(Method::GET, "/a", &plain), key(Method::HEAD, "/a", &plain));
+        assert_eq!(key(Method::POST, "/a", &plain), None);
+
+        let mut authorized = HeaderMap::new();
+        authorized.insert(header::AUTHORIZATION, "Bearer t".parse().unwrap());
+        assert_eq!(key(Method::GET, "/a", &authorized), None);
+        let mut metered = HeaderMap::new();
+        metered.insert("x-api-key", "k".parse().unwrap());
+        assert_eq!(key(Method::GET, "/a", &metered), None);
+    }
+
+    #[test]
+    fn test_negotiated_headers_are_keyed() {
+        let mut gzip = HeaderMap::new();
+        gzip.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        gzip.insert(header::USER_AGENT, "curl".parse().unwrap());
+        assert_eq!(key(Method::GET, "/a", &gzip).as_deref(), Some("svc GET /a accept-encoding=gzip"));
+        assert_ne!(key(Method::GET, "/a", &gzip), key(Method::GET, "/a", &HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_only_public_answers_are_shareable() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        assert!(shareable(StatusCode::OK, &headers(&[("cache-control", "public, max-age=60")])));
+        assert!(shareable(StatusCode::NOT_FOUND, &headers(&[("vary", "Accept-Encoding, accept")])));
+        assert!(!shareable(StatusCode::BAD_GATEWAY, &HeaderMap::new()));
+        assert!(!shareable(StatusCode::OK, &headers(&[("set-cookie", "session=1")])));
+        assert!(!shareable(StatusCode::OK, &headers(&[("cache-control", "max-age=0, Private")])));
+        assert!(!shareable(StatusCode::OK, &headers(&[("cache-control", "no-store")])));
+        assert!(!shareable(StatusCode::OK, &headers(&[("vary", "accept-encoding"), ("vary", "origin")])));
+        assert!(!shareable(StatusCode::OK, &headers(&[("vary", "*")])));
+    }
+
+    #[tokio::test]
+    async fn test_followers_get_the_leaders_answer() {
+        let cache = DedupeCache::new(Duration::from_millis(50), Vec::new());
+        let Dedupe::Leader(leader) = cache.acquire("k".to_string()) else { panic!("first request should lead") };
+        let Dedupe::Follower(slot) = cache.acquire("k".to_string()) else { panic!("second request should follow") };
+        let waiting = tokio::spawn(wait(slot));
+
+        leader.publish(answer("shared"));
+        assert_eq!(waiting.await.unwrap().unwrap().body, "shared");
+        // Still answered within the window, then forgotten.
+        let Dedupe::Follower(slot) = cache.acquire("k".to_string()) else { panic!("should follow within the window") };
+        assert_eq!(wait(slot).await.unwrap().body, "shared");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_leader_releases_followers() {
+        let cache = DedupeCache::new(Duration::from_millis(50), Vec::new());
+        let Dedupe::Leader(leader) = cache.acquire("k".to_string()) else { panic!("first request should lead") };
+        let Dedupe::Follower(slot) = cache.acquire("k".to_string()) else { panic!("second request should follow") };
+
+        drop(leader);
+        assert!(wait(slot).await.is_none());
+        assert!(matches!(cache.acquire("k".to_string()), Dedupe::Leader(_)));
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrainConfig {
+    // How long the listeners keep accepting once shutdown starts. Responses in
+    // this window close their connection (Connection: close on HTTP/1.1,
+    // GOAWAY on HTTP/2), so clients move elsewhere while the orchestrator is
+    // still taking the instance out of rotation.
+    pub delay_ms: u64,
+    // Called once when draining starts, e.g. to remove the instance from a
+    // service registry.
+    pub deregistration: Option<DeregistrationConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeregistrationConfig {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // Doubles after every failed attempt.
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_backoff_ms() -> u64 {
+    200
+}
+
+impl DeregistrationConfig {
+    pub fn method(&self) -> Result<reqwest::Method> {
+        reqwest::Method::from_bytes(self.method.as_bytes())
+            .with_context(|| format!("invalid deregistration method {:?}", self.method))
+    }
+}
+
+// Progress of a shutdown, as served at /admin/drain.
+#[derive(Debug, Clone, Serialize)]
+pub struct DrainStatus {
+    pub draining: bool,
+    // "serving", "draining" while still accepting, "closing" once the
+    // listeners are gone and only in-flight work remains.
+    pub phase: &'static str,
+    pub elapsed_ms: u64,
+    pub in_flight_requests: usize,
+    pub open_connections: usize,
+    // "disabled", "waiting" until draining starts, then "pending",
+    // "succeeded" or "failed".
+    pub deregistration: &'static str,
+}
+
+// Shared by the accept loop and every connection: when draining started,
+// whether the listeners have closed, and what is still in flight.
+pub struct Drain {
+    started: OnceLock<Instant>,
+    draining: CancellationToken,
+    closing: CancellationToken,
+    in_flight: AtomicUsize,
+    connections: AtomicUsize,
+    idle: Notify,
+    deregistration: Mutex<&'static str>,
+}
+
+impl Drain {
+    pub fn new(deregistration: bool) -> Self {
+        Self {
+            started: OnceLock::new(),
+            draining: CancellationToken::new(),
+            closing: CancellationToken::new(),
+            in_flight: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            idle: Notify::new(),
+            deregistration: Mutex::new(if deregistration { "waiting" } else { "disabled" }),
+        }
+    }
+
+    // True only for the call that started draining.
+    pub fn start(&self) -> bool {
+        if self.started.set(Instant::now()).is_err() {
+            return false;
+        }
+        let mut deregistration = self.deregistration.lock().unwrap();
+        if *deregistration == "waiting" {
+            *deregistration = "pending";
+        }
+        drop(deregistration);
+        self.draining.cancel();
+        true
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.started.get().is_some()
+    }
+
+    pub fn started(&self) -> WaitForCancellationFuture<'_> {
+        self.draining.cancelled()
+    }
+
+    // The listeners are closed; connections should finish what they have.
+    pub fn close(&self) {
+        self.start();
+        self.closing.cancel();
+    }
+
+    pub fn closing(&self) -> WaitForCancellationFuture<'_> {
+        self.closing.cancelled()
+    }
+
+    pub fn request(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight { drain: self, connection: false }
+    }
+
+    pub fn connection(&self) -> InFlight<'_> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        InFlight { drain: self, connection: true }
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    // Resolves once every connection has closed.
+    pub async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.open_connections() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn status(&self) -> DrainStatus {
+        let phase = if self.closing.is_cancelled() {
+            "closing"
+        } else if self.is_draining() {
+            "draining"
+        } else {
+            "serving"
+        };
+        DrainStatus {
+            draining: self.is_draining(),
+            phase,
+            elapsed_ms: self.started.get().map_or(0, |started| started.elapsed().as_millis() as u64),
+            in_flight_requests: self.in_flight.load(Ordering::SeqCst),
+            open_connections: self.open_connections(),
+            deregistration: *self.deregistration.lock().unwrap(),
+        }
+    }
+
+    // Retries with backoff until the endpoint answers 2xx or attempts run out.
+    pub async fn deregister(&self, config: &DeregistrationConfig) {
+        let result = Self::call_deregistration(config).await;
+        *self.deregistration.lock().unwrap() = if result { "succeeded" } else { "failed" };
+    }
+
+    async fn call_deregistration(config: &DeregistrationConfig) -> bool {
+        let Ok(method) = config.method() else {
+            return false;
+        };
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                warn!(error = %e, "failed to create deregistration client");
+                return false;
+            }
+        };
+
+        let mut backoff = Duration::from_millis(config.backoff_ms);
+        for attempt in 1..=config.attempts.max(1) {
+            let mut request = client.request(method.clone(), &config.url);
+            for (name, value) in &config.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            if let Some(body) = &config.body {
+                request = request.body(body.clone());
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!(attempt, url = %config.url, "deregistered");
+                    return true;
+                }
+                Ok(response) => {
+                    warn!(attempt, url = %config.url, status = response.status().as_u16(), "deregistration refused")
+                }
+                Err(e) => warn!(attempt, url = %config.url, error = %e, "deregistration failed"),
+            }
+            if attempt < config.attempts {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        false
+    }
+}
+
+// Counts a request or connection for as long as it is held.
+pub struct InFlight<'a> {
+    drain: &'a Drain,
+    connection: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.connection {
+            self.drain.in_flight.fetch_sub(1, Ordering::SeqCst);
+        } else if self.drain.connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.drain.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_idle_follows_connections() {
+        let drain = Drain::new(false);
+        drain.wait_idle().await;
+
+        let connection = drain.connection();
+        let request = drain.request();
+        assert_eq!(drain.status().in_flight_requests, 1);
+        let waiting = tokio::time::timeout(Duration::from_millis(50), drain.wait_idle()).await;
+        assert!(waiting.is_err());
+
+        drop(request);
+        drop(connection);
+        tokio::time::timeout(Duration::from_secs(1), drain.wait_idle()).await.unwrap();
+    }
+
+    #[test]
+    fn test_phases() {
+        let drain = Drain::new(false);
+        assert_eq!(drain.status().phase, "serving");
+        assert_eq!(Drain::new(true).status().deregistration, "waiting");
+        assert!(drain.start());
+        assert!(!drain.start());
+        assert_eq!(drain.status().phase, "draining");
+        drain.close();
+        assert_eq!(drain.status().phase, "closing");
+    }
+}
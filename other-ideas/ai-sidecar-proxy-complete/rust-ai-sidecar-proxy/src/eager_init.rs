@@ -0,0 +1,163 @@
+use crate::{ai::AIEngine, config::UpstreamService, egress::Direction, upstream_client::ClientCache};
+use futures::future::join_all;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Work the first request to each service would otherwise pay for, done before
+// the listener accepts anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EagerInitConfig {
+    // Off for fast local starts; everything is then built on first use.
+    pub enabled: bool,
+    // Startup carries on once this passes, with whatever is left done lazily.
+    pub deadline_ms: u64,
+}
+
+impl Default for EagerInitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            deadline_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EagerInitReport {
+    pub clients: usize,
+    pub client_failures: usize,
+    pub resolved: usize,
+    pub unresolved: usize,
+    pub health_entries: usize,
+    pub timed_out: bool,
+}
+
+pub async fn run(
+    config: &EagerInitConfig,
+    services: &[(Direction, &UpstreamService)],
+    clients: &ClientCache,
+    ai_engine: &AIEngine,
+) -> EagerInitReport {
+    let mut report = EagerInitReport::default();
+    if !config.enabled {
+        return report;
+    }
+    let started = Instant::now();
+    let deadline = Duration::from_millis(config.deadline_ms);
+    report.timed_out = tokio::time::timeout(deadline, steps(services, clients, ai_engine, &mut report))
+        .await
+        .is_err();
+
+    if report.timed_out {
+        warn!(deadline_ms = config.deadline_ms, ?report, "eager init ran out of time; the rest is built on first use");
+    } else {
+        info!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            clients = report.clients,
+            client_failures = report.client_failures,
+            resolved = report.resolved,
+            unresolved = report.unresolved,
+            health_entries = report.health_entries,
+            "eager init finished"
+        );
+    }
+    report
+}
+
+async fn steps(
+    services: &[(Direction, &UpstreamService)],
+    clients: &ClientCache,
+    ai_engine: &AIEngine,
+    report: &mut EagerInitReport,
+) {
+    let endpoints: Vec<String> = services.iter().flat_map(|(_, service)| service.endpoints.iter().cloned()).collect();
+    report.health_entries = ai_engine.seed_endpoints(&endpoints).await;
+
+    for (direction, service) in services {
+        match clients.get(*direction, service) {
+            Ok(_) => report.clients += 1,
+            // Left for the first request, which reports it to the client.
+            Err(e) => {
+                warn!(service = %service.name, error = format!("{:#}", e), "cannot build upstream client");
+                report.client_failures += 1;
+            }
+        }
+        // Building a client is synchronous; give the deadline a chance.
+        tokio::task::yield_now().await;
+    }
+
+    // The upstream resolver keeps no cache of its own, so this catches names
+    // that do not resolve and fills whatever cache the system has.
+    let lookups = services.iter().flat_map(|&(_, service)| {
+        service
+            .endpoints
+            .iter()
+            .filter_map(move |endpoint| lookup_target(service, endpoint))
+            .map(move |(host, port)| async move {
+                let resolved = tokio::net::lookup_host((host.as_str(), port)).await;
+                if let Err(e) = &resolved {
+                    warn!(service = %service.name, host = %host, error = %e, "endpoint host does not resolve");
+                }
+                resolved.is_ok()
+            })
+    });
+    for resolved in join_all(lookups).await {
+        if resolved {
+            report.resolved += 1;
+        } else {
+            report.unresolved += 1;
+        }
+    }
+}
+
+// Hosts the resolver would look up: not IP literals, and not names the
+// service pins in `hosts`.
+fn lookup_target(service: &UpstreamService, endpoint: &str) -> Option<(String, u16)> {
+    let url = Url::parse(endpoint).ok()?;
+    let host = url.domain()?;
+    if service.hosts.contains_key(host) {
+        return None;
+    }
+    Some((host.to_string(), url.port_or_known_default()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::metrics::MetricsCollector;
+    use crate::upstream_client::UpstreamPoolConfig;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_only_dns_names_are_looked_up() {
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.hosts.insert("pinned.internal".to_string(), vec!["10.0.0.1".parse().unwrap()]);
+
+        assert_eq!(lookup_target(service, "http://localhost:3001"), Some(("localhost".to_string(), 3001)));
+        assert_eq!(lookup_target(service, "https://api.internal"), Some(("api.internal".to_string(), 443)));
+        assert_eq!(lookup_target(service, "http://127.0.0.1:80"), None);
+        assert_eq!(lookup_target(service, "http://[::1]:80"), None);
+        assert_eq!(lookup_target(service, "http://pinned.internal:80"), None);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_does_nothing() {
+        let config = Config::new();
+        let services: Vec<_> = config.upstream_services.values().map(|service| (Direction::Ingress, service)).collect();
+        let clients = ClientCache::new(UpstreamPoolConfig::default(), Arc::new(MetricsCollector::new()));
+        let ai_engine = AIEngine::new();
+        let disabled = EagerInitConfig {
+            enabled: false,
+            ..EagerInitConfig::default()
+        };
+
+        assert_eq!(run(&disabled, &services, &clients, &ai_engine).await, EagerInitReport::default());
+        assert_eq!(clients.built(), 0);
+        assert_eq!(ai_engine.health_entries_created(), 0);
+    }
+}
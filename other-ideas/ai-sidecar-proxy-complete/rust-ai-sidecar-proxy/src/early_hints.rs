@@ -0,0 +1,587 @@
+use http::{header, HeaderMap, Version};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{ready, Context, Poll, Waker},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Sits between a client socket and hyper, whose server side has no way to
+// write a 1xx response. An upstream's 103 Early Hints goes straight to the
+// socket through an `EarlyHints` handle, as long as hyper has not started
+// writing the final response: as an HTTP/1.1 response between hyper's, or
+// as an HTTP/2 HEADERS frame between its frames.
+pub struct EarlyHintsIo<S> {
+    shared: Arc<Mutex<Shared<S>>>,
+}
+
+struct Shared<S> {
+    inner: S,
+    // Hints the socket has not taken yet; they go out ahead of hyper's next
+    // bytes.
+    pending: Vec<u8>,
+    // hyper has written bytes it has not flushed. HTTP/1 hints are dropped
+    // until it flushes, since they would land inside its response.
+    writing: bool,
+    // hyper is waiting on the socket. Polling it from elsewhere would take
+    // hyper's wakeup, so hints only queue until hyper's next call.
+    waiting: bool,
+    http2: Http2,
+}
+
+impl<S> EarlyHintsIo<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                inner,
+                pending: Vec::new(),
+                writing: false,
+                waiting: false,
+                http2: Http2::default(),
+            })),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin + Send + 'static> EarlyHintsIo<S> {
+    // The connection's handle; `for_request` narrows it to one request.
+    pub fn hints(&self) -> EarlyHints {
+        let shared: Arc<dyn Sink> = self.shared.clone();
+        EarlyHints { sink: Arc::downgrade(&shared), stream: None }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Shared<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // One of hyper's own writes, made after any queued hints.
+    fn poll_hyper<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let poll = match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => op(Pin::new(&mut self.inner), cx),
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+            Poll::Pending => Poll::Pending,
+        };
+        self.waiting = poll.is_pending();
+        poll
+    }
+
+    fn queue(&mut self, hints: &[u8]) {
+        self.pending.extend_from_slice(hints);
+        if !self.waiting {
+            // Nothing waits on this write. Whatever the socket does not take
+            // now goes out with hyper's next write, and a failed socket fails
+            // that write too.
+            let mut cx = Context::from_waker(Waker::noop());
+            if let Poll::Ready(Ok(())) = self.poll_pending(&mut cx) {
+                let _ = Pin::new(&mut self.inner).poll_flush(&mut cx);
+            }
+        }
+    }
+}
+
+trait Sink: Send + Sync {
+    fn next_stream(&self) -> Option<u32>;
+    fn send(&self, headers: &HeaderMap, stream: Option<u32>) -> bool;
+}
+
+impl<S: AsyncWrite + Unpin + Send> Sink for Mutex<Shared<S>> {
+    fn next_stream(&self) -> Option<u32> {
+        self.lock().unwrap().http2.opened.pop_front()
+    }
+
+    fn send(&self, headers: &HeaderMap, stream: Option<u32>) -> bool {
+        let mut shared = self.lock().unwrap();
+        let hints = match stream {
+            None if shared.writing || shared.http2.protocol == Protocol::Http2 => return false,
+            None => http1_hints(headers),
+            Some(stream) if !shared.http2.may_send(stream) => return false,
+            Some(stream) => match http2_hints(stream, headers) {
+                Some(hints) => hints,
+                None => return false,
+            },
+        };
+        shared.queue(&hints);
+        true
+    }
+}
+
+// Writes 103 Early Hints to one request's client. It holds the connection
+// weakly, so a closed connection is not kept open by a late upstream.
+#[derive(Clone)]
+pub struct EarlyHints {
+    sink: Weak<dyn Sink>,
+    // The HTTP/2 stream the request came on; none for HTTP/1.1.
+    stream: Option<u32>,
+}
+
+impl EarlyHints {
+    // The handle for a request hyper has just handed over. HTTP/1.0 clients
+    // must not be sent a 1xx. HTTP/2 requests are paired with the streams
+    // the client opened in order, which is the order hyper accepts them in;
+    // a stream h2 refuses before hyper's reset for it is written can shift a
+    // later request's hints onto the same client's next stream.
+    pub fn for_request(&self, version: Version) -> Option<EarlyHints> {
+        match version {
+            Version::HTTP_11 => Some(self.clone()),
+            Version::HTTP_2 => {
+                let stream = self.sink.upgrade()?.next_stream()?;
+                Some(EarlyHints { sink: self.sink.clone(), stream: Some(stream) })
+            }
+            _ => None,
+        }
+    }
+
+    // A 103 with the Link values in `headers`, the only ones a client acts on.
+    // False when there are none, or the request has moved on to the final
+    // response or its connection closed.
+    pub fn send(&self, headers: &HeaderMap) -> bool {
+        if !headers.contains_key(header::LINK) {
+            return false;
+        }
+        self.sink.upgrade().is_some_and(|sink| sink.send(headers, self.stream))
+    }
+}
+
+fn http1_hints(headers: &HeaderMap) -> Vec<u8> {
+    let mut hints = b"HTTP/1.1 103 Early Hints\r\n".to_vec();
+    for value in headers.get_all(header::LINK) {
+        hints.extend_from_slice(b"link: ");
+        hints.extend_from_slice(value.as_bytes());
+        hints.extend_from_slice(b"\r\n");
+    }
+    hints.extend_from_slice(b"\r\n");
+    hints
+}
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const CONTINUATION: u8 = 0x9;
+const END_HEADERS: u8 = 0x4;
+const ACK: u8 = 0x1;
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+// The smallest SETTINGS_MAX_FRAME_SIZE a client may set.
+const MAX_FRAME: usize = 16_384;
+
+// A HEADERS frame for stream `stream` holding `:status: 103` and the Link
+// values. Every field is a literal that is never indexed, so hyper's HPACK
+// table and the client's stay as they were. None when it would not fit in
+// one frame.
+fn http2_hints(stream: u32, headers: &HeaderMap) -> Option<Vec<u8>> {
+    let mut block = Vec::new();
+    // :status, static table entry 8.
+    hpack_int(&mut block, 0x00, 4, 8);
+    hpack_int(&mut block, 0x00, 7, 3);
+    block.extend_from_slice(b"103");
+    for value in headers.get_all(header::LINK) {
+        // link, static table entry 45.
+        hpack_int(&mut block, 0x00, 4, 45);
+        hpack_int(&mut block, 0x00, 7, value.len());
+        block.extend_from_slice(value.as_bytes());
+    }
+    if block.len() > MAX_FRAME {
+        return None;
+    }
+    let mut frame = Vec::with_capacity(9 + block.len());
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    frame.extend_from_slice(&[HEADERS, END_HEADERS]);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(&block);
+    Some(frame)
+}
+
+fn hpack_int(out: &mut Vec<u8>, flags: u8, prefix: u32, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[derive(PartialEq)]
+enum Protocol {
+    // Bytes of the HTTP/2 client preface the client has sent so far.
+    Preface(usize),
+    Http1,
+    Http2,
+}
+
+// What the frames each way say about where a 103 may go on an HTTP/2
+// connection.
+struct Http2 {
+    protocol: Protocol,
+    client: Frames,
+    server: Frames,
+    // Streams the client opened that hyper has not handed over yet.
+    opened: VecDeque<u32>,
+    last_opened: u32,
+    // Streams whose final response hyper has not started.
+    awaiting: Vec<u32>,
+    // hyper is partway through a header block, which nothing may interrupt.
+    in_block: bool,
+    // The client's HPACK table size as of hyper's last header block, and
+    // the sizes it has asked for since. Once it asks for less, its next
+    // block must start by saying so, which only hyper can do.
+    table_size: u32,
+    table_limit: u32,
+    table_min: u32,
+    settings_received: u32,
+    settings_acked: u32,
+}
+
+impl Default for Http2 {
+    fn default() -> Self {
+        Self {
+            protocol: Protocol::Preface(0),
+            client: Frames::default(),
+            server: Frames::default(),
+            opened: VecDeque::new(),
+            last_opened: 0,
+            awaiting: Vec::new(),
+            in_block: false,
+            table_size: 4096,
+            table_limit: 4096,
+            table_min: 4096,
+            settings_received: 0,
+            settings_acked: 0,
+        }
+    }
+}
+
+impl Http2 {
+    fn read(&mut self, mut bytes: &[u8]) {
+        if let Protocol::Preface(matched) = self.protocol {
+            let n = bytes.len().min(PREFACE.len() - matched);
+            if bytes[..n] != PREFACE[matched..matched + n] {
+                self.protocol = Protocol::Http1;
+                return;
+            }
+            self.protocol = if matched + n == PREFACE.len() { Protocol::Http2 } else { Protocol::Preface(matched + n) };
+            bytes = &bytes[n..];
+        }
+        if self.protocol != Protocol::Http2 {
+            return;
+        }
+        let mut frames = std::mem::take(&mut self.client);
+        frames.feed(bytes, |head, payload| self.client_frame(head, payload));
+        self.client = frames;
+    }
+
+    fn written(&mut self, bytes: &[u8]) {
+        if self.protocol != Protocol::Http2 {
+            return;
+        }
+        let mut frames = std::mem::take(&mut self.server);
+        frames.feed(bytes, |head, _| self.server_frame(head));
+        self.server = frames;
+    }
+
+    fn client_frame(&mut self, head: &Head, payload: &[u8]) {
+        match head.kind {
+            HEADERS if head.stream > self.last_opened => {
+                self.last_opened = head.stream;
+                self.opened.push_back(head.stream);
+                self.awaiting.push(head.stream);
+            }
+            RST_STREAM => self.awaiting.retain(|&stream| stream != head.stream),
+            SETTINGS if head.flags & ACK == 0 => {
+                self.settings_received += 1;
+                for setting in payload.chunks_exact(6) {
+                    if u16::from_be_bytes([setting[0], setting[1]]) == SETTINGS_HEADER_TABLE_SIZE {
+                        self.table_limit = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                        self.table_min = self.table_min.min(self.table_limit);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn server_frame(&mut self, head: &Head) {
+        match head.kind {
+            SETTINGS if head.flags & ACK != 0 => self.settings_acked += 1,
+            HEADERS | RST_STREAM => {
+                self.awaiting.retain(|&stream| stream != head.stream);
+                // One h2 refused or answered itself, never handed over.
+                self.opened.retain(|&stream| stream != head.stream);
+                if head.kind == HEADERS {
+                    self.in_block = head.flags & END_HEADERS == 0;
+                    // h2 takes up the client's settings as it acknowledges
+                    // them, and its next block carries any change.
+                    if self.settings_acked == self.settings_received {
+                        self.table_size = self.table_limit;
+                        self.table_min = self.table_limit;
+                    }
+                }
+            }
+            CONTINUATION => self.in_block = head.flags & END_HEADERS == 0,
+            _ => {}
+        }
+    }
+
+    fn may_send(&self, stream: u32) -> bool {
+        self.protocol == Protocol::Http2
+            && self.server.at_boundary()
+            && !self.in_block
+            && self.table_min >= self.table_size
+            && self.awaiting.contains(&stream)
+    }
+}
+
+struct Head {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+}
+
+// Follows frame boundaries through one direction's bytes.
+#[derive(Default)]
+struct Frames {
+    // The next frame header as far as it has come, then a SETTINGS
+    // frame's payload.
+    buf: Vec<u8>,
+    head: Option<Head>,
+    left: usize,
+}
+
+impl Frames {
+    // Calls `seen` with each frame once its last byte has gone by, along
+    // with the payload of a SETTINGS frame.
+    fn feed(&mut self, mut bytes: &[u8], mut seen: impl FnMut(&Head, &[u8])) {
+        while !bytes.is_empty() {
+            let Some(head) = &self.head else {
+                let n = bytes.len().min(9 - self.buf.len());
+                self.buf.extend_from_slice(&bytes[..n]);
+                bytes = &bytes[n..];
+                if self.buf.len() == 9 {
+                    let b = &self.buf;
+                    self.left = u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize;
+                    let head = Head {
+                        kind: b[3],
+                        flags: b[4],
+                        stream: u32::from_be_bytes([b[5], b[6], b[7], b[8]]) & 0x7fff_ffff,
+                    };
+                    self.buf.clear();
+                    if self.left == 0 {
+                        seen(&head, &[]);
+                    } else {
+                        self.head = Some(head);
+                    }
+                }
+                continue;
+            };
+            let n = bytes.len().min(self.left);
+            if head.kind == SETTINGS && self.buf.len() < MAX_FRAME {
+                self.buf.extend_from_slice(&bytes[..n]);
+            }
+            bytes = &bytes[n..];
+            self.left -= n;
+            if self.left == 0 {
+                if let Some(head) = self.head.take() {
+                    seen(&head, &self.buf);
+                }
+                self.buf.clear();
+            }
+        }
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.head.is_none() && self.buf.is_empty()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EarlyHintsIo<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut shared.inner).poll_read(cx, buf);
+        shared.http2.read(&buf.filled()[before..]);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EarlyHintsIo<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        let poll = shared.poll_hyper(cx, |io, cx| io.poll_write(cx, buf));
+        if let Poll::Ready(Ok(n)) = poll {
+            shared.writing |= n > 0;
+            shared.http2.written(&buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        let poll = shared.poll_hyper(cx, |io, cx| io.poll_write_vectored(cx, bufs));
+        if let Poll::Ready(Ok(mut n)) = poll {
+            shared.writing |= n > 0;
+            for buf in bufs {
+                let taken = n.min(buf.len());
+                shared.http2.written(&buf[..taken]);
+                n -= taken;
+            }
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.shared.lock().unwrap().inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        let poll = shared.poll_hyper(cx, |io, cx| io.poll_flush(cx));
+        if matches!(poll, Poll::Ready(Ok(()))) {
+            shared.writing = false;
+        }
+        poll
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().unwrap().poll_hyper(cx, |io, cx| io.poll_shutdown(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn links(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::LINK, header::HeaderValue::from_static(value));
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_hints_go_out_only_before_the_final_response() {
+        let (client, mut peer) = tokio::io::duplex(1024);
+        let mut io = EarlyHintsIo::new(client);
+        let hints = io.hints();
+
+        assert!(hints.send(&links("</a.css>; rel=preload")));
+        assert!(!hints.send(&HeaderMap::new()));
+        io.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        // Mid-response until hyper flushes.
+        assert!(!hints.send(&links("</b.css>; rel=preload")));
+        io.write_all(b"content-length: 0\r\n\r\n").await.unwrap();
+        io.flush().await.unwrap();
+        drop(io);
+
+        let mut written = String::new();
+        peer.read_to_string(&mut written).await.unwrap();
+        assert_eq!(
+            written,
+            "HTTP/1.1 103 Early Hints\r\nlink: </a.css>; rel=preload\r\n\r\n\
+             HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n"
+        );
+        assert!(!hints.send(&links("</c.css>; rel=preload")));
+    }
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // Preface, SETTINGS and then a request on each stream, read through `io`
+    // the way hyper would.
+    async fn open_streams(io: &mut EarlyHintsIo<DuplexStream>, peer: &mut DuplexStream, settings: &[u8], streams: &[u32]) {
+        let mut sent = PREFACE.to_vec();
+        sent.extend(frame(SETTINGS, 0, 0, settings));
+        for &stream in streams {
+            sent.extend(frame(HEADERS, END_HEADERS | 0x1, stream, &[0x82]));
+        }
+        peer.write_all(&sent).await.unwrap();
+        io.read_exact(&mut vec![0; sent.len()]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http2_hints_go_out_on_streams_still_awaiting_a_response() {
+        let (client, mut peer) = tokio::io::duplex(4096);
+        let mut io = EarlyHintsIo::new(client);
+        let connection = io.hints();
+        open_streams(&mut io, &mut peer, &[], &[1, 3]).await;
+        let first = connection.for_request(Version::HTTP_2).unwrap();
+        let second = connection.for_request(Version::HTTP_2).unwrap();
+        assert!(connection.for_request(Version::HTTP_2).is_none());
+        assert!(connection.for_request(Version::HTTP_10).is_none());
+
+        // Not partway through one of hyper's frames.
+        let settings = frame(SETTINGS, 0, 0, &[0, 3, 0, 0, 0, 100]);
+        io.write_all(&settings[..5]).await.unwrap();
+        assert!(!second.send(&links("</a.css>; rel=preload")));
+        io.write_all(&settings[5..]).await.unwrap();
+        assert!(second.send(&links("</a.css>; rel=preload")));
+        // Stream 3's final response has started.
+        let response = frame(HEADERS, END_HEADERS, 3, &[0x88]);
+        io.write_all(&response).await.unwrap();
+        assert!(!second.send(&links("</b.css>; rel=preload")));
+        assert!(first.send(&links("</c.css>; rel=preload")));
+        io.flush().await.unwrap();
+        drop(io);
+
+        let hints = |stream: u32, link: &str| {
+            let mut block = vec![0x08, 3];
+            block.extend_from_slice(b"103");
+            block.extend_from_slice(&[0x0f, 0x1e, link.len() as u8]);
+            block.extend_from_slice(link.as_bytes());
+            frame(HEADERS, END_HEADERS, stream, &block)
+        };
+        let mut written = Vec::new();
+        peer.read_to_end(&mut written).await.unwrap();
+        assert_eq!(
+            written,
+            [settings, hints(3, "</a.css>; rel=preload"), response, hints(1, "</c.css>; rel=preload")].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http2_hints_wait_out_a_smaller_hpack_table() {
+        let (client, mut peer) = tokio::io::duplex(4096);
+        let mut io = EarlyHintsIo::new(client);
+        let connection = io.hints();
+        open_streams(&mut io, &mut peer, &[0, 1, 0, 0, 0, 0], &[1, 3]).await;
+        let first = connection.for_request(Version::HTTP_2).unwrap();
+        let second = connection.for_request(Version::HTTP_2).unwrap();
+
+        assert!(!first.send(&links("</a.css>; rel=preload")));
+        io.write_all(&frame(SETTINGS, ACK, 0, &[])).await.unwrap();
+        assert!(!first.send(&links("</a.css>; rel=preload")));
+        // hyper's first block after the acknowledgement tells the client.
+        io.write_all(&frame(HEADERS, END_HEADERS, 3, &[0x20, 0x88])).await.unwrap();
+        assert!(first.send(&links("</a.css>; rel=preload")));
+        assert!(!second.send(&links("</a.css>; rel=preload")));
+    }
+}
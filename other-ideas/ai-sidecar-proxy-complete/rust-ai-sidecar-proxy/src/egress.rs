@@ -0,0 +1,149 @@
+use crate::config::UpstreamService;
+use hyper::{HeaderMap, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+// Which listener a request came in on. Ingress traffic is addressed to the
+// application behind the sidecar; egress traffic is the application's own
+// outbound calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 2] = [Direction::Ingress, Direction::Egress];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::Ingress => "ingress",
+            Direction::Egress => "egress",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EgressConfig {
+    pub destination_header: String,
+    // Logical destination name to upstream. Doubles as the allowlist: names
+    // not listed here are refused.
+    pub services: HashMap<String, UpstreamService>,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            destination_header: "x-proxy-destination".to_string(),
+            services: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestinationError {
+    Missing,
+    Malformed,
+    NotAllowed(String),
+}
+
+impl DestinationError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DestinationError::Missing => "missing",
+            DestinationError::Malformed => "malformed",
+            DestinationError::NotAllowed(_) => "not_allowed",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            DestinationError::Missing | DestinationError::Malformed => StatusCode::BAD_REQUEST,
+            DestinationError::NotAllowed(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl fmt::Display for DestinationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestinationError::Missing => f.write_str("no egress destination given"),
+            DestinationError::Malformed => f.write_str("malformed egress destination"),
+            DestinationError::NotAllowed(name) => write!(f, "egress destination {:?} is not allowed", name),
+        }
+    }
+}
+
+impl std::error::Error for DestinationError {}
+
+impl EgressConfig {
+    // The destination header wins; otherwise the host of an absolute-form
+    // request target (`GET http://payments/charge`), which is what clients
+    // configured to use the sidecar as their HTTP proxy send.
+    pub fn resolve(&self, headers: &HeaderMap, uri: &Uri) -> Result<(&str, &UpstreamService), DestinationError> {
+        let name = match headers.get(self.destination_header.as_str()) {
+            Some(value) => value.to_str().map_err(|_| DestinationError::Malformed)?.trim(),
+            None => uri.host().ok_or(DestinationError::Missing)?,
+        };
+        if !is_logical_name(name) {
+            return Err(DestinationError::Malformed);
+        }
+        self.services
+            .get_key_value(name)
+            .map(|(name, service)| (name.as_str(), service))
+            .ok_or_else(|| DestinationError::NotAllowed(name.to_string()))
+    }
+}
+
+// Names only, never addresses or URLs, so a destination cannot smuggle in a
+// port, path or credentials.
+fn is_logical_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn egress() -> EgressConfig {
+        let service = Config::new().upstream_services["service-a"].clone();
+        EgressConfig {
+            services: HashMap::from([("payments".to_string(), service)]),
+            ..EgressConfig::default()
+        }
+    }
+
+    fn resolve(header: Option<&str>, uri: &str) -> Result<String, DestinationError> {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = header {
+            headers.insert("x-proxy-destination", value.parse().unwrap());
+        }
+        egress()
+            .resolve(&headers, &uri.parse().unwrap())
+            .map(|(name, _)| name.to_string())
+    }
+
+    #[test]
+    fn test_destination_from_header_or_absolute_target() {
+        assert_eq!(resolve(Some("payments"), "/charge"), Ok("payments".to_string()));
+        assert_eq!(resolve(None, "http://payments:8080/charge"), Ok("payments".to_string()));
+        assert_eq!(resolve(None, "/charge"), Err(DestinationError::Missing));
+    }
+
+    #[test]
+    fn test_only_listed_logical_names_resolve() {
+        assert_eq!(
+            resolve(Some("ledger"), "/"),
+            Err(DestinationError::NotAllowed("ledger".to_string()))
+        );
+        for malformed in ["payments:443", "http://payments", "pay ments", "payments/../admin", ""] {
+            assert_eq!(resolve(Some(malformed), "/"), Err(DestinationError::Malformed), "{:?}", malformed);
+        }
+    }
+}
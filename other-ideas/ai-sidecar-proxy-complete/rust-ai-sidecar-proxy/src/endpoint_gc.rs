@@ -0,0 +1,241 @@
+use crate::{
+    ai::AIEngine, clock::Clock, health_checker::HealthChecker, load_balancer::LoadBalancers,
+    metrics::MetricsCollector, supervisor::TaskSupervisor,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::interval;
+use tracing::info;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointGcConfig {
+    pub interval_ms: u64,
+    // How long an endpoint must be gone from the config before its state is
+    // dropped. Long enough that re-adding it after a brief removal keeps its
+    // history, and that requests still in flight to it have finished.
+    pub ttl_ms: u64,
+}
+
+impl Default for EndpointGcConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 60_000,
+            ttl_ms: 600_000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RegistryState {
+    known: HashSet<String>,
+    absent_since: HashMap<String, Instant>,
+}
+
+// Every endpoint the proxy can currently route to, and when each of the
+// others disappeared. Only endpoints that were known and then removed ever
+// expire; health plays no part.
+pub struct EndpointRegistry {
+    clock: Arc<dyn Clock>,
+    state: Mutex<RegistryState>,
+}
+
+impl EndpointRegistry {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            state: Mutex::new(RegistryState::default()),
+        }
+    }
+
+    // Replaces the known set, e.g. after the endpoints of a service change.
+    pub fn update(&self, endpoints: impl IntoIterator<Item = String>) {
+        let now = self.clock.now();
+        let known: HashSet<String> = endpoints.into_iter().collect();
+        let mut state = self.state.lock().unwrap();
+        for endpoint in &known {
+            state.absent_since.remove(endpoint);
+        }
+        let removed: Vec<String> = state.known.difference(&known).cloned().collect();
+        for endpoint in removed {
+            state.absent_since.entry(endpoint).or_insert(now);
+        }
+        state.known = known;
+    }
+
+    pub fn is_known(&self, endpoint: &str) -> bool {
+        self.state.lock().unwrap().known.contains(endpoint)
+    }
+
+    // Endpoints absent for at least `ttl`. Each is handed out once.
+    pub fn take_expired(&self, ttl: Duration) -> Vec<String> {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        self.state.lock().unwrap().absent_since.retain(|endpoint, since| {
+            if now.saturating_duration_since(*since) < ttl {
+                return true;
+            }
+            expired.push(endpoint.clone());
+            false
+        });
+        expired.sort();
+        expired
+    }
+}
+
+// Periodically drops per-endpoint state for expired endpoints from every
+// module that keeps some. Circuit breakers are per service, and services
+// cannot be removed at runtime, so they have nothing to collect.
+pub struct EndpointGc {
+    config: EndpointGcConfig,
+    registry: Arc<EndpointRegistry>,
+    ai_engine: Arc<AIEngine>,
+    health_checker: Arc<HealthChecker>,
+    load_balancer: Arc<LoadBalancers>,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl EndpointGc {
+    pub fn new(
+        config: EndpointGcConfig,
+        registry: Arc<EndpointRegistry>,
+        ai_engine: Arc<AIEngine>,
+        health_checker: Arc<HealthChecker>,
+        load_balancer: Arc<LoadBalancers>,
+        metrics: Arc<MetricsCollector>,
+    ) -> Self {
+        Self {
+            config,
+            registry,
+            ai_engine,
+            health_checker,
+            load_balancer,
+            metrics,
+        }
+    }
+
+    pub fn start(self: &Arc<Self>, supervisor: &Arc<TaskSupervisor>) {
+        let gc = self.clone();
+        supervisor.spawn("endpoint_gc", false, move |heartbeat| {
+            let gc = gc.clone();
+            async move {
+                let mut interval = interval(Duration::from_millis(gc.config.interval_ms.max(1)));
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    gc.sweep().await;
+                }
+            }
+        });
+    }
+
+    // Returns the number of state entries removed.
+    pub async fn sweep(&self) -> usize {
+        let endpoints = self.registry.take_expired(Duration::from_millis(self.config.ttl_ms));
+        if endpoints.is_empty() {
+            return 0;
+        }
+
+        let ai_engine = self.ai_engine.forget_endpoints(&endpoints).await;
+        let health_checker = self.health_checker.forget_endpoints(&endpoints).await;
+        let load_balancer = self.load_balancer.forget_endpoints(&endpoints).await;
+        let metrics = self.metrics.forget_endpoints(&endpoints).await;
+        self.metrics.record_endpoint_gc("ai_engine", ai_engine);
+        self.metrics.record_endpoint_gc("health_checker", health_checker);
+        self.metrics.record_endpoint_gc("load_balancer", load_balancer);
+        self.metrics.record_endpoint_gc("metrics", metrics);
+
+        info!(
+            endpoints = ?endpoints,
+            ai_engine,
+            health_checker,
+            load_balancer,
+            metrics,
+            "collected state of removed endpoints"
+        );
+        ai_engine + health_checker + load_balancer + metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ai::RequestMetrics, clock::MockClock, egress::Direction, load_balancer::LoadBalancer};
+
+    const TTL: Duration = Duration::from_secs(600);
+
+    fn endpoints(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_only_removed_endpoints_expire() {
+        let clock = Arc::new(MockClock::new());
+        let registry = EndpointRegistry::new(clock.clone());
+        registry.update(endpoints(&["http://a", "http://b", "http://c"]));
+        registry.update(endpoints(&["http://a", "http://c"]));
+        assert!(!registry.is_known("http://b"));
+
+        clock.advance(TTL / 2);
+        // Back before the TTL: its clock restarts if it goes away again.
+        registry.update(endpoints(&["http://a"]));
+        registry.update(endpoints(&["http://a", "http://c"]));
+        assert!(registry.take_expired(TTL).is_empty());
+
+        clock.advance(TTL / 2);
+        assert_eq!(registry.take_expired(TTL), endpoints(&["http://b"]));
+        assert!(registry.take_expired(TTL).is_empty());
+
+        clock.advance(TTL * 10);
+        assert!(registry.take_expired(TTL).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_clears_every_module() {
+        let clock = Arc::new(MockClock::new());
+        let registry = Arc::new(EndpointRegistry::new(clock.clone()));
+        let ai_engine = Arc::new(AIEngine::new());
+        let health_checker = Arc::new(HealthChecker::new(HashMap::new(), ai_engine.clone()));
+        let load_balancer = Arc::new(LoadBalancers::new(LoadBalancer::new(), &HashMap::new()));
+        let metrics = Arc::new(MetricsCollector::new());
+        let gc = EndpointGc::new(
+            EndpointGcConfig::default(),
+            registry.clone(),
+            ai_engine.clone(),
+            health_checker,
+            load_balancer.clone(),
+            metrics.clone(),
+        );
+
+        for endpoint in ["http://kept", "http://removed"] {
+            ai_engine
+                .record_request(RequestMetrics {
+                    latency_ms: 5,
+                    status_code: 503,
+                    endpoint: endpoint.to_string(),
+                    timestamp: 0,
+                    success: false,
+                    phases: None,
+                })
+                .await;
+            load_balancer.for_service("svc").set_endpoint_weight("svc", endpoint, 3).await;
+            metrics.record_request(Direction::Ingress, endpoint, 5, false).await;
+        }
+        registry.update(endpoints(&["http://kept", "http://removed"]));
+        registry.update(endpoints(&["http://kept"]));
+        assert_eq!(gc.sweep().await, 0);
+
+        clock.advance(TTL);
+        assert_eq!(gc.sweep().await, 3);
+        assert!(ai_engine.get_service_health("http://removed").await.is_none());
+        // Failing, but still configured.
+        assert!(ai_engine.get_service_health("http://kept").await.is_some());
+        assert_eq!(metrics.get_endpoint_stats().await.keys().collect::<Vec<_>>(), vec!["http://kept"]);
+        assert_eq!(metrics.endpoint_gc_count("ai_engine"), 1);
+        assert_eq!(metrics.endpoint_gc_count("load_balancer"), 1);
+    }
+}
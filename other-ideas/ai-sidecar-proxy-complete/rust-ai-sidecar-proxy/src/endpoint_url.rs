@@ -0,0 +1,52 @@
+use reqwest::Url;
+
+// The one spelling of an endpoint URL that every map keyed by endpoint uses:
+// lowercase scheme and host, no default port, no trailing slash. Anything
+// that is not an absolute http or https URL is an error, including a bare
+// "host:port", which would otherwise parse with the host as its scheme.
+pub fn canonicalize(endpoint: &str) -> Result<String, String> {
+    if !endpoint.contains("://") {
+        return Err(format!("{:?} has no scheme; write it as {:?}", endpoint, format!("http://{}", endpoint)));
+    }
+    let url = Url::parse(endpoint).map_err(|e| format!("{:?} is not a valid URL: {}", endpoint, e))?;
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+        return Err(format!("{:?} is not an http or https URL", endpoint));
+    }
+    // Endpoints are prefixes that request paths are appended to, so the
+    // slash goes; with a query or fragment the URL is left as parsed.
+    let canonical = url.as_str();
+    if url.query().is_some() || url.fragment().is_some() {
+        return Ok(canonical.to_string());
+    }
+    Ok(canonical.trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equivalent_spellings_share_a_form() {
+        for endpoint in [
+            "http://api.internal:8080",
+            "HTTP://API.Internal:8080/",
+            "http://api.internal:8080//",
+        ] {
+            assert_eq!(canonicalize(endpoint).unwrap(), "http://api.internal:8080", "{}", endpoint);
+        }
+        assert_eq!(canonicalize("http://api.internal:80/").unwrap(), "http://api.internal");
+        assert_eq!(canonicalize("https://API.internal:443").unwrap(), "https://api.internal");
+        assert_eq!(canonicalize("https://api.internal:80").unwrap(), "https://api.internal:80");
+        assert_eq!(canonicalize("http://api.internal/v1/").unwrap(), "http://api.internal/v1");
+        assert_eq!(canonicalize("http://[::1]:3001/").unwrap(), "http://[::1]:3001");
+    }
+
+    #[test]
+    fn test_missing_scheme_is_rejected() {
+        let error = canonicalize("localhost:3001").unwrap_err();
+        assert!(error.contains("has no scheme"), "{}", error);
+        assert!(error.contains("http://localhost:3001"), "{}", error);
+        assert!(canonicalize("ftp://files.internal").unwrap_err().contains("not an http or https URL"));
+        assert!(canonicalize("http://").is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{Context, Result};
+use hyper::{header, HeaderMap};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentsConfig {
+    #[serde(default)]
+    pub experiments: Vec<ExperimentConfig>,
+}
+
+// Splits one route's traffic between services. The first variant is the
+// control; identifiers that land past the last variant's fraction, and
+// requests without an identifier, are not enrolled and route as usual.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    pub name: String,
+    // As matched by the router, e.g. "/api/a".
+    pub route: String,
+    // Changing the salt reshuffles every identifier.
+    pub salt: String,
+    pub identifier: IdentifierSource,
+    pub variants: Vec<VariantConfig>,
+    // Sends every request to the control, enrolled or not.
+    #[serde(default)]
+    pub kill_switch: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentifierSource {
+    Header(String),
+    Cookie(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    pub name: String,
+    pub service: String,
+    // Share of identifiers assigned to this variant, between 0 and 1.
+    pub fraction: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment<'a> {
+    pub experiment: &'a str,
+    pub variant: &'a str,
+    pub service: &'a str,
+}
+
+impl Assignment<'_> {
+    // Sent back to the client as x-experiment so conversions can be joined to
+    // the variant that served them.
+    pub fn header_value(&self) -> String {
+        format!("{}={}", self.experiment, self.variant)
+    }
+}
+
+#[derive(Default)]
+pub struct Experiments {
+    config: ExperimentsConfig,
+}
+
+impl Experiments {
+    // `known_service` rejects variants that would route nowhere.
+    pub fn compile(config: &ExperimentsConfig, known_service: impl Fn(&str) -> bool) -> Result<Self> {
+        let mut names = HashSet::new();
+        let mut routes = HashSet::new();
+        for experiment in &config.experiments {
+            Self::validate(experiment, &known_service).with_context(|| format!("experiment {:?}", experiment.name))?;
+            anyhow::ensure!(names.insert(&experiment.name), "duplicate experiment {:?}", experiment.name);
+            anyhow::ensure!(
+                routes.insert(&experiment.route),
+                "more than one experiment on route {:?}",
+                experiment.route
+            );
+        }
+        Ok(Self { config: config.clone() })
+    }
+
+    fn validate(experiment: &ExperimentConfig, known_service: &impl Fn(&str) -> bool) -> Result<()> {
+        anyhow::ensure!(!experiment.variants.is_empty(), "no variants");
+        let mut names = HashSet::new();
+        let mut total = 0.0;
+        for variant in &experiment.variants {
+            anyhow::ensure!(names.insert(&variant.name), "duplicate variant {:?}", variant.name);
+            anyhow::ensure!(known_service(&variant.service), "unknown service {:?}", variant.service);
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&variant.fraction),
+                "fraction {} of variant {:?} is not between 0 and 1",
+                variant.fraction,
+                variant.name
+            );
+            total += variant.fraction;
+        }
+        anyhow::ensure!(total <= 1.0 + 1e-9, "variant fractions add up to {}", total);
+        Ok(())
+    }
+
+    pub fn config(&self) -> &ExperimentsConfig {
+        &self.config
+    }
+
+    pub fn assign(&self, route: &str, headers: &HeaderMap) -> Option<Assignment<'_>> {
+        let experiment = self.config.experiments.iter().find(|experiment| experiment.route == route)?;
+        let assignment = |variant: usize| {
+            let variant = &experiment.variants[variant];
+            Some(Assignment {
+                experiment: experiment.name.as_str(),
+                variant: variant.name.as_str(),
+                service: variant.service.as_str(),
+            })
+        };
+        if experiment.kill_switch {
+            return assignment(0);
+        }
+
+        let identifier = identifier(&experiment.identifier, headers)?;
+        let bucket = bucket(&experiment.salt, identifier);
+        let mut upper = 0.0;
+        for (i, variant) in experiment.variants.iter().enumerate() {
+            upper += variant.fraction;
+            if bucket < upper {
+                return assignment(i);
+            }
+        }
+        None
+    }
+}
+
+fn identifier<'a>(source: &IdentifierSource, headers: &'a HeaderMap) -> Option<&'a str> {
+    let found = match source {
+        IdentifierSource::Header(name) => headers.get(name.as_str()).and_then(|value| value.to_str().ok()),
+        IdentifierSource::Cookie(name) => headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                (key == name).then_some(value)
+            }),
+    };
+    found.map(str::trim).filter(|identifier| !identifier.is_empty())
+}
+
+// Position of an identifier in [0, 1). SHA-256 rather than the std hasher so
+// assignments survive restarts and upgrades.
+fn bucket(salt: &str, identifier: &str) -> f64 {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(salt.as_bytes());
+    context.update(b":");
+    context.update(identifier.as_bytes());
+    let hash = context.finish();
+    let top = u64::from_be_bytes(hash.as_ref()[..8].try_into().unwrap());
+    (top >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn experiment(fractions: &[f64]) -> ExperimentConfig {
+        ExperimentConfig {
+            name: "checkout".to_string(),
+            route: "/api/a".to_string(),
+            salt: "2024-q1".to_string(),
+            identifier: IdentifierSource::Header("x-user-id".to_string()),
+            variants: fractions
+                .iter()
+                .enumerate()
+                .map(|(i, &fraction)| VariantConfig {
+                    name: ["control", "treatment"][i].to_string(),
+                    service: format!("service-{}", ["a", "b"][i]),
+                    fraction,
+                })
+                .collect(),
+            kill_switch: false,
+        }
+    }
+
+    fn compile(experiments: Vec<ExperimentConfig>) -> Experiments {
+        Experiments::compile(&ExperimentsConfig { experiments }, |_| true).unwrap()
+    }
+
+    fn user(id: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user-id", HeaderValue::from_str(id).unwrap());
+        headers
+    }
+
+    fn variant(experiments: &Experiments, id: &str) -> Option<String> {
+        experiments.assign("/api/a", &user(id)).map(|assignment| assignment.variant.to_string())
+    }
+
+    #[test]
+    fn test_assignment_is_sticky_per_identifier() {
+        let experiments = compile(vec![experiment(&[0.5, 0.5])]);
+        let recompiled = compile(vec![experiment(&[0.5, 0.5])]);
+        for i in 0..1000 {
+            let id = format!("user-{}", i);
+            let first = variant(&experiments, &id);
+            assert!(first.is_some());
+            assert_eq!(variant(&experiments, &id), first);
+            assert_eq!(variant(&recompiled, &id), first);
+        }
+    }
+
+    #[test]
+    fn test_salt_change_reshuffles() {
+        let experiments = compile(vec![experiment(&[0.5, 0.5])]);
+        let mut resalted = experiment(&[0.5, 0.5]);
+        resalted.salt = "2024-q2".to_string();
+        let resalted = compile(vec![resalted]);
+
+        let moved = (0..1000)
+            .map(|i| format!("user-{}", i))
+            .filter(|id| variant(&experiments, id) != variant(&resalted, id))
+            .count();
+        // Independent coin flips disagree about half the time.
+        assert!((400..600).contains(&moved), "{} of 1000 moved", moved);
+    }
+
+    #[test]
+    fn test_fractions_split_synthetic_identifiers() {
+        let experiments = compile(vec![experiment(&[0.1, 0.3])]);
+        let total = 20_000;
+        let (mut control, mut treatment, mut unenrolled) = (0, 0, 0);
+        for i in 0..total {
+            match variant(&experiments, &format!("{:x}", i * 7919)).as_deref() {
+                Some("control") => control += 1,
+                Some("treatment") => treatment += 1,
+                None => unenrolled += 1,
+                other => panic!("unexpected variant {:?}", other),
+            }
+        }
+        let share = |count: i32| count as f64 / total as f64;
+        assert!((share(control) - 0.1).abs() < 0.01, "control {}", share(control));
+        assert!((share(treatment) - 0.3).abs() < 0.015, "treatment {}", share(treatment));
+        assert!((share(unenrolled) - 0.6).abs() < 0.015, "unenrolled {}", share(unenrolled));
+    }
+
+    #[test]
+    fn test_kill_switch_forces_control() {
+        let mut killed = experiment(&[0.1, 0.3]);
+        killed.kill_switch = true;
+        let experiments = compile(vec![killed]);
+        for i in 0..200 {
+            assert_eq!(variant(&experiments, &format!("user-{}", i)).as_deref(), Some("control"));
+        }
+        // Even without an identifier.
+        let assignment = experiments.assign("/api/a", &HeaderMap::new()).unwrap();
+        assert_eq!(assignment.service, "service-a");
+    }
+
+    #[test]
+    fn test_identifier_from_cookie() {
+        let mut by_cookie = experiment(&[0.5, 0.5]);
+        by_cookie.identifier = IdentifierSource::Cookie("uid".to_string());
+        let experiments = compile(vec![by_cookie]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("theme=dark; uid=user-7"));
+        let from_cookie = experiments.assign("/api/a", &headers).map(|a| a.variant.to_string());
+        let by_header = compile(vec![experiment(&[0.5, 0.5])]);
+        assert_eq!(from_cookie, variant(&by_header, "user-7"));
+
+        headers.insert(header::COOKIE, HeaderValue::from_static("theme=dark"));
+        assert_eq!(experiments.assign("/api/a", &headers), None);
+        assert_eq!(experiments.assign("/api/b", &user("user-7")), None);
+    }
+
+    #[test]
+    fn test_compile_rejects_invalid_experiments() {
+        let compile = |experiments: Vec<ExperimentConfig>| {
+            Experiments::compile(&ExperimentsConfig { experiments }, |service| service != "service-b")
+        };
+        assert!(compile(vec![experiment(&[0.6, 0.6])]).is_err());
+        assert!(compile(vec![experiment(&[0.5])]).is_ok());
+        // service-b is unknown here.
+        assert!(compile(vec![experiment(&[0.5, 0.5])]).is_err());
+        assert!(compile(vec![experiment(&[0.5]), experiment(&[0.5])]).is_err());
+        assert!(compile(vec![experiment(&[])]).is_err());
+    }
+}
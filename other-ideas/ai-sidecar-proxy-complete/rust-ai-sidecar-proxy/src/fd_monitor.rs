@@ -0,0 +1,235 @@
+use crate::{config::FdMonitorConfig, metrics::MetricsCollector, supervisor::TaskSupervisor};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::time::interval;
+use tracing::{info, warn};
+
+pub trait FdSource: Send + Sync {
+    fn open_fds(&self) -> Option<usize>;
+    fn limit(&self) -> Option<u64>;
+}
+
+pub struct SystemFdSource;
+
+impl FdSource for SystemFdSource {
+    #[cfg(target_os = "linux")]
+    fn open_fds(&self) -> Option<usize> {
+        std::fs::read_dir("/proc/self/fd").ok().map(|entries| entries.count())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_fds(&self) -> Option<usize> {
+        None
+    }
+
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)] // rlim_t is not u64 on every unix
+    fn limit(&self) -> Option<u64> {
+        let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: getrlimit only writes into the struct we pass it.
+        let rc = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) };
+        (rc == 0 && rlim.rlim_cur != libc::RLIM_INFINITY).then_some(rlim.rlim_cur as u64)
+    }
+
+    #[cfg(not(unix))]
+    fn limit(&self) -> Option<u64> {
+        None
+    }
+}
+
+pub struct FdMonitor {
+    source: Arc<dyn FdSource>,
+    config: FdMonitorConfig,
+    metrics: Arc<MetricsCollector>,
+    under_pressure: AtomicBool,
+}
+
+impl FdMonitor {
+    pub fn new(config: FdMonitorConfig, metrics: Arc<MetricsCollector>) -> Self {
+        Self::with_source(Arc::new(SystemFdSource), config, metrics)
+    }
+
+    pub fn with_source(
+        source: Arc<dyn FdSource>,
+        config: FdMonitorConfig,
+        metrics: Arc<MetricsCollector>,
+    ) -> Self {
+        Self {
+            source,
+            config,
+            metrics,
+            under_pressure: AtomicBool::new(false),
+        }
+    }
+
+    // Returns false when the configured connection budget would not fit under the limit.
+    pub fn check_startup_budget(&self, max_connections: usize, pool_connections: usize) -> bool {
+        let Some(limit) = self.source.limit() else {
+            info!("RLIMIT_NOFILE unavailable or unlimited, skipping descriptor budget check");
+            return true;
+        };
+
+        let needed = (max_connections + pool_connections) as u64;
+        let safe = (limit as f64 * self.config.safe_fraction) as u64;
+        if needed > safe {
+            warn!(
+                limit,
+                needed,
+                safe,
+                max_connections,
+                pool_connections,
+                "connection budget exceeds the safe fraction of RLIMIT_NOFILE"
+            );
+            return false;
+        }
+
+        info!(limit, needed, "file descriptor budget ok");
+        true
+    }
+
+    pub fn check(&self) {
+        let (Some(open), Some(limit)) = (self.source.open_fds(), self.source.limit()) else {
+            return;
+        };
+
+        let high_water = (limit as f64 * self.config.high_water_fraction) as usize;
+        let pressure = open >= high_water;
+        let was_under_pressure = self.under_pressure.swap(pressure, Ordering::Relaxed);
+
+        self.metrics.set_open_fds(open);
+        self.metrics.set_fd_pressure(pressure);
+
+        if pressure && !was_under_pressure {
+            warn!(open, limit, high_water, "file descriptor pressure");
+        } else if !pressure && was_under_pressure {
+            info!(open, limit, high_water, "file descriptor pressure relieved");
+        }
+    }
+
+    pub fn should_shed(&self) -> bool {
+        self.config.shed_on_pressure && self.under_pressure.load(Ordering::Relaxed)
+    }
+
+    pub fn start(self: &Arc<Self>, supervisor: &Arc<TaskSupervisor>) {
+        if self.source.open_fds().is_none() {
+            info!("open descriptor count unavailable on this platform, runtime fd monitor disabled");
+            return;
+        }
+
+        let monitor = self.clone();
+        supervisor.spawn("fd_monitor", false, move |heartbeat| {
+            let monitor = monitor.clone();
+            async move {
+                let mut interval = interval(Duration::from_millis(monitor.config.check_interval_ms.max(1)));
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    monitor.check();
+                }
+            }
+        });
+    }
+}
+
+// Whether an accept or open failed because the process or the system is out
+// of file descriptors.
+#[cfg(unix)]
+pub fn out_of_fds(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+pub fn out_of_fds(_error: &std::io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct FakeFdSource {
+        open: AtomicUsize,
+        limit: Option<u64>,
+    }
+
+    impl FdSource for FakeFdSource {
+        fn open_fds(&self) -> Option<usize> {
+            Some(self.open.load(Ordering::Relaxed))
+        }
+
+        fn limit(&self) -> Option<u64> {
+            self.limit
+        }
+    }
+
+    fn monitor(source: Arc<FakeFdSource>, shed_on_pressure: bool) -> (FdMonitor, Arc<MetricsCollector>) {
+        let metrics = Arc::new(MetricsCollector::new());
+        let config = FdMonitorConfig {
+            shed_on_pressure,
+            ..FdMonitorConfig::default()
+        };
+        (FdMonitor::with_source(source, config, metrics.clone()), metrics)
+    }
+
+    #[test]
+    fn test_sheds_above_high_water_and_recovers() {
+        let source = Arc::new(FakeFdSource { open: AtomicUsize::new(100), limit: Some(1000) });
+        let (monitor, metrics) = monitor(source.clone(), true);
+
+        monitor.check();
+        assert!(!monitor.should_shed());
+        assert_eq!(metrics.fd_pressure(), 0.0);
+
+        source.open.store(950, Ordering::Relaxed);
+        monitor.check();
+        assert!(monitor.should_shed());
+        assert_eq!(metrics.fd_pressure(), 1.0);
+
+        source.open.store(500, Ordering::Relaxed);
+        monitor.check();
+        assert!(!monitor.should_shed());
+        assert_eq!(metrics.fd_pressure(), 0.0);
+    }
+
+    #[test]
+    fn test_pressure_without_shedding() {
+        let source = Arc::new(FakeFdSource { open: AtomicUsize::new(990), limit: Some(1000) });
+        let (monitor, metrics) = monitor(source, false);
+
+        monitor.check();
+        assert!(!monitor.should_shed());
+        assert_eq!(metrics.fd_pressure(), 1.0);
+    }
+
+    #[test]
+    fn test_startup_budget() {
+        let source = Arc::new(FakeFdSource { open: AtomicUsize::new(0), limit: Some(1024) });
+        let (monitor, _) = monitor(source, true);
+
+        assert!(monitor.check_startup_budget(500, 100));
+        assert!(!monitor.check_startup_budget(10000, 100));
+    }
+
+    #[test]
+    fn test_unknown_limit_is_skipped() {
+        let source = Arc::new(FakeFdSource { open: AtomicUsize::new(10_000), limit: None });
+        let (monitor, metrics) = monitor(source, true);
+
+        assert!(monitor.check_startup_budget(10000, 0));
+        monitor.check();
+        assert!(!monitor.should_shed());
+        assert_eq!(metrics.fd_pressure(), 0.0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_out_of_fds_is_told_apart() {
+        assert!(out_of_fds(&std::io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(out_of_fds(&std::io::Error::from_raw_os_error(libc::ENFILE)));
+        assert!(!out_of_fds(&std::io::Error::from(std::io::ErrorKind::ConnectionAborted)));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::interpolate::{ConfigTemplate, ParseError, Scope};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+// Set by the proxy itself for the body it forwards, so no rule may touch them.
+const RESERVED: [&str; 4] = ["content-length", "transfer-encoding", "connection", "host"];
+
+// One change a service makes to the headers passing through it. Rules apply
+// in the order they are listed; an injected value is a template, so it may
+// name the service as `${service}` or copy a header as `${header:x-tenant}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type", deny_unknown_fields)]
+pub enum HeaderRule {
+    // Replaces any value the client sent.
+    InjectRequest { name: String, value: ConfigTemplate },
+    RemoveRequest { name: String },
+    // Replaces any value the upstream sent.
+    InjectResponse { name: String, value: ConfigTemplate },
+    RemoveResponse { name: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Request,
+    Response,
+}
+
+impl HeaderRule {
+    pub fn name(&self) -> &str {
+        match self {
+            HeaderRule::InjectRequest { name, .. }
+            | HeaderRule::RemoveRequest { name }
+            | HeaderRule::InjectResponse { name, .. }
+            | HeaderRule::RemoveResponse { name } => name,
+        }
+    }
+
+    fn side(&self) -> Side {
+        match self {
+            HeaderRule::InjectRequest { .. } | HeaderRule::RemoveRequest { .. } => Side::Request,
+            HeaderRule::InjectResponse { .. } | HeaderRule::RemoveResponse { .. } => Side::Response,
+        }
+    }
+
+    // Why the rule cannot be applied, if it cannot.
+    pub fn problem(&self) -> Option<String> {
+        let name = self.name();
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Some(format!("{:?} is not a valid header name", name));
+        }
+        RESERVED
+            .iter()
+            .any(|reserved| name.eq_ignore_ascii_case(reserved))
+            .then(|| format!("{:?} is set by the proxy and cannot be rewritten", name))
+    }
+
+    // The injected value as written, and why it does not parse, if it does
+    // not.
+    pub fn value_error(&self) -> Option<(&str, &ParseError)> {
+        match self {
+            HeaderRule::InjectRequest { value, .. } | HeaderRule::InjectResponse { value, .. } => {
+                value.error().map(|error| (value.source(), error))
+            }
+            HeaderRule::RemoveRequest { .. } | HeaderRule::RemoveResponse { .. } => None,
+        }
+    }
+}
+
+// Applies the rules for one side in order. Values are rendered against the
+// headers as they arrived, which `scope` is given here. `to_value` turns a
+// rendered value into a header value, so the caller decides how untrusted
+// bytes are cleaned.
+pub fn apply(
+    rules: &[HeaderRule],
+    side: Side,
+    scope: Scope<'_>,
+    headers: &mut HeaderMap,
+    mut to_value: impl FnMut(&str) -> HeaderValue,
+) {
+    if !rules.iter().any(|rule| rule.side() == side) {
+        return;
+    }
+    let changes: Vec<(HeaderName, Option<HeaderValue>)> = {
+        let scope = match side {
+            Side::Request => scope.request_headers(headers),
+            Side::Response => scope.response_headers(headers),
+        };
+        rules
+            .iter()
+            .filter(|rule| rule.side() == side)
+            // Checked when the config was loaded.
+            .filter_map(|rule| Some((rule, HeaderName::from_bytes(rule.name().as_bytes()).ok()?)))
+            .map(|(rule, name)| match rule {
+                HeaderRule::InjectRequest { value, .. } | HeaderRule::InjectResponse { value, .. } => {
+                    (name, Some(to_value(&value.render(&scope))))
+                }
+                HeaderRule::RemoveRequest { .. } | HeaderRule::RemoveResponse { .. } => (name, None),
+            })
+            .collect()
+    };
+    for (name, value) in changes {
+        match value {
+            Some(value) => {
+                headers.insert(name, value);
+            }
+            None => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::RequestContext;
+    use hyper::Request;
+
+    fn rules() -> Vec<HeaderRule> {
+        serde_json::from_value(serde_json::json!([
+            {"type": "remove_request", "name": "Authorization"},
+            {"type": "inject_request", "name": "x-internal-token", "value": "${service}-token"},
+            {"type": "inject_request", "name": "x-tenant", "value": "${header:authorization|none}"},
+            {"type": "inject_response", "name": "x-served-by", "value": "${service} ${resp_header:server}"},
+            {"type": "remove_response", "name": "server"},
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rules_apply_to_their_side_in_order() {
+        let context = RequestContext::new(&Request::builder().uri("/orders/1").body(()).unwrap(), "10.0.0.7".to_string());
+        let scope = || Scope::new(&context).route("/orders", "orders");
+        let value = |rendered: &str| HeaderValue::from_str(rendered).unwrap();
+        let mut request = HeaderMap::new();
+        request.insert("authorization", "Bearer secret".parse().unwrap());
+        request.insert("x-internal-token", "forged".parse().unwrap());
+        apply(&rules(), Side::Request, scope(), &mut request, value);
+        assert!(request.get("authorization").is_none());
+        assert_eq!(request.get_all("x-internal-token").iter().collect::<Vec<_>>(), ["orders-token"]);
+        // Rendered from the headers as the client sent them.
+        assert_eq!(request["x-tenant"], "Bearer secret");
+        assert!(request.get("x-served-by").is_none());
+
+        let mut response = HeaderMap::new();
+        response.insert("server", "nginx".parse().unwrap());
+        apply(&rules(), Side::Response, scope(), &mut response, value);
+        assert_eq!(response.get("x-served-by").unwrap(), "orders nginx");
+        assert!(response.get("server").is_none());
+    }
+
+    #[test]
+    fn test_reserved_and_invalid_names_are_problems() {
+        let rule = |name: &str| HeaderRule::RemoveRequest { name: name.to_string() };
+        assert!(rule("x-ok").problem().is_none());
+        assert!(rule("Content-Length").problem().unwrap().contains("set by the proxy"));
+        assert!(rule("bad name").problem().unwrap().contains("not a valid header name"));
+
+        let unknown = serde_json::from_value::<HeaderRule>(serde_json::json!({"type": "remove_request", "name": "a", "value": "b"}));
+        assert!(unknown.is_err());
+    }
+}
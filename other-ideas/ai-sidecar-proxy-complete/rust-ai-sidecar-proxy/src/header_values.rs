@@ -0,0 +1,132 @@
+use crate::metrics::MetricsCollector;
+use hyper::header::HeaderValue;
+use std::{collections::HashSet, sync::Mutex};
+use tracing::warn;
+
+// Every header value the proxy builds from something it does not control
+// (endpoint URLs, config strings, rendered templates, client input) goes
+// through here, so a CR/LF can never end a header line early.
+pub struct HeaderValueGuard {
+    max_len: usize,
+    // Sources already warned about; later violations are only counted.
+    reported: Mutex<HashSet<&'static str>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    ControlCharacters,
+    TooLong,
+}
+
+impl Violation {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Violation::ControlCharacters => "control_characters",
+            Violation::TooLong => "too_long",
+        }
+    }
+}
+
+impl HeaderValueGuard {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            reported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // `source` names the insert site for the metric and the log line.
+    pub fn value(&self, source: &'static str, value: &str, metrics: &MetricsCollector) -> HeaderValue {
+        let (clean, violations) = sanitize(value, self.max_len);
+        for violation in &violations {
+            metrics.record_header_value_sanitized(source, violation.label());
+        }
+        if !violations.is_empty() && self.reported.lock().unwrap().insert(source) {
+            warn!(source, ?violations, value = ?value, "sanitized header value; further ones from this source are only counted");
+        }
+        clean
+    }
+}
+
+// Drops control characters other than tab, then cuts the value at the last
+// character boundary within `max_len` bytes.
+pub fn sanitize(value: &str, max_len: usize) -> (HeaderValue, Vec<Violation>) {
+    let mut violations = Vec::new();
+    let mut clean: String = value.chars().filter(|c| *c == '\t' || !c.is_control()).collect();
+    if clean.len() != value.len() {
+        violations.push(Violation::ControlCharacters);
+    }
+    if clean.len() > max_len {
+        let mut end = max_len;
+        while !clean.is_char_boundary(end) {
+            end -= 1;
+        }
+        clean.truncate(end);
+        violations.push(Violation::TooLong);
+    }
+    let clean = HeaderValue::from_bytes(clean.as_bytes()).expect("control characters were removed");
+    (clean, violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interpolate::{Scope, Template},
+        middleware::RequestContext,
+    };
+    use hyper::{HeaderMap, Request};
+
+    #[test]
+    fn test_strips_line_breaks_and_controls() {
+        let (value, violations) = sanitize("a\r\nx-injected: 1\0\x7f\tb", 64);
+        assert_eq!(value, "ax-injected: 1\tb");
+        assert_eq!(violations, vec![Violation::ControlCharacters]);
+
+        let (value, violations) = sanitize("plain value", 64);
+        assert_eq!(value, "plain value");
+        assert!(violations.is_empty());
+    }
+
+    #[test]
+    fn test_truncates_on_char_boundary() {
+        let (value, violations) = sanitize("ééé", 5);
+        assert_eq!(value.as_bytes(), "éé".as_bytes());
+        assert_eq!(violations, vec![Violation::TooLong]);
+    }
+
+    #[test]
+    fn test_counts_every_violation() {
+        let metrics = MetricsCollector::new();
+        let guard = HeaderValueGuard::new(8);
+        for _ in 0..3 {
+            guard.value("endpoint", "x\ny", &metrics);
+        }
+        guard.value("endpoint", "0123456789", &metrics);
+
+        assert_eq!(metrics.header_values_sanitized_count("endpoint", "control_characters"), 3);
+        assert_eq!(metrics.header_values_sanitized_count("endpoint", "too_long"), 1);
+        assert_eq!(guard.reported.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_interpolated_tenant_cannot_inject() {
+        let req = Request::builder().uri("/api/a/items").body(()).unwrap();
+        let context = RequestContext::new(&req, "10.0.0.7".to_string());
+        let metrics = MetricsCollector::new();
+        let guard = HeaderValueGuard::new(64);
+        let template = Template::parse("tenant=${header:x-tenant|anonymous\r\nx-admin: true}").unwrap();
+
+        let empty = HeaderMap::new();
+        let rendered = template.render(&Scope::new(&context).request_headers(&empty));
+        assert!(rendered.contains("\r\n"));
+        assert_eq!(guard.value("template", &rendered, &metrics), "tenant=anonymousx-admin: true");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "t".repeat(200).parse().unwrap());
+        let rendered = template.render(&Scope::new(&context).request_headers(&headers));
+        assert_eq!(guard.value("template", &rendered, &metrics).len(), 64);
+        assert_eq!(metrics.header_values_sanitized_count("template", "control_characters"), 1);
+        assert_eq!(metrics.header_values_sanitized_count("template", "too_long"), 1);
+    }
+}
@@ -1,24 +1,59 @@
-use crate::{config::UpstreamService, ai::AIEngine};
+use crate::{config::UpstreamService, ai::AIEngine, supervisor::TaskSupervisor, upstream_client};
 use std::{collections::HashMap, sync::Arc, time::Duration};
-use tokio::{sync::RwLock, time::interval};
+use tokio::{sync::{watch, RwLock}, time::interval};
 use tracing::{info, warn, error, debug};
 use reqwest::Client;
+use serde::Serialize;
+
+const DEFAULT_PROBE_TIMEOUT_MS: u64 = 10_000;
+
+// Degraded endpoints answered their probe, but slower than the service's
+// `health_check_warn_latency_ms`. They stay routable; the AI engine scores
+// them lower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthTier {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+impl HealthTier {
+    fn of_probe(success: bool, response_time_ms: u64, service: &UpstreamService) -> Self {
+        if !success {
+            HealthTier::Unhealthy
+        } else if service.health_check_warn_latency_ms.is_some_and(|warn| response_time_ms > warn) {
+            HealthTier::Degraded
+        } else {
+            HealthTier::Healthy
+        }
+    }
+
+    pub fn is_routable(self) -> bool {
+        self != HealthTier::Unhealthy
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct HealthStatus {
     pub endpoint: String,
-    pub is_healthy: bool,
+    pub tier: HealthTier,
     pub last_check: u64,
     pub response_time_ms: u64,
     pub consecutive_failures: u32,
     pub consecutive_successes: u32,
+    pub probe_error: Option<String>,
 }
 
+type ProbeClient = Result<Client, String>;
+
 pub struct HealthChecker {
-    services: HashMap<String, UpstreamService>,
+    services: Arc<RwLock<HashMap<String, UpstreamService>>>,
+    probe_clients: Arc<RwLock<HashMap<String, ProbeClient>>>,
     health_status: Arc<RwLock<HashMap<String, HealthStatus>>>,
     ai_engine: Arc<AIEngine>,
-    client: Client,
+    // Completed probe rounds.
+    sweeps: Arc<watch::Sender<u64>>,
 }
 
 impl HealthChecker {
@@ -26,123 +61,227 @@ impl HealthChecker {
         services: HashMap<String, UpstreamService>,
         ai_engine: Arc<AIEngine>,
     ) -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()
-            .expect("Failed to create HTTP client for health checks");
+        let probe_clients = Self::build_probe_clients(&services);
 
         Self {
-            services,
+            services: Arc::new(RwLock::new(services)),
+            probe_clients: Arc::new(RwLock::new(probe_clients)),
             health_status: Arc::new(RwLock::new(HashMap::new())),
             ai_engine,
-            client,
+            sweeps: Arc::new(watch::Sender::new(0)),
         }
     }
 
-    pub async fn start_health_checks(&self) {
+    fn build_probe_clients(services: &HashMap<String, UpstreamService>) -> HashMap<String, ProbeClient> {
+        services
+            .iter()
+            .map(|(service_name, service)| {
+                let timeout = Duration::from_millis(
+                    service.health_check_timeout_ms.unwrap_or(DEFAULT_PROBE_TIMEOUT_MS),
+                );
+                let client = upstream_client::build_client(service, timeout).map_err(|e| {
+                    let message = format!("{:#}", e);
+                    error!(service = %service_name, error = %message, "failed to build probe client");
+                    message
+                });
+                (service_name.clone(), client)
+            })
+            .collect()
+    }
+
+    pub async fn update_services(&self, services: HashMap<String, UpstreamService>) {
+        let probe_clients = Self::build_probe_clients(&services);
+        *self.probe_clients.write().await = probe_clients;
+        *self.services.write().await = services;
+        info!("health checker services updated");
+    }
+
+    pub async fn start_health_checks(&self, supervisor: &Arc<TaskSupervisor>) {
         let services = self.services.clone();
+        let probe_clients = self.probe_clients.clone();
         let health_status = self.health_status.clone();
         let ai_engine = self.ai_engine.clone();
-        let client = self.client.clone();
-
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(30));
-            
-            loop {
-                interval.tick().await;
-                
-                for (service_name, service_config) in &services {
-                    for endpoint in &service_config.endpoints {
-                        let health_url = format!("{}{}", endpoint, service_config.health_check_path);
-                        
-                        let start_time = std::time::Instant::now();
-                        let is_healthy = match client.get(&health_url).send().await {
-                            Ok(response) => {
-                                let status = response.status();
-                                let is_success = status.is_success();
-                                
-                                if !is_success {
-                                    warn!("Health check failed for {}: HTTP {}", endpoint, status);
-                                }
-                                
-                                is_success
-                            }
-                            Err(e) => {
-                                warn!("Health check error for {}: {}", endpoint, e);
-                                false
+        let sweeps = self.sweeps.clone();
+        let service_count = services.read().await.len();
+
+        supervisor.spawn("health_checker", true, move |heartbeat| {
+            let services = services.clone();
+            let probe_clients = probe_clients.clone();
+            let health_status = health_status.clone();
+            let ai_engine = ai_engine.clone();
+            let sweeps = sweeps.clone();
+
+            async move {
+                let mut interval = interval(Duration::from_secs(30));
+
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+
+                    let services = services.read().await.clone();
+                    let clients = probe_clients.read().await.clone();
+
+                    for (service_name, service_config) in &services {
+                        // Discovery lists only pods the kubelet finds ready;
+                        // probing them as well would second-guess it.
+                        if service_config.kubernetes.is_some() {
+                            continue;
+                        }
+                        let client = match clients.get(service_name) {
+                            Some(Ok(client)) => client,
+                            Some(Err(e)) => {
+                                Self::record_probe_error(&health_status, service_config, e).await;
+                                continue;
                             }
+                            None => continue,
                         };
-                        
-                        let response_time = start_time.elapsed().as_millis() as u64;
-                        
-                        let mut status_map = health_status.write().await;
-                        let status = status_map.entry(endpoint.clone()).or_insert(HealthStatus {
-                            endpoint: endpoint.clone(),
-                            is_healthy: true,
-                            last_check: 0,
-                            response_time_ms: 0,
-                            consecutive_failures: 0,
-                            consecutive_successes: 0,
-                        });
-
-                        status.is_healthy = is_healthy;
-                        status.last_check = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-                        status.response_time_ms = response_time;
-
-                        if is_healthy {
-                            status.consecutive_successes += 1;
-                            status.consecutive_failures = 0;
-                            
-                            if status.consecutive_successes == 1 {
-                                info!("Endpoint {} is now healthy", endpoint);
+
+                        for endpoint in &service_config.endpoints {
+                            let health_url = format!("{}{}", endpoint, service_config.health_check_path);
+
+                            let start_time = std::time::Instant::now();
+                            let is_healthy = match client.get(&health_url).send().await {
+                                Ok(response) => {
+                                    let status = response.status();
+                                    let is_success = status.is_success();
+
+                                    if !is_success {
+                                        warn!(
+                                            service = %service_name,
+                                            endpoint = %endpoint,
+                                            status = status.as_u16(),
+                                            "health check failed"
+                                        );
+                                    }
+
+                                    is_success
+                                }
+                                Err(e) => {
+                                    warn!(service = %service_name, endpoint = %endpoint, error = %e, "health check error");
+                                    false
+                                }
+                            };
+
+                            let response_time = start_time.elapsed().as_millis() as u64;
+                            let tier = HealthTier::of_probe(is_healthy, response_time, service_config);
+
+                            let mut status_map = health_status.write().await;
+                            let status = status_map
+                                .entry(endpoint.clone())
+                                .or_insert_with(|| Self::initial_status(endpoint));
+
+                            if tier == HealthTier::Degraded && status.tier != HealthTier::Degraded {
+                                warn!(
+                                    service = %service_name,
+                                    endpoint = %endpoint,
+                                    latency_ms = response_time,
+                                    "endpoint is now degraded"
+                                );
                             }
-                        } else {
-                            status.consecutive_failures += 1;
-                            status.consecutive_successes = 0;
-                            
-                            if status.consecutive_failures == 1 {
-                                warn!("Endpoint {} is now unhealthy", endpoint);
+                            status.tier = tier;
+                            status.last_check = Self::now_secs();
+                            status.response_time_ms = response_time;
+                            status.probe_error = None;
+                            ai_engine.set_degraded(endpoint, tier == HealthTier::Degraded);
+                            ai_engine.record_probe(endpoint, is_healthy);
+
+                            if is_healthy {
+                                status.consecutive_successes += 1;
+                                status.consecutive_failures = 0;
+
+                                if status.consecutive_successes == 1 {
+                                    info!(service = %service_name, endpoint = %endpoint, "endpoint is now healthy");
+                                }
+                            } else {
+                                status.consecutive_failures += 1;
+                                status.consecutive_successes = 0;
+
+                                if status.consecutive_failures == 1 {
+                                    warn!(service = %service_name, endpoint = %endpoint, "endpoint is now unhealthy");
+                                }
                             }
-                        }
 
-                        debug!(
-                            "Health check for {}: {} ({}ms, failures: {}, successes: {})",
-                            endpoint,
-                            if is_healthy { "HEALTHY" } else { "UNHEALTHY" },
-                            response_time,
-                            status.consecutive_failures,
-                            status.consecutive_successes
-                        );
-
-                        let request_metrics = crate::ai::RequestMetrics {
-                            latency_ms: response_time,
-                            status_code: if is_healthy { 200 } else { 503 },
-                            endpoint: endpoint.clone(),
-                            timestamp: status.last_check,
-                            success: is_healthy,
-                        };
-                        
-                        ai_engine.record_request(request_metrics).await;
+                            debug!(
+                                service = %service_name,
+                                endpoint = %endpoint,
+                                tier = ?tier,
+                                latency_ms = response_time,
+                                consecutive_failures = status.consecutive_failures,
+                                consecutive_successes = status.consecutive_successes,
+                                "health check completed"
+                            );
+
+                            let request_metrics = crate::ai::RequestMetrics {
+                                latency_ms: response_time,
+                                status_code: if is_healthy { 200 } else { 503 },
+                                endpoint: endpoint.clone(),
+                                timestamp: status.last_check,
+                                success: is_healthy,
+                                phases: None,
+                            };
+
+                            ai_engine.record_request(request_metrics).await;
+                        }
                     }
+                    sweeps.send_modify(|count| *count += 1);
                 }
             }
         });
 
-        info!("Health checker started for {} services", self.services.len());
+        info!(services = service_count, "health checker started");
+    }
+
+    fn initial_status(endpoint: &str) -> HealthStatus {
+        HealthStatus {
+            endpoint: endpoint.to_string(),
+            tier: HealthTier::Healthy,
+            last_check: 0,
+            response_time_ms: 0,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            probe_error: None,
+        }
+    }
+
+    fn now_secs() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // A probe that could not be attempted says nothing about the endpoint, so
+    // health and the AI engine are left untouched.
+    async fn record_probe_error(
+        health_status: &RwLock<HashMap<String, HealthStatus>>,
+        service: &UpstreamService,
+        error: &str,
+    ) {
+        let mut status_map = health_status.write().await;
+        for endpoint in &service.endpoints {
+            let status = status_map
+                .entry(endpoint.clone())
+                .or_insert_with(|| Self::initial_status(endpoint));
+            status.last_check = Self::now_secs();
+            status.probe_error = Some(error.to_string());
+        }
+        debug!(service = %service.name, error, "health probes errored");
+    }
+
+    // Resolves once a full probe round has finished since startup.
+    pub async fn wait_for_sweep(&self) {
+        let _ = self.sweeps.subscribe().wait_for(|count| *count > 0).await;
     }
 
     pub async fn get_healthy_endpoints(&self, service_name: &str) -> Vec<String> {
-        if let Some(service) = self.services.get(service_name) {
+        if let Some(service) = self.services.read().await.get(service_name) {
             let status_map = self.health_status.read().await;
-            
+
             service.endpoints
                 .iter()
                 .filter(|endpoint| {
                     status_map.get(*endpoint)
-                        .map(|status| status.is_healthy)
+                        .map(|status| status.tier.is_routable())
                         .unwrap_or(true)
                 })
                 .cloned()
@@ -162,57 +301,245 @@ impl HealthChecker {
         status_map.clone()
     }
 
+    pub async fn probe_snapshot(&self, endpoints: &[String]) -> HashMap<String, HealthStatus> {
+        let status_map = self.health_status.read().await;
+        endpoints
+            .iter()
+            .filter_map(|endpoint| status_map.get(endpoint).map(|status| (endpoint.clone(), status.clone())))
+            .collect()
+    }
+
     pub async fn is_endpoint_healthy(&self, endpoint: &str) -> bool {
         let status_map = self.health_status.read().await;
         status_map.get(endpoint)
-            .map(|status| status.is_healthy)
+            .map(|status| status.tier.is_routable())
             .unwrap_or(true)
     }
 
     pub async fn mark_endpoint_unhealthy(&self, endpoint: &str) {
         let mut status_map = self.health_status.write().await;
         if let Some(status) = status_map.get_mut(endpoint) {
-            status.is_healthy = false;
+            status.tier = HealthTier::Unhealthy;
+            self.ai_engine.set_degraded(endpoint, false);
             status.consecutive_failures += 1;
             status.consecutive_successes = 0;
-            warn!("Manually marked endpoint {} as unhealthy", endpoint);
+            warn!(endpoint = %endpoint, "endpoint manually marked unhealthy");
         }
     }
 
-    pub async fn force_health_check(&self, service_name: &str) {
-        if let Some(service_config) = self.services.get(service_name) {
-            info!("Forcing health check for service: {}", service_name);
-            
-            for endpoint in &service_config.endpoints {
-                let health_url = format!("{}{}", endpoint, service_config.health_check_path);
-                
-                let start_time = std::time::Instant::now();
-                let is_healthy = match self.client.get(&health_url).send().await {
-                    Ok(response) => response.status().is_success(),
-                    Err(_) => false,
-                };
-                
-                let response_time = start_time.elapsed().as_millis() as u64;
-                
-                let mut status_map = self.health_status.write().await;
-                let status = status_map.entry(endpoint.clone()).or_insert(HealthStatus {
-                    endpoint: endpoint.clone(),
-                    is_healthy: true,
-                    last_check: 0,
-                    response_time_ms: 0,
-                    consecutive_failures: 0,
-                    consecutive_successes: 0,
-                });
+    pub async fn forget_endpoints(&self, endpoints: &[String]) -> usize {
+        let mut status_map = self.health_status.write().await;
+        endpoints.iter().filter(|endpoint| status_map.remove(*endpoint).is_some()).count()
+    }
+
+    // Sends one health check to `endpoint` without touching its probe status;
+    // true on a 2xx.
+    pub async fn probe(&self, service_name: &str, endpoint: &str) -> bool {
+        let Some(path) = self
+            .services
+            .read()
+            .await
+            .get(service_name)
+            .map(|service| service.health_check_path.clone())
+        else {
+            return false;
+        };
+        let client = match self.probe_clients.read().await.get(service_name) {
+            Some(Ok(client)) => client.clone(),
+            _ => return false,
+        };
+        match client.get(format!("{}{}", endpoint, path)).send().await {
+            Ok(response) => response.status().is_success(),
+            Err(e) => {
+                debug!(service = %service_name, endpoint = %endpoint, error = %e, "health check probe failed");
+                false
+            }
+        }
+    }
 
-                status.is_healthy = is_healthy;
-                status.last_check = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                status.response_time_ms = response_time;
+    pub async fn force_health_check(&self, service_name: &str) {
+        let Some(service_config) = self.services.read().await.get(service_name).cloned() else {
+            return;
+        };
+        info!(service = %service_name, "forcing health check");
 
-                info!("Forced health check for {}: {}", endpoint, if is_healthy { "HEALTHY" } else { "UNHEALTHY" });
+        let client = match self.probe_clients.read().await.get(service_name) {
+            Some(Ok(client)) => client.clone(),
+            Some(Err(e)) => {
+                Self::record_probe_error(&self.health_status, &service_config, e).await;
+                return;
             }
+            None => return,
+        };
+
+        for endpoint in &service_config.endpoints {
+            let health_url = format!("{}{}", endpoint, service_config.health_check_path);
+
+            let start_time = std::time::Instant::now();
+            let is_healthy = match client.get(&health_url).send().await {
+                Ok(response) => response.status().is_success(),
+                Err(_) => false,
+            };
+
+            let response_time = start_time.elapsed().as_millis() as u64;
+            let tier = HealthTier::of_probe(is_healthy, response_time, &service_config);
+
+            let mut status_map = self.health_status.write().await;
+            let status = status_map
+                .entry(endpoint.clone())
+                .or_insert_with(|| Self::initial_status(endpoint));
+
+            status.tier = tier;
+            status.last_check = Self::now_secs();
+            status.response_time_ms = response_time;
+            status.probe_error = None;
+            self.ai_engine.set_degraded(endpoint, tier == HealthTier::Degraded);
+            self.ai_engine.record_probe(endpoint, is_healthy);
+
+            info!(
+                service = %service_name,
+                endpoint = %endpoint,
+                tier = ?tier,
+                latency_ms = response_time,
+                "forced health check completed"
+            );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AIConfig, Config, SupervisorConfig};
+    use crate::metrics::MetricsCollector;
+    use crate::mock_upstream::{MockResponse, MockUpstream};
+    #[cfg(feature = "tls")]
+    use crate::{
+        config::UpstreamTlsConfig,
+        test_support::{spawn_mtls_upstream, TestPki},
+    };
+
+    #[tokio::test]
+    async fn test_slow_probe_marks_the_endpoint_degraded() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_millis(200),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut service = Config::new().upstream_services.remove("service-a").unwrap();
+        service.endpoints = vec![upstream.url()];
+        service.health_check_warn_latency_ms = Some(50);
+        let ai_engine = Arc::new(AIEngine::new());
+        let checker = HealthChecker::new(HashMap::from([(service.name.clone(), service.clone())]), ai_engine.clone());
+
+        checker.force_health_check("service-a").await;
+        let status = checker.get_health_status(&upstream.url()).await.unwrap();
+        assert_eq!(status.tier, HealthTier::Degraded);
+        assert!(checker.is_endpoint_healthy(&upstream.url()).await);
+        assert_eq!(ai_engine.selection_score(&upstream.url(), None), 0.25);
+
+        service.health_check_warn_latency_ms = Some(5_000);
+        checker.update_services(HashMap::from([(service.name.clone(), service)])).await;
+        checker.force_health_check("service-a").await;
+        let status = checker.get_health_status(&upstream.url()).await.unwrap();
+        assert_eq!(status.tier, HealthTier::Healthy);
+        assert_eq!(ai_engine.selection_score(&upstream.url(), None), 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_passed_probes_rehabilitate_an_evicted_endpoint() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut service = Config::new().upstream_services.remove("service-a").unwrap();
+        service.endpoints = vec![upstream.url()];
+        let config = AIConfig { rehabilitation_successes: 1, ..AIConfig::default() };
+        let ai_engine = Arc::new(AIEngine::with_config(&config).await);
+        ai_engine.evict_endpoint(&upstream.url()).await;
+        let checker = HealthChecker::new(HashMap::from([(service.name.clone(), service)]), ai_engine.clone());
+
+        let supervisor = Arc::new(TaskSupervisor::new(SupervisorConfig::default(), Arc::new(MetricsCollector::new())));
+        checker.start_health_checks(&supervisor).await;
+        checker.wait_for_sweep().await;
+        assert!(!ai_engine.is_evicted(&upstream.url()));
+    }
+
+    #[tokio::test]
+    async fn test_probes_before_eviction_do_not_rehabilitate() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut service = Config::new().upstream_services.remove("service-a").unwrap();
+        service.endpoints = vec![upstream.url()];
+        let config = AIConfig { rehabilitation_successes: 3, ..AIConfig::default() };
+        let ai_engine = Arc::new(AIEngine::with_config(&config).await);
+        let checker = HealthChecker::new(HashMap::from([(service.name.clone(), service)]), ai_engine.clone());
+        for _ in 0..5 {
+            checker.force_health_check("service-a").await;
+        }
+
+        ai_engine.evict_endpoint(&upstream.url()).await;
+        for _ in 0..2 {
+            checker.force_health_check("service-a").await;
+            assert!(ai_engine.is_evicted(&upstream.url()));
+        }
+        checker.force_health_check("service-a").await;
+        assert!(!ai_engine.is_evicted(&upstream.url()));
+    }
+
+    #[cfg(feature = "tls")]
+    fn mtls_service(endpoint: String, tls: UpstreamTlsConfig) -> HashMap<String, UpstreamService> {
+        let mut service = Config::new().upstream_services.remove("service-a").unwrap();
+        service.endpoints = vec![endpoint];
+        service.health_check_timeout_ms = Some(2000);
+        service.tls = Some(tls);
+        HashMap::from([(service.name.clone(), service)])
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_mtls_probe_succeeds_only_with_identity() {
+        let pki = TestPki::generate();
+        let addr = spawn_mtls_upstream(&pki).await;
+        let endpoint = format!("https://127.0.0.1:{}", addr.port());
+
+        let without_identity = UpstreamTlsConfig {
+            ca_bundle_path: pki.path(&pki.ca_path),
+            ..UpstreamTlsConfig::default()
+        };
+        let checker = HealthChecker::new(
+            mtls_service(endpoint.clone(), without_identity.clone()),
+            Arc::new(AIEngine::new()),
+        );
+        checker.force_health_check("service-a").await;
+        let status = checker.get_health_status(&endpoint).await.unwrap();
+        assert_eq!(status.tier, HealthTier::Unhealthy);
+        assert!(status.probe_error.is_none());
+
+        let with_identity = UpstreamTlsConfig {
+            client_cert_path: pki.path(&pki.client_cert_path),
+            client_key_path: pki.path(&pki.client_key_path),
+            ..without_identity
+        };
+        checker.update_services(mtls_service(endpoint.clone(), with_identity)).await;
+        checker.force_health_check("service-a").await;
+        let status = checker.get_health_status(&endpoint).await.unwrap();
+        assert_eq!(status.tier, HealthTier::Healthy);
+        assert!(status.probe_error.is_none());
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_unbuildable_probe_client_is_reported_as_errored() {
+        let endpoint = "https://127.0.0.1:1".to_string();
+        let broken = UpstreamTlsConfig {
+            ca_bundle_path: Some("/nonexistent/ca.pem".to_string()),
+            ..UpstreamTlsConfig::default()
+        };
+        let checker = HealthChecker::new(mtls_service(endpoint.clone(), broken), Arc::new(AIEngine::new()));
+
+        checker.force_health_check("service-a").await;
+
+        let status = checker.get_health_status(&endpoint).await.unwrap();
+        assert!(status.probe_error.unwrap().contains("/nonexistent/ca.pem"));
+        assert_eq!(status.tier, HealthTier::Healthy);
+        assert!(checker.is_endpoint_healthy(&endpoint).await);
+    }
+}
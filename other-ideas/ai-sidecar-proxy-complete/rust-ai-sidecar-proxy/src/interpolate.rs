@@ -0,0 +1,420 @@
+use crate::middleware::RequestContext;
+use hyper::HeaderMap;
+use std::fmt;
+
+// Templates substitute `${name}` or `${function:argument}` with request data.
+// `$$` is a literal `$`, so `$${` renders as `${`. A missing value renders as
+// the fallback after `|` when one is given (`${header:x-tenant|anonymous}`),
+// and as an empty string otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Value { source: Source, fallback: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    ClientIp,
+    RequestId,
+    Method,
+    Path,
+    UserAgent,
+    Route,
+    Service,
+    Endpoint,
+    Status,
+    LatencyMs,
+    Header(String),
+    ResponseHeader(String),
+}
+
+const VARIABLES: &[&str] = &[
+    "client_ip",
+    "request_id",
+    "method",
+    "path",
+    "user_agent",
+    "route",
+    "service",
+    "endpoint",
+    "status",
+    "latency_ms",
+];
+const FUNCTIONS: &[&str] = &["header", "resp_header"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    // Byte offset into the template where the problem starts.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at byte {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut rest = source;
+        let mut position = 0;
+
+        while let Some(offset) = rest.find('$') {
+            literal.push_str(&rest[..offset]);
+            position += offset;
+            rest = &rest[offset..];
+
+            if let Some(after) = rest.strip_prefix("$$") {
+                literal.push('$');
+                rest = after;
+                position += 2;
+                continue;
+            }
+            if !rest.starts_with("${") {
+                literal.push('$');
+                rest = &rest[1..];
+                position += 1;
+                continue;
+            }
+
+            let Some(end) = rest.find('}') else {
+                return Err(ParseError {
+                    position,
+                    message: "unterminated `${`".to_string(),
+                });
+            };
+            let expression = &rest[2..end];
+            if !literal.is_empty() {
+                tokens.push(Token::Literal(std::mem::take(&mut literal)));
+            }
+            tokens.push(Self::parse_expression(expression, position + 2)?);
+            rest = &rest[end + 1..];
+            position += end + 1;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            tokens,
+        })
+    }
+
+    fn parse_expression(expression: &str, position: usize) -> Result<Token, ParseError> {
+        let error = |message: String| ParseError { position, message };
+
+        let (selector, fallback) = match expression.split_once('|') {
+            Some((selector, fallback)) => (selector, Some(fallback.to_string())),
+            None => (expression, None),
+        };
+        let selector = selector.trim();
+
+        let source = match selector.split_once(':') {
+            Some((function, argument)) => {
+                let argument = argument.trim();
+                if argument.is_empty() {
+                    return Err(error(format!("`{}` needs an argument", function)));
+                }
+                match function.trim() {
+                    "header" => Source::Header(argument.to_ascii_lowercase()),
+                    "resp_header" => Source::ResponseHeader(argument.to_ascii_lowercase()),
+                    other => {
+                        return Err(error(format!(
+                            "unknown function `{}` (expected one of: {})",
+                            other,
+                            FUNCTIONS.join(", ")
+                        )))
+                    }
+                }
+            }
+            None => match selector {
+                "client_ip" => Source::ClientIp,
+                "request_id" => Source::RequestId,
+                "method" => Source::Method,
+                "path" => Source::Path,
+                "user_agent" => Source::UserAgent,
+                "route" => Source::Route,
+                "service" => Source::Service,
+                "endpoint" => Source::Endpoint,
+                "status" => Source::Status,
+                "latency_ms" => Source::LatencyMs,
+                "" => return Err(error("empty `${}`".to_string())),
+                other => {
+                    return Err(error(format!(
+                        "unknown variable `{}` (expected one of: {})",
+                        other,
+                        VARIABLES.join(", ")
+                    )))
+                }
+            },
+        };
+
+        Ok(Token::Value { source, fallback })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // True when the template has no substitutions and always renders to itself.
+    pub fn is_static(&self) -> bool {
+        self.tokens.iter().all(|token| matches!(token, Token::Literal(_)))
+    }
+
+    pub fn render(&self, scope: &Scope<'_>) -> String {
+        let mut out = String::with_capacity(self.source.len());
+        for token in &self.tokens {
+            match token {
+                Token::Literal(text) => out.push_str(text),
+                Token::Value { source, fallback } => match scope.lookup(source) {
+                    Some(value) => out.push_str(&value),
+                    None => out.push_str(fallback.as_deref().unwrap_or("")),
+                },
+            }
+        }
+        out
+    }
+}
+
+impl serde::Serialize for Template {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Template {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Self::parse(&source).map_err(|e| {
+            serde::de::Error::custom(format!("invalid template {:?} {}", source, e))
+        })
+    }
+}
+
+// A template read from the config. One that does not parse is kept as
+// written instead of failing the load, so `Config::validate` reports it,
+// with its position, among the config's other problems.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigTemplate {
+    source: String,
+    parsed: Result<Template, ParseError>,
+}
+
+impl ConfigTemplate {
+    pub fn parse(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            parsed: Template::parse(source),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn error(&self) -> Option<&ParseError> {
+        self.parsed.as_ref().err()
+    }
+
+    // Empty for a template that did not parse; a validated config has none.
+    pub fn render(&self, scope: &Scope<'_>) -> String {
+        self.parsed.as_ref().map(|template| template.render(scope)).unwrap_or_default()
+    }
+}
+
+impl serde::Serialize for ConfigTemplate {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ConfigTemplate {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|source| Self::parse(&source))
+    }
+}
+
+// What a template can see. Values not yet known at the point of rendering
+// (a response status before the upstream answered) are simply left unset.
+pub struct Scope<'a> {
+    context: &'a RequestContext,
+    request_headers: Option<&'a HeaderMap>,
+    route: Option<&'a str>,
+    service: Option<&'a str>,
+    endpoint: Option<&'a str>,
+    status: Option<u16>,
+    response_headers: Option<&'a HeaderMap>,
+}
+
+impl<'a> Scope<'a> {
+    pub fn new(context: &'a RequestContext) -> Self {
+        Self {
+            context,
+            request_headers: None,
+            route: None,
+            service: None,
+            endpoint: None,
+            status: None,
+            response_headers: None,
+        }
+    }
+
+    pub fn request_headers(mut self, headers: &'a HeaderMap) -> Self {
+        self.request_headers = Some(headers);
+        self
+    }
+
+    pub fn route(mut self, route: &'a str, service: &'a str) -> Self {
+        self.route = Some(route);
+        self.service = Some(service);
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: &'a str) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    pub fn response(self, status: u16, headers: &'a HeaderMap) -> Self {
+        self.status(status).response_headers(headers)
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn response_headers(mut self, headers: &'a HeaderMap) -> Self {
+        self.response_headers = Some(headers);
+        self
+    }
+
+    fn lookup(&self, source: &Source) -> Option<String> {
+        let header = |headers: Option<&HeaderMap>, name: &str| {
+            headers?
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+
+        match source {
+            Source::ClientIp => Some(self.context.client_ip.clone()),
+            Source::RequestId => Some(self.context.request_id.clone()),
+            Source::Method => Some(self.context.method.clone()),
+            Source::Path => Some(self.context.path.clone()),
+            Source::UserAgent => self.context.user_agent.clone(),
+            Source::Route => self.route.map(str::to_string),
+            Source::Service => self.service.map(str::to_string),
+            Source::Endpoint => self.endpoint.map(str::to_string),
+            Source::Status => self.status.map(|status| status.to_string()),
+            Source::LatencyMs => Some(self.context.elapsed().to_string()),
+            Source::Header(name) => header(self.request_headers, name),
+            Source::ResponseHeader(name) => header(self.response_headers, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+    use proptest::prelude::*;
+
+    fn context() -> RequestContext {
+        let req = Request::builder()
+            .uri("/api/a/items")
+            .header("user-agent", "curl/8")
+            .body(())
+            .unwrap();
+        RequestContext::new(&req, "10.0.0.7".to_string())
+    }
+
+    #[test]
+    fn test_render_variables_and_headers() {
+        let context = context();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "acme".parse().unwrap());
+
+        let template = Template::parse("${client_ip} ${method} ${path} ${header:X-Tenant} ${route}").unwrap();
+        let scope = Scope::new(&context).request_headers(&headers).route("/api/a", "service-a");
+        assert_eq!(template.render(&scope), "10.0.0.7 GET /api/a/items acme /api/a");
+    }
+
+    #[test]
+    fn test_missing_values_use_fallback_or_empty() {
+        let context = context();
+        let template = Template::parse("[${status}] [${header:x-missing|none}] [${endpoint|}]").unwrap();
+        assert_eq!(template.render(&Scope::new(&context)), "[] [none] []");
+    }
+
+    #[test]
+    fn test_parse_errors_report_position() {
+        let error = Template::parse("id=${request_id} ${nope}").unwrap_err();
+        assert_eq!(error.position, 19);
+        assert!(error.message.contains("unknown variable `nope`"));
+
+        let error = Template::parse("ok ${header:x").unwrap_err();
+        assert_eq!(error.position, 3);
+        assert!(error.message.contains("unterminated"));
+
+        assert!(Template::parse("${header:}").is_err());
+        assert!(Template::parse("${}").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_rejects_invalid_template() {
+        let error = serde_json::from_str::<Template>(r#""${bogus:x}""#).unwrap_err();
+        assert!(error.to_string().contains("unknown function `bogus`"));
+
+        let template: Template = serde_json::from_str(r#""${request_id}""#).unwrap();
+        assert_eq!(template.source(), "${request_id}");
+    }
+
+    #[test]
+    fn test_config_template_keeps_parse_errors() {
+        let template: ConfigTemplate = serde_json::from_str(r#""x ${nope}""#).unwrap();
+        assert_eq!(template.source(), "x ${nope}");
+        assert_eq!(template.error().map(|error| error.position), Some(4));
+        assert_eq!(template.render(&Scope::new(&context())), "");
+
+        let template = ConfigTemplate::parse("${method} ${path}");
+        assert!(template.error().is_none());
+        assert_eq!(template.render(&Scope::new(&context())), "GET /api/a/items");
+    }
+
+    proptest! {
+        #[test]
+        fn prop_escaped_text_renders_literally(text in "[a-z${} ]{0,40}") {
+            let escaped = text.replace('$', "$$");
+            let template = Template::parse(&escaped).unwrap();
+            prop_assert!(template.is_static());
+            prop_assert_eq!(template.render(&Scope::new(&context())), text);
+        }
+
+        #[test]
+        fn prop_unknown_functions_are_rejected(
+            prefix in "[a-z ]{0,10}",
+            function in "[a-z_]{1,12}",
+            argument in "[a-z-]{1,12}",
+        ) {
+            prop_assume!(!FUNCTIONS.contains(&function.as_str()));
+            let error = Template::parse(&format!("{}${{{}:{}}}", prefix, function, argument)).unwrap_err();
+            prop_assert_eq!(error.position, prefix.len() + 2);
+            let expected = format!("unknown function `{}`", function);
+            prop_assert!(error.message.contains(&expected));
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use crate::endpoint_url;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Takes a service's endpoints from the EndpointSlices of a Kubernetes
+// Service instead of the config. Only ready endpoints are listed, so the
+// kubelet's readiness probe stands in for the health checker's.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubernetesDiscoveryConfig {
+    pub namespace: String,
+    pub service: String,
+    // The slice port to send to, by name; the first port when unset.
+    pub port_name: Option<String>,
+    pub scheme: String,
+    pub api_server: String,
+    // Read again for every request, since projected tokens are rotated.
+    pub token_path: PathBuf,
+    pub ca_path: PathBuf,
+    // Wait before listing again after a failure, doubled on each one in a
+    // row up to retry_max_ms.
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+}
+
+impl Default for KubernetesDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "default".to_string(),
+            service: String::new(),
+            port_name: None,
+            scheme: "http".to_string(),
+            api_server: "https://kubernetes.default.svc".to_string(),
+            token_path: PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount/token"),
+            ca_path: PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount/ca.crt"),
+            retry_initial_ms: 500,
+            retry_max_ms: 30_000,
+        }
+    }
+}
+
+impl KubernetesDiscoveryConfig {
+    pub fn retry_delay(&self, failures: u32) -> Duration {
+        let delay = self.retry_initial_ms.saturating_mul(1u64.checked_shl(failures).unwrap_or(u64::MAX));
+        Duration::from_millis(delay.min(self.retry_max_ms))
+    }
+}
+
+// The parts of the discovery.k8s.io/v1 objects the proxy reads.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ObjectMeta {
+    pub name: String,
+    pub resource_version: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EndpointSlice {
+    pub metadata: ObjectMeta,
+    pub endpoints: Vec<SliceEndpoint>,
+    pub ports: Vec<SlicePort>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SliceEndpoint {
+    pub addresses: Vec<String>,
+    pub conditions: EndpointConditions,
+}
+
+// An unset condition means ready, as the API defines it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EndpointConditions {
+    pub ready: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SlicePort {
+    pub name: Option<String>,
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SliceList {
+    pub metadata: ObjectMeta,
+    pub items: Vec<EndpointSlice>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WatchStatus {
+    pub code: u16,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "object", rename_all = "UPPERCASE")]
+pub enum WatchEvent {
+    Added(EndpointSlice),
+    Modified(EndpointSlice),
+    Deleted(EndpointSlice),
+    // Only the metadata is set: a newer version to resume from.
+    Bookmark(EndpointSlice),
+    Error(WatchStatus),
+}
+
+impl EndpointSlice {
+    // The slice's ready addresses as endpoint URLs.
+    pub fn ready_endpoints(&self, config: &KubernetesDiscoveryConfig) -> Vec<String> {
+        let port = self
+            .ports
+            .iter()
+            .find(|port| config.port_name.is_none() || port.name == config.port_name)
+            .and_then(|port| port.port);
+        let Some(port) = port else {
+            return Vec::new();
+        };
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.conditions.ready.unwrap_or(true))
+            .flat_map(|endpoint| &endpoint.addresses)
+            .filter_map(|address| {
+                let host = if address.contains(':') { format!("[{}]", address) } else { address.clone() };
+                endpoint_url::canonicalize(&format!("{}://{}:{}", config.scheme, host, port)).ok()
+            })
+            .collect()
+    }
+}
+
+// Where the slices come from: the API server, or a fake in tests.
+#[async_trait]
+pub trait SliceSource: Send + Sync {
+    async fn list(&self) -> Result<SliceList>;
+    // Changes after `resource_version`, until the server ends the watch.
+    async fn watch(&self, resource_version: &str) -> Result<BoxStream<'static, Result<WatchEvent>>>;
+}
+
+// Ready endpoints by slice name.
+#[derive(Debug, Default)]
+struct Slices(HashMap<String, Vec<String>>);
+
+impl Slices {
+    fn endpoints(&self) -> Vec<String> {
+        let endpoints: BTreeSet<&String> = self.0.values().flatten().collect();
+        endpoints.into_iter().cloned().collect()
+    }
+}
+
+enum Flow {
+    // The server closed the watch, as it does every few minutes.
+    Ended,
+    Stopped,
+}
+
+// Follows the service's slices until `publish` returns false, handing it the
+// ready endpoints whenever they change. A failed list or a dropped watch is
+// retried with backoff; nothing is published meanwhile, so the last
+// endpoints stay in use.
+pub async fn follow<P, F>(source: &dyn SliceSource, config: &KubernetesDiscoveryConfig, mut publish: P)
+where
+    P: FnMut(Vec<String>) -> F + Send,
+    F: Future<Output = bool> + Send,
+{
+    let mut published = None;
+    let mut failures = 0u32;
+    loop {
+        let delay = match sync(source, config, &mut published, &mut publish, &mut failures).await {
+            Ok(Flow::Stopped) => return,
+            Ok(Flow::Ended) => {
+                debug!(service = %config.service, "kubernetes watch ended");
+                config.retry_delay(0)
+            }
+            Err(e) => {
+                let delay = config.retry_delay(failures);
+                failures = failures.saturating_add(1);
+                warn!(
+                    namespace = %config.namespace,
+                    service = %config.service,
+                    error = format!("{:#}", e),
+                    retry_ms = delay.as_millis() as u64,
+                    "kubernetes discovery interrupted, keeping the last endpoints"
+                );
+                delay
+            }
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// One list and the watch that follows it.
+async fn sync<P, F>(
+    source: &dyn SliceSource,
+    config: &KubernetesDiscoveryConfig,
+    published: &mut Option<Vec<String>>,
+    publish: &mut P,
+    failures: &mut u32,
+) -> Result<Flow>
+where
+    P: FnMut(Vec<String>) -> F + Send,
+    F: Future<Output = bool> + Send,
+{
+    let list = source.list().await?;
+    *failures = 0;
+    let mut slices = Slices::default();
+    for slice in &list.items {
+        slices.0.insert(slice.metadata.name.clone(), slice.ready_endpoints(config));
+    }
+    let mut version = list.metadata.resource_version;
+    if !changed(published, &slices, config, publish).await {
+        return Ok(Flow::Stopped);
+    }
+
+    let mut events = source.watch(&version).await?;
+    while let Some(event) = events.next().await {
+        match event? {
+            WatchEvent::Added(slice) | WatchEvent::Modified(slice) => {
+                version = slice.metadata.resource_version.clone();
+                slices.0.insert(slice.metadata.name.clone(), slice.ready_endpoints(config));
+            }
+            WatchEvent::Deleted(slice) => {
+                version = slice.metadata.resource_version.clone();
+                slices.0.remove(&slice.metadata.name);
+            }
+            WatchEvent::Bookmark(slice) => {
+                version = slice.metadata.resource_version;
+                continue;
+            }
+            // Usually 410 Gone: the version is too old to resume from.
+            WatchEvent::Error(status) => bail!("watch error {}: {}", status.code, status.message),
+        }
+        if !changed(published, &slices, config, publish).await {
+            return Ok(Flow::Stopped);
+        }
+    }
+    debug!(service = %config.service, version = %version, "kubernetes watch closed by the server");
+    Ok(Flow::Ended)
+}
+
+// Publishes the endpoints if they differ from the last ones published.
+async fn changed<P, F>(
+    published: &mut Option<Vec<String>>,
+    slices: &Slices,
+    config: &KubernetesDiscoveryConfig,
+    publish: &mut P,
+) -> bool
+where
+    P: FnMut(Vec<String>) -> F + Send,
+    F: Future<Output = bool> + Send,
+{
+    let endpoints = slices.endpoints();
+    if published.as_ref() == Some(&endpoints) {
+        return true;
+    }
+    info!(
+        namespace = %config.namespace,
+        service = %config.service,
+        endpoints = ?endpoints,
+        "kubernetes endpoints changed"
+    );
+    *published = Some(endpoints.clone());
+    publish(endpoints).await
+}
+
+#[cfg(feature = "kubernetes")]
+pub use api_server::ApiServer;
+
+#[cfg(feature = "kubernetes")]
+mod api_server {
+    use super::*;
+    use anyhow::Context;
+    use std::io::ErrorKind;
+
+    // Lists and watches EndpointSlices over the API server's REST API, as
+    // the pod's service account.
+    pub struct ApiServer {
+        client: reqwest::Client,
+        config: KubernetesDiscoveryConfig,
+    }
+
+    impl ApiServer {
+        pub fn new(config: &KubernetesDiscoveryConfig) -> Result<Self> {
+            let mut builder = reqwest::Client::builder().connect_timeout(Duration::from_secs(10));
+            match std::fs::read(&config.ca_path) {
+                Ok(pem) => {
+                    let ca = reqwest::Certificate::from_pem(&pem)
+                        .with_context(|| format!("reading CA {}", config.ca_path.display()))?;
+                    builder = builder.add_root_certificate(ca);
+                }
+                // Outside a pod, or an API server with a public certificate.
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("reading CA {}", config.ca_path.display())),
+            }
+            Ok(Self {
+                client: builder.build()?,
+                config: config.clone(),
+            })
+        }
+
+        fn request(&self, query: &[(&str, &str)]) -> Result<reqwest::RequestBuilder> {
+            let url = format!(
+                "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices",
+                self.config.api_server.trim_end_matches('/'),
+                self.config.namespace
+            );
+            let selector = format!("kubernetes.io/service-name={}", self.config.service);
+            let request = self.client.get(url).query(&[("labelSelector", selector.as_str())]).query(query);
+            match std::fs::read_to_string(&self.config.token_path) {
+                Ok(token) => Ok(request.bearer_auth(token.trim())),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(request),
+                Err(e) => Err(e).with_context(|| format!("reading token {}", self.config.token_path.display())),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SliceSource for ApiServer {
+        async fn list(&self) -> Result<SliceList> {
+            let response = self.request(&[])?.send().await?.error_for_status()?;
+            Ok(response.json().await?)
+        }
+
+        async fn watch(&self, resource_version: &str) -> Result<BoxStream<'static, Result<WatchEvent>>> {
+            let query = [("watch", "true"), ("allowWatchBookmarks", "true"), ("resourceVersion", resource_version)];
+            let response = self.request(&query)?.send().await?.error_for_status()?;
+            // One JSON event per line, in chunks that need not line up with them.
+            let events = futures::stream::unfold(Some((response, Vec::new())), |state| async move {
+                let (mut response, mut buffer) = state?;
+                loop {
+                    if let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=newline).collect();
+                        if line.iter().all(u8::is_ascii_whitespace) {
+                            continue;
+                        }
+                        let event = serde_json::from_slice(&line).context("malformed watch event");
+                        return Some((event, Some((response, buffer))));
+                    }
+                    match response.chunk().await {
+                        Ok(Some(chunk)) => buffer.extend_from_slice(&chunk),
+                        Ok(None) => return None,
+                        Err(e) => return Some((Err(e.into()), None)),
+                    }
+                }
+            });
+            Ok(events.boxed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    fn slice(name: &str, version: &str, endpoints: serde_json::Value) -> EndpointSlice {
+        serde_json::from_value(json!({
+            "metadata": {"name": name, "resourceVersion": version},
+            "endpoints": endpoints,
+            "ports": [{"name": "metrics", "port": 9090}, {"name": "http", "port": 8080}],
+        }))
+        .unwrap()
+    }
+
+    fn config() -> KubernetesDiscoveryConfig {
+        KubernetesDiscoveryConfig {
+            service: "orders".to_string(),
+            port_name: Some("http".to_string()),
+            retry_initial_ms: 1,
+            retry_max_ms: 4,
+            ..KubernetesDiscoveryConfig::default()
+        }
+    }
+
+    // Plays back one scripted answer per call; a watch script ends with
+    // either the server closing it or an error.
+    #[derive(Default)]
+    struct Scripted {
+        lists: Mutex<Vec<Result<SliceList>>>,
+        watches: Mutex<Vec<Vec<Result<WatchEvent>>>>,
+    }
+
+    #[async_trait]
+    impl SliceSource for Scripted {
+        async fn list(&self) -> Result<SliceList> {
+            self.lists.lock().unwrap().remove(0)
+        }
+
+        async fn watch(&self, _resource_version: &str) -> Result<BoxStream<'static, Result<WatchEvent>>> {
+            let events = self.watches.lock().unwrap().remove(0);
+            Ok(futures::stream::iter(events).boxed())
+        }
+    }
+
+    #[test]
+    fn test_only_ready_addresses_on_the_named_port() {
+        let slice = slice(
+            "orders-abc",
+            "1",
+            json!([
+                {"addresses": ["10.0.0.1"], "conditions": {"ready": true}},
+                {"addresses": ["10.0.0.2"], "conditions": {"ready": false}},
+                {"addresses": ["10.0.0.3"]},
+                {"addresses": ["fd00::4"], "conditions": {"ready": true}},
+            ]),
+        );
+        assert_eq!(
+            slice.ready_endpoints(&config()),
+            vec!["http://10.0.0.1:8080", "http://10.0.0.3:8080", "http://[fd00::4]:8080"]
+        );
+        let unnamed = KubernetesDiscoveryConfig { port_name: None, ..config() };
+        assert_eq!(slice.ready_endpoints(&unnamed)[0], "http://10.0.0.1:9090");
+        let missing = KubernetesDiscoveryConfig { port_name: Some("grpc".to_string()), ..config() };
+        assert!(slice.ready_endpoints(&missing).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch_events_update_and_disconnects_keep_last_endpoints() {
+        let ready = |address: &str| json!([{"addresses": [address], "conditions": {"ready": true}}]);
+        let source = Scripted::default();
+        *source.lists.lock().unwrap() = vec![
+            Ok(SliceList {
+                metadata: ObjectMeta { resource_version: "10".to_string(), ..ObjectMeta::default() },
+                items: vec![slice("a", "9", ready("10.0.0.1")), slice("b", "10", ready("10.0.0.2"))],
+            }),
+            Err(anyhow::anyhow!("connection refused")),
+            Ok(SliceList {
+                metadata: ObjectMeta { resource_version: "20".to_string(), ..ObjectMeta::default() },
+                items: vec![slice("a", "20", ready("10.0.0.5"))],
+            }),
+        ];
+        *source.watches.lock().unwrap() = vec![
+            vec![
+                Ok(WatchEvent::Bookmark(slice("", "11", json!([])))),
+                Ok(WatchEvent::Modified(slice("b", "12", ready("10.0.0.3")))),
+                // A pod going unready drops out without the slice going away.
+                Ok(WatchEvent::Modified(slice("a", "13", json!([{"addresses": ["10.0.0.1"], "conditions": {"ready": false}}])))),
+                Ok(WatchEvent::Error(WatchStatus { code: 410, message: "too old".to_string() })),
+            ],
+            vec![Ok(WatchEvent::Deleted(slice("a", "21", json!([]))))],
+        ];
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let seen = published.clone();
+        follow(&source, &config(), move |endpoints| {
+            let seen = seen.clone();
+            async move {
+                let mut seen = seen.lock().unwrap();
+                seen.push(endpoints);
+                seen.len() < 5
+            }
+        })
+        .await;
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![
+                vec!["http://10.0.0.1:8080", "http://10.0.0.2:8080"],
+                vec!["http://10.0.0.1:8080", "http://10.0.0.3:8080"],
+                vec!["http://10.0.0.3:8080"],
+                // The failed list in between published nothing.
+                vec!["http://10.0.0.5:8080"],
+                Vec::<&str>::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_doubles_to_its_cap() {
+        let config = KubernetesDiscoveryConfig { retry_initial_ms: 500, retry_max_ms: 3000, ..config() };
+        let delays: Vec<u64> = (0..5).map(|failures| config.retry_delay(failures).as_millis() as u64).collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 3000, 3000]);
+    }
+
+    #[cfg(feature = "kubernetes")]
+    #[tokio::test]
+    async fn test_api_server_lists_and_watches_slices() {
+        let (addr, requests) = crate::test_support::spawn_fake_api_server(|target| {
+            let body = if target.contains("watch=true") {
+                let event = json!({"type": "ADDED", "object": {
+                    "metadata": {"name": "orders-b", "resourceVersion": "8"},
+                    "endpoints": [{"addresses": ["10.0.0.9"]}],
+                    "ports": [{"name": "http", "port": 8080}],
+                }});
+                format!("\n{}\n", event)
+            } else {
+                json!({"metadata": {"resourceVersion": "7"}, "items": []}).to_string()
+            };
+            Some(body)
+        })
+        .await;
+
+        let dir = std::env::temp_dir().join(format!("kubernetes-api-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("token"), "s3cret\n").unwrap();
+        let server = ApiServer::new(&KubernetesDiscoveryConfig {
+            namespace: "shop".to_string(),
+            api_server: format!("http://{}/", addr),
+            token_path: dir.join("token"),
+            ca_path: dir.join("missing.crt"),
+            ..config()
+        })
+        .unwrap();
+
+        let list = server.list().await.unwrap();
+        assert_eq!(list.metadata.resource_version, "7");
+        let events: Vec<WatchEvent> = server.watch("7").await.unwrap().map(Result::unwrap).collect().await;
+        assert!(matches!(&events[..], [WatchEvent::Added(slice)] if slice.metadata.name == "orders-b"));
+
+        let requests = requests.lock().unwrap();
+        let path = "/apis/discovery.k8s.io/v1/namespaces/shop/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3Dorders";
+        assert_eq!(requests[0], format!("{} Bearer s3cret", path));
+        assert_eq!(
+            requests[1],
+            format!("{}&watch=true&allowWatchBookmarks=true&resourceVersion=7 Bearer s3cret", path)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,81 @@
 pub mod config;
+pub mod config_migration;
+pub mod config_toml;
+pub mod config_yaml;
+pub mod config_env;
+pub mod config_reload;
+pub mod config_history;
+pub mod policy_templates;
 pub mod proxy;
+pub mod routes;
+pub mod path_templates;
 pub mod ai;
 pub mod metrics;
+#[cfg(not(feature = "metrics-prometheus"))]
+mod local_metrics;
+pub mod stats_history;
 pub mod load_balancer;
+pub mod auto_weight;
 pub mod circuit_breaker;
 pub mod rate_limiter;
+pub mod dedupe;
+pub mod quota;
+pub mod storage;
 pub mod health_checker;
+pub mod kubernetes;
+pub mod consul;
 pub mod middleware;
+pub mod sniff;
+pub mod request_target;
+pub mod strict_http;
+pub mod fd_monitor;
+pub mod overload;
+pub mod standby;
+pub mod websocket;
+pub mod connection_tasks;
+pub mod upstream_client;
+pub mod proxy_protocol;
+pub mod upstream_timing;
+pub mod server_timing;
+pub mod prewarm;
+pub mod eager_init;
+pub mod address_family;
+pub mod buffer_budget;
+pub mod request_body;
+pub mod response_body;
+pub mod client_timeouts;
+pub mod early_hints;
+pub mod body_pipeline;
+pub mod conditional;
+pub mod checksum;
+pub mod archive;
+pub mod capture;
+pub mod content_coding;
+pub mod supervisor;
+pub mod lifecycle;
+pub mod drain;
+pub mod clock;
+pub mod endpoint_gc;
+pub mod endpoint_url;
+pub mod interpolate;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(not(feature = "tls"))]
+#[path = "no_tls.rs"]
+pub mod tls;
+pub mod access;
+pub mod admin_auth;
+pub mod experiments;
+pub mod egress;
+pub mod mesh_metadata;
+pub mod response_headers;
+pub mod upstream_response;
+pub mod header_values;
+pub mod header_rules;
+pub mod routability;
+pub mod mock_upstream;
+pub mod bench;
+pub mod simulation;
+
+#[cfg(test)]
+mod test_support;
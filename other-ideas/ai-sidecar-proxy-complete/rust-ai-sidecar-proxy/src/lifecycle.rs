@@ -0,0 +1,360 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+type HookFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type HookFn = Box<dyn FnOnce() -> HookFuture + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookState {
+    Pending,
+    Starting,
+    Running,
+    // Start failed or timed out.
+    Failed,
+    // Not started because something it depends on did not start.
+    Skipped,
+    Stopping,
+    Stopped,
+    StopFailed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HookStatus {
+    pub name: String,
+    pub after: Vec<String>,
+    pub optional: bool,
+    pub state: HookState,
+    pub start_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+// A named component with async start and stop steps. Starts run after every
+// hook named in `after`; stops run in the reverse order.
+pub struct LifecycleHook {
+    name: String,
+    after: Vec<String>,
+    optional: bool,
+    timeout: Duration,
+    start: Option<HookFn>,
+    stop: Option<HookFn>,
+}
+
+impl LifecycleHook {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            after: Vec::new(),
+            optional: false,
+            timeout: DEFAULT_HOOK_TIMEOUT,
+            start: None,
+            stop: None,
+        }
+    }
+
+    pub fn after(mut self, dependency: &str) -> Self {
+        self.after.push(dependency.to_string());
+        self
+    }
+
+    // A failed optional hook is reported, and startup carries on without it
+    // and anything that depends on it.
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    // Applies to start and stop separately.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn on_start<F, Fut>(mut self, start: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.start = Some(Box::new(move || Box::pin(start())));
+        self
+    }
+
+    pub fn on_stop<F, Fut>(mut self, stop: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.stop = Some(Box::new(move || Box::pin(stop())));
+        self
+    }
+}
+
+struct Started {
+    name: String,
+    timeout: Duration,
+    stop: Option<HookFn>,
+}
+
+#[derive(Default)]
+pub struct Lifecycle {
+    hooks: Mutex<Vec<LifecycleHook>>,
+    status: Mutex<Vec<HookStatus>>,
+    // In start order.
+    started: Mutex<Vec<Started>>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, hook: LifecycleHook) {
+        self.status.lock().unwrap().push(HookStatus {
+            name: hook.name.clone(),
+            after: hook.after.clone(),
+            optional: hook.optional,
+            state: HookState::Pending,
+            start_ms: None,
+            error: None,
+        });
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    pub fn status(&self) -> Vec<HookStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Starts every registered hook in dependency order. A required hook that
+    // fails stops the hooks already running and fails startup with every
+    // failure seen so far.
+    pub async fn start_all(&self) -> Result<()> {
+        let hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        let hooks = order(hooks)?;
+
+        let mut down = HashSet::new();
+        let mut failures = Vec::new();
+        for mut hook in hooks {
+            if let Some(dependency) = hook.after.iter().find(|dependency| down.contains(*dependency)) {
+                let error = format!("depends on {}, which did not start", dependency);
+                self.set(&hook.name, HookState::Skipped, None, Some(error.clone()));
+                down.insert(hook.name.clone());
+                if hook.optional {
+                    warn!(hook = %hook.name, error = %error, "skipping optional lifecycle hook");
+                    continue;
+                }
+                failures.push(format!("{}: {}", hook.name, error));
+                break;
+            }
+
+            self.set(&hook.name, HookState::Starting, None, None);
+            let started_at = Instant::now();
+            let result = match hook.start.take() {
+                Some(start) => run(start, hook.timeout).await,
+                None => Ok(()),
+            };
+            let elapsed = Some(started_at.elapsed().as_millis() as u64);
+            match result {
+                Ok(()) => {
+                    self.set(&hook.name, HookState::Running, elapsed, None);
+                    info!(hook = %hook.name, elapsed_ms = elapsed, "lifecycle hook started");
+                    self.started.lock().unwrap().push(Started {
+                        name: hook.name,
+                        timeout: hook.timeout,
+                        stop: hook.stop,
+                    });
+                }
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    self.set(&hook.name, HookState::Failed, elapsed, Some(error.clone()));
+                    down.insert(hook.name.clone());
+                    failures.push(format!("{}: {}", hook.name, error));
+                    if hook.optional {
+                        warn!(hook = %hook.name, error = %error, "optional lifecycle hook failed to start");
+                        continue;
+                    }
+                    break;
+                }
+            }
+        }
+
+        let required_failed = self
+            .status()
+            .iter()
+            .any(|status| !status.optional && matches!(status.state, HookState::Failed | HookState::Skipped));
+        if required_failed {
+            self.stop_all().await;
+            bail!("startup failed: {}", failures.join("; "));
+        }
+        Ok(())
+    }
+
+    // Stops the running hooks, last started first. Failures are logged and the
+    // rest still stop.
+    pub async fn stop_all(&self) {
+        let started = std::mem::take(&mut *self.started.lock().unwrap());
+        for hook in started.into_iter().rev() {
+            let Some(stop) = hook.stop else {
+                self.set(&hook.name, HookState::Stopped, None, None);
+                continue;
+            };
+            self.set(&hook.name, HookState::Stopping, None, None);
+            match run(stop, hook.timeout).await {
+                Ok(()) => {
+                    self.set(&hook.name, HookState::Stopped, None, None);
+                    info!(hook = %hook.name, "lifecycle hook stopped");
+                }
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    warn!(hook = %hook.name, error = %error, "lifecycle hook failed to stop");
+                    self.set(&hook.name, HookState::StopFailed, None, Some(error));
+                }
+            }
+        }
+    }
+
+    fn set(&self, name: &str, state: HookState, start_ms: Option<u64>, error: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+        if let Some(status) = status.iter_mut().find(|status| status.name == name) {
+            status.state = state;
+            if start_ms.is_some() {
+                status.start_ms = start_ms;
+            }
+            if error.is_some() {
+                status.error = error;
+            }
+        }
+    }
+}
+
+async fn run(hook: HookFn, timeout: Duration) -> Result<()> {
+    tokio::time::timeout(timeout, hook())
+        .await
+        .map_err(|_| anyhow!("timed out after {}ms", timeout.as_millis()))?
+}
+
+// Dependencies first; otherwise registration order.
+fn order(hooks: Vec<LifecycleHook>) -> Result<Vec<LifecycleHook>> {
+    let names: HashMap<String, usize> = hooks.iter().enumerate().map(|(i, hook)| (hook.name.clone(), i)).collect();
+    if names.len() != hooks.len() {
+        bail!("lifecycle hook names must be unique");
+    }
+    for hook in &hooks {
+        if let Some(missing) = hook.after.iter().find(|dependency| !names.contains_key(*dependency)) {
+            bail!("lifecycle hook {} depends on unknown hook {}", hook.name, missing);
+        }
+    }
+
+    let mut placed = vec![false; hooks.len()];
+    let mut sequence = Vec::with_capacity(hooks.len());
+    while sequence.len() < hooks.len() {
+        let next = (0..hooks.len()).find(|&i| {
+            !placed[i] && hooks[i].after.iter().all(|dependency| placed[names[dependency]])
+        });
+        let Some(next) = next else {
+            let stuck: Vec<&str> = (0..hooks.len()).filter(|&i| !placed[i]).map(|i| hooks[i].name.as_str()).collect();
+            bail!("lifecycle hooks depend on each other in a cycle: {}", stuck.join(", "));
+        };
+        placed[next] = true;
+        sequence.push(next);
+    }
+
+    let mut hooks: Vec<Option<LifecycleHook>> = hooks.into_iter().map(Some).collect();
+    Ok(sequence.into_iter().map(|i| hooks[i].take().unwrap()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn recorded(log: &Arc<Mutex<Vec<String>>>, name: &str) -> LifecycleHook {
+        let (start_log, stop_log) = (log.clone(), log.clone());
+        let (start_name, stop_name) = (format!("start {}", name), format!("stop {}", name));
+        LifecycleHook::new(name)
+            .on_start(move || async move {
+                start_log.lock().unwrap().push(start_name);
+                Ok(())
+            })
+            .on_stop(move || async move {
+                stop_log.lock().unwrap().push(stop_name);
+                Ok(())
+            })
+    }
+
+    #[tokio::test]
+    async fn test_starts_in_dependency_order_and_stops_in_reverse() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let lifecycle = Lifecycle::new();
+        lifecycle.register(recorded(&log, "exporter").after("writer"));
+        lifecycle.register(recorded(&log, "writer").after("store"));
+        lifecycle.register(recorded(&log, "store"));
+
+        lifecycle.start_all().await.unwrap();
+        lifecycle.stop_all().await;
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["start store", "start writer", "start exporter", "stop exporter", "stop writer", "stop store"]
+        );
+        assert!(lifecycle.status().iter().all(|status| status.state == HookState::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_optional_failure_skips_dependents_only() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let lifecycle = Lifecycle::new();
+        lifecycle.register(
+            LifecycleHook::new("flaky")
+                .optional()
+                .timeout(Duration::from_millis(50))
+                .on_start(std::future::pending),
+        );
+        lifecycle.register(recorded(&log, "uses-flaky").after("flaky").optional());
+        lifecycle.register(recorded(&log, "core"));
+
+        lifecycle.start_all().await.unwrap();
+
+        assert_eq!(*log.lock().unwrap(), vec!["start core"]);
+        let status = lifecycle.status();
+        assert_eq!(status[0].state, HookState::Failed);
+        assert_eq!(status[0].error.as_deref(), Some("timed out after 50ms"));
+        assert_eq!(status[1].state, HookState::Skipped);
+        assert_eq!(status[2].state, HookState::Running);
+    }
+
+    #[tokio::test]
+    async fn test_required_failure_stops_started_hooks() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let lifecycle = Lifecycle::new();
+        lifecycle.register(recorded(&log, "store"));
+        lifecycle.register(LifecycleHook::new("broken").after("store").on_start(|| async { bail!("no spool dir") }));
+        lifecycle.register(recorded(&log, "late").after("broken"));
+
+        let error = lifecycle.start_all().await.unwrap_err();
+        assert_eq!(error.to_string(), "startup failed: broken: no spool dir");
+        assert_eq!(*log.lock().unwrap(), vec!["start store", "stop store"]);
+        assert_eq!(lifecycle.status()[2].state, HookState::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_and_cyclic_dependencies_rejected() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.register(LifecycleHook::new("a").after("missing"));
+        assert!(lifecycle.start_all().await.unwrap_err().to_string().contains("unknown hook missing"));
+
+        let lifecycle = Lifecycle::new();
+        lifecycle.register(LifecycleHook::new("a").after("b"));
+        lifecycle.register(LifecycleHook::new("b").after("a"));
+        assert!(lifecycle.start_all().await.unwrap_err().to_string().contains("cycle: a, b"));
+    }
+}
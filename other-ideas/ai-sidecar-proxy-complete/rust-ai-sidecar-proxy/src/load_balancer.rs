@@ -1,29 +1,172 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use crate::config::UpstreamService;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
 use tokio::sync::RwLock;
 use tracing::debug;
 
-#[derive(Debug, Clone)]
+const ACTIVE_EWMA_ALPHA: f64 = 0.3;
+const DEFAULT_WEIGHT: u32 = 1;
+// Samples taken by `least_request` when named in config.
+const DEFAULT_CHOICE_COUNT: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LoadBalancingStrategy {
     RoundRobin,
     WeightedRoundRobin,
     LeastConnections,
     Random,
+    LeastRequest { choice_count: usize },
+    WeightedRandom,
+}
+
+impl LoadBalancingStrategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoadBalancingStrategy::RoundRobin => "round_robin",
+            LoadBalancingStrategy::WeightedRoundRobin => "weighted_round_robin",
+            LoadBalancingStrategy::LeastConnections => "least_connections",
+            LoadBalancingStrategy::Random => "random",
+            LoadBalancingStrategy::LeastRequest { .. } => "least_request",
+            LoadBalancingStrategy::WeightedRandom => "weighted_random",
+        }
+    }
+
+    // Whether endpoint weights change what gets picked.
+    pub fn uses_weights(&self) -> bool {
+        matches!(
+            self,
+            LoadBalancingStrategy::WeightedRoundRobin
+                | LoadBalancingStrategy::WeightedRandom
+                | LoadBalancingStrategy::LeastRequest { .. }
+        )
+    }
+}
+
+// Parses the names `name` returns.
+impl FromStr for LoadBalancingStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "round_robin" => Ok(LoadBalancingStrategy::RoundRobin),
+            "weighted_round_robin" => Ok(LoadBalancingStrategy::WeightedRoundRobin),
+            "least_connections" => Ok(LoadBalancingStrategy::LeastConnections),
+            "random" => Ok(LoadBalancingStrategy::Random),
+            "least_request" => Ok(LoadBalancingStrategy::LeastRequest {
+                choice_count: DEFAULT_CHOICE_COUNT,
+            }),
+            "weighted_random" => Ok(LoadBalancingStrategy::WeightedRandom),
+            _ => Err(format!(
+                "unknown strategy {:?}; expected round_robin, weighted_round_robin, least_connections, random, least_request or weighted_random",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct EndpointLoad {
+    active: AtomicUsize,
+    active_ewma_bits: AtomicU64,
+}
+
+impl EndpointLoad {
+    fn active_ewma(&self) -> f64 {
+        f64::from_bits(self.active_ewma_bits.load(Ordering::Relaxed))
+    }
+
+    fn acquire(&self) {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.observe(active);
+    }
+
+    // Never below zero, whatever was counted before.
+    fn release(&self) {
+        let previous = self
+            .active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| Some(active.saturating_sub(1)))
+            .unwrap_or_default();
+        self.observe(previous.saturating_sub(1));
+    }
+
+    fn observe(&self, active: usize) {
+        let _ = self.active_ewma_bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            let previous = f64::from_bits(bits);
+            let next = ACTIVE_EWMA_ALPHA * active as f64 + (1.0 - ACTIVE_EWMA_ALPHA) * previous;
+            Some(next.to_bits())
+        });
+    }
+}
+
+// One request in flight to an endpoint, counted until this is dropped, so a
+// request abandoned mid-call stops counting too. It keeps the count it
+// started on: once endpoint GC forgets the endpoint, the count that replaces
+// it is never lowered by requests that began before.
+pub struct InFlight {
+    load: Arc<EndpointLoad>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.load.release();
+    }
+}
+
+// Where an endpoint's weight came from: config, the admin API or the
+// default of 1, or auto-weighting from AI scores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightSource {
+    Static,
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Weight {
+    value: u32,
+    source: WeightSource,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointExplanation {
+    pub endpoint: String,
+    pub weight: u32,
+    pub weight_source: WeightSource,
+    pub active_requests: usize,
+    pub active_requests_ewma: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelectionExplanation {
+    pub service: String,
+    pub strategy: &'static str,
+    pub endpoints: Vec<EndpointExplanation>,
 }
 
 pub struct LoadBalancer {
     strategy: LoadBalancingStrategy,
     round_robin_counters: RwLock<HashMap<String, AtomicUsize>>,
-    connection_counts: RwLock<HashMap<String, AtomicUsize>>,
+    connection_counts: RwLock<HashMap<String, Arc<EndpointLoad>>>,
+    // Per service, then per endpoint.
+    endpoint_weights: RwLock<HashMap<String, HashMap<String, Weight>>>,
+    // Weighted round robin's running score for each endpoint, per service.
+    current_weights: Mutex<HashMap<String, HashMap<String, i64>>>,
+    rng: Mutex<StdRng>,
+}
+
+impl Default for LoadBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LoadBalancer {
     pub fn new() -> Self {
-        Self {
-            strategy: LoadBalancingStrategy::RoundRobin,
-            round_robin_counters: RwLock::new(HashMap::new()),
-            connection_counts: RwLock::new(HashMap::new()),
-        }
+        Self::with_strategy(LoadBalancingStrategy::RoundRobin)
     }
 
     pub fn with_strategy(strategy: LoadBalancingStrategy) -> Self {
@@ -31,9 +174,37 @@ impl LoadBalancer {
             strategy,
             round_robin_counters: RwLock::new(HashMap::new()),
             connection_counts: RwLock::new(HashMap::new()),
+            endpoint_weights: RwLock::new(HashMap::new()),
+            current_weights: Mutex::new(HashMap::new()),
+            rng: Mutex::new(StdRng::from_entropy()),
         }
     }
 
+    // Starting weights for one service's endpoints, as configured.
+    pub fn with_service_weights(mut self, service_name: &str, weights: &HashMap<String, u32>) -> Self {
+        self.endpoint_weights
+            .get_mut()
+            .entry(service_name.to_string())
+            .or_default()
+            .extend(weights.iter().map(|(endpoint, weight)| {
+                let weight = Weight {
+                    value: *weight,
+                    source: WeightSource::Static,
+                };
+                (endpoint.clone(), weight)
+            }));
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Mutex::new(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    pub fn strategy(&self) -> &LoadBalancingStrategy {
+        &self.strategy
+    }
+
     pub async fn select_endpoint(&self, service_name: &str, endpoints: &[String]) -> Option<String> {
         if endpoints.is_empty() {
             return None;
@@ -52,6 +223,12 @@ impl LoadBalancer {
             LoadBalancingStrategy::Random => {
                 self.random_select(endpoints).await
             }
+            LoadBalancingStrategy::LeastRequest { choice_count } => {
+                self.least_request_select(service_name, endpoints, choice_count).await
+            }
+            LoadBalancingStrategy::WeightedRandom => {
+                self.weighted_random_select(service_name, endpoints).await
+            }
         }
     }
 
@@ -59,76 +236,542 @@ impl LoadBalancer {
         let mut counters = self.round_robin_counters.write().await;
         let counter = counters.entry(service_name.to_string())
             .or_insert_with(|| AtomicUsize::new(0));
-        
+
         let index = counter.fetch_add(1, Ordering::Relaxed) % endpoints.len();
         let selected = endpoints[index].clone();
-        
-        debug!("Round-robin selected endpoint: {} (index: {})", selected, index);
+
+        debug!(strategy = self.strategy.name(), endpoint = %selected, index, "endpoint selected");
         Some(selected)
     }
 
+    // Smooth weighted round robin, as in nginx: every pick adds each
+    // endpoint's weight to its score, takes the highest score and charges it
+    // the total. A 3:1:1 split comes out as a, b, a, c, a rather than three
+    // a's in a row.
     async fn weighted_round_robin_select(&self, service_name: &str, endpoints: &[String]) -> Option<String> {
-        self.round_robin_select(service_name, endpoints).await
+        let weights = self.resolve_weights(service_name, endpoints).await;
+        let total: i64 = weights.iter().map(|&w| w as i64).sum();
+        if total == 0 {
+            return Self::only_endpoint(endpoints);
+        }
+
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let current = current_weights.entry(service_name.to_string()).or_default();
+        current.retain(|endpoint, _| endpoints.contains(endpoint));
+        let mut best: Option<(usize, i64)> = None;
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            if weights[index] == 0 {
+                continue;
+            }
+            let score = current.entry(endpoint.clone()).or_insert(0);
+            *score += weights[index] as i64;
+            if best.is_none_or(|(_, best)| *score > best) {
+                best = Some((index, *score));
+            }
+        }
+        let (index, _) = best?;
+        *current.get_mut(&endpoints[index]).unwrap() -= total;
+
+        let selected = endpoints[index].clone();
+        debug!(strategy = self.strategy.name(), endpoint = %selected, weight = weights[index], "endpoint selected");
+        Some(selected)
     }
 
     async fn least_connections_select(&self, endpoints: &[String]) -> Option<String> {
         let connection_counts = self.connection_counts.read().await;
-        
+
         let mut min_connections = usize::MAX;
         let mut selected_endpoint = None;
-        
+
         for endpoint in endpoints {
             let count = connection_counts.get(endpoint)
-                .map(|c| c.load(Ordering::Relaxed))
+                .map(|c| c.active.load(Ordering::Relaxed))
                 .unwrap_or(0);
-            
+
             if count < min_connections {
                 min_connections = count;
                 selected_endpoint = Some(endpoint.clone());
             }
         }
-        
+
         if let Some(ref endpoint) = selected_endpoint {
-            debug!("Least connections selected endpoint: {} (connections: {})", endpoint, min_connections);
+            debug!(strategy = self.strategy.name(), endpoint = %endpoint, connections = min_connections, "endpoint selected");
         }
-        
+
         selected_endpoint
     }
 
     async fn random_select(&self, endpoints: &[String]) -> Option<String> {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        use std::time::{SystemTime, UNIX_EPOCH};
-        
-        let mut hasher = DefaultHasher::new();
-        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos().hash(&mut hasher);
-        let hash = hasher.finish();
-        
-        let index = (hash as usize) % endpoints.len();
+        let index = self.rng.lock().unwrap().gen_range(0..endpoints.len());
         let selected = endpoints[index].clone();
-        
-        debug!("Random selected endpoint: {} (index: {})", selected, index);
+
+        debug!(strategy = self.strategy.name(), endpoint = %selected, index, "endpoint selected");
         Some(selected)
     }
 
+    // Envoy-style least request: sample `choice_count` endpoints and keep the
+    // one with the best weight per unit of (smoothed) in-flight load.
+    async fn least_request_select(&self, service_name: &str, endpoints: &[String], choice_count: usize) -> Option<String> {
+        let weights = self.resolve_weights(service_name, endpoints).await;
+        let connection_counts = self.connection_counts.read().await;
+
+        let weighted: Vec<usize> = (0..endpoints.len()).filter(|&index| weights[index] > 0).collect();
+        if weighted.is_empty() {
+            return Self::only_endpoint(endpoints);
+        }
+        let candidates: Vec<usize> = {
+            let mut rng = self.rng.lock().unwrap();
+            (0..choice_count.max(1)).map(|_| weighted[rng.gen_range(0..weighted.len())]).collect()
+        };
+
+        let load = |index: usize| {
+            connection_counts
+                .get(&endpoints[index])
+                .map(|l| l.active.load(Ordering::Relaxed) as f64 + l.active_ewma())
+                .unwrap_or(0.0)
+        };
+
+        let best = candidates.into_iter().max_by(|&a, &b| {
+            let score_a = weights[a] as f64 / (load(a) + 1.0);
+            let score_b = weights[b] as f64 / (load(b) + 1.0);
+            score_a.total_cmp(&score_b)
+        })?;
+
+        let selected = endpoints[best].clone();
+        debug!(
+            strategy = self.strategy.name(),
+            endpoint = %selected,
+            weight = weights[best],
+            load = load(best),
+            "endpoint selected"
+        );
+        Some(selected)
+    }
+
+    async fn weighted_random_select(&self, service_name: &str, endpoints: &[String]) -> Option<String> {
+        let weights = self.resolve_weights(service_name, endpoints).await;
+        let total: u64 = weights.iter().map(|&w| w as u64).sum();
+        if total == 0 {
+            return Self::only_endpoint(endpoints);
+        }
+
+        let mut point = self.rng.lock().unwrap().gen_range(0..total);
+        let index = weights
+            .iter()
+            .position(|&w| {
+                if point < w as u64 {
+                    true
+                } else {
+                    point -= w as u64;
+                    false
+                }
+            })?;
+
+        let selected = endpoints[index].clone();
+        debug!(strategy = self.strategy.name(), endpoint = %selected, weight = weights[index], "endpoint selected");
+        Some(selected)
+    }
+
+    // Weight 0 takes an endpoint out of rotation, except when it is the only
+    // one there is.
+    fn only_endpoint(endpoints: &[String]) -> Option<String> {
+        match endpoints {
+            [endpoint] => {
+                debug!(endpoint = %endpoint, "only endpoint selected despite weight 0");
+                Some(endpoint.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Weight lookup shared by every weight-aware strategy; endpoints without an
+    // explicit weight count as 1.
+    async fn resolve_weights(&self, service_name: &str, endpoints: &[String]) -> Vec<u32> {
+        self.resolve_sourced_weights(service_name, endpoints)
+            .await
+            .into_iter()
+            .map(|weight| weight.value)
+            .collect()
+    }
+
+    async fn resolve_sourced_weights(&self, service_name: &str, endpoints: &[String]) -> Vec<Weight> {
+        let weights = self.endpoint_weights.read().await;
+        let weights = weights.get(service_name);
+        let default = Weight {
+            value: DEFAULT_WEIGHT,
+            source: WeightSource::Static,
+        };
+        endpoints
+            .iter()
+            .map(|endpoint| weights.and_then(|weights| weights.get(endpoint)).copied().unwrap_or(default))
+            .collect()
+    }
+
+    // Takes effect from the next pick.
+    pub async fn set_endpoint_weight(&self, service_name: &str, endpoint: &str, weight: u32) {
+        self.store_weight(service_name, endpoint, weight, WeightSource::Static).await;
+    }
+
+    // As `set_endpoint_weight`, but reported as learned rather than set.
+    pub async fn set_auto_weight(&self, service_name: &str, endpoint: &str, weight: u32) {
+        self.store_weight(service_name, endpoint, weight, WeightSource::Auto).await;
+    }
+
+    async fn store_weight(&self, service_name: &str, endpoint: &str, value: u32, source: WeightSource) {
+        self.endpoint_weights
+            .write()
+            .await
+            .entry(service_name.to_string())
+            .or_default()
+            .insert(endpoint.to_string(), Weight { value, source });
+    }
+
+    pub async fn explain(&self, service_name: &str, endpoints: &[String]) -> SelectionExplanation {
+        let weights = self.resolve_sourced_weights(service_name, endpoints).await;
+        let connection_counts = self.connection_counts.read().await;
+
+        SelectionExplanation {
+            service: service_name.to_string(),
+            strategy: self.strategy.name(),
+            endpoints: endpoints
+                .iter()
+                .zip(weights)
+                .map(|(endpoint, weight)| {
+                    let load = connection_counts.get(endpoint);
+                    EndpointExplanation {
+                        endpoint: endpoint.clone(),
+                        weight: weight.value,
+                        weight_source: weight.source,
+                        active_requests: load.map_or(0, |l| l.active.load(Ordering::Relaxed)),
+                        active_requests_ewma: load.map_or(0.0, |l| l.active_ewma()),
+                    }
+                })
+                .collect(),
+        }
+    }
+
     pub async fn increment_connections(&self, endpoint: &str) {
         let mut connection_counts = self.connection_counts.write().await;
-        let counter = connection_counts.entry(endpoint.to_string())
-            .or_insert_with(|| AtomicUsize::new(0));
-        counter.fetch_add(1, Ordering::Relaxed);
+        connection_counts.entry(endpoint.to_string()).or_default().acquire();
     }
 
     pub async fn decrement_connections(&self, endpoint: &str) {
         let connection_counts = self.connection_counts.read().await;
-        if let Some(counter) = connection_counts.get(endpoint) {
-            counter.fetch_sub(1, Ordering::Relaxed);
+        if let Some(load) = connection_counts.get(endpoint) {
+            load.release();
         }
     }
 
+    // Counts a request to `endpoint` as in flight until the guard is dropped.
+    pub async fn start_request(&self, endpoint: &str) -> InFlight {
+        let mut connection_counts = self.connection_counts.write().await;
+        let load = connection_counts.entry(endpoint.to_string()).or_default().clone();
+        load.acquire();
+        InFlight { load }
+    }
+
+    // Counts entries removed across connection counts and weights. A request
+    // still in flight to a forgotten endpoint simply stops being counted.
+    pub async fn forget_endpoints(&self, endpoints: &[String]) -> usize {
+        let mut connection_counts = self.connection_counts.write().await;
+        let mut weights = self.endpoint_weights.write().await;
+        let mut current_weights = self.current_weights.lock().unwrap();
+        for current in current_weights.values_mut() {
+            current.retain(|endpoint, _| !endpoints.contains(endpoint));
+        }
+        endpoints
+            .iter()
+            .map(|endpoint| {
+                let weighted = weights.values_mut().filter_map(|weights| weights.remove(endpoint)).count();
+                usize::from(connection_counts.remove(endpoint).is_some()) + weighted
+            })
+            .sum()
+    }
+
     pub async fn get_connection_count(&self, endpoint: &str) -> usize {
         let connection_counts = self.connection_counts.read().await;
         connection_counts.get(endpoint)
-            .map(|c| c.load(Ordering::Relaxed))
+            .map(|c| c.active.load(Ordering::Relaxed))
             .unwrap_or(0)
     }
 }
+
+// One balancer per service, keyed by name, running the strategy that service
+// configured. Services that configure none share the default one.
+pub struct LoadBalancers {
+    default: Arc<LoadBalancer>,
+    services: std::sync::RwLock<HashMap<String, Arc<LoadBalancer>>>,
+}
+
+impl LoadBalancers {
+    pub fn new(default: LoadBalancer, services: &HashMap<String, UpstreamService>) -> Self {
+        let balancers = Self {
+            default: Arc::new(default),
+            services: std::sync::RwLock::new(HashMap::new()),
+        };
+        balancers.configure(services);
+        balancers
+    }
+
+    // Called again after a reload. A service whose strategy is unchanged
+    // keeps its balancer, with the counts and weights it has built up.
+    // Strategy names were checked by `Config::validate`.
+    pub fn configure(&self, services: &HashMap<String, UpstreamService>) {
+        let mut balancers = self.services.write().unwrap();
+        let previous = std::mem::take(&mut *balancers);
+        for (name, service) in services {
+            let Some(strategy) = service.load_balancing_strategy.as_deref().and_then(|name| name.parse().ok()) else {
+                continue;
+            };
+            let balancer = match previous.get(name) {
+                Some(balancer) if *balancer.strategy() == strategy => balancer.clone(),
+                _ => {
+                    let mut balancer = LoadBalancer::with_strategy(strategy);
+                    if let Some(weights) = &service.endpoint_weights {
+                        balancer = balancer.with_service_weights(name, weights);
+                    }
+                    Arc::new(balancer)
+                }
+            };
+            balancers.insert(name.clone(), balancer);
+        }
+    }
+
+    pub fn for_service(&self, service_name: &str) -> Arc<LoadBalancer> {
+        self.services
+            .read()
+            .unwrap()
+            .get(service_name)
+            .cloned()
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    pub async fn forget_endpoints(&self, endpoints: &[String]) -> usize {
+        let balancers: Vec<Arc<LoadBalancer>> = self.services.read().unwrap().values().cloned().collect();
+        let mut forgotten = self.default.forget_endpoints(endpoints).await;
+        for balancer in balancers {
+            forgotten += balancer.forget_endpoints(endpoints).await;
+        }
+        forgotten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn endpoints() -> Vec<String> {
+        vec!["http://a".to_string(), "http://b".to_string(), "http://c".to_string()]
+    }
+
+    async fn distribution(lb: &LoadBalancer, endpoints: &[String], rounds: usize) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..rounds {
+            let selected = lb.select_endpoint("svc", endpoints).await.unwrap();
+            *counts.entry(selected).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[tokio::test]
+    async fn test_weighted_random_follows_weights() {
+        let endpoints = endpoints();
+        let lb = LoadBalancer::with_strategy(LoadBalancingStrategy::WeightedRandom).with_seed(7);
+        lb.set_endpoint_weight("svc", "http://a", 6).await;
+        lb.set_endpoint_weight("svc", "http://b", 3).await;
+        lb.set_endpoint_weight("svc", "http://c", 1).await;
+
+        let counts = distribution(&lb, &endpoints, 10_000).await;
+
+        let share = |e: &str| counts[e] as f64 / 10_000.0;
+        assert!((share("http://a") - 0.6).abs() < 0.03);
+        assert!((share("http://b") - 0.3).abs() < 0.03);
+        assert!((share("http://c") - 0.1).abs() < 0.03);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_random_is_deterministic_for_a_seed() {
+        let endpoints = endpoints();
+        let first = LoadBalancer::with_strategy(LoadBalancingStrategy::WeightedRandom).with_seed(42);
+        let second = LoadBalancer::with_strategy(LoadBalancingStrategy::WeightedRandom).with_seed(42);
+
+        for _ in 0..100 {
+            assert_eq!(
+                first.select_endpoint("svc", &endpoints).await,
+                second.select_endpoint("svc", &endpoints).await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_least_request_avoids_busy_endpoint() {
+        let endpoints = endpoints();
+        let lb = LoadBalancer::with_strategy(LoadBalancingStrategy::LeastRequest { choice_count: 2 }).with_seed(7);
+        for _ in 0..10 {
+            lb.increment_connections("http://a").await;
+        }
+
+        let counts = distribution(&lb, &endpoints, 3_000).await;
+
+        let busy = counts.get("http://a").copied().unwrap_or(0);
+        // Only chosen when both samples land on it: (1/3)^2 of the time.
+        assert!(busy < 450, "busy endpoint picked {} times", busy);
+        assert!(counts["http://b"] > 1_000);
+        assert!(counts["http://c"] > 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_least_request_prefers_weight_when_idle() {
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+        let lb = LoadBalancer::with_strategy(LoadBalancingStrategy::LeastRequest { choice_count: 2 }).with_seed(3);
+        lb.set_endpoint_weight("svc", "http://b", 5).await;
+
+        let counts = distribution(&lb, &endpoints, 2_000).await;
+
+        // `a` only wins when both samples are `a`.
+        assert!(counts["http://b"] > counts.get("http://a").copied().unwrap_or(0) * 2);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_guard_outlives_a_forgotten_endpoint() {
+        let lb = LoadBalancer::new();
+        let first = lb.start_request("http://a").await;
+        assert_eq!(lb.get_connection_count("http://a").await, 1);
+
+        lb.forget_endpoints(&["http://a".to_string()]).await;
+        let second = lb.start_request("http://a").await;
+        drop(first);
+        assert_eq!(lb.get_connection_count("http://a").await, 1);
+        drop(second);
+        assert_eq!(lb.get_connection_count("http://a").await, 0);
+
+        // Releases past zero stay at zero.
+        lb.decrement_connections("http://a").await;
+        assert_eq!(lb.get_connection_count("http://a").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_explain_reports_strategy_and_load() {
+        let endpoints = endpoints();
+        let lb = LoadBalancer::with_strategy(LoadBalancingStrategy::LeastRequest { choice_count: 2 });
+        lb.set_endpoint_weight("svc", "http://b", 4).await;
+        lb.increment_connections("http://a").await;
+
+        let explanation = lb.explain("svc", &endpoints).await;
+
+        assert_eq!(explanation.strategy, "least_request");
+        assert_eq!(explanation.endpoints[0].active_requests, 1);
+        assert!(explanation.endpoints[0].active_requests_ewma > 0.0);
+        assert_eq!(explanation.endpoints[1].weight, 4);
+        assert_eq!(explanation.endpoints[2].weight, 1);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_round_robin_interleaves() {
+        let endpoints = endpoints();
+        let lb = LoadBalancer::with_strategy(LoadBalancingStrategy::WeightedRoundRobin)
+            .with_service_weights("svc", &HashMap::from([("http://a".to_string(), 3)]));
+
+        let mut picks = Vec::new();
+        for _ in 0..10 {
+            picks.push(lb.select_endpoint("svc", &endpoints).await.unwrap());
+        }
+
+        let cycle = ["http://a", "http://b", "http://a", "http://c", "http://a"];
+        assert_eq!(picks, cycle.iter().chain(cycle.iter()).map(|e| e.to_string()).collect::<Vec<_>>());
+        assert!(picks.windows(3).all(|run| run.iter().any(|e| e != "http://a")));
+        // Weights belong to one service.
+        assert_eq!(lb.explain("other", &endpoints).await.endpoints[0].weight, 1);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_round_robin_follows_runtime_weights() {
+        let endpoints = endpoints();
+        let lb = LoadBalancer::with_strategy(LoadBalancingStrategy::WeightedRoundRobin);
+        lb.set_endpoint_weight("svc", "http://b", 0).await;
+        lb.set_endpoint_weight("svc", "http://c", 4).await;
+
+        let counts = distribution(&lb, &endpoints, 500).await;
+        assert_eq!(counts["http://a"], 100);
+        assert_eq!(counts["http://c"], 400);
+        assert!(!counts.contains_key("http://b"));
+
+        for endpoint in ["http://a", "http://c"] {
+            lb.set_endpoint_weight("svc", endpoint, 0).await;
+        }
+        assert_eq!(lb.select_endpoint("svc", &endpoints).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_tables_split_traffic_by_weight() {
+        let mut value = serde_json::to_value(Config::new()).unwrap();
+        value["upstream_services"]["service-a"]["endpoints"] = serde_json::json!([
+            {"url": "http://a", "weight": 3},
+            "http://b",
+            {"url": "http://c"},
+        ]);
+        let services = crate::config_migration::upgrade(&value.to_string()).unwrap().config.upstream_services;
+        let endpoints = endpoints();
+
+        for strategy in ["weighted_round_robin", "weighted_random"] {
+            let mut services = services.clone();
+            services.get_mut("service-a").unwrap().load_balancing_strategy = Some(strategy.to_string());
+            let lb = LoadBalancers::new(LoadBalancer::new(), &services).for_service("service-a");
+            let mut counts = HashMap::new();
+            for _ in 0..5_000 {
+                *counts.entry(lb.select_endpoint("service-a", &endpoints).await.unwrap()).or_insert(0) += 1;
+            }
+
+            let share = |e: &str| counts[e] as f64 / 5_000.0;
+            assert!((share("http://a") - 0.6).abs() < 0.03, "{}: {:?}", strategy, counts);
+            assert!((share("http://b") - 0.2).abs() < 0.03, "{}: {:?}", strategy, counts);
+            assert!((share("http://c") - 0.2).abs() < 0.03, "{}: {:?}", strategy, counts);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_weight_zero_only_picked_when_alone() {
+        let endpoints = endpoints();
+        for strategy in [
+            LoadBalancingStrategy::WeightedRoundRobin,
+            LoadBalancingStrategy::WeightedRandom,
+            LoadBalancingStrategy::LeastRequest { choice_count: 2 },
+        ] {
+            let lb = LoadBalancer::with_strategy(strategy.clone()).with_seed(11);
+            lb.set_endpoint_weight("svc", "http://b", 0).await;
+
+            let counts = distribution(&lb, &endpoints, 3_000).await;
+            assert!(!counts.contains_key("http://b"), "{:?}: {:?}", strategy, counts);
+            let alone = ["http://b".to_string()];
+            assert_eq!(lb.select_endpoint("svc", &alone).await.as_deref(), Some("http://b"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_service_keeps_its_strategy() {
+        let mut services = Config::new().upstream_services;
+        services.get_mut("service-a").unwrap().load_balancing_strategy = Some("least_connections".to_string());
+        let balancers = LoadBalancers::new(LoadBalancer::new(), &services);
+
+        let least = balancers.for_service("service-a");
+        assert_eq!(least.strategy(), &LoadBalancingStrategy::LeastConnections);
+        assert_eq!(balancers.for_service("service-b").strategy(), &LoadBalancingStrategy::RoundRobin);
+        assert_eq!(balancers.for_service("unknown").strategy(), &LoadBalancingStrategy::RoundRobin);
+
+        // An unchanged strategy survives a reconfigure; a changed one does not.
+        balancers.configure(&services);
+        assert!(Arc::ptr_eq(&least, &balancers.for_service("service-a")));
+        services.get_mut("service-a").unwrap().load_balancing_strategy = Some("random".to_string());
+        balancers.configure(&services);
+        assert_eq!(balancers.for_service("service-a").strategy(), &LoadBalancingStrategy::Random);
+    }
+
+    #[test]
+    fn test_strategy_names_round_trip() {
+        for name in ["round_robin", "weighted_round_robin", "least_connections", "random", "least_request", "weighted_random"] {
+            assert_eq!(name.parse::<LoadBalancingStrategy>().unwrap().name(), name);
+        }
+        assert!("fastest".parse::<LoadBalancingStrategy>().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+// Stand-ins for the prometheus types `metrics` uses, for builds without the
+// `metrics-prometheus` feature. Values are kept and read back the same way,
+// so the admin API and the accessors tests use still work; nothing is
+// exported.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+pub type Result<T> = std::result::Result<T, Infallible>;
+
+pub struct Opts;
+
+impl Opts {
+    pub fn new(_name: &str, _help: &str) -> Self {
+        Opts
+    }
+}
+
+pub struct HistogramOpts;
+
+impl HistogramOpts {
+    pub fn new(_name: &str, _help: &str) -> Self {
+        HistogramOpts
+    }
+
+    pub fn buckets(self, _buckets: Vec<f64>) -> Self {
+        self
+    }
+}
+
+// Accepts everything; there is nothing to expose the metrics through.
+#[derive(Default)]
+pub struct Registry;
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry
+    }
+
+    pub fn register<T>(&self, _metric: T) -> Result<()> {
+        Ok(())
+    }
+}
+
+// An f64 kept as its bits, so it can be updated without a lock.
+#[derive(Clone, Default)]
+struct F64(Arc<AtomicU64>);
+
+impl F64 {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, delta: f64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + delta).to_bits())
+        });
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Counter(F64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.add(1.0);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.0.get()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct IntCounter(Arc<AtomicU64>);
+
+impl IntCounter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Gauge(F64);
+
+impl Gauge {
+    pub fn new(_name: &str, _help: &str) -> Result<Self> {
+        Ok(Self::default())
+    }
+
+    pub fn inc(&self) {
+        self.0.add(1.0);
+    }
+
+    pub fn dec(&self) {
+        self.0.add(-1.0);
+    }
+
+    pub fn set(&self, value: f64) {
+        self.0.set(value);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.0.get()
+    }
+}
+
+// Only the sample count is read back.
+#[derive(Clone, Default)]
+pub struct Histogram(Arc<AtomicU64>);
+
+impl Histogram {
+    pub fn observe(&self, _value: f64) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_sample_count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// One metric per combination of label values, made on first use.
+#[derive(Clone)]
+pub struct MetricVec<M> {
+    metrics: Arc<Mutex<HashMap<Vec<String>, M>>>,
+}
+
+pub type CounterVec = MetricVec<Counter>;
+pub type IntCounterVec = MetricVec<IntCounter>;
+pub type GaugeVec = MetricVec<Gauge>;
+pub type HistogramVec = MetricVec<Histogram>;
+
+impl<M: Clone + Default> MetricVec<M> {
+    pub fn new<O>(_opts: O, _labels: &[&str]) -> Result<Self> {
+        Ok(Self {
+            metrics: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn with_label_values(&self, values: &[&str]) -> M {
+        let key = values.iter().map(|value| value.to_string()).collect();
+        self.metrics.lock().unwrap().entry(key).or_default().clone()
+    }
+
+    pub fn remove_label_values(&self, values: &[&str]) -> Option<M> {
+        let key: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        self.metrics.lock().unwrap().remove(&key)
+    }
+
+    // Label combinations seen and not removed.
+    pub fn len(&self) -> usize {
+        self.metrics.lock().unwrap().len()
+    }
+}
@@ -1,49 +1,345 @@
 use ai_sidecar_proxy::{
-    config::Config,
+    address_family,
+    config::{invalid_config, Config, ConfigFormat},
+    config_migration,
     proxy::ProxyServer,
     ai::AIEngine,
     metrics::MetricsCollector,
+    bench::{self, BenchOptions},
+    simulation::{self, Scenario},
+    storage,
+    clock::SystemClock,
 };
-use clap::Parser;
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use anyhow::Context;
 use tracing::{info, error};
-use std::sync::Arc;
+use std::{net::Ipv4Addr, path::PathBuf, sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser)]
 #[command(name = "ai-sidecar-proxy")]
 #[command(about = "High-performance AI-driven sidecar proxy")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    // Serving needs no subcommand; `run` takes the same arguments.
+    #[command(flatten)]
+    run: RunArgs,
+
+    // Defaults to "info" when serving; other commands are silent unless asked.
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(ClapArgs)]
+struct RunArgs {
     #[arg(short, long, default_value = "8080")]
     port: u16,
-    
+
     #[arg(short, long, default_value = "0.0.0.0")]
     bind: String,
-    
-    #[arg(long, default_value = "info")]
-    log_level: String,
+
+    // With an IPv6 bind address such as "::", accept only IPv6 clients rather
+    // than IPv4 ones as well.
+    #[arg(long)]
+    ipv6_only: bool,
+
+    // TOML (".toml"), YAML (".yaml" or ".yml") or JSON config file; the
+    // built-in defaults are used without one.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    // Read settings from `{PREFIX}_...` environment variables, over the
+    // --config file when there is one and the built-in defaults otherwise.
+    #[arg(long)]
+    env_prefix: Option<String>,
+
+    // Port on 127.0.0.1 where the application sends its outbound calls. No
+    // egress listener without one.
+    #[arg(long)]
+    egress_port: Option<u16>,
+
+    // Start without serving traffic, for a blue/green upgrade: health checks,
+    // AI learning and reloads run, and POST /admin/promote starts serving.
+    #[arg(long)]
+    standby: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve, as with no subcommand
+    Run(RunArgs),
+    /// Load a config file and report every problem with it, without serving
+    Validate(ValidateArgs),
+    /// Print the built-in defaults as a starting config file
+    PrintDefaultConfig(PrintDefaultConfigArgs),
+    /// Drive the proxy against in-process mock upstreams and print a report
+    Bench(BenchArgs),
+    /// Upgrade a config file written for an older version to the current schema
+    MigrateConfig(MigrateConfigArgs),
+}
+
+#[derive(ClapArgs)]
+struct ValidateArgs {
+    #[arg(long)]
+    config: PathBuf,
+
+    /// The proxy port it would be served on, checked against the metrics port
+    #[arg(long, default_value = "8080")]
+    port: u16,
+}
+
+#[derive(ClapArgs)]
+struct PrintDefaultConfigArgs {
+    #[arg(long, value_enum, default_value = "toml")]
+    format: FormatArg,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl From<FormatArg> for ConfigFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Toml => ConfigFormat::Toml,
+            FormatArg::Yaml => ConfigFormat::Yaml,
+            FormatArg::Json => ConfigFormat::Json,
+        }
+    }
+}
+
+#[derive(ClapArgs)]
+struct MigrateConfigArgs {
+    input: PathBuf,
+
+    /// Where to write the upgraded config; stdout if omitted
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(ClapArgs)]
+struct BenchArgs {
+    #[arg(long, default_value_t = 1000)]
+    rps: u32,
+
+    #[arg(long, default_value = "10s", value_parser = bench::parse_duration)]
+    duration: Duration,
+
+    #[arg(long, default_value_t = 3)]
+    upstreams: usize,
+
+    #[arg(long, default_value = "50ms", value_parser = bench::parse_duration)]
+    upstream_p99: Duration,
+
+    #[arg(long, default_value_t = 0.0)]
+    error_rate: f64,
+
+    #[arg(long, default_value_t = 0.0)]
+    post_ratio: f64,
+
+    #[arg(long, default_value_t = 1024)]
+    body_bytes: usize,
+
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+
+    /// Replay a simulation scenario file instead of load-testing a live proxy
+    #[arg(long)]
+    scenario: Option<PathBuf>,
+}
+
+impl From<BenchArgs> for BenchOptions {
+    fn from(args: BenchArgs) -> Self {
+        Self {
+            rps: args.rps,
+            duration: args.duration,
+            upstreams: args.upstreams,
+            upstream_p99: args.upstream_p99,
+            error_rate: args.error_rate,
+            post_ratio: args.post_ratio,
+            body_bytes: args.body_bytes,
+            seed: args.seed,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    
-    tracing_subscriber::fmt()
-        .with_env_filter(&args.log_level)
-        .init();
 
+    let default_level = match args.command {
+        None | Some(Command::Run(_)) => "info",
+        Some(_) => "off",
+    };
+    let log_level = args.log_level.as_deref().unwrap_or(default_level);
+
+    match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt()
+            .with_env_filter(log_level)
+            .init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_env_filter(log_level)
+            .init(),
+    }
+
+    match args.command.unwrap_or(Command::Run(args.run)) {
+        Command::Run(run_args) => run(run_args).await,
+        Command::Validate(validate_args) => validate(validate_args),
+        Command::PrintDefaultConfig(print_args) => {
+            print!("{}", ConfigFormat::from(print_args.format).write(&Config::new()));
+            Ok(())
+        }
+        Command::Bench(BenchArgs { scenario: Some(path), .. }) => simulate(&path).await,
+        Command::Bench(bench_args) => {
+            let report = bench::run(bench_args.into()).await?;
+            print!("{}", report);
+            Ok(())
+        }
+        Command::MigrateConfig(migrate_args) => migrate_config(migrate_args),
+    }
+}
+
+async fn run(args: RunArgs) -> anyhow::Result<()> {
     info!("Starting AI Sidecar Proxy v{}", env!("CARGO_PKG_VERSION"));
-    
-    let config = Config::new();
-    let ai_engine = Arc::new(AIEngine::new());
+
+    let config = match (&args.config, &args.env_prefix) {
+        (Some(path), Some(prefix)) => Config::merge(Config::from_file(path)?, Config::env_overrides(prefix)?),
+        (Some(path), None) => Config::from_file(path)?,
+        (None, Some(prefix)) => Config::from_env(prefix)?,
+        (None, None) => Config::new(),
+    };
+    config.validate_for_port(args.port).map_err(|errors| invalid_config(&errors))?;
+    // Opened once and shared, after every namespace has been read through.
+    let storage = match &config.storage {
+        Some(storage_config) => {
+            let store = storage::open(storage_config, Arc::new(SystemClock))?;
+            storage::check(store.as_ref(), &storage::NAMESPACES).await;
+            Some(store)
+        }
+        None => None,
+    };
+    let ai_engine = Arc::new(AIEngine::with_storage(&config.ai_config, storage.clone()).await);
     let metrics = Arc::new(MetricsCollector::new());
-    
-    let proxy = ProxyServer::new(config, ai_engine, metrics);
-    
-    info!("Proxy server listening on {}:{}", args.bind, args.port);
-    
-    if let Err(e) = proxy.run(&args.bind, args.port).await {
+
+    let shutdown = CancellationToken::new();
+    let mut builder = ProxyServer::builder().config(config);
+    if let Some(path) = &args.config {
+        builder = builder.config_path(path);
+    }
+    if let Some(prefix) = &args.env_prefix {
+        builder = builder.env_prefix(prefix);
+    }
+    if let Some(storage) = storage {
+        builder = builder.storage(storage);
+    }
+    let proxy = builder
+        .ai_engine(ai_engine)
+        .metrics(metrics)
+        .shutdown_token(shutdown.clone())
+        .standby(args.standby)
+        .build()?;
+
+    let bind_addr = tokio::net::lookup_host((args.bind.as_str(), args.port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("bind address {} did not resolve", args.bind))?;
+    let listener = address_family::bind_listener(bind_addr, args.ipv6_only)?;
+    info!(
+        addr = %bind_addr,
+        dual_stack = bind_addr.is_ipv6() && !args.ipv6_only,
+        "Proxy server listening"
+    );
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("received interrupt, shutting down");
+            shutdown.cancel();
+        }
+    });
+
+    let handle = match args.egress_port {
+        Some(port) => {
+            let egress = address_family::bind_listener((Ipv4Addr::LOCALHOST, port).into(), false)?;
+            proxy.run_with_egress(listener, egress)?
+        }
+        None => proxy.run(listener)?,
+    };
+
+    if let Err(e) = handle.await_terminated().await {
         error!("Proxy server error: {}", e);
         return Err(e);
     }
-    
+
+    Ok(())
+}
+
+// Loads and checks the config the way serving would, but binds nothing and
+// starts no tasks.
+fn validate(args: ValidateArgs) -> anyhow::Result<()> {
+    let config = Config::from_file(&args.config)?;
+    config.validate_for_port(args.port).map_err(|errors| invalid_config(&errors))?;
+    for warning in config.validation().warnings {
+        eprintln!("warning: {}", warning);
+    }
+    println!("{}: ok", args.config.display());
+    Ok(())
+}
+
+async fn simulate(path: &std::path::Path) -> anyhow::Result<()> {
+    let scenario = Scenario::load(path)?;
+    let report = simulation::run(&scenario).await?;
+    print!("{}", report);
+    let failures = scenario.check(&report);
+    for failure in &failures {
+        eprintln!("expectation failed: {}", failure);
+    }
+    anyhow::ensure!(failures.is_empty(), "{} expectation(s) failed", failures.len());
+    Ok(())
+}
+
+fn migrate_config(args: MigrateConfigArgs) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(&args.input)
+        .with_context(|| format!("reading {}", args.input.display()))?;
+    let format = ConfigFormat::from_path(&args.input);
+    let upgraded = format
+        .upgrade(&text)
+        .with_context(|| format!("migrating {}", args.input.display()))?;
+
+    if upgraded.from_version == config_migration::CURRENT_CONFIG_VERSION {
+        eprintln!("config is already at version {}", upgraded.from_version);
+    } else {
+        eprintln!(
+            "migrated config from version {} to {}:",
+            upgraded.from_version,
+            config_migration::CURRENT_CONFIG_VERSION
+        );
+        for change in &upgraded.changes {
+            eprintln!("  {}", change);
+        }
+    }
+
+    // Written back in the format it was read in.
+    let text = format.write(&upgraded.config);
+    match &args.output {
+        Some(path) => std::fs::write(path, text).with_context(|| format!("writing {}", path.display()))?,
+        None => print!("{}", text),
+    }
     Ok(())
 }
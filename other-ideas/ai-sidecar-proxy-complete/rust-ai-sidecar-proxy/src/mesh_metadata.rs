@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeshMetadataConfig {
+    pub inject: bool,
+    // Drop client-supplied copies of these headers before anything else sees
+    // them; disable only when the previous hop is itself a trusted sidecar.
+    pub strip_incoming: bool,
+    pub instance_id: Option<String>,
+    pub service_name: Option<String>,
+    pub zone: Option<String>,
+    pub headers: MeshHeaderNames,
+}
+
+impl Default for MeshMetadataConfig {
+    fn default() -> Self {
+        Self {
+            inject: true,
+            strip_incoming: true,
+            instance_id: None,
+            service_name: None,
+            zone: None,
+            headers: MeshHeaderNames::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeshHeaderNames {
+    pub instance: String,
+    pub version: String,
+    pub route: String,
+    pub source_service: String,
+    pub zone: String,
+}
+
+impl Default for MeshHeaderNames {
+    fn default() -> Self {
+        Self {
+            instance: "x-mesh-proxy-instance".to_string(),
+            version: "x-mesh-proxy-version".to_string(),
+            route: "x-mesh-route".to_string(),
+            source_service: "x-mesh-source-service".to_string(),
+            zone: "x-mesh-zone".to_string(),
+        }
+    }
+}
+
+// Resolved identity and validated header names, built once at startup.
+#[derive(Debug, Clone)]
+pub struct MeshMetadata {
+    inject: bool,
+    strip_incoming: bool,
+    route_header: HeaderName,
+    // Headers whose value is the same for every request.
+    fixed: Vec<(HeaderName, HeaderValue)>,
+    all_names: Vec<HeaderName>,
+}
+
+impl MeshMetadata {
+    pub fn from_config(config: &MeshMetadataConfig) -> Result<Self> {
+        Self::resolve(config, |name| std::env::var(name).ok())
+    }
+
+    fn resolve(config: &MeshMetadataConfig, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let name = |name: &str| {
+            HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid mesh metadata header name {:?}", name))
+        };
+        let names = &config.headers;
+        let instance = name(&names.instance)?;
+        let version = name(&names.version)?;
+        let route_header = name(&names.route)?;
+        let source_service = name(&names.source_service)?;
+        let zone = name(&names.zone)?;
+
+        let identity = [
+            (instance.clone(), config.instance_id.clone().or_else(|| env("HOSTNAME"))),
+            (version.clone(), Some(env!("CARGO_PKG_VERSION").to_string())),
+            (source_service.clone(), config.service_name.clone().or_else(|| env("SERVICE_NAME"))),
+            (zone.clone(), config.zone.clone().or_else(|| env("ZONE"))),
+        ];
+
+        let mut fixed = Vec::new();
+        for (header, value) in identity {
+            let Some(value) = value.filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            let value = HeaderValue::from_str(&value)
+                .with_context(|| format!("invalid value {:?} for {}", value, header))?;
+            fixed.push((header, value));
+        }
+
+        Ok(Self {
+            inject: config.inject,
+            strip_incoming: config.strip_incoming,
+            all_names: vec![instance, version, route_header.clone(), source_service, zone],
+            route_header,
+            fixed,
+        })
+    }
+
+    pub fn strip_incoming(&self, headers: &mut HeaderMap) {
+        if self.strip_incoming {
+            for name in &self.all_names {
+                headers.remove(name);
+            }
+        }
+    }
+
+    // Outbound headers replace any incoming header of the same name.
+    pub fn replaces(&self, name: &HeaderName) -> bool {
+        self.inject && self.all_names.contains(name)
+    }
+
+    // `route` comes already sanitized by the caller.
+    pub fn outbound(&self, route: HeaderValue) -> Vec<(HeaderName, HeaderValue)> {
+        if !self.inject {
+            return Vec::new();
+        }
+        let mut headers = self.fixed.clone();
+        headers.push((self.route_header.clone(), route));
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_defaults_from_env() {
+        let config = MeshMetadataConfig {
+            zone: Some("eu-west-1a".to_string()),
+            ..MeshMetadataConfig::default()
+        };
+        let metadata = MeshMetadata::resolve(&config, |name| match name {
+            "HOSTNAME" => Some("pod-7".to_string()),
+            "ZONE" => Some("ignored".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        let outbound: HeaderMap = metadata.outbound(HeaderValue::from_static("/api/a")).into_iter().collect();
+        assert_eq!(outbound["x-mesh-proxy-instance"], "pod-7");
+        assert_eq!(outbound["x-mesh-proxy-version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(outbound["x-mesh-route"], "/api/a");
+        assert_eq!(outbound["x-mesh-zone"], "eu-west-1a");
+        assert!(!outbound.contains_key("x-mesh-source-service"));
+    }
+
+    #[test]
+    fn test_custom_names_are_validated() {
+        let mut config = MeshMetadataConfig::default();
+        config.headers.route = "l5d-dst route".to_string();
+        assert!(MeshMetadata::resolve(&config, |_| None).is_err());
+
+        config.headers.route = "l5d-route".to_string();
+        let metadata = MeshMetadata::resolve(&config, |_| None).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("l5d-route", "spoofed".parse().unwrap());
+        headers.insert("x-mesh-route", "kept".parse().unwrap());
+        metadata.strip_incoming(&mut headers);
+        assert!(!headers.contains_key("l5d-route"));
+        assert_eq!(headers["x-mesh-route"], "kept");
+    }
+}
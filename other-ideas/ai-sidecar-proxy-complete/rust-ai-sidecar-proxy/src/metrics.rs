@@ -1,63 +1,704 @@
-use prometheus::{Counter, Histogram, Gauge, Registry, Encoder, TextEncoder};
+#[cfg(feature = "metrics-prometheus")]
+use prometheus::{CounterVec, HistogramOpts, HistogramVec, Gauge, GaugeVec, IntCounterVec, Opts, Registry, Encoder, TextEncoder};
+#[cfg(feature = "metrics-prometheus")]
+use prometheus::Result as RegisterResult;
+#[cfg(not(feature = "metrics-prometheus"))]
+use crate::local_metrics::{CounterVec, HistogramOpts, HistogramVec, Gauge, GaugeVec, IntCounterVec, Opts, Registry};
+#[cfg(not(feature = "metrics-prometheus"))]
+use crate::local_metrics::Result as RegisterResult;
+use crate::egress::Direction;
+use crate::stats_history::StatsHistory;
+use crate::upstream_timing::UpstreamPhases;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::debug;
 
 pub struct MetricsCollector {
+    #[cfg(feature = "metrics-prometheus")]
     registry: Registry,
-    request_counter: Counter,
-    request_duration: Histogram,
+    request_counter: CounterVec,
+    request_duration: HistogramVec,
     active_connections: Gauge,
+    protocol_errors: IntCounterVec,
+    connections_shed: IntCounterVec,
+    accept_errors: IntCounterVec,
+    open_fds: Gauge,
+    fd_pressure: Gauge,
+    draining: Gauge,
+    standby: Gauge,
+    ai_enabled: Gauge,
+    buffered_bytes: Gauge,
+    buffered_bytes_high_water: Gauge,
+    connection_tasks: Gauge,
+    connection_task_panics: IntCounterVec,
+    strict_http_rejections: IntCounterVec,
+    task_restarts: IntCounterVec,
+    task_panics: IntCounterVec,
+    tls_handshakes: IntCounterVec,
+    tls_handshake_duration: HistogramVec,
+    tls_handshake_failures: IntCounterVec,
+    tls_cert_reloads: IntCounterVec,
+    access_rule_matches: IntCounterVec,
+    upstream_phase_duration: HistogramVec,
+    upstream_connections: IntCounterVec,
+    upstream_address_family: IntCounterVec,
+    body_checksums: IntCounterVec,
+    egress_rejections: IntCounterVec,
+    config_rollbacks: IntCounterVec,
+    retries: IntCounterVec,
+    selections: IntCounterVec,
+    upstream_anomalies: IntCounterVec,
+    archive_records: IntCounterVec,
+    bodiless_violations: IntCounterVec,
+    experiment_requests: IntCounterVec,
+    endpoint_gc_collected: IntCounterVec,
+    upstream_prewarm: IntCounterVec,
+    client_timeouts: IntCounterVec,
+    request_bodies_rejected: IntCounterVec,
+    request_dedupe: IntCounterVec,
+    informational_responses: IntCounterVec,
+    breaker_probes: IntCounterVec,
+    response_headers_stripped: IntCounterVec,
+    header_values_sanitized: IntCounterVec,
+    request_targets: IntCounterVec,
+    pool_clients: GaugeVec,
+    operation_duration: HistogramVec,
+    scheduler_lag: Gauge,
+    overloaded: Gauge,
+    overload_shed: IntCounterVec,
+    processing_duration: HistogramVec,
+    websocket_handshakes: IntCounterVec,
+    websocket_sessions: GaugeVec,
+    websocket_bytes: IntCounterVec,
+    websocket_session_duration: HistogramVec,
+    history: StatsHistory,
     endpoint_metrics: Arc<RwLock<HashMap<String, EndpointMetrics>>>,
 }
 
 #[derive(Debug, Clone)]
-struct EndpointMetrics {
-    total_requests: u64,
-    successful_requests: u64,
-    failed_requests: u64,
-    avg_latency_ms: f64,
-    last_request_time: u64,
+pub struct EndpointMetrics {
+    pub total_requests: u64,
+    pub successful_requests: u64,
+    pub failed_requests: u64,
+    pub avg_latency_ms: f64,
+    pub last_request_time: u64,
+    // The average hides the tail; percentiles come from here.
+    pub latency: LatencyHistogram,
+}
+
+const LATENCY_SAMPLES: usize = 1000;
+
+// The last `LATENCY_SAMPLES` latencies, oldest overwritten first.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    samples: Vec<u64>,
+    next: usize,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency_ms: u64) {
+        if self.samples.len() < LATENCY_SAMPLES {
+            self.samples.push(latency_ms);
+        } else {
+            self.samples[self.next] = latency_ms;
+        }
+        self.next = (self.next + 1) % LATENCY_SAMPLES;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn p50(&self) -> f64 {
+        self.percentile(0.50)
+    }
+
+    pub fn p95(&self) -> f64 {
+        self.percentile(0.95)
+    }
+
+    pub fn p99(&self) -> f64 {
+        self.percentile(0.99)
+    }
+
+    // Nearest rank; 0 with no samples. Only the wanted rank is put in
+    // place, not the whole buffer sorted.
+    fn percentile(&self, quantile: f64) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let rank = ((quantile * self.samples.len() as f64).ceil() as usize).clamp(1, self.samples.len());
+        let mut samples = self.samples.clone();
+        let (_, value, _) = samples.select_nth_unstable(rank - 1);
+        *value as f64
+    }
+}
+
+impl Default for MetricsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MetricsCollector {
     pub fn new() -> Self {
-        let registry = Registry::new();
-        
-        let request_counter = Counter::new(
-            "proxy_requests_total",
-            "Total number of requests processed by the proxy"
+        Self::register_into(Registry::new()).expect("metrics register into a fresh registry")
+    }
+
+    // Registers into a registry the embedding application already exposes.
+    // Fails if any of the proxy's metric names are already taken there.
+    #[cfg(feature = "metrics-prometheus")]
+    pub fn with_registry(registry: Registry) -> prometheus::Result<Self> {
+        Self::register_into(registry)
+    }
+
+    fn register_into(registry: Registry) -> RegisterResult<Self> {
+
+        let request_counter = CounterVec::new(
+            Opts::new(
+                "proxy_requests_total",
+                "Total number of requests processed by the proxy"
+            ),
+            &["direction"]
         ).unwrap();
         
-        let request_duration = Histogram::with_opts(
-            prometheus::HistogramOpts::new(
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
                 "proxy_request_duration_seconds",
                 "Request duration in seconds"
-            ).buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])
+            ).buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["direction"]
         ).unwrap();
+
+        // Both directions are exported from the start, even before traffic.
+        for direction in Direction::ALL {
+            request_counter.with_label_values(&[direction.label()]);
+            request_duration.with_label_values(&[direction.label()]);
+        }
         
         let active_connections = Gauge::new(
             "proxy_active_connections",
             "Number of active connections"
         ).unwrap();
 
-        registry.register(Box::new(request_counter.clone())).unwrap();
-        registry.register(Box::new(request_duration.clone())).unwrap();
-        registry.register(Box::new(active_connections.clone())).unwrap();
+        let protocol_errors = IntCounterVec::new(
+            Opts::new(
+                "proxy_protocol_errors_total",
+                "Connections closed before reaching HTTP because the preface was not HTTP"
+            ),
+            &["listener", "reason"]
+        ).unwrap();
+
+        let connections_shed = IntCounterVec::new(
+            Opts::new(
+                "proxy_connections_shed_total",
+                "Connections closed right after accept to protect the process"
+            ),
+            &["reason"]
+        ).unwrap();
+
+        let accept_errors = IntCounterVec::new(
+            Opts::new(
+                "proxy_accept_errors_total",
+                "Failed accepts; the listener keeps accepting after each"
+            ),
+            &["listener", "kind"]
+        ).unwrap();
+
+        let open_fds = Gauge::new(
+            "proxy_open_fds",
+            "Number of open file descriptors"
+        ).unwrap();
+
+        let fd_pressure = Gauge::new(
+            "proxy_fd_pressure",
+            "1 while open file descriptors are above the high-water mark"
+        ).unwrap();
+
+        let draining = Gauge::new(
+            "proxy_draining",
+            "1 from the start of shutdown until the process exits"
+        ).unwrap();
+
+        let standby = Gauge::new(
+            "proxy_standby",
+            "1 while the proxy is a standby that does not serve traffic"
+        ).unwrap();
+
+        let ai_enabled = Gauge::new(
+            "proxy_ai_enabled",
+            "1 while the AI engine picks endpoints, 0 while it is bypassed"
+        ).unwrap();
+
+        let buffered_bytes = Gauge::new(
+            "proxy_buffered_bytes",
+            "Bytes of request and response bodies currently buffered in memory"
+        ).unwrap();
+
+        let buffered_bytes_high_water = Gauge::new(
+            "proxy_buffered_bytes_high_water",
+            "Highest number of bytes buffered in memory at once"
+        ).unwrap();
+
+        let connection_tasks = Gauge::new(
+            "proxy_connection_tasks",
+            "Per-connection tasks currently running"
+        ).unwrap();
+
+        let connection_task_panics = IntCounterVec::new(
+            Opts::new(
+                "proxy_connection_task_panics_total",
+                "Per-connection tasks that ended in a panic"
+            ),
+            &["direction"]
+        ).unwrap();
+
+        let task_restarts = IntCounterVec::new(
+            Opts::new(
+                "proxy_background_task_restarts_total",
+                "Times a supervised background task was restarted"
+            ),
+            &["task"]
+        ).unwrap();
+
+        let task_panics = IntCounterVec::new(
+            Opts::new(
+                "proxy_background_task_panics_total",
+                "Panics caught in supervised background tasks"
+            ),
+            &["task"]
+        ).unwrap();
+
+        let tls_handshakes = IntCounterVec::new(
+            Opts::new(
+                "proxy_tls_handshakes_total",
+                "Completed TLS handshakes on the listener"
+            ),
+            &["kind", "protocol", "cipher"]
+        ).unwrap();
+
+        let tls_handshake_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_tls_handshake_duration_seconds",
+                "Time from accept to a completed TLS handshake"
+            ).buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            &["kind"]
+        ).unwrap();
+
+        let tls_handshake_failures = IntCounterVec::new(
+            Opts::new(
+                "proxy_tls_handshake_failures_total",
+                "TLS handshakes that did not complete"
+            ),
+            &["reason"]
+        ).unwrap();
+
+        let tls_cert_reloads = IntCounterVec::new(
+            Opts::new(
+                "proxy_tls_cert_reloads_total",
+                "Attempts to install a rotated listener certificate"
+            ),
+            &["result"]
+        ).unwrap();
+
+        let access_rule_matches = IntCounterVec::new(
+            Opts::new(
+                "proxy_access_rule_matches_total",
+                "Requests matched by an access rule, or by the default action"
+            ),
+            &["rule", "action"]
+        ).unwrap();
+
+        registry.register(Box::new(request_counter.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(active_connections.clone()))?;
+        registry.register(Box::new(protocol_errors.clone()))?;
+        registry.register(Box::new(connections_shed.clone()))?;
+        registry.register(Box::new(accept_errors.clone()))?;
+        registry.register(Box::new(open_fds.clone()))?;
+        registry.register(Box::new(fd_pressure.clone()))?;
+        registry.register(Box::new(draining.clone()))?;
+        registry.register(Box::new(standby.clone()))?;
+        registry.register(Box::new(ai_enabled.clone()))?;
+        registry.register(Box::new(buffered_bytes.clone()))?;
+        registry.register(Box::new(buffered_bytes_high_water.clone()))?;
+        registry.register(Box::new(task_restarts.clone()))?;
+        registry.register(Box::new(task_panics.clone()))?;
+        registry.register(Box::new(tls_handshakes.clone()))?;
+        let upstream_phase_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_upstream_phase_duration_seconds",
+                "Time spent in each phase of an upstream call; connection phases only for new connections"
+            ).buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            &["direction", "service", "phase"]
+        ).unwrap();
+
+        let upstream_connections = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_connections_total",
+                "Upstream calls by whether they opened a new connection or reused one"
+            ),
+            &["direction", "service", "connection"]
+        ).unwrap();
+
+        let upstream_address_family = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_address_family_total",
+                "Upstream responses by the address family of the connection that served them"
+            ),
+            &["direction", "service", "family"]
+        ).unwrap();
+
+        let egress_rejections = IntCounterVec::new(
+            Opts::new(
+                "proxy_egress_rejections_total",
+                "Outbound requests refused before reaching an upstream, by reason"
+            ),
+            &["reason"]
+        ).unwrap();
+
+        let config_rollbacks = IntCounterVec::new(
+            Opts::new(
+                "proxy_config_rollbacks_total",
+                "Reloaded configs rolled back, automatically by the guardrail or manually"
+            ),
+            &["trigger"]
+        ).unwrap();
+
+        let retries = IntCounterVec::new(
+            Opts::new(
+                "proxy_retry_total",
+                "Upstream calls retried after a network error or 5xx, by the endpoint that failed and the retry number"
+            ),
+            &["endpoint", "attempt"]
+        ).unwrap();
+
+        let selections = IntCounterVec::new(
+            Opts::new(
+                "proxy_endpoint_selections_total",
+                "Endpoint picks, by what made them: ai, balancer when the engine was not confident enough, or bypassed"
+            ),
+            &["mode"]
+        ).unwrap();
+
+        let upstream_anomalies = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_response_anomalies_total",
+                "Upstream responses over a header limit or with a nonstandard status, by anomaly and what was done: rejected, truncated, mapped or passed"
+            ),
+            &["anomaly", "action"]
+        ).unwrap();
+
+        let body_checksums = IntCounterVec::new(
+            Opts::new(
+                "proxy_body_checksums_total",
+                "Body checksum outcomes by route, direction and result"
+            ),
+            &["route", "direction", "result"]
+        ).unwrap();
+
+        let archive_records = IntCounterVec::new(
+            Opts::new(
+                "proxy_archive_records_total",
+                "Archived request/response records by outcome (queued, dropped, written, write_error)"
+            ),
+            &["result"]
+        ).unwrap();
+
+        let bodiless_violations = IntCounterVec::new(
+            Opts::new(
+                "proxy_bodiless_violations_total",
+                "Bodies dropped from responses that cannot carry one, by source (upstream, local) and response (head, 1xx, 204, 304)"
+            ),
+            &["source", "response"]
+        ).unwrap();
 
-        Self {
+        let experiment_requests = IntCounterVec::new(
+            Opts::new(
+                "proxy_experiment_requests_total",
+                "Requests assigned to an experiment variant, by response status class"
+            ),
+            &["experiment", "variant", "status"]
+        ).unwrap();
+
+        let client_timeouts = IntCounterVec::new(
+            Opts::new(
+                "proxy_client_timeouts_total",
+                "Client connections given up on, by what the client was too slow at"
+            ),
+            &["class"]
+        ).unwrap();
+
+        let request_bodies_rejected = IntCounterVec::new(
+            Opts::new(
+                "proxy_request_bodies_rejected_total",
+                "Proxied requests answered 413 for a body over the limit, by whether the declared length or the bytes read crossed it"
+            ),
+            &["service", "stage"]
+        ).unwrap();
+
+        let informational_responses = IntCounterVec::new(
+            Opts::new(
+                "proxy_informational_responses_total",
+                "1xx interim responses upstreams sent ahead of their final response, by service and status; only 103s reach clients, and not HTTP/1.0 ones"
+            ),
+            &["service", "status"]
+        ).unwrap();
+
+        let request_dedupe = IntCounterVec::new(
+            Opts::new(
+                "proxy_request_dedupe_total",
+                "Deduplicated reads by whether they made the upstream call, shared another request's answer, or made their own call after the one they waited on gave up"
+            ),
+            &["route", "outcome"]
+        ).unwrap();
+
+        let endpoint_gc_collected = IntCounterVec::new(
+            Opts::new(
+                "proxy_endpoint_gc_collected_total",
+                "State entries removed for endpoints gone from the config, by owning module"
+            ),
+            &["module"]
+        ).unwrap();
+
+        let upstream_prewarm = IntCounterVec::new(
+            Opts::new(
+                "proxy_upstream_prewarm_total",
+                "Pre-warm calls by whether they opened a connection, found one pooled, or failed"
+            ),
+            &["service", "result"]
+        ).unwrap();
+
+        let breaker_probes = IntCounterVec::new(
+            Opts::new(
+                "proxy_breaker_probes_total",
+                "Half-open circuit breaker probes by probe mode and outcome; rejected counts live requests not picked as probes"
+            ),
+            &["service", "mode", "outcome"]
+        ).unwrap();
+
+        let response_headers_stripped = IntCounterVec::new(
+            Opts::new(
+                "proxy_response_headers_stripped_total",
+                "Response headers removed by a listener's header policy, by header name"
+            ),
+            &["direction", "header"]
+        ).unwrap();
+
+        let header_values_sanitized = IntCounterVec::new(
+            Opts::new(
+                "proxy_header_values_sanitized_total",
+                "Header values the proxy wrote after removing control characters or truncating them"
+            ),
+            &["source", "reason"]
+        ).unwrap();
+
+        let request_targets = IntCounterVec::new(
+            Opts::new(
+                "proxy_request_targets_total",
+                "Inbound request targets by form and what the proxy did with them"
+            ),
+            &["form", "outcome"]
+        ).unwrap();
+
+        let strict_http_rejections = IntCounterVec::new(
+            Opts::new(
+                "proxy_strict_http_rejections_total",
+                "Requests refused by strict HTTP conformance checks, by reason"
+            ),
+            &["reason"]
+        ).unwrap();
+
+        registry.register(Box::new(tls_handshake_duration.clone()))?;
+        registry.register(Box::new(tls_handshake_failures.clone()))?;
+        registry.register(Box::new(tls_cert_reloads.clone()))?;
+        registry.register(Box::new(access_rule_matches.clone()))?;
+        registry.register(Box::new(upstream_phase_duration.clone()))?;
+        registry.register(Box::new(upstream_connections.clone()))?;
+        registry.register(Box::new(upstream_address_family.clone()))?;
+        registry.register(Box::new(body_checksums.clone()))?;
+        registry.register(Box::new(egress_rejections.clone()))?;
+        registry.register(Box::new(config_rollbacks.clone()))?;
+        registry.register(Box::new(retries.clone()))?;
+        registry.register(Box::new(selections.clone()))?;
+        registry.register(Box::new(upstream_anomalies.clone()))?;
+        registry.register(Box::new(archive_records.clone()))?;
+        registry.register(Box::new(bodiless_violations.clone()))?;
+        registry.register(Box::new(experiment_requests.clone()))?;
+        registry.register(Box::new(endpoint_gc_collected.clone()))?;
+        registry.register(Box::new(upstream_prewarm.clone()))?;
+        registry.register(Box::new(client_timeouts.clone()))?;
+        registry.register(Box::new(request_bodies_rejected.clone()))?;
+        registry.register(Box::new(request_dedupe.clone()))?;
+        registry.register(Box::new(informational_responses.clone()))?;
+        registry.register(Box::new(breaker_probes.clone()))?;
+        registry.register(Box::new(response_headers_stripped.clone()))?;
+        registry.register(Box::new(header_values_sanitized.clone()))?;
+        registry.register(Box::new(request_targets.clone()))?;
+        registry.register(Box::new(connection_tasks.clone()))?;
+        registry.register(Box::new(connection_task_panics.clone()))?;
+        registry.register(Box::new(strict_http_rejections.clone()))?;
+
+        let pool_clients = GaugeVec::new(
+            Opts::new(
+                "proxy_pool_clients_active",
+                "1 for each upstream service with a pooled HTTP client"
+            ),
+            &["direction", "service"]
+        ).unwrap();
+        registry.register(Box::new(pool_clients.clone()))?;
+
+        let operation_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_operation_duration_seconds",
+                "Request duration by route and templated operation, for routes with a path template"
+            ).buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["route", "operation"]
+        ).unwrap();
+        registry.register(Box::new(operation_duration.clone()))?;
+
+        let scheduler_lag = Gauge::new(
+            "proxy_scheduler_lag_seconds",
+            "How late the overload monitor's last timer tick fired"
+        ).unwrap();
+
+        let overloaded = Gauge::new(
+            "proxy_overloaded",
+            "1 while scheduler lag has the proxy in overload mode"
+        ).unwrap();
+
+        let overload_shed = IntCounterVec::new(
+            Opts::new(
+                "proxy_overload_shed_total",
+                "Low-priority requests answered 503 while overloaded, by route"
+            ),
+            &["route"]
+        ).unwrap();
+
+        let processing_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_processing_duration_seconds",
+                "Time a proxied request spent in the proxy itself, not waiting on the upstream"
+            ).buckets(vec![0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            &["direction"]
+        ).unwrap();
+
+        registry.register(Box::new(scheduler_lag.clone()))?;
+        registry.register(Box::new(overloaded.clone()))?;
+        registry.register(Box::new(overload_shed.clone()))?;
+        registry.register(Box::new(processing_duration.clone()))?;
+
+        let websocket_handshakes = IntCounterVec::new(
+            Opts::new(
+                "proxy_websocket_handshakes_total",
+                "WebSocket upgrades by service and outcome: upgraded, refused or failed"
+            ),
+            &["service", "outcome"]
+        ).unwrap();
+
+        let websocket_sessions = GaugeVec::new(
+            Opts::new(
+                "proxy_websocket_sessions_active",
+                "WebSocket sessions being relayed, by service"
+            ),
+            &["service"]
+        ).unwrap();
+
+        let websocket_bytes = IntCounterVec::new(
+            Opts::new(
+                "proxy_websocket_bytes_total",
+                "Bytes relayed over WebSocket sessions, by service and direction: to_upstream or to_client"
+            ),
+            &["service", "direction"]
+        ).unwrap();
+
+        let websocket_session_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_websocket_session_duration_seconds",
+                "How long WebSocket sessions stayed open, by service"
+            ).buckets(vec![1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 14400.0, 86400.0]),
+            &["service"]
+        ).unwrap();
+
+        registry.register(Box::new(websocket_handshakes.clone()))?;
+        registry.register(Box::new(websocket_sessions.clone()))?;
+        registry.register(Box::new(websocket_bytes.clone()))?;
+        registry.register(Box::new(websocket_session_duration.clone()))?;
+
+        Ok(Self {
+            #[cfg(feature = "metrics-prometheus")]
             registry,
             request_counter,
             request_duration,
             active_connections,
+            protocol_errors,
+            connections_shed,
+            accept_errors,
+            open_fds,
+            fd_pressure,
+            draining,
+            standby,
+            ai_enabled,
+            buffered_bytes,
+            buffered_bytes_high_water,
+            connection_tasks,
+            connection_task_panics,
+            strict_http_rejections,
+            task_restarts,
+            task_panics,
+            tls_handshakes,
+            tls_handshake_duration,
+            tls_handshake_failures,
+            tls_cert_reloads,
+            access_rule_matches,
+            upstream_phase_duration,
+            upstream_connections,
+            upstream_address_family,
+            body_checksums,
+            egress_rejections,
+            config_rollbacks,
+            retries,
+            selections,
+            upstream_anomalies,
+            archive_records,
+            bodiless_violations,
+            experiment_requests,
+            endpoint_gc_collected,
+            upstream_prewarm,
+            client_timeouts,
+            request_bodies_rejected,
+            request_dedupe,
+            informational_responses,
+            breaker_probes,
+            response_headers_stripped,
+            header_values_sanitized,
+            request_targets,
+            pool_clients,
+            operation_duration,
+            scheduler_lag,
+            overloaded,
+            overload_shed,
+            processing_duration,
+            websocket_handshakes,
+            websocket_sessions,
+            websocket_bytes,
+            websocket_session_duration,
+            history: StatsHistory::new(),
             endpoint_metrics: Arc::new(RwLock::new(HashMap::new())),
-        }
+        })
     }
 
-    pub async fn record_request(&self, endpoint: &str, latency_ms: u64, success: bool) {
-        self.request_counter.inc();
-        self.request_duration.observe(latency_ms as f64 / 1000.0);
+    pub async fn record_request(&self, direction: Direction, endpoint: &str, latency_ms: u64, success: bool) {
+        self.request_counter.with_label_values(&[direction.label()]).inc();
+        self.request_duration
+            .with_label_values(&[direction.label()])
+            .observe(latency_ms as f64 / 1000.0);
         
         let mut metrics = self.endpoint_metrics.write().await;
         let endpoint_metric = metrics.entry(endpoint.to_string()).or_insert(EndpointMetrics {
@@ -66,6 +707,7 @@ impl MetricsCollector {
             failed_requests: 0,
             avg_latency_ms: 0.0,
             last_request_time: 0,
+            latency: LatencyHistogram::default(),
         });
 
         endpoint_metric.total_requests += 1;
@@ -78,14 +720,21 @@ impl MetricsCollector {
 
         let alpha = 0.1;
         endpoint_metric.avg_latency_ms = alpha * latency_ms as f64 + (1.0 - alpha) * endpoint_metric.avg_latency_ms;
+        endpoint_metric.latency.record(latency_ms);
         endpoint_metric.last_request_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
+        self.history.record(endpoint, latency_ms, success, std::time::SystemTime::now());
+
         debug!("Recorded metrics for endpoint {}: latency={}ms, success={}", endpoint, latency_ms, success);
     }
 
+    pub fn history(&self) -> &StatsHistory {
+        &self.history
+    }
+
     pub fn increment_connections(&self) {
         self.active_connections.inc();
     }
@@ -94,6 +743,432 @@ impl MetricsCollector {
         self.active_connections.dec();
     }
 
+    pub fn record_protocol_error(&self, listener: &str, reason: &str) {
+        self.protocol_errors.with_label_values(&[listener, reason]).inc();
+    }
+
+    pub fn protocol_error_count(&self, listener: &str, reason: &str) -> u64 {
+        self.protocol_errors.with_label_values(&[listener, reason]).get()
+    }
+
+    pub fn record_connection_shed(&self, reason: &str) {
+        self.connections_shed.with_label_values(&[reason]).inc();
+    }
+
+    pub fn connection_shed_count(&self, reason: &str) -> u64 {
+        self.connections_shed.with_label_values(&[reason]).get()
+    }
+
+    pub fn record_accept_error(&self, listener: &str, kind: &str) {
+        self.accept_errors.with_label_values(&[listener, kind]).inc();
+    }
+
+    pub fn accept_error_count(&self, listener: &str, kind: &str) -> u64 {
+        self.accept_errors.with_label_values(&[listener, kind]).get()
+    }
+
+    pub fn set_open_fds(&self, open: usize) {
+        self.open_fds.set(open as f64);
+    }
+
+    pub fn set_fd_pressure(&self, pressure: bool) {
+        self.fd_pressure.set(if pressure { 1.0 } else { 0.0 });
+    }
+
+    pub fn fd_pressure(&self) -> f64 {
+        self.fd_pressure.get()
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.set(if draining { 1.0 } else { 0.0 });
+    }
+
+    pub fn draining(&self) -> f64 {
+        self.draining.get()
+    }
+
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.set(if standby { 1.0 } else { 0.0 });
+    }
+
+    pub fn standby(&self) -> f64 {
+        self.standby.get()
+    }
+
+    pub fn set_ai_enabled(&self, enabled: bool) {
+        self.ai_enabled.set(if enabled { 1.0 } else { 0.0 });
+    }
+
+    pub fn ai_enabled(&self) -> f64 {
+        self.ai_enabled.get()
+    }
+
+    pub fn set_buffered_bytes(&self, used: usize, high_water: usize) {
+        self.buffered_bytes.set(used as f64);
+        self.buffered_bytes_high_water.set(high_water as f64);
+    }
+
+    pub fn set_connection_tasks(&self, running: usize) {
+        self.connection_tasks.set(running as f64);
+    }
+
+    pub fn connection_tasks(&self) -> f64 {
+        self.connection_tasks.get()
+    }
+
+    // A service whose client is dropped leaves the metric rather than
+    // reading 0, so removed services do not linger.
+    pub fn set_pool_client(&self, direction: Direction, service: &str, active: bool) {
+        if active {
+            self.pool_clients.with_label_values(&[direction.label(), service]).set(1.0);
+        } else {
+            let _ = self.pool_clients.remove_label_values(&[direction.label(), service]);
+        }
+    }
+
+    pub fn set_scheduler_lag(&self, lag: Duration) {
+        self.scheduler_lag.set(lag.as_secs_f64());
+    }
+
+    pub fn set_overloaded(&self, overloaded: bool) {
+        self.overloaded.set(if overloaded { 1.0 } else { 0.0 });
+    }
+
+    pub fn overloaded(&self) -> f64 {
+        self.overloaded.get()
+    }
+
+    pub fn record_overload_shed(&self, route: &str) {
+        self.overload_shed.with_label_values(&[route]).inc();
+    }
+
+    pub fn overload_shed_count(&self, route: &str) -> u64 {
+        self.overload_shed.with_label_values(&[route]).get()
+    }
+
+    pub fn record_processing(&self, direction: Direction, duration: Duration) {
+        self.processing_duration.with_label_values(&[direction.label()]).observe(duration.as_secs_f64());
+    }
+
+    pub fn processing_count(&self, direction: Direction) -> u64 {
+        self.processing_duration.with_label_values(&[direction.label()]).get_sample_count()
+    }
+
+    pub fn record_websocket_handshake(&self, service: &str, outcome: &str) {
+        self.websocket_handshakes.with_label_values(&[service, outcome]).inc();
+    }
+
+    pub fn websocket_handshake_count(&self, service: &str, outcome: &str) -> u64 {
+        self.websocket_handshakes.with_label_values(&[service, outcome]).get()
+    }
+
+    pub fn websocket_session_started(&self, service: &str) {
+        self.websocket_sessions.with_label_values(&[service]).inc();
+    }
+
+    // Sessions count by the bytes they carried and how long they lasted,
+    // not in the per-request latency.
+    pub fn websocket_session_ended(&self, service: &str, duration: Duration, to_upstream: u64, to_client: u64) {
+        self.websocket_sessions.with_label_values(&[service]).dec();
+        self.websocket_bytes.with_label_values(&[service, "to_upstream"]).inc_by(to_upstream);
+        self.websocket_bytes.with_label_values(&[service, "to_client"]).inc_by(to_client);
+        self.websocket_session_duration.with_label_values(&[service]).observe(duration.as_secs_f64());
+    }
+
+    pub fn websocket_sessions(&self, service: &str) -> f64 {
+        self.websocket_sessions.with_label_values(&[service]).get()
+    }
+
+    pub fn websocket_bytes(&self, service: &str, direction: &str) -> u64 {
+        self.websocket_bytes.with_label_values(&[service, direction]).get()
+    }
+
+    pub fn record_operation(&self, route: &str, operation: &str, duration: Duration) {
+        self.operation_duration.with_label_values(&[route, operation]).observe(duration.as_secs_f64());
+    }
+
+    pub fn operation_count(&self, route: &str, operation: &str) -> u64 {
+        self.operation_duration.with_label_values(&[route, operation]).get_sample_count()
+    }
+
+    #[cfg(feature = "metrics-prometheus")]
+    pub fn pool_clients(&self) -> usize {
+        use prometheus::core::Collector;
+        self.pool_clients
+            .collect()
+            .iter()
+            .map(|family| family.get_metric().len())
+            .sum()
+    }
+
+    #[cfg(not(feature = "metrics-prometheus"))]
+    pub fn pool_clients(&self) -> usize {
+        self.pool_clients.len()
+    }
+
+    pub fn record_connection_task_panic(&self, direction: Direction) {
+        self.connection_task_panics.with_label_values(&[direction.label()]).inc();
+    }
+
+    pub fn connection_task_panic_count(&self, direction: Direction) -> u64 {
+        self.connection_task_panics.with_label_values(&[direction.label()]).get()
+    }
+
+    pub fn record_task_restart(&self, task: &str) {
+        self.task_restarts.with_label_values(&[task]).inc();
+    }
+
+    pub fn record_task_panic(&self, task: &str) {
+        self.task_panics.with_label_values(&[task]).inc();
+    }
+
+    pub fn task_panic_count(&self, task: &str) -> u64 {
+        self.task_panics.with_label_values(&[task]).get()
+    }
+
+    pub fn record_tls_handshake(&self, kind: &str, protocol: &str, cipher: &str, duration: Duration) {
+        self.tls_handshakes.with_label_values(&[kind, protocol, cipher]).inc();
+        self.tls_handshake_duration.with_label_values(&[kind]).observe(duration.as_secs_f64());
+    }
+
+    pub fn tls_handshake_count(&self, kind: &str) -> u64 {
+        self.tls_handshake_duration.with_label_values(&[kind]).get_sample_count()
+    }
+
+    pub fn record_tls_handshake_failure(&self, reason: &str) {
+        self.tls_handshake_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn request_count(&self, direction: Direction) -> u64 {
+        self.request_counter.with_label_values(&[direction.label()]).get() as u64
+    }
+
+    pub fn record_upstream_phases(&self, direction: Direction, service: &str, phases: &UpstreamPhases) {
+        let connection = if phases.reused { "reused" } else { "new" };
+        self.upstream_connections
+            .with_label_values(&[direction.label(), service, connection])
+            .inc();
+        for (phase, ms) in phases.durations() {
+            // Zeros from a reused connection would drag the connection phases down.
+            if phases.reused && matches!(phase, "dns" | "connect" | "tls") {
+                continue;
+            }
+            self.upstream_phase_duration
+                .with_label_values(&[direction.label(), service, phase])
+                .observe(ms / 1000.0);
+        }
+    }
+
+    pub fn upstream_connection_count(&self, direction: Direction, service: &str, connection: &str) -> u64 {
+        self.upstream_connections
+            .with_label_values(&[direction.label(), service, connection])
+            .get()
+    }
+
+    pub fn upstream_phase_count(&self, direction: Direction, service: &str, phase: &str) -> u64 {
+        self.upstream_phase_duration
+            .with_label_values(&[direction.label(), service, phase])
+            .get_sample_count()
+    }
+
+    pub fn record_upstream_family(&self, direction: Direction, service: &str, family: &str) {
+        self.upstream_address_family
+            .with_label_values(&[direction.label(), service, family])
+            .inc();
+    }
+
+    pub fn upstream_family_count(&self, direction: Direction, service: &str, family: &str) -> u64 {
+        self.upstream_address_family
+            .with_label_values(&[direction.label(), service, family])
+            .get()
+    }
+
+    pub fn record_egress_rejection(&self, reason: &str) {
+        self.egress_rejections.with_label_values(&[reason]).inc();
+    }
+
+    pub fn egress_rejection_count(&self, reason: &str) -> u64 {
+        self.egress_rejections.with_label_values(&[reason]).get()
+    }
+
+    pub fn record_config_rollback(&self, trigger: &str) {
+        self.config_rollbacks.with_label_values(&[trigger]).inc();
+    }
+
+    pub fn config_rollback_count(&self, trigger: &str) -> u64 {
+        self.config_rollbacks.with_label_values(&[trigger]).get()
+    }
+
+    pub fn record_retry(&self, endpoint: &str, attempt: u32) {
+        self.retries.with_label_values(&[endpoint, &attempt.to_string()]).inc();
+    }
+
+    pub fn retry_count(&self, endpoint: &str, attempt: u32) -> u64 {
+        self.retries.with_label_values(&[endpoint, &attempt.to_string()]).get()
+    }
+
+    pub fn record_selection(&self, mode: &str) {
+        self.selections.with_label_values(&[mode]).inc();
+    }
+
+    pub fn selection_count(&self, mode: &str) -> u64 {
+        self.selections.with_label_values(&[mode]).get()
+    }
+
+    pub fn record_upstream_anomaly(&self, anomaly: &str, action: &str) {
+        self.upstream_anomalies.with_label_values(&[anomaly, action]).inc();
+    }
+
+    pub fn upstream_anomaly_count(&self, anomaly: &str, action: &str) -> u64 {
+        self.upstream_anomalies.with_label_values(&[anomaly, action]).get()
+    }
+
+    pub fn record_archive(&self, result: &str) {
+        self.archive_records.with_label_values(&[result]).inc();
+    }
+
+    pub fn archive_count(&self, result: &str) -> u64 {
+        self.archive_records.with_label_values(&[result]).get()
+    }
+
+    pub fn record_bodiless_violation(&self, source: &str, response: &str) {
+        self.bodiless_violations.with_label_values(&[source, response]).inc();
+    }
+
+    pub fn bodiless_violation_count(&self, source: &str, response: &str) -> u64 {
+        self.bodiless_violations.with_label_values(&[source, response]).get()
+    }
+
+    // `status` is the class, e.g. "2xx".
+    pub fn record_experiment_request(&self, experiment: &str, variant: &str, status: &str) {
+        self.experiment_requests.with_label_values(&[experiment, variant, status]).inc();
+    }
+
+    pub fn experiment_request_count(&self, experiment: &str, variant: &str, status: &str) -> u64 {
+        self.experiment_requests.with_label_values(&[experiment, variant, status]).get()
+    }
+
+    pub fn record_client_timeout(&self, class: &str) {
+        self.client_timeouts.with_label_values(&[class]).inc();
+    }
+
+    pub fn client_timeout_count(&self, class: &str) -> u64 {
+        self.client_timeouts.with_label_values(&[class]).get()
+    }
+
+    pub fn record_request_body_rejected(&self, service: &str, stage: &str) {
+        self.request_bodies_rejected.with_label_values(&[service, stage]).inc();
+    }
+
+    pub fn request_body_rejected_count(&self, service: &str, stage: &str) -> u64 {
+        self.request_bodies_rejected.with_label_values(&[service, stage]).get()
+    }
+
+    pub fn record_informational_response(&self, service: &str, status: u16) {
+        self.informational_responses
+            .with_label_values(&[service, &status.to_string()])
+            .inc();
+    }
+
+    pub fn informational_response_count(&self, service: &str, status: u16) -> u64 {
+        self.informational_responses
+            .with_label_values(&[service, &status.to_string()])
+            .get()
+    }
+
+    pub fn record_request_dedupe(&self, route: &str, outcome: &str) {
+        self.request_dedupe.with_label_values(&[route, outcome]).inc();
+    }
+
+    pub fn request_dedupe_count(&self, route: &str, outcome: &str) -> u64 {
+        self.request_dedupe.with_label_values(&[route, outcome]).get()
+    }
+
+    pub fn record_breaker_probe(&self, service: &str, mode: &str, outcome: &str) {
+        self.breaker_probes.with_label_values(&[service, mode, outcome]).inc();
+    }
+
+    pub fn breaker_probe_count(&self, service: &str, mode: &str, outcome: &str) -> u64 {
+        self.breaker_probes.with_label_values(&[service, mode, outcome]).get()
+    }
+
+    pub fn record_response_headers_stripped(&self, direction: Direction, header: &str, count: u64) {
+        self.response_headers_stripped
+            .with_label_values(&[direction.label(), header])
+            .inc_by(count);
+    }
+
+    pub fn response_headers_stripped_count(&self, direction: Direction, header: &str) -> u64 {
+        self.response_headers_stripped
+            .with_label_values(&[direction.label(), header])
+            .get()
+    }
+
+    pub fn record_header_value_sanitized(&self, source: &str, reason: &str) {
+        self.header_values_sanitized.with_label_values(&[source, reason]).inc();
+    }
+
+    pub fn header_values_sanitized_count(&self, source: &str, reason: &str) -> u64 {
+        self.header_values_sanitized.with_label_values(&[source, reason]).get()
+    }
+
+    pub fn record_request_target(&self, form: &str, outcome: &str) {
+        self.request_targets.with_label_values(&[form, outcome]).inc();
+    }
+
+    pub fn request_target_count(&self, form: &str, outcome: &str) -> u64 {
+        self.request_targets.with_label_values(&[form, outcome]).get()
+    }
+
+    pub fn record_strict_http_rejection(&self, reason: &str) {
+        self.strict_http_rejections.with_label_values(&[reason]).inc();
+    }
+
+    pub fn strict_http_rejection_count(&self, reason: &str) -> u64 {
+        self.strict_http_rejections.with_label_values(&[reason]).get()
+    }
+
+    pub fn record_endpoint_gc(&self, module: &str, collected: usize) {
+        self.endpoint_gc_collected.with_label_values(&[module]).inc_by(collected as u64);
+    }
+
+    pub fn endpoint_gc_count(&self, module: &str) -> u64 {
+        self.endpoint_gc_collected.with_label_values(&[module]).get()
+    }
+
+    pub fn record_prewarm(&self, service: &str, result: &str) {
+        self.upstream_prewarm.with_label_values(&[service, result]).inc();
+    }
+
+    pub fn prewarm_count(&self, service: &str, result: &str) -> u64 {
+        self.upstream_prewarm.with_label_values(&[service, result]).get()
+    }
+
+    pub fn record_body_checksum(&self, route: &str, direction: &str, result: &str) {
+        self.body_checksums.with_label_values(&[route, direction, result]).inc();
+    }
+
+    pub fn body_checksum_count(&self, route: &str, direction: &str, result: &str) -> u64 {
+        self.body_checksums.with_label_values(&[route, direction, result]).get()
+    }
+
+    pub fn record_tls_cert_reload(&self, result: &str) {
+        self.tls_cert_reloads.with_label_values(&[result]).inc();
+    }
+
+    pub fn tls_cert_reload_count(&self, result: &str) -> u64 {
+        self.tls_cert_reloads.with_label_values(&[result]).get()
+    }
+
+    pub fn record_access_rule_match(&self, rule: &str, action: &str) {
+        self.access_rule_matches.with_label_values(&[rule, action]).inc();
+    }
+
+    pub fn access_rule_match_count(&self, rule: &str, action: &str) -> u64 {
+        self.access_rule_matches.with_label_values(&[rule, action]).get()
+    }
+
+    #[cfg(feature = "metrics-prometheus")]
     pub async fn get_prometheus_metrics(&self) -> String {
         let encoder = TextEncoder::new();
         let metric_families = self.registry.gather();
@@ -105,23 +1180,15 @@ impl MetricsCollector {
         
         let endpoint_metrics = self.endpoint_metrics.read().await;
         for (endpoint, metrics) in endpoint_metrics.iter() {
-            result.push_str(&format!(
-                "# HELP proxy_endpoint_requests_total Total requests per endpoint\n"
-            ));
-            result.push_str(&format!(
-                "# TYPE proxy_endpoint_requests_total counter\n"
-            ));
+            result.push_str("# HELP proxy_endpoint_requests_total Total requests per endpoint\n");
+            result.push_str("# TYPE proxy_endpoint_requests_total counter\n");
             result.push_str(&format!(
                 "proxy_endpoint_requests_total{{endpoint=\"{}\"}} {}\n",
                 endpoint, metrics.total_requests
             ));
             
-            result.push_str(&format!(
-                "# HELP proxy_endpoint_success_rate Success rate per endpoint\n"
-            ));
-            result.push_str(&format!(
-                "# TYPE proxy_endpoint_success_rate gauge\n"
-            ));
+            result.push_str("# HELP proxy_endpoint_success_rate Success rate per endpoint\n");
+            result.push_str("# TYPE proxy_endpoint_success_rate gauge\n");
             let success_rate = if metrics.total_requests > 0 {
                 metrics.successful_requests as f64 / metrics.total_requests as f64
             } else {
@@ -132,23 +1199,87 @@ impl MetricsCollector {
                 endpoint, success_rate
             ));
             
-            result.push_str(&format!(
-                "# HELP proxy_endpoint_avg_latency_ms Average latency per endpoint in milliseconds\n"
-            ));
-            result.push_str(&format!(
-                "# TYPE proxy_endpoint_avg_latency_ms gauge\n"
-            ));
+            result.push_str("# HELP proxy_endpoint_avg_latency_ms Average latency per endpoint in milliseconds\n");
+            result.push_str("# TYPE proxy_endpoint_avg_latency_ms gauge\n");
             result.push_str(&format!(
                 "proxy_endpoint_avg_latency_ms{{endpoint=\"{}\"}} {:.2}\n",
                 endpoint, metrics.avg_latency_ms
             ));
+
+            for (name, value) in [
+                ("p50", metrics.latency.p50()),
+                ("p95", metrics.latency.p95()),
+                ("p99", metrics.latency.p99()),
+            ] {
+                result.push_str(&format!(
+                    "# HELP proxy_endpoint_latency_{}_ms {} latency per endpoint over the last {} requests, in milliseconds\n",
+                    name, name, LATENCY_SAMPLES
+                ));
+                result.push_str(&format!("# TYPE proxy_endpoint_latency_{}_ms gauge\n", name));
+                result.push_str(&format!(
+                    "proxy_endpoint_latency_{}_ms{{endpoint=\"{}\"}} {:.2}\n",
+                    name, endpoint, value
+                ));
+            }
         }
         
         result
     }
 
+    pub async fn forget_endpoints(&self, endpoints: &[String]) -> usize {
+        let mut endpoint_metrics = self.endpoint_metrics.write().await;
+        endpoints.iter().filter(|endpoint| endpoint_metrics.remove(*endpoint).is_some()).count()
+    }
+
     pub async fn get_endpoint_stats(&self) -> HashMap<String, EndpointMetrics> {
         let metrics = self.endpoint_metrics.read().await;
         metrics.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.p99(), 0.0);
+        // Shuffled, so the answers can't come from insertion order.
+        for latency in (1..=100).map(|n| (n * 37) % 100 + 1) {
+            histogram.record(latency);
+        }
+        assert_eq!((histogram.p50(), histogram.p95(), histogram.p99()), (50.0, 95.0, 99.0));
+    }
+
+    #[test]
+    fn test_latency_keeps_only_recent_samples() {
+        let mut histogram = LatencyHistogram::default();
+        for _ in 0..LATENCY_SAMPLES {
+            histogram.record(1000);
+        }
+        for _ in 0..LATENCY_SAMPLES - 20 {
+            histogram.record(10);
+        }
+        assert_eq!(histogram.len(), LATENCY_SAMPLES);
+        assert_eq!(histogram.p99(), 1000.0);
+        for _ in 0..20 {
+            histogram.record(10);
+        }
+        assert_eq!(histogram.p99(), 10.0);
+    }
+
+    #[cfg(feature = "metrics-prometheus")]
+    #[tokio::test]
+    async fn test_prometheus_output_has_percentiles() {
+        let metrics = MetricsCollector::new();
+        for latency in [10, 20, 300] {
+            metrics.record_request(Direction::Ingress, "http://a", latency, true).await;
+        }
+        let output = metrics.get_prometheus_metrics().await;
+        assert!(output.contains("proxy_endpoint_latency_p50_ms{endpoint=\"http://a\"} 20.00\n"));
+        assert!(output.contains("proxy_endpoint_latency_p95_ms{endpoint=\"http://a\"} 300.00\n"));
+        assert!(output.contains("proxy_endpoint_latency_p99_ms{endpoint=\"http://a\"} 300.00\n"));
+        assert!(output.contains("proxy_endpoint_avg_latency_ms{endpoint=\"http://a\"}"));
+    }
+}
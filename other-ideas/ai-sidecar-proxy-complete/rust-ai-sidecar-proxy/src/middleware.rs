@@ -1,10 +1,12 @@
-use hyper::{Request, Response, StatusCode};
+use hyper::{body::Incoming, Request, Response, StatusCode};
 use http_body_util::{combinators::BoxBody, BodyExt};
 use bytes::Bytes;
+use serde::Serialize;
 use std::time::Instant;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+#[derive(Clone)]
 pub struct RequestContext {
     pub request_id: String,
     pub start_time: Instant,
@@ -37,17 +39,29 @@ impl RequestContext {
     }
 }
 
+// Hook for applications embedding the proxy. Middleware runs in the order it
+// was added; `on_request` may answer the request itself, which skips the rest
+// of the chain and the upstream. `on_response` runs in reverse order for every
+// middleware whose `on_request` ran.
+pub trait Middleware: Send + Sync {
+    fn on_request(
+        &self,
+        _req: &mut Request<Incoming>,
+        _context: &RequestContext,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        None
+    }
+
+    fn on_response(&self, _response: &mut Response<BoxBody<Bytes, hyper::Error>>, _context: &RequestContext) {}
+}
+
 pub struct LoggingMiddleware;
 
 impl LoggingMiddleware {
-    pub fn log_request<T>(req: &Request<T>, context: &RequestContext) {
+    pub fn log_request<T>(_req: &Request<T>, context: &RequestContext) {
         info!(
-            "Request started: {} {} {} [{}] - {}",
-            context.method,
-            context.path,
-            context.client_ip,
-            context.request_id,
-            context.user_agent.as_deref().unwrap_or("unknown")
+            user_agent = context.user_agent.as_deref().unwrap_or("unknown"),
+            "request started"
         );
     }
 
@@ -57,33 +71,61 @@ impl LoggingMiddleware {
         upstream_endpoint: Option<&str>,
     ) {
         let status = response.status();
-        let elapsed = context.elapsed();
-        
-        let log_level = if status.is_server_error() {
-            "ERROR"
+        let latency_ms = context.elapsed();
+        let endpoint = upstream_endpoint.unwrap_or("");
+        let access_log = cfg!(feature = "structured-logs").then(|| {
+            let log = StructuredAccessLog::new(response, context, upstream_endpoint, latency_ms);
+            serde_json::to_string(&log).unwrap()
+        });
+        let access_log = access_log.as_deref();
+
+        if status.is_server_error() {
+            error!(status = status.as_u16(), latency_ms, endpoint, access_log, "request completed");
         } else if status.is_client_error() {
-            "WARN"
+            warn!(status = status.as_u16(), latency_ms, endpoint, access_log, "request completed");
         } else {
-            "INFO"
-        };
-
-        let upstream_info = upstream_endpoint
-            .map(|e| format!(" -> {}", e))
-            .unwrap_or_default();
-
-        match log_level {
-            "ERROR" => warn!(
-                "Request completed: {} {} {} [{}] - {}ms{}",
-                context.method, context.path, status, context.request_id, elapsed, upstream_info
-            ),
-            "WARN" => warn!(
-                "Request completed: {} {} {} [{}] - {}ms{}",
-                context.method, context.path, status, context.request_id, elapsed, upstream_info
-            ),
-            _ => info!(
-                "Request completed: {} {} {} [{}] - {}ms{}",
-                context.method, context.path, status, context.request_id, elapsed, upstream_info
-            ),
+            info!(status = status.as_u16(), latency_ms, endpoint, access_log, "request completed");
+        }
+    }
+}
+
+// One access log entry, emitted as the `access_log` field with the
+// `structured-logs` feature so log pipelines get it without parsing text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StructuredAccessLog {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status_code: u16,
+    pub latency_ms: u64,
+    pub upstream_endpoint: Option<String>,
+    pub client_ip: String,
+    pub user_agent: Option<String>,
+    // From the response's content-length; unknown for chunked bodies, which
+    // are still streaming when the entry is written.
+    pub bytes_sent: Option<u64>,
+}
+
+impl StructuredAccessLog {
+    pub fn new<T>(
+        response: &Response<T>,
+        context: &RequestContext,
+        upstream_endpoint: Option<&str>,
+        latency_ms: u64,
+    ) -> Self {
+        Self {
+            request_id: context.request_id.clone(),
+            method: context.method.clone(),
+            path: context.path.clone(),
+            status_code: response.status().as_u16(),
+            latency_ms,
+            upstream_endpoint: upstream_endpoint.map(str::to_string),
+            client_ip: context.client_ip.clone(),
+            user_agent: context.user_agent.clone(),
+            bytes_sent: response
+                .headers()
+                .get(hyper::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
         }
     }
 }
@@ -183,7 +225,7 @@ impl CompressionMiddleware {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hyper::{Method, Uri};
+    use hyper::Method;
 
     #[test]
     fn test_security_middleware_path_traversal() {
@@ -207,6 +249,39 @@ mod tests {
         assert!(!SecurityMiddleware::is_request_allowed(&req));
     }
 
+    #[test]
+    fn test_access_log_serializes_every_field() {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/api/a/items?page=2")
+            .header("user-agent", "curl/8.5")
+            .body(())
+            .unwrap();
+        let context = RequestContext::new(&req, "10.0.0.7".to_string());
+        let response = Response::builder().status(201).header("content-length", "42").body(()).unwrap();
+
+        let log = StructuredAccessLog::new(&response, &context, Some("http://a:80"), 12);
+        let value = serde_json::to_value(&log).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "request_id": context.request_id,
+                "method": "POST",
+                "path": "/api/a/items",
+                "status_code": 201,
+                "latency_ms": 12,
+                "upstream_endpoint": "http://a:80",
+                "client_ip": "10.0.0.7",
+                "user_agent": "curl/8.5",
+                "bytes_sent": 42,
+            })
+        );
+
+        let chunked = Response::builder().status(502).body(()).unwrap();
+        let log = StructuredAccessLog::new(&chunked, &context, None, 3);
+        assert_eq!((log.upstream_endpoint, log.bytes_sent), (None, None));
+    }
+
     #[test]
     fn test_security_middleware_valid_path() {
         let req = Request::builder()
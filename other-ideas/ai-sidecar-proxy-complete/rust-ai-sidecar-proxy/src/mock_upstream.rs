@@ -0,0 +1,159 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, service::service_fn, HeaderMap, Request, Response, StatusCode};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder as ServerBuilder,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+// In-process HTTP upstream for tests and local experiments.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub latency: Duration,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl Default for MockResponse {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            latency: Duration::ZERO,
+            headers: Vec::new(),
+            body: Bytes::from_static(b"ok"),
+        }
+    }
+}
+
+pub struct MockUpstream {
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    // Keyed by path so health probes do not mask the request a test cares about.
+    last_headers: Arc<Mutex<HashMap<String, HeaderMap>>>,
+    // "METHOD /path" for every request, in arrival order.
+    log: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub async fn start(response: MockResponse) -> std::io::Result<Self> {
+        Self::start_with(move || response.clone()).await
+    }
+
+    // `respond` is called once per request, so it can vary latency or status.
+    pub async fn start_with<F>(respond: F) -> std::io::Result<Self>
+    where
+        F: Fn() -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        Self::serve_with(listener, respond)
+    }
+
+    // Serves on a listener the caller set up, e.g. with a tiny backlog.
+    pub fn serve_with<F>(listener: TcpListener, respond: F) -> std::io::Result<Self>
+    where
+        F: Fn() -> MockResponse + Send + Sync + 'static,
+    {
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let last_headers = Arc::new(Mutex::new(HashMap::new()));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(respond);
+
+        let counter = requests.clone();
+        let seen_headers = last_headers.clone();
+        let seen_log = log.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let counter = counter.clone();
+                let seen_headers = seen_headers.clone();
+                let seen_log = seen_log.clone();
+                let respond = respond.clone();
+
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        seen_headers
+                            .lock()
+                            .unwrap()
+                            .insert(req.uri().path().to_string(), req.headers().clone());
+                        seen_log
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", req.method(), req.uri().path()));
+                        Self::respond(req, respond())
+                    });
+                    let _ = ServerBuilder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            requests,
+            last_headers,
+            log,
+            task,
+        })
+    }
+
+    async fn respond(
+        req: Request<Incoming>,
+        response: MockResponse,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let _ = req.into_body().collect().await;
+        if !response.latency.is_zero() {
+            tokio::time::sleep(response.latency).await;
+        }
+
+        let mut builder = Response::builder()
+            .status(StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+        for (name, value) in &response.headers {
+            builder = builder.header(name, value);
+        }
+        Ok(builder.body(Full::new(response.body)).unwrap())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn last_request_headers(&self, path: &str) -> Option<HeaderMap> {
+        self.last_headers.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn request_log(&self) -> Vec<String> {
+        self.log.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
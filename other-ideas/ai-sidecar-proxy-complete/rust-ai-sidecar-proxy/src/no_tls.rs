@@ -0,0 +1,27 @@
+// `tls` in builds without the `tls` feature. Config validation rejects a
+// listener `tls` section there, so no terminator is ever made and the
+// listener only speaks plaintext.
+use crate::{config::ListenerTlsConfig, metrics::MetricsCollector, supervisor::TaskSupervisor};
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+pub enum TlsTerminator {}
+
+impl TlsTerminator {
+    pub fn new(_config: &ListenerTlsConfig, _metrics: Arc<MetricsCollector>) -> Result<Self> {
+        bail!("this build has no TLS; rebuild with --features tls")
+    }
+
+    pub fn start_cert_reload(&self, _supervisor: &Arc<TaskSupervisor>) {
+        match *self {}
+    }
+
+    pub fn start_rotation(&self, _supervisor: &Arc<TaskSupervisor>) {
+        match *self {}
+    }
+
+    pub async fn accept(&self, _stream: TcpStream) -> Option<TcpStream> {
+        match *self {}
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{metrics::MetricsCollector, supervisor::TaskSupervisor};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+// When the proxy's own scheduler falls behind, every request slows alike and
+// the upstream metrics take the blame. Lag is how late a timer fires; past
+// `enter_lag_ms` the proxy is overloaded, sheds low-priority requests and
+// skips archiving, captures and per-request start logs until
+// `recovery_ticks` ticks in a row come in under `exit_lag_ms`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverloadConfig {
+    pub enabled: bool,
+    pub tick_ms: u64,
+    pub enter_lag_ms: u64,
+    pub exit_lag_ms: u64,
+    pub recovery_ticks: u32,
+    // Requests with this header set to "low", or to one of
+    // `low_priority_routes`, are answered 503 while overloaded.
+    pub priority_header: String,
+    pub low_priority_routes: Vec<String>,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tick_ms: 100,
+            enter_lag_ms: 250,
+            exit_lag_ms: 50,
+            recovery_ticks: 20,
+            priority_header: "x-request-priority".to_string(),
+            low_priority_routes: Vec::new(),
+        }
+    }
+}
+
+impl OverloadConfig {
+    // Why the settings cannot work together, if they cannot.
+    pub fn problem(&self) -> Option<&'static str> {
+        if !self.enabled {
+            return None;
+        }
+        if self.tick_ms == 0 || self.recovery_ticks == 0 {
+            Some("tick_ms and recovery_ticks must be above 0")
+        } else if self.exit_lag_ms > self.enter_lag_ms {
+            Some("exit_lag_ms must not be above enter_lag_ms")
+        } else if hyper::header::HeaderName::from_bytes(self.priority_header.as_bytes()).is_err() {
+            Some("priority_header is not a valid header name")
+        } else {
+            None
+        }
+    }
+}
+
+pub struct OverloadMonitor {
+    config: OverloadConfig,
+    metrics: Arc<MetricsCollector>,
+    overloaded: AtomicBool,
+    // Ticks in a row under exit_lag_ms while overloaded.
+    calm_ticks: AtomicU32,
+}
+
+impl OverloadMonitor {
+    pub fn new(config: OverloadConfig, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            config,
+            metrics,
+            overloaded: AtomicBool::new(false),
+            calm_ticks: AtomicU32::new(0),
+        }
+    }
+
+    // Takes one tick's lag; returns whether the proxy is overloaded after it.
+    pub fn observe(&self, lag: Duration) -> bool {
+        let lag_ms = lag.as_millis() as u64;
+        self.metrics.set_scheduler_lag(lag);
+        let was = self.overloaded.load(Ordering::Relaxed);
+        let now = if lag_ms >= self.config.enter_lag_ms {
+            self.calm_ticks.store(0, Ordering::Relaxed);
+            true
+        } else if !was {
+            false
+        } else if lag_ms < self.config.exit_lag_ms {
+            self.calm_ticks.fetch_add(1, Ordering::Relaxed) + 1 < self.config.recovery_ticks
+        } else {
+            self.calm_ticks.store(0, Ordering::Relaxed);
+            true
+        };
+
+        if now != was {
+            self.overloaded.store(now, Ordering::Relaxed);
+            self.metrics.set_overloaded(now);
+            if now {
+                warn!(lag_ms, enter_lag_ms = self.config.enter_lag_ms, "overload mode entered");
+            } else {
+                self.calm_ticks.store(0, Ordering::Relaxed);
+                info!(lag_ms, recovery_ticks = self.config.recovery_ticks, "overload mode left");
+            }
+        }
+        now
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn should_shed(&self, route: &str, headers: &HeaderMap) -> bool {
+        self.is_overloaded()
+            && (self.config.low_priority_routes.iter().any(|low| low == route)
+                || headers
+                    .get(self.config.priority_header.as_str())
+                    .is_some_and(|priority| priority.as_bytes().eq_ignore_ascii_case(b"low")))
+    }
+
+    pub fn start(self: &Arc<Self>, supervisor: &Arc<TaskSupervisor>) {
+        if !self.config.enabled {
+            return;
+        }
+        let monitor = self.clone();
+        supervisor.spawn("overload_monitor", false, move |heartbeat| {
+            let monitor = monitor.clone();
+            async move {
+                let tick = Duration::from_millis(monitor.config.tick_ms);
+                loop {
+                    let due = Instant::now() + tick;
+                    tokio::time::sleep_until(due).await;
+                    heartbeat.beat();
+                    monitor.observe(Instant::now().saturating_duration_since(due));
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> OverloadMonitor {
+        let config = OverloadConfig {
+            enter_lag_ms: 100,
+            exit_lag_ms: 20,
+            recovery_ticks: 3,
+            low_priority_routes: vec!["/reports".to_string()],
+            ..OverloadConfig::default()
+        };
+        OverloadMonitor::new(config, Arc::new(MetricsCollector::new()))
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_entered_on_lag_and_left_after_calm_ticks() {
+        let monitor = monitor();
+        assert!(!monitor.observe(ms(99)));
+        assert!(monitor.observe(ms(100)));
+        assert_eq!(monitor.metrics.overloaded(), 1.0);
+
+        // Lag between the thresholds resets the count.
+        assert!(monitor.observe(ms(5)));
+        assert!(monitor.observe(ms(5)));
+        assert!(monitor.observe(ms(50)));
+        assert!(monitor.observe(ms(5)));
+        assert!(monitor.observe(ms(5)));
+        assert!(!monitor.observe(ms(5)));
+        assert_eq!(monitor.metrics.overloaded(), 0.0);
+        assert!(!monitor.observe(ms(50)));
+    }
+
+    #[test]
+    fn test_only_low_priority_shed_while_overloaded() {
+        let monitor = monitor();
+        let mut low = HeaderMap::new();
+        low.insert("x-request-priority", "LOW".parse().unwrap());
+        assert!(!monitor.should_shed("/reports", &low));
+
+        monitor.observe(ms(500));
+        assert!(monitor.should_shed("/api/a", &low));
+        assert!(monitor.should_shed("/reports", &HeaderMap::new()));
+        assert!(!monitor.should_shed("/api/a", &HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_thresholds_checked() {
+        assert_eq!(OverloadConfig::default().problem(), None);
+        let inverted = OverloadConfig { exit_lag_ms: 300, ..OverloadConfig::default() };
+        assert!(inverted.problem().unwrap().contains("exit_lag_ms"));
+        let disabled = OverloadConfig { enabled: false, ..inverted };
+        assert_eq!(disabled.problem(), None);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::routes::bounded;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// Stands in for every operation past the cap.
+pub const OTHER_OPERATION: &str = "__other__";
+
+// How a route's paths become operations, such as `/users/{id}/orders/{id}`
+// for `/users/123/orders/456`, to label metrics and the access log by.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathTemplate {
+    // Tried in order; the first to match the whole path names the operation.
+    pub patterns: Vec<TemplatePattern>,
+    // Otherwise, every segment of one of these kinds becomes `{id}`.
+    pub collapse: Vec<SegmentKind>,
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            collapse: vec![SegmentKind::Numeric, SegmentKind::Uuid],
+        }
+    }
+}
+
+// `template` can refer to the pattern's captures as `$1` or `${name}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplatePattern {
+    pub pattern: String,
+    pub template: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    // Digits only.
+    Numeric,
+    // 8-4-4-4-12 hex digits, either case.
+    Uuid,
+}
+
+impl SegmentKind {
+    fn matches(self, segment: &str) -> bool {
+        match self {
+            SegmentKind::Numeric => !segment.is_empty() && segment.bytes().all(|byte| byte.is_ascii_digit()),
+            SegmentKind::Uuid => {
+                segment.len() == 36
+                    && segment.bytes().enumerate().all(|(index, byte)| match index {
+                        8 | 13 | 18 | 23 => byte == b'-',
+                        _ => byte.is_ascii_hexdigit(),
+                    })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CompiledTemplate {
+    patterns: Vec<(Regex, String)>,
+    collapse: Vec<SegmentKind>,
+}
+
+impl PathTemplate {
+    // Patterns are anchored to the whole path and compiled with the same
+    // size limit as route patterns.
+    pub fn compile(&self) -> Result<CompiledTemplate, (usize, regex::Error)> {
+        let patterns = self
+            .patterns
+            .iter()
+            .enumerate()
+            .map(|(index, pattern)| {
+                let regex = bounded(&format!("^(?:{})$", pattern.pattern)).map_err(|e| (index, e))?;
+                Ok((regex, pattern.template.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CompiledTemplate {
+            patterns,
+            collapse: self.collapse.clone(),
+        })
+    }
+}
+
+impl CompiledTemplate {
+    pub fn operation(&self, path: &str) -> String {
+        if let Some((regex, template)) = self.patterns.iter().find(|(regex, _)| regex.is_match(path)) {
+            return regex.replace(path, template.as_str()).into_owned();
+        }
+        path.split('/')
+            .map(|segment| {
+                if self.collapse.iter().any(|kind| kind.matches(segment)) {
+                    "{id}"
+                } else {
+                    segment
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+// The distinct route and operation pairs seen, up to `max`; a template that
+// lets ids through then costs at most that many label values.
+pub struct Operations {
+    max: usize,
+    seen: Mutex<HashSet<(String, String)>>,
+}
+
+impl Operations {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn label(&self, route: &str, operation: String) -> String {
+        let mut seen = self.seen.lock().unwrap();
+        let key = (route.to_string(), operation);
+        if seen.contains(&key) || seen.len() < self.max {
+            let operation = key.1.clone();
+            seen.insert(key);
+            operation
+        } else {
+            OTHER_OPERATION.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric_and_uuid_segments_collapse() {
+        let template = PathTemplate::default().compile().unwrap();
+        assert_eq!(template.operation("/users/123/orders/456"), "/users/{id}/orders/{id}");
+        assert_eq!(
+            template.operation("/carts/3F2504E0-4F89-11D3-9A0C-0305E82C3301/items"),
+            "/carts/{id}/items"
+        );
+        assert_eq!(template.operation("/users/me/v2"), "/users/me/v2");
+        assert_eq!(template.operation("/users/"), "/users/");
+        // Not quite a UUID.
+        assert_eq!(
+            template.operation("/carts/3F2504E0-4F89-11D3-9A0C-0305E82C330"),
+            "/carts/3F2504E0-4F89-11D3-9A0C-0305E82C330"
+        );
+
+        let numeric_only = PathTemplate {
+            collapse: vec![SegmentKind::Numeric],
+            ..PathTemplate::default()
+        };
+        assert_eq!(
+            numeric_only.compile().unwrap().operation("/carts/3f2504e0-4f89-11d3-9a0c-0305e82c3301/7"),
+            "/carts/3f2504e0-4f89-11d3-9a0c-0305e82c3301/{id}"
+        );
+    }
+
+    #[test]
+    fn test_patterns_come_before_collapsing() {
+        let template = PathTemplate {
+            patterns: vec![TemplatePattern {
+                pattern: "/files/(?<bucket>[^/]+)/.*".to_string(),
+                template: "/files/${bucket}/{key}".to_string(),
+            }],
+            ..PathTemplate::default()
+        };
+        let compiled = template.compile().unwrap();
+        assert_eq!(compiled.operation("/files/logs/2024/01/app.log"), "/files/logs/{key}");
+        assert_eq!(compiled.operation("/files"), "/files");
+        assert_eq!(compiled.operation("/users/42"), "/users/{id}");
+
+        let invalid = PathTemplate {
+            patterns: vec![
+                TemplatePattern { pattern: "/ok".to_string(), template: "/ok".to_string() },
+                TemplatePattern { pattern: "/bad(".to_string(), template: "/bad".to_string() },
+            ],
+            ..PathTemplate::default()
+        };
+        assert_eq!(invalid.compile().unwrap_err().0, 1);
+    }
+
+    #[test]
+    fn test_operations_past_the_cap_are_other() {
+        let operations = Operations::new(2);
+        assert_eq!(operations.label("/users", "/users/{id}".to_string()), "/users/{id}");
+        assert_eq!(operations.label("/orders", "/users/{id}".to_string()), "/users/{id}");
+        assert_eq!(operations.label("/users", "/users/me".to_string()), OTHER_OPERATION);
+        // Already seen, so still its own.
+        assert_eq!(operations.label("/users", "/users/{id}".to_string()), "/users/{id}");
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{circuit_breaker::BreakerProbeConfig, config::UpstreamService, egress::Direction};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+// The UpstreamService fields a template may set.
+const POLICY_FIELDS: [&str; 8] = [
+    "circuit_breaker_threshold",
+    "breaker_probe",
+    "max_retries",
+    "health_check_path",
+    "health_check_timeout_ms",
+    "health_check_warn_latency_ms",
+    "timeout_ms",
+    "max_timeout_ms",
+];
+
+// Shared breaker, retry, health-check and timeout settings. Services name one
+// in `policy` and override any field; a template may extend another.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyTemplate {
+    #[serde(default)]
+    pub extends: Option<String>,
+    #[serde(default)]
+    pub circuit_breaker_threshold: Option<u32>,
+    // Merged key by key, so a service can override just the mode.
+    #[serde(default)]
+    pub breaker_probe: Option<Map<String, Value>>,
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    #[serde(default)]
+    pub health_check_path: Option<String>,
+    #[serde(default)]
+    pub health_check_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub health_check_warn_latency_ms: Option<u64>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub max_timeout_ms: Option<u64>,
+}
+
+// Fills every service that names a `policy` with its template's fields,
+// leaving the service's own values in place. Runs on the raw JSON before it is
+// parsed, so the runtime only ever sees flat services. Returns whether any
+// service used a template.
+pub fn resolve(root: &mut Map<String, Value>) -> Result<bool> {
+    let templates = match root.get("policy_templates") {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::Object(templates)) => templates.clone(),
+        Some(_) => bail!("policy_templates must be an object"),
+    };
+    let mut resolved = Map::new();
+    for name in templates.keys() {
+        let template = flatten(&templates, name, &mut Vec::new())?;
+        resolved.insert(name.clone(), Value::Object(template));
+    }
+
+    let mut applied = false;
+    for path in ["upstream_services", "egress.services"] {
+        let services = path.split('.').try_fold(&mut *root, |object, key| match object.get_mut(key) {
+            Some(Value::Object(child)) => Some(child),
+            _ => None,
+        });
+        let Some(services) = services else {
+            continue;
+        };
+        for (service_name, service) in services.iter_mut() {
+            let Some(service) = service.as_object_mut() else {
+                continue;
+            };
+            let policy = match service.get("policy") {
+                None | Some(Value::Null) => continue,
+                Some(Value::String(policy)) => policy.clone(),
+                Some(_) => bail!("{}.{}.policy must be a template name", path, service_name),
+            };
+            let Some(Value::Object(template)) = resolved.get(&policy) else {
+                bail!("{}.{}: policy {:?} is not a defined policy template", path, service_name, policy);
+            };
+            let mut merged = template.clone();
+            merge(&mut merged, std::mem::take(service));
+            *service = merged;
+            applied = true;
+        }
+    }
+    Ok(applied)
+}
+
+// The template's fields with everything it extends folded in underneath.
+fn flatten(templates: &Map<String, Value>, name: &str, chain: &mut Vec<String>) -> Result<Map<String, Value>> {
+    if chain.iter().any(|seen| seen == name) {
+        chain.push(name.to_string());
+        bail!("policy template inheritance cycle: {}", chain.join(" -> "));
+    }
+    let Some(template) = templates.get(name) else {
+        bail!(
+            "policy template {:?} extends {:?}, which is not defined",
+            chain.last().map(String::as_str).unwrap_or_default(),
+            name
+        );
+    };
+    let template = template
+        .as_object()
+        .with_context(|| format!("policy_templates.{} must be an object", name))?;
+    chain.push(name.to_string());
+
+    let mut fields = Map::new();
+    for (field, value) in template {
+        if field == "extends" || value.is_null() {
+            continue;
+        }
+        if !POLICY_FIELDS.contains(&field.as_str()) {
+            bail!("policy_templates.{}: {:?} is not a policy field", name, field);
+        }
+        fields.insert(field.clone(), value.clone());
+    }
+    let mut flat = match template.get("extends") {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::String(parent)) => flatten(templates, parent, chain)?,
+        Some(_) => bail!("policy_templates.{}.extends must be a template name", name),
+    };
+    merge(&mut flat, fields);
+    chain.pop();
+    Ok(flat)
+}
+
+// Fields in `over` win; objects present on both sides are merged key by key.
+fn merge(base: &mut Map<String, Value>, over: Map<String, Value>) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(base)), Value::Object(over)) => merge(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// What a service runs with once its template is applied.
+#[derive(Debug, Clone, Serialize)]
+pub struct EffectivePolicy {
+    pub service: String,
+    pub direction: &'static str,
+    pub policy: Option<String>,
+    pub endpoints: Vec<String>,
+    // The spelling each endpoint had in the config, where it differs.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub endpoints_as_written: HashMap<String, String>,
+    pub timeout_ms: u64,
+    pub max_timeout_ms: Option<u64>,
+    pub max_retries: u32,
+    pub circuit_breaker_threshold: u32,
+    pub breaker_probe: BreakerProbeConfig,
+    pub health_check_path: String,
+    pub health_check_timeout_ms: Option<u64>,
+    pub health_check_warn_latency_ms: Option<u64>,
+}
+
+impl EffectivePolicy {
+    pub fn of(direction: Direction, service: &UpstreamService) -> Self {
+        Self {
+            service: service.name.clone(),
+            direction: direction.label(),
+            policy: service.policy.clone(),
+            endpoints: service.endpoints.clone(),
+            endpoints_as_written: service.endpoint_originals.clone(),
+            timeout_ms: service.timeout_ms,
+            max_timeout_ms: service.max_timeout_ms,
+            max_retries: service.max_retries,
+            circuit_breaker_threshold: service.circuit_breaker_threshold,
+            breaker_probe: service.breaker_probe.clone(),
+            health_check_path: service.health_check_path.clone(),
+            health_check_timeout_ms: service.health_check_timeout_ms,
+            health_check_warn_latency_ms: service.health_check_warn_latency_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn resolved(mut config: Value) -> Result<Value> {
+        resolve(config.as_object_mut().unwrap())?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_service_fields_override_extended_templates() {
+        let config = resolved(json!({
+            "policy_templates": {
+                "base": {"timeout_ms": 1000, "max_retries": 1, "breaker_probe": {"mode": "live", "open_ms": 500}},
+                "critical": {"extends": "base", "max_retries": 5, "breaker_probe": {"mode": "synthetic"}},
+            },
+            "upstream_services": {
+                "a": {"policy": "critical", "timeout_ms": 250, "breaker_probe": {"open_ms": 100}},
+                "b": {"timeout_ms": 9},
+            },
+        }))
+        .unwrap();
+
+        let a = &config["upstream_services"]["a"];
+        assert_eq!(a["timeout_ms"], 250);
+        assert_eq!(a["max_retries"], 5);
+        assert_eq!(a["breaker_probe"], json!({"mode": "synthetic", "open_ms": 100}));
+        assert_eq!(a["policy"], "critical");
+        assert_eq!(config["upstream_services"]["b"], json!({"timeout_ms": 9}));
+    }
+
+    #[test]
+    fn test_undefined_and_circular_templates_rejected() {
+        let error = resolved(json!({
+            "upstream_services": {"a": {"policy": "missing"}},
+        }))
+        .unwrap_err();
+        assert_eq!(error.to_string(), "upstream_services.a: policy \"missing\" is not a defined policy template");
+
+        let error = resolved(json!({
+            "policy_templates": {"x": {"extends": "y"}, "y": {"extends": "x"}},
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("inheritance cycle"), "{}", error);
+
+        let error = resolved(json!({
+            "policy_templates": {"x": {"extends": "nope"}},
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("\"nope\", which is not defined"), "{}", error);
+
+        let error = resolved(json!({
+            "policy_templates": {"x": {"endpoints": []}},
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("not a policy field"), "{}", error);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    config::UpstreamService,
+    metrics::MetricsCollector,
+    upstream_client::{self, UpstreamClient},
+    upstream_timing::PhaseRecorder,
+};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::{future::join_all, stream, StreamExt};
+use http_body_util::BodyExt;
+use hyper::Request;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tracing::{debug, info};
+
+// Opens connections to a service's endpoints before traffic needs them: once
+// the first health sweep is done, then again every `refresh_interval_ms` so
+// parked connections do not idle out of the pool overnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrewarmConfig {
+    // Per healthy endpoint. Each takes its own concurrent request, so HTTP/1.1
+    // ends up with this many pooled connections.
+    pub connections: usize,
+    // HEAD or OPTIONS.
+    #[serde(default = "default_method")]
+    pub method: String,
+    // Defaults to the service's health check path.
+    #[serde(default)]
+    pub path: Option<String>,
+    // Endpoints warmed at the same time.
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+    // Zero warms only at startup.
+    #[serde(default = "default_refresh_interval_ms")]
+    pub refresh_interval_ms: u64,
+}
+
+fn default_method() -> String {
+    "HEAD".to_string()
+}
+
+fn default_parallelism() -> usize {
+    4
+}
+
+fn default_refresh_interval_ms() -> u64 {
+    60_000
+}
+
+impl PrewarmConfig {
+    pub fn method(&self) -> Result<reqwest::Method> {
+        let method = reqwest::Method::from_bytes(self.method.as_bytes())
+            .with_context(|| format!("invalid prewarm method {:?}", self.method))?;
+        anyhow::ensure!(
+            method == reqwest::Method::HEAD || method == reqwest::Method::OPTIONS,
+            "prewarm method must be HEAD or OPTIONS, not {}",
+            method
+        );
+        Ok(method)
+    }
+}
+
+// Pre-warm calls go through the service's pooled client but are reported only
+// here: the AI engine, the load balancer and the request metrics never see
+// them.
+pub async fn warm(client: &UpstreamClient, service: &UpstreamService, endpoints: &[String], metrics: &MetricsCollector) {
+    let Some(config) = &service.prewarm else {
+        return;
+    };
+    let Ok(method) = config.method() else {
+        return;
+    };
+    let path = config.path.as_deref().unwrap_or(&service.health_check_path);
+    let started = Instant::now();
+
+    let mut warming = Vec::new();
+    for endpoint in endpoints {
+        let client = client.clone();
+        let method = method.clone();
+        let url = format!("{}{}", endpoint, path);
+        let connections = config.connections;
+        warming.push(async move {
+            let calls = (0..connections).map(|_| warm_one(client.clone(), method.clone(), url.clone()));
+            join_all(calls).await
+        });
+    }
+    let outcomes: Vec<&'static str> = stream::iter(warming)
+        .buffer_unordered(config.parallelism.max(1))
+        .flat_map(stream::iter)
+        .collect()
+        .await;
+
+    for outcome in &outcomes {
+        metrics.record_prewarm(&service.name, outcome);
+    }
+    let opened = outcomes.iter().filter(|outcome| **outcome == "new").count();
+    info!(
+        service = %service.name,
+        endpoints = endpoints.len(),
+        opened,
+        failed = outcomes.iter().filter(|outcome| **outcome == "failed").count(),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "upstream connections pre-warmed"
+    );
+}
+
+// Whether the call opened a connection, found one parked, or failed.
+async fn warm_one(client: UpstreamClient, method: reqwest::Method, url: String) -> &'static str {
+    let recorder = PhaseRecorder::start();
+    let call = async {
+        let request = Request::builder().method(method).uri(&url).body(upstream_client::full_body(Bytes::new()))?;
+        client.send(request, client.timeout()).await
+    };
+    match recorder.scope(call).await {
+        Ok(response) => {
+            let headers_at = Instant::now();
+            // Reading to the end hands the connection back to the pool.
+            let _ = response.into_body().collect().await;
+            if recorder.finish(headers_at, Instant::now()).reused {
+                "reused"
+            } else {
+                "new"
+            }
+        }
+        Err(e) => {
+            debug!(url = %url, error = format!("{:#}", e), "prewarm call failed");
+            "failed"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(method: &str) -> PrewarmConfig {
+        serde_json::from_value(serde_json::json!({"connections": 2, "method": method})).unwrap()
+    }
+
+    #[test]
+    fn test_only_bodiless_methods() {
+        assert_eq!(config("HEAD").method().unwrap(), reqwest::Method::HEAD);
+        assert_eq!(config("OPTIONS").method().unwrap(), reqwest::Method::OPTIONS);
+        assert!(config("GET").method().is_err());
+        assert_eq!(config("HEAD").refresh_interval_ms, 60_000);
+    }
+}
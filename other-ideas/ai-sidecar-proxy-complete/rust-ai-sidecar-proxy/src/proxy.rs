@@ -1,362 +1,7749 @@
 use crate::{
-    config::{Config, UpstreamService},
-    ai::{AIEngine, RequestMetrics},
+    config::{invalid_config, Config, ConfigError, UpstreamService},
+    config_reload::ReloadDiff,
+    config_history::{ConfigHistory, Rollback},
+    ai::{AIDecision, AIEngine, RequestMetrics, SelectionMode},
     metrics::MetricsCollector,
-    load_balancer::LoadBalancer,
-    circuit_breaker::CircuitBreaker,
+    load_balancer::{LoadBalancer, LoadBalancers},
+    auto_weight::AutoWeigher,
+    circuit_breaker::{self, Admission, CircuitBreaker, CircuitBreakerState, HalfOpenProbeMode},
+    rate_limiter::RateLimiter,
+    quota::Quotas,
     health_checker::HealthChecker,
+    sniff::{self, Preface},
+    middleware::{LoggingMiddleware, Middleware, RequestContext},
+    fd_monitor::{self, FdMonitor},
+    overload::OverloadMonitor,
+    standby::Standby,
+    upstream_client::{self, ClientCache, UpstreamBody, UpstreamClient},
+    prewarm,
+    eager_init,
+    buffer_budget::{self, BufferBudget, BufferError, BudgetedBody},
+    dedupe::{self, CachedResponse, Dedupe, DedupeCache, Leader},
+    request_body::{RequestBody, StreamFault, StreamedBody},
+    response_body::RelayedBody,
+    connection_tasks::ConnectionTasks,
+    content_coding::{self, DecodeError},
+    supervisor::TaskSupervisor,
+    tls::TlsTerminator,
+    access::{AccessRequest, AccessRules, AccessRulesConfig, RuleAction},
+    admin_auth::{AdminAuth, AdminScope},
+    experiments::{Experiments, ExperimentsConfig},
+    mesh_metadata::MeshMetadata,
+    policy_templates::EffectivePolicy,
+    request_target::{self, Disposition, TargetForm},
+    strict_http::StrictHttpIo,
+    response_headers::ResponseHeaderPolicies,
+    header_values::HeaderValueGuard,
+    header_rules::{self, Side},
+    interpolate::Scope,
+    routability::{self, ServiceView, Snapshot},
+    routes::{RouteMatch, RouteRule, Routes},
+    path_templates::Operations,
+    upstream_timing::PhaseRecorder,
+    server_timing::{HopTimer, ServerTiming, TimingDetail},
+    address_family,
+    body_pipeline::{self, Framing, ResponseBodyPipeline},
+    conditional::{self, Validators},
+    checksum::{BodyChecksums, MismatchAction, RouteChecksum, TeeHash, Verdict},
+    egress::Direction,
+    archive::{self, ArchiveRecord, ArchivedBody, Archiver},
+    capture::{CaptureRequest, CaptureStore, CapturedExchange, Sampled},
+    client_timeouts::{BodyReadError, IdleTimeoutBody, WriteTimeoutIo},
+    early_hints::{EarlyHints, EarlyHintsIo},
+    drain::Drain,
+    lifecycle::{Lifecycle, LifecycleHook},
+    clock::{Clock, SystemClock},
+    endpoint_gc::{EndpointGc, EndpointRegistry},
+    endpoint_url,
+    upstream_response::{self, HeaderCheck},
+    consul::{self, CatalogSource, ConsulDiscoveryConfig},
+    stats_history::{self, MinuteRow},
+    storage::{self, KeyValueStore},
+    websocket,
 };
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes::{self, ApiServer, KubernetesDiscoveryConfig, SliceSource};
 
 use hyper::{
-    body::Incoming, 
+    body::{Frame, Incoming},
+    header::{self, HeaderMap},
     service::service_fn, 
     Request, 
     Response, 
     StatusCode,
-    Method,
+    Uri,
 };
 use hyper_util::{
-    rt::{TokioIo, TokioExecutor},
+    rt::{TokioExecutor, TokioIo, TokioTimer},
     server::conn::auto::Builder as ServerBuilder,
 };
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
 use bytes::Bytes;
 use std::{
     collections::HashMap,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH, Instant},
-    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH, Instant},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
 };
-use tokio::net::TcpListener;
-use tracing::{info, error, warn, debug};
-use anyhow::Result;
+#[cfg(feature = "metrics-prometheus")]
+use prometheus::Registry;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Notify,
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{field, info, info_span, error, warn, debug, Instrument, Span};
+use anyhow::{Context, Result};
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, hyper::Error>;
+
+struct ProxyState {
+    config: Config,
+    // Re-read by /admin/config/reload; unset when the config was built in code.
+    config_path: Option<PathBuf>,
+    // Environment variables laid over the file again on each reload.
+    env_prefix: Option<String>,
+    upstreams: RwLock<Arc<Upstreams>>,
+    // Grows through POST /admin/routes; rebuilt from the config on restart.
+    routes: RwLock<Arc<Routes>>,
+    operations: Operations,
+    // Serializes endpoint updates so the health checker and the registry see
+    // them in the same order.
+    endpoint_updates: tokio::sync::Mutex<()>,
+    endpoints: Arc<EndpointRegistry>,
+    endpoint_gc: Arc<EndpointGc>,
+    upstream_clients: ClientCache,
+    overload: Arc<OverloadMonitor>,
+    // Per-request timeout caps set through the admin API, by ingress
+    // service, in place of the service's `max_timeout_ms`.
+    timeout_overrides: tokio::sync::RwLock<HashMap<String, u64>>,
+    ai_engine: Arc<AIEngine>,
+    metrics: Arc<MetricsCollector>,
+    load_balancers: Arc<LoadBalancers>,
+    // Egress destinations get their own breakers, even if a name is shared
+    // with an ingress service.
+    egress_breakers: HashMap<String, CircuitBreaker>,
+    buffer_budget: Arc<BufferBudget>,
+    connection_tasks: ConnectionTasks,
+    supervisor: Arc<TaskSupervisor>,
+    access_rules: RwLock<Arc<AccessRules>>,
+    experiments: RwLock<Arc<Experiments>>,
+    mesh_metadata: OnceLock<MeshMetadata>,
+    body_checksums: OnceLock<BodyChecksums>,
+    response_headers: OnceLock<ResponseHeaderPolicies>,
+    header_values: HeaderValueGuard,
+    server_timing: OnceLock<ServerTiming>,
+    admin_auth: OnceLock<AdminAuth>,
+    // Keyed by client IP; unset when the config sets no rate_limit.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Unset when the config sets no quota.
+    quotas: Option<Arc<Quotas>>,
+    // Unset unless enable_request_dedupe is.
+    dedupe: Option<DedupeCache>,
+    // Services applied by reloads, and the guardrail that reverts them.
+    config_history: ConfigHistory,
+    // Unset when no route is archived.
+    archiver: OnceLock<Archiver>,
+    // Unset unless capture is enabled.
+    captures: OnceLock<CaptureStore>,
+    clock: Arc<dyn Clock>,
+    drain: Drain,
+    standby: Standby,
+    health_checker: Arc<HealthChecker>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    lifecycle: Lifecycle,
+}
+
+impl ProxyState {
+    fn upstreams(&self) -> Arc<Upstreams> {
+        self.upstreams.read().unwrap().clone()
+    }
+}
+
+// The configured services, with endpoints as last set through
+// /admin/endpoints, and their breakers. Replaced as a whole, so a request
+// never pairs a service with a breaker from another generation.
+struct Upstreams {
+    services: HashMap<String, UpstreamService>,
+    breakers: HashMap<String, Arc<CircuitBreaker>>,
+}
+
+impl Upstreams {
+    // A service whose breaker settings are unchanged keeps its breaker, and
+    // the state it has built up, from `previous`.
+    fn new(services: HashMap<String, UpstreamService>, previous: Option<&Upstreams>) -> Self {
+        let breakers = services
+            .iter()
+            .map(|(name, service)| {
+                let kept = previous.and_then(|previous| {
+                    let old = previous.services.get(name)?;
+                    let unchanged = old.circuit_breaker_threshold == service.circuit_breaker_threshold
+                        && old.breaker_probe == service.breaker_probe;
+                    unchanged.then(|| previous.breakers.get(name).cloned()).flatten()
+                });
+                let breaker = kept.unwrap_or_else(|| {
+                    Arc::new(CircuitBreaker::new(service.circuit_breaker_threshold).with_probe_config(&service.breaker_probe))
+                });
+                (name.clone(), breaker)
+            })
+            .collect();
+        Self { services, breakers }
+    }
+}
+
+pub struct ProxyServer {
+    state: Arc<ProxyState>,
+    fd_monitor: Arc<FdMonitor>,
+    shutdown: CancellationToken,
+    shutdown_grace: Duration,
+}
+
+// Assembles a ProxyServer for applications that embed it; anything not set
+// falls back to what the standalone binary uses.
+pub struct ProxyServerBuilder {
+    config: Option<Config>,
+    ai_engine: Option<Arc<AIEngine>>,
+    load_balancer: Option<LoadBalancer>,
+    metrics: Option<Arc<MetricsCollector>>,
+    #[cfg(feature = "metrics-prometheus")]
+    registry: Option<Registry>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    hooks: Vec<LifecycleHook>,
+    shutdown: Option<CancellationToken>,
+    shutdown_grace: Duration,
+    clock: Option<Arc<dyn Clock>>,
+    config_path: Option<PathBuf>,
+    env_prefix: Option<String>,
+    standby: bool,
+    storage: Option<Arc<dyn KeyValueStore>>,
+}
+
+impl Default for ProxyServerBuilder {
+    fn default() -> Self {
+        Self {
+            config: None,
+            ai_engine: None,
+            load_balancer: None,
+            metrics: None,
+            #[cfg(feature = "metrics-prometheus")]
+            registry: None,
+            middleware: Vec::new(),
+            hooks: Vec::new(),
+            shutdown: None,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            clock: None,
+            config_path: None,
+            env_prefix: None,
+            standby: false,
+            storage: None,
+        }
+    }
+}
+
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+// How long the accept loop waits after a failed accept that was not the
+// client's doing.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// How often pre-warm tasks check whether a refresh is due.
+const PREWARM_TICK: Duration = Duration::from_secs(1);
+
+impl ProxyServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn ai_engine(mut self, ai_engine: Arc<AIEngine>) -> Self {
+        self.ai_engine = Some(ai_engine);
+        self
+    }
+
+    // Endpoint selector used when the AI engine has no opinion, for services
+    // that do not configure a strategy of their own.
+    pub fn load_balancer(mut self, load_balancer: LoadBalancer) -> Self {
+        self.load_balancer = Some(load_balancer);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<MetricsCollector>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // Registers the proxy's metrics into an existing registry instead of a
+    // private one. Ignored when `metrics` is also set.
+    #[cfg(feature = "metrics-prometheus")]
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Started with the proxy's own hooks before it accepts connections, and
+    // stopped in reverse once connections have drained.
+    pub fn lifecycle_hook(mut self, hook: LifecycleHook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    // Cancelling the token has the same effect as `ProxyHandle::shutdown`.
+    pub fn shutdown_token(mut self, token: CancellationToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    // How long in-flight connections get to finish once shutdown starts.
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    // Time source for state that ages out, such as removed endpoints.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Starts as a standby that serves no traffic until POST /admin/promote.
+    pub fn standby(mut self, standby: bool) -> Self {
+        self.standby = standby;
+        self
+    }
+
+    // The store for state kept across restarts, already opened from the
+    // config's `storage` section. Unset opens one from that section.
+    pub fn storage(mut self, storage: Arc<dyn KeyValueStore>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    // The file `config` was read from, re-read on POST /admin/config/reload.
+    pub fn config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    // `{PREFIX}_...` overrides that `config` was merged with, merged again
+    // over the file on reload.
+    pub fn env_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.env_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn build(mut self) -> Result<ProxyServer> {
+        if let Some(config) = &mut self.config {
+            ProxyServer::prepare_config(config)?;
+        }
+        #[cfg(feature = "metrics-prometheus")]
+        let metrics = match (self.metrics.take(), self.registry.take()) {
+            (Some(metrics), _) => metrics,
+            (None, Some(registry)) => Arc::new(MetricsCollector::with_registry(registry)?),
+            (None, None) => Arc::new(MetricsCollector::new()),
+        };
+        #[cfg(not(feature = "metrics-prometheus"))]
+        let metrics = self.metrics.take().unwrap_or_else(|| Arc::new(MetricsCollector::new()));
+
+        ProxyServer::assemble(self, metrics)
+    }
+}
+
+// Returned by `ProxyServer::run`; the server keeps running if this is dropped.
+pub struct ProxyHandle {
+    local_addr: SocketAddr,
+    egress_addr: Option<SocketAddr>,
+    shutdown: CancellationToken,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ProxyHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn egress_addr(&self) -> Option<SocketAddr> {
+        self.egress_addr
+    }
+
+    // Stops accepting connections and lets in-flight ones finish.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    // Resolves once the listener is closed, connections have drained (or the
+    // grace period ran out) and background tasks are stopped.
+    pub async fn await_terminated(self) -> Result<()> {
+        self.task.await?
+    }
+}
+
+impl ProxyServer {
+    pub fn new(
+        mut config: Config,
+        ai_engine: Arc<AIEngine>,
+        metrics: Arc<MetricsCollector>,
+    ) -> Result<Self> {
+        Self::prepare_config(&mut config)?;
+        let builder = Self::builder().config(config).ai_engine(ai_engine);
+        Self::assemble(builder, metrics)
+    }
+
+    pub fn builder() -> ProxyServerBuilder {
+        ProxyServerBuilder::default()
+    }
+
+    // Both constructors go through here, so a config names its endpoints the
+    // same way whichever built the server.
+    fn prepare_config(config: &mut Config) -> Result<()> {
+        config.canonicalize_endpoints();
+        let validation = config.validation();
+        Self::log_config_warnings(&validation.warnings);
+        if validation.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_config(&validation.errors))
+        }
+    }
+
+    fn log_config_warnings(warnings: &[ConfigError]) {
+        for warning in warnings {
+            warn!(path = warning.path(), "config warning: {}", warning.message());
+        }
+    }
+
+    // Caps the ingress service's per-request timeout at `ms` until a reload
+    // removes the service. False when there is no such service.
+    pub async fn set_service_timeout_override(&self, service: &str, ms: u64) -> bool {
+        Self::set_timeout_override(&self.state, service, Some(ms)).await
+    }
+
+    async fn set_timeout_override(state: &ProxyState, service: &str, ms: Option<u64>) -> bool {
+        if !state.upstreams().services.contains_key(service) {
+            return false;
+        }
+        let mut overrides = state.timeout_overrides.write().await;
+        match ms {
+            Some(ms) => overrides.insert(service.to_string(), ms),
+            None => overrides.remove(service),
+        };
+        true
+    }
+
+    // Everything but the metrics comes from the builder, with defaults for
+    // whatever it left unset.
+    fn assemble(builder: ProxyServerBuilder, metrics: Arc<MetricsCollector>) -> Result<Self> {
+        let config = builder.config.unwrap_or_default();
+        let ai_engine = builder.ai_engine.unwrap_or_else(|| Arc::new(AIEngine::new()));
+        // The config decides where the switch starts; PUT /admin/ai/enabled
+        // moves it afterwards.
+        ai_engine.set_enabled(config.ai_config.enabled);
+        metrics.set_ai_enabled(cfg!(feature = "ai") && config.ai_config.enabled);
+        metrics.history().configure(&config.metrics_config.history);
+        let mut load_balancer = builder.load_balancer.unwrap_or_default();
+        for service in config.upstream_services.values() {
+            if let Some(weights) = &service.endpoint_weights {
+                load_balancer = load_balancer.with_service_weights(&service.name, weights);
+            }
+        }
+        let load_balancers = Arc::new(LoadBalancers::new(load_balancer, &config.upstream_services));
+        ai_engine.set_endpoint_weights(Self::endpoint_weights(&config.upstream_services, &config));
+        let middleware = builder.middleware;
+        let lifecycle = Lifecycle::new();
+        for hook in builder.hooks {
+            lifecycle.register(hook);
+        }
+        let shutdown = builder.shutdown.unwrap_or_default();
+        let shutdown_grace = builder.shutdown_grace;
+        let clock = builder.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let storage = match (builder.storage, &config.storage) {
+            (Some(storage), _) => Some(storage),
+            (None, Some(storage_config)) => {
+                Some(storage::open(storage_config, clock.clone()).context("opening storage")?)
+            }
+            (None, None) => None,
+        };
+        
+        let header_values = HeaderValueGuard::new(config.proxy_config.max_header_value_bytes);
+        let egress_breakers = config
+            .egress
+            .services
+            .iter()
+            .map(|(name, service)| {
+                let breaker = CircuitBreaker::new(service.circuit_breaker_threshold).with_probe_config(&service.breaker_probe);
+                (name.clone(), breaker)
+            })
+            .collect();
+        
+        let health_checker = Arc::new(HealthChecker::new(
+            config.upstream_services.clone(),
+            ai_engine.clone(),
+        ));
+
+        let fd_monitor = Arc::new(FdMonitor::new(
+            config.proxy_config.fd_monitor.clone(),
+            metrics.clone(),
+        ));
+        let overload = Arc::new(OverloadMonitor::new(
+            config.proxy_config.overload.clone(),
+            metrics.clone(),
+        ));
+
+        let buffer_budget = Arc::new(BufferBudget::new(
+            config.proxy_config.max_buffered_bytes,
+            metrics.clone(),
+        ));
+
+        let connection_tasks = ConnectionTasks::new(config.proxy_config.max_connections, metrics.clone());
+
+        let supervisor = Arc::new(TaskSupervisor::new(
+            config.proxy_config.supervisor.clone(),
+            metrics.clone(),
+        ));
+
+        let drain = Drain::new(config.proxy_config.drain.deregistration.is_some());
+        let standby = Standby::new(builder.standby, metrics.clone());
+
+        let upstreams = Upstreams::new(config.upstream_services.clone(), None);
+        let routes = Routes::new(&config.routes)
+            .context("invalid route rewrite")?
+            .with_default_service(config.default_service.clone());
+        let operations = Operations::new(config.metrics_config.max_operations);
+        let upstream_clients = ClientCache::new(config.proxy_config.upstream_pool.clone(), metrics.clone());
+        let rate_limiter = config.rate_limit.clone().map(|rate_limit| Arc::new(RateLimiter::new(rate_limit)));
+        let quotas = config
+            .quota
+            .as_ref()
+            .map(|quota| Arc::new(Quotas::from_config(quota, clock.clone(), storage.clone())));
+        let dedupe = config.proxy_config.enable_request_dedupe.then(|| {
+            let excluded = config
+                .proxy_config
+                .request_dedupe_excluded_headers
+                .iter()
+                .chain(config.quota.as_ref().map(|quota| &quota.key_header))
+                .filter_map(|name| header::HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect();
+            DedupeCache::new(Duration::from_millis(config.proxy_config.request_dedupe_window_ms), excluded)
+        });
+        let config_history = ConfigHistory::new(
+            config.proxy_config.config_rollback.clone(),
+            clock.clone(),
+            upstreams.services.clone(),
+        );
+        let endpoints = Arc::new(EndpointRegistry::new(clock.clone()));
+        endpoints.update(Self::all_endpoints(&upstreams.services, &config));
+        let endpoint_gc = Arc::new(EndpointGc::new(
+            config.proxy_config.endpoint_gc.clone(),
+            endpoints.clone(),
+            ai_engine.clone(),
+            health_checker.clone(),
+            load_balancers.clone(),
+            metrics.clone(),
+        ));
+
+        Ok(Self {
+            state: Arc::new(ProxyState {
+                config,
+                config_path: builder.config_path,
+                env_prefix: builder.env_prefix,
+                upstreams: RwLock::new(Arc::new(upstreams)),
+                routes: RwLock::new(Arc::new(routes)),
+                operations,
+                endpoint_updates: tokio::sync::Mutex::new(()),
+                endpoints,
+                endpoint_gc,
+                upstream_clients,
+                overload,
+                timeout_overrides: tokio::sync::RwLock::new(HashMap::new()),
+                ai_engine,
+                metrics,
+                load_balancers,
+                egress_breakers,
+                buffer_budget,
+                connection_tasks,
+                supervisor,
+                access_rules: RwLock::new(Arc::new(AccessRules::default())),
+                experiments: RwLock::new(Arc::new(Experiments::default())),
+                mesh_metadata: OnceLock::new(),
+                body_checksums: OnceLock::new(),
+                response_headers: OnceLock::new(),
+                header_values,
+                server_timing: OnceLock::new(),
+                admin_auth: OnceLock::new(),
+                rate_limiter,
+                quotas,
+                dedupe,
+                config_history,
+                archiver: OnceLock::new(),
+                captures: OnceLock::new(),
+                clock,
+                drain,
+                standby,
+                health_checker,
+                middleware,
+                lifecycle,
+            }),
+            fd_monitor,
+            shutdown,
+            shutdown_grace,
+        })
+    }
+
+    // Background work for one upstream service: pre-warming and synthetic
+    // breaker probes, when configured. Both stop once the service is gone.
+    fn start_service_tasks(state: &Arc<ProxyState>, service: &UpstreamService) -> Result<()> {
+        if let Some(prewarm) = &service.prewarm {
+            prewarm.method().with_context(|| format!("service {:?}", service.name))?;
+            Self::start_prewarm(state, &service.name);
+        }
+        if service.breaker_probe.mode == HalfOpenProbeMode::Synthetic {
+            let interval = Duration::from_millis(service.breaker_probe.synthetic_interval_ms.max(1));
+            Self::start_breaker_probes(state, &service.name, interval);
+        }
+        if service.auto_weight.is_some() {
+            Self::start_auto_weight(state, &service.name);
+        }
+        #[cfg(feature = "kubernetes")]
+        if let Some(discovery) = &service.kubernetes {
+            Self::start_kubernetes_discovery(state, &service.name, discovery.clone())?;
+        }
+        if let Some(discovery) = &service.consul {
+            Self::start_consul_discovery(state, &service.name, discovery.clone())?;
+        }
+        Ok(())
+    }
+
+    // Keeps the service's endpoints in step with the Consul catalog until a
+    // reload removes the service or changes how it is discovered.
+    fn start_consul_discovery(state: &Arc<ProxyState>, service_name: &str, discovery: ConsulDiscoveryConfig) -> Result<()> {
+        let agent = consul::Agent::new(&discovery).with_context(|| format!("service {:?}", service_name))?;
+        let source: Arc<dyn CatalogSource> = Arc::new(agent);
+        let task_state = state.clone();
+        let service_name = service_name.to_string();
+        state.supervisor.spawn(&format!("consul:{}", service_name), false, move |heartbeat| {
+            let state = task_state.clone();
+            let service_name = service_name.clone();
+            let discovery = discovery.clone();
+            let source = source.clone();
+            async move {
+                let current = |state: &ProxyState| {
+                    let upstreams = state.upstreams();
+                    upstreams.services.get(&service_name).is_some_and(|service| service.consul.as_ref() == Some(&discovery))
+                };
+                let publish = |endpoints: Vec<String>| {
+                    let state = state.clone();
+                    let service_name = service_name.clone();
+                    async move { current(&state) && Self::set_endpoints(&state, &service_name, endpoints).await }
+                };
+                // A blocking query can hold for minutes, so this is what
+                // notices the service went away in the meantime.
+                let replaced = async {
+                    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+                    loop {
+                        ticks.tick().await;
+                        heartbeat.beat();
+                        if !current(&state) {
+                            return;
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = consul::follow(&*source, &discovery, publish) => {}
+                    _ = replaced => {}
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Keeps the service's endpoints in step with its Kubernetes Service until
+    // a reload removes the service or changes how it is discovered.
+    #[cfg(feature = "kubernetes")]
+    fn start_kubernetes_discovery(
+        state: &Arc<ProxyState>,
+        service_name: &str,
+        discovery: KubernetesDiscoveryConfig,
+    ) -> Result<()> {
+        let api_server = ApiServer::new(&discovery).with_context(|| format!("service {:?}", service_name))?;
+        let source: Arc<dyn SliceSource> = Arc::new(api_server);
+        let task_state = state.clone();
+        let service_name = service_name.to_string();
+        state.supervisor.spawn(&format!("kubernetes:{}", service_name), false, move |heartbeat| {
+            let state = task_state.clone();
+            let service_name = service_name.clone();
+            let discovery = discovery.clone();
+            let source = source.clone();
+            async move {
+                let current = |state: &ProxyState| {
+                    let upstreams = state.upstreams();
+                    upstreams.services.get(&service_name).is_some_and(|service| service.kubernetes.as_ref() == Some(&discovery))
+                };
+                let publish = |endpoints: Vec<String>| {
+                    let state = state.clone();
+                    let service_name = service_name.clone();
+                    async move { current(&state) && Self::set_endpoints(&state, &service_name, endpoints).await }
+                };
+                let replaced = async {
+                    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+                    loop {
+                        ticks.tick().await;
+                        heartbeat.beat();
+                        if !current(&state) {
+                            return;
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = kubernetes::follow(&*source, &discovery, publish) => {}
+                    _ = replaced => {}
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Drops the state of clients that have gone quiet, so one entry per IP
+    // ever seen does not pile up.
+    fn start_rate_limit_cleanup(state: &Arc<ProxyState>, rate_limiter: Arc<RateLimiter>) {
+        state.supervisor.spawn("rate_limit_cleanup", false, move |heartbeat| {
+            let rate_limiter = rate_limiter.clone();
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(60));
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    rate_limiter.cleanup_expired_buckets().await;
+                }
+            }
+        });
+    }
+
+    // Folds finished minutes of endpoint stats into export rows.
+    fn start_stats_history(state: &Arc<ProxyState>) {
+        let task_state = state.clone();
+        state.supervisor.spawn("stats_history", false, move |heartbeat| {
+            let state = task_state.clone();
+            async move {
+                let mut interval = tokio::time::interval(state.metrics.history().tick());
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    state.metrics.history().aggregate(state.clock.wall());
+                }
+            }
+        });
+    }
+
+    fn start_quota_persistence(state: &Arc<ProxyState>, quotas: Arc<Quotas>) {
+        state.supervisor.spawn("quota_persistence", false, move |heartbeat| {
+            let quotas = quotas.clone();
+            async move {
+                let mut interval = tokio::time::interval(quotas.persist_interval());
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    if let Err(e) = quotas.persist().await {
+                        warn!(error = %e, "failed to persist quota usage");
+                    }
+                }
+            }
+        });
+    }
+
+    // Feeds AI endpoint scores into the service's balancer weights.
+    fn start_auto_weight(state: &Arc<ProxyState>, service_name: &str) {
+        let task_state = state.clone();
+        let service_name = service_name.to_string();
+        state.supervisor.spawn(&format!("auto_weight:{}", service_name), false, move |heartbeat| {
+            let state = task_state.clone();
+            let service_name = service_name.clone();
+            async move {
+                let mut weigher: Option<AutoWeigher> = None;
+                loop {
+                    let interval = weigher.as_ref().map_or(Duration::ZERO, AutoWeigher::interval);
+                    tokio::time::sleep(interval).await;
+                    heartbeat.beat();
+                    // A reload can change the settings or remove the service.
+                    let upstreams = state.upstreams();
+                    let Some(service) = upstreams.services.get(&service_name) else {
+                        return;
+                    };
+                    let Some(config) = &service.auto_weight else {
+                        return;
+                    };
+                    let weigher = match &mut weigher {
+                        Some(weigher) if weigher.config() == config => weigher,
+                        _ => {
+                            weigher = Some(AutoWeigher::new(config.clone()));
+                            continue;
+                        }
+                    };
+                    let balancer = state.load_balancers.for_service(&service_name);
+                    let changes = weigher.tune(&service_name, &service.endpoints, &state.ai_engine, &balancer).await;
+                    for change in changes {
+                        info!(
+                            service = %service_name,
+                            endpoint = %change.endpoint,
+                            from = change.from,
+                            to = change.to,
+                            score = change.score,
+                            "auto weight changed"
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    // Warms the service's healthy endpoints once the first health sweep is in,
+    // then on its refresh interval.
+    fn start_prewarm(state: &Arc<ProxyState>, service_name: &str) {
+        let task_state = state.clone();
+        let service_name = service_name.to_string();
+        state.supervisor.spawn(&format!("prewarm:{}", service_name), false, move |heartbeat| {
+            let state = task_state.clone();
+            let service_name = service_name.clone();
+            async move {
+                state.health_checker.wait_for_sweep().await;
+                let mut ticks = tokio::time::interval(PREWARM_TICK);
+                let mut next_warm = Some(Instant::now());
+                loop {
+                    ticks.tick().await;
+                    heartbeat.beat();
+                    if next_warm.is_none_or(|at| Instant::now() < at) {
+                        continue;
+                    }
+                    let upstreams = state.upstreams();
+                    let Some(service) = upstreams.services.get(&service_name) else {
+                        return;
+                    };
+                    let Some(config) = &service.prewarm else {
+                        return;
+                    };
+                    next_warm = (config.refresh_interval_ms > 0)
+                        .then(|| Instant::now() + Duration::from_millis(config.refresh_interval_ms));
+                    let client = match state.upstream_clients.get(Direction::Ingress, service) {
+                        Ok(client) => client,
+                        Err(e) => {
+                            warn!(service = %service_name, error = format!("{:#}", e), "cannot pre-warm without a client");
+                            continue;
+                        }
+                    };
+                    let endpoints = state.health_checker.get_healthy_endpoints(&service_name).await;
+                    prewarm::warm(&client, service, &endpoints, &state.metrics).await;
+                }
+            }
+        });
+    }
+
+    // While the service's breaker is half-open, sends its health check to each
+    // endpoint in turn and feeds the outcome to the breaker in place of live
+    // traffic.
+    fn start_breaker_probes(state: &Arc<ProxyState>, service_name: &str, interval: Duration) {
+        let task_state = state.clone();
+        let service_name = service_name.to_string();
+        state.supervisor.spawn(&format!("breaker_probe:{}", service_name), false, move |heartbeat| {
+            let state = task_state.clone();
+            let service_name = service_name.clone();
+            async move {
+                let mut ticks = tokio::time::interval(interval);
+                let mut next_endpoint = 0usize;
+                loop {
+                    ticks.tick().await;
+                    heartbeat.beat();
+                    // Looked up every tick, since a reload can replace the
+                    // breaker or remove the service.
+                    let Some(breaker) = state.upstreams().breakers.get(&service_name).cloned() else {
+                        return;
+                    };
+                    if breaker.probe_mode() != HalfOpenProbeMode::Synthetic {
+                        return;
+                    }
+                    let mode = breaker.probe_mode().label();
+                    if breaker.current_state().await != CircuitBreakerState::HalfOpen {
+                        continue;
+                    }
+                    let Some(permit) = breaker.try_probe() else {
+                        continue;
+                    };
+                    let endpoint = {
+                        let upstreams = state.upstreams();
+                        let Some(service) = upstreams.services.get(&service_name) else {
+                            return;
+                        };
+                        if service.endpoints.is_empty() {
+                            continue;
+                        }
+                        next_endpoint = next_endpoint.wrapping_add(1);
+                        service.endpoints[next_endpoint % service.endpoints.len()].clone()
+                    };
+                    if state.health_checker.probe(&service_name, &endpoint).await {
+                        permit.succeeded().await;
+                        state.metrics.record_breaker_probe(&service_name, mode, "success");
+                    } else {
+                        permit.failed().await;
+                        state.metrics.record_breaker_probe(&service_name, mode, "failure");
+                    }
+                    let breaker_state = breaker.get_state().await;
+                    debug!(service = %service_name, endpoint = %endpoint, state = ?breaker_state, "synthetic breaker probe");
+                }
+            }
+        });
+    }
+
+    // Ingress endpoints as currently set, plus the fixed egress destinations.
+    fn all_endpoints(services: &HashMap<String, UpstreamService>, config: &Config) -> Vec<String> {
+        services
+            .values()
+            .chain(config.egress.services.values())
+            .flat_map(|service| service.endpoints.iter().cloned())
+            .collect()
+    }
+
+    // Configured weights by service, for the AI engine to scale scores by.
+    fn endpoint_weights(services: &HashMap<String, UpstreamService>, config: &Config) -> HashMap<String, HashMap<String, u32>> {
+        services
+            .values()
+            .chain(config.egress.services.values())
+            .filter_map(|service| Some((service.name.clone(), service.endpoint_weights.clone()?)))
+            .collect()
+    }
+
+    // Serves on a listener the caller has already bound, in the background.
+    pub fn run(self, listener: TcpListener) -> Result<ProxyHandle> {
+        let local_addr = listener.local_addr()?;
+        let shutdown = self.shutdown.clone();
+        let task = tokio::spawn(async move { self.serve(listener).await });
+        Ok(ProxyHandle {
+            local_addr,
+            egress_addr: None,
+            shutdown,
+            task,
+        })
+    }
+
+    // Like `run`, also serving the application's outbound calls on `egress`.
+    pub fn run_with_egress(self, listener: TcpListener, egress: TcpListener) -> Result<ProxyHandle> {
+        let local_addr = listener.local_addr()?;
+        let egress_addr = egress.local_addr()?;
+        let shutdown = self.shutdown.clone();
+        let task = tokio::spawn(async move { self.serve_with_egress(listener, egress).await });
+        Ok(ProxyHandle {
+            local_addr,
+            egress_addr: Some(egress_addr),
+            shutdown,
+            task,
+        })
+    }
+
+    // Runs until the shutdown token is cancelled.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_listeners(listener, None).await
+    }
+
+    pub async fn serve_with_egress(&self, listener: TcpListener, egress: TcpListener) -> Result<()> {
+        self.serve_listeners(listener, Some(egress)).await
+    }
+
+    async fn serve_listeners(&self, listener: impl Listener, egress: Option<TcpListener>) -> Result<()> {
+        let addr = listener.local_addr()?;
+        let ingress_label = addr.to_string();
+        let egress_label = match &egress {
+            Some(egress) => egress.local_addr()?.to_string(),
+            None => String::new(),
+        };
+        let config = &self.state.config;
+
+        let access_rules = AccessRules::compile(&config.access_rules)?;
+        *self.state.access_rules.write().unwrap() = Arc::new(access_rules);
+        let experiments = Experiments::compile(&config.experiments, |service| config.upstream_services.contains_key(service))?;
+        *self.state.experiments.write().unwrap() = Arc::new(experiments);
+        let mesh_metadata = MeshMetadata::from_config(&config.mesh_metadata)?;
+        let _ = self.state.mesh_metadata.set(mesh_metadata);
+        if let Some(deregistration) = &config.proxy_config.drain.deregistration {
+            deregistration.method()?;
+        }
+        // Egress destinations have no health check to send.
+        if let Some(service) = config
+            .egress
+            .services
+            .values()
+            .find(|service| service.breaker_probe.mode == HalfOpenProbeMode::Synthetic)
+        {
+            anyhow::bail!("egress service {:?}: synthetic breaker probes need a health-checked upstream service", service.name);
+        }
+        let body_checksums = BodyChecksums::from_config(&config.body_checksums)?;
+        let _ = self.state.body_checksums.set(body_checksums);
+        let response_headers = ResponseHeaderPolicies::from_config(&config.response_headers)?;
+        let _ = self.state.response_headers.set(response_headers);
+        let server_timing = ServerTiming::from_config(&config.proxy_config.server_timing)?;
+        let _ = self.state.server_timing.set(server_timing);
+        let admin_auth = AdminAuth::from_config(&config.admin_auth)?;
+        let _ = self.state.admin_auth.set(admin_auth);
+        if config.capture.enabled {
+            let captures = CaptureStore::new(&config.capture, self.state.clock.clone())?;
+            let _ = self.state.captures.set(captures);
+        }
+        
+        let eager_services: Vec<(Direction, &UpstreamService)> = config
+            .upstream_services
+            .values()
+            .map(|service| (Direction::Ingress, service))
+            .chain(config.egress.services.values().map(|service| (Direction::Egress, service)))
+            .collect();
+        eager_init::run(
+            &config.proxy_config.eager_init,
+            &eager_services,
+            &self.state.upstream_clients,
+            &self.state.ai_engine,
+        )
+        .await;
+
+        self.register_hooks();
+        self.state.lifecycle.start_all().await?;
+        self.state.endpoint_gc.start(&self.state.supervisor);
+        self.state.ai_engine.start_persistence(&self.state.supervisor);
+        if let Some(rate_limiter) = &self.state.rate_limiter {
+            Self::start_rate_limit_cleanup(&self.state, rate_limiter.clone());
+        }
+        if config.metrics_config.history.enabled {
+            Self::start_stats_history(&self.state);
+        }
+        for service in config.upstream_services.values() {
+            Self::start_service_tasks(&self.state, service)?;
+        }
+
+        let pool_connections = config.upstream_services
+            .values()
+            .chain(config.egress.services.values())
+            .map(|service| service.endpoints.len())
+            .sum();
+        self.fd_monitor.check_startup_budget(config.proxy_config.max_connections, pool_connections);
+        self.fd_monitor.start(&self.state.supervisor);
+        self.state.overload.start(&self.state.supervisor);
+
+        let tls = match &config.proxy_config.tls {
+            Some(tls_config) => {
+                let tls = Arc::new(TlsTerminator::new(tls_config, self.state.metrics.clone())?);
+                tls.start_rotation(&self.state.supervisor);
+                tls.start_cert_reload(&self.state.supervisor);
+                Some(tls)
+            }
+            None => None,
+        };
+        
+        info!(tls = tls.is_some(), "AI Sidecar Proxy listening on {}", addr);
+        if let Some(egress) = &egress {
+            info!(destinations = config.egress.services.len(), "egress listening on {}", egress.local_addr()?);
+        }
+
+        let drain_delay = Duration::from_millis(config.proxy_config.drain.delay_ms);
+        let mut drain_until = None;
+        let mut deregistration = None;
+
+        loop {
+            let drain_timer = tokio::time::sleep_until(drain_until.unwrap_or_else(tokio::time::Instant::now));
+            let (accepted, direction) = tokio::select! {
+                accepted = std::future::poll_fn(|cx| listener.poll_accept(cx)) => (accepted, Direction::Ingress),
+                accepted = accept_on(egress.as_ref()) => (accepted, Direction::Egress),
+                _ = self.shutdown.cancelled(), if drain_until.is_none() => {
+                    deregistration = self.start_drain();
+                    if drain_delay.is_zero() {
+                        break;
+                    }
+                    drain_until = Some(tokio::time::Instant::now() + drain_delay);
+                    continue;
+                }
+                _ = drain_timer, if drain_until.is_some() => break,
+            };
+            let (stream, remote_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    let label = match direction {
+                        Direction::Ingress => &ingress_label,
+                        Direction::Egress => &egress_label,
+                    };
+                    self.accept_failed(label, e).await;
+                    continue;
+                }
+            };
+
+            if self.fd_monitor.should_shed() {
+                self.state.metrics.record_connection_shed("fd_pressure");
+                debug!(client_ip = %remote_addr.ip(), "shedding connection under fd pressure");
+                drop(stream);
+                continue;
+            }
+
+            // The application reaches the egress listener over loopback in
+            // plaintext; only ingress is terminated.
+            let tls = match direction {
+                Direction::Ingress => tls.clone(),
+                Direction::Egress => None,
+            };
+            // A TLS listener expects a ClientHello, which the sniffer would reject.
+            let sniff_protocol = config.proxy_config.sniff_protocol && tls.is_none();
+            let tls_on_plaintext = config.proxy_config.tls_on_plaintext;
+            let listener_label = match direction {
+                Direction::Ingress => ingress_label.clone(),
+                Direction::Egress => egress_label.clone(),
+            };
+            let state = self.state.clone();
+            // One deadline from accept to the first request's headers, across
+            // sniffing, the TLS handshake and hyper.
+            let headers_by = config
+                .proxy_config
+                .client_timeouts
+                .header_read()
+                .map(|timeout| tokio::time::Instant::now() + timeout);
+
+            let task = async move {
+                if sniff_protocol {
+                    let Some(preface) = Self::before_headers(&state, remote_addr, headers_by, sniff::sniff(&stream)).await else {
+                        return;
+                    };
+                    match preface {
+                        Ok(Preface::Http) => {}
+                        Ok(Preface::Empty) => return,
+                        Ok(preface) => {
+                            debug!(
+                                client_ip = %remote_addr.ip(),
+                                listener = %listener_label,
+                                reason = preface.reason(),
+                                "rejecting non-HTTP connection"
+                            );
+                            state.metrics.record_protocol_error(&listener_label, preface.reason());
+                            sniff::reject(stream, preface, tls_on_plaintext).await;
+                            return;
+                        }
+                        Err(e) => {
+                            debug!(client_ip = %remote_addr.ip(), error = %e, "failed to read connection preface");
+                            return;
+                        }
+                    }
+                }
+
+                match tls {
+                    Some(tls) => {
+                        let Some(Some(stream)) = Self::before_headers(&state, remote_addr, headers_by, tls.accept(stream)).await else {
+                            return;
+                        };
+                        Self::serve_connection(stream, state, remote_addr, direction, headers_by).await;
+                    }
+                    None => Self::serve_connection(stream, state, remote_addr, direction, headers_by).await,
+                }
+            };
+            // At the limit the connection is closed rather than given a task,
+            // so an accept flood costs a close instead of memory.
+            if !self.state.connection_tasks.try_spawn(direction, task) {
+                self.state.metrics.record_connection_shed("task_limit");
+                debug!(client_ip = %remote_addr.ip(), "shedding connection at the connection task limit");
+            }
+        }
+
+        drop(listener);
+        drop(egress);
+        let drain = &self.state.drain;
+        drain.close();
+        info!(connections = drain.open_connections(), "shutting down, draining connections");
+        let finished = tokio::time::timeout(self.shutdown_grace, async {
+            drain.wait_idle().await;
+            self.state.connection_tasks.wait().await;
+            if let Some(deregistration) = deregistration {
+                let _ = deregistration.await;
+            }
+        });
+        if finished.await.is_err() {
+            warn!(
+                connections = drain.open_connections(),
+                "connections still open after the shutdown grace period"
+            );
+        }
+        self.state.lifecycle.stop_all().await;
+        self.state.supervisor.shutdown();
+        info!("AI Sidecar Proxy stopped");
+        Ok(())
+    }
+
+    // The proxy's own components that start and stop with it.
+    fn register_hooks(&self) {
+        let start_state = self.state.clone();
+        let stop_state = self.state.clone();
+        self.state.lifecycle.register(
+            LifecycleHook::new("archive_writer")
+                .on_start(move || async move {
+                    let state = start_state;
+                    if let Some(archiver) = Archiver::start(&state.config.archive, state.metrics.clone(), &state.supervisor)? {
+                        let _ = state.archiver.set(archiver);
+                    }
+                    Ok(())
+                })
+                // Records from the last requests are written before the
+                // writer task is stopped.
+                .on_stop(move || async move {
+                    if let Some(archiver) = stop_state.archiver.get() {
+                        archiver.flush().await;
+                    }
+                    Ok(())
+                }),
+        );
+
+        let start_state = self.state.clone();
+        let stop_state = self.state.clone();
+        self.state.lifecycle.register(
+            LifecycleHook::new("quotas")
+                .on_start(move || async move {
+                    if let Some(quotas) = &start_state.quotas {
+                        quotas.restore().await;
+                        Self::start_quota_persistence(&start_state, quotas.clone());
+                    }
+                    Ok(())
+                })
+                // Counts since the last periodic write would otherwise be
+                // lost.
+                .on_stop(move || async move {
+                    if let Some(quotas) = &stop_state.quotas {
+                        quotas.persist().await?;
+                    }
+                    Ok(())
+                }),
+        );
+
+        let state = self.state.clone();
+        self.state.lifecycle.register(LifecycleHook::new("health_checker").on_start(move || async move {
+            state.health_checker.start_health_checks(&state.supervisor).await;
+            Ok(())
+        }));
+    }
+
+    // A failed accept is about one connection, or a shortage that passes,
+    // such as running out of file descriptors; the listener is still good.
+    // A shortage is waited out briefly rather than retried at once.
+    async fn accept_failed(&self, listener: &str, error: std::io::Error) {
+        let kind = if fd_monitor::out_of_fds(&error) {
+            "fd_limit"
+        } else if error.kind() == std::io::ErrorKind::ConnectionAborted {
+            "aborted"
+        } else {
+            "other"
+        };
+        self.state.metrics.record_accept_error(listener, kind);
+        if kind == "aborted" {
+            debug!(listener, error = %error, "client went away before its connection was accepted");
+            return;
+        }
+        warn!(listener, kind, error = %error, "accept failed; retrying");
+        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+    }
+
+    // Marks the proxy as draining and calls the deregistration webhook, if any.
+    fn start_drain(&self) -> Option<JoinHandle<()>> {
+        if !self.state.drain.start() {
+            return None;
+        }
+        let config = &self.state.config.proxy_config.drain;
+        self.state.metrics.set_draining(true);
+        info!(delay_ms = config.delay_ms, "draining before shutdown");
+
+        let deregistration = config.deregistration.clone()?;
+        let state = self.state.clone();
+        Some(tokio::spawn(async move { state.drain.deregister(&deregistration).await }))
+    }
+
+    // Runs a step that comes before a connection's first request, giving up on
+    // the client once `deadline` passes.
+    async fn before_headers<F: std::future::Future>(
+        state: &ProxyState,
+        remote_addr: SocketAddr,
+        deadline: Option<tokio::time::Instant>,
+        step: F,
+    ) -> Option<F::Output> {
+        let Some(deadline) = deadline else {
+            return Some(step.await);
+        };
+        let output = tokio::time::timeout_at(deadline, step).await.ok();
+        if output.is_none() {
+            state.metrics.record_client_timeout("header_read");
+            debug!(client_ip = %remote_addr.ip(), "client sent no request headers in time");
+        }
+        output
+    }
+
+    async fn serve_connection<S>(
+        stream: S,
+        state: Arc<ProxyState>,
+        remote_addr: SocketAddr,
+        direction: Direction,
+        headers_by: Option<tokio::time::Instant>,
+    )
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let _open = state.drain.connection();
+        // Learned from the first request; only HTTP/2 can be told to go away
+        // without a request to answer.
+        let http2 = Arc::new(AtomicBool::new(false));
+        let go_away = Arc::new(Notify::new());
+
+        let timeouts = &state.config.proxy_config.client_timeouts;
+        let stream = EarlyHintsIo::new(stream);
+        let early_hints = stream.hints();
+        let stream = StrictHttpIo::new(stream, state.config.proxy_config.strict_http, state.metrics.clone());
+        let rejections = stream.rejections();
+        let io = TokioIo::new(WriteTimeoutIo::new(stream, timeouts.response_write_idle(), state.metrics.clone()));
+        let requested = Arc::new(AtomicBool::new(false));
+        let request_index = AtomicU64::new(0);
+        let service = {
+            let state = state.clone();
+            let http2 = http2.clone();
+            let go_away = go_away.clone();
+            let requested = requested.clone();
+            service_fn(move |mut req: Request<Incoming>| {
+                let state = state.clone();
+                let http2 = http2.clone();
+                let go_away = go_away.clone();
+                if let Some(early_hints) = early_hints.for_request(req.version()) {
+                    req.extensions_mut().insert(early_hints);
+                }
+                requested.store(true, Ordering::Relaxed);
+                let rejected = rejections.take(request_index.fetch_add(1, Ordering::Relaxed));
+                async move {
+                    if let Some(violation) = rejected {
+                        return Ok(Self::error_response_with_code(StatusCode::BAD_REQUEST, violation.message(), violation.code()));
+                    }
+                    let _in_flight = state.drain.request();
+                    let is_http2 = req.version() == hyper::Version::HTTP_2;
+                    http2.store(is_http2, Ordering::Relaxed);
+                    let mut response = Self::handle_request(req, state.clone(), remote_addr, direction).await?;
+                    if state.drain.is_draining() {
+                        if is_http2 {
+                            go_away.notify_one();
+                        } else {
+                            response.headers_mut().insert(header::CONNECTION, header::HeaderValue::from_static("close"));
+                        }
+                    }
+                    Ok::<_, hyper::Error>(response)
+                }
+            })
+        };
+
+        let mut builder = ServerBuilder::new(TokioExecutor::new());
+        if let Some(timeout) = timeouts.header_read() {
+            builder.http1().timer(TokioTimer::new()).header_read_timeout(timeout);
+        }
+        let connection = builder.serve_connection_with_upgrades(io, service);
+        tokio::pin!(connection);
+        // hyper only starts its header timer once it knows the connection is
+        // HTTP/1, so a client that never sends a full first request is cut off
+        // here.
+        let first_request = tokio::time::sleep_until(headers_by.unwrap_or_else(tokio::time::Instant::now));
+        tokio::pin!(first_request);
+
+        // HTTP/1.1 connections close after their next response; HTTP/2 ones
+        // get a GOAWAY as soon as draining starts. Anything left is shut down
+        // once the listeners close.
+        let mut drain_seen = false;
+        let mut shutting_down = false;
+        let mut first_request_seen = headers_by.is_none();
+        let result = loop {
+            let shut_down = tokio::select! {
+                result = connection.as_mut() => break result,
+                _ = &mut first_request, if !first_request_seen => {
+                    first_request_seen = true;
+                    if !requested.load(Ordering::Relaxed) {
+                        state.metrics.record_client_timeout("header_read");
+                        debug!(client_ip = %remote_addr.ip(), "client sent no request headers in time");
+                        return;
+                    }
+                    false
+                }
+                _ = state.drain.started(), if !drain_seen => {
+                    drain_seen = true;
+                    http2.load(Ordering::Relaxed)
+                }
+                _ = go_away.notified() => true,
+                _ = state.drain.closing(), if !shutting_down => true,
+            };
+            if shut_down && !shutting_down {
+                connection.as_mut().graceful_shutdown();
+                shutting_down = true;
+            }
+        };
+        match result {
+            Ok(()) => {}
+            Err(err) if err.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_timeout) => {
+                state.metrics.record_client_timeout("header_read");
+                debug!(client_ip = %remote_addr.ip(), "client sent no request headers in time");
+            }
+            // Already counted by the connection's writer.
+            Err(err) if Self::is_write_timeout(&*err) => {
+                debug!(client_ip = %remote_addr.ip(), "client stopped reading the response");
+            }
+            Err(err) => error!(client_ip = %remote_addr.ip(), error = %err, "error serving connection"),
+        }
+    }
+
+    fn is_write_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+        let mut source = Some(err);
+        while let Some(err) = source {
+            if err.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut) {
+                return true;
+            }
+            source = err.source();
+        }
+        false
+    }
+
+    async fn handle_request(
+        mut req: Request<Incoming>,
+        state: Arc<ProxyState>,
+        remote_addr: SocketAddr,
+        direction: Direction,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let context = RequestContext::new(&req, remote_addr.ip().to_string());
+        let span = info_span!(
+            "request",
+            direction = direction.label(),
+            request_id = %context.request_id,
+            method = %context.method,
+            path = %context.path,
+            client_ip = %context.client_ip,
+            route = field::Empty,
+            operation = field::Empty,
+            service = field::Empty,
+        );
+
+        async move {
+            if !state.overload.is_overloaded() {
+                LoggingMiddleware::log_request(&req, &context);
+            }
+            let method = req.method().clone();
+            // For the templates of whatever handles it.
+            req.extensions_mut().insert(context.clone());
+            if let Some(detail) = state.server_timing.get().and_then(|timing| timing.requested(req.headers(), remote_addr.ip())) {
+                req.extensions_mut().insert(detail);
+            }
+
+            let mut ran = 0;
+            let mut answered = match direction {
+                Direction::Ingress => Self::check_request_target(&mut req, &state),
+                // Absolute-form names the destination here; egress resolves it.
+                Direction::Egress => None,
+            };
+            for middleware in &state.middleware {
+                if answered.is_some() {
+                    break;
+                }
+                ran += 1;
+                answered = middleware.on_request(&mut req, &context);
+                if answered.is_some() {
+                    break;
+                }
+            }
+
+            let mut response = match answered {
+                Some(response) => response,
+                None => match direction {
+                    Direction::Ingress => Self::route_request(req, &state, remote_addr, context.start_time).await?,
+                    Direction::Egress => Self::route_egress(req, &state, remote_addr, context.start_time).await?,
+                },
+            };
+            // Read before the header policy, which may strip it from the
+            // client's copy.
+            let endpoint = response
+                .headers()
+                .get("x-proxy-endpoint")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            // Ahead of middleware, so headers it adds are never stripped.
+            if let Some(response_headers) = state.response_headers.get() {
+                response_headers.apply(direction, response.headers_mut(), &state.metrics);
+            }
+            for middleware in state.middleware[..ran].iter().rev() {
+                middleware.on_response(&mut response, &context);
+            }
+            // Upstream bodies were dropped in proxy_request; anything left
+            // here came from the proxy itself or its middleware.
+            if ResponseBodyPipeline::finish_response(&method, &mut response) == Framing::Suppressed {
+                let status = response.status();
+                let kind = body_pipeline::bodiless_kind(method == hyper::Method::HEAD, status).unwrap_or("unknown");
+                state.metrics.record_bodiless_violation("local", kind);
+                warn!(status = status.as_u16(), "dropping body the response cannot carry");
+            }
+
+            LoggingMiddleware::log_response(&response, &context, endpoint.as_deref());
+
+            Ok(response)
+        }
+        .instrument(span)
+        .await
+    }
+
+    // Answers OPTIONS * and unusable targets itself, and leaves every other
+    // request with an origin-form target.
+    fn check_request_target(req: &mut Request<Incoming>, state: &ProxyState) -> Option<Response<BoxBody>> {
+        let form = TargetForm::of(req.uri());
+        match request_target::check(req) {
+            Ok(disposition) => {
+                state.metrics.record_request_target(form.label(), disposition.label());
+                (disposition == Disposition::AnswerLocally).then(|| {
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header(header::ALLOW, "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS")
+                        .header(header::CONTENT_LENGTH, 0)
+                        .body(Self::full(""))
+                        .unwrap()
+                })
+            }
+            Err(e) => {
+                state.metrics.record_request_target(form.label(), "rejected");
+                warn!(form = form.label(), error = %e, "rejecting request target");
+                Some(Self::error_response_with_code(e.status(), &e.to_string(), e.code()))
+            }
+        }
+    }
+
+    async fn route_request(
+        mut req: Request<Incoming>,
+        state: &Arc<ProxyState>,
+        remote_addr: SocketAddr,
+        start_time: Instant,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let client_ip = remote_addr.ip();
+        if let Some(mesh_metadata) = state.mesh_metadata.get() {
+            mesh_metadata.strip_incoming(req.headers_mut());
+        }
+
+        let uri = req.uri().clone();
+        let path = uri.path();
+
+        if let Some(denied) = Self::check_access(&req, state, client_ip) {
+            return Ok(denied);
+        }
+
+        if path == "/health" {
+            return Ok(Self::health_response(state));
+        }
+
+        if path == "/metrics" {
+            return Ok(Self::metrics_response(&state.metrics).await);
+        }
+
+        if let Some(rate_limiter) = &state.rate_limiter {
+            let key = client_ip.to_string();
+            if !rate_limiter.is_allowed(&key).await {
+                return Ok(Self::rate_limited_response(rate_limiter.retry_after(&key).await));
+            }
+        }
+
+        if path.starts_with("/admin") {
+            return Self::admin_handler(req, state).await;
+        }
+
+        let Some(_serving) = state.standby.request() else {
+            return Ok(Self::standby_response());
+        };
+        let routes = state.routes.read().unwrap().clone();
+        let host = req.headers().get(header::HOST).and_then(|value| value.to_str().ok());
+        let Some(matched) = routes.resolve(host, &uri) else {
+            warn!("no route for path");
+            return Ok(Self::no_route_response(uri.path()));
+        };
+        if state.overload.should_shed(&matched.route, req.headers()) {
+            state.metrics.record_overload_shed(&matched.route);
+            debug!(route = %matched.route, "shedding low-priority request while overloaded");
+            let mut response =
+                Self::error_response_with_code(StatusCode::SERVICE_UNAVAILABLE, "Proxy overloaded", "overloaded");
+            response.headers_mut().insert(header::RETRY_AFTER, 1.into());
+            return Ok(response);
+        }
+        let quota = state
+            .quotas
+            .as_ref()
+            .and_then(|quotas| Some(quotas.check(quotas.key(req.headers())?)));
+        if let Some(quota) = quota.filter(|quota| !quota.allowed) {
+            debug!(remaining = quota.status.remaining, reset_at = quota.status.reset_at, "quota exhausted");
+            let mut response =
+                Self::error_response_with_code(StatusCode::TOO_MANY_REQUESTS, "Quota exhausted for this period", "quota_exhausted");
+            quota.insert_headers(response.headers_mut());
+            response.headers_mut().insert(header::RETRY_AFTER, quota.retry_after.as_secs().max(1).into());
+            return Ok(response);
+        }
+        let RouteMatch {
+            route,
+            service: mut service_name,
+            upstream_path,
+            operation,
+        } = matched;
+        let operation = operation.map(|operation| state.operations.label(&route, operation));
+        if let Some(upstream_path) = upstream_path {
+            let mut parts = uri.into_parts();
+            parts.path_and_query = upstream_path.parse().ok();
+            if let Ok(stripped) = Uri::from_parts(parts) {
+                *req.uri_mut() = stripped;
+            }
+        }
+        let experiments = state.experiments.read().unwrap().clone();
+        let assignment = experiments.assign(&route, req.headers());
+        if let Some(assignment) = &assignment {
+            service_name = assignment.service.to_string();
+        }
+        let span = Span::current();
+        span.record("route", route.as_str());
+        if let Some(operation) = &operation {
+            span.record("operation", operation.as_str());
+        }
+        span.record("service", service_name.as_str());
+
+        let upstreams = state.upstreams();
+        let Some(upstream_service) = upstreams.services.get(&service_name) else {
+            warn!("no upstream service for route");
+            Self::record_outcome(state, true);
+            return Ok(Self::error_response(StatusCode::NOT_FOUND, "Service not found"));
+        };
+        let breaker = upstreams.breakers.get(&service_name).map(|breaker| &**breaker);
+        let dedupe = state.dedupe.as_ref().and_then(|dedupe| {
+            Some(dedupe.acquire(dedupe.key(&service_name, req.method(), req.uri(), req.headers())?))
+        });
+        let mut response = match dedupe {
+            Some(Dedupe::Follower(slot)) => match dedupe::wait(slot).await {
+                Some(shared) => {
+                    state.metrics.record_request_dedupe(&route, "shared");
+                    Self::shared_response(shared)
+                }
+                None => {
+                    state.metrics.record_request_dedupe(&route, "fallback");
+                    Self::proxy_request(req, upstream_service, breaker, &route, Direction::Ingress, state, remote_addr, start_time)
+                        .await?
+                }
+            },
+            Some(Dedupe::Leader(leader)) => {
+                state.metrics.record_request_dedupe(&route, "leader");
+                let response =
+                    Self::proxy_request(req, upstream_service, breaker, &route, Direction::Ingress, state, remote_addr, start_time)
+                        .await?;
+                Self::share_response(response, leader, state).await?
+            }
+            None => {
+                Self::proxy_request(req, upstream_service, breaker, &route, Direction::Ingress, state, remote_addr, start_time)
+                    .await?
+            }
+        };
+        Self::record_outcome(state, response.status().is_server_error());
+        if let Some(operation) = &operation {
+            state.metrics.record_operation(&route, operation, start_time.elapsed());
+        }
+        if let Some(assignment) = assignment {
+            let status = format!("{}xx", response.status().as_u16() / 100);
+            state.metrics.record_experiment_request(assignment.experiment, assignment.variant, &status);
+            let value = state.header_values.value("experiment", &assignment.header_value(), &state.metrics);
+            response.headers_mut().insert("x-experiment", value);
+        }
+        if let Some(quota) = quota {
+            quota.insert_headers(response.headers_mut());
+        }
+        Ok(response)
+    }
+
+    // Outbound calls from the application: no admin or health routes, and the
+    // destination must be one of the configured egress services.
+    async fn route_egress(
+        mut req: Request<Incoming>,
+        state: &Arc<ProxyState>,
+        remote_addr: SocketAddr,
+        start_time: Instant,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let Some(_serving) = state.standby.request() else {
+            return Ok(Self::standby_response());
+        };
+        let egress = &state.config.egress;
+        let (name, upstream_service) = match egress.resolve(req.headers(), req.uri()) {
+            Ok(destination) => destination,
+            Err(e) => {
+                state.metrics.record_egress_rejection(e.reason());
+                warn!(error = %e, "rejecting egress request");
+                return Ok(Self::error_response(e.status(), &e.to_string()));
+            }
+        };
+        req.headers_mut().remove(egress.destination_header.as_str());
+
+        let span = Span::current();
+        span.record("route", name);
+        span.record("service", name);
+        let breaker = state.egress_breakers.get(name);
+        Self::proxy_request(req, upstream_service, breaker, name, Direction::Egress, state, remote_addr, start_time).await
+    }
+
+    fn check_access<T>(req: &Request<T>, state: &ProxyState, client_ip: IpAddr) -> Option<Response<BoxBody>> {
+        let rules = state.access_rules.read().unwrap().clone();
+        let decision = rules.evaluate(&AccessRequest {
+            method: req.method().as_str(),
+            path: req.uri().path(),
+            source: client_ip,
+            headers: req.headers(),
+        });
+
+        for rule in &decision.logged_by {
+            state.metrics.record_access_rule_match(rule, RuleAction::LogOnly.label());
+            info!(rule = %rule, action = RuleAction::LogOnly.label(), "access rule matched");
+        }
+
+        let rule = decision.rule.as_deref().unwrap_or("default");
+        state.metrics.record_access_rule_match(rule, decision.action.label());
+
+        match decision.action {
+            RuleAction::Deny { status } => {
+                warn!(rule, action = decision.action.label(), status, "access rule matched");
+                Some(Self::error_response_with_code(
+                    StatusCode::from_u16(status).unwrap_or(StatusCode::FORBIDDEN),
+                    "Request denied by access rule",
+                    "access_denied",
+                ))
+            }
+            _ => {
+                if decision.rule.is_some() {
+                    debug!(rule, action = decision.action.label(), "access rule matched");
+                }
+                None
+            }
+        }
+    }
+
+    async fn settle_breaker(
+        admission: Admission<'_>,
+        circuit_breaker: Option<&CircuitBreaker>,
+        service_name: &str,
+        success: bool,
+        state: &ProxyState,
+    ) {
+        match (admission, circuit_breaker) {
+            (Admission::Probe(permit), Some(circuit_breaker)) => {
+                let outcome = if success { "success" } else { "failure" };
+                state
+                    .metrics
+                    .record_breaker_probe(service_name, circuit_breaker.probe_mode().label(), outcome);
+                if success {
+                    permit.succeeded().await;
+                } else {
+                    permit.failed().await;
+                }
+            }
+            (_, Some(circuit_breaker)) => {
+                if success {
+                    circuit_breaker.record_success().await;
+                } else {
+                    circuit_breaker.record_failure().await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The upstream's answer to the handshake goes back as is. A 101 starts a
+    // relay that outlives this request, so the session is measured by its
+    // bytes and duration and only the handshake reaches the breaker; the AI
+    // engine and latency metrics never see it.
+    #[allow(clippy::too_many_arguments)]
+    async fn proxy_websocket(
+        mut req: Request<Incoming>,
+        upstream_service: &UpstreamService,
+        route: &str,
+        admission: Admission<'_>,
+        circuit_breaker: Option<&CircuitBreaker>,
+        endpoint: &str,
+        state: &ProxyState,
+        remote_addr: SocketAddr,
+    ) -> Response<BoxBody> {
+        let service_name = &upstream_service.name;
+        let context = Self::request_context(&req, remote_addr);
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        let (parts, _) = req.into_parts();
+        let mut headers = parts.headers;
+        let header_value = |value: &str| state.header_values.value("header_rule", value, &state.metrics);
+        let scope = Scope::new(&context).route(route, service_name).endpoint(endpoint);
+        header_rules::apply(&upstream_service.header_rules, Side::Request, scope, &mut headers, header_value);
+        let target = parts.uri.path_and_query().map_or("/", |target| target.as_str());
+
+        let timeout = Duration::from_millis(upstream_service.timeout_ms);
+        let handshake = async {
+            let host = header::HeaderValue::try_from(websocket::authority(endpoint)?)?;
+            headers.insert(header::HOST, host);
+            let mut upstream_req = Request::builder()
+                .method(parts.method)
+                .uri(target)
+                .body(http_body_util::Empty::<Bytes>::new())?;
+            *upstream_req.headers_mut() = headers;
+            match tokio::time::timeout(timeout, websocket::handshake(upstream_service, endpoint, upstream_req, remote_addr)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("no answer within {:?}", timeout)),
+            }
+        };
+        let mut upstream_response = match handshake.await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(endpoint, error = format!("{:#}", e), "websocket handshake with upstream failed");
+                state.metrics.record_websocket_handshake(service_name, "failed");
+                Self::settle_breaker(admission, circuit_breaker, service_name, false, state).await;
+                return Self::error_response(StatusCode::BAD_GATEWAY, "WebSocket upstream unavailable");
+            }
+        };
+        let status = upstream_response.status();
+        Self::settle_breaker(admission, circuit_breaker, service_name, !status.is_server_error(), state).await;
+
+        if status != StatusCode::SWITCHING_PROTOCOLS {
+            debug!(endpoint, status = status.as_u16(), "upstream declined the websocket upgrade");
+            state.metrics.record_websocket_handshake(service_name, "refused");
+            return upstream_response.map(|body| body.boxed());
+        }
+        state.metrics.record_websocket_handshake(service_name, "upgraded");
+        let upstream_upgrade = hyper::upgrade::on(&mut upstream_response);
+        let mut response = Response::new(Self::full(Bytes::new()));
+        *response.status_mut() = status;
+        *response.headers_mut() = upstream_response.headers().clone();
+
+        state.metrics.websocket_session_started(service_name);
+        let metrics = state.metrics.clone();
+        let service_name = service_name.clone();
+        tokio::spawn(
+            async move {
+                let started = Instant::now();
+                let (to_upstream, to_client) = match websocket::relay(client_upgrade, upstream_upgrade).await {
+                    Ok(copied) => copied,
+                    Err(e) => {
+                        debug!(error = %e, "websocket session ended with an error");
+                        (0, 0)
+                    }
+                };
+                metrics.websocket_session_ended(&service_name, started.elapsed(), to_upstream, to_client);
+                debug!(to_upstream, to_client, "websocket session closed");
+            }
+            .instrument(Span::current()),
+        );
+        response
+    }
+
+    // `remote_addr` is the client's, for upstreams that take it in a PROXY
+    // protocol header.
+    #[allow(clippy::too_many_arguments)]
+    async fn proxy_request(
+        req: Request<Incoming>,
+        upstream_service: &UpstreamService,
+        circuit_breaker: Option<&CircuitBreaker>,
+        route: &str,
+        direction: Direction,
+        state: &Arc<ProxyState>,
+        remote_addr: SocketAddr,
+        start_time: Instant,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let service_name = &upstream_service.name;
+        let ai_engine = &state.ai_engine;
+        let context = Self::request_context(&req, remote_addr);
+        let timing = req.extensions().get::<TimingDetail>().copied();
+        let early_hints = req
+            .extensions()
+            .get::<EarlyHints>()
+            .filter(|_| state.config.proxy_config.relay_early_hints)
+            .cloned();
+        let mut hops = HopTimer::new(start_time);
+        hops.mark("route");
+
+        let admission = match circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker.admit(req.method()).await,
+            None => Admission::Closed,
+        };
+        let probes = state.health_checker.probe_snapshot(&upstream_service.endpoints).await;
+        let candidates = routability::candidates(
+            &upstream_service.endpoints,
+            &probes,
+            admission.state(),
+            upstream_service.endpoint_priorities.as_ref(),
+        );
+
+        if candidates.breaker_open {
+            if let (Admission::Refused, Some(circuit_breaker)) = (&admission, circuit_breaker) {
+                state
+                    .metrics
+                    .record_breaker_probe(service_name, circuit_breaker.probe_mode().label(), "rejected");
+            }
+            warn!("circuit breaker open, rejecting request");
+            return Ok(Self::error_response_with_code(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service temporarily unavailable",
+                "CIRCUIT_OPEN",
+            ));
+        }
+        if candidates.panic {
+            warn!("every endpoint failed its health probe, routing across all of them");
+        }
+        hops.mark("wait");
+
+        let balancer = state.load_balancers.for_service(service_name);
+        let mut ai_decision = Self::choose_endpoint(state, service_name, &balancer, &candidates.endpoints).await;
+        if ai_decision.selected_endpoint.is_empty() {
+            error!("no available endpoints");
+            return Ok(Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "No available endpoints"));
+        }
+
+        info!(
+            endpoint = %ai_decision.selected_endpoint,
+            confidence = ai_decision.confidence,
+            mode = ai_decision.mode.label(),
+            "endpoint selected"
+        );
+
+        if websocket::is_upgrade(&req) {
+            let endpoint = &ai_decision.selected_endpoint;
+            return Ok(
+                Self::proxy_websocket(req, upstream_service, route, admission, circuit_breaker, endpoint, state, remote_addr)
+                    .await,
+            );
+        }
+
+        let client = match state.upstream_clients.get(direction, upstream_service) {
+            Ok(client) => client,
+            Err(e) => {
+                error!(error = format!("{:#}", e), "failed to create HTTP client");
+                return Ok(Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create HTTP client"));
+            }
+        };
+        hops.mark("select");
+
+        let (parts, body) = req.into_parts();
+        let method = parts.method;
+        let uri = parts.uri;
+        let mut headers = parts.headers;
+        // Sending it again must not repeat a side effect.
+        let retryable = circuit_breaker::is_idempotent(&method) || headers.contains_key("idempotency-key");
+        // Before anything archives or captures them, so a removed header is
+        // never recorded.
+        let header_value = |value: &str| state.header_values.value("header_rule", value, &state.metrics);
+        let scope = Scope::new(&context).route(route, service_name).endpoint(&ai_decision.selected_endpoint);
+        header_rules::apply(&upstream_service.header_rules, Side::Request, scope, &mut headers, header_value);
+
+        let checksum = state.body_checksums.get().and_then(|checksums| checksums.for_route(route));
+        let mut request_hasher = checksum
+            .filter(|policy| policy.hashes_request())
+            .map(|policy| policy.algorithm.hasher());
+
+        // A declared length over the limit is refused before any of the body
+        // is read.
+        let max_body_bytes = upstream_service
+            .max_body_bytes
+            .unwrap_or(state.config.proxy_config.max_body_bytes);
+        let declared = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+        if declared.is_some_and(|length| length > max_body_bytes as u64) {
+            state.metrics.record_request_body_rejected(service_name, "declared");
+            return Ok(Self::body_too_large_response(max_body_bytes));
+        }
+
+        let validators = (upstream_service.validate_with_head && method == hyper::Method::GET)
+            .then(|| Validators::from_request(&headers))
+            .flatten();
+        // Held until the upstream call finishes, since each attempt's request keeps the bytes alive until then.
+        let mut request_permit = state.buffer_budget.permit();
+        let mut body = IdleTimeoutBody::new(body, state.config.proxy_config.client_timeouts.body_read_idle());
+        let collected = if request_hasher.is_some() {
+            // Checked before any of it goes upstream, so all of it is needed.
+            let teed = TeeHash::new(body, request_hasher.as_mut());
+            buffer_budget::collect_body(teed, &mut request_permit, max_body_bytes)
+                .await
+                .map(RequestBody::Buffered)
+        } else {
+            // Only a body that may be sent again is worth holding, and only
+            // when it is small enough; the rest streams as it arrives.
+            let replay_limit = state.config.proxy_config.replay_buffer_bytes.min(max_body_bytes);
+            let resent = (retryable && upstream_service.max_retries > 0) || validators.is_some();
+            let limit = match declared {
+                Some(length) if length > replay_limit as u64 => 0,
+                _ if resent => replay_limit,
+                _ => 0,
+            };
+            match buffer_budget::read_ahead(&mut body, &mut request_permit, limit).await {
+                Ok((read_ahead, _)) if read_ahead.len() > max_body_bytes => Err(BufferError::TooLarge),
+                Ok((read_ahead, true)) => Ok(RequestBody::Buffered(read_ahead)),
+                Ok((read_ahead, false)) => {
+                    let permit = std::mem::replace(&mut request_permit, state.buffer_budget.permit());
+                    Ok(RequestBody::Streamed(Some(StreamedBody::new(read_ahead, permit, body, max_body_bytes))))
+                }
+                Err(e) => Err(e),
+            }
+        };
+        let mut request_body = match collected {
+            Ok(request_body) => request_body,
+            Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+            Err(BufferError::TooLarge) => {
+                state.metrics.record_request_body_rejected(service_name, "read");
+                return Ok(Self::body_too_large_response(max_body_bytes));
+            }
+            Err(BufferError::Body(BodyReadError::Idle)) => return Ok(Self::body_stalled_response(state)),
+            Err(BufferError::Body(BodyReadError::Body(e))) => return Err(e),
+        };
+        let stream_fault = request_body.fault();
+        // A streamed body cannot be sent twice.
+        let max_retries = if retryable && request_body.replayable() { upstream_service.max_retries } else { 0 };
+        let validators = validators.filter(|_| request_body.replayable());
+
+        // The body is fully buffered, so a corrupt one never reaches the upstream.
+        if let (Some(policy), Some(hasher)) = (checksum, request_hasher) {
+            let digest = hasher.finish();
+            let verdict = policy.check(&headers, &digest);
+            if policy.verify_request && verdict == Verdict::Mismatch {
+                state.metrics.record_body_checksum(route, "request", verdict.label());
+                warn!(header = %policy.header, "request body checksum mismatch");
+                return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Request body checksum mismatch"));
+            }
+            if policy.generate_request && verdict == Verdict::Absent {
+                headers.insert(policy.header.clone(), RouteChecksum::header_value(&digest));
+                state.metrics.record_body_checksum(route, "request", "generated");
+            } else {
+                state.metrics.record_body_checksum(route, "request", verdict.label());
+            }
+        }
+
+        // Copied out now; the writer never sees more than the route's cap.
+        // Neither is sampled while overloaded.
+        let overloaded = state.overload.is_overloaded();
+        let archive = state.archiver.get().filter(|_| !overloaded).and_then(|archiver| {
+            let cap = archiver.sample(route)?;
+            Some((archiver, cap, archiver.headers(&headers), request_body.archived(declared, cap)))
+        });
+        let capture = state.captures.get().filter(|_| !overloaded).and_then(|captures| {
+            let sampled = captures.sample(route, service_name)?;
+            let body = sampled.body_cap.map(|cap| request_body.archived(declared, cap));
+            Some((captures, sampled, captures.headers(&headers), body))
+        });
+        
+        let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("");
+
+        let content_coding = upstream_service.content_coding;
+        let client_accept = headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok());
+        let upstream_accept = content_coding::upstream_accept_encoding(content_coding, client_accept);
+
+        let mesh_metadata = state.mesh_metadata.get();
+        let outbound = mesh_metadata
+            .map(|mesh| mesh.outbound(state.header_values.value("mesh_route", route, &state.metrics)))
+            .unwrap_or_default();
+        // Built again for every attempt. The method and header values go out
+        // as they came in, byte for byte; hyper derives Content-Length from
+        // the body, which keeps the client's when it streams.
+        let build_request = |endpoint: &str, body: UpstreamBody| -> anyhow::Result<Request<UpstreamBody>> {
+            let mut upstream_req = Request::builder()
+                .method(method.clone())
+                .uri(format!("{}{}", endpoint, path_and_query))
+                .body(body)?;
+            let upstream_headers = upstream_req.headers_mut();
+
+            for (name, value) in headers.iter() {
+                if name == header::ACCEPT_ENCODING && upstream_accept.is_some() {
+                    continue;
+                }
+                if mesh_metadata.is_some_and(|mesh| mesh.replaces(name)) {
+                    continue;
+                }
+                if name != header::HOST && name != header::CONTENT_LENGTH {
+                    upstream_headers.append(name.clone(), value.clone());
+                }
+            }
+
+            if let Some(accept) = &upstream_accept {
+                upstream_headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_str(accept)?);
+            }
+
+            for (name, value) in &outbound {
+                upstream_headers.append(name.clone(), value.clone());
+            }
+
+            // HTTP/1 upstreams may send 1xx interim responses ahead of the
+            // final one. All are counted; a 103's Link headers are passed on
+            // to HTTP/1.1 and HTTP/2 clients, and the others are dropped.
+            let (metrics, service, early_hints) = (state.metrics.clone(), service_name.clone(), early_hints.clone());
+            hyper::ext::on_informational(&mut upstream_req, move |interim| {
+                let status = interim.status().as_u16();
+                metrics.record_informational_response(&service, status);
+                let relayed = status == 103 && early_hints.as_ref().is_some_and(|hints| hints.send(interim.headers()));
+                debug!(status, relayed, "informational upstream response");
+            });
+            Ok(upstream_req)
+        };
+
+        // A network error, timeout or retry_on_status answer is retried after
+        // a growing, jittered delay on the selection's next fallback, skipping
+        // any whose probe has since failed. Once those run out, a freshly
+        // picked endpoint, one not tried yet while there are any.
+        let mut retries = 0u32;
+        let retry_on_status = &state.config.proxy_config.retry_on_status;
+        let mut tried: Vec<String> = Vec::new();
+        let mut fallbacks = std::mem::take(&mut ai_decision.fallback_endpoints).into_iter();
+        let mut failovers = 0u32;
+        // Attempts, retry delays and the response body; the rest of the
+        // request's time is the proxy's own.
+        let mut upstream_wait = Duration::ZERO;
+        let max_timeout_ms = match direction {
+            Direction::Ingress => state.timeout_overrides.read().await.get(service_name).copied(),
+            Direction::Egress => None,
+        }
+        .or(upstream_service.max_timeout_ms);
+        let (response_result, recorder, deadline) = loop {
+            let timeout = Duration::from_millis(
+                ai_engine
+                    .adaptive_timeout(&ai_decision.selected_endpoint)
+                    .await
+                    .min(max_timeout_ms.unwrap_or(u64::MAX)),
+            );
+            // The attempt gets `timeout` up to the response head, and through
+            // the body too when that is buffered.
+            let deadline = tokio::time::Instant::now() + timeout;
+            let recorder = PhaseRecorder::start();
+            let attempt_start = Instant::now();
+            // In flight until the response head, for the least-connections
+            // and least-request strategies. A guard, since the client can
+            // go away mid-attempt.
+            let in_flight = balancer.start_request(&ai_decision.selected_endpoint).await;
+            let attempt = async {
+                let upstream_req = build_request(&ai_decision.selected_endpoint, request_body.for_attempt())?;
+                Self::send_upstream(&client, upstream_req, timeout, validators.clone(), remote_addr).await
+            };
+            let result = recorder.scope(attempt).await;
+            drop(in_flight);
+            upstream_wait += attempt_start.elapsed();
+
+            let status_code = match &result {
+                Ok(resp) if !retry_on_status.contains(&resp.status().as_u16()) => break (result, recorder, deadline),
+                Ok(resp) => resp.status().as_u16(),
+                Err(_) => 503,
+            };
+            // Opened by other requests meanwhile: no endpoint of the service
+            // is worth another try.
+            let breaker_open = match circuit_breaker {
+                Some(circuit_breaker) => circuit_breaker.get_state().await == CircuitBreakerState::Open,
+                None => false,
+            };
+            if retries >= max_retries || breaker_open {
+                break (result, recorder, deadline);
+            }
+            // Counted against the endpoint now, so the next pick sees it.
+            let latency_ms = attempt_start.elapsed().as_millis() as u64;
+            ai_engine
+                .record_request(RequestMetrics {
+                    latency_ms,
+                    status_code,
+                    endpoint: ai_decision.selected_endpoint.clone(),
+                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                    success: false,
+                    phases: None,
+                })
+                .await;
+            state
+                .metrics
+                .record_request(direction, &ai_decision.selected_endpoint, latency_ms, false)
+                .await;
+            retries += 1;
+            state.metrics.record_retry(&ai_decision.selected_endpoint, retries);
+            let delay = state.config.proxy_config.jittered_retry_delay(retries - 1);
+            warn!(
+                endpoint = %ai_decision.selected_endpoint,
+                attempt = retries,
+                status = status_code,
+                delay_ms = delay.as_millis() as u64,
+                "upstream call failed, retrying"
+            );
+            drop(result);
+            tried.push(std::mem::take(&mut ai_decision.selected_endpoint));
+            tokio::time::sleep(delay).await;
+            upstream_wait += delay;
+
+            let probes = state.health_checker.probe_snapshot(fallbacks.as_slice()).await;
+            let fallback = fallbacks.by_ref().find(|endpoint| {
+                !tried.contains(endpoint) && probes.get(endpoint).is_none_or(|probe| probe.tier.is_routable())
+            });
+            if let Some(fallback) = fallback {
+                failovers += 1;
+                ai_decision.selected_endpoint = fallback;
+            } else {
+                let untried: Vec<String> = candidates.endpoints.iter().filter(|e| !tried.contains(*e)).cloned().collect();
+                let pool = if untried.is_empty() { &candidates.endpoints } else { &untried };
+                ai_decision = Self::choose_endpoint(state, service_name, &balancer, pool).await;
+            }
+        };
+        let headers_at = Instant::now();
+        let elapsed = start_time.elapsed();
+        drop(request_permit);
+        // The client's doing, not the upstream's.
+        if let Some(fault) = stream_fault.as_ref().and_then(|fault| fault.get()) {
+            return Ok(match fault {
+                StreamFault::TooLarge => {
+                    state.metrics.record_request_body_rejected(service_name, "read");
+                    Self::body_too_large_response(max_body_bytes)
+                }
+                StreamFault::Idle => Self::body_stalled_response(state),
+                StreamFault::Client => {
+                    debug!("client request body failed while streaming upstream");
+                    Self::error_response(StatusCode::BAD_REQUEST, "Request body interrupted")
+                }
+            });
+        }
+
+        let mut phases = None;
+        let mut response_permit = state.buffer_budget.permit();
+        let (status_code, success, response_headers, response_body, relayed, pipeline) = match response_result {
+            Ok(resp) => 'call: {
+                let status = resp.status();
+                let success = status.is_success();
+                let family = upstream_client::remote_addr(&resp).map(|addr| address_family::label(&addr));
+                if let Some(family) = family {
+                    state.metrics.record_upstream_family(direction, service_name, family);
+                }
+                let mut response_headers = resp.headers().clone();
+                match state.config.upstream_responses.check_headers(&mut response_headers) {
+                    HeaderCheck::Fits => {}
+                    HeaderCheck::Truncated(anomaly, dropped) => {
+                        state.metrics.record_upstream_anomaly(anomaly, "truncated");
+                        warn!(endpoint = %ai_decision.selected_endpoint, dropped, "dropped upstream cookies over the header limit");
+                    }
+                    HeaderCheck::TooLarge(anomaly) => {
+                        state.metrics.record_upstream_anomaly(anomaly, "rejected");
+                        warn!(
+                            endpoint = %ai_decision.selected_endpoint,
+                            anomaly,
+                            headers = response_headers.len(),
+                            "rejecting upstream response headers over the limit"
+                        );
+                        return Ok(Self::error_response_with_code(
+                            StatusCode::BAD_GATEWAY,
+                            "Upstream response headers too large",
+                            "upstream_headers_too_large",
+                        ));
+                    }
+                }
+                let mut pipeline = match ResponseBodyPipeline::from_upstream(&method, &response_headers) {
+                    Ok(pipeline) => pipeline,
+                    Err(e) => {
+                        warn!(
+                            endpoint = %ai_decision.selected_endpoint,
+                            error = %e,
+                            "rejecting upstream response framing"
+                        );
+                        return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Malformed upstream response framing"));
+                    }
+                };
+                let bodiless = body_pipeline::bodiless_kind(method == hyper::Method::HEAD, status);
+                // Nor is there a body for the digest to describe.
+                let verify = checksum.filter(|policy| policy.verify_response && bodiless.is_none());
+                let encoding = response_headers
+                    .get(header::CONTENT_ENCODING)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                let decode = content_coding::should_decode(content_coding, client_accept, encoding.as_deref());
+
+                // A body that is checked or decoded is needed whole before the
+                // head goes out, and one the response cannot carry is read only
+                // to be dropped. Every other body is relayed as it arrives.
+                let mut body_bytes = Bytes::new();
+                let mut relayed = None;
+                if bodiless.is_none() && verify.is_none() && !decode {
+                    relayed = Some(resp.into_body());
+                } else {
+                    let body_started = Instant::now();
+                    let collected =
+                        tokio::time::timeout_at(deadline, buffer_budget::collect_response(resp.into_body(), &mut response_permit))
+                            .await;
+                    let failure = match collected {
+                        Ok(Ok(bytes)) => {
+                            body_bytes = bytes;
+                            None
+                        }
+                        // Responses have no limit of their own.
+                        Ok(Err(BufferError::Exhausted | BufferError::TooLarge)) => {
+                            return Ok(Self::buffer_exhausted_response())
+                        }
+                        Ok(Err(BufferError::Body(e))) => Some(e.to_string()),
+                        Err(_) => Some("deadline passed before the body ended".to_string()),
+                    };
+                    upstream_wait += body_started.elapsed();
+                    // A truncated body must not pass for the whole one, nor
+                    // the call for a success.
+                    if let Some(error) = failure {
+                        warn!(endpoint = %ai_decision.selected_endpoint, error = %error, "upstream response body failed");
+                        break 'call (502, false, HeaderMap::new(), Bytes::from("Upstream response body failed"), None, None);
+                    }
+                }
+                // A relayed body's time is recorded again once it ends.
+                let timings = recorder.finish(headers_at, Instant::now());
+
+                // Over HTTP/1 the client reads no body for these and drops the
+                // connection, but a 204 still announces one; over HTTP/2 any
+                // DATA frames the upstream sent arrive here.
+                if let Some(kind) = bodiless {
+                    let announced = status == StatusCode::NO_CONTENT
+                        && (response_headers.contains_key(header::TRANSFER_ENCODING)
+                            || response_headers.get(header::CONTENT_LENGTH).is_some_and(|length| length != "0"));
+                    if announced || !body_bytes.is_empty() {
+                        state.metrics.record_bodiless_violation("upstream", kind);
+                        warn!(
+                            endpoint = %ai_decision.selected_endpoint,
+                            status = status.as_u16(),
+                            "dropping upstream body the response cannot carry"
+                        );
+                        body_bytes = Bytes::new();
+                    }
+                }
+
+                if let Some(policy) = verify {
+                    let verdict = policy.check(&response_headers, &policy.algorithm.digest(&body_bytes));
+                    state.metrics.record_body_checksum(route, "response", verdict.label());
+                    if verdict == Verdict::Mismatch {
+                        warn!(
+                            endpoint = %ai_decision.selected_endpoint,
+                            header = %policy.header,
+                            "upstream response checksum mismatch"
+                        );
+                        if policy.on_response_mismatch == MismatchAction::Reject {
+                            return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Upstream response checksum mismatch"));
+                        }
+                    }
+                }
+                debug!(
+                    endpoint = %ai_decision.selected_endpoint,
+                    attempt = retries + 1,
+                    status = status.as_u16(),
+                    latency_ms = elapsed.as_millis() as u64,
+                    dns_ms = timings.dns_ms,
+                    connect_ms = timings.connect_ms,
+                    tls_ms = timings.tls_ms,
+                    ttfb_ms = timings.ttfb_ms,
+                    body_ms = timings.body_ms,
+                    reused = timings.reused,
+                    family = family.unwrap_or("unknown"),
+                    "upstream call completed"
+                );
+                phases = Some(timings);
+
+                if decode {
+                    let encoding = encoding.unwrap_or_default();
+                    // A HEAD response has nothing to decode but must describe the decoded GET.
+                    if !body_bytes.is_empty() {
+                        body_bytes = match content_coding::decode(&encoding, &body_bytes, &mut response_permit) {
+                            Ok(decoded) => decoded,
+                            Err(DecodeError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+                            Err(DecodeError::Corrupt(e)) => {
+                                warn!(
+                                    endpoint = %ai_decision.selected_endpoint,
+                                    encoding = %encoding,
+                                    error = %e,
+                                    "failed to decode upstream response"
+                                );
+                                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Malformed upstream response encoding"));
+                            }
+                        };
+                    }
+                    response_headers.remove(header::CONTENT_ENCODING);
+                    pipeline.mutate("decompress");
+                }
+                let scope = Scope::new(&context)
+                    .route(route, service_name)
+                    .endpoint(&ai_decision.selected_endpoint)
+                    .status(status.as_u16());
+                header_rules::apply(&upstream_service.header_rules, Side::Response, scope, &mut response_headers, header_value);
+                (status.as_u16(), success, response_headers, body_bytes, relayed, Some(pipeline))
+            }
+            Err(e) => {
+                error!(
+                    endpoint = %ai_decision.selected_endpoint,
+                    attempt = retries + 1,
+                    latency_ms = elapsed.as_millis() as u64,
+                    error = format!("{:#}", e),
+                    "upstream call failed"
+                );
+                (503, false, HeaderMap::new(), Bytes::from("Upstream service unavailable"), None, None)
+            }
+        };
+
+        let request_metrics = RequestMetrics {
+            latency_ms: elapsed.as_millis() as u64,
+            status_code,
+            endpoint: ai_decision.selected_endpoint.clone(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            success,
+            phases,
+        };
+        if let Some(phases) = phases.as_ref().filter(|_| relayed.is_none()) {
+            state.metrics.record_upstream_phases(direction, service_name, phases);
+        }
+
+        ai_engine.record_request(request_metrics).await;
+        state
+            .metrics
+            .record_request(direction, &ai_decision.selected_endpoint, elapsed.as_millis() as u64, success)
+            .await;
+
+        Self::settle_breaker(admission, circuit_breaker, service_name, success, state).await;
+
+        let records = PendingRecords {
+            archive: archive.map(|(archiver, cap, request_headers, request_body)| {
+                let record = ArchiveRecord {
+                    timestamp_ms: archive::now_ms(),
+                    direction: direction.label(),
+                    route: route.to_string(),
+                    method: method.to_string(),
+                    path: uri.path().to_string(),
+                    endpoint: ai_decision.selected_endpoint.clone(),
+                    status: status_code,
+                    request_headers,
+                    request_body,
+                    response_headers: archiver.headers(&response_headers),
+                    response_body: ArchivedBody::default(),
+                };
+                (cap, record)
+            }),
+            capture: capture.map(|(captures, sampled, request_headers, request_body)| {
+                let exchange = CapturedExchange {
+                    timestamp_ms: archive::now_ms(),
+                    route: route.to_string(),
+                    service: service_name.to_string(),
+                    method: method.to_string(),
+                    path: uri.path().to_string(),
+                    endpoint: ai_decision.selected_endpoint.clone(),
+                    status: status_code,
+                    request_headers,
+                    request_body,
+                    response_headers: captures.headers(&response_headers),
+                    response_body: None,
+                };
+                (sampled, exchange)
+            }),
+        };
+
+        let body = match relayed {
+            Some(relayed) => {
+                let cap = records.cap();
+                let (state, service_name) = (state.clone(), service_name.clone());
+                RelayedBody::new(relayed, cap, move |relayed| {
+                    let phases = recorder.finish(headers_at, Instant::now());
+                    state.metrics.record_upstream_phases(direction, &service_name, &phases);
+                    records.submit(&state, &relayed.kept, relayed.size);
+                })
+                .boxed()
+            }
+            None => {
+                records.submit(state, &response_body, response_body.len());
+                BudgetedBody::new(response_body, response_permit)
+                    .map_err(|never| match never {})
+                    .boxed()
+            }
+        };
+        // The upstream's own status still counts above; only the client gets
+        // the stand-in.
+        let replacement = state.config.upstream_responses.replace_status(status_code);
+        if !upstream_response::is_standard_status(status_code) {
+            let action = if replacement.is_some() { "mapped" } else { "passed" };
+            state.metrics.record_upstream_anomaly("nonstandard_status", action);
+            warn!(endpoint = %ai_decision.selected_endpoint, status = status_code, replacement, "nonstandard upstream status");
+        }
+        let mut response = Response::builder()
+            .status(replacement.unwrap_or(status_code))
+            .body(body)
+            .unwrap();
+
+        Self::copy_response_headers(&response_headers, response.headers_mut());
+        if replacement.is_some() {
+            response.headers_mut().insert("x-proxy-original-status", status_code.into());
+        }
+        if let Some(pipeline) = pipeline {
+            response.extensions_mut().insert(pipeline);
+        }
+        let endpoint = state.header_values.value("endpoint", &ai_decision.selected_endpoint, &state.metrics);
+        response.headers_mut().insert("x-proxy-endpoint", endpoint);
+        response.headers_mut().insert("x-proxy-attempts", (retries + 1).into());
+        response.headers_mut().insert("x-proxy-failover-count", failovers.into());
+        if let (Some(detail), Some(server_timing)) = (timing, state.server_timing.get()) {
+            let upstream = phases.as_ref().filter(|_| detail == TimingDetail::Full);
+            let value = hops.finish(phases.as_ref()).server_timing(upstream, server_timing.max_header_bytes());
+            let value = state.header_values.value("server_timing", &value, &state.metrics);
+            response.headers_mut().insert("server-timing", value);
+        }
+        response.headers_mut().insert("x-proxy-confidence", ai_decision.confidence.to_string().parse().unwrap());
+        response.headers_mut().insert("x-proxy-selection", header::HeaderValue::from_static(ai_decision.mode.label()));
+        state.metrics.record_processing(direction, start_time.elapsed().saturating_sub(upstream_wait));
+
+        Ok(response)
+    }
+
+    // The AI engine's pick stands only when it is confident enough;
+    // otherwise the service's balancer picks from the same candidates, less
+    // any the engine has evicted. While the engine is switched off it is not
+    // asked at all.
+    async fn choose_endpoint(
+        state: &ProxyState,
+        service_name: &str,
+        balancer: &LoadBalancer,
+        candidates: &[String],
+    ) -> AIDecision {
+        let kept;
+        let candidates = if state.ai_engine.is_enabled() {
+            kept = state.ai_engine.without_evicted(candidates);
+            &kept[..]
+        } else {
+            candidates
+        };
+        let mut ai_decision = if state.ai_engine.is_enabled() {
+            state.ai_engine.select_endpoint(service_name, candidates).await
+        } else {
+            AIDecision {
+                selected_endpoint: String::new(),
+                confidence: 0.0,
+                reasoning: "No available endpoints".to_string(),
+                fallback_endpoints: Vec::new(),
+                mode: SelectionMode::Bypassed,
+            }
+        };
+        let ai_config = &state.config.ai_config;
+        if ai_decision.mode == SelectionMode::Bypassed || ai_decision.confidence < ai_config.decision_threshold {
+            if let Some(endpoint) = balancer.select_endpoint(service_name, candidates).await {
+                ai_decision.reasoning = format!("Selected {} by {}", endpoint, balancer.strategy().name());
+                ai_decision.fallback_endpoints = candidates.iter().filter(|e| **e != endpoint).cloned().collect();
+                ai_decision.selected_endpoint = endpoint;
+                if ai_decision.mode == SelectionMode::Ai {
+                    ai_decision.mode = SelectionMode::Balancer;
+                }
+            }
+        }
+        if !ai_decision.selected_endpoint.is_empty() {
+            state.metrics.record_selection(ai_decision.mode.label());
+        }
+        ai_decision
+    }
+
+    // With validators, a HEAD goes upstream first and a match is answered with
+    // a 304 built from its headers, so the body is only fetched when it changed.
+    async fn send_upstream(
+        client: &UpstreamClient,
+        request: Request<UpstreamBody>,
+        timeout: Duration,
+        validators: Option<Validators>,
+        remote_addr: SocketAddr,
+    ) -> anyhow::Result<Response<BoxBody>> {
+        let Some(validators) = validators else {
+            return Ok(client.send_from(request, timeout, remote_addr).await?.map(|body| body.boxed()));
+        };
+        let mut head = Request::new(upstream_client::full_body(Bytes::new()));
+        *head.method_mut() = hyper::Method::HEAD;
+        *head.uri_mut() = request.uri().clone();
+        *head.headers_mut() = request.headers().clone();
+
+        let validation = client.send_from(head, timeout, remote_addr).await?;
+        let status = validation.status();
+        let matched = status == StatusCode::NOT_MODIFIED || validators.matches(status, validation.headers());
+        debug!(status = status.as_u16(), matched, "conditional GET validated with HEAD");
+        if status == StatusCode::NOT_MODIFIED {
+            return Ok(validation.map(|body| body.boxed()));
+        }
+        if matched {
+            let mut not_modified = Response::new(Self::full(Bytes::new()));
+            *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+            *not_modified.headers_mut() = conditional::not_modified_headers(validation.headers());
+            return Ok(not_modified);
+        }
+        Ok(client.send_from(request, timeout, remote_addr).await?.map(|body| body.boxed()))
+    }
+
+    // Content-Length is left to hyper, which derives it from the body we actually send.
+    fn copy_response_headers(upstream: &HeaderMap, downstream: &mut HeaderMap) {
+        const HOP_BY_HOP: [header::HeaderName; 6] = [
+            header::CONNECTION,
+            header::TRANSFER_ENCODING,
+            header::TE,
+            header::TRAILER,
+            header::UPGRADE,
+            header::CONTENT_LENGTH,
+        ];
+
+        for (name, value) in upstream.iter() {
+            if !HOP_BY_HOP.contains(name) && name != "keep-alive" {
+                downstream.append(name.clone(), value.clone());
+            }
+        }
+    }
+
+    async fn admin_handler(
+        req: Request<Incoming>,
+        state: &Arc<ProxyState>,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let path = req.uri().path();
+        let promotion = matches!(path, "/admin/promote" | "/admin/demote");
+        if state.standby.is_standby() && req.method() != hyper::Method::GET && !promotion {
+            return Ok(Self::standby_response());
+        }
+        let capture = path == "/admin/capture" || path.starts_with("/admin/capture/");
+        let scope = match state.admin_auth.get() {
+            Some(auth) => auth.scope(req.headers()),
+            None => Some(AdminScope::All),
+        };
+        let Some(scope) = scope else {
+            return Ok(Self::error_response_with_code(StatusCode::UNAUTHORIZED, "Admin token required", "unauthorized"));
+        };
+        // These replace settings shared by every service.
+        let global_change = (matches!(
+            path,
+            "/admin/access-rules"
+                | "/admin/routes"
+                | "/admin/experiments"
+                | "/admin/config/reload"
+                | "/admin/config/rollback"
+                | "/admin/ai/enabled"
+        )
+            && req.method() != hyper::Method::GET)
+            || promotion
+            // Reports on every service's config.
+            || path == "/admin/config/validate"
+            // Captures can record any service's traffic.
+            || capture
+            // Quotas belong to API keys, not services.
+            || path == "/admin/quotas"
+            || path.starts_with("/admin/quotas/");
+        if global_change && !scope.is_all() {
+            return Ok(Self::error_response_with_code(
+                StatusCode::FORBIDDEN,
+                "Token is limited to some services",
+                "out_of_scope",
+            ));
+        }
+        // Captures also name their operator with a token of their own.
+        if capture {
+            return Self::capture_admin(req, state).await;
+        }
+
+        match path {
+            "/admin/health" => {
+                let mut services = Self::service_views(state).await;
+                services.retain(|view| scope.allows(&view.service));
+                let json = serde_json::to_string_pretty(&serde_json::json!({ "services": services }))
+                    .unwrap_or_else(|_| "{}".to_string());
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(json))
+                    .unwrap())
+            }
+            "/admin/services" => {
+                let mut services: Vec<EffectivePolicy> = state
+                    .upstreams()
+                    .services
+                    .values()
+                    .map(|service| EffectivePolicy::of(Direction::Ingress, service))
+                    .chain(
+                        state.config.egress.services.values().map(|service| EffectivePolicy::of(Direction::Egress, service)),
+                    )
+                    .filter(|policy| scope.allows(&policy.service))
+                    .collect();
+                services.sort_by(|a, b| (a.direction, &a.service).cmp(&(b.direction, &b.service)));
+                let json = serde_json::to_string_pretty(&serde_json::json!({ "services": services }))
+                    .unwrap_or_else(|_| "{}".to_string());
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(json))
+                    .unwrap())
+            }
+            "/admin/status" => {
+                let status = serde_json::json!({
+                    "status": "healthy",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "uptime": "running",
+                    "ai_enabled": cfg!(feature = "ai") && state.ai_engine.is_enabled(),
+                });
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(status.to_string()))
+                    .unwrap())
+            }
+            "/admin/selection" => {
+                let upstreams = state.upstreams();
+                let Some(service) = Self::query_param(&req, "service").and_then(|name| upstreams.services.get(&name)) else {
+                    return Ok(Self::error_response(StatusCode::NOT_FOUND, "Service not found"));
+                };
+                if !scope.allows(&service.name) {
+                    return Ok(Self::out_of_scope_response(&service.name));
+                }
+                let explanation = serde_json::json!({
+                    "load_balancer": state.load_balancers.for_service(&service.name).explain(&service.name, &service.endpoints).await,
+                    "ai_decision": state.ai_engine.select_endpoint(&service.name, &service.endpoints).await,
+                });
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(explanation.to_string()))
+                    .unwrap())
+            }
+            "/admin/load-balancer" => {
+                let upstreams = state.upstreams();
+                let mut services = serde_json::Map::new();
+                for service in upstreams.services.values().filter(|service| scope.allows(&service.name)) {
+                    let balancer = state.load_balancers.for_service(&service.name);
+                    let explanation = balancer.explain(&service.name, &service.endpoints).await;
+                    services.insert(
+                        service.name.clone(),
+                        serde_json::json!({
+                            "strategy": explanation.strategy,
+                            "auto_weight": service.auto_weight,
+                            "endpoints": explanation.endpoints,
+                        }),
+                    );
+                }
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(serde_json::json!({ "services": services }).to_string()))
+                    .unwrap())
+            }
+            "/admin/runtime" => {
+                let runtime = serde_json::json!({
+                    "buffers": state.buffer_budget.snapshot(),
+                });
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(runtime.to_string()))
+                    .unwrap())
+            }
+            "/admin/tasks" => {
+                // Per-service tasks are named "kind:service".
+                let mut statuses = state.supervisor.status();
+                statuses.retain(|task| task.name.split_once(':').is_none_or(|(_, service)| scope.allows(service)));
+                let tasks = serde_json::json!({
+                    "ready": state.supervisor.is_ready(),
+                    "tasks": statuses,
+                    "hooks": state.lifecycle.status(),
+                    "connection_tasks": state.connection_tasks.snapshot(),
+                });
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(tasks.to_string()))
+                    .unwrap())
+            }
+            "/admin/drain" => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(serde_json::to_string(&state.drain.status()).unwrap_or_default()))
+                .unwrap()),
+            "/admin/access-rules" | "/admin/access-rules/test" => Self::access_rules_admin(req, state).await,
+            "/admin/routes" => Self::routes_admin(req, state, &scope).await,
+            "/admin/experiments" => Self::experiments_admin(req, state, &scope).await,
+            "/admin/endpoints" => Self::endpoints_admin(req, state, &scope).await,
+            "/admin/ai/enabled" => Self::ai_switch_admin(req, state).await,
+            "/admin/promote" | "/admin/demote" => Ok(Self::standby_admin(&req, state).await),
+            "/admin/stats/export" => Ok(Self::stats_export_admin(&req, state, &scope)),
+            _ if path.starts_with("/admin/services/") && path.ends_with("/timeout") => {
+                Self::service_timeout_admin(req, state, &scope).await
+            }
+            "/admin/config/reload" => Self::config_reload_admin(req, state).await,
+            "/admin/config/validate" => Ok(Self::config_validate_admin(&req, state)),
+            "/admin/config/history" | "/admin/config/rollback" => Self::config_history_admin(req, state, &scope).await,
+            "/admin/quotas" | "/admin/quotas/grant" | "/admin/quotas/reset" => Self::quotas_admin(req, state).await,
+            _ => Ok(Self::error_response(StatusCode::NOT_FOUND, "Admin endpoint not found"))
+        }
+    }
+
+    // POST /admin/promote starts serving at once; POST /admin/demote stops
+    // taking requests and answers once those in flight are done.
+    async fn standby_admin(req: &Request<Incoming>, state: &ProxyState) -> Response<BoxBody> {
+        if req.method() != hyper::Method::POST {
+            return Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        let changed = if req.uri().path() == "/admin/promote" {
+            state.standby.promote()
+        } else {
+            state.standby.demote().await
+        };
+        let body = serde_json::json!({ "standby": state.standby.is_standby(), "changed": changed });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(body.to_string()))
+            .unwrap()
+    }
+
+    // GET shows whether the AI engine picks endpoints; PUT {"enabled": false}
+    // hands every pick to the services' balancers from the next request on.
+    async fn ai_switch_admin(req: Request<Incoming>, state: &ProxyState) -> Result<Response<BoxBody>, hyper::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Switch {
+            enabled: bool,
+        }
+
+        if !cfg!(feature = "ai") {
+            return Ok(Self::error_response_with_code(
+                StatusCode::NOT_FOUND,
+                "this build has no AI engine; rebuild with --features ai",
+                "feature_disabled",
+            ));
+        }
+        if req.method() == hyper::Method::PUT {
+            let mut permit = state.buffer_budget.permit();
+            let max_body_bytes = state.config.proxy_config.max_body_bytes;
+            let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+                Ok(bytes) => bytes,
+                Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+                Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+                Err(BufferError::Body(e)) => return Err(e),
+            };
+            let switch: Switch = match serde_json::from_slice(&body) {
+                Ok(switch) => switch,
+                Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid AI switch: {}", e))),
+            };
+            let was = state.ai_engine.set_enabled(switch.enabled);
+            state.metrics.set_ai_enabled(switch.enabled);
+            if was != switch.enabled {
+                warn!(enabled = switch.enabled, "AI engine switched at runtime");
+            }
+        } else if req.method() != hyper::Method::GET {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+        let body = serde_json::json!({ "enabled": state.ai_engine.is_enabled() });
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(body.to_string()))
+            .unwrap())
+    }
+
+    // GET shows an ingress service's timeout cap; PUT {"max_timeout_ms": 1500}
+    // overrides its configured max_timeout_ms until the service is removed,
+    // and DELETE drops the override.
+    async fn service_timeout_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+        scope: &AdminScope,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Override {
+            max_timeout_ms: u64,
+        }
+
+        let name = req
+            .uri()
+            .path()
+            .trim_start_matches("/admin/services/")
+            .trim_end_matches("/timeout")
+            .to_string();
+        let Some(configured) = state.upstreams().services.get(&name).map(|service| service.max_timeout_ms) else {
+            return Ok(Self::error_response(StatusCode::NOT_FOUND, "Service not found"));
+        };
+        if !scope.allows(&name) {
+            return Ok(Self::out_of_scope_response(&name));
+        }
+
+        match *req.method() {
+            hyper::Method::GET => {}
+            hyper::Method::DELETE => {
+                Self::set_timeout_override(state, &name, None).await;
+                info!(service = %name, "timeout override dropped");
+            }
+            hyper::Method::PUT => {
+                let mut permit = state.buffer_budget.permit();
+                let max_body_bytes = state.config.proxy_config.max_body_bytes;
+                let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+                    Ok(bytes) => bytes,
+                    Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+                    Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+                    Err(BufferError::Body(e)) => return Err(e),
+                };
+                let ms = match serde_json::from_slice::<Override>(&body) {
+                    Ok(Override { max_timeout_ms }) if max_timeout_ms > 0 => max_timeout_ms,
+                    Ok(_) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "max_timeout_ms must be greater than 0")),
+                    Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid timeout override: {}", e))),
+                };
+                Self::set_timeout_override(state, &name, Some(ms)).await;
+                warn!(service = %name, max_timeout_ms = ms, "timeout override set at runtime");
+            }
+            _ => return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")),
+        }
+
+        let override_ms = state.timeout_overrides.read().await.get(&name).copied();
+        let body = serde_json::json!({
+            "service": name,
+            "configured_ms": configured,
+            "override_ms": override_ms,
+            "max_timeout_ms": override_ms.or(configured),
+        });
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(body.to_string()))
+            .unwrap())
+    }
+
+    // Per-minute endpoint rows as CSV or a JSON array, sent a chunk of rows at
+    // a time. `since` and `until` are Unix seconds; `service` narrows the rows
+    // to the endpoints the service has now.
+    fn stats_export_admin(req: &Request<Incoming>, state: &ProxyState, scope: &AdminScope) -> Response<BoxBody> {
+        const ROWS_PER_CHUNK: usize = 256;
+
+        if req.method() != hyper::Method::GET {
+            return Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        let csv = match Self::query_param(req, "format").as_deref() {
+            None | Some("csv") => true,
+            Some("json") => false,
+            Some(other) => {
+                return Self::error_response(StatusCode::BAD_REQUEST, &format!("Unknown format {:?}; use csv or json", other))
+            }
+        };
+        let mut bounds = [None, None];
+        for (bound, name) in bounds.iter_mut().zip(["since", "until"]) {
+            if let Some(value) = Self::query_param(req, name) {
+                match value.parse::<u64>() {
+                    Ok(seconds) => *bound = Some(seconds),
+                    Err(_) => {
+                        return Self::error_response(
+                            StatusCode::BAD_REQUEST,
+                            &format!("{} must be Unix seconds, not {:?}", name, value),
+                        )
+                    }
+                }
+            }
+        }
+
+        let upstreams = state.upstreams();
+        let services = upstreams.services.values().chain(state.config.egress.services.values());
+        let endpoints: Option<Vec<String>> = match Self::query_param(req, "service") {
+            Some(name) => {
+                let Some(service) = services.clone().find(|service| service.name == name) else {
+                    return Self::error_response(StatusCode::NOT_FOUND, "Service not found");
+                };
+                if !scope.allows(&service.name) {
+                    return Self::out_of_scope_response(&service.name);
+                }
+                Some(service.endpoints.clone())
+            }
+            // A limited token sees only its own services' endpoints.
+            None if !scope.is_all() => Some(
+                services
+                    .filter(|service| scope.allows(&service.name))
+                    .flat_map(|service| service.endpoints.iter().cloned())
+                    .collect(),
+            ),
+            None => None,
+        };
+        let rows = state.metrics.history().rows(bounds[0], bounds[1], endpoints.as_deref());
+
+        let chunks = rows.len().div_ceil(ROWS_PER_CHUNK);
+        let render = move |index: usize| {
+            let chunk = &rows[index * ROWS_PER_CHUNK..((index + 1) * ROWS_PER_CHUNK).min(rows.len())];
+            if csv {
+                return chunk.iter().map(MinuteRow::csv_line).collect::<String>();
+            }
+            let lines: Vec<String> = chunk.iter().map(|row| serde_json::to_string(row).unwrap_or_default()).collect();
+            let separator = if index + 1 < chunks { ",\n" } else { "\n" };
+            lines.join(",\n") + separator
+        };
+        let (head, tail) = if csv { (stats_history::CSV_HEADER, "") } else { ("[\n", "]\n") };
+        let body = std::iter::once(head.to_string())
+            .chain((0..chunks).map(render))
+            .chain(std::iter::once(tail.to_string()))
+            .filter(|chunk| !chunk.is_empty());
+        let frames = futures::stream::iter(body.map(|chunk| Ok(Frame::data(Bytes::from(chunk)))));
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", if csv { "text/csv" } else { "application/json" })
+            .body(BodyExt::boxed(StreamBody::new(frames)))
+            .unwrap()
+    }
+
+    // Each source is copied once under its own lock and the copies are merged,
+    // so `routable` is what selection would decide from the same inputs.
+    async fn service_views(state: &ProxyState) -> Vec<ServiceView> {
+        let probes = state.health_checker.get_all_health_status().await;
+        let passive = state.ai_engine.get_all_service_health().await;
+
+        let upstreams = state.upstreams();
+        let mut services: Vec<&UpstreamService> = upstreams.services.values().collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut views = Vec::with_capacity(services.len());
+        for service in services {
+            let (breaker, breaker_probes) = match upstreams.breakers.get(&service.name) {
+                Some(circuit_breaker) => (circuit_breaker.current_state().await, Some(circuit_breaker.probe_stats())),
+                None => (CircuitBreakerState::Closed, None),
+            };
+            let load = state.load_balancers.for_service(&service.name).explain(&service.name, &service.endpoints).await;
+            let snapshot = Snapshot {
+                probes: &probes,
+                passive: &passive,
+                load: &load,
+                breaker,
+                breaker_probes,
+                priorities: service.endpoint_priorities.as_ref(),
+            };
+            views.push(ServiceView::build(&service.name, &service.endpoints, &snapshot, |endpoint, health| {
+                state.ai_engine.selection_score(endpoint, health)
+            }));
+        }
+        views
+    }
+
+    // GET shows the active rules, PUT swaps them in without a restart, and
+    // POST .../test evaluates a described request without sending it anywhere.
+    async fn access_rules_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        #[derive(serde::Deserialize)]
+        struct TestRequest {
+            method: String,
+            path: String,
+            source_ip: IpAddr,
+            #[serde(default)]
+            headers: HashMap<String, String>,
+        }
+
+        let is_test = req.uri().path().ends_with("/test");
+        let method = req.method().clone();
+
+        if method == hyper::Method::GET && !is_test {
+            let rules = state.access_rules.read().unwrap().clone();
+            let json = serde_json::to_string(rules.config()).unwrap_or_else(|_| "{}".to_string());
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(json))
+                .unwrap());
+        }
+
+        let expected = if is_test { hyper::Method::POST } else { hyper::Method::PUT };
+        if method != expected {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+
+        let mut permit = state.buffer_budget.permit();
+        let max_body_bytes = state.config.proxy_config.max_body_bytes;
+        let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+            Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+            Err(BufferError::Body(e)) => return Err(e),
+        };
+
+        if is_test {
+            let test: TestRequest = match serde_json::from_slice(&body) {
+                Ok(test) => test,
+                Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid test request: {}", e))),
+            };
+            let mut headers = HeaderMap::new();
+            for (name, value) in &test.headers {
+                match (header::HeaderName::from_bytes(name.as_bytes()), value.parse()) {
+                    (Ok(name), Ok(value)) => {
+                        headers.append(name, value);
+                    }
+                    _ => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid header {:?}", name))),
+                }
+            }
+
+            let rules = state.access_rules.read().unwrap().clone();
+            let decision = rules.evaluate(&AccessRequest {
+                method: &test.method,
+                path: &test.path,
+                source: test.source_ip,
+                headers: &headers,
+            });
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(serde_json::to_string(&decision).unwrap_or_else(|_| "{}".to_string())))
+                .unwrap());
+        }
+
+        let compiled = serde_json::from_slice::<AccessRulesConfig>(&body)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| AccessRules::compile(&config));
+        match compiled {
+            Ok(rules) => {
+                info!(rules = rules.config().rules.len(), "access rules reloaded");
+                *state.access_rules.write().unwrap() = Arc::new(rules);
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(r#"{"status":"reloaded"}"#))
+                    .unwrap())
+            }
+            Err(e) => Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid access rules: {:#}", e))),
+        }
+    }
+
+    // GET shows the route table in the order rules are tried, and POST adds
+    // a rule to it, checked as the config file would check it. An added rule
+    // is tried after every existing one: one that would sort ahead of any, by
+    // priority, pattern before prefix or a longer prefix, is refused with 409
+    // rather than quietly taking their traffic. Added rules last until the
+    // proxy restarts.
+    async fn routes_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+        scope: &AdminScope,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let method = req.method().clone();
+        if method == hyper::Method::GET {
+            let routes = state.routes.read().unwrap().clone();
+            let json = serde_json::json!({
+                "rules": routes.rules().filter(|rule| scope.allows(&rule.service)).collect::<Vec<_>>(),
+                "default_service": routes.default_service().filter(|service| scope.allows(service)),
+            });
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(json.to_string()))
+                .unwrap());
+        }
+        if method != hyper::Method::POST {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+
+        let mut permit = state.buffer_budget.permit();
+        let max_body_bytes = state.config.proxy_config.max_body_bytes;
+        let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+            Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+            Err(BufferError::Body(e)) => return Err(e),
+        };
+        let rule: RouteRule = match serde_json::from_slice(&body) {
+            Ok(rule) => rule,
+            Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid route: {}", e))),
+        };
+
+        // Held across the check so two additions cannot both pass it.
+        let mut routes = state.routes.write().unwrap();
+        let mut config = state.config.clone();
+        config.routes = routes.rules().cloned().chain([rule.clone()]).collect();
+        config.upstream_services = state.upstreams().services.clone();
+        let added = format!("routes[{}].", config.routes.len() - 1);
+        let errors: Vec<String> = config
+            .validate()
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|error| Some(format!("{}: {}", error.path().strip_prefix(&added)?, error.message())))
+            .collect();
+        if !errors.is_empty() {
+            return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid route: {}", errors.join("; "))));
+        }
+        if let Some(existing) = routes.tried_after(&rule) {
+            let message = format!(
+                "Route would be tried ahead of existing route {}; give it a lower priority or add it to the config file",
+                existing.name()
+            );
+            return Ok(Self::error_response(StatusCode::CONFLICT, &message));
+        }
+        let updated = routes.with_rule(rule).map_err(|e| e.to_string());
+        match updated {
+            Ok(updated) => {
+                *routes = Arc::new(updated);
+                drop(routes);
+                info!(rules = config.routes.len(), "route added");
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(r#"{"status":"added"}"#))
+                    .unwrap())
+            }
+            Err(e) => Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid route: {}", e))),
+        }
+    }
+
+    // GET shows this period's usage by key. POST .../grant adds requests to a
+    // key's allowance and POST .../reset clears its usage, both until the
+    // period ends. Keys go in the body, not the path, to stay out of logs.
+    async fn quotas_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct QuotaChange {
+            key: String,
+            #[serde(default)]
+            requests: u64,
+        }
+
+        let Some(quotas) = &state.quotas else {
+            return Ok(Self::error_response(StatusCode::NOT_FOUND, "Quotas are not enabled"));
+        };
+        let path = req.uri().path().to_string();
+        if path == "/admin/quotas" {
+            if req.method() != hyper::Method::GET {
+                return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+            }
+            let usage = serde_json::json!({
+                "period": quotas.config().period,
+                "keys": quotas.statuses(),
+            });
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(usage.to_string()))
+                .unwrap());
+        }
+        if req.method() != hyper::Method::POST {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+
+        let mut permit = state.buffer_budget.permit();
+        let max_body_bytes = state.config.proxy_config.max_body_bytes;
+        let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+            Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+            Err(BufferError::Body(e)) => return Err(e),
+        };
+        let change: QuotaChange = match serde_json::from_slice(&body) {
+            Ok(change) => change,
+            Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid quota change: {}", e))),
+        };
+        let status = if path.ends_with("/grant") {
+            info!(requests = change.requests, "granted extra quota");
+            quotas.grant(&change.key, change.requests)
+        } else {
+            info!("reset quota usage");
+            quotas.reset(&change.key)
+        };
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(serde_json::to_string(&status).unwrap_or_else(|_| "{}".to_string())))
+            .unwrap())
+    }
+
+    // GET shows the running experiments; PUT replaces them, which is also how
+    // a kill switch is flipped.
+    async fn experiments_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+        scope: &AdminScope,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        if req.method() == hyper::Method::GET {
+            let experiments = state.experiments.read().unwrap().clone();
+            // Shown only when every variant's service is in scope.
+            let mut visible = experiments.config().clone();
+            visible
+                .experiments
+                .retain(|experiment| experiment.variants.iter().all(|variant| scope.allows(&variant.service)));
+            let json = serde_json::to_string(&visible).unwrap_or_else(|_| "{}".to_string());
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(json))
+                .unwrap());
+        }
+        if req.method() != hyper::Method::PUT {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+
+        let mut permit = state.buffer_budget.permit();
+        let max_body_bytes = state.config.proxy_config.max_body_bytes;
+        let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+            Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+            Err(BufferError::Body(e)) => return Err(e),
+        };
+
+        let upstreams = state.upstreams();
+        let services = &upstreams.services;
+        let compiled = serde_json::from_slice::<ExperimentsConfig>(&body)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| Experiments::compile(&config, |service| services.contains_key(service)));
+        match compiled {
+            Ok(experiments) => {
+                info!(experiments = experiments.config().experiments.len(), "experiments reloaded");
+                *state.experiments.write().unwrap() = Arc::new(experiments);
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(r#"{"status":"reloaded"}"#))
+                    .unwrap())
+            }
+            Err(e) => Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid experiments: {:#}", e))),
+        }
+    }
+
+    // GET lists each service's endpoints; PUT replaces the endpoints of one
+    // service. State kept for removed endpoints is collected once they have
+    // been gone for the endpoint GC's TTL.
+    async fn endpoints_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+        scope: &AdminScope,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct EndpointUpdate {
+            service: String,
+            endpoints: Vec<String>,
+        }
+
+        if req.method() == hyper::Method::GET {
+            let upstreams = state.upstreams();
+            let endpoints: HashMap<&String, &Vec<String>> = upstreams
+                .services
+                .iter()
+                .filter(|(name, _)| scope.allows(name))
+                .map(|(name, service)| (name, &service.endpoints))
+                .collect();
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Self::full(serde_json::to_string(&endpoints).unwrap_or_else(|_| "{}".to_string())))
+                .unwrap());
+        }
+        if req.method() != hyper::Method::PUT {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+
+        let mut permit = state.buffer_budget.permit();
+        let max_body_bytes = state.config.proxy_config.max_body_bytes;
+        let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+            Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+            Err(BufferError::Body(e)) => return Err(e),
+        };
+        let update: EndpointUpdate = match serde_json::from_slice(&body) {
+            Ok(update) => update,
+            Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid endpoint update: {}", e))),
+        };
+        if !scope.allows(&update.service) {
+            return Ok(Self::out_of_scope_response(&update.service));
+        }
+        if let Some(message) = update.endpoints.iter().find_map(|endpoint| endpoint_url::canonicalize(endpoint).err()) {
+            return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid endpoint: {}", message)));
+        }
+
+        let discovered = state.upstreams().services.get(&update.service).is_some_and(UpstreamService::discovered);
+        if discovered {
+            return Ok(Self::error_response_with_code(
+                StatusCode::CONFLICT,
+                "Service endpoints come from service discovery",
+                "discovered_endpoints",
+            ));
+        }
+        if !Self::set_endpoints(state, &update.service, update.endpoints).await {
+            return Ok(Self::error_response(StatusCode::NOT_FOUND, "Service not found"));
+        }
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(r#"{"status":"updated"}"#))
+            .unwrap())
+    }
+
+    // Replaces one service's endpoints, keeping its breaker and balancer.
+    // False when there is no such service.
+    async fn set_endpoints(state: &ProxyState, service_name: &str, endpoints: Vec<String>) -> bool {
+        let _serialized = state.endpoint_updates.lock().await;
+        let current = state.upstreams();
+        let mut services = current.services.clone();
+        let Some(service) = services.get_mut(service_name) else {
+            return false;
+        };
+        service.endpoints = endpoints;
+        service.endpoint_originals.clear();
+        service.canonicalize_endpoints();
+        info!(service = %service_name, endpoints = ?service.endpoints, "service endpoints updated");
+
+        state.endpoints.update(Self::all_endpoints(&services, &state.config));
+        state.ai_engine.set_endpoint_weights(Self::endpoint_weights(&services, &state.config));
+        state.health_checker.update_services(services.clone()).await;
+        *state.upstreams.write().unwrap() = Arc::new(Upstreams::new(services, Some(&current)));
+        true
+    }
+
+    // Swaps in `config`'s upstream services; the rest of it is only compared
+    // with the running config for the diff. `reset_breakers` gives changed
+    // services new breakers even when their breaker settings are the same.
+    async fn apply_services(state: &Arc<ProxyState>, config: &Config, reset_breakers: bool) -> ReloadDiff {
+        let _serialized = state.endpoint_updates.lock().await;
+        let current = state.upstreams();
+        // Discovered endpoints stay while the discovery settings do.
+        let mut config = config.clone();
+        for (name, service) in config.upstream_services.iter_mut() {
+            let Some(running) = current.services.get(name) else {
+                continue;
+            };
+            if service.discovered() && service.kubernetes == running.kubernetes && service.consul == running.consul {
+                service.endpoints = running.endpoints.clone();
+                service.endpoint_originals.clear();
+            }
+        }
+        let diff = ReloadDiff::new(&current.services, &state.config, &config);
+
+        let services = config.upstream_services.clone();
+        state.load_balancers.configure(&services);
+        for service in services.values() {
+            if let Some(weights) = &service.endpoint_weights {
+                for (endpoint, weight) in weights {
+                    state.load_balancers.for_service(&service.name).set_endpoint_weight(&service.name, endpoint, *weight).await;
+                }
+            }
+        }
+        state.endpoints.update(Self::all_endpoints(&services, &state.config));
+        state.health_checker.update_services(services.clone()).await;
+        let mut previous = Upstreams {
+            services: current.services.clone(),
+            breakers: current.breakers.clone(),
+        };
+        if reset_breakers {
+            previous.breakers.retain(|name, _| !diff.changed.contains(name));
+        }
+        *state.upstreams.write().unwrap() = Arc::new(Upstreams::new(services, Some(&previous)));
+        state.upstream_clients.reconfigure(Direction::Ingress, &state.upstreams().services);
+        for name in &diff.removed {
+            state.timeout_overrides.write().await.remove(name);
+        }
+
+        let upstreams = state.upstreams();
+        for name in &diff.added {
+            if let Err(e) = Self::start_service_tasks(state, &upstreams.services[name]) {
+                warn!(service = %name, error = format!("{:#}", e), "cannot start tasks for added service");
+            }
+        }
+        #[cfg(feature = "kubernetes")]
+        for name in &diff.changed {
+            let service = &upstreams.services[name];
+            let Some(discovery) = service.kubernetes.clone().filter(|_| service.kubernetes != current.services[name].kubernetes) else {
+                continue;
+            };
+            if let Err(e) = Self::start_kubernetes_discovery(state, name, discovery) {
+                warn!(service = %name, error = format!("{:#}", e), "cannot start Kubernetes discovery");
+            }
+        }
+        for name in &diff.changed {
+            let service = &upstreams.services[name];
+            let Some(discovery) = service.consul.clone().filter(|_| service.consul != current.services[name].consul) else {
+                continue;
+            };
+            if let Err(e) = Self::start_consul_discovery(state, name, discovery) {
+                warn!(service = %name, error = format!("{:#}", e), "cannot start Consul discovery");
+            }
+        }
+        diff
+    }
+
+    // Puts back the services a rejected config replaced.
+    async fn roll_back(state: &Arc<ProxyState>, rollback: Rollback, trigger: &'static str) -> ReloadDiff {
+        let restored = Config {
+            upstream_services: rollback.services,
+            ..state.config.clone()
+        };
+        // The breakers of the services it changes back tripped on the
+        // rejected config.
+        let diff = Self::apply_services(state, &restored, true).await;
+        state.metrics.record_config_rollback(trigger);
+        error!(
+            trigger,
+            rejected = rollback.rejected,
+            restored = rollback.restore,
+            reason = %rollback.reason,
+            added = ?diff.added,
+            removed = ?diff.removed,
+            changed = ?diff.changed,
+            "config rolled back"
+        );
+        if let Some(url) = &state.config_history.config().webhook_url {
+            let body = serde_json::json!({
+                "event": "config_rollback",
+                "trigger": trigger,
+                "rejected_version": rollback.rejected,
+                "restored_version": rollback.restore,
+                "reason": rollback.reason,
+            });
+            let url = url.clone();
+            tokio::spawn(async move {
+                let result = reqwest::Client::new().post(&url).timeout(Duration::from_secs(5)).json(&body).send().await;
+                match result.and_then(|response| response.error_for_status()) {
+                    Ok(_) => info!(url = %url, "config rollback webhook called"),
+                    Err(e) => warn!(url = %url, error = %e, "config rollback webhook failed"),
+                }
+            });
+        }
+        diff
+    }
+
+    // Counts a routed request towards the reload guardrail, rolling back when
+    // it trips.
+    fn record_outcome(state: &Arc<ProxyState>, error: bool) {
+        if let Some(rollback) = state.config_history.record(error) {
+            let state = state.clone();
+            tokio::spawn(async move {
+                Self::roll_back(&state, rollback, "automatic").await;
+            });
+        }
+    }
+
+    // GET .../history lists the recent configs; POST .../rollback rejects the
+    // running one and goes back to the one before it.
+    async fn config_history_admin(
+        req: Request<Incoming>,
+        state: &Arc<ProxyState>,
+        scope: &AdminScope,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let body = if req.uri().path().ends_with("/history") {
+            if req.method() != hyper::Method::GET {
+                return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+            }
+            let mut entries = state.config_history.entries();
+            for entry in &mut entries {
+                let diff = &mut entry.diff;
+                for services in [&mut diff.added, &mut diff.removed, &mut diff.changed] {
+                    services.retain(|service| scope.allows(service));
+                }
+            }
+            serde_json::json!({
+                "rollback": state.config_history.config(),
+                "configs": entries,
+            })
+        } else {
+            if req.method() != hyper::Method::POST {
+                return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+            }
+            let Some(rollback) = state.config_history.rollback("rolled back over the admin API".to_string()) else {
+                return Ok(Self::error_response_with_code(
+                    StatusCode::CONFLICT,
+                    "No earlier config to roll back to",
+                    "no_previous_config",
+                ));
+            };
+            let (rejected, restored) = (rollback.rejected, rollback.restore);
+            let diff = Self::roll_back(state, rollback, "manual").await;
+            serde_json::json!({
+                "status": "rolled_back",
+                "rejected_version": rejected,
+                "restored_version": restored,
+                "added": diff.added,
+                "removed": diff.removed,
+                "changed": diff.changed,
+            })
+        };
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(body.to_string()))
+            .unwrap())
+    }
+
+    // The config file as a reload would read it, under any environment
+    // overrides the proxy was started with.
+    fn read_config_file(path: &std::path::Path, state: &ProxyState) -> anyhow::Result<Config> {
+        let config = Config::from_file(path)?;
+        Ok(match &state.env_prefix {
+            Some(prefix) => Config::merge(config, Config::env_overrides(prefix)?),
+            None => config,
+        })
+    }
+
+    // GET /admin/config/validate: what a reload would make of the config
+    // file now, errors and warnings both, without applying anything.
+    fn config_validate_admin(req: &Request<Incoming>, state: &ProxyState) -> Response<BoxBody> {
+        if req.method() != hyper::Method::GET {
+            return Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        let Some(path) = &state.config_path else {
+            return Self::error_response_with_code(
+                StatusCode::CONFLICT,
+                "Proxy was not started from a config file",
+                "no_config_file",
+            );
+        };
+        let config = match Self::read_config_file(path, state) {
+            Ok(config) => config,
+            Err(e) => {
+                return Self::error_response_with_code(StatusCode::BAD_REQUEST, &format!("{:#}", e), "config_unreadable")
+            }
+        };
+        let validation = config.validation();
+        let findings = |found: &[ConfigError]| -> Vec<serde_json::Value> {
+            found
+                .iter()
+                .map(|finding| serde_json::json!({ "path": finding.path(), "message": finding.message() }))
+                .collect()
+        };
+        let body = serde_json::json!({
+            "valid": validation.errors.is_empty(),
+            "errors": findings(&validation.errors),
+            "warnings": findings(&validation.warnings),
+        });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(body.to_string()))
+            .unwrap()
+    }
+
+    // Re-reads the config file and swaps in its upstream services in one step.
+    // Breakers are kept for services whose breaker settings did not change;
+    // every other section of the file takes a restart, and the response says
+    // which of them differ from what is running.
+    async fn config_reload_admin(
+        req: Request<Incoming>,
+        state: &Arc<ProxyState>,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        if req.method() != hyper::Method::POST {
+            return Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
+        }
+        let Some(path) = &state.config_path else {
+            return Ok(Self::error_response_with_code(
+                StatusCode::CONFLICT,
+                "Proxy was not started from a config file",
+                "no_config_file",
+            ));
+        };
+        let config = match Self::read_config_file(path, state) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(path = %path.display(), error = format!("{:#}", e), "config reload failed");
+                return Ok(Self::error_response_with_code(
+                    StatusCode::BAD_REQUEST,
+                    &format!("{:#}", e),
+                    "config_unreadable",
+                ));
+            }
+        };
+        let validation = config.validation();
+        if !validation.errors.is_empty() {
+            let message = invalid_config(&validation.errors).to_string();
+            warn!(path = %path.display(), error = %message, "config reload rejected");
+            return Ok(Self::error_response_with_code(StatusCode::BAD_REQUEST, &message, "config_invalid"));
+        }
+        Self::log_config_warnings(&validation.warnings);
+
+        let diff = Self::apply_services(state, &config, false).await;
+        let version = state.config_history.reloaded(diff.clone(), config.upstream_services);
+        info!(
+            version,
+            added = ?diff.added,
+            removed = ?diff.removed,
+            changed = ?diff.changed,
+            restart_required = ?diff.restart_required,
+            "upstream services reloaded"
+        );
+
+        let body = serde_json::json!({
+            "status": "reloaded",
+            "version": version,
+            "added": diff.added,
+            "removed": diff.removed,
+            "changed": diff.changed,
+            "restart_required": diff.restart_required,
+        });
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(body.to_string()))
+            .unwrap())
+    }
+
+    // POST /admin/capture starts a capture; GET and DELETE
+    // /admin/capture/{id} read and discard one. Every call past the admin
+    // token check, refused ones included, goes to the audit log.
+    async fn capture_admin(
+        req: Request<Incoming>,
+        state: &ProxyState,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let Some(captures) = state.captures.get() else {
+            return Ok(Self::error_response(StatusCode::NOT_FOUND, "Admin endpoint not found"));
+        };
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let Some(operator) = captures.authorize(req.headers()).map(str::to_string) else {
+            warn!(target: "audit", action = "capture.denied", method = %method, path = %path, "capture request refused");
+            return Ok(Self::error_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+        };
+        let id = path.strip_prefix("/admin/capture/").filter(|id| !id.is_empty());
+
+        match (method, id) {
+            (hyper::Method::POST, None) => {
+                let mut permit = state.buffer_budget.permit();
+                let max_body_bytes = state.config.proxy_config.max_body_bytes;
+                let body = match buffer_budget::collect_body(req.into_body(), &mut permit, max_body_bytes).await {
+                    Ok(bytes) => bytes,
+                    Err(BufferError::Exhausted) => return Ok(Self::buffer_exhausted_response()),
+                    Err(BufferError::TooLarge) => return Ok(Self::body_too_large_response(max_body_bytes)),
+                    Err(BufferError::Body(e)) => return Err(e),
+                };
+                let request: CaptureRequest = match serde_json::from_slice(&body) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Invalid capture request: {}", e)))
+                    }
+                };
+                match captures.start(&operator, request) {
+                    Ok(id) => Ok(Response::builder()
+                        .status(StatusCode::CREATED)
+                        .header("content-type", "application/json")
+                        .body(Self::full(serde_json::json!({ "id": id }).to_string()))
+                        .unwrap()),
+                    Err(e) => {
+                        warn!(target: "audit", action = "capture.rejected", operator = %operator, reason = %e, "capture request rejected");
+                        Ok(Self::error_response(e.status(), &format!("Invalid capture request: {}", e)))
+                    }
+                }
+            }
+            (hyper::Method::GET, Some(id)) => match captures.get(&operator, id) {
+                Some(view) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/json")
+                    .body(Self::full(serde_json::to_string(&view).unwrap_or_else(|_| "{}".to_string())))
+                    .unwrap()),
+                None => Ok(Self::error_response(StatusCode::NOT_FOUND, "Capture not found")),
+            },
+            (hyper::Method::DELETE, Some(id)) => {
+                if captures.delete(&operator, id) {
+                    Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .header("content-type", "application/json")
+                        .body(Self::full(r#"{"status":"deleted"}"#))
+                        .unwrap())
+                } else {
+                    Ok(Self::error_response(StatusCode::NOT_FOUND, "Capture not found"))
+                }
+            }
+            _ => Ok(Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")),
+        }
+    }
+
+    fn query_param<T>(req: &Request<T>, name: &str) -> Option<String> {
+        req.uri().query()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (key == name).then(|| value.to_string())
+        })
+    }
+
+    fn health_response(state: &ProxyState) -> Response<BoxBody> {
+        // Taken out of rotation for the rest of the process's life.
+        if state.drain.is_draining() {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("content-type", "application/json")
+                .body(Self::full(r#"{"status":"draining"}"#))
+                .unwrap();
+        }
+        // Out of rotation until promoted, however healthy.
+        if state.standby.is_standby() {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("content-type", "application/json")
+                .body(Self::full(r#"{"status":"standby"}"#))
+                .unwrap();
+        }
+        if !state.supervisor.is_ready() {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("content-type", "application/json")
+                .body(Self::full(r#"{"status":"degraded","reason":"critical background task down"}"#))
+                .unwrap();
+        }
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Self::full(r#"{"status":"healthy"}"#))
+            .unwrap()
+    }
+
+    #[cfg(feature = "metrics-prometheus")]
+    async fn metrics_response(metrics: &Arc<MetricsCollector>) -> Response<BoxBody> {
+        let metrics_data = metrics.get_prometheus_metrics().await;
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain")
+            .body(Self::full(metrics_data))
+            .unwrap()
+    }
+
+    #[cfg(not(feature = "metrics-prometheus"))]
+    async fn metrics_response(_metrics: &Arc<MetricsCollector>) -> Response<BoxBody> {
+        Self::error_response_with_code(
+            StatusCode::NOT_FOUND,
+            "this build has no Prometheus metrics; rebuild with --features metrics-prometheus",
+            "feature_disabled",
+        )
+    }
+
+    fn error_response(status: StatusCode, message: &str) -> Response<BoxBody> {
+        let error_json = serde_json::json!({
+            "error": message,
+            "status": status.as_u16()
+        });
+        
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Self::full(error_json.to_string()))
+            .unwrap()
+    }
+
+    fn out_of_scope_response(service: &str) -> Response<BoxBody> {
+        Self::error_response_with_code(
+            StatusCode::FORBIDDEN,
+            &format!("Token does not cover service {:?}", service),
+            "out_of_scope",
+        )
+    }
+
+    // Names the path, so a request sent to the wrong place is easy to spot.
+    fn no_route_response(path: &str) -> Response<BoxBody> {
+        let error_json = serde_json::json!({
+            "error": "No route for path",
+            "code": "no_route",
+            "status": StatusCode::NOT_FOUND.as_u16(),
+            "path": path
+        });
+
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("content-type", "application/json")
+            .body(Self::full(error_json.to_string()))
+            .unwrap()
+    }
+
+    fn error_response_with_code(status: StatusCode, message: &str, code: &str) -> Response<BoxBody> {
+        let error_json = serde_json::json!({
+            "error": message,
+            "code": code,
+            "status": status.as_u16()
+        });
+
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Self::full(error_json.to_string()))
+            .unwrap()
+    }
+
+    // Retry-After is in whole seconds; round up so a client that waits that
+    // long finds a token.
+    fn rate_limited_response(retry_after: Duration) -> Response<BoxBody> {
+        debug!(retry_after_ms = retry_after.as_millis() as u64, "rate limited");
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        let mut response =
+            Self::error_response_with_code(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded", "rate_limited");
+        response.headers_mut().insert(header::RETRY_AFTER, seconds.max(1).into());
+        response
+    }
+
+    fn standby_response() -> Response<BoxBody> {
+        debug!("refusing request while in standby");
+        Self::error_response_with_code(StatusCode::SERVICE_UNAVAILABLE, "Proxy is in standby", "standby")
+    }
+
+    fn buffer_exhausted_response() -> Response<BoxBody> {
+        warn!("buffer budget exhausted, rejecting request");
+        Self::error_response_with_code(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Proxy buffer budget exhausted",
+            "buffer_budget_exhausted",
+        )
+    }
+
+    // The unread rest of the body is left on the connection, so it is closed
+    // rather than reused.
+    // The context `handle_request` made for `req`, or a new one for requests
+    // that did not pass through it.
+    fn request_context(req: &Request<Incoming>, remote_addr: SocketAddr) -> RequestContext {
+        req.extensions()
+            .get::<RequestContext>()
+            .cloned()
+            .unwrap_or_else(|| RequestContext::new(req, remote_addr.ip().to_string()))
+    }
+
+    fn body_stalled_response(state: &ProxyState) -> Response<BoxBody> {
+        state.metrics.record_client_timeout("body_read");
+        warn!("client stalled while sending the request body");
+        let mut response = Self::error_response(StatusCode::REQUEST_TIMEOUT, "Request body not received in time");
+        response.headers_mut().insert(header::CONNECTION, header::HeaderValue::from_static("close"));
+        response
+    }
+
+    // Buffers a deduplicated read's response so the requests waiting on it
+    // get a copy. Event streams, answers that are not `dedupe::shareable`,
+    // bodies over request_dedupe_max_body_bytes and bodies the buffer budget
+    // has no room for are passed on unshared, and their waiters make their
+    // own calls.
+    async fn share_response(
+        response: Response<BoxBody>,
+        leader: Leader,
+        state: &ProxyState,
+    ) -> Result<Response<BoxBody>, hyper::Error> {
+        let event_stream = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if event_stream || !dedupe::shareable(response.status(), response.headers()) {
+            return Ok(response);
+        }
+        let (parts, mut body) = response.into_parts();
+        let mut permit = state.buffer_budget.permit();
+        let max_body_bytes = state.config.proxy_config.request_dedupe_max_body_bytes;
+        let body = match buffer_budget::read_ahead_or_stop(&mut body, &mut permit, max_body_bytes.saturating_add(1)).await {
+            Ok((read, true)) if read.len() <= max_body_bytes => read,
+            Ok((read, true)) => {
+                let body = BudgetedBody::new(read, permit).map_err(|never| match never {}).boxed();
+                return Ok(Response::from_parts(parts, body));
+            }
+            // Over the limit or the budget. The rest follows the part already
+            // read, which holds its share of the budget until it is sent.
+            Ok((read, false)) => {
+                use futures::StreamExt;
+                let read = futures::stream::once(async move {
+                    let _permit = permit;
+                    Ok(Frame::data(read))
+                });
+                let body = StreamBody::new(read.chain(BodyStream::new(body)));
+                return Ok(Response::from_parts(parts, BodyExt::boxed(body)));
+            }
+            Err(e) => return Err(e),
+        };
+        leader.publish(CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        });
+        let body = BudgetedBody::new(body, permit).map_err(|never| match never {}).boxed();
+        Ok(Response::from_parts(parts, body))
+    }
+
+    fn shared_response(shared: CachedResponse) -> Response<BoxBody> {
+        let mut response = Response::new(Self::full(shared.body));
+        *response.status_mut() = shared.status;
+        *response.headers_mut() = shared.headers;
+        response
+    }
+
+    fn body_too_large_response(max_body_bytes: usize) -> Response<BoxBody> {
+        warn!(max_body_bytes, "request body over the limit, rejecting request");
+        let mut response = Self::error_response_with_code(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Request body exceeds {} bytes", max_body_bytes),
+            "body_too_large",
+        );
+        response.headers_mut().insert(header::CONNECTION, header::HeaderValue::from_static("close"));
+        response
+    }
+
+    fn full<T: Into<Bytes>>(chunk: T) -> BoxBody {
+        Full::new(chunk.into())
+            .map_err(|never| match never {})
+            .boxed()
+    }
+}
+
+// Where `serve` takes connections from: a bound socket, or in tests one that
+// fails on purpose.
+trait Listener: Send + Sync {
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+    fn poll_accept(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<(tokio::net::TcpStream, SocketAddr)>>;
+}
+
+impl Listener for TcpListener {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+
+    fn poll_accept(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<(tokio::net::TcpStream, SocketAddr)>> {
+        TcpListener::poll_accept(self, cx)
+    }
+}
+
+async fn accept_on(listener: Option<&TcpListener>) -> std::io::Result<(tokio::net::TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// An exchange's archive record and capture, waiting on the response body.
+// They go out at once when it was buffered, or when a relayed one ends.
+struct PendingRecords {
+    archive: Option<(usize, ArchiveRecord)>,
+    capture: Option<(Sampled, CapturedExchange)>,
+}
+
+impl PendingRecords {
+    // The most of the response body either of them keeps.
+    fn cap(&self) -> usize {
+        let archive = self.archive.as_ref().map_or(0, |(cap, _)| *cap);
+        let capture = self.capture.as_ref().and_then(|(sampled, _)| sampled.body_cap).unwrap_or(0);
+        archive.max(capture)
+    }
+
+    fn submit(self, state: &ProxyState, kept: &[u8], size: usize) {
+        if let (Some((cap, mut record)), Some(archiver)) = (self.archive, state.archiver.get()) {
+            record.response_body = ArchivedBody::prefix(kept, size, cap);
+            archiver.submit(record);
+        }
+        if let (Some((sampled, mut exchange)), Some(captures)) = (self.capture, state.captures.get()) {
+            exchange.response_body = sampled.body_cap.map(|cap| ArchivedBody::prefix(kept, size, cap));
+            captures.record(sampled, exchange);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::{ChecksumAlgorithm, RouteChecksumConfig};
+    use crate::circuit_breaker::BreakerProbeConfig;
+    use crate::clock::MockClock;
+    use crate::content_coding::ContentCodingMode;
+    use crate::routes::{ConflictStrictness, PathPattern, PathRewrite};
+    use crate::path_templates::{PathTemplate, OTHER_OPERATION};
+    use crate::auto_weight::AutoWeightConfig;
+    use crate::rate_limiter::RateLimitConfig;
+    use crate::quota::QuotaConfig;
+    use crate::config_history::ConfigRollbackConfig;
+    use crate::mock_upstream::{MockResponse, MockUpstream};
+    use crate::proxy_protocol;
+    use crate::upstream_response::OversizedHeaders;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use std::sync::{atomic::AtomicUsize, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for CapturedLogs {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    impl CapturedLogs {
+        fn events(&self) -> Vec<serde_json::Value> {
+            let bytes = self.0.lock().unwrap().clone();
+            String::from_utf8(bytes)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    async fn start_proxy(config: Config) -> SocketAddr {
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        addr
+    }
+
+    fn config_with_endpoint(endpoint: String) -> Config {
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![endpoint];
+        config
+    }
+
+    const PLAIN_BODY: &[u8] = b"compressible compressible compressible compressible";
+
+    async fn gzip_proxy(mode: ContentCodingMode) -> (MockUpstream, SocketAddr) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(PLAIN_BODY).unwrap();
+        let upstream = MockUpstream::start(MockResponse {
+            headers: vec![("content-encoding".to_string(), "gzip".to_string())],
+            body: Bytes::from(encoder.finish().unwrap()),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+
+        let mut config = config_with_endpoint(upstream.url());
+        config.upstream_services.get_mut("service-a").unwrap().content_coding = mode;
+        let addr = start_proxy(config).await;
+        (upstream, addr)
+    }
+
+    async fn fetch(addr: SocketAddr, accept_encoding: Option<&str>) -> reqwest::Response {
+        let mut request = reqwest::Client::new().get(format!("http://{}/api/a/items", addr));
+        if let Some(accept) = accept_encoding {
+            request = request.header("accept-encoding", accept);
+        }
+        request.send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_relays_encoded_bytes() {
+        let (_upstream, addr) = gzip_proxy(ContentCodingMode::Passthrough).await;
+
+        let response = fetch(addr, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let length: usize = response.headers()["content-length"].to_str().unwrap().parse().unwrap();
+        let body = response.bytes().await.unwrap();
+        assert_eq!(body.len(), length);
+        assert_eq!(&body[..2], &[0x1f, 0x8b]);
+    }
+
+    #[tokio::test]
+    async fn test_decompress_always_decodes() {
+        let (_upstream, addr) = gzip_proxy(ContentCodingMode::Decompress).await;
+
+        let response = fetch(addr, Some("gzip")).await;
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.headers()["content-length"], PLAIN_BODY.len().to_string().as_str());
+        assert_eq!(response.bytes().await.unwrap(), PLAIN_BODY);
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_decodes_only_when_not_offered() {
+        let (_upstream, addr) = gzip_proxy(ContentCodingMode::Negotiate).await;
+
+        let response = fetch(addr, Some("gzip, br")).await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(&response.bytes().await.unwrap()[..2], &[0x1f, 0x8b]);
+
+        let response = fetch(addr, Some("br")).await;
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.bytes().await.unwrap(), PLAIN_BODY);
+
+        let response = fetch(addr, None).await;
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.bytes().await.unwrap(), PLAIN_BODY);
+    }
+
+    #[tokio::test]
+    async fn test_default_decodes_for_clients_without_the_encoding() {
+        let upstream = MockUpstream::start(MockResponse {
+            headers: vec![("content-encoding".to_string(), "deflate".to_string())],
+            body: Bytes::from_static(include_bytes!("../tests/fixtures/content_coding/body.json.deflate")),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let addr = start_proxy(config_with_endpoint(upstream.url())).await;
+
+        let response = fetch(addr, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.bytes().await.unwrap(), &include_bytes!("../tests/fixtures/content_coding/body.json")[..]);
+
+        let response = fetch(addr, Some("deflate")).await;
+        assert_eq!(response.headers()["content-encoding"], "deflate");
+    }
+
+    #[tokio::test]
+    async fn test_admin_tasks_lists_supervised_tasks() {
+        let addr = start_proxy(Config::new()).await;
+
+        let tasks: serde_json::Value = reqwest::get(format!("http://{}/admin/tasks", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert_eq!(tasks["ready"], true);
+        let health_checker = tasks["tasks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|task| task["name"] == "health_checker")
+            .expect("health checker not supervised");
+        assert_eq!(health_checker["critical"], true);
+        assert_eq!(health_checker["state"], "running");
+        assert_eq!(health_checker["restarts"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_access_rules_hot_reload_and_test_endpoint() {
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(Config::new(), Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let client = reqwest::Client::new();
+        let rules = serde_json::json!({
+            "default_action": {"type": "allow"},
+            "rules": [{
+                "name": "block-external-admin-writes",
+                "path_glob": "/api/*/admin/**",
+                "methods": ["PUT"],
+                "excluded_source_cidrs": ["10.0.0.0/8"],
+                "action": {"type": "deny", "status": 403}
+            }]
+        });
+        let response = client
+            .put(format!("http://{}/admin/access-rules", addr))
+            .json(&rules)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = client
+            .put(format!("http://{}/api/a/admin/users", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let error: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(error["code"], "access_denied");
+        assert_eq!(metrics.access_rule_match_count("block-external-admin-writes", "deny"), 1);
+
+        let decision: serde_json::Value = client
+            .post(format!("http://{}/admin/access-rules/test", addr))
+            .json(&serde_json::json!({"method": "PUT", "path": "/api/a/admin/users", "source_ip": "10.2.3.4"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(decision["rule"], serde_json::Value::Null);
+        assert_eq!(decision["action"]["type"], "allow");
+
+        let response = client
+            .put(format!("http://{}/admin/access-rules", addr))
+            .json(&serde_json::json!({"rules": [{"name": "bad", "source_cidrs": ["nope"], "action": {"type": "allow"}}]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_experiment_routes_variants_and_kill_switch() {
+        let control = MockUpstream::start(MockResponse {
+            body: Bytes::from_static(b"control"),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let treatment = MockUpstream::start(MockResponse {
+            body: Bytes::from_static(b"treatment"),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(control.url());
+        config.upstream_services.get_mut("service-b").unwrap().endpoints = vec![treatment.url()];
+        let mut experiments: ExperimentsConfig = serde_json::from_value(serde_json::json!({
+            "experiments": [{
+                "name": "checkout",
+                "route": "/api/a",
+                "salt": "v1",
+                "identifier": {"header": "x-user-id"},
+                "variants": [
+                    {"name": "control", "service": "service-a", "fraction": 0.5},
+                    {"name": "treatment", "service": "service-b", "fraction": 0.5}
+                ]
+            }]
+        }))
+        .unwrap();
+        config.experiments = experiments.clone();
+
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let client = reqwest::Client::new();
+        let fetch = |user: String| {
+            let request = client.get(format!("http://{}/api/a/cart", addr)).header("x-user-id", user);
+            async move {
+                let response = request.send().await.unwrap();
+                let assigned = response.headers()["x-experiment"].to_str().unwrap().to_string();
+                let body = response.text().await.unwrap();
+                (assigned, body)
+            }
+        };
+
+        let mut treated = 0;
+        for i in 0..40 {
+            let (assigned, body) = fetch(format!("user-{}", i)).await;
+            assert_eq!(assigned, format!("checkout={}", body));
+            assert_eq!(fetch(format!("user-{}", i)).await, (assigned, body.clone()));
+            treated += usize::from(body == "treatment");
+        }
+        assert!((5..35).contains(&treated), "{} of 40 treated", treated);
+        assert_eq!(metrics.experiment_request_count("checkout", "treatment", "2xx"), 2 * treated as u64);
+        assert_eq!(metrics.experiment_request_count("checkout", "control", "2xx"), 2 * (40 - treated) as u64);
+
+        experiments.experiments[0].kill_switch = true;
+        let response = client
+            .put(format!("http://{}/admin/experiments", addr))
+            .json(&experiments)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        for i in 0..40 {
+            assert_eq!(fetch(format!("user-{}", i)).await, ("checkout=control".to_string(), "control".to_string()));
+        }
+
+        experiments.experiments[0].variants[1].service = "service-missing".to_string();
+        let response = client
+            .put(format!("http://{}/admin/experiments", addr))
+            .json(&experiments)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let active: ExperimentsConfig = client
+            .get(format!("http://{}/admin/experiments", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(active.experiments[0].kill_switch);
+        assert_eq!(active.experiments[0].variants[1].service, "service-b");
+    }
+
+    #[tokio::test]
+    async fn test_removed_endpoint_state_collected_after_ttl() {
+        let kept = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let removed = MockUpstream::start(MockResponse::default()).await.unwrap();
+        // Configured but never answers, so it stays unhealthy.
+        let dead = "http://127.0.0.1:1".to_string();
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![kept.url(), removed.url(), dead.clone()];
+        config.proxy_config.endpoint_gc.interval_ms = 20;
+        config.proxy_config.endpoint_gc.ttl_ms = 60_000;
+
+        let clock = Arc::new(MockClock::new());
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::builder()
+            .config(config)
+            .metrics(metrics.clone())
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let state = proxy.state.clone();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+
+        let all = [kept.url(), removed.url(), dead.clone()];
+        for endpoint in &all {
+            state
+                .ai_engine
+                .record_request(RequestMetrics {
+                    latency_ms: 3,
+                    status_code: 200,
+                    endpoint: endpoint.clone(),
+                    timestamp: 0,
+                    success: true,
+                    phases: None,
+                })
+                .await;
+            state.metrics.record_request(Direction::Ingress, endpoint, 3, true).await;
+            state.load_balancers.for_service("service-a").set_endpoint_weight("service-a", endpoint, 5).await;
+        }
+        // The first probe round runs at startup. Other services' endpoints
+        // are probed too, so counting statuses does not show it is over.
+        tokio::time::timeout(Duration::from_secs(5), state.health_checker.wait_for_sweep()).await.unwrap();
+        assert!(!state.health_checker.is_endpoint_healthy(&dead).await);
+
+        let client = reqwest::Client::new();
+        let response = client
+            .put(format!("http://{}/admin/endpoints", addr))
+            .json(&serde_json::json!({"service": "service-a", "endpoints": [kept.url(), dead.clone()]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let endpoints: serde_json::Value = client
+            .get(format!("http://{}/admin/endpoints", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(endpoints["service-a"], serde_json::json!([kept.url(), dead.clone()]));
+
+        let has_state = |endpoint: String| {
+            let state = state.clone();
+            async move {
+                let weight = state.load_balancers.for_service("service-a").explain("service-a", std::slice::from_ref(&endpoint)).await.endpoints[0].weight;
+                [
+                    state.ai_engine.get_service_health(&endpoint).await.is_some(),
+                    state.health_checker.get_health_status(&endpoint).await.is_some(),
+                    state.metrics.get_endpoint_stats().await.contains_key(&endpoint),
+                    weight == 5,
+                ]
+            }
+        };
+
+        clock.advance(Duration::from_secs(30));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(has_state(removed.url()).await, [true; 4]);
+
+        clock.advance(Duration::from_secs(31));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while metrics.endpoint_gc_count("metrics") == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(has_state(removed.url()).await, [false; 4]);
+        assert_eq!(has_state(kept.url()).await, [true; 4]);
+        assert_eq!(has_state(dead.clone()).await, [true; 4]);
+        for module in ["ai_engine", "health_checker", "load_balancer", "metrics"] {
+            assert!(metrics.endpoint_gc_count(module) > 0, "{} collected nothing", module);
+        }
+    }
+
+    async fn forward_spoofed_mesh_headers(config: Config, upstream: &MockUpstream) -> HeaderMap {
+        let addr = start_proxy(config).await;
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/a/items", addr))
+            .header("x-mesh-source-service", "spoofed")
+            .header("x-mesh-route", "/api/admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        upstream.last_request_headers("/api/a/items").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mesh_metadata_injected_upstream() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.mesh_metadata.instance_id = Some("sidecar-7".to_string());
+        config.mesh_metadata.service_name = Some("checkout".to_string());
+
+        let headers = forward_spoofed_mesh_headers(config, &upstream).await;
+        assert_eq!(headers["x-mesh-proxy-instance"], "sidecar-7");
+        assert_eq!(headers["x-mesh-proxy-version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(headers["x-mesh-route"], "/api/a");
+        assert_eq!(headers.get_all("x-mesh-source-service").iter().collect::<Vec<_>>(), vec!["checkout"]);
+    }
+
+    #[tokio::test]
+    async fn test_mesh_metadata_stripped_at_ingress() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.mesh_metadata.inject = false;
+
+        let headers = forward_spoofed_mesh_headers(config.clone(), &upstream).await;
+        assert!(!headers.contains_key("x-mesh-source-service"));
+        assert!(!headers.contains_key("x-mesh-route"));
+
+        config.mesh_metadata.strip_incoming = false;
+        let headers = forward_spoofed_mesh_headers(config, &upstream).await;
+        assert_eq!(headers["x-mesh-source-service"], "spoofed");
+    }
+
+    fn server_timing(response: &reqwest::Response) -> HashMap<String, String> {
+        response.headers()["server-timing"]
+            .to_str()
+            .unwrap()
+            .split(", ")
+            .filter_map(|metric| metric.split_once(';'))
+            .map(|(name, value)| (name.to_string(), value.split_once('=').unwrap().1.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_slow_connect_dominates_upstream_phases() {
+        // With a backlog of zero and one connection already queued, the kernel
+        // drops the proxy's SYN; connect completes on the client's retransmit,
+        // after the queue has been drained.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        let _queued = TcpStream::connect(upstream_addr).await.unwrap();
+        let upstream = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            MockUpstream::serve_with(listener, MockResponse::default).unwrap()
+        });
+
+        let addr = start_proxy(config_with_endpoint(format!("http://{}", upstream_addr))).await;
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/a/items", addr))
+            .header("x-proxy-debug", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let timing = server_timing(&response);
+        assert_eq!(timing["upstream-conn"], "new");
+        let phase = |name: &str| timing[&format!("upstream-{}", name)].parse::<f64>().unwrap();
+        assert!(phase("connect") >= 200.0, "{:?}", timing);
+        assert!(phase("connect") > phase("dns") + phase("tls") + phase("ttfb") + phase("body"), "{:?}", timing);
+        drop(upstream.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_first_request_reuses_prewarmed_connection() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.upstream_services.get_mut("service-a").unwrap().prewarm =
+            Some(serde_json::from_value(serde_json::json!({"connections": 2})).unwrap());
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let state = proxy.state.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.prewarm_count("service-a", "new") < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        // Only the startup probe reached the AI engine and nothing was counted
+        // as a request.
+        assert_eq!(state.ai_engine.get_service_health(&upstream.url()).await.unwrap().total_requests, 1);
+        assert_eq!(metrics.request_count(Direction::Ingress), 0);
+        assert!(metrics.get_endpoint_stats().await.is_empty());
+        assert_eq!(upstream.request_log().iter().filter(|line| *line == "HEAD /health").count(), 2);
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(metrics.upstream_connection_count(Direction::Ingress, "service-a", "reused"), 1);
+        assert_eq!(metrics.upstream_connection_count(Direction::Ingress, "service-a", "new"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_hop_phases_add_up_to_round_trip() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_millis(50),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.server_timing.always = true;
+        let addr = start_proxy(config).await;
+
+        let sent = Instant::now();
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        let round_trip = sent.elapsed().as_secs_f64() * 1000.0;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let timing = server_timing(&response);
+        let phase = |name: &str| timing[name].parse::<f64>().unwrap();
+        let names = ["route", "wait", "select", "connect", "ttfb", "transfer", "overhead"];
+        let sum: f64 = names.iter().map(|name| phase(name)).sum();
+        // Each entry is rounded to 0.1ms.
+        assert!((sum - phase("total")).abs() <= 0.05 * (names.len() + 1) as f64, "{:?}", timing);
+        assert!(phase("total") <= round_trip, "{:?} vs {}", timing, round_trip);
+        assert!(phase("ttfb") >= 50.0, "{:?}", timing);
+        // Connection detail is for trusted debug requests only.
+        assert!(!timing.contains_key("upstream-conn"), "{:?}", timing);
+    }
+
+    #[tokio::test]
+    async fn test_debug_timing_ignored_from_untrusted_callers() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.server_timing.trusted_cidrs = vec!["10.0.0.0/8".to_string()];
+        let addr = start_proxy(config).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/a/items", addr))
+            .header("x-proxy-debug", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("server-timing"));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timing_only_on_request() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let addr = start_proxy(config_with_endpoint(upstream.url())).await;
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert!(!response.headers().contains_key("server-timing"));
+    }
+
+    // An upstream whose port on ::1 never completes a handshake (same backlog
+    // trick as above) while 127.0.0.1 serves normally.
+    async fn dual_stack_upstream_with_stalled_ipv6() -> (MockUpstream, TcpListener, TcpStream) {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let port = upstream.addr().port();
+        let socket = tokio::net::TcpSocket::new_v6().unwrap();
+        socket.bind(SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port))).unwrap();
+        let stalled = socket.listen(0).unwrap();
+        let queued = TcpStream::connect(stalled.local_addr().unwrap()).await.unwrap();
+        (upstream, stalled, queued)
+    }
+
+    #[tokio::test]
+    async fn test_stalled_ipv6_falls_back_to_ipv4() {
+        let (upstream, _stalled, _queued) = dual_stack_upstream_with_stalled_ipv6().await;
+        let mut config = config_with_endpoint(format!("http://dual.test:{}", upstream.addr().port()));
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        // Listed IPv4 first; auto must still give IPv6 the head start.
+        service.hosts.insert(
+            "dual.test".to_string(),
+            vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+        );
+
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/a/items", addr))
+            .header("x-proxy-debug", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let timing = server_timing(&response);
+        let connect: f64 = timing["upstream-connect"].parse().unwrap();
+        assert!(connect >= 250.0, "IPv4 raced before the IPv6 head start ran out: {:?}", timing);
+        assert!(connect < 1000.0, "waited out the IPv6 connect: {:?}", timing);
+        assert_eq!(metrics.upstream_family_count(Direction::Ingress, "service-a", "ipv4"), 1);
+        assert_eq!(metrics.upstream_family_count(Direction::Ingress, "service-a", "ipv6"), 0);
+
+    }
+
+    #[tokio::test]
+    async fn test_v4_only_skips_ipv6_addresses() {
+        let (upstream, _stalled, _queued) = dual_stack_upstream_with_stalled_ipv6().await;
+        let mut config = config_with_endpoint(format!("http://dual.test:{}", upstream.addr().port()));
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.address_family = address_family::AddressFamily::V4Only;
+        service.hosts.insert(
+            "dual.test".to_string(),
+            vec!["::1".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+        );
+        let addr = start_proxy(config).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/a/items", addr))
+            .header("x-proxy-debug", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let connect: f64 = server_timing(&response)["upstream-connect"].parse().unwrap();
+        assert!(connect < 250.0, "{}", connect);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get_validated_with_head() {
+        let upstream = MockUpstream::start(MockResponse {
+            headers: vec![
+                ("etag".to_string(), "\"v1\"".to_string()),
+                ("content-type".to_string(), "text/plain".to_string()),
+            ],
+            body: Bytes::from_static(b"current body"),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.upstream_services.get_mut("service-a").unwrap().validate_with_head = true;
+        let addr = start_proxy(config).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/a/doc", addr);
+
+        let response = client.get(&url).header("if-none-match", "W/\"v1\"").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], "\"v1\"");
+        assert!(!response.headers().contains_key("content-type"));
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        let response = client.get(&url).header("if-none-match", "\"v0\"").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), "current body");
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let log: Vec<_> = upstream
+            .request_log()
+            .into_iter()
+            .filter(|line| line.ends_with("/api/a/doc"))
+            .collect();
+        assert_eq!(log, ["HEAD /api/a/doc", "HEAD /api/a/doc", "GET /api/a/doc", "GET /api/a/doc"]);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_announces_each_client() {
+        // Hands back what each announced connection carried before its
+        // request line; health probes come unannounced and are just answered.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let (heads, mut announced) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let heads = heads.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut byte = [0u8];
+                    while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).await.unwrap_or(0) == 1 {
+                        head.push(byte[0]);
+                    }
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").await;
+                    let head = String::from_utf8(head).unwrap();
+                    if head.starts_with("PROXY ") {
+                        let _ = heads.send(head);
+                    }
+                });
+            }
+        });
+        let mut config = config_with_endpoint(format!("http://{}", upstream_addr));
+        config.upstream_services.get_mut("service-a").unwrap().proxy_protocol = true;
+        let addr = start_proxy(config).await;
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET /api/a/items HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200"));
+            assert!(response.ends_with(b"ok"));
+
+            // A connection of its own per request, so each names its client.
+            let head = announced.recv().await.unwrap();
+            let (proxy_line, request) = head.split_once("\r\n").unwrap();
+            assert_eq!(
+                format!("{}\r\n", proxy_line),
+                proxy_protocol::header(client.local_addr().unwrap(), upstream_addr)
+            );
+            assert!(request.starts_with("GET /api/a/items HTTP/1.1\r\n"), "{}", request);
+            assert!(request.contains(&format!("host: {}", upstream_addr)), "{}", request);
+        }
+    }
+
+    fn checksum_config(upstream: &MockUpstream, policy: RouteChecksumConfig) -> Config {
+        let mut config = config_with_endpoint(upstream.url());
+        config.body_checksums.routes.insert("/api/a".to_string(), policy);
+        config
+    }
+
+    fn sha256_header(body: &[u8]) -> String {
+        RouteChecksum::header_value(&ChecksumAlgorithm::Sha256.digest(body)).to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_request_body_checksum_verified_and_generated() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let addr = start_proxy(checksum_config(
+            &upstream,
+            RouteChecksumConfig {
+                algorithm: ChecksumAlgorithm::Sha256,
+                header: None,
+                verify_request: true,
+                generate_request: true,
+                verify_response: false,
+                on_response_mismatch: MismatchAction::Reject,
+            },
+        ))
+        .await;
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{}{}", addr, path);
+
+        let response = client
+            .put(url("/api/a/corrupt"))
+            .header("x-amz-checksum-sha256", sha256_header(b"original bytes"))
+            .body("flipped bytes")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(upstream.last_request_headers("/api/a/corrupt").is_none());
+
+        let response = client
+            .put(url("/api/a/intact"))
+            .header("x-amz-checksum-sha256", sha256_header(b"original bytes"))
+            .body("original bytes")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = client.put(url("/api/a/unsigned")).body("original bytes").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let forwarded = upstream.last_request_headers("/api/a/unsigned").unwrap();
+        assert_eq!(forwarded["x-amz-checksum-sha256"], sha256_header(b"original bytes").as_str());
+    }
+
+    #[tokio::test]
+    async fn test_response_body_checksum_mismatch_action() {
+        let body = Bytes::from_static(b"stored object");
+        let respond = |claimed: &[u8]| MockResponse {
+            headers: vec![("x-amz-checksum-sha256".to_string(), sha256_header(claimed))],
+            body: body.clone(),
+            ..MockResponse::default()
+        };
+        let policy = |action| RouteChecksumConfig {
+            algorithm: ChecksumAlgorithm::Sha256,
+            header: None,
+            verify_request: false,
+            generate_request: false,
+            verify_response: true,
+            on_response_mismatch: action,
+        };
+
+        let intact = MockUpstream::start(respond(b"stored object")).await.unwrap();
+        let addr = start_proxy(checksum_config(&intact, policy(MismatchAction::Reject))).await;
+        let response = reqwest::get(format!("http://{}/api/a/object", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), body);
+
+        let corrupt = MockUpstream::start(respond(b"something else")).await.unwrap();
+        let addr = start_proxy(checksum_config(&corrupt, policy(MismatchAction::Reject))).await;
+        let response = reqwest::get(format!("http://{}/api/a/object", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let addr = start_proxy(checksum_config(&corrupt, policy(MismatchAction::Log))).await;
+        let response = reqwest::get(format!("http://{}/api/a/object", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), body);
+    }
+
+    async fn start_egress_proxy(upstream: &MockUpstream) -> (Arc<MetricsCollector>, SocketAddr) {
+        let mut config = Config::new();
+        let mut payments = config.upstream_services["service-a"].clone();
+        payments.name = "payments".to_string();
+        payments.endpoints = vec![upstream.url()];
+        config.egress.services.insert("payments".to_string(), payments);
+
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let ingress = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let egress = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = proxy.run_with_egress(ingress, egress).unwrap();
+        (metrics, handle.egress_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_egress_forwards_allowed_destinations() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let (metrics, egress) = start_egress_proxy(&upstream).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/v1/charges?id=7", egress))
+            .header("x-proxy-destination", "payments")
+            .body("{}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let forwarded = upstream.last_request_headers("/v1/charges").unwrap();
+        assert!(!forwarded.contains_key("x-proxy-destination"));
+
+        // An application using the sidecar as its HTTP proxy names the
+        // destination in an absolute-form request target instead.
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://{}", egress)).unwrap())
+            .build()
+            .unwrap();
+        let response = client.get("http://payments/v1/refunds").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(upstream.last_request_headers("/v1/refunds").is_some());
+
+        assert_eq!(metrics.request_count(Direction::Egress), 2);
+        assert_eq!(metrics.request_count(Direction::Ingress), 0);
+    }
+
+    #[tokio::test]
+    async fn test_response_header_policy_applies_per_listener() {
+        let upstream = MockUpstream::start(MockResponse {
+            headers: vec![
+                ("x-internal-shard".to_string(), "7".to_string()),
+                ("x-internal-build".to_string(), "2024.1".to_string()),
+                ("x-request-id".to_string(), "abc".to_string()),
+            ],
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        let mut payments = config.upstream_services["service-a"].clone();
+        payments.name = "payments".to_string();
+        config.egress.services.insert("payments".to_string(), payments);
+        config.response_headers.ingress.deny = vec!["x-internal-*".to_string()];
+
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let ingress = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let egress = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = proxy.run_with_egress(ingress, egress).unwrap();
+
+        let external = reqwest::get(format!("http://{}/api/a/items", handle.local_addr())).await.unwrap();
+        assert_eq!(external.status(), StatusCode::OK);
+        assert!(!external.headers().contains_key("x-internal-shard"));
+        assert!(!external.headers().contains_key("x-internal-build"));
+        assert_eq!(external.headers()["x-request-id"], "abc");
+
+        let internal = reqwest::Client::new()
+            .get(format!("http://{}/items", handle.egress_addr().unwrap()))
+            .header("x-proxy-destination", "payments")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(internal.status(), StatusCode::OK);
+        assert_eq!(internal.headers()["x-internal-shard"], "7");
+        assert_eq!(internal.headers()["x-internal-build"], "2024.1");
+
+        assert_eq!(metrics.response_headers_stripped_count(Direction::Ingress, "x-internal-shard"), 1);
+        assert_eq!(metrics.response_headers_stripped_count(Direction::Egress, "x-internal-shard"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_egress_rejects_denied_and_malformed_destinations() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let (metrics, egress) = start_egress_proxy(&upstream).await;
+        let client = reqwest::Client::new();
+        let send = |destination: Option<&'static str>| {
+            let mut request = client.get(format!("http://{}/v1/users", egress));
+            if let Some(destination) = destination {
+                request = request.header("x-proxy-destination", destination);
+            }
+            request.send()
+        };
+
+        assert_eq!(send(Some("ledger")).await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(send(Some("payments:8443")).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(send(Some("http://payments")).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(send(None).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        // Ingress-only routes do not exist on the egress listener.
+        assert_eq!(
+            reqwest::get(format!("http://{}/admin/health", egress)).await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        assert!(upstream.last_request_headers("/v1/users").is_none());
+        assert_eq!(metrics.egress_rejection_count("not_allowed"), 1);
+        assert_eq!(metrics.egress_rejection_count("malformed"), 2);
+        assert_eq!(metrics.egress_rejection_count("missing"), 2);
+    }
+
+    fn archive_config(upstream: &MockUpstream, spool_dir: &std::path::Path, queue_capacity: usize) -> Config {
+        let mut config = config_with_endpoint(upstream.url());
+        config.archive.spool_dir = spool_dir.display().to_string();
+        config.archive.queue_capacity = queue_capacity;
+        config.archive.routes.insert(
+            "/api/a".to_string(),
+            crate::archive::RouteArchiveConfig {
+                sample_rate: 1.0,
+                max_body_bytes: 8,
+            },
+        );
+        config
+    }
+
+    #[tokio::test]
+    async fn test_archive_spools_redacted_records() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let spool_dir = std::env::temp_dir().join(format!("ai-sidecar-proxy-{}", uuid::Uuid::new_v4()));
+        let addr = start_proxy(archive_config(&upstream, &spool_dir, 16)).await;
+
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/api/a/items", addr))
+            .header("authorization", "Bearer secret")
+            .body("a request body longer than the cap")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let record = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let spooled = std::fs::read_dir(&spool_dir)
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+                    .find(|text| !text.is_empty());
+                if let Some(text) = spooled {
+                    return serde_json::from_str::<serde_json::Value>(text.trim_end()).unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(record["route"], "/api/a");
+        assert_eq!(record["path"], "/api/a/items");
+        assert_eq!(record["status"], 200);
+        let authorization = record["request_headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair[0] == "authorization")
+            .unwrap();
+        assert_eq!(authorization[1], "[redacted]");
+        assert_eq!(record["request_body"]["truncated"], true);
+        assert_eq!(record["request_body"]["data"], "YSByZXF1ZXM=");
+        std::fs::remove_dir_all(&spool_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_capture_records_sampled_exchanges_until_expiry() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let client = reqwest::Client::new();
+        let disabled = start_proxy(config_with_endpoint(upstream.url())).await;
+        let response = client.get(format!("http://{}/admin/capture/any", disabled)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let mut config = config_with_endpoint(upstream.url());
+        config.capture.enabled = true;
+        config.capture.max_body_bytes = 8;
+        config.capture.retention_s = 60;
+        config.capture.admin_tokens.insert("alice".to_string(), "s3cret".to_string());
+        let clock = Arc::new(MockClock::new());
+        let proxy = ProxyServer::builder().config(config).clock(clock.clone()).build().unwrap();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+
+        let start = serde_json::json!({
+            "service": "service-a",
+            "max_records": 2,
+            "duration_s": 30,
+            "include_bodies": true,
+        });
+        let capture_url = format!("http://{}/admin/capture", addr);
+        let response = client.post(&capture_url).json(&start).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client.post(&capture_url).bearer_auth("s3cret").json(&start).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let id = response.json::<serde_json::Value>().await.unwrap()["id"].as_str().unwrap().to_string();
+
+        for _ in 0..3 {
+            let response = client
+                .put(format!("http://{}/api/a/items", addr))
+                .header("authorization", "Bearer client-token")
+                .body("a request body longer than the cap")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let view_url = format!("{}/{}", capture_url, id);
+        let view: serde_json::Value =
+            client.get(&view_url).bearer_auth("s3cret").send().await.unwrap().json().await.unwrap();
+        assert_eq!(view["operator"], "alice");
+        let records = view["records"].as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        let authorization = records[0]["request_headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|pair| pair[0] == "authorization")
+            .unwrap();
+        assert_eq!(authorization[1], "[redacted]");
+        assert_eq!(records[0]["request_body"]["truncated"], true);
+        assert_eq!(records[0]["request_body"]["data"], "YSByZXF1ZXM=");
+
+        clock.advance(Duration::from_secs(90));
+        let response = client.get(&view_url).bearer_auth("s3cret").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_full_archive_spool_never_blocks_requests() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        // A file where the spool directory should be, so every write fails,
+        // behind a one-slot queue.
+        let spool_dir = std::env::temp_dir().join(format!("ai-sidecar-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&spool_dir, "not a directory").unwrap();
+        let config = archive_config(&upstream, &spool_dir, 1);
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let client = reqwest::Client::new();
+        let requests = (0..50).map(|i| {
+            client
+                .post(format!("http://{}/api/a/{}", addr, i))
+                .body("payload")
+                .send()
+        });
+        let responses = tokio::time::timeout(Duration::from_secs(10), futures::future::join_all(requests))
+            .await
+            .expect("requests waited on the archive writer");
+        for response in responses {
+            assert_eq!(response.unwrap().status(), StatusCode::OK);
+        }
+
+        assert_eq!(metrics.archive_count("queued") + metrics.archive_count("dropped"), 50);
+        std::fs::remove_file(&spool_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_health_merges_views() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let addr = start_proxy(config_with_endpoint(upstream.url())).await;
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let health: serde_json::Value = reqwest::get(format!("http://{}/admin/health", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let service = health["services"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|service| service["service"] == "service-a")
+            .unwrap();
+        assert_eq!(service["breaker"], "closed");
+
+        let endpoint = &service["endpoints"][0];
+        assert_eq!(endpoint["endpoint"], upstream.url().as_str());
+        assert_eq!(endpoint["routable"], true);
+        assert_eq!(endpoint["reasons"].as_array().unwrap().len(), 0);
+        assert_eq!(endpoint["in_flight"], 0);
+        assert!(endpoint["passive"]["total_requests"].as_u64().unwrap() >= 1);
+    }
+
+    // An upstream that answers 500 while the returned flag is set.
+    async fn failing_upstream() -> (MockUpstream, Arc<AtomicBool>) {
+        let failing = Arc::new(AtomicBool::new(true));
+        let flag = failing.clone();
+        let upstream = MockUpstream::start_with(move || MockResponse {
+            status: if flag.load(Ordering::Relaxed) { 500 } else { 200 },
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        (upstream, failing)
+    }
+
+    fn breaker_probe_config(endpoint: String, threshold: u32, probe: BreakerProbeConfig) -> Config {
+        let mut config = config_with_endpoint(endpoint);
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.circuit_breaker_threshold = threshold;
+        service.breaker_probe = probe;
+        // One upstream call per request, so the upstream's log counts requests.
+        service.max_retries = 0;
+        config
+    }
+
+    async fn circuit_open(response: reqwest::Response) -> bool {
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.unwrap();
+        status == StatusCode::SERVICE_UNAVAILABLE && body["code"] == "CIRCUIT_OPEN"
+    }
+
+    #[tokio::test]
+    async fn test_synthetic_probes_close_breaker_without_live_traffic() {
+        let (upstream, failing) = failing_upstream().await;
+        let config = breaker_probe_config(
+            upstream.url(),
+            2,
+            BreakerProbeConfig {
+                mode: HalfOpenProbeMode::Synthetic,
+                open_ms: 100,
+                successes_to_close: 2,
+                synthetic_interval_ms: 250,
+                ..BreakerProbeConfig::default()
+            },
+        );
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let state = proxy.state.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let url = format!("http://{}/api/a/items", addr);
+        for _ in 0..2 {
+            assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        // Half-open or not, live requests are turned away before reaching the
+        // upstream, and the failing health check keeps reopening the breaker.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.breaker_probe_count("service-a", "synthetic", "rejected") == 0
+                || metrics.breaker_probe_count("service-a", "synthetic", "failure") == 0
+            {
+                assert!(circuit_open(reqwest::get(&url).await.unwrap()).await);
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+        })
+        .await
+        .expect("no live request was refused while half-open");
+        let live = |log: Vec<String>| log.iter().filter(|line| *line == "GET /api/a/items").count();
+        assert_eq!(live(upstream.request_log()), 2);
+
+        failing.store(false, Ordering::Relaxed);
+        let breaker = state.upstreams().breakers["service-a"].clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while breaker.current_state().await != CircuitBreakerState::Closed {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("synthetic probes never closed the breaker");
+        assert_eq!(live(upstream.request_log()), 2);
+        assert_eq!(metrics.breaker_probe_count("service-a", "synthetic", "success"), 2);
+        assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::OK);
+
+        let health: serde_json::Value = reqwest::get(format!("http://{}/admin/health", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let service = health["services"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|service| service["service"] == "service-a")
+            .unwrap();
+        assert_eq!(service["breaker"], "closed");
+        assert_eq!(service["breaker_probes"]["mode"], "synthetic");
+        assert_eq!(service["breaker_probes"]["succeeded"], 2);
+        assert!(service["breaker_probes"]["failed"].as_u64().unwrap() >= 1);
+        assert!(service["breaker_probes"]["refused"].as_u64().unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_probes_refuse_unsafe_methods() {
+        let (upstream, failing) = failing_upstream().await;
+        let config = breaker_probe_config(
+            upstream.url(),
+            1,
+            BreakerProbeConfig {
+                mode: HalfOpenProbeMode::Idempotent,
+                open_ms: 100,
+                successes_to_close: 1,
+                ..BreakerProbeConfig::default()
+            },
+        );
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/a/items", addr);
+        assert_eq!(client.get(&url).send().await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        failing.store(false, Ordering::Relaxed);
+        assert!(circuit_open(client.get(&url).send().await.unwrap()).await);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(circuit_open(client.post(&url).body("order").send().await.unwrap()).await);
+        assert_eq!(metrics.breaker_probe_count("service-a", "idempotent", "rejected"), 1);
+
+        assert_eq!(client.get(&url).send().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(metrics.breaker_probe_count("service-a", "idempotent", "success"), 1);
+        assert_eq!(client.post(&url).body("order").send().await.unwrap().status(), StatusCode::OK);
+
+        let posts = upstream.request_log().iter().filter(|line| *line == "POST /api/a/items").count();
+        assert_eq!(posts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_services_shows_effective_policy() {
+        let mut config = serde_json::to_value(Config::new()).unwrap();
+        config["policy_templates"] = serde_json::json!({
+            "standard": {"timeout_ms": 2000, "max_retries": 2, "circuit_breaker_threshold": 4, "health_check_path": "/ready"},
+            "strict": {"extends": "standard", "breaker_probe": {"mode": "idempotent"}},
+        });
+        let service = config["upstream_services"]["service-a"].as_object_mut().unwrap();
+        for field in ["timeout_ms", "max_retries", "circuit_breaker_threshold", "health_check_path", "breaker_probe"] {
+            service.remove(field);
+        }
+        service.insert("policy".to_string(), "strict".into());
+        service.insert("max_retries".to_string(), 0.into());
+        let config = crate::config_migration::upgrade(&config.to_string()).unwrap().config;
+
+        let addr = start_proxy(config).await;
+        let services: serde_json::Value = reqwest::get(format!("http://{}/admin/services", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let service = services["services"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|service| service["service"] == "service-a")
+            .unwrap();
+        assert_eq!(service["direction"], "ingress");
+        assert_eq!(service["policy"], "strict");
+        assert_eq!(service["timeout_ms"], 2000);
+        assert_eq!(service["max_retries"], 0);
+        assert_eq!(service["circuit_breaker_threshold"], 4);
+        assert_eq!(service["health_check_path"], "/ready");
+        assert_eq!(service["breaker_probe"]["mode"], "idempotent");
+        assert!(service.get("auth").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_budget_rejects_when_saturated() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_millis(500),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.max_buffered_bytes = 1024 * 1024;
+        let addr = start_proxy(config).await;
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/a/upload", addr);
+        let body = vec![b'x'; 600 * 1024];
+        let requests = (0..4).map(|_| client.put(&url).body(body.clone()).send());
+        let responses = futures::future::join_all(requests).await;
+
+        let mut accepted = 0;
+        let mut rejected = 0;
+        for response in responses {
+            let response = response.unwrap();
+            if response.status() == StatusCode::OK {
+                accepted += 1;
+            } else {
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+                let error: serde_json::Value = response.json().await.unwrap();
+                assert_eq!(error["code"], "buffer_budget_exhausted");
+                rejected += 1;
+            }
+        }
+        assert!(accepted <= 1);
+        assert!(rejected >= 3);
+
+        let runtime: serde_json::Value = client
+            .get(format!("http://{}/admin/runtime", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(runtime["buffers"]["used_bytes"], 0);
+        let high_water = runtime["buffers"]["high_water_bytes"].as_u64().unwrap();
+        assert!(high_water > 0 && high_water <= 1024 * 1024);
+
+        let response = client.put(&url).body(body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Answers with the length and CRC of the body it got and how it was
+    // framed, and reports the first body bytes as soon as they arrive.
+    async fn spawn_counting_upstream() -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (first_bytes, arrived) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let first_bytes = first_bytes.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let first_bytes = first_bytes.clone();
+                    async move {
+                        let header = |name| req.headers().get(name).map(|v: &header::HeaderValue| v.to_str().unwrap().to_string());
+                        let (content_length, transfer_encoding) = (header("content-length"), header("transfer-encoding"));
+                        let mut body = req.into_body();
+                        let (mut bytes, mut crc) = (0usize, crc32fast::Hasher::new());
+                        while let Some(frame) = body.frame().await {
+                            if let Ok(data) = frame?.into_data() {
+                                if bytes == 0 {
+                                    let _ = first_bytes.send(());
+                                }
+                                bytes += data.len();
+                                crc.update(&data);
+                            }
+                        }
+                        let seen = serde_json::json!({
+                            "bytes": bytes,
+                            "crc": crc.finalize(),
+                            "content_length": content_length,
+                            "transfer_encoding": transfer_encoding,
+                        });
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from(seen.to_string()))))
+                    }
+                });
+                tokio::spawn(ServerBuilder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).into_owned());
+            }
+        });
+        (addr, arrived)
+    }
+
+    #[tokio::test]
+    async fn test_large_request_bodies_stream_through_a_small_budget() {
+        const CHUNK: usize = 256 * 1024;
+        const CHUNKS: usize = 64;
+        let (upstream, mut arrived) = spawn_counting_upstream().await;
+        let mut config = config_with_endpoint(format!("http://{}", upstream));
+        config.proxy_config.max_buffered_bytes = 256 * 1024;
+        config.proxy_config.replay_buffer_bytes = 64 * 1024;
+        config.proxy_config.max_body_bytes = 64 * 1024 * 1024;
+        let addr = start_proxy(config).await;
+
+        let chunk: Vec<u8> = (0..CHUNK).map(|i| (i % 251) as u8).collect();
+        let mut crc = crc32fast::Hasher::new();
+        for _ in 0..CHUNKS {
+            crc.update(&chunk);
+        }
+        let crc = crc.finalize();
+
+        for chunked in [true, false] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let framing = match chunked {
+                true => "transfer-encoding: chunked".to_string(),
+                false => format!("content-length: {}", CHUNK * CHUNKS),
+            };
+            let head = format!("POST /api/a/upload HTTP/1.1\r\nhost: proxy\r\n{}\r\nconnection: close\r\n\r\n", framing);
+            client.write_all(head.as_bytes()).await.unwrap();
+            for sent in 0..CHUNKS {
+                if chunked {
+                    client.write_all(format!("{:x}\r\n", CHUNK).as_bytes()).await.unwrap();
+                }
+                client.write_all(&chunk).await.unwrap();
+                if chunked {
+                    client.write_all(b"\r\n").await.unwrap();
+                }
+                // The upstream has bytes before the client is done sending.
+                if sent == 0 {
+                    tokio::time::timeout(Duration::from_secs(5), arrived.recv())
+                        .await
+                        .expect("nothing reached the upstream while the body was still coming");
+                }
+            }
+            if chunked {
+                client.write_all(b"0\r\n\r\n").await.unwrap();
+            }
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            let seen: serde_json::Value = serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+            assert_eq!(seen["bytes"], CHUNK * CHUNKS);
+            assert_eq!(seen["crc"], crc);
+            if chunked {
+                assert_eq!(seen["transfer_encoding"], "chunked");
+                assert!(seen["content_length"].is_null());
+            } else {
+                assert_eq!(seen["content_length"], (CHUNK * CHUNKS).to_string());
+                assert!(seen["transfer_encoding"].is_null());
+            }
+        }
+
+        let runtime: serde_json::Value = reqwest::get(format!("http://{}/admin/runtime", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(runtime["buffers"]["used_bytes"], 0);
+        assert!(runtime["buffers"]["high_water_bytes"].as_u64().unwrap() <= 256 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_event_streams_are_relayed_as_they_arrive() {
+        use futures::StreamExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let released = Arc::new(std::sync::Mutex::new(Some(released)));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let released = released.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let released = released.clone();
+                    async move {
+                        // Health probes get a plain answer.
+                        if req.uri().path() == "/health" {
+                            let ok = Full::new(Bytes::from("ok")).map_err(|never| match never {});
+                            return Ok::<_, hyper::Error>(Response::new(BodyExt::boxed(ok)));
+                        }
+                        let released = released.lock().unwrap().take().unwrap();
+                        let first = futures::stream::once(async { Bytes::from("data: first\n\n") });
+                        let last = futures::stream::once(async move {
+                            let _ = released.await;
+                            Bytes::from("data: last\n\n")
+                        });
+                        let frames = first.chain(last).map(|chunk| Ok::<_, hyper::Error>(Frame::data(chunk)));
+                        let response = Response::builder()
+                            .header("content-type", "text/event-stream")
+                            .body(BodyExt::boxed(StreamBody::new(frames)))
+                            .unwrap();
+                        Ok(response)
+                    }
+                });
+                tokio::spawn(ServerBuilder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).into_owned());
+            }
+        });
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(
+            config_with_endpoint(format!("http://{}", upstream)),
+            Arc::new(AIEngine::new()),
+            metrics.clone(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let mut response = reqwest::get(format!("http://{}/api/a/events", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        assert!(!response.headers().contains_key("content-length"));
+        let first = tokio::time::timeout(Duration::from_secs(5), response.chunk()).await.unwrap().unwrap();
+        assert_eq!(first.unwrap(), "data: first\n\n");
+        // Counted when the head arrived; the transfer is counted once it ends.
+        assert_eq!(metrics.request_count(Direction::Ingress), 1);
+        assert_eq!(metrics.upstream_phase_count(Direction::Ingress, "service-a", "body"), 0);
+
+        release.send(()).unwrap();
+        assert_eq!(response.chunk().await.unwrap().unwrap(), "data: last\n\n");
+        assert!(response.chunk().await.unwrap().is_none());
+        assert_eq!(metrics.upstream_phase_count(Direction::Ingress, "service-a", "body"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_only_replayable_bodies_are_retried() {
+        let upstream = MockUpstream::start(MockResponse { status: 503, ..MockResponse::default() }).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.replay_buffer_bytes = 64;
+        config.proxy_config.retry_base_delay_ms = 1;
+        let addr = start_proxy(config).await;
+        let client = reqwest::Client::new();
+        let puts = || upstream.request_log().iter().filter(|line| line.starts_with("PUT")).count();
+
+        let response = client.put(format!("http://{}/api/a/small", addr)).body(vec![b'x'; 64]).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(puts(), 4);
+
+        // Over the replay buffer, so streamed and sent once.
+        let response = client.put(format!("http://{}/api/a/large", addr)).body(vec![b'x'; 65]).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(puts(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body_over_the_limit_is_refused() {
+        let (upstream, _arrived) = spawn_counting_upstream().await;
+        let mut config = config_with_endpoint(format!("http://{}", upstream));
+        config.proxy_config.replay_buffer_bytes = 16;
+        config.proxy_config.max_body_bytes = 64;
+        let addr = start_proxy(config).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let body = format!("20\r\n{}\r\n", "x".repeat(32));
+        client
+            .write_all(format!("POST /api/a/upload HTTP/1.1\r\nhost: proxy\r\ntransfer-encoding: chunked\r\n\r\n{}{}{}0\r\n\r\n", body, body, body).as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![0; 1024];
+        let read = client.read(&mut response).await.unwrap();
+        assert!(response[..read].starts_with(b"HTTP/1.1 413"), "{}", String::from_utf8_lossy(&response[..read]));
+    }
+
+    #[tokio::test]
+    async fn test_completion_event_has_structured_fields() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(logs.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let closed_port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let endpoint = format!("http://127.0.0.1:{}", closed_port);
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![endpoint.clone()];
+
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /api/a/items HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 503"));
+
+        let events = logs.events();
+        let completion = events
+            .iter()
+            .find(|e| e["fields"]["message"] == "request completed")
+            .expect("no completion event");
+
+        assert_eq!(completion["fields"]["status"], 503);
+        assert!(completion["fields"]["latency_ms"].is_u64());
+        assert_eq!(completion["fields"]["endpoint"], endpoint.as_str());
+        assert!(completion["span"]["request_id"].is_string());
+        assert_eq!(completion["span"]["route"], "/api/a");
+        assert_eq!(completion["span"]["service"], "service-a");
+        assert_eq!(completion["span"]["client_ip"], "127.0.0.1");
+        if cfg!(feature = "structured-logs") {
+            let access_log: serde_json::Value =
+                serde_json::from_str(completion["fields"]["access_log"].as_str().unwrap()).unwrap();
+            assert_eq!(access_log["status_code"], 503);
+            assert_eq!(access_log["path"], "/api/a/items");
+            assert_eq!(access_log["upstream_endpoint"], endpoint.as_str());
+            assert_eq!(access_log["request_id"], completion["span"]["request_id"]);
+        } else {
+            assert!(completion["fields"].get("access_log").is_none());
+        }
+
+        let upstream_call = events
+            .iter()
+            .find(|e| e["fields"]["message"] == "upstream call failed")
+            .expect("no upstream call event");
+        // The default max_retries of 3, all on the one endpoint there is.
+        assert_eq!(upstream_call["fields"]["attempt"], 4);
+        assert_eq!(upstream_call["fields"]["endpoint"], endpoint.as_str());
+        let retries: Vec<_> = events
+            .iter()
+            .filter(|e| e["fields"]["message"] == "upstream call failed, retrying")
+            .map(|e| e["fields"]["attempt"].clone())
+            .collect();
+        assert_eq!(retries, vec![1, 2, 3]);
+    }
+
+    async fn start_timeout_proxy(upstream: &MockUpstream, metrics: Arc<MetricsCollector>) -> SocketAddr {
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.client_timeouts.header_read_ms = 300;
+        config.proxy_config.client_timeouts.body_read_idle_ms = 300;
+        config.proxy_config.client_timeouts.response_write_idle_ms = 300;
+        let proxy = ProxyServer::builder().config(config).metrics(metrics).build().unwrap();
+        proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr()
+    }
+
+    async fn raw_exchange(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_target_forms_handled_explicitly() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config_with_endpoint(upstream.url()), Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let response = raw_exchange(addr, "OPTIONS * HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.to_ascii_lowercase().contains("allow: get,"));
+        assert_eq!(metrics.request_target_count("asterisk", "answered"), 1);
+
+        let response = raw_exchange(
+            addr,
+            "GET http://proxy.test/api/a/items?page=2 HTTP/1.1\r\nhost: proxy.test\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(upstream.request_log().contains(&"GET /api/a/items".to_string()));
+        assert_eq!(
+            upstream.last_request_headers("/api/a/items").unwrap()["host"],
+            upstream.url().trim_start_matches("http://")
+        );
+        assert_eq!(metrics.request_target_count("absolute", "normalized"), 1);
+
+        let rejected = [
+            ("GET http://evil.test/api/a/items HTTP/1.1\r\nhost: proxy.test", "400", "host_mismatch", "absolute"),
+            ("CONNECT upstream.test:443 HTTP/1.1\r\nhost: upstream.test:443", "405", "connect_not_supported", "authority"),
+            ("GET upstream.test:443 HTTP/1.1\r\nhost: upstream.test", "400", "malformed_request_target", "authority"),
+            ("DELETE * HTTP/1.1\r\nhost: proxy", "400", "malformed_request_target", "asterisk"),
+            ("GET ftp://proxy.test/x HTTP/1.1\r\nhost: proxy.test", "400", "malformed_request_target", "absolute"),
+        ];
+        for (head, status, code, form) in rejected {
+            let response = raw_exchange(addr, &format!("{}\r\nconnection: close\r\n\r\n", head)).await;
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{}: {}", head, response);
+            assert!(response.contains(&format!("\"code\":\"{}\"", code)), "{}: {}", head, response);
+            assert!(metrics.request_target_count(form, "rejected") >= 1, "{}", head);
+        }
+        let forwarded = upstream.request_log().iter().filter(|line| !line.ends_with("/health")).count();
+        assert_eq!(forwarded, 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_tasks_capped_at_max_connections() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.max_connections = 1;
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.connection_tasks() != 1.0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection task never counted");
+
+        // Over the cap the connection is closed without being served.
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), refused.read_to_end(&mut response)).await;
+        assert!(read.is_ok(), "refused connection left open");
+        assert!(response.is_empty());
+        assert_eq!(metrics.connection_shed_count("task_limit"), 1);
+        assert_eq!(metrics.connection_tasks(), 1.0);
+
+        drop(idle);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.connection_tasks() != 0.0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection task never finished");
+
+        let response = raw_exchange(addr, "GET /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    // Fails the accepts it is given errors for, then accepts for real.
+    struct FailingListener {
+        inner: TcpListener,
+        errors: std::sync::Mutex<Vec<std::io::Error>>,
+    }
+
+    impl Listener for FailingListener {
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        fn poll_accept(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<(TcpStream, SocketAddr)>> {
+            match self.errors.lock().unwrap().pop() {
+                Some(error) => std::task::Poll::Ready(Err(error)),
+                None => self.inner.poll_accept(cx),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors_do_not_stop_the_listener() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config_with_endpoint(upstream.url()), Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = inner.local_addr().unwrap();
+        let errors = vec![
+            std::io::Error::from(std::io::ErrorKind::ConnectionAborted),
+            std::io::Error::other("no buffer space available"),
+        ];
+        let listener = FailingListener { inner, errors: std::sync::Mutex::new(errors) };
+        let serving = tokio::spawn(async move { proxy.serve_listeners(listener, None).await });
+
+        let response = raw_exchange(addr, "GET /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(!serving.is_finished());
+        assert_eq!(metrics.accept_error_count(&addr.to_string(), "other"), 1);
+        assert_eq!(metrics.accept_error_count(&addr.to_string(), "aborted"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_strict_http_rejects_smuggling_shapes() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let shapes = [
+            (
+                "content-length: 5\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n",
+                "content_length_with_transfer_encoding",
+            ),
+            ("content-length: 1\r\ncontent-length: 2\r\n\r\nx", "conflicting_content_length"),
+            ("x-folded: one\r\n two\r\n\r\n", "obs_fold"),
+            ("bad name: x\r\n\r\n", "invalid_header_name"),
+        ];
+
+        for strict in [true, false] {
+            let metrics = Arc::new(MetricsCollector::new());
+            let mut config = config_with_endpoint(upstream.url());
+            config.proxy_config.strict_http = strict;
+            let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { proxy.serve(listener).await });
+
+            for (rest, code) in shapes {
+                let request = format!("POST /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n{}", rest);
+                let response = raw_exchange(addr, &request).await;
+                if strict {
+                    assert!(response.starts_with("HTTP/1.1 400"), "{}: {}", code, response);
+                    assert!(response.contains(&format!("\"code\":\"{}\"", code)), "{}: {}", code, response);
+                    assert_eq!(metrics.strict_http_rejection_count(code), 1);
+                } else if code == "content_length_with_transfer_encoding" {
+                    // hyper reads the body as chunked and lets it through.
+                    assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", code, response);
+                } else {
+                    assert!(response.starts_with("HTTP/1.1 400"), "{}: {}", code, response);
+                    assert!(!response.contains("\"code\""), "{}: {}", code, response);
+                    assert_eq!(metrics.strict_http_rejection_count(code), 0);
+                }
+            }
+
+            // A bad request after a good one on the same connection is caught too.
+            let pipelined = "POST /api/a/items HTTP/1.1\r\nhost: proxy\r\ncontent-length: 2\r\n\r\nok\
+                GET /api/a/items HTTP/1.1\r\nhost: proxy\r\nbad name: x\r\n\r\n";
+            let response = raw_exchange(addr, pipelined).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert_eq!(response.contains("\"code\":\"invalid_header_name\""), strict, "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_headers_close_connection() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let addr = start_timeout_proxy(&upstream, metrics.clone()).await;
+
+        // One client never sends a byte, the other stops partway through its headers.
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let mut partial = TcpStream::connect(addr).await.unwrap();
+        partial.write_all(b"GET /api/a/items HTTP/1.1\r\nhost: localhost\r\n").await.unwrap();
+
+        for stream in [&mut silent, &mut partial] {
+            let mut response = Vec::new();
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+            assert!(read.is_ok(), "connection left open");
+            assert!(response.is_empty());
+        }
+        assert_eq!(metrics.client_timeout_count("header_read"), 2);
+        assert!(!upstream.request_log().iter().any(|request| request.contains("/api/a/items")));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_answered_with_408() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let addr = start_timeout_proxy(&upstream, metrics.clone()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PUT /api/a/items HTTP/1.1\r\nhost: localhost\r\ncontent-length: 100\r\n\r\npartial")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+        assert!(response.to_ascii_lowercase().contains("connection: close"));
+        assert_eq!(metrics.client_timeout_count("body_read"), 1);
+        assert!(!upstream.request_log().iter().any(|request| request.contains("/api/a/items")));
+    }
+
+    #[tokio::test]
+    async fn test_client_not_reading_response_is_dropped() {
+        // Far more than the socket buffers on both ends can absorb.
+        let upstream = MockUpstream::start(MockResponse {
+            body: Bytes::from(vec![b'x'; 32 * 1024 * 1024]),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let addr = start_timeout_proxy(&upstream, metrics.clone()).await;
+
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut stream = socket.connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /api/a/items HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let dropped = tokio::time::timeout(Duration::from_secs(10), async {
+            while metrics.client_timeout_count("response_write") == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        assert!(dropped.await.is_ok(), "stalled response write never timed out");
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn test_first_request_after_eager_init_builds_nothing() {
+        for enabled in [true, false] {
+            let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+            let mut config = config_with_endpoint(upstream.url());
+            config.proxy_config.eager_init.enabled = enabled;
+            let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+            let state = proxy.state.clone();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { proxy.serve(listener).await });
+
+            // Accepted only once startup, eager init included, is done.
+            let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let (clients, entries) = (state.upstream_clients.built(), state.ai_engine.health_entries_created());
+            if enabled {
+                // One client per service and one entry per endpoint, all
+                // from eager init.
+                assert_eq!((clients, entries), (2, 2));
+            } else {
+                assert_eq!(clients, 1);
+                assert!(entries >= 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_answered_with_413() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.max_body_bytes = 16;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let response = raw_exchange(addr, "POST /api/a/items HTTP/1.1\r\nhost: proxy\r\ncontent-length: 5\r\nconnection: close\r\n\r\nsmall").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // Declared too long: refused before any body arrives.
+        let mut declared = TcpStream::connect(addr).await.unwrap();
+        declared
+            .write_all(b"POST /api/a/items HTTP/1.1\r\nhost: proxy\r\ncontent-length: 1048576\r\n\r\n")
+            .await
+            .unwrap();
+        // Chunked, with the stream left open after the limit is passed.
+        let mut streamed = TcpStream::connect(addr).await.unwrap();
+        streamed
+            .write_all(format!("POST /api/a/items HTTP/1.1\r\nhost: proxy\r\ntransfer-encoding: chunked\r\n\r\n40\r\n{}\r\n", "x".repeat(64)).as_bytes())
+            .await
+            .unwrap();
+
+        for stream in [&mut declared, &mut streamed] {
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .expect("connection left open")
+                .unwrap();
+            let response = String::from_utf8_lossy(&response);
+            assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+            assert!(response.to_ascii_lowercase().contains("connection: close"));
+            assert!(response.contains(r#""code":"body_too_large""#), "{}", response);
+        }
+        assert_eq!(upstream.request_log().iter().filter(|request| request.contains("/api/a/items")).count(), 1);
+        assert_eq!(metrics.request_body_rejected_count("service-a", "declared"), 1);
+        assert_eq!(metrics.request_body_rejected_count("service-a", "read"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_service_body_limit_replaces_the_global_one() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.max_body_bytes = 4;
+        config.upstream_services.get_mut("service-a").unwrap().max_body_bytes = Some(16);
+        let addr = start_proxy(config).await;
+
+        let response = raw_exchange(addr, "POST /api/a/items HTTP/1.1\r\nhost: proxy\r\ncontent-length: 5\r\nconnection: close\r\n\r\nsmall").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        let response = raw_exchange(
+            addr,
+            &format!("POST /api/a/items HTTP/1.1\r\nhost: proxy\r\ncontent-length: 32\r\nconnection: close\r\n\r\n{}", "x".repeat(32)),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+        assert!(response.contains("exceeds 16 bytes"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_identical_reads_share_one_upstream_call() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_millis(200),
+            body: Bytes::from_static(b"catalog"),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.enable_request_dedupe = true;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let client = reqwest::Client::new();
+        let reads = (0..3).map(|_| client.get(format!("http://{}/api/a/items?page=1", addr)).send());
+        for response in futures::future::join_all(reads).await {
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.text().await.unwrap(), "catalog");
+        }
+        let calls = |target: &str| upstream.request_log().iter().filter(|request| request.contains(target)).count();
+        assert_eq!(calls("/api/a/items"), 1);
+        assert_eq!(metrics.request_dedupe_count("/api/a", "leader"), 1);
+        assert_eq!(metrics.request_dedupe_count("/api/a", "shared"), 2);
+
+        // Writes, and reads carrying credentials, always go upstream.
+        let writes = (0..2).map(|_| client.post(format!("http://{}/api/a/orders", addr)).send());
+        let authorized =
+            (0..2).map(|_| client.get(format!("http://{}/api/a/mine", addr)).bearer_auth("token").send());
+        futures::future::join_all(writes).await;
+        futures::future::join_all(authorized).await;
+        assert_eq!(calls("/api/a/orders"), 2);
+        assert_eq!(calls("/api/a/mine"), 2);
+    }
+
+    async fn start_dedupe_proxy(response: MockResponse, max_body_bytes: usize) -> (MockUpstream, SocketAddr) {
+        let upstream = MockUpstream::start(MockResponse { latency: Duration::from_millis(200), ..response }).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.enable_request_dedupe = true;
+        config.proxy_config.request_dedupe_max_body_bytes = max_body_bytes;
+        let addr = start_proxy(config).await;
+        (upstream, addr)
+    }
+
+    #[tokio::test]
+    async fn test_reads_in_other_encodings_are_not_shared() {
+        let (upstream, addr) = start_dedupe_proxy(MockResponse::default(), 1024).await;
+        let client = reqwest::Client::new();
+        let reads = ["gzip", "identity"].map(|encoding| {
+            client.get(format!("http://{}/api/a/items", addr)).header("accept-encoding", encoding).send()
+        });
+        for response in futures::future::join_all(reads).await {
+            assert_eq!(response.unwrap().status(), StatusCode::OK);
+        }
+        assert_eq!(upstream.request_log().iter().filter(|request| request.contains("/api/a/items")).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_answers_setting_cookies_are_not_shared() {
+        let response = MockResponse {
+            headers: vec![("set-cookie".to_string(), "session=1".to_string())],
+            ..MockResponse::default()
+        };
+        let (upstream, addr) = start_dedupe_proxy(response, 1024).await;
+        let client = reqwest::Client::new();
+        let reads = (0..3).map(|_| client.get(format!("http://{}/api/a/items", addr)).send());
+        for response in futures::future::join_all(reads).await {
+            let response = response.unwrap();
+            assert_eq!(response.headers()["set-cookie"], "session=1");
+        }
+        assert_eq!(upstream.request_log().iter().filter(|request| request.contains("/api/a/items")).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_answers_are_streamed_unshared() {
+        let body = Bytes::from(vec![b'x'; 4096]);
+        let (upstream, addr) = start_dedupe_proxy(MockResponse { body: body.clone(), ..MockResponse::default() }, 1024).await;
+        let client = reqwest::Client::new();
+        let reads = (0..2).map(|_| client.get(format!("http://{}/api/a/items", addr)).send());
+        for response in futures::future::join_all(reads).await {
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.bytes().await.unwrap(), body);
+        }
+        assert_eq!(upstream.request_log().iter().filter(|request| request.contains("/api/a/items")).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_answers_over_the_buffer_budget_still_reach_the_leader() {
+        let body = Bytes::from(vec![b'x'; 4096]);
+        let upstream = MockUpstream::start(MockResponse { body: body.clone(), ..MockResponse::default() }).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.enable_request_dedupe = true;
+        config.proxy_config.request_dedupe_max_body_bytes = 8192;
+        config.proxy_config.max_buffered_bytes = 1024;
+        let addr = start_proxy(config).await;
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), body);
+    }
+
+    fn reload_file(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("proxy-reload-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("config.json")
+    }
+
+    fn write_config(path: &std::path::Path, config: &Config) {
+        std::fs::write(path, serde_json::to_string_pretty(config).unwrap()).unwrap();
+    }
+
+    async fn reload(addr: SocketAddr) -> (StatusCode, serde_json::Value) {
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/admin/config/reload", addr))
+            .send()
+            .await
+            .unwrap();
+        (response.status(), response.json().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_config_validate_reports_route_conflicts() {
+        let path = reload_file("validate");
+        let config = Config::new();
+        write_config(&path, &config);
+        let proxy = ProxyServer::builder().config(config.clone()).config_path(&path).build().unwrap();
+        let state = proxy.state.clone();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+        let validate = || async move {
+            let response = reqwest::get(format!("http://{}/admin/config/validate", addr)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            response.json::<serde_json::Value>().await.unwrap()
+        };
+
+        let mut edited = config.clone();
+        edited.routes = serde_json::from_value(serde_json::json!([
+            {"path_prefix": "/", "service": "service-a"},
+            {"path_prefix": "/orders", "service": "service-b", "priority": -1},
+        ]))
+        .unwrap();
+        write_config(&path, &edited);
+        let report = validate().await;
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["errors"][0]["path"], "routes[1].path_prefix");
+        assert_eq!(report["warnings"][0]["path"], "routes[0].path_prefix");
+
+        edited.route_conflicts = ConflictStrictness::Warn;
+        write_config(&path, &edited);
+        let report = validate().await;
+        assert_eq!(report["valid"], true);
+        assert_eq!(report["warnings"].as_array().unwrap().len(), 2);
+        // Nothing was applied.
+        assert_eq!(state.routes.read().unwrap().rules().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_config_reload_swaps_upstream_services() {
+        let (first, second) = (
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+        );
+        let path = reload_file("swap");
+        let config = config_with_endpoint(first.url());
+        write_config(&path, &config);
+        let proxy = ProxyServer::builder().config(config.clone()).config_path(&path).build().unwrap();
+        let state = proxy.state.clone();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+        let kept_breaker = state.upstreams().breakers["service-a"].clone();
+
+        let mut reloaded = config.clone();
+        reloaded.upstream_services.get_mut("service-a").unwrap().endpoints = vec![second.url()];
+        let mut added = reloaded.upstream_services.remove("service-b").unwrap();
+        added.name = "service-c".to_string();
+        added.endpoints = vec![first.url()];
+        reloaded.upstream_services.insert("service-c".to_string(), added);
+        reloaded.proxy_config.max_connections = 10;
+        write_config(&path, &reloaded);
+
+        let (status, diff) = reload(addr).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(diff["added"], serde_json::json!(["service-c"]));
+        assert_eq!(diff["removed"], serde_json::json!(["service-b"]));
+        assert_eq!(diff["changed"], serde_json::json!(["service-a"]));
+        assert_eq!(diff["restart_required"], serde_json::json!(["proxy_config"]));
+
+        let upstreams = state.upstreams();
+        let mut names: Vec<&String> = upstreams.breakers.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["service-a", "service-c"]);
+        // Breaker settings unchanged, so its state carries over.
+        assert!(Arc::ptr_eq(&upstreams.breakers["service-a"], &kept_breaker));
+
+        let status = |path: &'static str| async move { reqwest::get(format!("http://{}{}", addr, path)).await.unwrap().status() };
+        assert_eq!(status("/api/a/items").await, StatusCode::OK);
+        assert_eq!(status("/api/b/items").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/api/c/items").await, StatusCode::OK);
+        assert!(second.request_log().iter().any(|line| line == "GET /api/a/items"));
+        assert!(!first.request_log().iter().any(|line| line == "GET /api/a/items"));
+
+        // A broken file leaves the running services alone.
+        std::fs::write(&path, "{ not json").unwrap();
+        let (status, body) = reload(addr).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "config_unreadable");
+        reloaded.upstream_services.get_mut("service-a").unwrap().timeout_ms = 0;
+        write_config(&path, &reloaded);
+        let (status, body) = reload(addr).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "config_invalid");
+        assert!(Arc::ptr_eq(&state.upstreams(), &upstreams));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requests_during_reload_see_whole_generations() {
+        let (first, second) = (
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+        );
+        let path = reload_file("concurrent");
+        let config_a = config_with_endpoint(first.url());
+        let mut config_b = config_with_endpoint(second.url());
+        config_b.upstream_services.get_mut("service-a").unwrap().circuit_breaker_threshold = 7;
+        write_config(&path, &config_a);
+        let proxy = ProxyServer::builder().config(config_a.clone()).config_path(&path).build().unwrap();
+        let state = proxy.state.clone();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let mut clients = Vec::new();
+        for _ in 0..4 {
+            let done = done.clone();
+            clients.push(tokio::spawn(async move {
+                let client = reqwest::Client::new();
+                let mut sent = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let response = client.get(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
+                    sent += 1;
+                }
+                sent
+            }));
+        }
+        // Every generation pairs each service with a breaker built from that
+        // same generation's settings.
+        let checker = {
+            let state = state.clone();
+            let done = done.clone();
+            let first_url = first.url();
+            tokio::spawn(async move {
+                while !done.load(Ordering::Relaxed) {
+                    let upstreams = state.upstreams();
+                    let service = &upstreams.services["service-a"];
+                    let expected = if service.endpoints[0] == first_url { 5 } else { 7 };
+                    assert_eq!(service.circuit_breaker_threshold, expected);
+                    assert_eq!(upstreams.breakers["service-a"].get_failure_threshold(), expected);
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        for round in 0..20 {
+            let config = if round % 2 == 0 { &config_b } else { &config_a };
+            write_config(&path, config);
+            let (status, _) = reload(addr).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        done.store(true, Ordering::Relaxed);
+        checker.await.unwrap();
+        for client in clients {
+            assert!(client.await.unwrap() > 0);
+        }
+        assert!(!first.request_log().is_empty() && !second.request_log().is_empty());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_config_reload_needs_a_config_file() {
+        let addr = start_proxy(Config::new()).await;
+        let (status, body) = reload(addr).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "no_config_file");
+    }
+
+    #[tokio::test]
+    async fn test_crafted_endpoint_cannot_split_response() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        // The URL parser drops CR/LF, so this passes validation, and the
+        // endpoint runs in its canonical form without them.
+        let config = config_with_endpoint(format!("{}/\r\nx-injected: 1", upstream.url()));
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::builder().config(config).metrics(metrics.clone()).build().unwrap();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+
+        let response = raw_exchange(addr, "GET /api/a/items HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(!response.to_ascii_lowercase().contains("\r\nx-injected"), "{}", response);
+        assert!(response.contains(&format!("x-proxy-endpoint: {}/x-injected:%201\r\n", upstream.url())), "{}", response);
+        assert_eq!(metrics.header_values_sanitized_count("endpoint", "control_characters"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_scoped_admin_token_sees_only_its_services() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.admin_auth = serde_json::from_value(serde_json::json!({
+            "tokens": {
+                "ops": { "token": "ops-token" },
+                "team-a": { "token": "team-a-token", "services": ["service-a"] },
+            }
+        }))
+        .unwrap();
+        let addr = start_proxy(config).await;
+        let client = reqwest::Client::new();
+        let get = |path: &str, token: &str| {
+            client.get(format!("http://{}{}", addr, path)).bearer_auth(token).send()
+        };
+        let names = |body: &serde_json::Value| -> Vec<String> {
+            body["services"].as_array().unwrap().iter().map(|view| view["service"].as_str().unwrap().to_string()).collect()
+        };
+
+        let health: serde_json::Value = get("/admin/health", "team-a-token").await.unwrap().json().await.unwrap();
+        assert_eq!(names(&health), vec!["service-a"]);
+        let services: serde_json::Value = get("/admin/services", "team-a-token").await.unwrap().json().await.unwrap();
+        assert_eq!(names(&services), vec!["service-a"]);
+        let endpoints: HashMap<String, Vec<String>> =
+            get("/admin/endpoints", "team-a-token").await.unwrap().json().await.unwrap();
+        assert_eq!(endpoints.keys().collect::<Vec<_>>(), vec!["service-a"]);
+
+        let health: serde_json::Value = get("/admin/health", "ops-token").await.unwrap().json().await.unwrap();
+        assert_eq!(names(&health), vec!["service-a", "service-b"]);
+
+        let response = get("/admin/selection?service=service-b", "team-a-token").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client
+            .put(format!("http://{}/admin/endpoints", addr))
+            .bearer_auth("team-a-token")
+            .json(&serde_json::json!({"service": "service-b", "endpoints": [upstream.url()]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "out_of_scope");
+        let response = client
+            .put(format!("http://{}/admin/endpoints", addr))
+            .bearer_auth("team-a-token")
+            .json(&serde_json::json!({"service": "service-a", "endpoints": [upstream.url()]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client
+            .post(format!("http://{}/admin/config/reload", addr))
+            .bearer_auth("team-a-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = client.get(format!("http://{}/admin/health", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/admin/health", "wrong").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_capture_needs_a_global_admin_token() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.capture.enabled = true;
+        config.capture.admin_tokens.insert("alice".to_string(), "ops-token".to_string());
+        config.admin_auth = serde_json::from_value(serde_json::json!({
+            "tokens": {
+                "ops": { "token": "ops-token" },
+                "team-a": { "token": "team-a-token", "services": ["service-a"] },
+            }
+        }))
+        .unwrap();
+        let addr = start_proxy(config).await;
+        let client = reqwest::Client::new();
+        let capture_url = format!("http://{}/admin/capture", addr);
+        let start = serde_json::json!({ "service": "service-a", "max_records": 1, "duration_s": 30 });
+
+        let response = client.post(&capture_url).json(&start).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client.get(format!("{}/any", capture_url)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client.post(&capture_url).bearer_auth("team-a-token").json(&start).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client.post(&capture_url).bearer_auth("ops-token").json(&start).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_scoped_admin_token_sees_only_its_routes_and_history() {
+        let mut config = Config::new();
+        config.admin_auth = serde_json::from_value(serde_json::json!({
+            "tokens": { "team-a": { "token": "team-a-token", "services": ["service-a"] } }
+        }))
+        .unwrap();
+        config.routes = vec![
+            RouteRule { path_prefix: "/orders".to_string(), service: "service-a".to_string(), ..RouteRule::default() },
+            RouteRule { path_prefix: "/billing".to_string(), service: "service-b".to_string(), ..RouteRule::default() },
+        ];
+        config.default_service = Some("service-b".to_string());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+        let services = proxy.state.upstreams().services.clone();
+        proxy.state.config_history.reloaded(
+            ReloadDiff {
+                changed: vec!["service-a".to_string(), "service-b".to_string()],
+                removed: vec!["service-c".to_string()],
+                ..ReloadDiff::default()
+            },
+            services,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("http://{}{}", addr, path)).bearer_auth("team-a-token").send();
+
+        let routes: serde_json::Value = get("/admin/routes").await.unwrap().json().await.unwrap();
+        let rules = routes["rules"].as_array().unwrap();
+        assert_eq!((rules.len(), &rules[0]["service"]), (1, &serde_json::json!("service-a")));
+        assert!(routes["default_service"].is_null());
+
+        let history: serde_json::Value = get("/admin/config/history").await.unwrap().json().await.unwrap();
+        let diff = &history["configs"][1]["diff"];
+        assert_eq!(diff["changed"], serde_json::json!(["service-a"]));
+        assert_eq!(diff["removed"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_services_balance_with_their_own_strategy() {
+        let upstreams = [
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+        ];
+        let [a1, a2, b1, b2] = upstreams.each_ref().map(|upstream| upstream.url());
+        let mut config = Config::new();
+        config.ai_config.enabled = false;
+        let service_a = config.upstream_services.get_mut("service-a").unwrap();
+        service_a.endpoints = vec![a1.clone(), a2.clone()];
+        service_a.load_balancing_strategy = Some("round_robin".to_string());
+        let service_b = config.upstream_services.get_mut("service-b").unwrap();
+        service_b.endpoints = vec![b1.clone(), b2.clone()];
+        service_b.endpoint_weights = Some(HashMap::from([(b1.clone(), 3)]));
+        service_b.load_balancing_strategy = Some("weighted_round_robin".to_string());
+        let addr = start_proxy(config).await;
+
+        let client = reqwest::Client::new();
+        let mut picks = HashMap::<&str, Vec<String>>::new();
+        // Interleaved, so one service's picks cannot move the other's.
+        for _ in 0..4 {
+            for service in ["a", "b"] {
+                let response = client.get(format!("http://{}/api/{}/items", addr, service)).send().await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let endpoint = response.headers()["x-proxy-endpoint"].to_str().unwrap().to_string();
+                picks.entry(service).or_default().push(endpoint);
+            }
+        }
+
+        assert_eq!(picks["a"], [a1.clone(), a2.clone(), a1.clone(), a2.clone()]);
+        assert_eq!(picks["b"], [b1.clone(), b1.clone(), b2.clone(), b1.clone()]);
+
+        let selection: serde_json::Value = client
+            .get(format!("http://{}/admin/selection?service=service-b", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(selection["load_balancer"]["strategy"], "weighted_round_robin");
+    }
+
+    #[tokio::test]
+    async fn test_route_table_picks_longest_prefix_and_strips() {
+        let legacy = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let v2 = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = Config::new();
+        config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![legacy.url()];
+        config.upstream_services.get_mut("service-b").unwrap().endpoints = vec![v2.url()];
+        config.routes = vec![
+            RouteRule {
+                path_prefix: "/api".to_string(),
+                service: "service-a".to_string(),
+                strip_prefix: false,
+                ..RouteRule::default()
+            },
+            RouteRule {
+                path_prefix: "/api/v2".to_string(),
+                service: "service-b".to_string(),
+                strip_prefix: true,
+                ..RouteRule::default()
+            },
+            RouteRule {
+                path_pattern: Some(PathPattern::Wildcard("/api/*/export".to_string())),
+                service: "service-b".to_string(),
+                ..RouteRule::default()
+            },
+        ];
+        let addr = start_proxy(config).await;
+
+        let client = reqwest::Client::new();
+        for path in ["/api/v2/orders/7?expand=lines", "/api/orders/7", "/api/v20/orders", "/api/orders/export"] {
+            let response = client.get(format!("http://{}{}", addr, path)).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        }
+        let proxied = |upstream: &MockUpstream| -> Vec<String> {
+            upstream.request_log().into_iter().filter(|line| line != "GET /health").collect()
+        };
+        assert_eq!(proxied(&v2), ["GET /orders/7", "GET /api/orders/export"]);
+        assert_eq!(proxied(&legacy), ["GET /api/orders/7", "GET /api/v20/orders"]);
+
+        let response = client.get(format!("http://{}/orders", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "no_route");
+    }
+
+    #[tokio::test]
+    async fn test_routes_added_at_runtime() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.default_service = Some("service-b".to_string());
+        let addr = start_proxy(config).await;
+        let client = reqwest::Client::new();
+
+        let rule = RouteRule::regex(r"/reports/\d+", "service-a").unwrap();
+        let response = client
+            .post(format!("http://{}/admin/routes", addr))
+            .json(&rule)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client.get(format!("http://{}/reports/42", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(upstream.request_log().contains(&"GET /reports/42".to_string()));
+
+        let table: serde_json::Value = client
+            .get(format!("http://{}/admin/routes", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(table["rules"][0]["path_pattern"]["regex"], r"/reports/\d+");
+        assert_eq!(table["default_service"], "service-b");
+
+        for rule in [
+            serde_json::json!({"path_prefix": "/orders", "service": "service-z"}),
+            serde_json::json!({"path_pattern": {"regex": "/reports/\\d+"}, "service": "service-b"}),
+            serde_json::json!({"path_prefix": "/orders", "service": "service-a", "unknown": true}),
+        ] {
+            let response = client
+                .post(format!("http://{}/admin/routes", addr))
+                .json(&rule)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", rule);
+        }
+        let table: serde_json::Value = client
+            .get(format!("http://{}/admin/routes", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(table["rules"].as_array().unwrap().len(), 1);
 
-type BoxBody = http_body_util::combinators::BoxBody<Bytes, hyper::Error>;
+        // Ahead of the rule already there, by priority, it would take its traffic.
+        let rule = serde_json::json!({"path_pattern": {"regex": "/reports/.*"}, "service": "service-b", "priority": 1});
+        let response = client
+            .post(format!("http://{}/admin/routes", addr))
+            .json(&rule)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = client
+            .post(format!("http://{}/admin/routes", addr))
+            .json(&serde_json::json!({"path_prefix": "/orders", "service": "service-a"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client.get(format!("http://{}/reports/42", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(upstream.request_log().iter().filter(|line| *line == "GET /reports/42").count(), 2);
+    }
 
-pub struct ProxyServer {
-    config: Config,
-    ai_engine: Arc<AIEngine>,
-    metrics: Arc<MetricsCollector>,
-    load_balancer: Arc<LoadBalancer>,
-    circuit_breakers: Arc<HashMap<String, CircuitBreaker>>,
-    health_checker: Arc<HealthChecker>,
-}
+    #[tokio::test]
+    async fn test_route_rewrite_changes_upstream_path() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.routes = vec![RouteRule {
+            path_prefix: "/users".to_string(),
+            service: "service-a".to_string(),
+            strip_prefix: false,
+            rewrite: Some(PathRewrite {
+                pattern: "^/users/(.*)".to_string(),
+                replacement: "/v2/users/$1".to_string(),
+            }),
+            ..RouteRule::default()
+        }];
+        let addr = start_proxy(config).await;
 
-impl ProxyServer {
-    pub fn new(
-        config: Config,
-        ai_engine: Arc<AIEngine>,
-        metrics: Arc<MetricsCollector>,
-    ) -> Self {
-        let load_balancer = Arc::new(LoadBalancer::new());
-        
-        let mut circuit_breakers = HashMap::new();
-        for (service_name, service_config) in &config.upstream_services {
-            circuit_breakers.insert(
-                service_name.clone(),
-                CircuitBreaker::new(service_config.circuit_breaker_threshold),
-            );
+        for path in ["/users/123?fields=name", "/users"] {
+            let response = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
         }
-        let circuit_breakers = Arc::new(circuit_breakers);
-        
-        let health_checker = Arc::new(HealthChecker::new(
-            config.upstream_services.clone(),
-            ai_engine.clone(),
-        ));
+        let proxied: Vec<String> = upstream.request_log().into_iter().filter(|line| line != "GET /health").collect();
+        assert_eq!(proxied, ["GET /v2/users/123", "GET /users"]);
+    }
 
-        Self {
-            config,
-            ai_engine,
-            metrics,
-            load_balancer,
-            circuit_breakers,
-            health_checker,
+    #[tokio::test]
+    async fn test_unmatched_paths_go_to_the_default_service_or_404() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let unrouted = ["/", "/favicon.ico", "/api"];
+
+        let addr = start_proxy(config_with_endpoint(upstream.url())).await;
+        for path in unrouted {
+            let response = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["code"], "no_route");
+            assert_eq!(body["path"], path);
+        }
+        let proxied = |upstream: &MockUpstream| -> Vec<String> {
+            upstream.request_log().into_iter().filter(|line| line != "GET /health").collect()
+        };
+        assert!(proxied(&upstream).is_empty());
+
+        let mut config = config_with_endpoint(upstream.url());
+        config.default_service = Some("service-a".to_string());
+        let addr = start_proxy(config).await;
+        for path in unrouted {
+            let response = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
         }
+        assert_eq!(proxied(&upstream), ["GET /", "GET /favicon.ico", "GET /api"]);
     }
 
-    pub async fn run(&self, bind_addr: &str, port: u16) -> Result<()> {
-        let addr: SocketAddr = format!("{}:{}", bind_addr, port).parse()?;
-        let listener = TcpListener::bind(addr).await?;
-        
-        self.health_checker.start_health_checks().await;
-        
-        info!("AI Sidecar Proxy listening on {}", addr);
+    #[tokio::test]
+    async fn test_max_timeout_caps_adaptive_timeout_and_admin_overrides_it() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_millis(800),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.max_timeout_ms = Some(100);
+        service.max_retries = 0;
+        let addr = start_proxy(config).await;
+        let client = reqwest::Client::new();
+        let items = format!("http://{}/api/a/items", addr);
+        let admin = format!("http://{}/admin/services/service-a/timeout", addr);
 
-        loop {
-            let (stream, remote_addr) = listener.accept().await?;
-            let io = TokioIo::new(stream);
-
-            let config = self.config.clone();
-            let ai_engine = self.ai_engine.clone();
-            let metrics = self.metrics.clone();
-            let load_balancer = self.load_balancer.clone();
-            let circuit_breakers = self.circuit_breakers.clone();
-
-            tokio::task::spawn(async move {
-                let service = service_fn(move |req| {
-                    Self::handle_request(
-                        req,
-                        config.clone(),
-                        ai_engine.clone(),
-                        metrics.clone(),
-                        load_balancer.clone(),
-                        circuit_breakers.clone(),
-                        remote_addr,
-                    )
-                });
+        let started = Instant::now();
+        let response = client.get(&items).send().await.unwrap();
+        assert!(response.status().is_server_error());
+        assert!(started.elapsed() < Duration::from_millis(700));
 
-                let builder = ServerBuilder::new(TokioExecutor::new());
-                
-                if let Err(err) = builder.serve_connection(io, service).await {
-                    error!("Error serving connection from {}: {}", remote_addr, err);
-                }
-            });
+        let response = client.put(&admin).json(&serde_json::json!({"max_timeout_ms": 5000})).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body, serde_json::json!({"service": "service-a", "configured_ms": 100, "override_ms": 5000, "max_timeout_ms": 5000}));
+        assert_eq!(client.get(&items).send().await.unwrap().status(), StatusCode::OK);
+
+        let body: serde_json::Value = client.delete(&admin).send().await.unwrap().json().await.unwrap();
+        assert_eq!(body["max_timeout_ms"], 100);
+        assert!(client.get(&items).send().await.unwrap().status().is_server_error());
+
+        let response = client.put(&admin).json(&serde_json::json!({"max_timeout_ms": 0})).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = client.get(format!("http://{}/admin/services/nope/timeout", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_templated_operations_label_metrics_and_log() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_writer(logs.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.routes = vec![RouteRule {
+            path_prefix: "/users".to_string(),
+            service: "service-a".to_string(),
+            path_template: Some(PathTemplate::default()),
+            ..RouteRule::default()
+        }];
+        config.metrics_config.max_operations = 2;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        for path in ["/users/1/orders/2", "/users/3/orders/4", "/users/me", "/users/you", "/api/a/items"] {
+            let response = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
         }
+        assert_eq!(metrics.operation_count("/users", "/users/{id}/orders/{id}"), 2);
+        assert_eq!(metrics.operation_count("/users", "/users/me"), 1);
+        assert_eq!(metrics.operation_count("/users", OTHER_OPERATION), 1);
+        assert_eq!(metrics.operation_count("/users", "/users/you"), 0);
+
+        let operations: Vec<serde_json::Value> = logs
+            .events()
+            .iter()
+            .filter(|e| e["fields"]["message"] == "request completed")
+            .map(|e| e["span"]["operation"].clone())
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                serde_json::json!("/users/{id}/orders/{id}"),
+                serde_json::json!("/users/{id}/orders/{id}"),
+                serde_json::json!("/users/me"),
+                serde_json::json!(OTHER_OPERATION),
+                serde_json::Value::Null,
+            ]
+        );
     }
 
-    async fn handle_request(
-        req: Request<Incoming>,
-        config: Config,
-        ai_engine: Arc<AIEngine>,
-        metrics: Arc<MetricsCollector>,
-        load_balancer: Arc<LoadBalancer>,
-        circuit_breakers: Arc<HashMap<String, CircuitBreaker>>,
-        remote_addr: SocketAddr,
-    ) -> Result<Response<BoxBody>, hyper::Error> {
-        let start_time = Instant::now();
-        let method = req.method().clone();
-        let uri = req.uri().clone();
-        let path = uri.path();
+    #[tokio::test]
+    async fn test_admin_load_balancer_reports_weight_sources() {
+        let mut config = Config::new();
+        let service_a = config.upstream_services.get_mut("service-a").unwrap();
+        service_a.load_balancing_strategy = Some("weighted_round_robin".to_string());
+        service_a.endpoint_weights = Some(HashMap::from([("http://localhost:3001".to_string(), 4)]));
+        service_a.auto_weight = Some(AutoWeightConfig::default());
+        let proxy = ProxyServer::builder().config(config).build().unwrap();
+        let state = proxy.state.clone();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+        state.load_balancers.for_service("service-b").set_auto_weight("service-b", "http://localhost:3002", 9).await;
 
-        debug!("Handling request: {} {} from {}", method, path, remote_addr);
+        let body: serde_json::Value = reqwest::get(format!("http://{}/admin/load-balancer", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let service_a = &body["services"]["service-a"];
+        assert_eq!(service_a["strategy"], "weighted_round_robin");
+        assert_eq!(service_a["auto_weight"]["max_step"], 10);
+        assert_eq!(service_a["endpoints"][0]["weight"], 4);
+        assert_eq!(service_a["endpoints"][0]["weight_source"], "static");
+        let service_b = &body["services"]["service-b"];
+        assert_eq!(service_b["strategy"], "round_robin");
+        assert!(service_b["auto_weight"].is_null());
+        assert_eq!(service_b["endpoints"][0]["weight"], 9);
+        assert_eq!(service_b["endpoints"][0]["weight_source"], "auto");
+    }
 
-        if path == "/health" {
-            return Ok(Self::health_response());
+    #[tokio::test]
+    async fn test_rate_limit_answers_429_once_burst_is_spent() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.rate_limit = Some(RateLimitConfig {
+            requests_per_second: 1,
+            burst_size: 3,
+            ..RateLimitConfig::default()
+        });
+        let addr = start_proxy(config).await;
+
+        let client = reqwest::Client::new();
+        for _ in 0..3 {
+            let response = client.get(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
         }
+        let response = client.get(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "rate_limited");
 
-        if path == "/metrics" {
-            return Ok(Self::metrics_response(&metrics).await);
+        let metrics_status = if cfg!(feature = "metrics-prometheus") { StatusCode::OK } else { StatusCode::NOT_FOUND };
+        for (path, status) in [("/health", StatusCode::OK), ("/metrics", metrics_status)] {
+            let response = client.get(format!("http://{}{}", addr, path)).send().await.unwrap();
+            assert_eq!(response.status(), status, "{}", path);
         }
+        let forwarded = upstream.request_log().iter().filter(|line| !line.ends_with("/health")).count();
+        assert_eq!(forwarded, 3);
+    }
 
-        if path.starts_with("/admin") {
-            return Self::admin_handler(req, &ai_engine).await;
+    #[tokio::test]
+    async fn test_quota_enforced_granted_and_kept_across_restart() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("proxy-quota-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.quota = Some(QuotaConfig {
+            limit: 2,
+            persist_path: Some(dir.join("quotas.json")),
+            ..QuotaConfig::default()
+        });
+        let start = |config: Config| async move {
+            let proxy = ProxyServer::builder().config(config).build().unwrap();
+            proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap()
+        };
+        let client = reqwest::Client::new();
+        let call = |addr: SocketAddr, key: &'static str| {
+            let request = client.get(format!("http://{}/api/a/items", addr));
+            async move { request.header("x-api-key", key).send().await.unwrap() }
+        };
+
+        let handle = start(config.clone()).await;
+        let addr = handle.local_addr();
+        let first = call(addr, "k1").await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()["x-quota-limit"], "2");
+        assert_eq!(first.headers()["x-quota-remaining"], "1");
+        assert_eq!(call(addr, "k1").await.status(), StatusCode::OK);
+        let refused = call(addr, "k1").await;
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()["x-quota-remaining"], "0");
+        assert!(refused.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(refused.json::<serde_json::Value>().await.unwrap()["code"], "quota_exhausted");
+        // Unkeyed requests are not metered.
+        let unkeyed = client.get(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+        assert_eq!(unkeyed.status(), StatusCode::OK);
+        assert!(!unkeyed.headers().contains_key("x-quota-limit"));
+
+        let granted: serde_json::Value = client
+            .post(format!("http://{}/admin/quotas/grant", addr))
+            .body(r#"{"key":"k1","requests":1}"#)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!((granted["limit"].as_u64(), granted["remaining"].as_u64()), (Some(3), Some(1)));
+        assert_eq!(call(addr, "k1").await.status(), StatusCode::OK);
+        handle.shutdown();
+        handle.await_terminated().await.unwrap();
+
+        let handle = start(config).await;
+        let addr = handle.local_addr();
+        assert_eq!(call(addr, "k1").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        let usage: serde_json::Value =
+            reqwest::get(format!("http://{}/admin/quotas", addr)).await.unwrap().json().await.unwrap();
+        assert_eq!(usage["period"], "month");
+        assert_eq!(usage["keys"]["k1"]["used"], 3);
+        let reset = client
+            .post(format!("http://{}/admin/quotas/reset", addr))
+            .body(r#"{"key":"k1"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(reset.status(), StatusCode::OK);
+        assert_eq!(call(addr, "k1").await.headers()["x-quota-remaining"], "1");
+        handle.shutdown();
+        handle.await_terminated().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_config_rollback_reverts_broken_reload() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let path = reload_file("rollback");
+        let mut config = config_with_endpoint(upstream.url());
+        config.proxy_config.config_rollback = ConfigRollbackConfig {
+            enabled: true,
+            min_requests: 5,
+            ..ConfigRollbackConfig::default()
+        };
+        write_config(&path, &config);
+        let clock = Arc::new(MockClock::new());
+        let proxy = ProxyServer::builder()
+            .config(config.clone())
+            .config_path(&path)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let state = proxy.state.clone();
+        let addr = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap().local_addr();
+        let status = |path: &'static str| async move { reqwest::get(format!("http://{}{}", addr, path)).await.unwrap().status() };
+        let history = || async move {
+            let history: serde_json::Value =
+                reqwest::get(format!("http://{}/admin/config/history", addr)).await.unwrap().json().await.unwrap();
+            history["configs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| (entry["version"].as_u64().unwrap(), entry["status"].as_str().unwrap().to_string()))
+                .collect::<Vec<_>>()
+        };
+        for _ in 0..10 {
+            assert_eq!(status("/api/a/items").await, StatusCode::OK);
         }
 
-        let service_name = Self::extract_service_name(path);
-        
-        if let Some(upstream_service) = config.upstream_services.get(&service_name) {
-            Self::proxy_request(
-                req,
-                upstream_service,
-                &ai_engine,
-                &metrics,
-                &load_balancer,
-                &circuit_breakers,
-                start_time,
-            ).await
-        } else {
-            warn!("No upstream service found for path: {}", path);
-            Ok(Self::error_response(StatusCode::NOT_FOUND, "Service not found"))
+        // Nothing listens on port 1.
+        let mut broken = config.clone();
+        broken.upstream_services.get_mut("service-a").unwrap().endpoints = vec!["http://127.0.0.1:1".to_string()];
+        write_config(&path, &broken);
+        let (code, body) = reload(addr).await;
+        assert_eq!((code, body["version"].as_u64()), (StatusCode::OK, Some(2)));
+        assert_eq!(history().await, [(1, "superseded".to_string()), (2, "observing".to_string())]);
+        for _ in 0..5 {
+            assert!(status("/api/a/items").await.is_server_error());
         }
+        for _ in 0..100 {
+            if state.metrics.config_rollback_count("automatic") == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.metrics.config_rollback_count("automatic"), 1);
+        assert_eq!(history().await, [(1, "active".to_string()), (2, "rejected".to_string())]);
+        assert_eq!(state.upstreams().services["service-a"].endpoints, [upstream.url()]);
+        assert_eq!(status("/api/a/items").await, StatusCode::OK);
+
+        // A reload that outlasts the window is kept, and can still be rolled
+        // back by hand.
+        let mut next = config.clone();
+        next.upstream_services.get_mut("service-a").unwrap().timeout_ms = 5000;
+        write_config(&path, &next);
+        assert_eq!(reload(addr).await.0, StatusCode::OK);
+        clock.advance(Duration::from_millis(ConfigRollbackConfig::default().observation_window_ms));
+        assert_eq!(history().await[2], (3, "active".to_string()));
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/admin/config/rollback", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!((body["rejected_version"].as_u64(), body["restored_version"].as_u64()), (Some(3), Some(1)));
+        assert_eq!(state.upstreams().services["service-a"].timeout_ms, config.upstream_services["service-a"].timeout_ms);
+        assert_eq!(state.metrics.config_rollback_count("manual"), 1);
     }
 
-    fn extract_service_name(path: &str) -> String {
-        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
-        if parts.is_empty() {
-            "default".to_string()
-        } else {
-            match parts[0] {
-                "api" if parts.len() > 1 => format!("service-{}", parts[1]),
-                _ => "service-a".to_string(),
+    #[tokio::test]
+    async fn test_truncated_upstream_body_is_a_failed_call() {
+        // Promises 100 bytes of gzip and sends 10; health probes are answered in full.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response: &[u8] = if request.starts_with(b"GET /health ") {
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    } else {
+                        b"HTTP/1.1 200 OK\r\ncontent-encoding: gzip\r\ncontent-length: 100\r\n\r\n0123456789"
+                    };
+                    let _ = stream.write_all(response).await;
+                });
             }
+        });
+        let mut config = config_with_endpoint(upstream.clone());
+        config.upstream_services.get_mut("service-a").unwrap().content_coding = ContentCodingMode::Decompress;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let stats = metrics.get_endpoint_stats().await;
+        assert_eq!((stats[&upstream].failed_requests, stats[&upstream].successful_requests), (1, 0));
+    }
+
+    #[test]
+    fn test_both_constructors_canonicalize_endpoints() {
+        let config = config_with_endpoint("http://API.internal:80/".to_string());
+        let built = ProxyServer::builder().config(config.clone()).build().unwrap();
+        let constructed = ProxyServer::new(config, Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+        for proxy in [built, constructed] {
+            assert_eq!(proxy.state.upstreams().services["service-a"].endpoints, ["http://api.internal"]);
         }
     }
 
-    async fn proxy_request(
-        mut req: Request<Incoming>,
-        upstream_service: &UpstreamService,
-        ai_engine: &Arc<AIEngine>,
-        metrics: &Arc<MetricsCollector>,
-        load_balancer: &Arc<LoadBalancer>,
-        circuit_breakers: &Arc<HashMap<String, CircuitBreaker>>,
-        start_time: Instant,
-    ) -> Result<Response<BoxBody>, hyper::Error> {
-        let service_name = &upstream_service.name;
-        
-        if let Some(circuit_breaker) = circuit_breakers.get(service_name) {
-            if circuit_breaker.is_open().await {
-                warn!("Circuit breaker is open for service: {}", service_name);
-                return Ok(Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "Service temporarily unavailable"));
+    // An upstream that sends a 103 ahead of each answer.
+    async fn early_hints_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+                              HTTP/1.1 200 OK\r\ncontent-length: 5\r\nx-final: yes\r\nconnection: close\r\n\r\nfinal",
+                        )
+                        .await;
+                });
             }
-        }
+        });
+        upstream
+    }
 
-        let ai_decision = ai_engine
-            .select_endpoint(service_name, &upstream_service.endpoints)
-            .await;
+    #[tokio::test]
+    async fn test_early_hints_reach_http1_clients() {
+        let upstream = early_hints_upstream().await;
+        for relay in [true, false] {
+            let mut config = config_with_endpoint(upstream.clone());
+            config.proxy_config.relay_early_hints = relay;
+            let metrics = Arc::new(MetricsCollector::new());
+            let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { proxy.serve(listener).await });
 
-        if ai_decision.selected_endpoint.is_empty() {
-            error!("No available endpoints for service: {}", service_name);
-            return Ok(Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "No available endpoints"));
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET /api/a/page HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut received = String::new();
+            client.read_to_string(&mut received).await.unwrap();
+            let hints = "HTTP/1.1 103 Early Hints\r\nlink: </style.css>; rel=preload\r\n\r\nHTTP/1.1 200 OK\r\n";
+            assert_eq!(received.starts_with(hints), relay, "{received}");
+            assert!(received.contains("x-final: yes\r\n"));
+            assert!(received.ends_with("final"));
+            assert_eq!(metrics.informational_response_count("service-a", 103), 1);
         }
+    }
 
-        info!("AI selected endpoint: {} (confidence: {:.3})", ai_decision.selected_endpoint, ai_decision.confidence);
+    #[tokio::test]
+    async fn test_early_hints_reach_http2_clients() {
+        let proxy = ProxyServer::new(
+            config_with_endpoint(early_hints_upstream().await),
+            Arc::new(AIEngine::new()),
+            Arc::new(MetricsCollector::new()),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
 
-        let timeout = ai_engine.adaptive_timeout(&ai_decision.selected_endpoint).await;
-        
-        let client = match reqwest::Client::builder()
-            .timeout(std::time::Duration::from_millis(timeout))
-            .build() {
-            Ok(client) => client,
-            Err(e) => {
-                error!("Failed to create HTTP client: {}", e);
-                return Ok(Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create HTTP client"));
-            }
+        let frame = |kind: u8, flags: u8, stream: u32, payload: &[u8]| {
+            let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+            frame.extend_from_slice(&[kind, flags]);
+            frame.extend_from_slice(&stream.to_be_bytes());
+            frame.extend_from_slice(payload);
+            frame
         };
+        // GET http://proxy/api/a/page, every field a literal or static entry.
+        let mut request = vec![0x82, 0x86, 0x04, 11];
+        request.extend_from_slice(b"/api/a/page");
+        request.extend_from_slice(&[0x01, 5]);
+        request.extend_from_slice(b"proxy");
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut sent = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        sent.extend(frame(0x4, 0, 0, &[]));
+        sent.extend(frame(0x1, 0x5, 1, &request));
+        client.write_all(&sent).await.unwrap();
 
-        let method = req.method().clone();
-        let uri = req.uri().clone();
-        let headers = req.headers().clone();
-        
-        let body_bytes = req.collect().await?.to_bytes();
-        
-        let upstream_url = format!("{}{}", ai_decision.selected_endpoint, uri.path_and_query().map(|pq| pq.as_str()).unwrap_or(""));
-        
-        let reqwest_method = match method {
-            hyper::Method::GET => reqwest::Method::GET,
-            hyper::Method::POST => reqwest::Method::POST,
-            hyper::Method::PUT => reqwest::Method::PUT,
-            hyper::Method::DELETE => reqwest::Method::DELETE,
-            hyper::Method::HEAD => reqwest::Method::HEAD,
-            hyper::Method::OPTIONS => reqwest::Method::OPTIONS,
-            hyper::Method::PATCH => reqwest::Method::PATCH,
-            _ => reqwest::Method::GET,
+        // Stream 1's frames until it ends: HEADERS carry their block, DATA
+        // only its length.
+        let mut frames = Vec::new();
+        loop {
+            let mut head = [0u8; 9];
+            client.read_exact(&mut head).await.unwrap();
+            let mut payload = vec![0; u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize];
+            client.read_exact(&mut payload).await.unwrap();
+            if u32::from_be_bytes([head[5], head[6], head[7], head[8]]) != 1 {
+                continue;
+            }
+            frames.push((head[3], payload));
+            if head[4] & 0x1 != 0 {
+                break;
+            }
+        }
+        let link = b"</style.css>; rel=preload";
+        let mut hints = vec![0x08, 3];
+        hints.extend_from_slice(b"103");
+        hints.extend_from_slice(&[0x0f, 0x1e, link.len() as u8]);
+        hints.extend_from_slice(link);
+        assert_eq!(frames[0], (0x1, hints));
+        assert_eq!(frames[1].0, 0x1);
+        let body: Vec<u8> = frames[2..].iter().filter(|(kind, _)| *kind == 0x0).flat_map(|(_, data)| data.clone()).collect();
+        assert_eq!(body, b"final");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_calls_count_as_in_flight() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_millis(300),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let proxy = ProxyServer::new(config_with_endpoint(upstream.url()), Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+        let state = proxy.state.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        let active = || {
+            let state = state.clone();
+            let endpoint = upstream.url();
+            async move {
+                let balancer = state.load_balancers.for_service("service-a");
+                balancer.explain("service-a", std::slice::from_ref(&endpoint)).await.endpoints[0].active_requests
+            }
         };
-        
-        let mut upstream_req = client.request(reqwest_method, &upstream_url);
-        
-        for (name, value) in headers.iter() {
-            if name != "host" && name != "content-length" {
-                if let Ok(value_str) = value.to_str() {
-                    upstream_req = upstream_req.header(name.as_str(), value_str);
+
+        let request = tokio::spawn(reqwest::get(format!("http://{}/api/a/items", addr)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(active().await, 1);
+        assert_eq!(request.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(active().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_call_stops_counting() {
+        let upstream = MockUpstream::start(MockResponse {
+            latency: Duration::from_secs(5),
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let proxy = ProxyServer::new(config_with_endpoint(upstream.url()), Arc::new(AIEngine::new()), Arc::new(MetricsCollector::new())).unwrap();
+        let balancer = proxy.state.load_balancers.for_service("service-a");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /api/a/items HTTP/1.1\r\nhost: proxy\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(balancer.get_connection_count(&upstream.url()).await, 1);
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(balancer.get_connection_count(&upstream.url()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_call_retried_on_another_endpoint() {
+        let broken = MockUpstream::start(MockResponse { status: 503, ..MockResponse::default() }).await.unwrap();
+        let healthy = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(broken.url());
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.push(healthy.url());
+        service.max_retries = 2;
+        config.proxy_config.retry_base_delay_ms = 10;
+        config.proxy_config.retry_max_delay_ms = 20;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        for _ in 0..4 {
+            let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["x-proxy-endpoint"], healthy.url().as_str());
+        }
+        let failed = broken.request_log().iter().filter(|line| *line == "GET /api/a/items").count() as u64;
+        assert!(failed >= 1);
+        assert_eq!(metrics.retry_count(&broken.url(), 1), failed);
+        assert_eq!(metrics.retry_count(&broken.url(), 2), 0);
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_failover_walks_the_fallbacks_in_order() {
+        // Both pass their health probes and fail every proxied call.
+        let mut failing = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let calls = Arc::new(AtomicUsize::new(0));
+            failing.push((format!("http://{}", listener.local_addr().unwrap()), calls.clone()));
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let calls = calls.clone();
+                    let service = service_fn(move |req: Request<Incoming>| {
+                        let status = if req.uri().path() == "/health" {
+                            StatusCode::OK
+                        } else {
+                            calls.fetch_add(1, Ordering::Relaxed);
+                            StatusCode::SERVICE_UNAVAILABLE
+                        };
+                        let body = Full::new(Bytes::new()).map_err(|never| match never {});
+                        let mut response: Response<BoxBody> = Response::new(BodyExt::boxed(body));
+                        *response.status_mut() = status;
+                        async move { Ok::<_, hyper::Error>(response) }
+                    });
+                    tokio::spawn(ServerBuilder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).into_owned());
                 }
-            }
+            });
         }
-        
-        if !body_bytes.is_empty() {
-            upstream_req = upstream_req.body(body_bytes.to_vec());
+        let healthy = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut config = config_with_endpoint(failing[0].0.clone());
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.extend([failing[1].0.clone(), healthy.url()]);
+        service.max_retries = 2;
+        // The engine's pick stands, and with no stats yet ties keep the
+        // listed order: the first endpoint, then its fallbacks as configured.
+        config.ai_config.decision_threshold = 0.0;
+        config.proxy_config.retry_base_delay_ms = 1;
+        let addr = start_proxy(config).await;
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-proxy-endpoint"], healthy.url().as_str());
+        assert_eq!(response.headers()["x-proxy-failover-count"], "2");
+        assert_eq!(response.headers()["x-proxy-attempts"], "3");
+        for (_, calls) in &failing {
+            assert_eq!(calls.load(Ordering::Relaxed), 1);
         }
+    }
 
-        let response_result = upstream_req.send().await;
-        let elapsed = start_time.elapsed();
+    #[tokio::test]
+    async fn test_retries_stop_at_max_retries() {
+        let broken = MockUpstream::start(MockResponse { status: 502, ..MockResponse::default() }).await.unwrap();
+        let mut config = config_with_endpoint(broken.url());
+        config.upstream_services.get_mut("service-a").unwrap().max_retries = 2;
+        config.proxy_config.retry_base_delay_ms = 10;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
 
-        let (status_code, success, response_body) = match response_result {
-            Ok(resp) => {
-                let status = resp.status();
-                let success = status.is_success();
-                let body_bytes = resp.bytes().await.unwrap_or_default();
-                (status.as_u16(), success, body_bytes)
-            }
-            Err(e) => {
-                error!("Upstream request failed: {}", e);
-                (503, false, Bytes::from("Upstream service unavailable"))
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let calls = broken.request_log().iter().filter(|line| *line == "GET /api/a/items").count();
+        assert_eq!(calls, 3);
+        assert_eq!(metrics.retry_count(&broken.url(), 1), 1);
+        assert_eq!(metrics.retry_count(&broken.url(), 2), 1);
+        assert_eq!(metrics.retry_count(&broken.url(), 3), 0);
+    }
+
+    #[tokio::test]
+    async fn test_flaky_upstream_answers_on_the_third_attempt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let flaky = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let calls = calls.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    // Every third proxied call succeeds; health probes always do.
+                    let status = if req.uri().path() == "/health" || calls.fetch_add(1, Ordering::Relaxed) % 3 == 2 {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    };
+                    let body = Full::new(Bytes::new()).map_err(|never| match never {});
+                    let mut response: Response<BoxBody> = Response::new(BodyExt::boxed(body));
+                    *response.status_mut() = status;
+                    async move { Ok::<_, hyper::Error>(response) }
+                });
+                tokio::spawn(ServerBuilder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).into_owned());
             }
-        };
+        });
+        let mut config = config_with_endpoint(flaky.clone());
+        config.upstream_services.get_mut("service-a").unwrap().max_retries = 2;
+        config.proxy_config.retry_base_delay_ms = 1;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        let client = reqwest::Client::new();
 
-        let request_metrics = RequestMetrics {
-            latency_ms: elapsed.as_millis() as u64,
-            status_code,
-            endpoint: ai_decision.selected_endpoint.clone(),
-            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-            success,
-        };
+        let response = client.get(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-proxy-attempts"], "3");
+        // Each attempt is counted against the endpoint.
+        let stats = metrics.get_endpoint_stats().await;
+        assert_eq!((stats[&flaky].failed_requests, stats[&flaky].successful_requests), (2, 1));
 
-        ai_engine.record_request(request_metrics).await;
-        metrics.record_request(&ai_decision.selected_endpoint, elapsed.as_millis() as u64, success).await;
+        // A POST could repeat its side effect, so it gets one attempt.
+        let response = client.post(format!("http://{}/api/a/orders", addr)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["x-proxy-attempts"], "1");
 
-        if let Some(circuit_breaker) = circuit_breakers.get(service_name) {
-            if success {
-                circuit_breaker.record_success().await;
-            } else {
-                circuit_breaker.record_failure().await;
+        // Unless the client says the upstream will notice a repeat.
+        let response = client
+            .post(format!("http://{}/api/a/orders", addr))
+            .header("idempotency-key", "order-1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-proxy-attempts"], "2");
+    }
+
+    #[cfg(feature = "kubernetes")]
+    #[tokio::test]
+    async fn test_kubernetes_discovery_supplies_ready_endpoints() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let port: u16 = upstream.url().rsplit(':').next().unwrap().parse().unwrap();
+        let (api_server, _) = crate::test_support::spawn_fake_api_server(move |target| {
+            // The watch stays open with nothing to say.
+            if target.contains("watch=true") {
+                return None;
             }
-        }
+            let slice = serde_json::json!({
+                "metadata": {"name": "orders-a", "resourceVersion": "1"},
+                "endpoints": [
+                    {"addresses": ["127.0.0.1"], "conditions": {"ready": true}},
+                    {"addresses": ["127.0.0.2"], "conditions": {"ready": false}},
+                ],
+                "ports": [{"port": port}],
+            });
+            Some(serde_json::json!({"metadata": {"resourceVersion": "1"}, "items": [slice]}).to_string())
+        })
+        .await;
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.clear();
+        service.kubernetes = Some(KubernetesDiscoveryConfig {
+            service: "orders".to_string(),
+            api_server: format!("http://{}", api_server),
+            token_path: "/nonexistent/token".into(),
+            ca_path: "/nonexistent/ca.crt".into(),
+            ..KubernetesDiscoveryConfig::default()
+        });
+        let handle = ProxyServer::builder()
+            .config(config)
+            .build()
+            .unwrap()
+            .run(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .unwrap();
+        let addr = handle.local_addr();
 
-        let mut response = Response::builder()
-            .status(status_code)
-            .body(Self::full(response_body))
+        let url = format!("http://{}/api/a/items", addr);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while reqwest::get(&url).await.unwrap().status() != StatusCode::OK {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("discovered endpoint never served");
+        let endpoints: serde_json::Value =
+            reqwest::get(format!("http://{}/admin/endpoints", addr)).await.unwrap().json().await.unwrap();
+        assert_eq!(endpoints["service-a"], serde_json::json!([upstream.url()]));
+
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/admin/endpoints", addr))
+            .json(&serde_json::json!({"service": "service-a", "endpoints": ["http://127.0.0.1:1"]}))
+            .send()
+            .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        handle.shutdown();
+        handle.await_terminated().await.unwrap();
+    }
 
-        response.headers_mut().insert("x-proxy-endpoint", ai_decision.selected_endpoint.parse().unwrap());
-        response.headers_mut().insert("x-proxy-confidence", ai_decision.confidence.to_string().parse().unwrap());
+    #[tokio::test]
+    async fn test_consul_discovery_supplies_passing_instances() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let port: u16 = upstream.url().rsplit(':').next().unwrap().parse().unwrap();
+        let (agent, requests) = crate::test_support::spawn_fake_consul(move |target| {
+            // A blocking query with nothing new to say.
+            if target.contains("index=") {
+                return None;
+            }
+            let instances = serde_json::json!([{"Node": {"Address": "127.0.0.1"}, "Service": {"Port": port}}]);
+            Some((7, instances.to_string()))
+        })
+        .await;
+        let mut config = Config::new();
+        let service = config.upstream_services.get_mut("service-a").unwrap();
+        service.endpoints.clear();
+        service.consul = Some(ConsulDiscoveryConfig {
+            address: format!("http://{}", agent),
+            service: "orders".to_string(),
+            ..ConsulDiscoveryConfig::default()
+        });
+        let handle = ProxyServer::builder()
+            .config(config)
+            .build()
+            .unwrap()
+            .run(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .unwrap();
+        let addr = handle.local_addr();
 
-        Ok(response)
+        let url = format!("http://{}/api/a/items", addr);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while reqwest::get(&url).await.unwrap().status() != StatusCode::OK {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("discovered endpoint never served");
+        let endpoints: serde_json::Value =
+            reqwest::get(format!("http://{}/admin/endpoints", addr)).await.unwrap().json().await.unwrap();
+        assert_eq!(endpoints["service-a"], serde_json::json!([upstream.url()]));
+        assert!(requests.lock().unwrap().iter().any(|line| line.contains("passing=true&index=7")));
+
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/admin/endpoints", addr))
+            .json(&serde_json::json!({"service": "service-a", "endpoints": ["http://127.0.0.1:1"]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        handle.shutdown();
+        handle.await_terminated().await.unwrap();
     }
 
-    async fn admin_handler(
-        req: Request<Incoming>,
-        ai_engine: &Arc<AIEngine>,
-    ) -> Result<Response<BoxBody>, hyper::Error> {
-        let path = req.uri().path();
-        
-        match path {
-            "/admin/health" => {
-                let health_data = ai_engine.get_all_service_health().await;
-                let json = serde_json::to_string_pretty(&health_data).unwrap_or_else(|_| "{}".to_string());
-                Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .header("content-type", "application/json")
-                    .body(Self::full(json))
-                    .unwrap())
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_ai_switch_bypasses_the_engine_under_load() {
+        let upstreams = [
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+            MockUpstream::start(MockResponse::default()).await.unwrap(),
+        ];
+        let mut config = Config::new();
+        config.ai_config.decision_threshold = 0.0;
+        config.upstream_services.get_mut("service-a").unwrap().endpoints = upstreams.iter().map(|upstream| upstream.url()).collect();
+        let ai_engine = Arc::new(AIEngine::new());
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, ai_engine.clone(), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/a/items", addr);
+        let mode = |client: reqwest::Client, url: String| async move {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            response.headers()["x-proxy-selection"].to_str().unwrap().to_string()
+        };
+        let switch = |enabled: bool| {
+            let client = client.clone();
+            async move {
+                let response = client
+                    .put(format!("http://{}/admin/ai/enabled", addr))
+                    .json(&serde_json::json!({ "enabled": enabled }))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
             }
-            "/admin/status" => {
-                let status = serde_json::json!({
-                    "status": "healthy",
-                    "version": env!("CARGO_PKG_VERSION"),
-                    "uptime": "running"
-                });
-                Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .header("content-type", "application/json")
-                    .body(Self::full(status.to_string()))
-                    .unwrap())
+        };
+        assert_eq!(mode(client.clone(), url.clone()).await, "ai");
+
+        let stop = CancellationToken::new();
+        let served = Arc::new(AtomicU64::new(0));
+        let load: Vec<_> = (0..4)
+            .map(|_| {
+                let (client, url, stop, served) = (client.clone(), url.clone(), stop.clone(), served.clone());
+                tokio::spawn(async move {
+                    while !stop.is_cancelled() {
+                        mode(client.clone(), url.clone()).await;
+                        served.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+
+        switch(false).await;
+        assert_eq!(mode(client.clone(), url.clone()).await, "bypassed");
+        // Any request that reached for the engine would wait on these.
+        let locks = ai_engine.hold_locks().await;
+        let before = served.load(Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for _ in 0..20 {
+                assert_eq!(mode(client.clone(), url.clone()).await, "bypassed");
             }
-            _ => Ok(Self::error_response(StatusCode::NOT_FOUND, "Admin endpoint not found"))
+        })
+        .await
+        .expect("a bypassed request waited on an AI engine lock");
+        assert!(served.load(Ordering::Relaxed) > before);
+        let status: serde_json::Value =
+            reqwest::get(format!("http://{}/admin/status", addr)).await.unwrap().json().await.unwrap();
+        assert_eq!(status["ai_enabled"], false);
+        assert_eq!(metrics.ai_enabled(), 0.0);
+        drop(locks);
+
+        switch(true).await;
+        assert_eq!(mode(client.clone(), url.clone()).await, "ai");
+        assert_eq!(metrics.ai_enabled(), 1.0);
+        stop.cancel();
+        for task in load {
+            task.await.unwrap();
         }
+        assert!(metrics.selection_count("bypassed") >= 21);
+        assert!(metrics.selection_count("ai") >= 2);
     }
 
-    fn health_response() -> Response<BoxBody> {
-        Response::builder()
-            .status(StatusCode::OK)
-            .header("content-type", "application/json")
-            .body(Self::full(r#"{"status":"healthy"}"#))
-            .unwrap()
+    // A build without a feature says so where the feature would answer.
+    #[cfg(not(all(feature = "ai", feature = "metrics-prometheus")))]
+    #[tokio::test]
+    async fn test_missing_features_answer_not_found() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let addr = start_proxy(config_with_endpoint(upstream.url())).await;
+        let client = reqwest::Client::new();
+
+        for (path, feature, built) in [
+            ("/metrics", "metrics-prometheus", cfg!(feature = "metrics-prometheus")),
+            ("/admin/ai/enabled", "ai", cfg!(feature = "ai")),
+        ] {
+            let response = client.get(format!("http://{}{}", addr, path)).send().await.unwrap();
+            if built {
+                assert_eq!(response.status(), StatusCode::OK, "{}", path);
+                continue;
+            }
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["code"], "feature_disabled");
+            assert!(body["error"].as_str().unwrap().contains(&format!("--features {}", feature)), "{}", body);
+        }
     }
 
-    async fn metrics_response(metrics: &Arc<MetricsCollector>) -> Response<BoxBody> {
-        let metrics_data = metrics.get_prometheus_metrics().await;
-        Response::builder()
-            .status(StatusCode::OK)
-            .header("content-type", "text/plain")
-            .body(Self::full(metrics_data))
-            .unwrap()
+    #[cfg(not(feature = "ai"))]
+    #[tokio::test]
+    async fn test_balancer_picks_without_the_ai_feature() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let metrics = Arc::new(MetricsCollector::new());
+        let ai_engine = Arc::new(AIEngine::new());
+        let proxy = ProxyServer::new(config_with_endpoint(upstream.url()), ai_engine.clone(), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-proxy-selection"], "bypassed");
+        assert_eq!(metrics.selection_count("bypassed"), 1);
+        assert_eq!(metrics.ai_enabled(), 0.0);
+        let status: serde_json::Value =
+            reqwest::get(format!("http://{}/admin/status", addr)).await.unwrap().json().await.unwrap();
+        assert_eq!(status["ai_enabled"], false);
+        // Passive health is still kept for the checks and weights that read it.
+        assert!(ai_engine.get_service_health(&upstream.url()).await.is_some_and(|health| health.total_requests > 0));
     }
 
-    fn error_response(status: StatusCode, message: &str) -> Response<BoxBody> {
-        let error_json = serde_json::json!({
-            "error": message,
-            "status": status.as_u16()
-        });
-        
-        Response::builder()
-            .status(status)
-            .header("content-type", "application/json")
-            .body(Self::full(error_json.to_string()))
-            .unwrap()
+    #[tokio::test]
+    async fn test_nonstandard_upstream_status_mapped() {
+        let upstream = MockUpstream::start(MockResponse { status: 599, ..MockResponse::default() }).await.unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.upstream_services.get_mut("service-a").unwrap().max_retries = 0;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()["x-proxy-original-status"], "599");
+        assert_eq!(metrics.upstream_anomaly_count("nonstandard_status", "mapped"), 1);
     }
 
-    fn full<T: Into<Bytes>>(chunk: T) -> BoxBody {
-        Full::new(chunk.into())
-            .map_err(|never| match never {})
-            .boxed()
+    #[tokio::test]
+    async fn test_oversized_upstream_headers_rejected_or_cookies_truncated() {
+        let cookies: Vec<(String, String)> =
+            (0..80).map(|index| ("set-cookie".to_string(), format!("c{}={}", index, "x".repeat(100)))).collect();
+        let upstream = MockUpstream::start(MockResponse { headers: cookies, ..MockResponse::default() }).await.unwrap();
+        let get = |addr: SocketAddr| reqwest::get(format!("http://{}/api/a/items", addr));
+
+        let mut config = config_with_endpoint(upstream.url());
+        config.upstream_responses.max_header_bytes = 4096;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config.clone(), Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        let response = get(addr).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "upstream_headers_too_large");
+        assert_eq!(metrics.upstream_anomaly_count("header_bytes", "rejected"), 1);
+
+        config.upstream_responses.on_oversized_headers = OversizedHeaders::TruncateCookies;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        let response = get(addr).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let kept = response.headers().get_all("set-cookie").iter().count();
+        assert!(kept > 0 && kept < 80, "{} cookies kept", kept);
+        assert!(response.headers()["set-cookie"].to_str().unwrap().starts_with("c0="));
+        assert_eq!(metrics.upstream_anomaly_count("header_bytes", "truncated"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_header_rules_rewrite_both_directions() {
+        let upstream = MockUpstream::start(MockResponse {
+            headers: vec![("server".to_string(), "nginx".to_string())],
+            ..MockResponse::default()
+        })
+        .await
+        .unwrap();
+        let mut config = config_with_endpoint(upstream.url());
+        config.upstream_services.get_mut("service-a").unwrap().header_rules = serde_json::from_value(serde_json::json!([
+            {"type": "remove_request", "name": "authorization"},
+            {"type": "inject_request", "name": "x-internal-token", "value": "${service}\r\nx-admin: 1"},
+            {"type": "inject_response", "name": "x-served-by", "value": "${service}"},
+            {"type": "remove_response", "name": "server"},
+        ]))
+        .unwrap();
+        let addr = start_proxy(config).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/a/items", addr))
+            .header("authorization", "Bearer secret")
+            .header("x-internal-token", "forged")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-served-by"], "service-a");
+        assert!(response.headers().get("server").is_none());
+
+        let forwarded = upstream.last_request_headers("/api/a/items").unwrap();
+        assert!(forwarded.get("authorization").is_none());
+        // Rendered values go through the header value guard.
+        assert_eq!(forwarded["x-internal-token"], "service-ax-admin: 1");
+        assert!(forwarded.get("x-admin").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_methods_and_header_bytes_forwarded_verbatim() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let addr = start_proxy(config_with_endpoint(upstream.url())).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/a/items", addr);
+
+        for method in ["PROPFIND", "MKCOL", "REINDEX-NOW"] {
+            let method = reqwest::Method::from_bytes(method.as_bytes()).unwrap();
+            let response = client.request(method, &url).body("<propfind/>").send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let proxied: Vec<String> =
+            upstream.request_log().into_iter().filter(|line| !line.ends_with("/health")).collect();
+        assert_eq!(proxied, vec!["PROPFIND /api/a/items", "MKCOL /api/a/items", "REINDEX-NOW /api/a/items"]);
+
+        // Not valid UTF-8, and repeated; both survive the hop.
+        let latin1 = reqwest::header::HeaderValue::from_bytes(b"caf\xe9").unwrap();
+        let response = client
+            .get(&url)
+            .header("x-label", latin1.clone())
+            .header("x-tag", "one")
+            .header("x-tag", "two")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let forwarded = upstream.last_request_headers("/api/a/items").unwrap();
+        assert_eq!(forwarded["x-label"].as_bytes(), latin1.as_bytes());
+        let tags: Vec<_> = forwarded.get_all("x-tag").iter().collect();
+        assert_eq!(tags, vec!["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_stats_export_as_csv_and_json() {
+        let metrics = Arc::new(MetricsCollector::new());
+        // Recent enough that the background tick keeps them.
+        let base = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 60 - 10;
+        let minute = |minutes: u64| UNIX_EPOCH + Duration::from_secs((base + minutes) * 60);
+        let at = |minutes: u64| (base + minutes) * 60;
+        let history = metrics.history();
+        history.record("http://localhost:3001", 10, true, minute(1));
+        history.record("http://localhost:3001", 30, false, minute(1));
+        history.record("http://localhost:3002", 5, true, minute(1));
+        history.record("http://localhost:3001", 20, true, minute(2));
+        history.aggregate(minute(3));
+
+        let proxy = ProxyServer::new(Config::new(), Arc::new(AIEngine::new()), metrics).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+        let get = |query: &str| reqwest::get(format!("http://{}/admin/stats/export?{}", addr, query));
+
+        let response = get(&format!("service=service-a&until={}", at(2))).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        assert_eq!(
+            response.text().await.unwrap(),
+            format!("minute,endpoint,requests,errors,p50_ms,p95_ms\n{},http://localhost:3001,2,1,10.00,30.00\n", at(1))
+        );
+
+        let rows: serde_json::Value = get(&format!("format=json&since={}", at(1))).await.unwrap().json().await.unwrap();
+        let rows: Vec<(u64, &str)> = rows
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["minute"].as_u64().unwrap(), row["endpoint"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![(at(1), "http://localhost:3001"), (at(1), "http://localhost:3002"), (at(2), "http://localhost:3001")]
+        );
+
+        let empty: serde_json::Value = get(&format!("format=json&since={}", at(3))).await.unwrap().json().await.unwrap();
+        assert_eq!(empty, serde_json::json!([]));
+        assert_eq!(get("format=xml").await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get("since=yesterday").await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get("service=nope").await.unwrap().status(), StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,66 @@
+use std::net::{IpAddr, SocketAddr};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+// The PROXY protocol v1 line for a connection from `client` to `upstream`,
+// as in the HAProxy spec section 2.1. Both addresses must be of one family,
+// so an IPv4 address meeting an IPv6 one is sent IPv4-mapped.
+pub fn header(client: SocketAddr, upstream: SocketAddr) -> String {
+    // IPv4 clients of a dual-stack listener arrive IPv4-mapped.
+    let (client_ip, upstream_ip) = (client.ip().to_canonical(), upstream.ip().to_canonical());
+    let (family, client_ip, upstream_ip) = match (client_ip, upstream_ip) {
+        (IpAddr::V4(client_ip), IpAddr::V4(upstream_ip)) => ("TCP4", client_ip.into(), upstream_ip.into()),
+        (client_ip, upstream_ip) => ("TCP6", mapped(client_ip), mapped(upstream_ip)),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        client_ip,
+        upstream_ip,
+        client.port(),
+        upstream.port()
+    )
+}
+
+fn mapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().into(),
+        v6 => v6,
+    }
+}
+
+// Writes the header ahead of anything else on a freshly opened connection,
+// TLS included.
+pub async fn announce(stream: &mut TcpStream, client: SocketAddr) -> std::io::Result<()> {
+    let line = header(client, stream.peer_addr()?);
+    stream.write_all(line.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_names_both_ends() {
+        let client: SocketAddr = "192.0.2.10:51234".parse().unwrap();
+        let upstream: SocketAddr = "10.0.0.5:8080".parse().unwrap();
+        assert_eq!(header(client, upstream), "PROXY TCP4 192.0.2.10 10.0.0.5 51234 8080\r\n");
+
+        let client: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        let upstream: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        assert_eq!(header(client, upstream), "PROXY TCP6 2001:db8::1 2001:db8::2 40000 443\r\n");
+    }
+
+    #[test]
+    fn test_header_settles_on_one_family() {
+        let dual_stack: SocketAddr = "[::ffff:192.0.2.10]:51234".parse().unwrap();
+        let upstream: SocketAddr = "10.0.0.5:8080".parse().unwrap();
+        assert_eq!(header(dual_stack, upstream), "PROXY TCP4 192.0.2.10 10.0.0.5 51234 8080\r\n");
+
+        let client: SocketAddr = "192.0.2.10:51234".parse().unwrap();
+        let upstream: SocketAddr = "[2001:db8::2]:8080".parse().unwrap();
+        assert_eq!(
+            header(client, upstream),
+            "PROXY TCP6 ::ffff:192.0.2.10 2001:db8::2 51234 8080\r\n"
+        );
+    }
+}
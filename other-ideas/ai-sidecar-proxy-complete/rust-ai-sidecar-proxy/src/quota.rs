@@ -0,0 +1,548 @@
+use crate::{
+    clock::Clock,
+    storage::{self, KeyValueStore, Slot},
+};
+use async_trait::async_trait;
+use hyper::header::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+const DAY: u64 = 86_400;
+
+// Unlike the rate limiter, which smooths bursts and forgets everything on
+// restart, quotas count every request an API key makes in a calendar period
+// and keep the counts across restarts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    // Requests without this header are not metered.
+    pub key_header: String,
+    pub period: QuotaPeriod,
+    // Requests per key per period.
+    pub limit: u64,
+    // Keys with a limit of their own.
+    pub limits: HashMap<String, u64>,
+    // Where usage survives restarts; unset keeps it in memory only.
+    pub persist_path: Option<PathBuf>,
+    // Requests counted since the last write are lost on a crash; a clean
+    // shutdown writes them too.
+    pub persist_interval_ms: u64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            key_header: "x-api-key".to_string(),
+            period: QuotaPeriod::Month,
+            limit: 10_000,
+            limits: HashMap::new(),
+            persist_path: None,
+            persist_interval_ms: 10_000,
+        }
+    }
+}
+
+// Calendar periods in UTC.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Day,
+    #[default]
+    Month,
+}
+
+impl QuotaPeriod {
+    // The period holding `secs`, as [start, end) in Unix seconds.
+    pub fn bounds(self, secs: u64) -> (u64, u64) {
+        let days = secs / DAY;
+        match self {
+            QuotaPeriod::Day => (days * DAY, (days + 1) * DAY),
+            QuotaPeriod::Month => {
+                let (year, month) = year_month(days);
+                let next = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                (first_of_month(year, month) * DAY, first_of_month(next.0, next.1) * DAY)
+            }
+        }
+    }
+}
+
+// Civil date arithmetic from Howard Hinnant's `civil_from_days` and
+// `days_from_civil`, on days since 1970-01-01.
+fn year_month(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+fn first_of_month(year: u64, month: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    // Start of the period the counts belong to, in Unix seconds.
+    pub period_start: u64,
+    pub used: u64,
+    // Extra requests granted over the admin API, for this period only.
+    pub granted: u64,
+}
+
+// Absolute counts, never deltas: restoring the same snapshot twice, or one
+// written after another, can't count a request twice.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QuotaSnapshot {
+    pub usage: HashMap<String, KeyUsage>,
+}
+
+// Where quota usage is kept between runs.
+#[async_trait]
+pub trait QuotaStore: Send + Sync {
+    // None when nothing has been saved yet.
+    async fn load(&self) -> std::io::Result<Option<QuotaSnapshot>>;
+    async fn save(&self, snapshot: &QuotaSnapshot) -> std::io::Result<()>;
+}
+
+#[async_trait]
+impl QuotaStore for Slot {
+    async fn load(&self) -> std::io::Result<Option<QuotaSnapshot>> {
+        match Slot::load(self).await? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    async fn save(&self, snapshot: &QuotaSnapshot) -> std::io::Result<()> {
+        let json = serde_json::to_vec(snapshot).expect("quota usage serializes");
+        Slot::save(self, &json).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QuotaStatus {
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    // When the period ends, in Unix seconds.
+    pub reset_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaDecision {
+    pub allowed: bool,
+    pub status: QuotaStatus,
+    // Until the period ends; what a refused client should wait.
+    pub retry_after: Duration,
+}
+
+impl QuotaDecision {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("x-quota-limit", HeaderValue::from(self.status.limit));
+        headers.insert("x-quota-remaining", HeaderValue::from(self.status.remaining));
+        headers.insert("x-quota-reset", HeaderValue::from(self.status.reset_at));
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    keys: HashMap<String, KeyUsage>,
+    // Bumped on every change, so a save only clears what it wrote.
+    version: u64,
+    saved_version: u64,
+}
+
+pub struct Quotas {
+    config: QuotaConfig,
+    clock: Arc<dyn Clock>,
+    store: Option<Arc<dyn QuotaStore>>,
+    usage: Mutex<Usage>,
+    // One save at a time, so an older snapshot never lands after a newer one.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig, clock: Arc<dyn Clock>, store: Option<Arc<dyn QuotaStore>>) -> Self {
+        Self {
+            config,
+            clock,
+            store,
+            usage: Mutex::new(Usage::default()),
+            saving: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // Persists to `store` when one is given, else to `persist_path` when one
+    // is set.
+    pub fn from_config(config: &QuotaConfig, clock: Arc<dyn Clock>, store: Option<Arc<dyn KeyValueStore>>) -> Self {
+        let slot = match (store, &config.persist_path) {
+            (Some(store), _) => Some(Slot::Stored { store, namespace: storage::QUOTA_NAMESPACE, key: "usage" }),
+            (None, path) => path.clone().map(Slot::File),
+        };
+        let store = slot.map(|slot| Arc::new(slot) as Arc<dyn QuotaStore>);
+        Self::new(config.clone(), clock, store)
+    }
+
+    pub fn config(&self) -> &QuotaConfig {
+        &self.config
+    }
+
+    pub fn persist_interval(&self) -> Duration {
+        Duration::from_millis(self.config.persist_interval_ms.max(1))
+    }
+
+    pub fn key<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        headers
+            .get(self.config.key_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .filter(|key| !key.is_empty())
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.clock.wall().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+
+    fn limit_for(&self, key: &str) -> u64 {
+        self.config.limits.get(key).copied().unwrap_or(self.config.limit)
+    }
+
+    // Starts from the saved usage. A missing snapshot is a first run; an
+    // unreadable one is logged and ignored, as the AI engine does.
+    pub async fn restore(&self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        match store.load().await {
+            Ok(Some(snapshot)) => {
+                info!(keys = snapshot.usage.len(), "restored quota usage");
+                let mut usage = self.usage.lock().unwrap();
+                usage.keys = snapshot.usage;
+                usage.saved_version = usage.version;
+            }
+            Ok(None) => {}
+            Err(e) => warn!(error = %e, "ignoring unreadable quota usage"),
+        }
+    }
+
+    // Counts one request against `key` when it has quota left. Refused
+    // requests are not counted.
+    pub fn check(&self, key: &str) -> QuotaDecision {
+        let now = self.now_secs();
+        let (period_start, period_end) = self.config.period.bounds(now);
+        let limit = self.limit_for(key);
+        let mut usage = self.usage.lock().unwrap();
+        let entry = Self::current(&mut usage.keys, key, period_start);
+        let allowed = entry.used < limit.saturating_add(entry.granted);
+        if allowed {
+            entry.used += 1;
+        }
+        let status = Self::status(entry, limit, period_end);
+        if allowed {
+            usage.version += 1;
+        }
+        QuotaDecision {
+            allowed,
+            status,
+            retry_after: Duration::from_secs(period_end.saturating_sub(now)),
+        }
+    }
+
+    // Extra requests for `key`, until the period ends.
+    pub fn grant(&self, key: &str, requests: u64) -> QuotaStatus {
+        self.update(key, |entry| entry.granted = entry.granted.saturating_add(requests))
+    }
+
+    // Forgets what `key` used and was granted this period.
+    pub fn reset(&self, key: &str) -> QuotaStatus {
+        self.update(key, |entry| {
+            entry.used = 0;
+            entry.granted = 0;
+        })
+    }
+
+    fn update(&self, key: &str, change: impl FnOnce(&mut KeyUsage)) -> QuotaStatus {
+        let (period_start, period_end) = self.config.period.bounds(self.now_secs());
+        let limit = self.limit_for(key);
+        let mut usage = self.usage.lock().unwrap();
+        let entry = Self::current(&mut usage.keys, key, period_start);
+        change(entry);
+        let status = Self::status(entry, limit, period_end);
+        usage.version += 1;
+        status
+    }
+
+    // The entry for this period; counts from an earlier one start over.
+    fn current<'a>(keys: &'a mut HashMap<String, KeyUsage>, key: &str, period_start: u64) -> &'a mut KeyUsage {
+        let entry = keys.entry(key.to_string()).or_insert(KeyUsage {
+            period_start,
+            used: 0,
+            granted: 0,
+        });
+        if entry.period_start != period_start {
+            *entry = KeyUsage {
+                period_start,
+                used: 0,
+                granted: 0,
+            };
+        }
+        entry
+    }
+
+    fn status(entry: &KeyUsage, limit: u64, period_end: u64) -> QuotaStatus {
+        let allowance = limit.saturating_add(entry.granted);
+        QuotaStatus {
+            limit: allowance,
+            used: entry.used,
+            remaining: allowance.saturating_sub(entry.used),
+            reset_at: period_end,
+        }
+    }
+
+    // Every key with usage in the current period.
+    pub fn statuses(&self) -> HashMap<String, QuotaStatus> {
+        let (period_start, period_end) = self.config.period.bounds(self.now_secs());
+        let usage = self.usage.lock().unwrap();
+        usage
+            .keys
+            .iter()
+            .filter(|(_, entry)| entry.period_start == period_start)
+            .map(|(key, entry)| (key.clone(), Self::status(entry, self.limit_for(key), period_end)))
+            .collect()
+    }
+
+    // Saves the usage if it changed since the last save. Returns whether it
+    // wrote anything.
+    pub async fn persist(&self) -> std::io::Result<bool> {
+        let Some(store) = &self.store else {
+            return Ok(false);
+        };
+        let _saving = self.saving.lock().await;
+        let (snapshot, version) = {
+            let mut usage = self.usage.lock().unwrap();
+            if usage.version == usage.saved_version {
+                return Ok(false);
+            }
+            let (period_start, _) = self.config.period.bounds(self.now_secs());
+            // Earlier periods are never read again.
+            usage.keys.retain(|_, entry| entry.period_start >= period_start);
+            (
+                QuotaSnapshot {
+                    usage: usage.keys.clone(),
+                },
+                usage.version,
+            )
+        };
+        store.save(&snapshot).await?;
+        self.usage.lock().unwrap().saved_version = version;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    // 2024-01-31T23:59:00Z.
+    const JAN_31_LATE: u64 = 1_706_745_540;
+    // 2024-02-01T00:00:00Z and 2024-03-01T00:00:00Z.
+    const FEB_1: u64 = 1_706_745_600;
+    const MAR_1: u64 = 1_709_251_200;
+
+    fn clock_at(secs: u64) -> Arc<MockClock> {
+        Arc::new(MockClock::at(UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+
+    fn config(limit: u64) -> QuotaConfig {
+        QuotaConfig {
+            limit,
+            ..QuotaConfig::default()
+        }
+    }
+
+    // Counts saves and can be told to fail.
+    #[derive(Default)]
+    struct MemoryStore {
+        saved: Mutex<Option<QuotaSnapshot>>,
+        saves: Mutex<u32>,
+        fail: Mutex<bool>,
+    }
+
+    #[async_trait]
+    impl QuotaStore for MemoryStore {
+        async fn load(&self) -> std::io::Result<Option<QuotaSnapshot>> {
+            Ok(self.saved.lock().unwrap().clone())
+        }
+
+        async fn save(&self, snapshot: &QuotaSnapshot) -> std::io::Result<()> {
+            if *self.fail.lock().unwrap() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            *self.saves.lock().unwrap() += 1;
+            *self.saved.lock().unwrap() = Some(snapshot.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_period_bounds() {
+        assert_eq!(QuotaPeriod::Month.bounds(JAN_31_LATE), (FEB_1 - 31 * DAY, FEB_1));
+        // 2024 is a leap year.
+        assert_eq!(QuotaPeriod::Month.bounds(FEB_1), (FEB_1, MAR_1));
+        assert_eq!(MAR_1 - FEB_1, 29 * DAY);
+        assert_eq!(QuotaPeriod::Day.bounds(JAN_31_LATE), (FEB_1 - DAY, FEB_1));
+        // 2023-12-15 to 2024-01-01.
+        assert_eq!(QuotaPeriod::Month.bounds(1_702_598_400).1, 1_704_067_200);
+        assert_eq!(QuotaPeriod::Month.bounds(0), (0, 31 * DAY));
+    }
+
+    #[test]
+    fn test_refuses_past_limit_and_starts_over_next_period() {
+        let clock = clock_at(JAN_31_LATE);
+        let quotas = Quotas::new(config(2), clock.clone(), None);
+
+        assert!(quotas.check("k").allowed);
+        let second = quotas.check("k");
+        assert!(second.allowed);
+        assert_eq!(second.status.remaining, 0);
+        let refused = quotas.check("k");
+        assert!(!refused.allowed);
+        assert_eq!(refused.status.used, 2);
+        assert_eq!(refused.status.reset_at, FEB_1);
+        assert_eq!(refused.retry_after, Duration::from_secs(60));
+        assert!(quotas.check("other").allowed);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!quotas.check("k").allowed);
+        clock.advance(Duration::from_secs(1));
+        let next = quotas.check("k");
+        assert!(next.allowed);
+        assert_eq!((next.status.used, next.status.reset_at), (1, MAR_1));
+    }
+
+    #[test]
+    fn test_grants_and_resets_last_one_period() {
+        let clock = clock_at(JAN_31_LATE);
+        let mut config = config(1);
+        config.limits.insert("big".to_string(), 3);
+        let quotas = Quotas::new(config, clock.clone(), None);
+
+        assert!(quotas.check("k").allowed);
+        assert!(!quotas.check("k").allowed);
+        assert_eq!(quotas.grant("k", 2).remaining, 2);
+        assert!(quotas.check("k").allowed);
+        assert_eq!(quotas.reset("k").remaining, 1);
+        assert_eq!(quotas.statuses()["k"].limit, 1);
+        assert_eq!(quotas.check("big").status.limit, 3);
+
+        quotas.grant("k", 5);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(quotas.check("k").status.limit, 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart_replays_saved_counts_once() {
+        let clock = clock_at(JAN_31_LATE - DAY);
+        let store = Arc::new(MemoryStore::default());
+        let quotas = Quotas::new(config(10), clock.clone(), Some(store.clone()));
+
+        for _ in 0..3 {
+            quotas.check("k");
+        }
+        assert!(quotas.persist().await.unwrap());
+        // Nothing new since: no write.
+        assert!(!quotas.persist().await.unwrap());
+        quotas.check("k");
+        quotas.check("k");
+        assert!(quotas.persist().await.unwrap());
+        assert_eq!(*store.saves.lock().unwrap(), 2);
+        // Counted after the last save; lost in a crash.
+        quotas.check("k");
+
+        // Two snapshots were written; the restored count is the last one,
+        // not their sum.
+        let restarted = Quotas::new(config(10), clock.clone(), Some(store.clone()));
+        restarted.restore().await;
+        assert_eq!(restarted.statuses()["k"].used, 5);
+        assert!(!restarted.persist().await.unwrap());
+        assert_eq!(restarted.check("k").status.used, 6);
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_period_boundary_starts_over() {
+        let clock = clock_at(JAN_31_LATE);
+        let store = Arc::new(MemoryStore::default());
+        let quotas = Quotas::new(config(1), clock.clone(), Some(store.clone()));
+        quotas.check("k");
+        quotas.persist().await.unwrap();
+
+        clock.advance(Duration::from_secs(120));
+        let restarted = Quotas::new(config(1), clock.clone(), Some(store.clone()));
+        restarted.restore().await;
+        assert!(restarted.statuses().is_empty());
+        assert!(restarted.check("k").allowed);
+        restarted.persist().await.unwrap();
+        assert_eq!(store.saved.lock().unwrap().as_ref().unwrap().usage["k"].period_start, FEB_1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_is_retried() {
+        let store = Arc::new(MemoryStore::default());
+        let quotas = Quotas::new(config(10), clock_at(FEB_1), Some(store.clone()));
+        quotas.check("k");
+        *store.fail.lock().unwrap() = true;
+        assert!(quotas.persist().await.is_err());
+        *store.fail.lock().unwrap() = false;
+        assert!(quotas.persist().await.unwrap());
+        assert_eq!(store.saved.lock().unwrap().as_ref().unwrap().usage["k"].used, 1);
+    }
+
+    #[tokio::test]
+    async fn test_slots_round_trip() {
+        let dir = std::env::temp_dir().join(format!("quota-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shared: Arc<dyn KeyValueStore> =
+            Arc::new(storage::FileStore::new(dir.join("shared"), Arc::new(crate::clock::SystemClock)).unwrap());
+        let slots = [
+            Slot::File(dir.join("quotas.json")),
+            Slot::Stored { store: shared.clone(), namespace: storage::QUOTA_NAMESPACE, key: "usage" },
+        ];
+
+        let snapshot = QuotaSnapshot {
+            usage: HashMap::from([(
+                "k".to_string(),
+                KeyUsage {
+                    period_start: FEB_1,
+                    used: 4,
+                    granted: 1,
+                },
+            )]),
+        };
+        for slot in slots {
+            assert_eq!(QuotaStore::load(&slot).await.unwrap(), None);
+            QuotaStore::save(&slot, &snapshot).await.unwrap();
+            assert_eq!(QuotaStore::load(&slot).await.unwrap(), Some(snapshot.clone()));
+        }
+        assert!(shared.get(storage::QUOTA_NAMESPACE, "usage").await.unwrap().is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,14 +1,29 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tracing::debug;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    // Allows bursts up to `burst_size`, refilled at `requests_per_second`.
+    #[default]
+    TokenBucket,
+    // Allows exactly `requests_per_second * window_size_ms / 1000` requests in
+    // any `window_size_ms` span.
+    SlidingWindow,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct RateLimitConfig {
     pub requests_per_second: u32,
     pub burst_size: u32,
-    pub window_size: Duration,
+    // Only read by the sliding window.
+    pub window_size_ms: u64,
+    pub algorithm: RateLimitAlgorithm,
 }
 
 impl Default for RateLimitConfig {
@@ -16,11 +31,18 @@ impl Default for RateLimitConfig {
         Self {
             requests_per_second: 100,
             burst_size: 10,
-            window_size: Duration::from_secs(1),
+            window_size_ms: 1000,
+            algorithm: RateLimitAlgorithm::TokenBucket,
         }
     }
 }
 
+impl RateLimitConfig {
+    pub fn window_size(&self) -> Duration {
+        Duration::from_millis(self.window_size_ms)
+    }
+}
+
 #[derive(Debug)]
 struct TokenBucket {
     tokens: f64,
@@ -62,17 +84,135 @@ impl TokenBucket {
     fn available_tokens(&self) -> f64 {
         self.tokens
     }
+
+    // Until `tokens` will have refilled, as of the last refill.
+    fn time_until(&self, tokens: f64) -> Duration {
+        let missing = (tokens - self.tokens).max(0.0);
+        if missing == 0.0 {
+            return Duration::ZERO;
+        }
+        if self.refill_rate <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(missing / self.refill_rate)
+    }
+}
+
+// Counts the requests admitted per key within the last `window_size`. A
+// weighted request takes `n.ceil()` slots.
+pub struct SlidingWindowRateLimiter {
+    windows: Arc<RwLock<HashMap<String, VecDeque<Instant>>>>,
+    limit: usize,
+    window: Duration,
+}
+
+impl SlidingWindowRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let window = config.window_size();
+        let limit = (config.requests_per_second as f64 * window.as_secs_f64()).floor() as usize;
+        Self {
+            windows: Arc::new(RwLock::new(HashMap::new())),
+            limit,
+            window,
+        }
+    }
+
+    pub async fn is_allowed(&self, key: &str) -> bool {
+        self.is_allowed_n(key, 1.0).await
+    }
+
+    pub async fn is_allowed_n(&self, key: &str, requests: f64) -> bool {
+        self.is_allowed_at(key, requests, Instant::now()).await
+    }
+
+    async fn is_allowed_at(&self, key: &str, requests: f64, now: Instant) -> bool {
+        let mut windows = self.windows.write().await;
+        let window = windows.entry(key.to_string()).or_default();
+        self.expire(window, now);
+
+        let slots = requests.max(0.0).ceil() as usize;
+        let allowed = window.len() + slots <= self.limit;
+        if allowed {
+            window.extend(std::iter::repeat_n(now, slots));
+        }
+
+        debug!(
+            "Sliding window check for {}: {} (requests: {:.1}, in window: {}/{})",
+            key,
+            if allowed { "ALLOWED" } else { "DENIED" },
+            requests,
+            window.len(),
+            self.limit
+        );
+
+        allowed
+    }
+
+    // A request admitted at t counts until t + window.
+    fn expire(&self, window: &mut VecDeque<Instant>, now: Instant) {
+        while window.front().is_some_and(|admitted| now.duration_since(*admitted) >= self.window) {
+            window.pop_front();
+        }
+    }
+
+    pub async fn get_remaining(&self, key: &str) -> usize {
+        let mut windows = self.windows.write().await;
+        match windows.get_mut(key) {
+            Some(window) => {
+                self.expire(window, Instant::now());
+                self.limit.saturating_sub(window.len())
+            }
+            None => self.limit,
+        }
+    }
+
+    // Until enough of the window expires to admit one more request.
+    pub async fn retry_after(&self, key: &str) -> Duration {
+        if self.limit == 0 {
+            return Duration::MAX;
+        }
+        let windows = self.windows.read().await;
+        let Some(window) = windows.get(key) else {
+            return Duration::ZERO;
+        };
+        match window.len().checked_sub(self.limit).and_then(|index| window.get(index)) {
+            Some(admitted) => (*admitted + self.window).saturating_duration_since(Instant::now()),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub async fn reset(&self, key: &str) {
+        let mut windows = self.windows.write().await;
+        windows.remove(key);
+    }
+
+    pub async fn cleanup_expired(&self) {
+        let mut windows = self.windows.write().await;
+        let now = Instant::now();
+
+        windows.retain(|_, window| {
+            self.expire(window, now);
+            !window.is_empty()
+        });
+    }
 }
 
 pub struct RateLimiter {
     buckets: Arc<RwLock<HashMap<String, TokenBucket>>>,
+    // Set when the config asks for a sliding window; the buckets go unused.
+    sliding: Option<SlidingWindowRateLimiter>,
     config: RateLimitConfig,
 }
 
 impl RateLimiter {
     pub fn new(config: RateLimitConfig) -> Self {
+        let sliding = match config.algorithm {
+            RateLimitAlgorithm::TokenBucket => None,
+            RateLimitAlgorithm::SlidingWindow => Some(SlidingWindowRateLimiter::new(config.clone())),
+        };
         Self {
             buckets: Arc::new(RwLock::new(HashMap::new())),
+            sliding,
             config,
         }
     }
@@ -82,6 +222,9 @@ impl RateLimiter {
     }
 
     pub async fn is_allowed_n(&self, key: &str, tokens: f64) -> bool {
+        if let Some(sliding) = &self.sliding {
+            return sliding.is_allowed_n(key, tokens).await;
+        }
         let mut buckets = self.buckets.write().await;
         
         let bucket = buckets.entry(key.to_string()).or_insert_with(|| {
@@ -105,18 +248,42 @@ impl RateLimiter {
     }
 
     pub async fn get_remaining_tokens(&self, key: &str) -> f64 {
+        if let Some(sliding) = &self.sliding {
+            return sliding.get_remaining(key).await as f64;
+        }
         let buckets = self.buckets.read().await;
         buckets.get(key)
             .map(|bucket| bucket.available_tokens())
             .unwrap_or(self.config.burst_size as f64)
     }
 
+    // How long `key` has to wait before a request would be allowed.
+    pub async fn retry_after(&self, key: &str) -> Duration {
+        if let Some(sliding) = &self.sliding {
+            return sliding.retry_after(key).await;
+        }
+        let mut buckets = self.buckets.write().await;
+        match buckets.get_mut(key) {
+            Some(bucket) => {
+                bucket.refill();
+                bucket.time_until(1.0)
+            }
+            None => Duration::ZERO,
+        }
+    }
+
     pub async fn reset_bucket(&self, key: &str) {
+        if let Some(sliding) = &self.sliding {
+            sliding.reset(key).await;
+        }
         let mut buckets = self.buckets.write().await;
         buckets.remove(key);
     }
 
     pub async fn cleanup_expired_buckets(&self) {
+        if let Some(sliding) = &self.sliding {
+            sliding.cleanup_expired().await;
+        }
         let mut buckets = self.buckets.write().await;
         let now = Instant::now();
         
@@ -136,7 +303,8 @@ mod tests {
         let config = RateLimitConfig {
             requests_per_second: 2,
             burst_size: 5,
-            window_size: Duration::from_secs(1),
+            window_size_ms: 1000,
+            algorithm: RateLimitAlgorithm::TokenBucket,
         };
         
         let limiter = RateLimiter::new(config);
@@ -153,7 +321,8 @@ mod tests {
         let config = RateLimitConfig {
             requests_per_second: 10,
             burst_size: 1,
-            window_size: Duration::from_secs(1),
+            window_size_ms: 1000,
+            algorithm: RateLimitAlgorithm::TokenBucket,
         };
         
         let limiter = RateLimiter::new(config);
@@ -165,4 +334,86 @@ mod tests {
         
         assert!(limiter.is_allowed("test").await);
     }
+
+    fn sliding_config(requests_per_second: u32, window: Duration) -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_second,
+            burst_size: 0,
+            window_size_ms: window.as_millis() as u64,
+            algorithm: RateLimitAlgorithm::SlidingWindow,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sliding_window_reopens_exactly_at_window_boundary() {
+        let window = Duration::from_secs(1);
+        let limiter = SlidingWindowRateLimiter::new(sliding_config(3, window));
+        let epsilon = Duration::from_millis(1);
+        let t0 = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.is_allowed_at("key", 1.0, t0).await);
+        }
+        assert!(!limiter.is_allowed_at("key", 1.0, t0).await);
+        assert!(!limiter.is_allowed_at("key", 1.0, t0 + window - epsilon).await);
+        assert!(limiter.is_allowed_at("key", 1.0, t0 + window + epsilon).await);
+        assert!(limiter.is_allowed_at("other", 1.0, t0).await);
+    }
+
+    #[tokio::test]
+    async fn test_sliding_window_counts_each_request_exactly() {
+        let limiter = SlidingWindowRateLimiter::new(sliding_config(1, Duration::from_secs(4)));
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+
+        assert!(limiter.is_allowed_at("key", 2.0, at(0)).await);
+        assert!(limiter.is_allowed_at("key", 1.0, at(2)).await);
+        assert!(!limiter.is_allowed_at("key", 2.0, at(3)).await);
+        // The first two expire; the one from t0+2s still counts.
+        assert!(limiter.is_allowed_at("key", 3.0, at(4)).await);
+        assert!(!limiter.is_allowed_at("key", 1.0, at(5)).await);
+        assert!(limiter.is_allowed_at("key", 1.0, at(6)).await);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_follows_refill() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_second: 4,
+            burst_size: 2,
+            window_size_ms: 1000,
+            algorithm: RateLimitAlgorithm::TokenBucket,
+        });
+        assert_eq!(limiter.retry_after("test").await, Duration::ZERO);
+        assert!(limiter.is_allowed("test").await);
+        assert!(limiter.is_allowed("test").await);
+        assert!(!limiter.is_allowed("test").await);
+
+        let wait = limiter.retry_after("test").await;
+        assert!(wait > Duration::from_millis(200) && wait <= Duration::from_millis(250), "{:?}", wait);
+
+        let sliding = SlidingWindowRateLimiter::new(sliding_config(2, Duration::from_secs(1)));
+        let t0 = Instant::now();
+        assert!(sliding.is_allowed_at("key", 2.0, t0).await);
+        let wait = sliding.retry_after("key").await;
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_uses_configured_algorithm() {
+        let window = Duration::from_millis(100);
+        let limiter = RateLimiter::new(sliding_config(20, window));
+
+        assert!(limiter.is_allowed("test").await);
+        assert!(limiter.is_allowed("test").await);
+        assert!(!limiter.is_allowed("test").await);
+        assert_eq!(limiter.get_remaining_tokens("test").await, 0.0);
+
+        sleep(window + Duration::from_millis(20)).await;
+
+        assert_eq!(limiter.get_remaining_tokens("test").await, 2.0);
+        assert!(limiter.is_allowed("test").await);
+        limiter.cleanup_expired_buckets().await;
+        limiter.reset_bucket("test").await;
+        assert_eq!(limiter.get_remaining_tokens("test").await, 2.0);
+    }
 }
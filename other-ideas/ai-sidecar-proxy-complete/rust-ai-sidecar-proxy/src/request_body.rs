@@ -0,0 +1,200 @@
+use crate::{
+    archive::ArchivedBody,
+    buffer_budget::BufferPermit,
+    client_timeouts::BodyReadError,
+    upstream_client::{self, UpstreamBody},
+};
+use bytes::{Buf, Bytes};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+};
+
+// Why a streamed request body stopped short. The client is answered for its
+// own fault rather than the upstream being blamed for the failed call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFault {
+    // Passed `max_body_bytes`.
+    TooLarge,
+    // The client went quiet for longer than the body read timeout.
+    Idle,
+    // The client's side failed, as when it disconnects mid-body.
+    Client,
+}
+
+impl fmt::Display for StreamFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StreamFault::TooLarge => "request body over the limit",
+            StreamFault::Idle => "client stalled while sending the request body",
+            StreamFault::Client => "client request body failed",
+        })
+    }
+}
+
+impl std::error::Error for StreamFault {}
+
+// What a proxied request sends upstream: a buffered body every attempt gets
+// a copy of, or a streamed one only the first attempt gets.
+pub enum RequestBody<B> {
+    Buffered(Bytes),
+    Streamed(Option<StreamedBody<B>>),
+}
+
+impl<B, E> RequestBody<B>
+where
+    B: Body<Data = Bytes, Error = BodyReadError<E>> + Send + Unpin + 'static,
+{
+    pub fn replayable(&self) -> bool {
+        matches!(self, RequestBody::Buffered(_))
+    }
+
+    // Empty once a streamed body has been taken.
+    pub fn for_attempt(&mut self) -> UpstreamBody {
+        match self {
+            RequestBody::Buffered(bytes) => upstream_client::full_body(bytes.clone()),
+            RequestBody::Streamed(streamed) => match streamed.take() {
+                Some(streamed) => streamed.map_err(Into::into).boxed_unsync(),
+                None => upstream_client::full_body(Bytes::new()),
+            },
+        }
+    }
+
+    pub fn fault(&self) -> Option<Arc<OnceLock<StreamFault>>> {
+        match self {
+            RequestBody::Streamed(Some(streamed)) => Some(streamed.fault()),
+            _ => None,
+        }
+    }
+
+    // For archives and captures, which only ever see the read-ahead of a
+    // streamed body.
+    pub fn archived(&self, declared: Option<u64>, cap: usize) -> ArchivedBody {
+        match self {
+            RequestBody::Buffered(bytes) => ArchivedBody::capture(bytes, cap),
+            RequestBody::Streamed(streamed) => {
+                let read_ahead = streamed.as_ref().and_then(|streamed| streamed.read_ahead.as_deref());
+                ArchivedBody::streamed(read_ahead.unwrap_or_default(), declared, cap)
+            }
+        }
+    }
+}
+
+// A request body passed to the upstream as it arrives, starting with the
+// part read ahead while deciding whether to buffer it. It can be sent only
+// once, so a request carrying one is never retried. The read-ahead's bytes
+// go back to the budget when hyper drops the body.
+pub struct StreamedBody<B> {
+    read_ahead: Option<Bytes>,
+    inner: B,
+    // Still allowed under `max_body_bytes`.
+    allowed: usize,
+    fault: Arc<OnceLock<StreamFault>>,
+    _permit: BufferPermit,
+}
+
+impl<B> StreamedBody<B> {
+    pub fn new(read_ahead: Bytes, permit: BufferPermit, inner: B, max_bytes: usize) -> Self {
+        Self {
+            allowed: max_bytes.saturating_sub(read_ahead.len()),
+            read_ahead: (!read_ahead.is_empty()).then_some(read_ahead),
+            inner,
+            fault: Arc::new(OnceLock::new()),
+            _permit: permit,
+        }
+    }
+
+    // Set when the body fails, for whoever is waiting on the upstream call.
+    pub fn fault(&self) -> Arc<OnceLock<StreamFault>> {
+        self.fault.clone()
+    }
+
+    fn fail(&self, fault: StreamFault) -> Poll<Option<Result<Frame<Bytes>, StreamFault>>> {
+        let _ = self.fault.set(fault);
+        Poll::Ready(Some(Err(fault)))
+    }
+}
+
+impl<B, E> Body for StreamedBody<B>
+where
+    B: Body<Data = Bytes, Error = BodyReadError<E>> + Unpin,
+{
+    type Data = Bytes;
+    type Error = StreamFault;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, StreamFault>>> {
+        let this = &mut *self;
+        if let Some(read_ahead) = this.read_ahead.take() {
+            return Poll::Ready(Some(Ok(Frame::data(read_ahead))));
+        }
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                let size = frame.data_ref().map_or(0, |data| data.remaining());
+                if size > this.allowed {
+                    return this.fail(StreamFault::TooLarge);
+                }
+                this.allowed -= size;
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(BodyReadError::Idle))) => this.fail(StreamFault::Idle),
+            Poll::Ready(Some(Err(BodyReadError::Body(_)))) => this.fail(StreamFault::Client),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.read_ahead.is_none() && self.inner.is_end_stream()
+    }
+
+    // Exact when the client declared a Content-Length, so hyper sends one
+    // too; chunked stays chunked.
+    fn size_hint(&self) -> SizeHint {
+        let ahead = self.read_ahead.as_ref().map_or(0, |data| data.len() as u64);
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + ahead);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + ahead);
+        }
+        hint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer_budget::BufferBudget, metrics::MetricsCollector};
+    use futures::StreamExt;
+    use http_body_util::StreamBody;
+    use std::convert::Infallible;
+
+    fn chunks(parts: &'static [&'static [u8]]) -> impl Body<Data = Bytes, Error = BodyReadError<Infallible>> + Unpin {
+        let frames = futures::stream::iter(parts.iter())
+            .map(|part| Ok::<_, BodyReadError<Infallible>>(Frame::data(Bytes::from_static(part))));
+        StreamBody::new(frames)
+    }
+
+    fn permit() -> BufferPermit {
+        Arc::new(BufferBudget::new(1024, Arc::new(MetricsCollector::new()))).permit()
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_comes_first() {
+        let body = StreamedBody::new(Bytes::from_static(b"0123"), permit(), chunks(&[b"45", b"6789"]), 64);
+        assert_eq!(body.size_hint().lower(), 4);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_limit_counts_the_read_ahead() {
+        let body = StreamedBody::new(Bytes::from_static(b"0123"), permit(), chunks(&[b"45", b"6789"]), 8);
+        let fault = body.fault();
+        assert_eq!(body.collect().await.unwrap_err(), StreamFault::TooLarge);
+        assert_eq!(fault.get(), Some(&StreamFault::TooLarge));
+    }
+}
@@ -0,0 +1,217 @@
+use hyper::{
+    header::{self, HeaderValue},
+    http::uri::{Authority, Scheme},
+    Method, Request, StatusCode, Uri,
+};
+use std::fmt;
+
+// The four request-target shapes of RFC 9112 section 3.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetForm {
+    Origin,
+    Absolute,
+    Authority,
+    Asterisk,
+}
+
+impl TargetForm {
+    pub fn of(uri: &Uri) -> Self {
+        if uri.scheme().is_some() {
+            TargetForm::Absolute
+        } else if uri.authority().is_some() {
+            TargetForm::Authority
+        } else if uri.path() == "*" {
+            TargetForm::Asterisk
+        } else {
+            TargetForm::Origin
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TargetForm::Origin => "origin",
+            TargetForm::Absolute => "absolute",
+            TargetForm::Authority => "authority",
+            TargetForm::Asterisk => "asterisk",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetError {
+    // "*" with anything but OPTIONS, a scheme other than http(s), userinfo,
+    // authority-form outside CONNECT, or a path not starting with '/'.
+    Malformed(&'static str),
+    // Absolute-form naming a different host than the Host header.
+    HostMismatch,
+    // Authority-form CONNECT; the proxy does not tunnel.
+    ConnectUnsupported,
+}
+
+impl TargetError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            TargetError::Malformed(_) | TargetError::HostMismatch => StatusCode::BAD_REQUEST,
+            TargetError::ConnectUnsupported => StatusCode::METHOD_NOT_ALLOWED,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            TargetError::Malformed(_) => "malformed_request_target",
+            TargetError::HostMismatch => "host_mismatch",
+            TargetError::ConnectUnsupported => "connect_not_supported",
+        }
+    }
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetError::Malformed(reason) => write!(f, "Malformed request target: {}", reason),
+            TargetError::HostMismatch => write!(f, "Request target host does not match the Host header"),
+            TargetError::ConnectUnsupported => write!(f, "CONNECT is not supported"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    // Origin-form, passed on untouched.
+    Route,
+    // Rewritten from absolute-form to origin-form.
+    Normalized,
+    // OPTIONS *, to be answered by the proxy itself.
+    AnswerLocally,
+}
+
+impl Disposition {
+    pub fn label(self) -> &'static str {
+        match self {
+            Disposition::Route => "routed",
+            Disposition::Normalized => "normalized",
+            Disposition::AnswerLocally => "answered",
+        }
+    }
+}
+
+// Decides what to do with the request's target, rewriting an absolute-form
+// target to origin-form so routing only ever sees a path.
+pub fn check<B>(req: &mut Request<B>) -> Result<Disposition, TargetError> {
+    let uri = req.uri().clone();
+    match TargetForm::of(&uri) {
+        TargetForm::Origin if uri.path().starts_with('/') => Ok(Disposition::Route),
+        TargetForm::Origin => Err(TargetError::Malformed("path must start with '/'")),
+        TargetForm::Asterisk if req.method() == Method::OPTIONS => Ok(Disposition::AnswerLocally),
+        TargetForm::Asterisk => Err(TargetError::Malformed("'*' is only valid for OPTIONS")),
+        TargetForm::Authority if req.method() == Method::CONNECT => Err(TargetError::ConnectUnsupported),
+        TargetForm::Authority => Err(TargetError::Malformed("authority-form is only valid for CONNECT")),
+        TargetForm::Absolute => {
+            normalize(req, &uri)?;
+            Ok(Disposition::Normalized)
+        }
+    }
+}
+
+fn normalize<B>(req: &mut Request<B>, uri: &Uri) -> Result<(), TargetError> {
+    let scheme = uri.scheme().unwrap();
+    if *scheme != Scheme::HTTP && *scheme != Scheme::HTTPS {
+        return Err(TargetError::Malformed("scheme must be http or https"));
+    }
+    let authority = uri.authority().ok_or(TargetError::Malformed("missing host"))?;
+    if authority.as_str().contains('@') {
+        return Err(TargetError::Malformed("userinfo is not allowed"));
+    }
+    if authority.host().is_empty() {
+        return Err(TargetError::Malformed("missing host"));
+    }
+
+    match req.headers().get(header::HOST) {
+        Some(host) => {
+            let host = host
+                .to_str()
+                .ok()
+                .and_then(|host| host.parse::<Authority>().ok())
+                .ok_or(TargetError::HostMismatch)?;
+            if !same_origin(scheme, authority, &host) {
+                return Err(TargetError::HostMismatch);
+            }
+        }
+        None => {
+            let value = HeaderValue::from_str(authority.as_str()).map_err(|_| TargetError::Malformed("invalid host"))?;
+            req.headers_mut().insert(header::HOST, value);
+        }
+    }
+
+    // "http://host" has an empty path rather than "/".
+    let path = match (uri.path(), uri.query()) {
+        ("", None) => "/".to_string(),
+        ("", Some(query)) => format!("/?{}", query),
+        (path, None) => path.to_string(),
+        (path, Some(query)) => format!("{}?{}", path, query),
+    };
+    *req.uri_mut() = path.parse().map_err(|_| TargetError::Malformed("invalid path"))?;
+    Ok(())
+}
+
+// Hosts compare case-insensitively, and an omitted port is the scheme's default.
+fn same_origin(scheme: &Scheme, target: &Authority, host: &Authority) -> bool {
+    let default_port = if *scheme == Scheme::HTTPS { 443 } else { 80 };
+    target.host().eq_ignore_ascii_case(host.host())
+        && target.port_u16().unwrap_or(default_port) == host.port_u16().unwrap_or(default_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, target: &str, host: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri(target);
+        if let Some(host) = host {
+            builder = builder.header(header::HOST, host);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_absolute_form_rewritten_to_origin_form() {
+        let mut req = request(Method::GET, "http://Example.com:80/api/a/items?x=1", Some("example.com"));
+        assert_eq!(check(&mut req), Ok(Disposition::Normalized));
+        assert_eq!(req.uri(), "/api/a/items?x=1");
+        assert_eq!(req.headers()[header::HOST], "example.com");
+
+        let mut req = request(Method::GET, "https://example.com", None);
+        assert_eq!(check(&mut req), Ok(Disposition::Normalized));
+        assert_eq!(req.uri(), "/");
+        assert_eq!(req.headers()[header::HOST], "example.com");
+    }
+
+    #[test]
+    fn test_absolute_form_must_agree_with_host() {
+        for (target, host) in [
+            ("http://example.com/", "other.com"),
+            ("http://example.com:8080/", "example.com"),
+            ("https://example.com/", "example.com:80"),
+        ] {
+            let mut req = request(Method::GET, target, Some(host));
+            assert_eq!(check(&mut req), Err(TargetError::HostMismatch), "{} vs {}", target, host);
+        }
+    }
+
+    #[test]
+    fn test_other_forms_classified() {
+        let cases = [
+            (Method::OPTIONS, "*", Ok(Disposition::AnswerLocally)),
+            (Method::GET, "*", Err("malformed_request_target")),
+            (Method::CONNECT, "example.com:443", Err("connect_not_supported")),
+            (Method::GET, "example.com:443", Err("malformed_request_target")),
+            (Method::GET, "ftp://example.com/", Err("malformed_request_target")),
+            (Method::GET, "http://user@example.com/", Err("malformed_request_target")),
+            (Method::GET, "/api/a", Ok(Disposition::Route)),
+        ];
+        for (method, target, expected) in cases {
+            let mut req = request(method, target, None);
+            assert_eq!(check(&mut req).map_err(|e| e.code()), expected, "{}", target);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use bytes::{Buf, Bytes, BytesMut};
+use hyper::body::{Body, Frame, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// How a relayed response body went, reported once it ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relayed {
+    // Its first bytes, up to the body's cap.
+    pub kept: Bytes,
+    pub size: usize,
+    // False when the upstream failed or the client went away first.
+    pub complete: bool,
+}
+
+type OnEnd = Box<dyn FnOnce(Relayed) + Send + Sync>;
+
+// An upstream response body passed to the client frame by frame as it
+// arrives, so a server-sent event stream or a large download is never held
+// in full. Up to `cap` bytes are kept for archives and captures, and `on_end`
+// hears about the transfer once the body ends or is dropped unfinished.
+pub struct RelayedBody<B> {
+    inner: B,
+    cap: usize,
+    kept: BytesMut,
+    size: usize,
+    on_end: Option<OnEnd>,
+}
+
+impl<B> RelayedBody<B> {
+    pub fn new(inner: B, cap: usize, on_end: impl FnOnce(Relayed) + Send + Sync + 'static) -> Self {
+        Self {
+            inner,
+            cap,
+            kept: BytesMut::new(),
+            size: 0,
+            on_end: Some(Box::new(on_end)),
+        }
+    }
+
+    fn end(&mut self, complete: bool) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(Relayed {
+                kept: std::mem::take(&mut self.kept).freeze(),
+                size: self.size,
+                complete,
+            });
+        }
+    }
+}
+
+impl<B> Body for RelayedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.size += data.remaining();
+                    let room = this.cap.saturating_sub(this.kept.len());
+                    this.kept.extend_from_slice(&data[..data.len().min(room)]);
+                }
+                if this.inner.is_end_stream() {
+                    this.end(true);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.end(false);
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                this.end(true);
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for RelayedBody<B> {
+    fn drop(&mut self) {
+        self.end(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use http_body_util::{BodyExt, StreamBody};
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+
+    fn chunks(parts: &'static [&'static [u8]]) -> impl Body<Data = Bytes, Error = Infallible> + Unpin {
+        StreamBody::new(
+            futures::stream::iter(parts.iter()).map(|part| Ok::<_, Infallible>(Frame::data(Bytes::from_static(part)))),
+        )
+    }
+
+    fn reported() -> (Arc<Mutex<Vec<Relayed>>>, impl FnOnce(Relayed) + Send + Sync + 'static) {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        (reports, move |relayed| sink.lock().unwrap().push(relayed))
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_first_bytes_and_counts_the_rest() {
+        let (reports, on_end) = reported();
+        let body = RelayedBody::new(chunks(&[b"0123", b"4567", b"89"]), 6, on_end);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "0123456789");
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(
+            *reports,
+            vec![Relayed {
+                kept: Bytes::from_static(b"012345"),
+                size: 10,
+                complete: true,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_body_reports_once_as_incomplete() {
+        let (reports, on_end) = reported();
+        let mut body = RelayedBody::new(chunks(&[b"0123", b"4567"]), 64, on_end);
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].kept, "0123");
+        assert!(!reports[0].complete);
+    }
+}
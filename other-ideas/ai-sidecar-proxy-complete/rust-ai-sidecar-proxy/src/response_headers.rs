@@ -0,0 +1,229 @@
+use crate::{egress::Direction, metrics::MetricsCollector};
+use anyhow::{bail, Context, Result};
+use hyper::{header::HeaderName, HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Mutex};
+
+// Framing and content description; they pass a strict allowlist and cannot
+// be denied.
+const ESSENTIAL: [&str; 7] = [
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "content-range",
+    "transfer-encoding",
+    "date",
+    "location",
+];
+
+// Distinct header names counted per listener before the rest share "other".
+const MAX_COUNTED_NAMES: usize = 64;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseHeadersConfig {
+    // Responses to the clients calling in.
+    pub ingress: ResponseHeaderPolicyConfig,
+    // Responses to the local application's outbound calls.
+    pub egress: ResponseHeaderPolicyConfig,
+}
+
+// Entries are header names, or prefixes ending in '*' such as "x-internal-*".
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseHeaderPolicyConfig {
+    pub deny: Vec<String>,
+    // When set, only these and the essential headers reach the client.
+    pub allow: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pattern {
+    Name(String),
+    Prefix(String),
+}
+
+impl Pattern {
+    fn parse(entry: &str) -> Result<Self> {
+        let lower = entry.to_ascii_lowercase();
+        let (pattern, name) = match lower.strip_suffix('*') {
+            Some(prefix) => (Pattern::Prefix(prefix.to_string()), prefix),
+            None => (Pattern::Name(lower.clone()), lower.as_str()),
+        };
+        if name.is_empty() {
+            bail!("empty header pattern {:?}", entry);
+        }
+        HeaderName::from_bytes(name.as_bytes()).with_context(|| format!("invalid header pattern {:?}", entry))?;
+        Ok(pattern)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Name(pattern) => name == pattern,
+            Pattern::Prefix(prefix) => name.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+struct ResponseHeaderPolicy {
+    deny: Vec<Pattern>,
+    allow: Option<Vec<Pattern>>,
+    // Names already given their own metric label.
+    counted: Mutex<HashSet<String>>,
+}
+
+impl ResponseHeaderPolicy {
+    fn compile(config: &ResponseHeaderPolicyConfig) -> Result<Self> {
+        let deny = config.deny.iter().map(|entry| Pattern::parse(entry)).collect::<Result<Vec<_>>>()?;
+        if let Some((pattern, essential)) = deny
+            .iter()
+            .find_map(|pattern| ESSENTIAL.iter().find(|name| pattern.matches(name)).map(|name| (pattern, name)))
+        {
+            bail!("deny pattern {:?} would strip the essential header {}", pattern, essential);
+        }
+        let allow = match &config.allow {
+            Some(allow) => Some(allow.iter().map(|entry| Pattern::parse(entry)).collect::<Result<Vec<_>>>()?),
+            None => None,
+        };
+        Ok(Self {
+            deny,
+            allow,
+            counted: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn is_noop(&self) -> bool {
+        self.deny.is_empty() && self.allow.is_none()
+    }
+
+    fn strips(&self, name: &str) -> bool {
+        if self.deny.iter().any(|pattern| pattern.matches(name)) {
+            return true;
+        }
+        match &self.allow {
+            Some(allow) => !ESSENTIAL.contains(&name) && !allow.iter().any(|pattern| pattern.matches(name)),
+            None => false,
+        }
+    }
+
+    fn label(&self, name: &str) -> String {
+        let mut counted = self.counted.lock().unwrap();
+        if counted.contains(name) || counted.len() < MAX_COUNTED_NAMES {
+            counted.insert(name.to_string());
+            name.to_string()
+        } else {
+            "other".to_string()
+        }
+    }
+}
+
+// Per-listener policies, validated once at startup.
+pub struct ResponseHeaderPolicies {
+    ingress: ResponseHeaderPolicy,
+    egress: ResponseHeaderPolicy,
+}
+
+impl ResponseHeaderPolicies {
+    pub fn from_config(config: &ResponseHeadersConfig) -> Result<Self> {
+        Ok(Self {
+            ingress: ResponseHeaderPolicy::compile(&config.ingress).context("response_headers.ingress")?,
+            egress: ResponseHeaderPolicy::compile(&config.egress).context("response_headers.egress")?,
+        })
+    }
+
+    // Removes what the listener's policy strips; returns how many headers went.
+    pub fn apply(&self, direction: Direction, headers: &mut HeaderMap, metrics: &MetricsCollector) -> usize {
+        let policy = match direction {
+            Direction::Ingress => &self.ingress,
+            Direction::Egress => &self.egress,
+        };
+        if policy.is_noop() {
+            return 0;
+        }
+        let stripped: Vec<HeaderName> = headers.keys().filter(|name| policy.strips(name.as_str())).cloned().collect();
+        for name in &stripped {
+            let count = headers.get_all(name).iter().count() as u64;
+            headers.remove(name);
+            metrics.record_response_headers_stripped(direction, &policy.label(name.as_str()), count);
+        }
+        stripped.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(names: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for name in names {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), "v".parse().unwrap());
+        }
+        headers
+    }
+
+    fn policies(ingress: ResponseHeaderPolicyConfig) -> ResponseHeaderPolicies {
+        ResponseHeaderPolicies::from_config(&ResponseHeadersConfig {
+            ingress,
+            egress: ResponseHeaderPolicyConfig::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_deny_strips_names_and_prefixes() {
+        let metrics = MetricsCollector::new();
+        let policies = policies(ResponseHeaderPolicyConfig {
+            deny: vec!["Server".to_string(), "x-internal-*".to_string()],
+            allow: None,
+        });
+        let mut map = headers(&["server", "x-internal-shard", "x-internal-shard", "x-request-id", "content-type"]);
+
+        assert_eq!(policies.apply(Direction::Ingress, &mut map, &metrics), 2);
+        assert_eq!(map.len(), 2);
+        assert!(map.contains_key("x-request-id") && map.contains_key("content-type"));
+        assert_eq!(metrics.response_headers_stripped_count(Direction::Ingress, "x-internal-shard"), 2);
+        assert_eq!(metrics.response_headers_stripped_count(Direction::Ingress, "server"), 1);
+    }
+
+    #[test]
+    fn test_allowlist_keeps_essential_headers() {
+        let metrics = MetricsCollector::new();
+        let policies = policies(ResponseHeaderPolicyConfig {
+            deny: vec![],
+            allow: Some(vec!["x-request-id".to_string(), "cache-*".to_string()]),
+        });
+        let mut map = headers(&["x-request-id", "cache-control", "content-length", "etag", "x-powered-by"]);
+
+        assert_eq!(policies.apply(Direction::Ingress, &mut map, &metrics), 2);
+        assert!(!map.contains_key("etag") && !map.contains_key("x-powered-by"));
+        assert!(map.contains_key("content-length"));
+    }
+
+    #[test]
+    fn test_egress_policy_is_separate() {
+        let metrics = MetricsCollector::new();
+        let policies = policies(ResponseHeaderPolicyConfig {
+            deny: vec!["x-internal-*".to_string()],
+            allow: None,
+        });
+        let mut map = headers(&["x-internal-shard"]);
+
+        assert_eq!(policies.apply(Direction::Egress, &mut map, &metrics), 0);
+        assert!(map.contains_key("x-internal-shard"));
+    }
+
+    #[test]
+    fn test_rejects_denying_essential_headers() {
+        for deny in ["content-*", "Content-Length", "bad header"] {
+            let config = ResponseHeadersConfig {
+                egress: ResponseHeaderPolicyConfig {
+                    deny: vec![deny.to_string()],
+                    allow: None,
+                },
+                ..ResponseHeadersConfig::default()
+            };
+            assert!(ResponseHeaderPolicies::from_config(&config).is_err(), "{}", deny);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{
+    ai::ServiceHealth,
+    circuit_breaker::{CircuitBreakerState, ProbeStats},
+    health_checker::{HealthStatus, HealthTier},
+    load_balancer::SelectionExplanation,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const REASON_BREAKER_OPEN: &str = "breaker_open";
+pub const REASON_PROBE_UNHEALTHY: &str = "probe_unhealthy";
+pub const REASON_STANDBY_PRIORITY: &str = "standby_priority";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidates {
+    pub endpoints: Vec<String>,
+    // Every endpoint failed its probe, so all of them are tried rather than none.
+    pub panic: bool,
+    pub breaker_open: bool,
+}
+
+// The one place that decides which endpoints selection may pick from. Used by
+// the data path and by /admin/health so the two cannot drift apart.
+pub fn candidates(
+    endpoints: &[String],
+    probes: &HashMap<String, HealthStatus>,
+    breaker: CircuitBreakerState,
+    priorities: Option<&HashMap<String, u32>>,
+) -> Candidates {
+    if breaker == CircuitBreakerState::Open {
+        return Candidates {
+            endpoints: Vec::new(),
+            panic: false,
+            breaker_open: true,
+        };
+    }
+
+    let healthy: Vec<&String> = endpoints
+        .iter()
+        .filter(|endpoint| probe_healthy(probes.get(*endpoint)))
+        .collect();
+    let tier = |endpoint: &String| priorities.and_then(|priorities| priorities.get(endpoint)).copied().unwrap_or(0);
+    let best = healthy.iter().map(|endpoint| tier(endpoint)).min();
+    let healthy: Vec<String> = healthy
+        .into_iter()
+        .filter(|endpoint| Some(tier(endpoint)) == best)
+        .cloned()
+        .collect();
+
+    if healthy.is_empty() && !endpoints.is_empty() {
+        return Candidates {
+            endpoints: endpoints.to_vec(),
+            panic: true,
+            breaker_open: false,
+        };
+    }
+
+    Candidates {
+        endpoints: healthy,
+        panic: false,
+        breaker_open: false,
+    }
+}
+
+// Endpoints that have not been probed yet are given the benefit of the doubt.
+// Degraded ones stay in; selection scores them lower.
+fn probe_healthy(probe: Option<&HealthStatus>) -> bool {
+    probe.is_none_or(|status| status.tier.is_routable())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeView {
+    pub tier: HealthTier,
+    pub last_check: u64,
+    pub response_time_ms: u64,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointView {
+    pub endpoint: String,
+    pub probe: Option<ProbeView>,
+    pub passive: Option<ServiceHealth>,
+    pub score: f64,
+    pub in_flight: usize,
+    pub routable: bool,
+    pub reasons: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceView {
+    pub service: String,
+    pub breaker: &'static str,
+    // Probe mode and half-open probe outcomes so far.
+    pub breaker_probes: Option<ProbeStats>,
+    pub panic_routing: bool,
+    pub endpoints: Vec<EndpointView>,
+}
+
+// Point-in-time copies of every source, each taken under a single lock.
+pub struct Snapshot<'a> {
+    pub probes: &'a HashMap<String, HealthStatus>,
+    pub passive: &'a HashMap<String, ServiceHealth>,
+    pub load: &'a SelectionExplanation,
+    pub breaker: CircuitBreakerState,
+    pub breaker_probes: Option<ProbeStats>,
+    // The service's configured priority tiers, if it has any.
+    pub priorities: Option<&'a HashMap<String, u32>>,
+}
+
+impl ServiceView {
+    pub fn build(
+        service: &str,
+        endpoints: &[String],
+        snapshot: &Snapshot<'_>,
+        score: impl Fn(&str, Option<&ServiceHealth>) -> f64,
+    ) -> Self {
+        let candidates = candidates(endpoints, snapshot.probes, snapshot.breaker, snapshot.priorities);
+
+        let endpoints = endpoints
+            .iter()
+            .map(|endpoint| {
+                let probe = snapshot.probes.get(endpoint);
+                let passive = snapshot.passive.get(endpoint);
+
+                let mut reasons = Vec::new();
+                if candidates.breaker_open {
+                    reasons.push(REASON_BREAKER_OPEN);
+                }
+                if !probe_healthy(probe) {
+                    reasons.push(REASON_PROBE_UNHEALTHY);
+                } else if !candidates.breaker_open && !candidates.endpoints.contains(endpoint) {
+                    reasons.push(REASON_STANDBY_PRIORITY);
+                }
+
+                EndpointView {
+                    endpoint: endpoint.clone(),
+                    probe: probe.map(|status| ProbeView {
+                        tier: status.tier,
+                        last_check: status.last_check,
+                        response_time_ms: status.response_time_ms,
+                        consecutive_failures: status.consecutive_failures,
+                        consecutive_successes: status.consecutive_successes,
+                        error: status.probe_error.clone(),
+                    }),
+                    passive: passive.cloned(),
+                    score: score(endpoint, passive),
+                    in_flight: snapshot
+                        .load
+                        .endpoints
+                        .iter()
+                        .find(|load| &load.endpoint == endpoint)
+                        .map_or(0, |load| load.active_requests),
+                    routable: candidates.endpoints.contains(endpoint),
+                    reasons,
+                }
+            })
+            .collect();
+
+        Self {
+            service: service.to_string(),
+            breaker: match snapshot.breaker {
+                CircuitBreakerState::Closed => "closed",
+                CircuitBreakerState::Open => "open",
+                CircuitBreakerState::HalfOpen => "half_open",
+            },
+            breaker_probes: snapshot.breaker_probes.clone(),
+            panic_routing: candidates.panic,
+            endpoints,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(endpoint: &str, healthy: bool) -> (String, HealthStatus) {
+        (
+            endpoint.to_string(),
+            HealthStatus {
+                endpoint: endpoint.to_string(),
+                tier: if healthy { HealthTier::Healthy } else { HealthTier::Unhealthy },
+                last_check: 1,
+                response_time_ms: 3,
+                consecutive_failures: if healthy { 0 } else { 2 },
+                consecutive_successes: if healthy { 2 } else { 0 },
+                probe_error: None,
+            },
+        )
+    }
+
+    fn load(service: &str) -> SelectionExplanation {
+        SelectionExplanation {
+            service: service.to_string(),
+            strategy: "round_robin",
+            endpoints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_open_breaker_overrides_healthy_probe() {
+        let endpoints = vec!["http://a".to_string()];
+        let probes = HashMap::from([probe("http://a", true)]);
+        let passive = HashMap::new();
+        let load = load("svc");
+
+        let view = ServiceView::build(
+            "svc",
+            &endpoints,
+            &Snapshot {
+                probes: &probes,
+                passive: &passive,
+                load: &load,
+                breaker: CircuitBreakerState::Open,
+                breaker_probes: None,
+                priorities: None,
+            },
+            |_, _| 0.5,
+        );
+
+        let endpoint = &view.endpoints[0];
+        assert_eq!(endpoint.probe.as_ref().unwrap().tier, HealthTier::Healthy);
+        assert!(!endpoint.routable);
+        assert_eq!(endpoint.reasons, vec![REASON_BREAKER_OPEN]);
+        assert_eq!(view.breaker, "open");
+    }
+
+    #[test]
+    fn test_unhealthy_probes_excluded_until_all_fail() {
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string()];
+
+        let probes = HashMap::from([probe("http://a", false), probe("http://b", true)]);
+        let picked = candidates(&endpoints, &probes, CircuitBreakerState::Closed, None);
+        assert_eq!(picked.endpoints, vec!["http://b".to_string()]);
+        assert!(!picked.panic);
+
+        let probes = HashMap::from([probe("http://a", false), probe("http://b", false)]);
+        let picked = candidates(&endpoints, &probes, CircuitBreakerState::HalfOpen, None);
+        assert_eq!(picked.endpoints, endpoints);
+        assert!(picked.panic);
+    }
+
+    #[test]
+    fn test_standby_tier_used_only_when_primary_fails() {
+        let endpoints = vec!["http://a".to_string(), "http://b".to_string(), "http://c".to_string()];
+        let priorities = HashMap::from([("http://c".to_string(), 1)]);
+
+        let probes = HashMap::from([probe("http://a", false)]);
+        let picked = candidates(&endpoints, &probes, CircuitBreakerState::Closed, Some(&priorities));
+        assert_eq!(picked.endpoints, vec!["http://b".to_string()]);
+
+        let probes = HashMap::from([probe("http://a", false), probe("http://b", false)]);
+        let picked = candidates(&endpoints, &probes, CircuitBreakerState::Closed, Some(&priorities));
+        assert_eq!(picked.endpoints, vec!["http://c".to_string()]);
+        assert!(!picked.panic);
+
+        let passive = HashMap::new();
+        let load = load("svc");
+        let view = ServiceView::build(
+            "svc",
+            &endpoints,
+            &Snapshot {
+                probes: &HashMap::new(),
+                passive: &passive,
+                load: &load,
+                breaker: CircuitBreakerState::Closed,
+                breaker_probes: None,
+                priorities: Some(&priorities),
+            },
+            |_, _| 0.5,
+        );
+        assert!(view.endpoints[0].routable);
+        assert!(!view.endpoints[2].routable);
+        assert_eq!(view.endpoints[2].reasons, vec![REASON_STANDBY_PRIORITY]);
+    }
+}
@@ -0,0 +1,817 @@
+use crate::path_templates::{CompiledTemplate, PathTemplate};
+use hyper::Uri;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+// Compiled size allowed for a route's regex. The regex crate never
+// backtracks, so this is what keeps a pattern from costing unbounded memory
+// and time per request.
+const REGEX_SIZE_LIMIT: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    // Only requests for this host, compared without case or port. Tried
+    // ahead of rules for any host of the same priority.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // Matches the path itself and anything below it: `/orders` takes
+    // `/orders` and `/orders/7`, not `/orders-archive`.
+    #[serde(default)]
+    pub path_prefix: String,
+    // Matches the whole path instead; set this or `path_prefix`.
+    #[serde(default)]
+    pub path_pattern: Option<PathPattern>,
+    // Higher goes first. Among rules of equal priority, patterns are tried
+    // in file order, then prefixes longest first.
+    #[serde(default)]
+    pub priority: i32,
+    pub service: String,
+    // Forward `/orders/7` as `/7`. Only for prefixes.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    // Rewrites the path sent upstream; the query is passed on as it came.
+    // Not combined with `strip_prefix`.
+    #[serde(default)]
+    pub rewrite: Option<PathRewrite>,
+    // Names each request's operation for metrics and the access log.
+    #[serde(default)]
+    pub path_template: Option<PathTemplate>,
+}
+
+// `pattern` is a regex over the client's path; the first match is replaced
+// with `replacement`, which can refer to captures as `$1` or `${name}`. A
+// path it does not match is forwarded unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathRewrite {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+impl PathRewrite {
+    pub fn compile(&self) -> Result<Regex, regex::Error> {
+        bounded(&self.pattern)
+    }
+}
+
+// `wildcard` is a path where `*` stands for one segment, or part of one, and
+// `**` for the rest of the path: `/api/*/export` takes `/api/7/export` only,
+// `/reports/**` everything below `/reports/`. `regex` must match the whole
+// path, as if written between `^` and `$`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PathPattern {
+    Wildcard(String),
+    Regex(String),
+}
+
+impl PathPattern {
+    pub fn source(&self) -> &str {
+        match self {
+            PathPattern::Wildcard(source) | PathPattern::Regex(source) => source,
+        }
+    }
+
+    pub fn compile(&self) -> Result<Regex, regex::Error> {
+        match self {
+            PathPattern::Wildcard(wildcard) => {
+                let mut pattern = String::from("^");
+                let mut rest = wildcard.as_str();
+                while let Some(star) = rest.find('*') {
+                    pattern.push_str(&regex::escape(&rest[..star]));
+                    rest = &rest[star..];
+                    if let Some(after) = rest.strip_prefix("**") {
+                        pattern.push_str(".*");
+                        rest = after;
+                    } else {
+                        pattern.push_str("[^/]*");
+                        rest = &rest[1..];
+                    }
+                }
+                pattern.push_str(&regex::escape(rest));
+                pattern.push('$');
+                bounded(&pattern)
+            }
+            PathPattern::Regex(regex) => bounded(&format!("^(?:{})$", regex)),
+        }
+    }
+}
+
+pub(crate) fn bounded(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).size_limit(REGEX_SIZE_LIMIT).build()
+}
+
+impl RouteRule {
+    // A rule for the paths `pattern` matches whole, checked here rather than
+    // when the routes are built.
+    pub fn regex(pattern: &str, service_name: &str) -> Result<RouteRule, regex::Error> {
+        let pattern = PathPattern::Regex(pattern.to_string());
+        pattern.compile()?;
+        Ok(RouteRule {
+            path_pattern: Some(pattern),
+            service: service_name.to_string(),
+            ..RouteRule::default()
+        })
+    }
+
+    // Names the route: its prefix or its pattern as written.
+    pub fn name(&self) -> &str {
+        match &self.path_pattern {
+            Some(pattern) => pattern.source(),
+            None => &self.path_prefix,
+        }
+    }
+}
+
+// Compiled once when the config is loaded, in the order they are tried, so a
+// request is matched by at most one rule and always the same one.
+#[derive(Debug, Default)]
+pub struct Routes {
+    rules: Vec<CompiledRule>,
+    default_service: Option<String>,
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    rule: RouteRule,
+    pattern: Option<Regex>,
+    rewrite: Option<Regex>,
+    template: Option<CompiledTemplate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMatch {
+    // Names the route in metrics, experiments and per-route policies.
+    pub route: String,
+    pub service: String,
+    // Set when the rule strips its prefix: the path and query to send
+    // upstream instead of the client's.
+    pub upstream_path: Option<String>,
+    // The client's path as its rule's template has it, when there is one.
+    pub operation: Option<String>,
+}
+
+// Rules sort by this, lowest first.
+fn standing(rule: &RouteRule) -> (std::cmp::Reverse<i32>, bool, bool, std::cmp::Reverse<usize>) {
+    let prefix = rule.path_prefix.trim_end_matches('/').len();
+    (
+        std::cmp::Reverse(rule.priority),
+        rule.host.is_none(),
+        rule.path_pattern.is_none(),
+        std::cmp::Reverse(prefix),
+    )
+}
+
+impl Routes {
+    pub fn new(rules: &[RouteRule]) -> Result<Self, regex::Error> {
+        let mut rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(CompiledRule {
+                    rule: rule.clone(),
+                    pattern: rule.path_pattern.as_ref().map(PathPattern::compile).transpose()?,
+                    rewrite: rule.rewrite.as_ref().map(PathRewrite::compile).transpose()?,
+                    template: rule.path_template.as_ref().map(PathTemplate::compile).transpose().map_err(|(_, e)| e)?,
+                })
+            })
+            .collect::<Result<Vec<_>, regex::Error>>()?;
+        // Stable, so patterns of equal priority keep their file order.
+        rules.sort_by_key(|compiled| standing(&compiled.rule));
+        Ok(Self { rules, default_service: None })
+    }
+
+    // Where paths nothing else matches go, as the route named "default".
+    pub fn with_default_service(mut self, service: Option<String>) -> Self {
+        self.default_service = service;
+        self
+    }
+
+    // In the order they are tried.
+    pub fn rules(&self) -> impl Iterator<Item = &RouteRule> {
+        self.rules.iter().map(|compiled| &compiled.rule)
+    }
+
+    pub fn default_service(&self) -> Option<&str> {
+        self.default_service.as_deref()
+    }
+
+    // The first rule here that `rule` would be tried ahead of once added, if
+    // any.
+    pub fn tried_after(&self, rule: &RouteRule) -> Option<&RouteRule> {
+        let added = standing(rule);
+        self.rules().find(|existing| added < standing(existing))
+    }
+
+    // These routes with `rule` tried after any of equal standing.
+    pub fn with_rule(&self, rule: RouteRule) -> Result<Self, regex::Error> {
+        let rules: Vec<RouteRule> = self.rules().cloned().chain([rule]).collect();
+        Ok(Self::new(&rules)?.with_default_service(self.default_service.clone()))
+    }
+
+    // Configured rules first; then `/api/<x>` goes to `service-<x>`, as it
+    // did before routes were configurable; then the default service. None
+    // when none applies. `host` is the request's Host, port and all.
+    pub fn resolve(&self, host: Option<&str>, uri: &Uri) -> Option<RouteMatch> {
+        let path = uri.path();
+        let host = host.map(without_port);
+        let matched = self.rules.iter().find_map(|compiled| {
+            if let Some(wanted) = &compiled.rule.host {
+                if !host.is_some_and(|host| host.eq_ignore_ascii_case(wanted)) {
+                    return None;
+                }
+            }
+            let rest = match &compiled.pattern {
+                Some(pattern) => pattern.is_match(path).then_some("")?,
+                None => under(path, &compiled.rule.path_prefix)?,
+            };
+            Some((&compiled.rule, &compiled.rewrite, &compiled.template, rest))
+        });
+        if let Some((rule, compiled, template, rest)) = matched {
+            let upstream_path = match rule.rewrite.as_ref().zip(compiled.as_ref()) {
+                Some((rewrite, regex)) => regex
+                    .is_match(path)
+                    .then(|| regex.replace(path, rewrite.replacement.as_str()).into_owned()),
+                None => rule.strip_prefix.then(|| rest.to_string()),
+            };
+            let upstream_path = upstream_path.map(|path| {
+                let path = if path.starts_with('/') { path } else { format!("/{}", path) };
+                match uri.query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                }
+            });
+            return Some(RouteMatch {
+                route: rule.name().to_string(),
+                service: rule.service.clone(),
+                upstream_path,
+                operation: template.as_ref().map(|template| template.operation(path)),
+            });
+        }
+
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match parts[..] {
+            ["api", name, ..] if !name.is_empty() => Some(RouteMatch {
+                route: format!("/api/{}", name),
+                service: format!("service-{}", name),
+                upstream_path: None,
+                operation: None,
+            }),
+            _ => self.default_service.as_ref().map(|service| RouteMatch {
+                route: "default".to_string(),
+                service: service.clone(),
+                upstream_path: None,
+                operation: None,
+            }),
+        }
+    }
+}
+
+// How the conflicts `conflicts` finds are reported when a config is
+// validated. A rule that catches every path, and patterns that only may
+// overlap, are only ever warnings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrictness {
+    #[default]
+    Error,
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    // Matches what an earlier rule in the file matches, for the same host.
+    Duplicate,
+    // Every request it matches is taken by a rule tried ahead of it.
+    Shadowed,
+    // A pattern that may match some of the same paths as another of the
+    // same standing; whichever the file lists first wins. Patterns are told
+    // apart only by their literal text, so this is a warning.
+    Ambiguous,
+    // Matches every path on every host, so `/api/<x>` and the default
+    // service are never reached.
+    CatchAll,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteConflict {
+    pub kind: ConflictKind,
+    // Index of the rule reported, in the rules given to `conflicts`.
+    pub rule: usize,
+    pub message: String,
+}
+
+// Rules that sort into the same place in any order, or that can never
+// match, by index. Rules whose matcher is malformed are left to the
+// config's own checks. Only what can be told without running a regex is
+// found: a regex is judged by the literal text it starts with.
+pub fn conflicts(rules: &[RouteRule]) -> Vec<RouteConflict> {
+    let checked: Vec<usize> = (0..rules.len()).filter(|&index| well_formed(&rules[index])).collect();
+    let mut order = checked.clone();
+    // Stable, as in `Routes::new`.
+    order.sort_by_key(|&index| standing(&rules[index]));
+
+    let mut found = Vec::new();
+    for (position, &index) in order.iter().enumerate() {
+        let rule = &rules[index];
+        let report = |kind, message| RouteConflict { kind, rule: index, message };
+        if let Some(&other) = checked.iter().take_while(|&&other| other < index).find(|&&other| same_matcher(&rules[other], rule)) {
+            found.push(report(ConflictKind::Duplicate, format!("{} is already routed by routes[{}]", describe(rule), other)));
+            continue;
+        }
+        // A duplicate of this rule is reported on whichever of the two comes
+        // later in the file.
+        let mut ahead = order[..position].iter().copied().filter(|&other| !same_matcher(&rules[other], rule));
+        if let Some(other) = ahead.clone().find(|&other| covers(&rules[other], rule)) {
+            found.push(report(
+                ConflictKind::Shadowed,
+                format!(
+                    "{} is never reached: routes[{}], {}, is tried first and takes every request it matches",
+                    describe(rule),
+                    other,
+                    describe(&rules[other])
+                ),
+            ));
+        } else if let Some(other) = ahead.find(|&other| ambiguous(&rules[other], rule)) {
+            found.push(report(
+                ConflictKind::Ambiguous,
+                format!(
+                    "{} may match the same paths as routes[{}], {}, at the same priority; which one is used depends on their order in the file",
+                    describe(rule),
+                    other,
+                    describe(&rules[other])
+                ),
+            ));
+        } else if rule.host.is_none() && reach(rule).is_everything() {
+            found.push(report(
+                ConflictKind::CatchAll,
+                format!("{} matches every path, so /api/<name> routing and default_service never apply", describe(rule)),
+            ));
+        }
+    }
+    found.sort_by_key(|conflict| conflict.rule);
+    found
+}
+
+fn well_formed(rule: &RouteRule) -> bool {
+    match &rule.path_pattern {
+        Some(pattern) => {
+            rule.path_prefix.is_empty()
+                && !matches!(pattern, PathPattern::Wildcard(wildcard) if !wildcard.starts_with('/'))
+                && pattern.compile().is_ok()
+        }
+        None => rule.path_prefix.starts_with('/'),
+    }
+}
+
+fn describe(rule: &RouteRule) -> String {
+    match &rule.host {
+        Some(host) => format!("{:?} on {}", rule.name(), host),
+        None => format!("{:?}", rule.name()),
+    }
+}
+
+fn same_host(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+fn same_matcher(a: &RouteRule, b: &RouteRule) -> bool {
+    same_host(&a.host, &b.host)
+        && match (&a.path_pattern, &b.path_pattern) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => a.path_prefix.trim_end_matches('/') == b.path_prefix.trim_end_matches('/'),
+            _ => false,
+        }
+}
+
+// Whether `a` matches every request `b` does.
+fn covers(a: &RouteRule, b: &RouteRule) -> bool {
+    let hosts = match (&a.host, &b.host) {
+        (None, _) => true,
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (Some(_), None) => false,
+    };
+    hosts && reach(a).covers(&reach(b))
+}
+
+fn ambiguous(a: &RouteRule, b: &RouteRule) -> bool {
+    let hosts = a.host.is_none() || b.host.is_none() || same_host(&a.host, &b.host);
+    let paths = match (&a.path_pattern, &b.path_pattern) {
+        (Some(PathPattern::Wildcard(a)), Some(PathPattern::Wildcard(b))) => wildcards_overlap(a, b),
+        (Some(_), Some(_)) => reach(a).overlaps(&reach(b)),
+        _ => false,
+    };
+    standing(a) == standing(b) && hosts && paths
+}
+
+// False only when no path can match both. Segments are compared pairwise up
+// to the first `**`, which stands for any number of them.
+fn wildcards_overlap(a: &str, b: &str) -> bool {
+    let (a, b): (Vec<&str>, Vec<&str>) = (a.split('/').collect(), b.split('/').collect());
+    for (a, b) in a.iter().zip(&b) {
+        if a.contains("**") || b.contains("**") {
+            return true;
+        }
+        if !segments_overlap(a, b) {
+            return false;
+        }
+    }
+    a.len() == b.len()
+}
+
+// Two segments whose `*`s match within the segment; they overlap unless the
+// text before their first `*` or after their last one cannot agree.
+fn segments_overlap(a: &str, b: &str) -> bool {
+    let (a_head, a_tail) = (&a[..a.find('*').unwrap_or(a.len())], &a[a.rfind('*').map_or(0, |star| star + 1)..]);
+    let (b_head, b_tail) = (&b[..b.find('*').unwrap_or(b.len())], &b[b.rfind('*').map_or(0, |star| star + 1)..]);
+    if !a.contains('*') && !b.contains('*') {
+        return a == b;
+    }
+    (a_head.starts_with(b_head) || b_head.starts_with(a_head)) && (a_tail.ends_with(b_tail) || b_tail.ends_with(a_tail))
+}
+
+// The paths a rule matches, as far as can be told without running it.
+enum Reach<'a> {
+    // A path and everything below it; "" for every path.
+    Under(&'a str),
+    // Every path that starts with this.
+    Starting(String),
+    // Some paths that start with this.
+    Within(String),
+    // Some paths that start with this, in any case.
+    WithinAnyCase(String),
+}
+
+fn reach(rule: &RouteRule) -> Reach<'_> {
+    match &rule.path_pattern {
+        None => Reach::Under(rule.path_prefix.trim_end_matches('/')),
+        Some(PathPattern::Wildcard(wildcard)) => {
+            let head = &wildcard[..wildcard.find('*').unwrap_or(wildcard.len())];
+            if &wildcard[head.len()..] == "**" {
+                Reach::Starting(head.to_string())
+            } else {
+                Reach::Within(head.to_string())
+            }
+        }
+        Some(PathPattern::Regex(regex)) => {
+            // Anchors are implied, so a leading one changes nothing.
+            let regex = regex.strip_prefix('^').unwrap_or(regex);
+            if let Some(rest) = regex.strip_prefix("(?i)") {
+                return Reach::WithinAnyCase(literal_head(rest.strip_prefix('^').unwrap_or(rest)).0);
+            }
+            let (head, rest) = literal_head(regex);
+            if rest == ".*" {
+                Reach::Starting(head)
+            } else {
+                Reach::Within(head)
+            }
+        }
+    }
+}
+
+impl Reach<'_> {
+    fn head(&self) -> &str {
+        match self {
+            Reach::Under(prefix) => prefix,
+            Reach::Starting(head) | Reach::Within(head) | Reach::WithinAnyCase(head) => head,
+        }
+    }
+
+    fn is_everything(&self) -> bool {
+        match self {
+            Reach::Under(prefix) => prefix.is_empty(),
+            Reach::Starting(head) => head.is_empty() || head == "/",
+            Reach::Within(_) | Reach::WithinAnyCase(_) => false,
+        }
+    }
+
+    fn covers(&self, other: &Reach) -> bool {
+        match (self, other) {
+            (_, Reach::WithinAnyCase(_)) => self.is_everything(),
+            (Reach::Under(prefix), Reach::Under(other)) => under(other, prefix).is_some(),
+            (Reach::Under(prefix), other) => prefix.is_empty() || other.head().starts_with(&format!("{}/", prefix)),
+            (Reach::Starting(head), Reach::Under(other)) => {
+                // A prefix of "/" still matches "/" itself.
+                let first = if other.is_empty() { "/" } else { other };
+                first.starts_with(head.as_str())
+            }
+            (Reach::Starting(head), other) => other.head().starts_with(head.as_str()),
+            (Reach::Within(_) | Reach::WithinAnyCase(_), _) => false,
+        }
+    }
+
+    fn overlaps(&self, other: &Reach) -> bool {
+        let (mut a, mut b) = (self.head().to_string(), other.head().to_string());
+        if matches!(self, Reach::WithinAnyCase(_)) || matches!(other, Reach::WithinAnyCase(_)) {
+            a.make_ascii_lowercase();
+            b.make_ascii_lowercase();
+        }
+        a.starts_with(&b) || b.starts_with(&a)
+    }
+}
+
+// The literal text every match of `regex` starts with, and the rest of the
+// regex after it. Alternation anywhere means no literal start at all.
+fn literal_head(regex: &str) -> (String, &str) {
+    let mut head = String::new();
+    if regex.contains('|') {
+        return (head, regex);
+    }
+    let mut chars = regex.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(&(_, escaped)) if !escaped.is_ascii_alphanumeric() => {
+                    head.push(escaped);
+                    chars.next();
+                }
+                _ => return (head, &regex[at..]),
+            },
+            // The character before may not be there at all.
+            '?' | '*' | '{' => {
+                let last = head.pop().map_or(0, char::len_utf8);
+                return (head, &regex[at - last..]);
+            }
+            '.' | '^' | '$' | '(' | ')' | '[' | ']' | '}' | '+' => return (head, &regex[at..]),
+            c => head.push(c),
+        }
+    }
+    (head, "")
+}
+
+fn without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+// What follows `prefix` in `path`, when `prefix` ends on a segment boundary.
+fn under<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let trimmed = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(trimmed)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path_prefix: &str, service: &str, strip_prefix: bool) -> RouteRule {
+        RouteRule {
+            path_prefix: path_prefix.to_string(),
+            service: service.to_string(),
+            strip_prefix,
+            ..RouteRule::default()
+        }
+    }
+
+    fn pattern(pattern: PathPattern, service: &str, priority: i32) -> RouteRule {
+        RouteRule {
+            path_pattern: Some(pattern),
+            service: service.to_string(),
+            priority,
+            ..RouteRule::default()
+        }
+    }
+
+    fn rewriting(path_prefix: &str, pattern: &str, replacement: &str) -> RouteRule {
+        RouteRule {
+            rewrite: Some(PathRewrite {
+                pattern: pattern.to_string(),
+                replacement: replacement.to_string(),
+            }),
+            ..rule(path_prefix, "svc", false)
+        }
+    }
+
+    fn resolve(routes: &Routes, uri: &str) -> Option<(String, String, Option<String>)> {
+        routes
+            .resolve(None, &uri.parse().unwrap())
+            .map(|matched| (matched.route, matched.service, matched.upstream_path))
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let routes = Routes::new(&[rule("/api", "legacy", false), rule("/api/v2", "v2", false), rule("/orders", "orders", false)]).unwrap();
+
+        assert_eq!(resolve(&routes, "/api/v2/items").unwrap().1, "v2");
+        assert_eq!(resolve(&routes, "/api/v2").unwrap().1, "v2");
+        assert_eq!(resolve(&routes, "/api/v20").unwrap().1, "legacy");
+        assert_eq!(resolve(&routes, "/api/a/items").unwrap().1, "legacy");
+        assert_eq!(resolve(&routes, "/orders/7").unwrap(), ("/orders".to_string(), "orders".to_string(), None));
+        assert_eq!(resolve(&routes, "/orders-archive"), None);
+    }
+
+    #[test]
+    fn test_default_service_takes_only_what_nothing_else_does() {
+        let routes = Routes::new(&[rule("/orders", "orders", false)])
+            .unwrap()
+            .with_default_service(Some("web".to_string()));
+
+        assert_eq!(resolve(&routes, "/orders/7").unwrap().1, "orders");
+        assert_eq!(resolve(&routes, "/api/a/items").unwrap().1, "service-a");
+        for path in ["/", "/favicon.ico", "/api", "/orders-archive"] {
+            assert_eq!(resolve(&routes, path).unwrap(), ("default".to_string(), "web".to_string(), None), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_added_regex_rule_keeps_the_table_in_order() {
+        assert!(RouteRule::regex("/reports/(", "reports").is_err());
+
+        let routes = Routes::new(&[rule("/reports", "legacy", false)])
+            .unwrap()
+            .with_default_service(Some("web".to_string()));
+        let routes = routes.with_rule(RouteRule::regex(r"/reports/\d+", "reports").unwrap()).unwrap();
+
+        assert_eq!(resolve(&routes, "/reports/42").unwrap().1, "reports");
+        assert_eq!(resolve(&routes, "/reports/weekly").unwrap().1, "legacy");
+        assert_eq!(resolve(&routes, "/elsewhere").unwrap().1, "web");
+        let names: Vec<&str> = routes.rules().map(RouteRule::name).collect();
+        assert_eq!(names, vec![r"/reports/\d+", "/reports"]);
+    }
+
+    #[test]
+    fn test_tried_after_names_the_rule_an_addition_would_overtake() {
+        let routes = Routes::new(&[rule("/reports", "legacy", false), rule("/", "web", false)]).unwrap();
+
+        assert_eq!(routes.tried_after(&rule("/orders", "orders", false)).unwrap().service, "web");
+        assert_eq!(routes.tried_after(&RouteRule::regex("/x", "x").unwrap()).unwrap().service, "legacy");
+        assert_eq!(routes.tried_after(&rule("/", "other", false)), None);
+        assert_eq!(routes.tried_after(&rule("/reports/weekly", "weekly", false)).unwrap().service, "legacy");
+    }
+
+    #[test]
+    fn test_falls_back_to_api_services() {
+        let routes = Routes::default();
+        assert_eq!(resolve(&routes, "/api/b/items").unwrap(), ("/api/b".to_string(), "service-b".to_string(), None));
+        assert_eq!(resolve(&routes, "/api"), None);
+        assert_eq!(resolve(&routes, "/api/"), None);
+        assert_eq!(resolve(&routes, "/"), None);
+        assert_eq!(resolve(&routes, "/items"), None);
+    }
+
+    #[test]
+    fn test_strip_prefix_keeps_query() {
+        let routes = Routes::new(&[rule("/orders/", "orders", true)]).unwrap();
+        assert_eq!(resolve(&routes, "/orders/7?expand=lines").unwrap().2.as_deref(), Some("/7?expand=lines"));
+        assert_eq!(resolve(&routes, "/orders").unwrap().2.as_deref(), Some("/"));
+        assert_eq!(resolve(&routes, "/orders?page=2").unwrap().2.as_deref(), Some("/?page=2"));
+    }
+
+    #[test]
+    fn test_rewrite_uses_captures_and_keeps_query() {
+        let routes = Routes::new(&[
+            rewriting("/api", "^/api/(.*)", "/v2/$1"),
+            rewriting("/legacy", "^/legacy/(?P<rest>.*)$", "/${rest}/"),
+        ])
+        .unwrap();
+
+        let upstream_path = |uri| resolve(&routes, uri).unwrap().2;
+        assert_eq!(upstream_path("/api/users/123").as_deref(), Some("/v2/users/123"));
+        assert_eq!(upstream_path("/api/users?page=2&sort=a%20b").as_deref(), Some("/v2/users?page=2&sort=a%20b"));
+        assert_eq!(upstream_path("/legacy/items").as_deref(), Some("/items/"));
+        // The rule matches `/api` but the pattern does not: passed through.
+        assert_eq!(upstream_path("/api?page=2"), None);
+    }
+
+    #[test]
+    fn test_rewrite_to_nothing_is_root() {
+        let routes = Routes::new(&[rewriting("/static", "^/static", ""), rewriting("/bare", "^/bare/", "")]).unwrap();
+        assert_eq!(resolve(&routes, "/static?v=1").unwrap().2.as_deref(), Some("/?v=1"));
+        assert_eq!(resolve(&routes, "/bare/x").unwrap().2.as_deref(), Some("/x"));
+    }
+
+    #[test]
+    fn test_wildcards_match_segments_and_remainders() {
+        let routes = Routes::new(&[
+            pattern(PathPattern::Wildcard("/api/*/export".to_string()), "reporting", 0),
+            pattern(PathPattern::Wildcard("/files/**".to_string()), "files", 0),
+            pattern(PathPattern::Wildcard("/v1.*/items".to_string()), "items", 0),
+        ])
+        .unwrap();
+
+        assert_eq!(resolve(&routes, "/api/orders/export?day=1").unwrap(), ("/api/*/export".to_string(), "reporting".to_string(), None));
+        assert_eq!(resolve(&routes, "/api/orders/7/export").unwrap().1, "service-orders");
+        assert_eq!(resolve(&routes, "/files/a/b/c").unwrap().1, "files");
+        assert_eq!(resolve(&routes, "/files"), None);
+        // The dot is literal.
+        assert_eq!(resolve(&routes, "/v1.2/items").unwrap().1, "items");
+        assert_eq!(resolve(&routes, "/v1x2/items"), None);
+    }
+
+    #[test]
+    fn test_overlapping_patterns_and_prefixes_resolve_in_one_order() {
+        let rules = [
+            rule("/api", "legacy", false),
+            rule("/api/orders", "orders", false),
+            pattern(PathPattern::Wildcard("/api/*/export".to_string()), "reporting", 0),
+            pattern(PathPattern::Regex("/api/orders/[0-9]+/export".to_string()), "order-export", 0),
+            pattern(PathPattern::Regex("/.*".to_string()), "catch-all", -1),
+            pattern(PathPattern::Wildcard("/api/admin/**".to_string()), "admin", 10),
+        ];
+        let routes = Routes::new(&rules).unwrap();
+        let service = |uri| resolve(&routes, uri).unwrap().1;
+
+        // Patterns go before prefixes of the same priority.
+        assert_eq!(service("/api/orders/export"), "reporting");
+        assert_eq!(service("/api/orders/7/export"), "order-export");
+        assert_eq!(service("/api/orders/7"), "orders");
+        assert_eq!(service("/api/users"), "legacy");
+        assert_eq!(service("/api/admin/export"), "admin");
+        // Below every prefix; only what they miss reaches it.
+        assert_eq!(service("/other/path"), "catch-all");
+
+        // The same rules listed the other way round resolve the same way.
+        let reversed: Vec<RouteRule> = rules.iter().rev().cloned().collect();
+        let reversed = Routes::new(&reversed).unwrap();
+        for uri in ["/api/orders/export", "/api/orders/7/export", "/api/orders/7", "/api/users", "/api/admin/export", "/other/path"] {
+            assert_eq!(resolve(&reversed, uri).unwrap().1, service(uri), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn test_regex_must_match_the_whole_path_and_stay_small() {
+        let routes = Routes::new(&[pattern(PathPattern::Regex("/health|/ready".to_string()), "probe", 0)]).unwrap();
+        assert_eq!(resolve(&routes, "/ready").unwrap().1, "probe");
+        assert_eq!(resolve(&routes, "/health/deep"), None);
+
+        assert!(PathPattern::Regex("(a{1000}){1000}".to_string()).compile().is_err());
+        assert!(PathPattern::Regex("/(x+x+)+y".to_string()).compile().is_ok());
+    }
+
+    #[test]
+    fn test_malformed_rewrite_is_an_error() {
+        assert!(Routes::new(&[rewriting("/api", "^/api/(.*", "/v2/$1")]).is_err());
+    }
+
+    #[test]
+    fn test_host_rules_match_their_host_only() {
+        let routes = Routes::new(&[
+            RouteRule { host: Some("shop.example".to_string()), ..rule("/cart", "shop", false) },
+            rule("/cart", "cart", false),
+        ])
+        .unwrap();
+        let service = |host| routes.resolve(host, &"/cart/1".parse().unwrap()).unwrap().service;
+
+        assert_eq!(service(Some("SHOP.example:8080")), "shop");
+        assert_eq!(service(Some("other.example")), "cart");
+        assert_eq!(service(None), "cart");
+    }
+
+    #[test]
+    fn test_conflicts_found_by_kind() {
+        let rules: Vec<RouteRule> = serde_json::from_value(serde_json::json!([
+            {"path_prefix": "/files", "service": "a"},
+            {"path_prefix": "/files/", "service": "b"},
+            {"path_pattern": {"regex": "/reports/.*"}, "service": "a", "priority": 1},
+            {"path_pattern": {"wildcard": "/reports/*/pdf"}, "service": "b", "priority": 1},
+            {"path_pattern": {"wildcard": "/users/*"}, "service": "a"},
+            {"path_pattern": {"regex": "/users/\\d+"}, "service": "b"},
+            {"path_pattern": {"regex": "/orders?/\\d+"}, "service": "b"},
+            {"path_pattern": {"wildcard": "/**"}, "service": "a", "priority": -1},
+        ]))
+        .unwrap();
+        let found: Vec<(usize, ConflictKind)> = conflicts(&rules).iter().map(|c| (c.rule, c.kind)).collect();
+
+        assert_eq!(
+            found,
+            vec![
+                (1, ConflictKind::Duplicate),
+                (3, ConflictKind::Shadowed),
+                (5, ConflictKind::Ambiguous),
+                (7, ConflictKind::CatchAll),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patterns_that_cannot_overlap_are_not_ambiguous() {
+        let rules: Vec<RouteRule> = serde_json::from_value(serde_json::json!([
+            {"path_pattern": {"wildcard": "/reports/*/pdf"}, "service": "a"},
+            {"path_pattern": {"wildcard": "/reports/*/csv"}, "service": "b"},
+            {"path_pattern": {"wildcard": "/reports/*"}, "service": "b"},
+            {"path_pattern": {"wildcard": "/img/*.png"}, "service": "a"},
+            {"path_pattern": {"wildcard": "/img/*.jpg"}, "service": "b"},
+            {"path_pattern": {"regex": "^/x/\\d+"}, "service": "a"},
+            {"path_pattern": {"regex": "^/y/\\d+"}, "service": "b"},
+            {"path_pattern": {"regex": "(?i)/z/\\d+"}, "service": "b"},
+        ]))
+        .unwrap();
+        assert_eq!(conflicts(&rules), vec![]);
+
+        let rules: Vec<RouteRule> = serde_json::from_value(serde_json::json!([
+            {"path_pattern": {"wildcard": "/reports/*/pdf"}, "service": "a"},
+            {"path_pattern": {"wildcard": "/reports/2024*/*"}, "service": "b"},
+            {"path_pattern": {"regex": "(?i)/Z/\\d+"}, "service": "a"},
+            {"path_pattern": {"regex": "^/z/\\w+"}, "service": "b"},
+        ]))
+        .unwrap();
+        let found: Vec<(usize, ConflictKind)> = conflicts(&rules).iter().map(|c| (c.rule, c.kind)).collect();
+        assert_eq!(found, vec![(1, ConflictKind::Ambiguous), (3, ConflictKind::Ambiguous)]);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::upstream_timing::UpstreamPhases;
+use anyhow::{Context, Result};
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, time::Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerTimingConfig {
+    // Send the per-hop phases on every proxied response.
+    pub always: bool,
+    // Asks for the phases plus the upstream connection detail; honoured only
+    // from `trusted_cidrs`.
+    pub debug_header: String,
+    pub trusted_cidrs: Vec<String>,
+    // Entries that would take the header past this are left out.
+    pub max_header_bytes: usize,
+}
+
+impl Default for ServerTimingConfig {
+    fn default() -> Self {
+        Self {
+            always: false,
+            debug_header: "x-proxy-debug".to_string(),
+            trusted_cidrs: vec!["127.0.0.0/8".to_string(), "::1/128".to_string()],
+            max_header_bytes: 1024,
+        }
+    }
+}
+
+// What a request asked for; carried to the proxy as a request extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingDetail {
+    Hops,
+    // Hops and the upstream dns/connect/tls split.
+    Full,
+}
+
+pub struct ServerTiming {
+    always: bool,
+    debug_header: String,
+    trusted: Vec<IpNet>,
+    max_header_bytes: usize,
+}
+
+impl ServerTiming {
+    pub fn from_config(config: &ServerTimingConfig) -> Result<Self> {
+        let trusted = config
+            .trusted_cidrs
+            .iter()
+            .map(|cidr| cidr.parse::<IpNet>().with_context(|| format!("server_timing: invalid CIDR {:?}", cidr)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            always: config.always,
+            debug_header: config.debug_header.to_ascii_lowercase(),
+            trusted,
+            max_header_bytes: config.max_header_bytes,
+        })
+    }
+
+    pub fn requested(&self, headers: &HeaderMap, client_ip: IpAddr) -> Option<TimingDetail> {
+        let debug = headers.contains_key(self.debug_header.as_str())
+            && self.trusted.iter().any(|net| net.contains(&client_ip));
+        match (debug, self.always) {
+            (true, _) => Some(TimingDetail::Full),
+            (false, true) => Some(TimingDetail::Hops),
+            (false, false) => None,
+        }
+    }
+
+    pub fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes
+    }
+}
+
+// Marks where the proxy's own phases end, from the moment the request
+// arrived.
+pub struct HopTimer {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, f64)>,
+}
+
+impl HopTimer {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            last: start,
+            phases: Vec::new(),
+        }
+    }
+
+    // Closes the phase that ran since the previous mark.
+    pub fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, ms(self.last, now)));
+        self.last = now;
+    }
+
+    // Upstream phases are measured by the client hooks; whatever the named
+    // phases do not cover is proxy overhead, so everything adds up to `total`.
+    pub fn finish(&self, upstream: Option<&UpstreamPhases>) -> HopTimings {
+        let total = ms(self.start, Instant::now());
+        let mut phases = self.phases.clone();
+        if let Some(upstream) = upstream {
+            phases.push(("connect", upstream.dns_ms + upstream.connect_ms + upstream.tls_ms));
+            phases.push(("ttfb", upstream.ttfb_ms));
+            phases.push(("transfer", upstream.body_ms));
+        }
+        let named: f64 = phases.iter().map(|(_, ms)| ms).sum();
+        phases.push(("overhead", (total - named).max(0.0)));
+        HopTimings { phases, total }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HopTimings {
+    pub phases: Vec<(&'static str, f64)>,
+    pub total: f64,
+}
+
+impl HopTimings {
+    // `total` comes first so the cap never drops it; durations keep one
+    // decimal, which is all a millisecond reading is good for here.
+    pub fn server_timing(&self, upstream: Option<&UpstreamPhases>, max_bytes: usize) -> String {
+        let mut entries = vec![format!("total;dur={:.1}", self.total)];
+        entries.extend(self.phases.iter().map(|(name, ms)| format!("{};dur={:.1}", name, ms)));
+        if let Some(upstream) = upstream {
+            entries.extend(upstream.server_timing_entries());
+        }
+
+        let mut value = String::new();
+        for entry in entries {
+            let separator = if value.is_empty() { 0 } else { 2 };
+            if value.len() + separator + entry.len() > max_bytes {
+                break;
+            }
+            if separator > 0 {
+                value.push_str(", ");
+            }
+            value.push_str(&entry);
+        }
+        value
+    }
+}
+
+fn ms(from: Instant, to: Instant) -> f64 {
+    to.saturating_duration_since(from).as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_debug_header_needs_trusted_source() {
+        let timing = ServerTiming::from_config(&ServerTimingConfig::default()).unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(timing.requested(&headers, "127.0.0.1".parse().unwrap()), None);
+
+        headers.insert("x-proxy-debug", "1".parse().unwrap());
+        assert_eq!(timing.requested(&headers, "127.0.0.1".parse().unwrap()), Some(TimingDetail::Full));
+        assert_eq!(timing.requested(&headers, "10.1.2.3".parse().unwrap()), None);
+
+        let always = ServerTiming::from_config(&ServerTimingConfig {
+            always: true,
+            ..ServerTimingConfig::default()
+        })
+        .unwrap();
+        assert_eq!(always.requested(&headers, "10.1.2.3".parse().unwrap()), Some(TimingDetail::Hops));
+    }
+
+    #[test]
+    fn test_phases_add_up_to_total() {
+        let mut timer = HopTimer::new(Instant::now());
+        std::thread::sleep(Duration::from_millis(5));
+        timer.mark("route");
+        // Room for the upstream phases below.
+        std::thread::sleep(Duration::from_millis(5));
+        let upstream = UpstreamPhases {
+            ttfb_ms: 1.0,
+            body_ms: 0.5,
+            reused: true,
+            ..UpstreamPhases::default()
+        };
+        let timings = timer.finish(Some(&upstream));
+
+        let names: Vec<_> = timings.phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["route", "connect", "ttfb", "transfer", "overhead"]);
+        let sum: f64 = timings.phases.iter().map(|(_, ms)| ms).sum();
+        assert!((sum - timings.total).abs() < 1e-6, "{:?}", timings);
+    }
+
+    #[test]
+    fn test_header_is_rounded_and_capped() {
+        let timings = HopTimings {
+            phases: vec![("route", 0.123456), ("select", 2.0)],
+            total: 12.34567,
+        };
+        assert_eq!(timings.server_timing(None, 1024), "total;dur=12.3, route;dur=0.1, select;dur=2.0");
+        assert_eq!(timings.server_timing(None, 30), "total;dur=12.3, route;dur=0.1");
+    }
+}
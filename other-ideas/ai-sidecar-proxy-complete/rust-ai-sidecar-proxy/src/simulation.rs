@@ -0,0 +1,430 @@
+use crate::{
+    ai::{AIEngine, RequestMetrics},
+    auto_weight::{AutoWeightConfig, AutoWeigher},
+    clock::{Clock, MockClock},
+    load_balancer::{LoadBalancer, LoadBalancingStrategy},
+};
+use anyhow::{bail, Context, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const SERVICE: &str = "simulated";
+
+// A scripted run of the routing path: requests arrive at a fixed rate, the AI
+// engine (or the auto-weighted balancer) picks an endpoint for each, and the
+// endpoint answers as its script says at that moment. Time is simulated, so a run is exact and repeatable
+// for a seed. Scenarios are JSON files; see tests/fixtures/simulation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub duration_s: u64,
+    pub rps: u32,
+    #[serde(default = "default_seed")]
+    pub seed: u64,
+    // Bucket size of the traffic-share table in the report.
+    #[serde(default = "default_window_s")]
+    pub window_s: u64,
+    // In the order the proxy would list them; the AI engine breaks ties by it.
+    pub endpoints: Vec<EndpointScript>,
+    // When set, requests are picked by smooth weighted round robin instead of
+    // the AI engine, with weights tuned from its scores every `interval_ms` of
+    // simulated time.
+    #[serde(default)]
+    pub auto_weight: Option<AutoWeightConfig>,
+    #[serde(default)]
+    pub expect: Expectations,
+}
+
+fn default_seed() -> u64 {
+    1
+}
+
+fn default_window_s() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointScript {
+    pub name: String,
+    // Starting weight under `auto_weight`; 1 when unset.
+    #[serde(default)]
+    pub weight: Option<u32>,
+    // Each phase holds until the next one starts; the first starts at 0.
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Phase {
+    pub from_s: u64,
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub error_rate: f64,
+}
+
+// Golden values recorded from a run, each with the tolerance it may drift by.
+// A change that moves one on purpose updates the scenario file with it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expectations {
+    pub share: Vec<ShareExpectation>,
+    pub errors_served: Option<Bounds>,
+    pub mean_latency_ms: Option<Bounds>,
+}
+
+// Fraction of the requests arriving in [from_s, to_s) sent to `endpoint`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareExpectation {
+    pub endpoint: String,
+    pub from_s: u64,
+    pub to_s: u64,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Bounds {
+    fn contains(&self, value: f64) -> bool {
+        self.min <= value && value <= self.max
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading scenario {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing scenario {}", path.display()))
+    }
+
+    // Every *.json scenario in `dir`, sorted by file name.
+    pub fn load_dir(dir: &Path) -> Result<Vec<(PathBuf, Self)>> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .with_context(|| format!("reading scenario directory {}", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?;
+        paths.retain(|path| path.extension().is_some_and(|extension| extension == "json"));
+        paths.sort();
+        paths.into_iter().map(|path| Self::load(&path).map(|scenario| (path, scenario))).collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.rps == 0 || self.duration_s == 0 || self.window_s == 0 {
+            bail!("scenario {:?}: rps, duration_s and window_s must be greater than zero", self.name);
+        }
+        if self.endpoints.is_empty() {
+            bail!("scenario {:?} has no endpoints", self.name);
+        }
+        for endpoint in &self.endpoints {
+            if endpoint.phases.first().map(|phase| phase.from_s) != Some(0) {
+                bail!("endpoint {:?} must have a phase starting at 0", endpoint.name);
+            }
+            if endpoint.phases.windows(2).any(|pair| pair[0].from_s >= pair[1].from_s) {
+                bail!("phases of endpoint {:?} must be in increasing from_s order", endpoint.name);
+            }
+            if let Some(phase) = endpoint.phases.iter().find(|phase| !(0.0..=1.0).contains(&phase.error_rate)) {
+                bail!("endpoint {:?} has error_rate {} outside 0..=1", endpoint.name, phase.error_rate);
+            }
+        }
+        Ok(())
+    }
+
+    // Failed expectations, one line each; empty when the report matches.
+    pub fn check(&self, report: &SimulationReport) -> Vec<String> {
+        let mut failures = Vec::new();
+        for expected in &self.expect.share {
+            let share = report.share(&expected.endpoint, expected.from_s, expected.to_s);
+            if !(expected.min <= share && share <= expected.max) {
+                failures.push(format!(
+                    "share of {} in {}s..{}s is {:.3}, expected {}..{}",
+                    expected.endpoint, expected.from_s, expected.to_s, share, expected.min, expected.max
+                ));
+            }
+        }
+        if let Some(bounds) = self.expect.errors_served {
+            if !bounds.contains(report.errors_served as f64) {
+                failures.push(format!(
+                    "errors served {}, expected {}..{}",
+                    report.errors_served, bounds.min, bounds.max
+                ));
+            }
+        }
+        if let Some(bounds) = self.expect.mean_latency_ms {
+            if !bounds.contains(report.mean_latency_ms()) {
+                failures.push(format!(
+                    "mean latency {:.1}ms, expected {}..{}",
+                    report.mean_latency_ms(),
+                    bounds.min,
+                    bounds.max
+                ));
+            }
+        }
+        failures
+    }
+}
+
+impl EndpointScript {
+    fn phase_at(&self, at_ms: u64) -> &Phase {
+        self.phases
+            .iter()
+            .rev()
+            .find(|phase| phase.from_s * 1000 <= at_ms)
+            .unwrap_or(&self.phases[0])
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SimulationReport {
+    pub name: String,
+    pub requests: usize,
+    pub errors_served: usize,
+    pub total_latency_ms: u64,
+    pub window_s: u64,
+    pub endpoints: Vec<String>,
+    // Arrival time in ms and endpoint index of every request, in order.
+    pub decisions: Vec<(u64, usize)>,
+    // Most requests one endpoint had in flight at once.
+    pub peak_in_flight: BTreeMap<String, usize>,
+    // Under `auto_weight`: the time in ms of every tuning round and the
+    // weights it left, in endpoint order.
+    pub weights: Vec<(u64, Vec<u32>)>,
+}
+
+impl SimulationReport {
+    pub fn share(&self, endpoint: &str, from_s: u64, to_s: u64) -> f64 {
+        let Some(index) = self.endpoints.iter().position(|name| name == endpoint) else {
+            return 0.0;
+        };
+        let in_range: Vec<usize> = self
+            .decisions
+            .iter()
+            .filter(|(at_ms, _)| from_s * 1000 <= *at_ms && *at_ms < to_s * 1000)
+            .map(|(_, selected)| *selected)
+            .collect();
+        let hits = in_range.iter().filter(|selected| **selected == index).count();
+        hits as f64 / in_range.len().max(1) as f64
+    }
+
+    pub fn mean_latency_ms(&self) -> f64 {
+        self.total_latency_ms as f64 / self.requests.max(1) as f64
+    }
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "scenario:      {}", self.name)?;
+        writeln!(f, "requests:      {}", self.requests)?;
+        writeln!(f, "errors served: {}", self.errors_served)?;
+        writeln!(f, "mean latency:  {:.1}ms", self.mean_latency_ms())?;
+        writeln!(f, "peak in flight:")?;
+        for (endpoint, peak) in &self.peak_in_flight {
+            writeln!(f, "  {:<12} {}", endpoint, peak)?;
+        }
+        write!(f, "traffic share:\n  {:<12}", "window")?;
+        for endpoint in &self.endpoints {
+            write!(f, " {:>8}", endpoint)?;
+        }
+        writeln!(f)?;
+        let end_s = self.decisions.last().map_or(0, |(at_ms, _)| at_ms / 1000 + 1);
+        for from_s in (0..end_s).step_by(self.window_s.max(1) as usize) {
+            let to_s = from_s + self.window_s;
+            write!(f, "  {:<12}", format!("{}-{}s", from_s, to_s))?;
+            for endpoint in &self.endpoints {
+                write!(f, " {:>7.1}%", self.share(endpoint, from_s, to_s) * 100.0)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.weights.is_empty() {
+            write!(f, "weights:\n  {:<12}", "at")?;
+            for endpoint in &self.endpoints {
+                write!(f, " {:>8}", endpoint)?;
+            }
+            writeln!(f)?;
+            for (at_ms, weights) in self.weights.iter().filter(|(at_ms, _)| at_ms % (self.window_s * 1000) == 0) {
+                write!(f, "  {:<12}", format!("{}s", at_ms / 1000))?;
+                for weight in weights {
+                    write!(f, " {:>8}", weight)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Completion {
+    endpoint: usize,
+    latency_ms: u64,
+    success: bool,
+}
+
+// Responses are fed back to the engine when they complete, not when they are
+// sent, so a slow endpoint keeps drawing traffic until its answers arrive.
+pub async fn run(scenario: &Scenario) -> Result<SimulationReport> {
+    scenario.validate()?;
+    let clock = MockClock::new();
+    let started = clock.now();
+    let ai_engine = AIEngine::new();
+    let mut weigher = scenario.auto_weight.clone().map(AutoWeigher::new);
+    let mut load_balancer = LoadBalancer::with_strategy(LoadBalancingStrategy::WeightedRoundRobin);
+    for endpoint in &scenario.endpoints {
+        if let Some(weight) = endpoint.weight {
+            load_balancer = load_balancer.with_service_weights(SERVICE, &[(endpoint.name.clone(), weight)].into());
+        }
+    }
+    let mut next_tune_us = weigher.as_ref().map_or(u64::MAX, |weigher| weigher.interval().as_micros() as u64);
+    let mut rng = StdRng::seed_from_u64(scenario.seed);
+    let names: Vec<String> = scenario.endpoints.iter().map(|endpoint| endpoint.name.clone()).collect();
+
+    let mut report = SimulationReport {
+        name: scenario.name.clone(),
+        window_s: scenario.window_s,
+        endpoints: names.clone(),
+        ..SimulationReport::default()
+    };
+    // Keyed by completion time, then by send order.
+    let mut in_flight: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+    let mut completions: Vec<Completion> = Vec::new();
+    let mut now_us = 0;
+
+    let total = scenario.duration_s * scenario.rps as u64;
+    for sent in 0..=total {
+        // One pass past the last request drains everything still in flight.
+        let at_us = if sent < total { sent * 1_000_000 / scenario.rps as u64 } else { u64::MAX };
+        while let Some(Reverse((done_us, id))) = in_flight.peek().copied() {
+            if done_us > at_us {
+                break;
+            }
+            in_flight.pop();
+            clock.advance(Duration::from_micros(done_us - now_us));
+            now_us = done_us;
+            let completion = &completions[id];
+            let endpoint = &names[completion.endpoint];
+            load_balancer.decrement_connections(endpoint).await;
+            ai_engine
+                .record_request(RequestMetrics {
+                    latency_ms: completion.latency_ms,
+                    status_code: if completion.success { 200 } else { 503 },
+                    endpoint: endpoint.clone(),
+                    timestamp: clock.now().duration_since(started).as_secs(),
+                    success: completion.success,
+                    phases: None,
+                })
+                .await;
+        }
+        if sent == total {
+            break;
+        }
+
+        clock.advance(Duration::from_micros(at_us - now_us));
+        now_us = at_us;
+        if let Some(weigher) = &mut weigher {
+            while next_tune_us <= at_us {
+                weigher.tune(SERVICE, &names, &ai_engine, &load_balancer).await;
+                let explanation = load_balancer.explain(SERVICE, &names).await;
+                let weights = explanation.endpoints.iter().map(|endpoint| endpoint.weight).collect();
+                report.weights.push((next_tune_us / 1000, weights));
+                next_tune_us += weigher.interval().as_micros() as u64;
+            }
+        }
+        // Without the `ai` feature the engine never picks, so the balancer does.
+        let selected = match weigher {
+            None if cfg!(feature = "ai") => ai_engine.select_endpoint(SERVICE, &names).await.selected_endpoint,
+            _ => load_balancer.select_endpoint(SERVICE, &names).await.unwrap_or_default(),
+        };
+        let index = names
+            .iter()
+            .position(|name| *name == selected)
+            .context("routing picked an endpoint outside the scenario")?;
+        let phase = scenario.endpoints[index].phase_at(at_us / 1000);
+        let success = !rng.gen_bool(phase.error_rate);
+
+        load_balancer.increment_connections(&selected).await;
+        let in_use = load_balancer.get_connection_count(&selected).await;
+        let peak = report.peak_in_flight.entry(selected).or_default();
+        *peak = (*peak).max(in_use);
+
+        report.requests += 1;
+        report.errors_served += usize::from(!success);
+        report.total_latency_ms += phase.latency_ms;
+        report.decisions.push((at_us / 1000, index));
+        in_flight.push(Reverse((at_us + phase.latency_ms * 1000, completions.len())));
+        completions.push(Completion {
+            endpoint: index,
+            latency_ms: phase.latency_ms,
+            success,
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario(error_rate: f64) -> Scenario {
+        serde_json::from_value(serde_json::json!({
+            "name": "two endpoints",
+            "duration_s": 20,
+            "rps": 10,
+            "endpoints": [
+                {"name": "a", "phases": [{"from_s": 0, "latency_ms": 20}, {"from_s": 10, "latency_ms": 20, "error_rate": error_rate}]},
+                {"name": "b", "phases": [{"from_s": 0, "latency_ms": 150}]},
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_phase_in_effect() {
+        let endpoint = &scenario(1.0).endpoints[0];
+        assert_eq!(endpoint.phase_at(9_999).error_rate, 0.0);
+        assert_eq!(endpoint.phase_at(10_000).error_rate, 1.0);
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_runs_are_repeatable() {
+        let first = run(&scenario(0.5)).await.unwrap();
+        let second = run(&scenario(0.5)).await.unwrap();
+        assert_eq!(first.requests, 200);
+        assert_eq!(first.decisions, second.decisions);
+        assert_eq!(first.errors_served, second.errors_served);
+        // Ties go to the first endpoint, and a healthy one keeps its traffic.
+        assert_eq!(first.share("a", 0, 10), 1.0);
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn test_check_reports_each_miss() {
+        let mut scenario = scenario(0.0);
+        scenario.expect.share.push(ShareExpectation {
+            endpoint: "b".to_string(),
+            from_s: 0,
+            to_s: 20,
+            min: 0.5,
+            max: 1.0,
+        });
+        scenario.expect.errors_served = Some(Bounds { min: 0.0, max: 0.0 });
+        let report = run(&scenario).await.unwrap();
+        let failures = scenario.check(&report);
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        assert!(failures[0].starts_with("share of b"));
+    }
+}
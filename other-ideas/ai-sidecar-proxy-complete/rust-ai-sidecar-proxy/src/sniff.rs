@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const SNIFF_LEN: usize = 16;
+const DRAIN_LIMIT: usize = 16 * 1024;
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(50);
+
+// Fatal handshake_failure alert, record version TLS 1.0 so every client parses it.
+const TLS_HANDSHAKE_FAILURE_ALERT: [u8; 7] = [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x28];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsOnPlaintext {
+    Close,
+    Alert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preface {
+    Http,
+    TlsClientHello,
+    NonHttp,
+    Empty,
+}
+
+impl Preface {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Preface::Http => "http",
+            Preface::TlsClientHello => "tls_on_plaintext",
+            Preface::NonHttp => "non_http",
+            Preface::Empty => "empty",
+        }
+    }
+}
+
+pub fn classify(bytes: &[u8]) -> Preface {
+    let Some(&first) = bytes.first() else {
+        return Preface::Empty;
+    };
+
+    if first == 0x16 && bytes.get(1).is_none_or(|&b| b == 0x03) {
+        return Preface::TlsClientHello;
+    }
+
+    if !first.is_ascii_uppercase() {
+        return Preface::NonHttp;
+    }
+
+    let method_is_token = bytes
+        .iter()
+        .take_while(|&&b| b != b' ')
+        .all(|&b| is_tchar(b));
+
+    if method_is_token {
+        Preface::Http
+    } else {
+        Preface::NonHttp
+    }
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Peeking leaves the bytes in the kernel buffer, so hyper reads them as usual.
+pub async fn sniff(stream: &TcpStream) -> std::io::Result<Preface> {
+    let mut buf = [0u8; SNIFF_LEN];
+    let n = stream.peek(&mut buf).await?;
+    Ok(classify(&buf[..n]))
+}
+
+pub async fn reject(mut stream: TcpStream, preface: Preface, tls_on_plaintext: TlsOnPlaintext) {
+    // Consume what the client already sent so closing emits FIN rather than RST.
+    let mut buf = [0u8; 4096];
+    let mut drained = 0;
+    while drained < DRAIN_LIMIT {
+        match timeout(DRAIN_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => drained += n,
+            _ => break,
+        }
+    }
+
+    if preface == Preface::TlsClientHello && tls_on_plaintext == TlsOnPlaintext::Alert {
+        let _ = stream.write_all(&TLS_HANDSHAKE_FAILURE_ALERT).await;
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ai::AIEngine, config::Config, metrics::MetricsCollector, proxy::ProxyServer};
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::net::TcpListener;
+
+    const CLIENT_HELLO: [u8; 11] = [0x16, 0x03, 0x01, 0x00, 0xf4, 0x01, 0x00, 0x00, 0xf0, 0x03, 0x03];
+
+    async fn start_proxy(tls_on_plaintext: TlsOnPlaintext) -> (SocketAddr, Arc<MetricsCollector>) {
+        let mut config = Config::new();
+        config.proxy_config.tls_on_plaintext = tls_on_plaintext;
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        (addr, metrics)
+    }
+
+    async fn send_and_read_all(addr: SocketAddr, bytes: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(bytes).await.unwrap();
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+            .await
+            .expect("connection was not closed")
+            .expect("connection was reset instead of closed");
+        received
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(b"GET / HTTP/1.1\r\n"), Preface::Http);
+        assert_eq!(classify(b"PRI * HTTP/2.0\r\n"), Preface::Http);
+        assert_eq!(classify(b"M-SEARCH * HTTP/1.1"), Preface::Http);
+        assert_eq!(classify(b"GE"), Preface::Http);
+        assert_eq!(classify(&CLIENT_HELLO), Preface::TlsClientHello);
+        assert_eq!(classify(&[0x16]), Preface::TlsClientHello);
+        assert_eq!(classify(&[0x00, 0xff, 0x13, 0x37]), Preface::NonHttp);
+        assert_eq!(classify(b"get / HTTP/1.1"), Preface::NonHttp);
+        assert_eq!(classify(b"G\x01T /"), Preface::NonHttp);
+        assert_eq!(classify(b""), Preface::Empty);
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_is_closed_and_counted() {
+        let (addr, metrics) = start_proxy(TlsOnPlaintext::Close).await;
+
+        let received = send_and_read_all(addr, &CLIENT_HELLO).await;
+
+        assert!(received.is_empty());
+        assert_eq!(metrics.protocol_error_count(&addr.to_string(), "tls_on_plaintext"), 1);
+        assert_eq!(metrics.protocol_error_count(&addr.to_string(), "non_http"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_gets_tls_alert() {
+        let (addr, metrics) = start_proxy(TlsOnPlaintext::Alert).await;
+
+        let received = send_and_read_all(addr, &CLIENT_HELLO).await;
+
+        assert_eq!(received, TLS_HANDSHAKE_FAILURE_ALERT);
+        assert_eq!(metrics.protocol_error_count(&addr.to_string(), "tls_on_plaintext"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_garbage_is_closed_and_counted() {
+        let (addr, metrics) = start_proxy(TlsOnPlaintext::Close).await;
+
+        for _ in 0..2 {
+            let received = send_and_read_all(addr, &[0x00, 0x01, 0xfe, 0xff, 0x42]).await;
+            assert!(received.is_empty());
+        }
+
+        assert_eq!(metrics.protocol_error_count(&addr.to_string(), "non_http"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_http_is_served() {
+        let (addr, metrics) = start_proxy(TlsOnPlaintext::Close).await;
+
+        let received = send_and_read_all(
+            addr,
+            b"GET /health HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+
+        assert!(received.starts_with(b"HTTP/1.1 200 OK"));
+        assert_eq!(metrics.protocol_error_count(&addr.to_string(), "non_http"), 0);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::metrics::MetricsCollector;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::Notify;
+use tracing::info;
+
+// For blue/green upgrades: a standby proxy checks health, learns and takes
+// reloads like a serving one, so its state is warm, but answers only /health,
+// /metrics and admin reads until it is promoted. Demoting it again waits for
+// the requests it is serving to finish.
+pub struct Standby {
+    standby: AtomicBool,
+    // Requests let through and not yet answered.
+    serving: AtomicUsize,
+    idle: Notify,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl Standby {
+    pub fn new(standby: bool, metrics: Arc<MetricsCollector>) -> Self {
+        metrics.set_standby(standby);
+        Self {
+            standby: AtomicBool::new(standby),
+            serving: AtomicUsize::new(0),
+            idle: Notify::new(),
+            metrics,
+        }
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+
+    // None while in standby. Counted first and checked after, so a request
+    // that gets through is always seen by a demotion that follows.
+    pub fn request(&self) -> Option<Serving<'_>> {
+        self.serving.fetch_add(1, Ordering::SeqCst);
+        let serving = Serving { standby: self };
+        (!self.is_standby()).then_some(serving)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.serving.load(Ordering::SeqCst)
+    }
+
+    // Returns whether the proxy was in standby.
+    pub fn promote(&self) -> bool {
+        let was = self.standby.swap(false, Ordering::SeqCst);
+        if was {
+            self.metrics.set_standby(false);
+            info!("promoted from standby, serving traffic");
+        }
+        was
+    }
+
+    // Refuses new requests at once and resolves when those already let
+    // through have been answered. Returns whether the proxy was serving.
+    pub async fn demote(&self) -> bool {
+        let was = !self.standby.swap(true, Ordering::SeqCst);
+        if was {
+            self.metrics.set_standby(true);
+            info!(in_flight = self.in_flight(), "demoted to standby, draining requests");
+        }
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.in_flight() == 0 {
+                return was;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Counts a request for as long as it is held.
+pub struct Serving<'a> {
+    standby: &'a Standby,
+}
+
+impl Drop for Serving<'_> {
+    fn drop(&mut self) {
+        if self.standby.serving.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.standby.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_demotion_waits_for_requests_let_through() {
+        let metrics = Arc::new(MetricsCollector::new());
+        let standby = Standby::new(true, metrics.clone());
+        assert!(standby.request().is_none());
+        assert_eq!((standby.in_flight(), metrics.standby()), (0, 1.0));
+
+        assert!(standby.promote());
+        assert!(!standby.promote());
+        assert_eq!(metrics.standby(), 0.0);
+        let serving = standby.request().unwrap();
+
+        let demote = standby.demote();
+        tokio::pin!(demote);
+        assert!(tokio::time::timeout(Duration::from_millis(50), demote.as_mut()).await.is_err());
+        assert!(standby.request().is_none());
+        assert_eq!(metrics.standby(), 1.0);
+
+        drop(serving);
+        assert!(demote.await);
+        assert!(!standby.demote().await);
+    }
+}
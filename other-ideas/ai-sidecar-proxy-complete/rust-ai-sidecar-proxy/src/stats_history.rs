@@ -0,0 +1,272 @@
+use crate::metrics::LatencyHistogram;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Per-endpoint request counts and latencies, one row per endpoint per minute,
+// for exporting without a Prometheus server. At most
+// retention_hours * 60 * max_endpoints rows are kept, each about 64 bytes
+// plus its endpoint URL; with the defaults, under 10 MB however busy the
+// proxy is. Minutes still open hold up to 1000 latency samples for each of at
+// most 2 * max_endpoints endpoints.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsHistoryConfig {
+    pub enabled: bool,
+    pub retention_hours: u32,
+    // Endpoints expected to take traffic in any one minute. Minutes not yet
+    // folded into rows track at most twice this many endpoints between them,
+    // and requests to any others are left out.
+    pub max_endpoints: usize,
+    // Between folding finished minutes into rows.
+    pub tick_ms: u64,
+}
+
+impl Default for StatsHistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_hours: 24,
+            max_endpoints: 64,
+            tick_ms: 10_000,
+        }
+    }
+}
+
+impl StatsHistoryConfig {
+    fn max_rows(&self) -> usize {
+        self.retention_hours as usize * 60 * self.max_endpoints
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MinuteRow {
+    // Unix seconds at the start of the minute.
+    pub minute: u64,
+    pub endpoint: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+}
+
+pub const CSV_HEADER: &str = "minute,endpoint,requests,errors,p50_ms,p95_ms\n";
+
+impl MinuteRow {
+    pub fn csv_line(&self) -> String {
+        format!(
+            "{},{},{},{},{:.2},{:.2}\n",
+            self.minute,
+            csv_field(&self.endpoint),
+            self.requests,
+            self.errors,
+            self.p50_ms,
+            self.p95_ms
+        )
+    }
+}
+
+// Quoted when it holds a separator, a quote or a line break, with quotes
+// doubled, as RFC 4180 has it.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Default)]
+struct OpenMinute {
+    requests: u64,
+    errors: u64,
+    latency: LatencyHistogram,
+}
+
+pub struct StatsHistory {
+    config: RwLock<StatsHistoryConfig>,
+    // By minute and endpoint, until the tick after the minute ends.
+    open: Mutex<HashMap<(u64, String), OpenMinute>>,
+    // Oldest first.
+    rows: Mutex<VecDeque<MinuteRow>>,
+}
+
+impl Default for StatsHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsHistory {
+    pub fn new() -> Self {
+        Self {
+            config: RwLock::new(StatsHistoryConfig::default()),
+            open: Mutex::new(HashMap::new()),
+            rows: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn configure(&self, config: &StatsHistoryConfig) {
+        *self.config.write().unwrap() = config.clone();
+    }
+
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.config.read().unwrap().tick_ms)
+    }
+
+    pub fn record(&self, endpoint: &str, latency_ms: u64, success: bool, at: SystemTime) {
+        let (enabled, max_endpoints) = {
+            let config = self.config.read().unwrap();
+            (config.enabled, config.max_endpoints)
+        };
+        if !enabled {
+            return;
+        }
+        let key = (minute_of(at), endpoint.to_string());
+        let mut open = self.open.lock().unwrap();
+        if !open.contains_key(&key) && open.len() >= 2 * max_endpoints {
+            return;
+        }
+        let minute = open.entry(key).or_default();
+        minute.requests += 1;
+        if !success {
+            minute.errors += 1;
+        }
+        minute.latency.record(latency_ms);
+    }
+
+    // Turns every minute that ended before `now` into rows, and drops rows
+    // past the retention or over the bound. Returns how many rows were added.
+    pub fn aggregate(&self, now: SystemTime) -> usize {
+        let config = self.config.read().unwrap().clone();
+        let current = minute_of(now);
+        let mut closed: Vec<MinuteRow> = {
+            let mut open = self.open.lock().unwrap();
+            let ended: Vec<(u64, String)> = open.keys().filter(|(minute, _)| *minute < current).cloned().collect();
+            ended
+                .into_iter()
+                .filter_map(|key| {
+                    let minute = open.remove(&key)?;
+                    Some(MinuteRow {
+                        minute: key.0,
+                        endpoint: key.1,
+                        requests: minute.requests,
+                        errors: minute.errors,
+                        p50_ms: minute.latency.p50(),
+                        p95_ms: minute.latency.p95(),
+                    })
+                })
+                .collect()
+        };
+        closed.sort_by(|a, b| (a.minute, &a.endpoint).cmp(&(b.minute, &b.endpoint)));
+        let added = closed.len();
+
+        let oldest = current.saturating_sub(config.retention_hours as u64 * 3600);
+        let mut rows = self.rows.lock().unwrap();
+        rows.extend(closed);
+        while rows.front().is_some_and(|row| row.minute < oldest) || rows.len() > config.max_rows() {
+            rows.pop_front();
+        }
+        added
+    }
+
+    // Rows from `since` up to but not including `until`, both Unix seconds,
+    // for the given endpoints or all of them.
+    pub fn rows(&self, since: Option<u64>, until: Option<u64>, endpoints: Option<&[String]>) -> Vec<MinuteRow> {
+        self.rows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| since.is_none_or(|since| row.minute >= since))
+            .filter(|row| until.is_none_or(|until| row.minute < until))
+            .filter(|row| endpoints.is_none_or(|endpoints| endpoints.contains(&row.endpoint)))
+            .cloned()
+            .collect()
+    }
+}
+
+fn minute_of(at: SystemTime) -> u64 {
+    let seconds = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    seconds - seconds % 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_requests_are_bucketed_by_minute() {
+        let history = StatsHistory::new();
+        for (second, latency, success) in [(600, 10, true), (630, 30, false), (659, 20, true), (660, 99, true)] {
+            history.record("http://a:80", latency, success, at(second));
+        }
+        history.record("http://b:80", 5, true, at(610));
+
+        // The minute at 660 is still open.
+        assert_eq!(history.aggregate(at(665)), 2);
+        let rows = history.rows(None, None, None);
+        assert_eq!(
+            rows,
+            vec![
+                MinuteRow { minute: 600, endpoint: "http://a:80".to_string(), requests: 3, errors: 1, p50_ms: 20.0, p95_ms: 30.0 },
+                MinuteRow { minute: 600, endpoint: "http://b:80".to_string(), requests: 1, errors: 0, p50_ms: 5.0, p95_ms: 5.0 },
+            ]
+        );
+
+        assert_eq!(history.aggregate(at(720)), 1);
+        assert_eq!(history.rows(Some(660), None, None).len(), 1);
+        assert_eq!(history.rows(None, Some(660), Some(&["http://b:80".to_string()])).len(), 1);
+    }
+
+    #[test]
+    fn test_rows_are_bounded_by_retention_and_count() {
+        let history = StatsHistory::new();
+        history.configure(&StatsHistoryConfig {
+            retention_hours: 1,
+            max_endpoints: 2,
+            ..StatsHistoryConfig::default()
+        });
+        // Only 2 * max_endpoints endpoints are tracked in an open minute.
+        for endpoint in ["a", "b", "c", "d", "e"] {
+            history.record(endpoint, 1, true, at(0));
+        }
+        assert_eq!(history.aggregate(at(60)), 4);
+
+        // 4 rows a minute for 40 minutes, all within the hour, is over the
+        // 1 * 60 * 2 rows allowed; the oldest go.
+        for minute in 1..=40u64 {
+            for endpoint in ["a", "b", "c", "d"] {
+                history.record(endpoint, 1, true, at(minute * 60));
+            }
+            history.aggregate(at((minute + 1) * 60));
+        }
+        let rows = history.rows(None, None, None);
+        assert_eq!(rows.len(), 120);
+        assert_eq!(rows[0].minute, 11 * 60);
+
+        // Past the retention, rows go even under the count.
+        history.aggregate(at(91 * 60));
+        let rows = history.rows(None, None, None);
+        assert_eq!(rows.len(), 40);
+        assert_eq!(rows[0].minute, 31 * 60);
+    }
+
+    #[test]
+    fn test_csv_quotes_endpoints_with_separators() {
+        let row = MinuteRow {
+            minute: 60,
+            endpoint: "http://a:80/x,y\"z".to_string(),
+            requests: 2,
+            errors: 1,
+            p50_ms: 1.5,
+            p95_ms: 2.0,
+        };
+        assert_eq!(row.csv_line(), "60,\"http://a:80/x,y\"\"z\",2,1,1.50,2.00\n");
+        assert_eq!(csv_field("http://a:80"), "http://a:80");
+    }
+}
@@ -0,0 +1,619 @@
+use crate::clock::Clock;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+// What the proxy keeps across restarts, by namespace.
+pub const AI_NAMESPACE: &str = "ai";
+pub const QUOTA_NAMESPACE: &str = "quotas";
+pub const NAMESPACES: [&str; 2] = [AI_NAMESPACE, QUOTA_NAMESPACE];
+
+// One backend for everything the proxy persists. Without this section each
+// feature writes the file its own `persist_path` names, as before.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // Directory the file backend keeps one file per entry under.
+    pub path: Option<PathBuf>,
+    pub redis: RedisStorageConfig,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::File,
+            path: None,
+            redis: RedisStorageConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    File,
+    // Needs the `redis` feature.
+    Redis,
+}
+
+impl StorageBackend {
+    pub fn label(self) -> &'static str {
+        match self {
+            StorageBackend::File => "file",
+            StorageBackend::Redis => "redis",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisStorageConfig {
+    pub url: String,
+    // Put before every key, so several proxies can share one database.
+    pub key_prefix: String,
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for RedisStorageConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1:6379".to_string(),
+            key_prefix: "ai-sidecar-proxy:".to_string(),
+            connect_timeout_ms: 2000,
+        }
+    }
+}
+
+impl StorageConfig {
+    // Why the settings cannot work together, if they cannot.
+    pub fn problem(&self) -> Option<&'static str> {
+        match self.backend {
+            StorageBackend::File if self.path.is_none() => Some("the file backend needs a path"),
+            StorageBackend::File => None,
+            StorageBackend::Redis if !cfg!(feature = "redis") => {
+                Some("this build has no Redis backend; rebuild with --features redis")
+            }
+            StorageBackend::Redis if self.redis.url.is_empty() => Some("redis.url must not be empty"),
+            StorageBackend::Redis if self.redis.connect_timeout_ms == 0 => {
+                Some("redis.connect_timeout_ms must be above 0")
+            }
+            StorageBackend::Redis => None,
+        }
+    }
+}
+
+// Byte values by key, grouped in namespaces. Entries put with a TTL are gone
+// once it runs out, whichever backend holds them.
+#[async_trait]
+pub trait KeyValueStore: Send + Sync {
+    fn backend(&self) -> StorageBackend;
+    async fn get(&self, namespace: &str, key: &str) -> io::Result<Option<Vec<u8>>>;
+    // `ttl` of None keeps the entry until it is deleted.
+    async fn put(&self, namespace: &str, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()>;
+    // Deleting a missing entry is not an error.
+    async fn delete(&self, namespace: &str, key: &str) -> io::Result<()>;
+    // Every live entry whose key starts with `prefix`, sorted by key.
+    async fn scan(&self, namespace: &str, prefix: &str) -> io::Result<Vec<(String, Vec<u8>)>>;
+}
+
+pub fn open(config: &StorageConfig, clock: Arc<dyn Clock>) -> io::Result<Arc<dyn KeyValueStore>> {
+    if let Some(problem) = config.problem() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, problem));
+    }
+    match config.backend {
+        StorageBackend::File => {
+            let dir = config.path.clone().expect("checked by problem()");
+            Ok(Arc::new(FileStore::new(dir, clock)?))
+        }
+        #[cfg(feature = "redis")]
+        StorageBackend::Redis => Ok(Arc::new(RedisStore::new(&config.redis)?)),
+        #[cfg(not(feature = "redis"))]
+        StorageBackend::Redis => unreachable!("checked by problem()"),
+    }
+}
+
+// Namespaces are fixed names in the code; keys come from clients and
+// operators and may hold anything but nothing at all.
+fn check_names(namespace: &str, key: &str) -> io::Result<()> {
+    let namespace_ok = !namespace.is_empty()
+        && namespace.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-');
+    if !namespace_ok {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid namespace {:?}", namespace)));
+    }
+    if key.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "empty key"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NamespaceReport {
+    pub namespace: &'static str,
+    pub entries: usize,
+    // Why the namespace could not be read; None when it loaded.
+    pub error: Option<String>,
+}
+
+// Reads every entry of `namespaces` once at startup, so a store that is
+// unreachable or holds a damaged entry is reported before any feature quietly
+// starts from nothing.
+pub async fn check(store: &dyn KeyValueStore, namespaces: &[&'static str]) -> Vec<NamespaceReport> {
+    let backend = store.backend().label();
+    let mut reports = Vec::with_capacity(namespaces.len());
+    for &namespace in namespaces {
+        let report = match store.scan(namespace, "").await {
+            Ok(entries) => {
+                info!(backend, namespace, entries = entries.len(), "storage namespace loaded");
+                NamespaceReport { namespace, entries: entries.len(), error: None }
+            }
+            Err(e) => {
+                warn!(backend, namespace, error = %e, "storage namespace unreadable");
+                NamespaceReport { namespace, entries: 0, error: Some(e.to_string()) }
+            }
+        };
+        reports.push(report);
+    }
+    reports
+}
+
+// One value a feature keeps across restarts: a key in the shared store, or
+// the file of its own that `persist_path` names when there is none.
+#[derive(Clone)]
+pub enum Slot {
+    Stored {
+        store: Arc<dyn KeyValueStore>,
+        namespace: &'static str,
+        key: &'static str,
+    },
+    File(PathBuf),
+}
+
+impl Slot {
+    pub async fn load(&self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Slot::Stored { store, namespace, key } => store.get(namespace, key).await,
+            Slot::File(path) => match tokio::fs::read(path).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    pub async fn save(&self, value: &[u8]) -> io::Result<()> {
+        match self {
+            Slot::Stored { store, namespace, key } => store.put(namespace, key, value, None).await,
+            Slot::File(path) => write_atomically(path, value).await,
+        }
+    }
+
+    pub async fn delete(&self) -> io::Result<()> {
+        match self {
+            Slot::Stored { store, namespace, key } => store.delete(namespace, key).await,
+            Slot::File(path) => remove_if_present(path).await,
+        }
+    }
+
+    // Where the value lives, for logs.
+    pub fn location(&self) -> String {
+        match self {
+            Slot::Stored { store, namespace, key } => format!("{}:{}/{}", store.backend().label(), namespace, key),
+            Slot::File(path) => path.display().to_string(),
+        }
+    }
+}
+
+// Written and synced beside the target, then renamed over it, so a crash
+// leaves either the old contents or the new ones. The temporary name has a
+// dot, which encoded keys never do.
+async fn write_atomically(path: &Path, value: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+    let result = async {
+        let mut file = tokio::fs::File::create(&temp).await?;
+        file.write_all(value).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    result
+}
+
+async fn remove_if_present(path: &Path) -> io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// One file per entry at `<dir>/<namespace>/<key>`, the key percent-encoded
+// down to letters, digits, `-` and `_`. Each file starts with the entry's
+// expiry in Unix milliseconds, 0 for none, as 8 big-endian bytes.
+pub struct FileStore {
+    dir: PathBuf,
+    clock: Arc<dyn Clock>,
+}
+
+const EXPIRY_BYTES: usize = 8;
+
+impl FileStore {
+    pub fn new(dir: PathBuf, clock: Arc<dyn Clock>) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir, clock })
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.clock.wall().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    fn entry_path(&self, namespace: &str, key: &str) -> PathBuf {
+        self.dir.join(namespace).join(encode_key(key))
+    }
+
+    // The value, or None when the entry has expired. Expired files are
+    // removed as they are found.
+    async fn read_entry(&self, path: &Path, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some(expiry) = bytes.get(..EXPIRY_BYTES) else {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("entry {:?} is truncated", key)));
+        };
+        let expires_at = u64::from_be_bytes(expiry.try_into().expect("8 bytes"));
+        if expires_at != 0 && expires_at <= self.now_ms() {
+            remove_if_present(path).await?;
+            return Ok(None);
+        }
+        bytes.drain(..EXPIRY_BYTES);
+        Ok(Some(bytes))
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for FileStore {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::File
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> io::Result<Option<Vec<u8>>> {
+        check_names(namespace, key)?;
+        self.read_entry(&self.entry_path(namespace, key), key).await
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        check_names(namespace, key)?;
+        // At least a millisecond, so a TTL never reads as "no expiry".
+        let expires_at = ttl.map_or(0, |ttl| self.now_ms() + (ttl.as_millis() as u64).max(1));
+        let mut bytes = Vec::with_capacity(EXPIRY_BYTES + value.len());
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes.extend_from_slice(value);
+        tokio::fs::create_dir_all(self.dir.join(namespace)).await?;
+        write_atomically(&self.entry_path(namespace, key), &bytes).await
+    }
+
+    async fn delete(&self, namespace: &str, key: &str) -> io::Result<()> {
+        check_names(namespace, key)?;
+        remove_if_present(&self.entry_path(namespace, key)).await
+    }
+
+    async fn scan(&self, namespace: &str, prefix: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+        check_names(namespace, "-")?;
+        let mut dir = match tokio::fs::read_dir(self.dir.join(namespace)).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        while let Some(file) = dir.next_entry().await? {
+            // Temporary files left by a crash mid-write are skipped.
+            let Some(key) = file.file_name().to_str().and_then(decode_key) else {
+                continue;
+            };
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            if let Some(value) = self.read_entry(&file.path(), &key).await? {
+                entries.push((key, value));
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+}
+
+fn encode_key(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name
+}
+
+// None for names `encode_key` cannot have produced.
+fn decode_key(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            bytes.push(byte);
+            rest = tail;
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(bytes).ok().filter(|key| !key.is_empty())
+}
+
+// Entries are plain Redis strings at `<key_prefix><namespace>:<key>`, with
+// Redis keeping the TTL. The connection is made on first use and remade
+// after failures.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    client: redis::Client,
+    connection: tokio::sync::OnceCell<redis::aio::ConnectionManager>,
+    key_prefix: String,
+    connect_timeout: Duration,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    pub fn new(config: &RedisStorageConfig) -> io::Result<Self> {
+        let client = redis::Client::open(config.url.as_str()).map_err(redis_error)?;
+        Ok(Self {
+            client,
+            connection: tokio::sync::OnceCell::new(),
+            key_prefix: config.key_prefix.clone(),
+            connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+        })
+    }
+
+    async fn connection(&self) -> io::Result<redis::aio::ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| async {
+                tokio::time::timeout(self.connect_timeout, redis::aio::ConnectionManager::new(self.client.clone()))
+                    .await
+                    .map_err(|_| io::Error::new(ErrorKind::TimedOut, "connecting to redis timed out"))?
+                    .map_err(redis_error)
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+
+    fn redis_key(&self, namespace: &str, key: &str) -> String {
+        format!("{}{}:{}", self.key_prefix, namespace, key)
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_error(e: redis::RedisError) -> io::Error {
+    io::Error::other(e)
+}
+
+// SCAN patterns are globs; the namespace and prefix match literally.
+#[cfg(feature = "redis")]
+fn glob_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\' | '^') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl KeyValueStore for RedisStore {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Redis
+    }
+
+    async fn get(&self, namespace: &str, key: &str) -> io::Result<Option<Vec<u8>>> {
+        check_names(namespace, key)?;
+        let mut connection = self.connection().await?;
+        redis::cmd("GET")
+            .arg(self.redis_key(namespace, key))
+            .query_async(&mut connection)
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn put(&self, namespace: &str, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        check_names(namespace, key)?;
+        let mut connection = self.connection().await?;
+        let mut command = redis::cmd("SET");
+        command.arg(self.redis_key(namespace, key)).arg(value);
+        if let Some(ttl) = ttl {
+            command.arg("PX").arg((ttl.as_millis() as u64).max(1));
+        }
+        command.query_async(&mut connection).await.map_err(redis_error)
+    }
+
+    async fn delete(&self, namespace: &str, key: &str) -> io::Result<()> {
+        check_names(namespace, key)?;
+        let mut connection = self.connection().await?;
+        redis::cmd("DEL")
+            .arg(self.redis_key(namespace, key))
+            .query_async(&mut connection)
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn scan(&self, namespace: &str, prefix: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+        check_names(namespace, "-")?;
+        let mut connection = self.connection().await?;
+        let base = self.redis_key(namespace, "");
+        let pattern = format!("{}*", glob_escape(&format!("{}{}", base, prefix)));
+        let mut keys: Vec<String> = Vec::new();
+        let mut cursor = 0u64;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut connection)
+                .await
+                .map_err(redis_error)?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        // SCAN may return a key twice.
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut connection)
+            .await
+            .map_err(redis_error)?;
+        // Entries that expired between the scan and the read are left out.
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key.strip_prefix(&base)?.to_string(), value?)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // What every backend must do, run against each of them.
+    async fn conformance(store: &dyn KeyValueStore) {
+        assert_eq!(store.get("quotas", "missing").await.unwrap(), None);
+        store.put("quotas", "usage", b"one", None).await.unwrap();
+        store.put("quotas", "usage", b"two", None).await.unwrap();
+        assert_eq!(store.get("quotas", "usage").await.unwrap(), Some(b"two".to_vec()));
+        assert_eq!(store.get("ai", "usage").await.unwrap(), None, "namespaces are separate");
+
+        // Keys may hold anything a client sends.
+        let odd = "key/with spaces.and:colons*?%";
+        store.put("quotas", odd, &[0, 255, 10], None).await.unwrap();
+        assert_eq!(store.get("quotas", odd).await.unwrap(), Some(vec![0, 255, 10]));
+
+        store.put("bans", "ip:10.0.0.2", b"b", None).await.unwrap();
+        store.put("bans", "ip:10.0.0.1", b"a", None).await.unwrap();
+        store.put("bans", "key:abc", b"c", None).await.unwrap();
+        let ips = store.scan("bans", "ip:").await.unwrap();
+        assert_eq!(
+            ips,
+            vec![("ip:10.0.0.1".to_string(), b"a".to_vec()), ("ip:10.0.0.2".to_string(), b"b".to_vec())]
+        );
+        assert_eq!(store.scan("bans", "").await.unwrap().len(), 3);
+        assert_eq!(store.scan("empty", "").await.unwrap(), Vec::new());
+
+        store.delete("bans", "key:abc").await.unwrap();
+        store.delete("bans", "key:abc").await.unwrap();
+        assert_eq!(store.get("bans", "key:abc").await.unwrap(), None);
+
+        store.put("dedup", "short", b"x", Some(Duration::from_millis(50))).await.unwrap();
+        store.put("dedup", "long", b"y", Some(Duration::from_secs(60))).await.unwrap();
+        assert_eq!(store.get("dedup", "short").await.unwrap(), Some(b"x".to_vec()));
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(store.get("dedup", "short").await.unwrap(), None);
+        assert_eq!(store.scan("dedup", "").await.unwrap(), vec![("long".to_string(), b"y".to_vec())]);
+
+        assert_eq!(store.get("quotas", "").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(store.get("../etc", "passwd").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_file_store_conformance() {
+        let dir = temp_dir("conformance");
+        let store = FileStore::new(dir.clone(), Arc::new(SystemClock)).unwrap();
+        conformance(&store).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Needs a server to talk to, named by REDIS_URL; skipped without one.
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_redis_store_conformance() {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            eprintln!("REDIS_URL not set, skipping");
+            return;
+        };
+        let config = RedisStorageConfig {
+            url,
+            key_prefix: format!("conformance-{}:", uuid::Uuid::new_v4().simple()),
+            ..RedisStorageConfig::default()
+        };
+        conformance(&RedisStore::new(&config).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_store_survives_interrupted_writes() {
+        let dir = temp_dir("crash");
+        let store = FileStore::new(dir.clone(), Arc::new(SystemClock)).unwrap();
+        store.put("quotas", "usage", b"saved", None).await.unwrap();
+        // What a crash between the write and the rename leaves behind.
+        std::fs::write(dir.join("quotas").join("usage.0123.tmp"), b"half").unwrap();
+        assert_eq!(store.get("quotas", "usage").await.unwrap(), Some(b"saved".to_vec()));
+        assert_eq!(store.scan("quotas", "").await.unwrap().len(), 1);
+
+        std::fs::write(dir.join("quotas").join("cut"), [0, 0]).unwrap();
+        let reports = check(&store, &NAMESPACES).await;
+        assert_eq!(reports[0], NamespaceReport { namespace: "ai", entries: 0, error: None });
+        assert!(reports[1].error.as_deref().unwrap().contains("truncated"), "{:?}", reports[1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_keys_round_trip_through_file_names() {
+        for key in ["usage", "a b/c", "ключ", "%41", "x.tmp"] {
+            let name = encode_key(key);
+            assert!(name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'%'));
+            assert_eq!(decode_key(&name).as_deref(), Some(key));
+        }
+        assert_eq!(decode_key("usage.1234.tmp"), None);
+        assert_eq!(decode_key("%4"), None);
+    }
+
+    #[test]
+    fn test_backend_settings_checked() {
+        assert_eq!(StorageConfig::default().problem(), Some("the file backend needs a path"));
+        let file = StorageConfig { path: Some(PathBuf::from("state")), ..StorageConfig::default() };
+        assert_eq!(file.problem(), None);
+        let redis = StorageConfig { backend: StorageBackend::Redis, ..StorageConfig::default() };
+        assert_eq!(redis.problem().is_some(), !cfg!(feature = "redis"));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::metrics::MetricsCollector;
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+// Longer heads and chunk lines are left to hyper's own limits; the connection
+// is passed through unchecked from then on.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+const MAX_LINE_BYTES: usize = 4096;
+
+// Request shapes that different HTTP/1 parsers disagree on, and so can be used
+// to smuggle a second request past the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    ContentLengthWithTransferEncoding,
+    ConflictingContentLength,
+    ObsFold,
+    InvalidHeaderName,
+}
+
+impl Violation {
+    pub fn code(self) -> &'static str {
+        match self {
+            Violation::ContentLengthWithTransferEncoding => "content_length_with_transfer_encoding",
+            Violation::ConflictingContentLength => "conflicting_content_length",
+            Violation::ObsFold => "obs_fold",
+            Violation::InvalidHeaderName => "invalid_header_name",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Violation::ContentLengthWithTransferEncoding => "Request has both Content-Length and Transfer-Encoding",
+            Violation::ConflictingContentLength => "Request has conflicting Content-Length values",
+            Violation::ObsFold => "Request uses obsolete header line folding",
+            Violation::InvalidHeaderName => "Request has an invalid header name",
+        }
+    }
+
+}
+
+// How the bytes after a request head are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    // An upgrade, CONNECT, the HTTP/2 preface, or framing hyper will reject
+    // itself; nothing after it is checked.
+    Opaque,
+}
+
+// Checks one request head: the request line and header lines, without the
+// blank line that ends it.
+fn check_head(head: &[u8]) -> Result<Framing, Violation> {
+    let mut lines = head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let request_line = lines.next().unwrap_or_default();
+    let mut opaque = request_line.starts_with(b"PRI * HTTP/2.0") || request_line.starts_with(b"CONNECT ");
+
+    let mut lengths = Vec::new();
+    let mut invalid_length = false;
+    let mut transfer_encoding = false;
+    let mut chunked = false;
+    for line in lines {
+        if line.first().is_some_and(|b| *b == b' ' || *b == b'\t') {
+            return Err(Violation::ObsFold);
+        }
+        let colon = line.iter().position(|b| *b == b':').ok_or(Violation::InvalidHeaderName)?;
+        let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+        if name.is_empty() || !name.iter().all(|b| is_tchar(*b)) {
+            return Err(Violation::InvalidHeaderName);
+        }
+        if name.eq_ignore_ascii_case(b"content-length") {
+            for part in value.split(|b| *b == b',') {
+                match std::str::from_utf8(part.trim_ascii()).ok().and_then(|part| part.parse::<u64>().ok()) {
+                    Some(length) => lengths.push(length),
+                    None => invalid_length = true,
+                }
+            }
+        } else if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            transfer_encoding = true;
+            chunked = value
+                .rsplit(|b| *b == b',')
+                .next()
+                .is_some_and(|last| last.trim_ascii().eq_ignore_ascii_case(b"chunked"));
+        } else if name.eq_ignore_ascii_case(b"upgrade") {
+            opaque = true;
+        }
+    }
+
+    if transfer_encoding && (invalid_length || !lengths.is_empty()) {
+        return Err(Violation::ContentLengthWithTransferEncoding);
+    }
+    if lengths.windows(2).any(|pair| pair[0] != pair[1]) {
+        return Err(Violation::ConflictingContentLength);
+    }
+    if opaque || invalid_length || (transfer_encoding && !chunked) {
+        return Ok(Framing::Opaque);
+    }
+    Ok(match (chunked, lengths.first()) {
+        (true, _) => Framing::Chunked,
+        (false, None | Some(0)) => Framing::Empty,
+        (false, Some(length)) => Framing::Length(*length),
+    })
+}
+
+// RFC 9110 token characters.
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    ChunkSize,
+    ChunkEnd,
+    Trailer,
+}
+
+#[derive(Debug)]
+enum State {
+    Head(Vec<u8>),
+    Body(u64),
+    Line(LineKind, Vec<u8>),
+    ChunkData(u64),
+    Opaque,
+}
+
+// Follows HTTP/1 message framing through the bytes a connection reads, so every
+// request head is checked, not just the first. Head bytes are held back until
+// the whole head has passed.
+#[derive(Debug)]
+struct Framer {
+    state: State,
+    // Heads released so far.
+    heads: u64,
+}
+
+impl Framer {
+    fn new() -> Self {
+        Self {
+            state: State::Head(Vec::new()),
+            heads: 0,
+        }
+    }
+
+    // Appends to `out` whatever of `data` may go on to hyper. Stops at the
+    // first head that fails, dropping it and everything after.
+    fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> Option<Violation> {
+        let mut i = 0;
+        while i < data.len() {
+            match &mut self.state {
+                State::Opaque => {
+                    out.extend_from_slice(&data[i..]);
+                    return None;
+                }
+                State::Head(head) => {
+                    let b = data[i];
+                    i += 1;
+                    // Blank lines before a request line are ignored.
+                    if head.is_empty() && (b == b'\r' || b == b'\n') {
+                        out.push(b);
+                        continue;
+                    }
+                    head.push(b);
+                    let ended = head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n");
+                    if !ended {
+                        if head.len() > MAX_HEAD_BYTES {
+                            out.append(head);
+                            self.state = State::Opaque;
+                        }
+                        continue;
+                    }
+                    let framing = check_head(head.trim_ascii_end());
+                    if let Err(violation) = framing {
+                        return Some(violation);
+                    }
+                    out.append(head);
+                    self.heads += 1;
+                    self.state = match framing {
+                        Ok(Framing::Empty) => State::Head(Vec::new()),
+                        Ok(Framing::Length(length)) => State::Body(length),
+                        Ok(Framing::Chunked) => State::Line(LineKind::ChunkSize, Vec::new()),
+                        _ => State::Opaque,
+                    };
+                }
+                State::Body(remaining) | State::ChunkData(remaining) => {
+                    let taken = (*remaining).min((data.len() - i) as u64);
+                    out.extend_from_slice(&data[i..i + taken as usize]);
+                    *remaining -= taken;
+                    i += taken as usize;
+                    if *remaining == 0 {
+                        self.state = match self.state {
+                            State::Body(_) => State::Head(Vec::new()),
+                            _ => State::Line(LineKind::ChunkEnd, Vec::new()),
+                        };
+                    }
+                }
+                State::Line(kind, line) => {
+                    let b = data[i];
+                    i += 1;
+                    out.push(b);
+                    if b != b'\n' {
+                        line.push(b);
+                        if line.len() > MAX_LINE_BYTES {
+                            self.state = State::Opaque;
+                        }
+                        continue;
+                    }
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    self.state = match kind {
+                        LineKind::ChunkSize => {
+                            let size = line.split(|b| *b == b';').next().unwrap_or_default().trim_ascii();
+                            let size = std::str::from_utf8(size).ok().and_then(|size| u64::from_str_radix(size, 16).ok());
+                            match size {
+                                Some(0) => State::Line(LineKind::Trailer, Vec::new()),
+                                Some(size) => State::ChunkData(size),
+                                None => State::Opaque,
+                            }
+                        }
+                        LineKind::ChunkEnd if line.is_empty() => State::Line(LineKind::ChunkSize, Vec::new()),
+                        LineKind::ChunkEnd => State::Opaque,
+                        LineKind::Trailer if line.is_empty() => State::Head(Vec::new()),
+                        LineKind::Trailer => State::Line(LineKind::Trailer, Vec::new()),
+                    };
+                }
+            }
+        }
+        None
+    }
+
+    // A head cut off by end of stream goes to hyper to report as incomplete.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if let State::Head(head) = &mut self.state {
+            out.append(head);
+        }
+    }
+}
+
+// Stands in for a rejected head so hyper answers it in order, after any
+// requests before it on the connection.
+const REJECTED_HEAD: &[u8] = b"GET / HTTP/1.1\r\nhost: strict-http.invalid\r\nconnection: close\r\n\r\n";
+
+// Shared between a connection's IO and its service: which request, counted
+// from zero, stands in for a rejected head.
+#[derive(Clone, Default)]
+pub struct Rejections {
+    rejected: Arc<Mutex<Option<(u64, Violation)>>>,
+}
+
+impl Rejections {
+    pub fn take(&self, request_index: u64) -> Option<Violation> {
+        let mut rejected = self.rejected.lock().unwrap();
+        match *rejected {
+            Some((index, violation)) if index == request_index => {
+                *rejected = None;
+                Some(violation)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Sits between the client socket and hyper. With strict_http on, a request
+// head that fails the checks never reaches hyper; the connection's service
+// answers the stand-in request with a 400 and the connection closes.
+pub struct StrictHttpIo<S> {
+    inner: S,
+    framer: Option<Framer>,
+    metrics: Arc<MetricsCollector>,
+    rejections: Rejections,
+    // Checked bytes not yet handed to hyper.
+    released: Vec<u8>,
+    read_buf: Box<[u8]>,
+    eof: bool,
+    // Nothing is read after a rejected head. hyper would drop its response on
+    // seeing end of stream, so reads stay pending until it closes the
+    // connection after answering.
+    rejected: bool,
+}
+
+impl<S> StrictHttpIo<S> {
+    pub fn new(inner: S, strict: bool, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            inner,
+            framer: strict.then(Framer::new),
+            metrics,
+            rejections: Rejections::default(),
+            released: Vec::new(),
+            read_buf: if strict { vec![0; 8192].into_boxed_slice() } else { Box::default() },
+            eof: false,
+            rejected: false,
+        }
+    }
+
+    pub fn rejections(&self) -> Rejections {
+        self.rejections.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for StrictHttpIo<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.released.is_empty() && this.rejected {
+            return Poll::Pending;
+        }
+        if this.framer.is_none() && this.released.is_empty() && !this.eof {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        while this.released.is_empty() && !this.eof {
+            let Some(framer) = &mut this.framer else {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            };
+            let mut read = ReadBuf::new(&mut this.read_buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                framer.finish(&mut this.released);
+                this.eof = true;
+                break;
+            }
+            if let Some(violation) = framer.feed(read.filled(), &mut this.released) {
+                this.metrics.record_strict_http_rejection(violation.code());
+                debug!(reason = violation.code(), "rejecting non-conforming request");
+                *this.rejections.rejected.lock().unwrap() = Some((framer.heads, violation));
+                this.released.extend_from_slice(REJECTED_HEAD);
+                this.framer = None;
+                this.rejected = true;
+            }
+        }
+        let n = this.released.len().min(buf.remaining());
+        buf.put_slice(&this.released[..n]);
+        this.released.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StrictHttpIo<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(lines: &[&str]) -> Vec<u8> {
+        lines.join("\r\n").into_bytes()
+    }
+
+    #[test]
+    fn test_head_checks() {
+        let cases = [
+            (vec!["POST / HTTP/1.1", "Content-Length: 5", "Transfer-Encoding: chunked"], Err(Violation::ContentLengthWithTransferEncoding)),
+            (vec!["POST / HTTP/1.1", "Content-Length: 5", "content-length: 6"], Err(Violation::ConflictingContentLength)),
+            (vec!["POST / HTTP/1.1", "Content-Length: 5, 6"], Err(Violation::ConflictingContentLength)),
+            (vec!["GET / HTTP/1.1", "X-A: one", " two"], Err(Violation::ObsFold)),
+            (vec!["GET / HTTP/1.1", "X A: one"], Err(Violation::InvalidHeaderName)),
+            (vec!["GET / HTTP/1.1", "X-A : one"], Err(Violation::InvalidHeaderName)),
+            (vec!["GET / HTTP/1.1", "no colon"], Err(Violation::InvalidHeaderName)),
+            (vec!["POST / HTTP/1.1", "Content-Length: 5", "Content-Length: 5"], Ok(Framing::Length(5))),
+            (vec!["POST / HTTP/1.1", "Transfer-Encoding: gzip, chunked"], Ok(Framing::Chunked)),
+            (vec!["GET / HTTP/1.1", "Upgrade: websocket"], Ok(Framing::Opaque)),
+            (vec!["GET / HTTP/1.1", "Host: a"], Ok(Framing::Empty)),
+        ];
+        for (lines, expected) in cases {
+            assert_eq!(check_head(&head(&lines)), expected, "{:?}", lines);
+        }
+    }
+
+    #[test]
+    fn test_framer_checks_every_request_on_a_connection() {
+        let stream = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcPOST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3;ext\r\nxyz\r\n0\r\nTrailer: t\r\n\r\n\r\nGET /c HTTP/1.1\r\nBad Name: x\r\n\r\nGET /d HTTP/1.1\r\n\r\n";
+        let start = stream.windows(6).position(|w| w == b"GET /c").unwrap();
+
+        // Reads split anywhere release everything before the bad head and
+        // nothing of it or after it.
+        for split in [1, 7, 40, stream.len()] {
+            let mut framer = Framer::new();
+            let mut out = Vec::new();
+            let found = stream.chunks(split).find_map(|chunk| framer.feed(chunk, &mut out));
+            assert_eq!(found, Some(Violation::InvalidHeaderName), "split {}", split);
+            assert_eq!(out, &stream[..start], "split {}", split);
+            assert_eq!(framer.heads, 2);
+        }
+
+        let mut framer = Framer::new();
+        let upgraded = b"GET / HTTP/1.1\r\nUpgrade: x\r\n\r\nBad Name: x\r\n\r\n";
+        let mut out = Vec::new();
+        assert_eq!(framer.feed(upgraded, &mut out), None);
+        assert_eq!(out, upgraded);
+    }
+}
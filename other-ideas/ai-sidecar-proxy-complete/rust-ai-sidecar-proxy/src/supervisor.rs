@@ -0,0 +1,316 @@
+use crate::{config::SupervisorConfig, metrics::MetricsCollector};
+use dashmap::DashMap;
+use futures::FutureExt;
+use serde::Serialize;
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    Stalled,
+    Restarting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub critical: bool,
+    pub state: TaskState,
+    pub last_heartbeat: u64,
+    pub heartbeat_age_ms: u64,
+    pub restarts: u64,
+    pub panics: u64,
+    pub last_error: Option<String>,
+}
+
+struct TaskRecord {
+    critical: bool,
+    running: bool,
+    last_heartbeat: Instant,
+    last_heartbeat_unix: u64,
+    exited_at: Option<Instant>,
+    restarts: u64,
+    panics: u64,
+    last_error: Option<String>,
+}
+
+impl TaskRecord {
+    // When the task stopped doing useful work, if it has.
+    fn unhealthy_since(&self, stall_after: Duration) -> Option<Instant> {
+        if !self.running {
+            return self.exited_at;
+        }
+        let stalled_at = self.last_heartbeat + stall_after;
+        (Instant::now() >= stalled_at).then_some(stalled_at)
+    }
+}
+
+// Handed to a supervised task; the task calls `beat` once per unit of work.
+#[derive(Clone)]
+pub struct Heartbeat {
+    record: Arc<Mutex<TaskRecord>>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        let mut record = self.record.lock().unwrap();
+        record.last_heartbeat = Instant::now();
+        record.last_heartbeat_unix = now_secs();
+    }
+}
+
+// Runs background loops, restarting them with backoff when they panic or return.
+pub struct TaskSupervisor {
+    config: SupervisorConfig,
+    metrics: Arc<MetricsCollector>,
+    tasks: DashMap<String, Arc<Mutex<TaskRecord>>>,
+    handles: Mutex<Vec<AbortHandle>>,
+}
+
+impl TaskSupervisor {
+    pub fn new(config: SupervisorConfig, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            config,
+            metrics,
+            tasks: DashMap::new(),
+            handles: Mutex::new(Vec::new()),
+        }
+    }
+
+    // `run` builds a fresh future for every (re)start, so it must not rely on
+    // state a panicking attempt may have left behind.
+    pub fn spawn<F, Fut>(self: &Arc<Self>, name: &str, critical: bool, run: F)
+    where
+        F: Fn(Heartbeat) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let record = Arc::new(Mutex::new(TaskRecord {
+            critical,
+            running: true,
+            last_heartbeat: Instant::now(),
+            last_heartbeat_unix: now_secs(),
+            exited_at: None,
+            restarts: 0,
+            panics: 0,
+            last_error: None,
+        }));
+        self.tasks.insert(name.to_string(), record.clone());
+
+        let supervisor = self.clone();
+        let name = name.to_string();
+        let handle = tokio::spawn(async move {
+            let initial_backoff = Duration::from_millis(supervisor.config.initial_backoff_ms);
+            let max_backoff = Duration::from_millis(supervisor.config.max_backoff_ms);
+            let mut backoff = initial_backoff;
+
+            loop {
+                let heartbeat = Heartbeat { record: record.clone() };
+                heartbeat.beat();
+                record.lock().unwrap().running = true;
+
+                let started = Instant::now();
+                let outcome = AssertUnwindSafe(async { run(heartbeat).await }).catch_unwind().await;
+
+                let message = match outcome {
+                    Ok(()) => "task exited".to_string(),
+                    Err(payload) => {
+                        supervisor.metrics.record_task_panic(&name);
+                        record.lock().unwrap().panics += 1;
+                        format!("panicked: {}", panic_message(payload.as_ref()))
+                    }
+                };
+                error!(task = %name, error = %message, "background task stopped");
+
+                {
+                    let mut record = record.lock().unwrap();
+                    record.running = false;
+                    record.exited_at = Some(Instant::now());
+                    record.last_error = Some(message);
+                }
+
+                // A run that outlived the longest backoff was healthy for a while.
+                if started.elapsed() >= max_backoff {
+                    backoff = initial_backoff;
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
+
+                record.lock().unwrap().restarts += 1;
+                supervisor.metrics.record_task_restart(&name);
+                info!(task = %name, "restarting background task");
+            }
+        });
+        self.handles.lock().unwrap().push(handle.abort_handle());
+    }
+
+    // Stops every task for good; used when an embedded server shuts down.
+    pub fn shutdown(&self) {
+        for handle in self.handles.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+
+    pub fn status(&self) -> Vec<TaskStatus> {
+        let stall_after = Duration::from_millis(self.config.stall_after_ms);
+        let mut statuses: Vec<TaskStatus> = self
+            .tasks
+            .iter()
+            .map(|entry| {
+                let record = entry.value().lock().unwrap();
+                let state = if !record.running {
+                    TaskState::Restarting
+                } else if record.unhealthy_since(stall_after).is_some() {
+                    TaskState::Stalled
+                } else {
+                    TaskState::Running
+                };
+                TaskStatus {
+                    name: entry.key().clone(),
+                    critical: record.critical,
+                    state,
+                    last_heartbeat: record.last_heartbeat_unix,
+                    heartbeat_age_ms: record.last_heartbeat.elapsed().as_millis() as u64,
+                    restarts: record.restarts,
+                    panics: record.panics,
+                    last_error: record.last_error.clone(),
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+
+    // Not ready once any critical task has been dead or stalled past the threshold.
+    pub fn is_ready(&self) -> bool {
+        let stall_after = Duration::from_millis(self.config.stall_after_ms);
+        let dead_after = Duration::from_millis(self.config.critical_dead_after_ms);
+
+        let dead = self.tasks.iter().find(|entry| {
+            let record = entry.value().lock().unwrap();
+            record.critical
+                && record
+                    .unhealthy_since(stall_after)
+                    .is_some_and(|since| since.elapsed() >= dead_after)
+        });
+
+        if let Some(entry) = dead {
+            debug!(task = %entry.key(), "critical background task is down");
+            return false;
+        }
+        true
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn supervisor(config: SupervisorConfig) -> (Arc<TaskSupervisor>, Arc<MetricsCollector>) {
+        let metrics = Arc::new(MetricsCollector::new());
+        (Arc::new(TaskSupervisor::new(config, metrics.clone())), metrics)
+    }
+
+    fn fast_config() -> SupervisorConfig {
+        SupervisorConfig {
+            initial_backoff_ms: 10,
+            max_backoff_ms: 50,
+            stall_after_ms: 200,
+            critical_dead_after_ms: 100,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restarts_panicked_task() {
+        let (supervisor, metrics) = supervisor(fast_config());
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let counter = attempts.clone();
+        supervisor.spawn("flaky", false, move |heartbeat| {
+            let attempt = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    panic!("boom");
+                }
+                loop {
+                    heartbeat.beat();
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let status = &supervisor.status()[0];
+        assert_eq!(status.name, "flaky");
+        assert_eq!(status.state, TaskState::Running);
+        assert_eq!(status.restarts, 1);
+        assert_eq!(status.panics, 1);
+        assert_eq!(status.last_error.as_deref(), Some("panicked: boom"));
+        assert!(status.heartbeat_age_ms < 100);
+        assert_eq!(metrics.task_panic_count("flaky"), 1);
+        assert!(supervisor.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_dead_critical_task_flips_readiness() {
+        let (supervisor, _) = supervisor(SupervisorConfig {
+            initial_backoff_ms: 10_000,
+            max_backoff_ms: 10_000,
+            ..fast_config()
+        });
+
+        supervisor.spawn("health_checker", true, |_| async { panic!("probe loop died") });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let status = &supervisor.status()[0];
+        assert_eq!(status.state, TaskState::Restarting);
+        assert!(supervisor.is_ready());
+
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(!supervisor.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_task_reported() {
+        let (supervisor, _) = supervisor(SupervisorConfig {
+            stall_after_ms: 30,
+            ..fast_config()
+        });
+
+        supervisor.spawn("stuck", false, |_| std::future::pending());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let status = &supervisor.status()[0];
+        assert_eq!(status.state, TaskState::Stalled);
+        assert_eq!(status.restarts, 0);
+        assert!(supervisor.is_ready());
+    }
+}
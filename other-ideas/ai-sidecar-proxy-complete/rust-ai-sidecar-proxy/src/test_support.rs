@@ -0,0 +1,206 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{service::service_fn, Response};
+use hyper_util::rt::TokioIo;
+#[cfg(feature = "tls")]
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+#[cfg(feature = "tls")]
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "tls")]
+pub struct TestPki {
+    pub dir: PathBuf,
+    pub ca_cert_der: CertificateDer<'static>,
+    pub server_cert_der: CertificateDer<'static>,
+    pub server_key_der: PrivatePkcs8KeyDer<'static>,
+    pub ca_path: PathBuf,
+    pub server_cert_path: PathBuf,
+    pub server_key_path: PathBuf,
+    pub client_cert_path: PathBuf,
+    pub client_key_path: PathBuf,
+}
+
+#[cfg(feature = "tls")]
+impl TestPki {
+    pub fn generate() -> Self {
+        let dir = std::env::temp_dir().join(format!("ai-sidecar-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "ai-sidecar-proxy test ca");
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let mut server_params =
+            CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap();
+        server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server_cert = server_params.signed_by(&server_key, &ca_cert, &ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec!["proxy".to_string()]).unwrap();
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_cert = client_params.signed_by(&client_key, &ca_cert, &ca_key).unwrap();
+
+        let write = |name: &str, contents: String| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+
+        Self {
+            ca_path: write("ca.pem", ca_cert.pem()),
+            server_cert_path: write("server.pem", server_cert.pem()),
+            server_key_path: write("server.key", server_key.serialize_pem()),
+            client_cert_path: write("client.pem", client_cert.pem()),
+            client_key_path: write("client.key", client_key.serialize_pem()),
+            ca_cert_der: ca_cert.der().clone(),
+            server_cert_der: server_cert.der().clone(),
+            server_key_der: PrivatePkcs8KeyDer::from(server_key.serialize_der()),
+            dir,
+        }
+    }
+
+    pub fn path(&self, path: &std::path::Path) -> Option<String> {
+        Some(path.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Drop for TestPki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// HTTPS upstream answering 200 to everything, but only to clients presenting a
+// certificate issued by the test CA.
+#[cfg(feature = "tls")]
+pub async fn spawn_mtls_upstream(pki: &TestPki) -> SocketAddr {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut roots = RootCertStore::empty();
+    roots.add(pki.ca_cert_der.clone()).unwrap();
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .unwrap();
+
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            vec![pki.server_cert_der.clone()],
+            PrivateKeyDer::Pkcs8(pki.server_key_der.clone_key()),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(tls) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let service = service_fn(|_req| async {
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok"))))
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(tls), service)
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
+
+// Plain-HTTP stand-in for the Kubernetes API server. `respond` gets each
+// request's path and query and returns the body, or None to hold the request
+// open as an idle watch would. Requests are logged with their authorization.
+#[cfg(feature = "kubernetes")]
+pub async fn spawn_fake_api_server<F>(respond: F) -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+{
+    spawn_fake_http("authorization", move |target| respond(target).map(|body| Response::new(Full::new(Bytes::from(body)))))
+        .await
+}
+
+// The same for a Consul agent: `respond` also gives the X-Consul-Index to
+// answer with, and requests are logged with their X-Consul-Token.
+pub async fn spawn_fake_consul<F>(respond: F) -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> Option<(u64, String)> + Send + Sync + 'static,
+{
+    spawn_fake_http("x-consul-token", move |target| {
+        respond(target).map(|(index, body)| {
+            Response::builder()
+                .header("x-consul-index", index)
+                .header("content-type", "application/json")
+                .body(Full::new(Bytes::from(body)))
+                .unwrap()
+        })
+    })
+    .await
+}
+
+async fn spawn_fake_http<F>(logged_header: &'static str, respond: F) -> (SocketAddr, Arc<std::sync::Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> Option<Response<Full<Bytes>>> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = requests.clone();
+    let respond = Arc::new(respond);
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let log = log.clone();
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                    let target = req.uri().to_string();
+                    let logged = req.headers().get(logged_header).and_then(|value| value.to_str().ok());
+                    log.lock().unwrap().push(format!("{} {}", target, logged.unwrap_or("")));
+                    let response = respond(&target);
+                    async move {
+                        let Some(response) = response else {
+                            return std::future::pending().await;
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    (addr, requests)
+}
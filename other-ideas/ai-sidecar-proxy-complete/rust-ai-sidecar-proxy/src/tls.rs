@@ -0,0 +1,651 @@
+use crate::{
+    config::{ListenerTlsConfig, TlsVersion},
+    metrics::MetricsCollector,
+    supervisor::TaskSupervisor,
+};
+use anyhow::{Context, Result};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ProducesTickets, ResolvesServerCert, ServerSessionMemoryCache},
+    sign::CertifiedKey,
+    HandshakeKind, ServerConfig, SupportedProtocolVersion,
+};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, info, warn};
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 4;
+
+struct TicketKey {
+    id: [u8; KEY_ID_LEN],
+    key: LessSafeKey,
+}
+
+impl TicketKey {
+    fn new(material: &[u8; KEY_LEN]) -> Self {
+        // Derived from the key so instances sharing a key file agree on the id.
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&digest(&SHA256, material).as_ref()[..KEY_ID_LEN]);
+        let key = UnboundKey::new(&CHACHA20_POLY1305, material).expect("key has the AEAD length");
+        Self {
+            id,
+            key: LessSafeKey::new(key),
+        }
+    }
+}
+
+struct TicketKeys {
+    current: Arc<TicketKey>,
+    previous: Option<Arc<TicketKey>>,
+}
+
+// Session ticket encryption with two live keys: rotating promotes a fresh key
+// and keeps the old one for decryption only, so tickets issued just before a
+// rotation still resume for one more period.
+pub struct Ticketer {
+    keys: RwLock<TicketKeys>,
+    lifetime: u32,
+    key_path: Option<PathBuf>,
+    rng: SystemRandom,
+}
+
+impl fmt::Debug for Ticketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ticketer")
+            .field("lifetime", &self.lifetime)
+            .field("key_path", &self.key_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Ticketer {
+    pub fn new(rotation: Duration, key_path: Option<PathBuf>) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let current = Self::next_key(&rng, key_path.as_deref())?;
+        Ok(Self {
+            keys: RwLock::new(TicketKeys {
+                current: Arc::new(current),
+                previous: None,
+            }),
+            lifetime: rotation.as_secs().min(u32::MAX as u64) as u32,
+            key_path,
+            rng,
+        })
+    }
+
+    fn next_key(rng: &SystemRandom, key_path: Option<&Path>) -> Result<TicketKey> {
+        let mut material = [0u8; KEY_LEN];
+        match key_path {
+            Some(path) => {
+                let hex = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading ticket key {}", path.display()))?;
+                decode_hex(hex.trim(), &mut material)
+                    .with_context(|| format!("ticket key {} must be {} hex-encoded bytes", path.display(), KEY_LEN))?;
+            }
+            None => rng
+                .fill(&mut material)
+                .map_err(|_| anyhow::anyhow!("generating ticket key"))?,
+        }
+        Ok(TicketKey::new(&material))
+    }
+
+    // Returns whether the active key changed; a shared key file that has not
+    // been replaced since the last rotation leaves the keys as they are.
+    pub fn rotate(&self) -> Result<bool> {
+        let next = Self::next_key(&self.rng, self.key_path.as_deref())?;
+        let mut keys = self.keys.write().unwrap();
+        if next.id == keys.current.id {
+            return Ok(false);
+        }
+        let previous = std::mem::replace(&mut keys.current, Arc::new(next));
+        keys.previous = Some(previous);
+        Ok(true)
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let key = self.keys.read().unwrap().current.clone();
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+
+        let mut sealed = plain.to_vec();
+        key.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(key.id), &mut sealed)
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&key.id);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < KEY_ID_LEN + NONCE_LEN {
+            return None;
+        }
+        let (id, rest) = cipher.split_at(KEY_ID_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+
+        let key = {
+            let keys = self.keys.read().unwrap();
+            std::iter::once(&keys.current)
+                .chain(keys.previous.as_ref())
+                .find(|key| key.id == id)?
+                .clone()
+        };
+
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut buf = sealed.to_vec();
+        let plain_len = key.key.open_in_place(nonce, Aad::from(key.id), &mut buf).ok()?.len();
+        buf.truncate(plain_len);
+        Some(buf)
+    }
+}
+
+fn decode_hex(hex: &str, out: &mut [u8; KEY_LEN]) -> Result<()> {
+    anyhow::ensure!(hex.len() == KEY_LEN * 2, "expected {} hex digits, got {}", KEY_LEN * 2, hex.len());
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair)?;
+        *byte = u8::from_str_radix(pair, 16)?;
+    }
+    Ok(())
+}
+
+// Serves the listener certificate and swaps in a new one when the files on
+// disk change. Handshakes already under way keep the key they started with.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+    // Digest of the files last looked at, loaded or not, so a bad pair is
+    // reported once rather than on every poll.
+    seen: Mutex<Vec<u8>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CertResolver {
+    pub fn new(cert_path: &str, key_path: &str, provider: Arc<CryptoProvider>) -> Result<Self> {
+        let cert_path = PathBuf::from(cert_path);
+        let key_path = PathBuf::from(key_path);
+        let (cert_pem, key_pem) = Self::read(&cert_path, &key_path)?;
+        let certified = Self::load(&cert_path, &key_path, &cert_pem, &key_pem, &provider)?;
+        Ok(Self {
+            seen: Mutex::new(Self::fingerprint(&cert_pem, &key_pem)),
+            current: RwLock::new(Arc::new(certified)),
+            cert_path,
+            key_path,
+            provider,
+        })
+    }
+
+    fn read(cert_path: &Path, key_path: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+        let cert_pem = std::fs::read(cert_path)
+            .with_context(|| format!("reading certificate chain {}", cert_path.display()))?;
+        let key_pem = std::fs::read(key_path)
+            .with_context(|| format!("reading private key {}", key_path.display()))?;
+        Ok((cert_pem, key_pem))
+    }
+
+    fn fingerprint(cert_pem: &[u8], key_pem: &[u8]) -> Vec<u8> {
+        digest(&SHA256, &[cert_pem, key_pem].concat()).as_ref().to_vec()
+    }
+
+    fn load(
+        cert_path: &Path,
+        key_path: &Path,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        provider: &CryptoProvider,
+    ) -> Result<CertifiedKey> {
+        let certs = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("reading certificate chain {}", cert_path.display()))?;
+        anyhow::ensure!(!certs.is_empty(), "no certificates in {}", cert_path.display());
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .with_context(|| format!("reading private key {}", key_path.display()))?;
+        CertifiedKey::from_der(certs, key, provider).with_context(|| {
+            format!("{} does not match {}", key_path.display(), cert_path.display())
+        })
+    }
+
+    // Returns whether a new certificate was installed. A pair that fails to
+    // parse or whose key does not match the certificate leaves the current
+    // one in place.
+    pub fn reload(&self) -> Result<bool> {
+        let (cert_pem, key_pem) = Self::read(&self.cert_path, &self.key_path)?;
+        let fingerprint = Self::fingerprint(&cert_pem, &key_pem);
+        {
+            let mut seen = self.seen.lock().unwrap();
+            if *seen == fingerprint {
+                return Ok(false);
+            }
+            *seen = fingerprint;
+        }
+
+        let certified = Self::load(&self.cert_path, &self.key_path, &cert_pem, &key_pem, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(certified);
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// TLS termination for the client-facing listener.
+pub struct TlsTerminator {
+    acceptor: TlsAcceptor,
+    ticketer: Arc<Ticketer>,
+    rotation: Duration,
+    certs: Arc<CertResolver>,
+    cert_reload: Duration,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl TlsTerminator {
+    pub fn new(config: &ListenerTlsConfig, metrics: Arc<MetricsCollector>) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let certs = Arc::new(CertResolver::new(&config.cert_path, &config.key_path, provider.clone())?);
+
+        let rotation = Duration::from_secs(config.ticket_rotation_secs.max(1));
+        let ticketer = Arc::new(Ticketer::new(
+            rotation,
+            config.ticket_key_path.as_ref().map(PathBuf::from),
+        )?);
+
+        let versions: &[&'static SupportedProtocolVersion] = match config.min_version {
+            TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            TlsVersion::Tls13 => &[&rustls::version::TLS13],
+        };
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(versions)
+            .context("selecting TLS protocol versions")?
+            .with_no_client_auth()
+            .with_cert_resolver(certs.clone());
+        server_config.session_storage = ServerSessionMemoryCache::new(config.session_cache_size);
+        server_config.ticketer = ticketer.clone();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            ticketer,
+            rotation,
+            certs,
+            cert_reload: Duration::from_secs(config.cert_reload_secs.max(1)),
+            metrics,
+        })
+    }
+
+    // Polls the certificate files and installs a changed pair for new handshakes.
+    pub fn start_cert_reload(&self, supervisor: &Arc<TaskSupervisor>) {
+        let certs = self.certs.clone();
+        let metrics = self.metrics.clone();
+        let period = self.cert_reload;
+        supervisor.spawn("tls_cert_reload", false, move |heartbeat| {
+            let certs = certs.clone();
+            let metrics = metrics.clone();
+            async move {
+                let start = tokio::time::Instant::now() + period;
+                let mut interval = tokio::time::interval_at(start, period);
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    match certs.reload() {
+                        Ok(true) => {
+                            metrics.record_tls_cert_reload("success");
+                            info!(cert_path = %certs.cert_path.display(), "reloaded TLS certificate");
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            metrics.record_tls_cert_reload("failure");
+                            warn!(
+                                error = format!("{:#}", e),
+                                "failed to reload TLS certificate, keeping the current one"
+                            );
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn start_rotation(&self, supervisor: &Arc<TaskSupervisor>) {
+        let ticketer = self.ticketer.clone();
+        let rotation = self.rotation;
+        supervisor.spawn("tls_ticket_rotation", false, move |heartbeat| {
+            let ticketer = ticketer.clone();
+            async move {
+                let start = tokio::time::Instant::now() + rotation;
+                let mut interval = tokio::time::interval_at(start, rotation);
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                    match ticketer.rotate() {
+                        Ok(true) => info!("rotated TLS session ticket key"),
+                        Ok(false) => debug!("TLS session ticket key unchanged"),
+                        Err(e) => warn!(error = format!("{:#}", e), "failed to rotate TLS session ticket key"),
+                    }
+                }
+            }
+        });
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> Option<TlsStream<TcpStream>> {
+        let started = Instant::now();
+        match self.acceptor.accept(stream).await {
+            Ok(stream) => {
+                let (_, conn) = stream.get_ref();
+                let kind = match conn.handshake_kind() {
+                    Some(HandshakeKind::Resumed) => "resumed",
+                    _ => "full",
+                };
+                let protocol = conn
+                    .protocol_version()
+                    .and_then(|version| version.as_str())
+                    .unwrap_or("unknown");
+                let cipher = conn
+                    .negotiated_cipher_suite()
+                    .and_then(|suite| suite.suite().as_str())
+                    .unwrap_or("unknown");
+                self.metrics.record_tls_handshake(kind, protocol, cipher, started.elapsed());
+                Some(stream)
+            }
+            Err(e) => {
+                let reason = failure_reason(&e);
+                debug!(reason, error = %e, "TLS handshake failed");
+                self.metrics.record_tls_handshake_failure(reason);
+                None
+            }
+        }
+    }
+}
+
+fn failure_reason(error: &std::io::Error) -> &'static str {
+    let Some(tls_error) = error.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) else {
+        return match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => "eof",
+            _ => "io",
+        };
+    };
+
+    match tls_error {
+        rustls::Error::InvalidMessage(_) => "invalid_message",
+        rustls::Error::PeerIncompatible(_) => "peer_incompatible",
+        rustls::Error::PeerMisbehaved(_) => "peer_misbehaved",
+        rustls::Error::AlertReceived(_) => "alert_received",
+        rustls::Error::NoCertificatesPresented | rustls::Error::InvalidCertificate(_) => "certificate",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ai::AIEngine, config::Config, proxy::ProxyServer, test_support::TestPki};
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn test_rotation_keeps_previous_key() {
+        let ticketer = Ticketer::new(Duration::from_secs(60), None).unwrap();
+        let ticket = ticketer.encrypt(b"session state").unwrap();
+
+        assert!(ticketer.rotate().unwrap());
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+
+        assert!(ticketer.rotate().unwrap());
+        assert!(ticketer.decrypt(&ticket).is_none());
+        assert!(ticketer.decrypt(&ticket[..KEY_ID_LEN]).is_none());
+    }
+
+    #[test]
+    fn test_shared_key_file() {
+        let dir = std::env::temp_dir().join(format!("ai-sidecar-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ticket.key");
+        std::fs::write(&path, format!("{}\n", "ab".repeat(KEY_LEN))).unwrap();
+
+        let first = Ticketer::new(Duration::from_secs(60), Some(path.clone())).unwrap();
+        let second = Ticketer::new(Duration::from_secs(60), Some(path.clone())).unwrap();
+        let ticket = first.encrypt(b"shared").unwrap();
+        assert_eq!(second.decrypt(&ticket).unwrap(), b"shared");
+        assert!(!second.rotate().unwrap());
+
+        std::fs::write(&path, "cd".repeat(KEY_LEN)).unwrap();
+        assert!(second.rotate().unwrap());
+        assert_eq!(second.decrypt(&ticket).unwrap(), b"shared");
+
+        std::fs::write(&path, "not hex").unwrap();
+        assert!(first.rotate().is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_second_connection_resumes() {
+        let pki = TestPki::generate();
+        let mut config = Config::new();
+        config.proxy_config.tls = Some(ListenerTlsConfig {
+            cert_path: pki.path(&pki.server_cert_path).unwrap(),
+            key_path: pki.path(&pki.server_key_path).unwrap(),
+            session_cache_size: 64,
+            ticket_rotation_secs: 3600,
+            ticket_key_path: None,
+            cert_reload_secs: 10,
+            min_version: TlsVersion::Tls12,
+        });
+
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca_cert_der.clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let mut kinds = Vec::new();
+        for _ in 0..2 {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let mut tls = connector.connect("localhost".try_into().unwrap(), tcp).await.unwrap();
+            tls.write_all(b"GET /health HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            tls.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200"));
+            kinds.push(tls.get_ref().1.handshake_kind());
+        }
+
+        assert_eq!(kinds, vec![Some(HandshakeKind::Full), Some(HandshakeKind::Resumed)]);
+        assert_eq!(metrics.tls_handshake_count("full"), 1);
+        assert_eq!(metrics.tls_handshake_count("resumed"), 1);
+    }
+
+    fn client_trusting(pkis: &[&TestPki]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for pki in pkis {
+            roots.add(pki.ca_cert_der.clone()).unwrap();
+        }
+        let client_config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(client_config))
+    }
+
+    async fn presented_cert(connector: &TlsConnector, addr: std::net::SocketAddr) -> CertificateDer<'static> {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = connector.connect("localhost".try_into().unwrap(), tcp).await.unwrap();
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_rotated_certificate_served_to_new_connections() {
+        let old = TestPki::generate();
+        let new = TestPki::generate();
+        let cert_path = old.dir.join("live.pem");
+        let key_path = old.dir.join("live.key");
+        std::fs::copy(&old.server_cert_path, &cert_path).unwrap();
+        std::fs::copy(&old.server_key_path, &key_path).unwrap();
+
+        let mut config = Config::new();
+        config.proxy_config.tls = Some(ListenerTlsConfig {
+            cert_path: old.path(&cert_path).unwrap(),
+            key_path: old.path(&key_path).unwrap(),
+            session_cache_size: 64,
+            ticket_rotation_secs: 3600,
+            ticket_key_path: None,
+            cert_reload_secs: 1,
+            min_version: TlsVersion::Tls12,
+        });
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let connector = client_trusting(&[&old, &new]);
+        assert_eq!(presented_cert(&connector, addr).await, old.server_cert_der);
+
+        // An established connection keeps working across the swap.
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut established = connector.connect("localhost".try_into().unwrap(), tcp).await.unwrap();
+
+        std::fs::copy(&new.server_cert_path, &cert_path).unwrap();
+        std::fs::copy(&new.server_key_path, &key_path).unwrap();
+        let mut presented = presented_cert(&connector, addr).await;
+        for _ in 0..40 {
+            if presented == new.server_cert_der {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            presented = presented_cert(&connector, addr).await;
+        }
+        assert_eq!(presented, new.server_cert_der);
+        assert_eq!(metrics.tls_cert_reload_count("success"), 1);
+
+        established
+            .write_all(b"GET /health HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        established.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200"));
+        assert_eq!(established.get_ref().1.peer_certificates().unwrap()[0], old.server_cert_der);
+    }
+
+    #[tokio::test]
+    async fn test_min_version_refuses_older_clients() {
+        let pki = TestPki::generate();
+        let mut config = Config::new();
+        config.proxy_config.tls = Some(ListenerTlsConfig {
+            cert_path: pki.path(&pki.server_cert_path).unwrap(),
+            key_path: pki.path(&pki.server_key_path).unwrap(),
+            session_cache_size: 64,
+            ticket_rotation_secs: 3600,
+            ticket_key_path: None,
+            cert_reload_secs: 10,
+            min_version: TlsVersion::Tls13,
+        });
+        let metrics = Arc::new(MetricsCollector::new());
+        let proxy = ProxyServer::new(config, Arc::new(AIEngine::new()), metrics).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.serve(listener).await });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca_cert_der.clone()).unwrap();
+        let tls12_only = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let refused = TlsConnector::from(Arc::new(tls12_only))
+            .connect("localhost".try_into().unwrap(), tcp)
+            .await;
+        assert!(refused.is_err());
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = client_trusting(&[&pki])
+            .connect("localhost".try_into().unwrap(), tcp)
+            .await
+            .unwrap();
+        assert_eq!(tls.get_ref().1.protocol_version(), Some(rustls::ProtocolVersion::TLSv1_3));
+    }
+
+    #[test]
+    fn test_mismatched_pair_keeps_current_certificate() {
+        let old = TestPki::generate();
+        let other = TestPki::generate();
+        let cert_path = old.dir.join("live.pem");
+        let key_path = old.dir.join("live.key");
+        std::fs::copy(&old.server_cert_path, &cert_path).unwrap();
+        std::fs::copy(&old.server_key_path, &key_path).unwrap();
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = CertResolver::new(
+            &old.path(&cert_path).unwrap(),
+            &old.path(&key_path).unwrap(),
+            provider,
+        )
+        .unwrap();
+        assert!(!resolver.reload().unwrap());
+
+        std::fs::copy(&other.server_key_path, &key_path).unwrap();
+        let error = resolver.reload().unwrap_err();
+        assert!(format!("{:#}", error).contains("does not match"));
+        assert_eq!(resolver.current.read().unwrap().cert[0], old.server_cert_der);
+        assert!(!resolver.reload().unwrap());
+
+        std::fs::copy(&other.server_cert_path, &cert_path).unwrap();
+        assert!(resolver.reload().unwrap());
+        assert_eq!(resolver.current.read().unwrap().cert[0], other.server_cert_der);
+    }
+}
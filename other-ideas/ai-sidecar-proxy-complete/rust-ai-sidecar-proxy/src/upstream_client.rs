@@ -0,0 +1,574 @@
+use crate::{
+    address_family::AddressFamily,
+    config::{UpstreamAuthConfig, UpstreamService, UpstreamTlsConfig},
+    egress::Direction,
+    metrics::MetricsCollector,
+    proxy_protocol,
+    upstream_timing::{self, TimedConnect, TimedConnectLayer, TimedResolver},
+};
+#[cfg(feature = "tls")]
+use crate::upstream_timing::TimedSessionStore;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full};
+use hyper::{body::Incoming, header, Request, Response};
+#[cfg(feature = "tls")]
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{
+        connect::{HttpConnector, HttpInfo},
+        Client as HyperClient,
+    },
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use tower::Layer;
+#[cfg(feature = "tls")]
+use rustls::{
+    client::Resumption,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+
+// How the pooled upstream clients keep connections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamPoolConfig {
+    // Idle connections are closed after this long.
+    pub idle_timeout_ms: u64,
+    pub max_idle_per_endpoint: usize,
+    // TCP keepalive probes on upstream connections; the OS default without.
+    pub tcp_keepalive_ms: Option<u64>,
+}
+
+impl Default for UpstreamPoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_ms: 90_000,
+            max_idle_per_endpoint: 64,
+            tcp_keepalive_ms: Some(60_000),
+        }
+    }
+}
+
+impl UpstreamPoolConfig {
+    // Why the settings cannot work, if they cannot.
+    pub fn problem(&self) -> Option<&'static str> {
+        if self.idle_timeout_ms == 0 {
+            Some("idle_timeout_ms must be above 0")
+        } else if self.tcp_keepalive_ms == Some(0) {
+            Some("tcp_keepalive_ms must be above 0")
+        } else {
+            None
+        }
+    }
+}
+
+pub fn build_client(service: &UpstreamService, timeout: Duration) -> Result<Client> {
+    build_pooled_client(service, timeout, &UpstreamPoolConfig::default())
+}
+
+pub fn build_pooled_client(service: &UpstreamService, timeout: Duration, pool: &UpstreamPoolConfig) -> Result<Client> {
+    // Content codings are handled by the proxy per service, so reqwest must never
+    // decode behind our back and leave Content-Encoding/Length lying.
+    let builder = Client::builder();
+    #[cfg(feature = "tls")]
+    let builder = builder.use_preconfigured_tls(tls_config(service)?);
+    let mut builder = builder
+        .dns_resolver(Arc::new(TimedResolver::new(service.address_family, service.hosts.clone())))
+        .connector_layer(TimedConnectLayer)
+        .timeout(timeout)
+        .pool_idle_timeout(Duration::from_millis(pool.idle_timeout_ms))
+        .pool_max_idle_per_host(pool.max_idle_per_endpoint)
+        .tcp_keepalive(pool.tcp_keepalive_ms.map(Duration::from_millis))
+        .no_gzip()
+        .no_deflate()
+        .no_brotli()
+        .no_zstd();
+
+    if let Some(auth) = &service.auth {
+        let (name, value) = auth_header(auth)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value);
+        builder = builder.default_headers(headers);
+    }
+
+    builder.build().context("building upstream HTTP client")
+}
+
+fn auth_header(auth: &UpstreamAuthConfig) -> Result<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_bytes(auth.header.as_bytes())
+        .with_context(|| format!("invalid auth header name {:?}", auth.header))?;
+    let mut value = HeaderValue::from_str(&auth.value).context("invalid auth header value")?;
+    value.set_sensitive(true);
+    Ok((name, value))
+}
+
+// Request bodies as the upstream clients take them: buffered, or streamed
+// from the client as it arrives.
+pub type UpstreamBody = UnsyncBoxBody<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+pub fn full_body(bytes: Bytes) -> UpstreamBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed_unsync()
+}
+
+#[cfg(feature = "tls")]
+type Connector = TimedConnect<HttpsConnector<HttpConnector<TimedResolver>>>;
+// Plain HTTP only; config validation rejects https endpoints in this build.
+#[cfg(not(feature = "tls"))]
+type Connector = TimedConnect<HttpConnector<TimedResolver>>;
+
+// What proxied and pre-warm requests go through. Built on hyper rather than
+// reqwest so a request's method, header bytes and body reach the upstream
+// as they arrived, with no conversion on the way. Content codings are the
+// proxy's business, and hyper never decodes them. Probes use `build_client`.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    client: HyperClient<Connector, UpstreamBody>,
+    auth: Option<(HeaderName, HeaderValue)>,
+    timeout: Duration,
+    // Set for services with `proxy_protocol`.
+    announced: Option<Announced>,
+}
+
+impl UpstreamClient {
+    pub fn new(service: &UpstreamService, pool: &UpstreamPoolConfig) -> Result<Self> {
+        let mut http = HttpConnector::new_with_resolver(TimedResolver::new(
+            service.address_family,
+            service.hosts.clone(),
+        ));
+        http.set_nodelay(true);
+        http.set_keepalive(pool.tcp_keepalive_ms.map(Duration::from_millis));
+        #[cfg(feature = "tls")]
+        let http = {
+            http.enforce_http(false);
+            let mut tls = tls_config(service)?;
+            // The connector offers h2 and http/1.1 itself.
+            tls.alpn_protocols.clear();
+            HttpsConnectorBuilder::new()
+                .with_tls_config(tls)
+                .https_or_http()
+                .enable_http1()
+                .enable_http2()
+                .wrap_connector(http)
+        };
+        let client = HyperClient::builder(TokioExecutor::new())
+            .pool_idle_timeout(Duration::from_millis(pool.idle_timeout_ms))
+            .pool_max_idle_per_host(pool.max_idle_per_endpoint)
+            .pool_timer(TokioTimer::new())
+            .build(TimedConnectLayer.layer(http));
+        let announced = match service.proxy_protocol {
+            true => Some(Announced::new(service)?),
+            false => None,
+        };
+        Ok(Self {
+            client,
+            auth: service.auth.as_ref().map(auth_header).transpose()?,
+            timeout: Duration::from_millis(service.timeout_ms),
+            announced,
+        })
+    }
+
+    // The service's `timeout_ms`, for callers without a timeout of their own.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    // Waits at most `timeout` for the response head; reading the body is
+    // bounded by the caller. The service's auth header replaces any the
+    // client sent, so a client cannot stand in its own credential.
+    pub async fn send(&self, mut request: Request<UpstreamBody>, timeout: Duration) -> Result<Response<Incoming>> {
+        self.add_auth(&mut request);
+        match tokio::time::timeout(timeout, self.client.request(request)).await {
+            Ok(response) => Ok(response?),
+            Err(_) => anyhow::bail!("no response within {:?}", timeout),
+        }
+    }
+
+    // `send` on behalf of the client at `client`. A service with
+    // `proxy_protocol` gets the request on a connection of its own, opened
+    // with a PROXY header naming that client; the pool is never used, since
+    // the header speaks for every request on the connection.
+    pub async fn send_from(
+        &self,
+        mut request: Request<UpstreamBody>,
+        timeout: Duration,
+        client: SocketAddr,
+    ) -> Result<Response<Incoming>> {
+        let Some(announced) = &self.announced else {
+            return self.send(request, timeout).await;
+        };
+        self.add_auth(&mut request);
+        match tokio::time::timeout(timeout, announced.send(request, client)).await {
+            Ok(response) => response,
+            Err(_) => anyhow::bail!("no response within {:?}", timeout),
+        }
+    }
+
+    fn add_auth(&self, request: &mut Request<UpstreamBody>) {
+        if let Some((name, value)) = &self.auth {
+            request.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+}
+
+// Connections for a `proxy_protocol` service, one per request. They speak
+// HTTP/1.1 only.
+#[derive(Clone)]
+struct Announced {
+    resolver: TimedResolver,
+    #[cfg(feature = "tls")]
+    tls: TlsConnector,
+}
+
+impl Announced {
+    fn new(service: &UpstreamService) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        let tls = {
+            let mut tls = tls_config(service)?;
+            tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+            TlsConnector::from(Arc::new(tls))
+        };
+        Ok(Self {
+            resolver: TimedResolver::new(service.address_family, service.hosts.clone()),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    async fn send(&self, mut request: Request<UpstreamBody>, client: SocketAddr) -> Result<Response<Incoming>> {
+        let uri = request.uri().clone();
+        let host = uri.host().context("request has no host")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        let scheme = uri.scheme_str().unwrap_or("http");
+        let port = uri.port_u16().unwrap_or(if scheme == "https" { 443 } else { 80 });
+
+        // The pooled client fills in Host and the origin-form target itself.
+        if let Some(authority) = uri.authority() {
+            if !request.headers().contains_key(header::HOST) {
+                request.headers_mut().insert(header::HOST, authority.as_str().parse()?);
+            }
+        }
+        *request.uri_mut() = uri.path_and_query().map_or("/", |target| target.as_str()).parse()?;
+
+        upstream_timing::connect_started();
+        let mut stream = connect(&self.resolver, &host, port).await?;
+        proxy_protocol::announce(&mut stream, client)
+            .await
+            .context("sending the PROXY header")?;
+        match scheme {
+            "http" => {
+                upstream_timing::connected();
+                exchange(stream, request).await
+            }
+            #[cfg(feature = "tls")]
+            "https" => {
+                let name = ServerName::try_from(host).context("invalid TLS server name")?;
+                let stream = self.tls.connect(name, stream).await.context("TLS handshake with upstream")?;
+                upstream_timing::connected();
+                exchange(stream, request).await
+            }
+            scheme => bail!("unsupported endpoint scheme {:?}", scheme),
+        }
+    }
+}
+
+async fn exchange<S>(stream: S, request: Request<UpstreamBody>) -> Result<Response<Incoming>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    // Closes the connection once the response body has been read.
+    tokio::spawn(connection);
+    Ok(sender.send_request(request).await?)
+}
+
+// A connection outside the pooled client, for WebSocket tunnels and
+// announced requests. Fixed hosts and the family preference apply as they
+// do to pooled connections; addresses are tried in the order the family
+// gives them.
+pub(crate) async fn connect(resolver: &TimedResolver, host: &str, port: u16) -> Result<TcpStream> {
+    let addrs = resolver.lookup(host).await.with_context(|| format!("resolving {}", host))?;
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect(SocketAddr::new(addr.ip(), port)).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        Some(e) => anyhow!(e).context(format!("connecting to {}:{}", host, port)),
+        None => anyhow!("no address for {}", host),
+    })
+}
+
+// The address a response came from, when it came over the network.
+pub fn remote_addr<B>(response: &Response<B>) -> Option<SocketAddr> {
+    response.extensions().get::<HttpInfo>().map(HttpInfo::remote_addr)
+}
+
+// One client per service and direction, so connections are pooled across
+// requests and connections opened ahead of time are there to be reused; the
+// client keeps idle connections per endpoint. Callers set a per-request
+// timeout; the service's `timeout_ms` is the client-wide fallback.
+pub struct ClientCache {
+    clients: Mutex<HashMap<(Direction, String), CachedClient>>,
+    pool: UpstreamPoolConfig,
+    built: AtomicU64,
+    metrics: Arc<MetricsCollector>,
+}
+
+struct CachedClient {
+    client: UpstreamClient,
+    settings: ClientSettings,
+}
+
+impl ClientCache {
+    pub fn new(pool: UpstreamPoolConfig, metrics: Arc<MetricsCollector>) -> Self {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+            pool,
+            built: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    // The service's client, built on first use. Settings are only compared
+    // when services are reloaded; see `reconfigure`.
+    pub fn get(&self, direction: Direction, service: &UpstreamService) -> Result<UpstreamClient> {
+        let key = (direction, service.name.clone());
+        if let Some(cached) = self.clients.lock().unwrap().get(&key) {
+            return Ok(cached.client.clone());
+        }
+        let client = UpstreamClient::new(service, &self.pool)?;
+        self.built.fetch_add(1, Ordering::Relaxed);
+
+        let cached = self
+            .clients
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert(CachedClient { client, settings: ClientSettings::of(service) })
+            .client
+            .clone();
+        self.metrics.set_pool_client(direction, &service.name, true);
+        Ok(cached)
+    }
+
+    // Brings cached clients in line with reloaded `services`. A service gone
+    // from them loses its client, closing its idle connections once in-flight
+    // requests are done with it. One reloaded with different client settings
+    // gets a new client straight away, so requests still holding the old
+    // config cannot cache a client built from it; one whose endpoints alone
+    // changed keeps its pooled connections.
+    pub fn reconfigure(&self, direction: Direction, services: &HashMap<String, UpstreamService>) {
+        let stale: Vec<(String, Option<&UpstreamService>)> = {
+            let clients = self.clients.lock().unwrap();
+            clients
+                .iter()
+                .filter(|((cached_direction, _), _)| *cached_direction == direction)
+                .filter_map(|((_, name), cached)| match services.get(name) {
+                    None => Some((name.clone(), None)),
+                    Some(service) if ClientSettings::of(service) != cached.settings => Some((name.clone(), Some(service))),
+                    Some(_) => None,
+                })
+                .collect()
+        };
+        for (name, service) in stale {
+            let key = (direction, name);
+            let rebuilt = service.and_then(|service| match UpstreamClient::new(service, &self.pool) {
+                Ok(client) => {
+                    self.built.fetch_add(1, Ordering::Relaxed);
+                    Some(CachedClient { client, settings: ClientSettings::of(service) })
+                }
+                Err(e) => {
+                    warn!(service = %key.1, error = format!("{:#}", e), "cannot rebuild upstream client; building it on next use");
+                    None
+                }
+            });
+            let mut clients = self.clients.lock().unwrap();
+            match rebuilt {
+                Some(cached) => {
+                    clients.insert(key, cached);
+                }
+                None => {
+                    if clients.remove(&key).is_some() {
+                        self.metrics.set_pool_client(direction, &key.1, false);
+                    }
+                }
+            }
+        }
+    }
+
+    // Clients built so far, including any that lost a race to be cached.
+    pub fn built(&self) -> u64 {
+        self.built.load(Ordering::Relaxed)
+    }
+}
+
+// What `UpstreamClient::new` reads from a service.
+#[derive(Debug, Clone, PartialEq)]
+struct ClientSettings {
+    tls: Option<UpstreamTlsConfig>,
+    auth: Option<UpstreamAuthConfig>,
+    address_family: AddressFamily,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    timeout_ms: u64,
+    proxy_protocol: bool,
+}
+
+impl ClientSettings {
+    fn of(service: &UpstreamService) -> Self {
+        Self {
+            tls: service.tls.clone(),
+            auth: service.auth.clone(),
+            address_family: service.address_family,
+            hosts: service.hosts.clone(),
+            timeout_ms: service.timeout_ms,
+            proxy_protocol: service.proxy_protocol,
+        }
+    }
+}
+
+// Built here rather than by the clients so the session store can mark when
+// the TLS handshake starts; see `upstream_timing`.
+#[cfg(feature = "tls")]
+pub(crate) fn tls_config(service: &UpstreamService) -> Result<ClientConfig> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let tls = service.tls.clone().unwrap_or_default();
+
+    if let Some(ca_path) = &tls.ca_bundle_path {
+        let pem = std::fs::read(ca_path)
+            .with_context(|| format!("reading CA bundle {}", ca_path))?;
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            let cert = cert.with_context(|| format!("parsing CA bundle {}", ca_path))?;
+            roots
+                .add(cert)
+                .with_context(|| format!("parsing CA bundle {}", ca_path))?;
+        }
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("configuring upstream TLS")?
+        .with_root_certificates(roots);
+
+    let mut config = match (&tls.client_cert_path, &tls.client_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let pem = std::fs::read(cert_path)
+                .with_context(|| format!("reading client certificate {}", cert_path))?;
+            let chain = CertificateDer::pem_slice_iter(&pem)
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("parsing client identity {}", cert_path))?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .with_context(|| format!("reading client key {}", key_path))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .with_context(|| format!("parsing client identity {}", cert_path))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("client_cert_path and client_key_path must be set together"),
+    };
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config.resumption = Resumption::store(Arc::new(TimedSessionStore::new(SESSION_CACHE_SIZE)));
+    Ok(config)
+}
+
+#[cfg(feature = "tls")]
+const SESSION_CACHE_SIZE: usize = 256;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::mock_upstream::{MockResponse, MockUpstream};
+
+    #[test]
+    fn test_clients_reused_until_their_settings_change() {
+        let metrics = Arc::new(MetricsCollector::new());
+        let cache = ClientCache::new(UpstreamPoolConfig::default(), metrics.clone());
+        let mut services = Config::new().upstream_services;
+
+        cache.get(Direction::Ingress, &services["service-a"]).unwrap();
+        let service = services.get_mut("service-a").unwrap();
+        service.endpoints.push("http://127.0.0.1:9".to_string());
+        cache.reconfigure(Direction::Ingress, &services);
+        cache.get(Direction::Ingress, &services["service-a"]).unwrap();
+        assert_eq!(cache.built(), 1);
+
+        // Only a reload changes which client a service gets.
+        services.get_mut("service-a").unwrap().timeout_ms += 1;
+        cache.get(Direction::Ingress, &services["service-a"]).unwrap();
+        assert_eq!(cache.built(), 1);
+        cache.reconfigure(Direction::Ingress, &services);
+        assert_eq!(cache.built(), 2);
+        cache.get(Direction::Ingress, &services["service-a"]).unwrap();
+        assert_eq!(cache.built(), 2);
+
+        cache.get(Direction::Egress, &services["service-a"]).unwrap();
+        assert_eq!(cache.built(), 3);
+        assert_eq!(metrics.pool_clients(), 2);
+
+        let removed = services.remove("service-a").unwrap();
+        cache.reconfigure(Direction::Ingress, &services);
+        cache.reconfigure(Direction::Ingress, &services);
+        assert_eq!(metrics.pool_clients(), 1);
+        cache.get(Direction::Ingress, &removed).unwrap();
+        assert_eq!(cache.built(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_configured_auth_replaces_the_clients() {
+        let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+        let mut service = Config::new().upstream_services["service-a"].clone();
+        service.auth = Some(UpstreamAuthConfig {
+            header: "authorization".to_string(),
+            value: "Bearer sidecar".to_string(),
+        });
+        let client = UpstreamClient::new(&service, &UpstreamPoolConfig::default()).unwrap();
+
+        let request = Request::get(format!("{}/data", upstream.url()))
+            .header(header::AUTHORIZATION, "Bearer client")
+            .body(full_body(Bytes::new()))
+            .unwrap();
+        client.send(request, Duration::from_secs(5)).await.unwrap();
+
+        let headers = upstream.last_request_headers("/data").unwrap();
+        let sent: Vec<_> = headers.get_all(header::AUTHORIZATION).iter().collect();
+        assert_eq!(sent, ["Bearer sidecar"]);
+    }
+
+    #[test]
+    fn test_pool_settings_checked() {
+        assert_eq!(UpstreamPoolConfig::default().problem(), None);
+        let no_idle = UpstreamPoolConfig { idle_timeout_ms: 0, ..UpstreamPoolConfig::default() };
+        assert!(no_idle.problem().unwrap().contains("idle_timeout_ms"));
+        let zero_keepalive = UpstreamPoolConfig { tcp_keepalive_ms: Some(0), ..UpstreamPoolConfig::default() };
+        assert!(zero_keepalive.problem().unwrap().contains("tcp_keepalive_ms"));
+        let os_keepalive = UpstreamPoolConfig { tcp_keepalive_ms: None, ..UpstreamPoolConfig::default() };
+        assert_eq!(os_keepalive.problem(), None);
+    }
+}
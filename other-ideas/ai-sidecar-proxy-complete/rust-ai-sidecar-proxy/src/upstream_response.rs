@@ -0,0 +1,144 @@
+use hyper::{header, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+// Sanity limits on what an upstream may answer with, applied before the
+// response is rebuilt for the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamResponseConfig {
+    // Every header's name and value bytes, summed.
+    pub max_header_bytes: usize,
+    pub max_header_count: usize,
+    pub on_oversized_headers: OversizedHeaders,
+    // Answered in place of a status with no standard meaning, such as 599,
+    // with the upstream's in x-proxy-original-status. Unset passes them on.
+    pub nonstandard_status: Option<u16>,
+}
+
+impl Default for UpstreamResponseConfig {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 64 * 1024,
+            max_header_count: 100,
+            on_oversized_headers: OversizedHeaders::Reject,
+            nonstandard_status: Some(502),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedHeaders {
+    // Answer 502 instead.
+    #[default]
+    Reject,
+    // Drop Set-Cookie headers, the last first, until the block fits; 502
+    // when it still does not.
+    TruncateCookies,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderCheck {
+    Fits,
+    // Over a limit, "header_bytes" or "header_count", until this many
+    // cookies were dropped.
+    Truncated(&'static str, usize),
+    // Over a limit, and left so.
+    TooLarge(&'static str),
+}
+
+impl UpstreamResponseConfig {
+    pub fn check_headers(&self, headers: &mut HeaderMap) -> HeaderCheck {
+        let Some(over) = self.over_limit(headers) else {
+            return HeaderCheck::Fits;
+        };
+        if self.on_oversized_headers == OversizedHeaders::Reject {
+            return HeaderCheck::TooLarge(over);
+        }
+
+        let mut cookies: Vec<_> = headers.get_all(header::SET_COOKIE).iter().cloned().collect();
+        let mut dropped = 0;
+        while self.over_limit(headers).is_some() {
+            if cookies.pop().is_none() {
+                return HeaderCheck::TooLarge(over);
+            }
+            dropped += 1;
+            headers.remove(header::SET_COOKIE);
+            for cookie in &cookies {
+                headers.append(header::SET_COOKIE, cookie.clone());
+            }
+        }
+        HeaderCheck::Truncated(over, dropped)
+    }
+
+    fn over_limit(&self, headers: &HeaderMap) -> Option<&'static str> {
+        let bytes: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+        if bytes > self.max_header_bytes {
+            Some("header_bytes")
+        } else if headers.len() > self.max_header_count {
+            Some("header_count")
+        } else {
+            None
+        }
+    }
+
+    // The status to answer with, when it is not the upstream's own.
+    pub fn replace_status(&self, status: u16) -> Option<u16> {
+        self.nonstandard_status.filter(|_| !is_standard_status(status))
+    }
+}
+
+// A status `nonstandard_status` may be set to.
+pub fn is_standard_status(status: u16) -> bool {
+    StatusCode::from_u16(status).is_ok_and(|status| status.canonical_reason().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(cookies: usize) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        for index in 0..cookies {
+            headers.append(header::SET_COOKIE, format!("c{}={}", index, "x".repeat(90)).parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_cookies_dropped_last_first_until_the_block_fits() {
+        let config = UpstreamResponseConfig {
+            max_header_bytes: 500,
+            on_oversized_headers: OversizedHeaders::TruncateCookies,
+            ..UpstreamResponseConfig::default()
+        };
+        let mut oversized = headers(8);
+        assert_eq!(config.check_headers(&mut oversized), HeaderCheck::Truncated("header_bytes", 4));
+        let kept: Vec<&str> = oversized.get_all(header::SET_COOKIE).iter().map(|value| &value.to_str().unwrap()[..3]).collect();
+        assert_eq!(kept, ["c0=", "c1=", "c2=", "c3="]);
+        assert_eq!(oversized.get(header::CONTENT_TYPE).unwrap(), "text/plain");
+
+        let mut fits = headers(2);
+        assert_eq!(config.check_headers(&mut fits), HeaderCheck::Fits);
+        let rejecting = UpstreamResponseConfig { max_header_bytes: 500, ..UpstreamResponseConfig::default() };
+        assert_eq!(rejecting.check_headers(&mut headers(8)), HeaderCheck::TooLarge("header_bytes"));
+        let few = UpstreamResponseConfig { max_header_count: 1, ..config };
+        let mut no_cookies = headers(0);
+        no_cookies.insert("x-a", "1".parse().unwrap());
+        assert_eq!(few.check_headers(&mut no_cookies), HeaderCheck::TooLarge("header_count"));
+    }
+
+    #[test]
+    fn test_only_nonstandard_statuses_replaced() {
+        let config = UpstreamResponseConfig::default();
+        assert_eq!(config.replace_status(599), Some(502));
+        assert_eq!(config.replace_status(299), Some(502));
+        assert_eq!(config.replace_status(503), None);
+        assert_eq!(config.replace_status(418), None);
+        let passing = UpstreamResponseConfig { nonstandard_status: None, ..config };
+        assert_eq!(passing.replace_status(599), None);
+        assert!(!is_standard_status(599));
+        assert!(is_standard_status(502));
+    }
+}
@@ -0,0 +1,351 @@
+use crate::address_family::AddressFamily;
+use hyper_util::client::legacy::connect::dns::Name as HyperName;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+#[cfg(feature = "tls")]
+use rustls::{
+    client::{ClientSessionMemoryCache, ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue},
+    pki_types::ServerName,
+    NamedGroup,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{Layer, Service};
+
+// Where the time of one upstream call went. The connection phases are zero
+// when a pooled connection was reused, and `reused` says so.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamPhases {
+    pub dns_ms: f64,
+    pub connect_ms: f64,
+    pub tls_ms: f64,
+    pub ttfb_ms: f64,
+    pub body_ms: f64,
+    pub reused: bool,
+}
+
+impl UpstreamPhases {
+    pub fn durations(&self) -> [(&'static str, f64); 5] {
+        [
+            ("dns", self.dns_ms),
+            ("connect", self.connect_ms),
+            ("tls", self.tls_ms),
+            ("ttfb", self.ttfb_ms),
+            ("body", self.body_ms),
+        ]
+    }
+
+    // Entries for a `Server-Timing` response header.
+    pub fn server_timing_entries(&self) -> Vec<String> {
+        let mut phases: Vec<String> = self
+            .durations()
+            .iter()
+            .map(|(name, ms)| format!("upstream-{};dur={:.1}", name, ms))
+            .collect();
+        phases.push(format!(
+            "upstream-conn;desc={}",
+            if self.reused { "reused" } else { "new" }
+        ));
+        phases
+    }
+}
+
+#[derive(Debug, Default)]
+struct Marks {
+    connect_start: Option<Instant>,
+    dns_start: Option<Instant>,
+    dns_end: Option<Instant>,
+    tls_start: Option<Instant>,
+    connect_end: Option<Instant>,
+}
+
+tokio::task_local! {
+    static RECORDER: Arc<PhaseRecorder>;
+}
+
+// Collects timestamps from the client's resolver, connector and TLS session
+// store while one upstream call runs. The hooks find the recorder through a
+// task-local, so a connection opened on some other task (a pool racing the
+// checkout) is not attributed to this call and it reads as reused.
+#[derive(Debug)]
+pub struct PhaseRecorder {
+    sent: Instant,
+    marks: Mutex<Marks>,
+}
+
+impl PhaseRecorder {
+    pub fn start() -> Arc<Self> {
+        Arc::new(Self {
+            sent: Instant::now(),
+            marks: Mutex::new(Marks::default()),
+        })
+    }
+
+    pub async fn scope<F: Future>(self: &Arc<Self>, call: F) -> F::Output {
+        RECORDER.scope(self.clone(), call).await
+    }
+
+    // `headers_at` is when the response head arrived, `body_done` when the
+    // body had been read in full.
+    pub fn finish(&self, headers_at: Instant, body_done: Instant) -> UpstreamPhases {
+        let marks = self.marks.lock().unwrap();
+        let ms = |from: Instant, to: Instant| to.saturating_duration_since(from).as_secs_f64() * 1000.0;
+        let body_ms = ms(headers_at, body_done);
+
+        let (Some(connect_start), Some(connect_end)) = (marks.connect_start, marks.connect_end) else {
+            return UpstreamPhases {
+                ttfb_ms: ms(self.sent, headers_at),
+                body_ms,
+                reused: true,
+                ..UpstreamPhases::default()
+            };
+        };
+
+        let dns_ms = match (marks.dns_start, marks.dns_end) {
+            (Some(start), Some(end)) => ms(start, end),
+            _ => 0.0,
+        };
+        let tcp_start = marks.dns_end.unwrap_or(connect_start);
+        let tcp_end = marks.tls_start.unwrap_or(connect_end);
+
+        UpstreamPhases {
+            dns_ms,
+            connect_ms: ms(tcp_start, tcp_end),
+            tls_ms: marks.tls_start.map_or(0.0, |start| ms(start, connect_end)),
+            ttfb_ms: ms(connect_end, headers_at),
+            body_ms,
+            reused: false,
+        }
+    }
+}
+
+fn mark(set: impl FnOnce(&mut Marks)) {
+    let _ = RECORDER.try_with(|recorder| set(&mut recorder.marks.lock().unwrap()));
+}
+
+// For connections opened outside the client's connector, which bracket TCP
+// connect and any TLS handshake with these two.
+pub(crate) fn connect_started() {
+    mark(|marks| marks.connect_start = Some(Instant::now()));
+}
+
+pub(crate) fn connected() {
+    mark(|marks| marks.connect_end = Some(Instant::now()));
+}
+
+// System resolver that reports how long the lookup took, and orders the
+// addresses by the service's family preference for the connector to race.
+#[derive(Debug, Clone, Default)]
+pub struct TimedResolver {
+    family: AddressFamily,
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl TimedResolver {
+    pub fn new(family: AddressFamily, hosts: HashMap<String, Vec<IpAddr>>) -> Self {
+        Self {
+            family,
+            hosts: Arc::new(hosts),
+        }
+    }
+}
+
+impl TimedResolver {
+    // Port 0 throughout; the connector fills in the endpoint's.
+    pub(crate) fn lookup(&self, host: &str) -> impl Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'static {
+        let family = self.family;
+        let fixed = self.hosts.get(host).cloned();
+        let host = host.to_string();
+        async move {
+            mark(|marks| marks.dns_start = Some(Instant::now()));
+            let resolved = match fixed {
+                Some(ips) => Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect()),
+                None => tokio::net::lookup_host((host.as_str(), 0))
+                    .await
+                    .map(|addrs| addrs.collect::<Vec<_>>()),
+            };
+            mark(|marks| marks.dns_end = Some(Instant::now()));
+            let arranged = family.arrange(resolved?);
+            if arranged.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no {:?} address for {}", family, host),
+                ));
+            }
+            Ok(arranged)
+        }
+    }
+}
+
+// For reqwest, which the probe clients use.
+impl Resolve for TimedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let lookup = self.lookup(name.as_str());
+        Box::pin(async move {
+            let addrs: Addrs = Box::new(lookup.await?.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// For hyper's connector, which the proxied requests use.
+impl Service<HyperName> for TimedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: HyperName) -> Self::Future {
+        let lookup = self.lookup(name.as_str());
+        Box::pin(async move { Ok(lookup.await?.into_iter()) })
+    }
+}
+
+// Wraps the client's connector, which covers DNS, TCP connect and the TLS
+// handshake for a new connection. Never invoked for a reused one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimedConnectLayer;
+
+impl<S> Layer<S> for TimedConnectLayer {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimedConnect { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimedConnect<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for TimedConnect<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let connect = self.inner.call(req);
+        Box::pin(async move {
+            mark(|marks| marks.connect_start = Some(Instant::now()));
+            let connected = connect.await;
+            mark(|marks| marks.connect_end = Some(Instant::now()));
+            connected
+        })
+    }
+}
+
+// rustls asks the session store for a resumption ticket while it builds the
+// ClientHello, which is the moment TCP is done and the TLS handshake begins.
+#[cfg(feature = "tls")]
+#[derive(Debug)]
+pub struct TimedSessionStore {
+    inner: ClientSessionMemoryCache,
+}
+
+#[cfg(feature = "tls")]
+impl TimedSessionStore {
+    pub fn new(size: usize) -> Self {
+        Self {
+            inner: ClientSessionMemoryCache::new(size),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl ClientSessionStore for TimedSessionStore {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.inner.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.inner.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.inner.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.inner.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.inner.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(&self, server_name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.inner.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        mark(|marks| {
+            marks.tls_start.get_or_insert_with(Instant::now);
+        });
+        self.inner.take_tls13_ticket(server_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_no_connect_hooks_reads_as_reused() {
+        let recorder = PhaseRecorder::start();
+        recorder.scope(async {}).await;
+        let headers_at = recorder.sent + Duration::from_millis(40);
+        let phases = recorder.finish(headers_at, headers_at + Duration::from_millis(5));
+
+        assert!(phases.reused);
+        assert_eq!((phases.dns_ms, phases.connect_ms, phases.tls_ms), (0.0, 0.0, 0.0));
+        assert_eq!(phases.ttfb_ms, 40.0);
+        assert_eq!(phases.body_ms, 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_phases_split_at_hook_marks() {
+        let recorder = PhaseRecorder::start();
+        let at = |ms| recorder.sent + Duration::from_millis(ms);
+        recorder
+            .scope(async {
+                mark(|marks| {
+                    marks.connect_start = Some(at(1));
+                    marks.dns_start = Some(at(1));
+                    marks.dns_end = Some(at(3));
+                    marks.tls_start = Some(at(10));
+                    marks.connect_end = Some(at(25));
+                });
+            })
+            .await;
+
+        let phases = recorder.finish(at(30), at(31));
+        assert!(!phases.reused);
+        assert_eq!(phases.dns_ms, 2.0);
+        assert_eq!(phases.connect_ms, 7.0);
+        assert_eq!(phases.tls_ms, 15.0);
+        assert_eq!(phases.ttfb_ms, 5.0);
+        assert_eq!(phases.body_ms, 1.0);
+        assert!(phases.server_timing_entries().contains(&"upstream-tls;dur=15.0".to_string()));
+    }
+}
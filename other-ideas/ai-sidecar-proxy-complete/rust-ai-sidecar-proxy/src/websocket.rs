@@ -0,0 +1,129 @@
+use crate::{config::UpstreamService, proxy_protocol, upstream_client, upstream_timing::TimedResolver};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{body::Incoming, header, upgrade::OnUpgrade, Request, Response};
+use hyper_util::rt::TokioIo;
+use reqwest::Url;
+#[cfg(feature = "tls")]
+use rustls::pki_types::ServerName;
+use std::net::SocketAddr;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+
+// A client asking to switch its connection to WebSocket, as in RFC 6455
+// section 4.1. Only HTTP/1.1 has this handshake; HTTP/2's extended CONNECT
+// is not proxied.
+pub fn is_upgrade<T>(req: &Request<T>) -> bool {
+    let lists = |name: header::HeaderName, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .is_ok_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+        })
+    };
+    req.version() == hyper::Version::HTTP_11
+        && lists(header::UPGRADE, "websocket")
+        && lists(header::CONNECTION, "upgrade")
+}
+
+// The Host header for requests to `endpoint`, which hyper's client leaves
+// to the caller.
+pub fn authority(endpoint: &str) -> Result<String> {
+    let url = Url::parse(endpoint).with_context(|| format!("invalid endpoint {:?}", endpoint))?;
+    let host = url.host_str().context("endpoint has no host")?;
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+// Opens a connection of its own to `endpoint` and sends the handshake on
+// it. A 101 answer is turned into the upstream side of the tunnel with
+// `hyper::upgrade::on`; anything else is the upstream declining. A service
+// with `proxy_protocol` is told about `client` first.
+pub async fn handshake(
+    service: &UpstreamService,
+    endpoint: &str,
+    request: Request<Empty<Bytes>>,
+    client: SocketAddr,
+) -> Result<Response<Incoming>> {
+    let url = Url::parse(endpoint).with_context(|| format!("invalid endpoint {:?}", endpoint))?;
+    let host = url.host_str().context("endpoint has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port_or_known_default().context("endpoint has no port")?;
+    let resolver = TimedResolver::new(service.address_family, service.hosts.clone());
+    let mut stream = upstream_client::connect(&resolver, &host, port).await?;
+    if service.proxy_protocol {
+        proxy_protocol::announce(&mut stream, client)
+            .await
+            .context("sending the PROXY header")?;
+    }
+    match url.scheme() {
+        "http" => send(stream, request).await,
+        #[cfg(feature = "tls")]
+        "https" => {
+            let mut tls = upstream_client::tls_config(service)?;
+            // The upgrade is an HTTP/1.1 exchange.
+            tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+            let name = ServerName::try_from(host).context("invalid TLS server name")?;
+            let stream = TlsConnector::from(Arc::new(tls))
+                .connect(name, stream)
+                .await
+                .context("TLS handshake with upstream")?;
+            send(stream, request).await
+        }
+        scheme => bail!("unsupported endpoint scheme {:?}", scheme),
+    }
+}
+
+async fn send<S>(stream: S, request: Request<Empty<Bytes>>) -> Result<Response<Incoming>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    // Resolves once the upgraded stream has taken the connection over.
+    tokio::spawn(connection.with_upgrades());
+    Ok(sender.send_request(request).await?)
+}
+
+// Copies bytes both ways until both sides have closed, after both upgrades
+// complete. Returns the bytes sent to the upstream and to the client.
+pub async fn relay(client: OnUpgrade, upstream: OnUpgrade) -> Result<(u64, u64)> {
+    let (client, upstream) = tokio::try_join!(client, upstream)?;
+    let (mut client, mut upstream) = (TokioIo::new(client), TokioIo::new(upstream));
+    Ok(tokio::io::copy_bidirectional(&mut client, &mut upstream).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri("/chat");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_upgrade_needs_both_headers() {
+        assert!(is_upgrade(&request(&[("upgrade", "WebSocket"), ("connection", "keep-alive, Upgrade")])));
+        assert!(!is_upgrade(&request(&[("upgrade", "websocket")])));
+        assert!(!is_upgrade(&request(&[("upgrade", "h2c"), ("connection", "upgrade")])));
+        let mut http2 = request(&[("upgrade", "websocket"), ("connection", "upgrade")]);
+        *http2.version_mut() = hyper::Version::HTTP_2;
+        assert!(!is_upgrade(&http2));
+    }
+
+    #[test]
+    fn test_authority_keeps_explicit_ports() {
+        assert_eq!(authority("http://localhost:3001").unwrap(), "localhost:3001");
+        assert_eq!(authority("https://api.internal").unwrap(), "api.internal");
+        assert_eq!(authority("http://[::1]:8080").unwrap(), "[::1]:8080");
+    }
+}
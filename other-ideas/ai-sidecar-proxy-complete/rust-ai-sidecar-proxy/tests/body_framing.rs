@@ -0,0 +1,369 @@
+// Every way the proxy can change a response body, against every way a client
+// can read one. Responses are parsed strictly: one framing, and exactly as
+// many body bytes as it announces.
+
+use ai_sidecar_proxy::{
+    body_pipeline::ResponseBodyPipeline,
+    config::Config,
+    content_coding::ContentCodingMode,
+    metrics::MetricsCollector,
+    middleware::{Middleware, RequestContext},
+    mock_upstream::{MockResponse, MockUpstream},
+    proxy::{ProxyHandle, ProxyServer},
+};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::{body::Frame, Response};
+use std::{io::Write, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const PLAIN: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz";
+const REWRITTEN: [&[u8]; 2] = [b"rewritten by ", b"middleware"];
+
+#[derive(Clone, Copy, Debug)]
+enum Mutation {
+    None,
+    Decompress,
+    Rewrite,
+}
+
+impl Mutation {
+    const ALL: [Mutation; 3] = [Mutation::None, Mutation::Decompress, Mutation::Rewrite];
+
+    fn expected_body(self) -> Vec<u8> {
+        match self {
+            Mutation::None | Mutation::Decompress => PLAIN.to_vec(),
+            Mutation::Rewrite => REWRITTEN.concat(),
+        }
+    }
+
+    // What a HEAD response may announce: the upstream's length, unless the
+    // GET body would not have been the upstream's bytes.
+    fn head_length(self) -> Option<usize> {
+        match self {
+            Mutation::None | Mutation::Rewrite => Some(PLAIN.len()),
+            Mutation::Decompress => None,
+        }
+    }
+}
+
+// Replaces GET bodies with a stream of unknown length.
+struct Rewrite;
+
+impl Middleware for Rewrite {
+    fn on_response(&self, response: &mut Response<BoxBody<Bytes, hyper::Error>>, context: &RequestContext) {
+        if context.method == "HEAD" || !context.path.starts_with("/api/") {
+            return;
+        }
+        let frames = REWRITTEN.map(|chunk| Ok(Frame::data(Bytes::from_static(chunk))));
+        *response.body_mut() = StreamBody::new(futures::stream::iter(frames)).boxed();
+        ResponseBodyPipeline::mark_mutated(response, "rewrite");
+    }
+}
+
+async fn start(mutation: Mutation) -> (MockUpstream, ProxyHandle) {
+    let response = match mutation {
+        Mutation::Decompress => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(PLAIN).unwrap();
+            MockResponse {
+                headers: vec![("content-encoding".to_string(), "gzip".to_string())],
+                body: Bytes::from(encoder.finish().unwrap()),
+                ..MockResponse::default()
+            }
+        }
+        Mutation::None | Mutation::Rewrite => MockResponse {
+            body: Bytes::from_static(PLAIN),
+            ..MockResponse::default()
+        },
+    };
+    let upstream = MockUpstream::start(response).await.unwrap();
+
+    let mut config = Config::new();
+    let service = config.upstream_services.get_mut("service-a").unwrap();
+    service.endpoints = vec![upstream.url()];
+    if let Mutation::Decompress = mutation {
+        service.content_coding = ContentCodingMode::Decompress;
+    }
+
+    let mut builder = ProxyServer::builder().config(config);
+    if let Mutation::Rewrite = mutation {
+        builder = builder.middleware(Rewrite);
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = builder.build().unwrap().run(listener).unwrap();
+    (upstream, handle)
+}
+
+struct Parsed {
+    status: u16,
+    content_length: Option<usize>,
+    chunked: bool,
+    body: Vec<u8>,
+}
+
+fn header_end(raw: &[u8]) -> usize {
+    raw.windows(4).position(|w| w == b"\r\n\r\n").expect("complete response head") + 4
+}
+
+fn parse(raw: &[u8], head: bool) -> Parsed {
+    let end = header_end(raw);
+    let text = std::str::from_utf8(&raw[..end]).unwrap();
+    let mut lines = text.split("\r\n");
+    let status_line = lines.next().unwrap();
+    assert!(status_line.starts_with("HTTP/1."), "{}", status_line);
+    let status = status_line[9..12].parse().unwrap();
+
+    let mut lengths = Vec::new();
+    let mut encodings = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').unwrap();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => lengths.push(value.trim().parse::<usize>().unwrap()),
+            "transfer-encoding" => encodings.push(value.trim().to_ascii_lowercase()),
+            _ => {}
+        }
+    }
+    assert!(lengths.len() <= 1, "repeated content-length");
+    assert!(lengths.is_empty() || encodings.is_empty(), "content-length with transfer-encoding");
+    let chunked = encodings.iter().any(|encoding| encoding == "chunked");
+
+    let rest = &raw[end..];
+    let body = if head {
+        assert!(rest.is_empty(), "HEAD response carried a body");
+        Vec::new()
+    } else if chunked {
+        dechunk(rest)
+    } else if let Some(&length) = lengths.first() {
+        assert_eq!(rest.len(), length, "body size disagrees with content-length");
+        rest.to_vec()
+    } else {
+        rest.to_vec()
+    };
+
+    Parsed {
+        status,
+        content_length: lengths.first().copied(),
+        chunked,
+        body,
+    }
+}
+
+fn dechunk(mut rest: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = rest.windows(2).position(|w| w == b"\r\n").expect("chunk size line");
+        let size = usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            assert_eq!(rest, b"\r\n", "data after the last chunk");
+            return body;
+        }
+        body.extend_from_slice(&rest[..size]);
+        assert_eq!(&rest[size..size + 2], b"\r\n");
+        rest = &rest[size + 2..];
+    }
+}
+
+async fn raw_request(addr: SocketAddr, request: &str) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).await.unwrap();
+    raw
+}
+
+async fn check(mutation: Mutation) {
+    let (_upstream, handle) = start(mutation).await;
+    let addr = handle.local_addr();
+    let expected = mutation.expected_body();
+
+    // HTTP/1.1 GET
+    let raw = raw_request(addr, "GET /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+    let parsed = parse(&raw, false);
+    assert_eq!(parsed.status, 200, "{:?}", mutation);
+    assert_eq!(parsed.body, expected, "{:?}", mutation);
+    assert!(parsed.content_length.is_some() || parsed.chunked, "{:?}: no framing", mutation);
+
+    // HTTP/1.1 HEAD
+    let raw = raw_request(addr, "HEAD /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+    let parsed = parse(&raw, true);
+    assert_eq!(parsed.status, 200, "{:?}", mutation);
+    assert_eq!(parsed.content_length, mutation.head_length(), "{:?}", mutation);
+
+    // HTTP/1.0 GET: no chunked coding, so an unknown length is close-delimited.
+    let raw = raw_request(addr, "GET /api/a/items HTTP/1.0\r\nhost: proxy\r\n\r\n").await;
+    let parsed = parse(&raw, false);
+    assert_eq!(parsed.status, 200, "{:?}", mutation);
+    assert!(!parsed.chunked, "{:?}: chunked to an HTTP/1.0 client", mutation);
+    assert_eq!(parsed.body, expected, "{:?}", mutation);
+
+    // HTTP/2 GET: the client enforces content-length against DATA frames.
+    let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+    let response = client.get(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.status(), 200);
+    let declared = response.content_length();
+    let body = response.bytes().await.unwrap();
+    assert_eq!(body.as_ref(), expected.as_slice(), "{:?}", mutation);
+    if let Some(declared) = declared {
+        assert_eq!(declared as usize, body.len(), "{:?}", mutation);
+    }
+
+    handle.shutdown();
+    handle.await_terminated().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_framing_matrix() {
+    for mutation in Mutation::ALL {
+        check(mutation).await;
+    }
+}
+
+#[tokio::test]
+async fn test_upstream_length_with_transfer_encoding_is_rejected() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+                    )
+                    .await;
+            });
+        }
+    });
+
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![format!("http://{}", upstream_addr)];
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = ProxyServer::builder().config(config).build().unwrap().run(listener).unwrap();
+
+    let response = reqwest::get(format!("http://{}/api/a/items", handle.local_addr())).await.unwrap();
+    assert_eq!(response.status(), 502);
+}
+
+// Answers every request with a body, whatever the method or status: 204 for
+// /api/a/no-content, 304 for /api/a/not-modified, 200 otherwise.
+async fn sloppy_upstream() -> SocketAddr {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let read = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..read]);
+                let status = if request.contains(" /api/a/no-content ") {
+                    "204 No Content"
+                } else if request.contains(" /api/a/not-modified ") {
+                    "304 Not Modified"
+                } else {
+                    "200 OK"
+                };
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_bodies_on_bodiless_upstream_responses_are_dropped() {
+    let upstream = sloppy_upstream().await;
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![format!("http://{}", upstream)];
+    let metrics = Arc::new(MetricsCollector::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = ProxyServer::builder()
+        .config(config)
+        .metrics(metrics.clone())
+        .build()
+        .unwrap()
+        .run(listener)
+        .unwrap();
+    let addr = handle.local_addr();
+
+    let raw = raw_request(addr, "HEAD /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+    let parsed = parse(&raw, true);
+    assert_eq!((parsed.status, parsed.content_length), (200, Some(5)));
+
+    for (path, status) in [("/api/a/no-content", 204), ("/api/a/not-modified", 304)] {
+        let request = format!("GET {} HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n", path);
+        let raw = raw_request(addr, &request).await;
+        let parsed = parse(&raw, false);
+        assert_eq!(parsed.status, status);
+        assert!(!parsed.chunked, "{}", path);
+        assert!(parsed.body.is_empty(), "{}: {:?}", path, parsed.body);
+    }
+    // A 204 cannot announce a length either.
+    let raw = raw_request(addr, "GET /api/a/no-content HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+    assert_eq!(parse(&raw, false).content_length, None);
+
+    let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+    let response = client.head(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+    assert_eq!(response.headers()["content-length"], "5");
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    assert_eq!(metrics.bodiless_violation_count("upstream", "204"), 2);
+}
+
+// Gives every response a body, including ones that cannot carry it.
+struct Sloppy;
+
+impl Middleware for Sloppy {
+    fn on_response(&self, response: &mut Response<BoxBody<Bytes, hyper::Error>>, context: &RequestContext) {
+        if !context.path.starts_with("/api/") {
+            return;
+        }
+        if context.path.ends_with("/not-modified") {
+            *response.status_mut() = hyper::StatusCode::NOT_MODIFIED;
+        }
+        *response.body_mut() = Full::new(Bytes::from_static(b"sloppy")).map_err(|never| match never {}).boxed();
+    }
+}
+
+#[tokio::test]
+async fn test_middleware_bodies_never_reach_bodiless_responses() {
+    let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![upstream.url()];
+    let metrics = Arc::new(MetricsCollector::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = ProxyServer::builder()
+        .config(config)
+        .metrics(metrics.clone())
+        .middleware(Sloppy)
+        .build()
+        .unwrap()
+        .run(listener)
+        .unwrap();
+    let addr = handle.local_addr();
+
+    // HTTP/2 sends any body it is handed as DATA frames, even for HEAD.
+    let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+    let response = client.head(format!("http://{}/api/a/items", addr)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-length"], "2");
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = client.get(format!("http://{}/api/a/not-modified", addr)).send().await.unwrap();
+    assert_eq!(response.status(), 304);
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let raw = raw_request(addr, "HEAD /api/a/items HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n").await;
+    assert_eq!(parse(&raw, true).content_length, Some(2));
+
+    assert_eq!(metrics.bodiless_violation_count("local", "head"), 2);
+    assert_eq!(metrics.bodiless_violation_count("local", "304"), 1);
+}
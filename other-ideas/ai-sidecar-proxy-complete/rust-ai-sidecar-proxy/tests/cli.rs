@@ -0,0 +1,77 @@
+use assert_cmd::Command;
+use predicates::prelude::*;
+use std::path::PathBuf;
+
+fn proxy() -> Command {
+    let mut command = Command::cargo_bin("ai-sidecar-proxy").unwrap();
+    command.env("RUST_BACKTRACE", "0");
+    command
+}
+
+fn scratch(name: &str, contents: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_printed_defaults_validate_in_every_format() {
+    for (format, extension) in [("toml", "toml"), ("yaml", "yaml"), ("json", "json")] {
+        let output = proxy().args(["print-default-config", "--format", format]).output().unwrap();
+        assert!(output.status.success(), "{}", format);
+        let path = scratch(&format!("default.{}", extension), &output.stdout);
+
+        proxy()
+            .args(["validate", "--config"])
+            .arg(&path)
+            .assert()
+            .success()
+            .stdout(predicate::str::ends_with(": ok\n"));
+    }
+}
+
+#[test]
+fn test_validate_reports_every_error_and_fails() {
+    let path = scratch(
+        "invalid.toml",
+        b"[upstream_services.orders]\nname = \"orders\"\nendpoints = []\n\n[upstream_responses]\nmax_header_count = 0\n",
+    );
+    proxy()
+        .args(["validate", "--config"])
+        .arg(&path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("upstream_services.orders.endpoints"))
+        .stderr(predicate::str::contains("upstream_responses"));
+
+    // The metrics port is checked against the port it would serve on.
+    let defaults = proxy().args(["print-default-config"]).output().unwrap();
+    let path = scratch("defaults.toml", &defaults.stdout);
+    proxy()
+        .args(["validate", "--port", "9090", "--config"])
+        .arg(&path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("metrics_config.port"));
+}
+
+#[test]
+fn test_validate_fails_on_unreadable_config() {
+    proxy()
+        .args(["validate", "--config", "/nonexistent/proxy.toml"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("reading config"));
+    proxy().arg("validate").assert().failure().stderr(predicate::str::contains("--config"));
+}
+
+#[test]
+fn test_serving_arguments_do_not_mix_with_other_commands() {
+    proxy()
+        .args(["--port", "9000", "print-default-config"])
+        .assert()
+        .failure();
+    proxy().args(["print-default-config", "--format", "ini"]).assert().failure();
+}
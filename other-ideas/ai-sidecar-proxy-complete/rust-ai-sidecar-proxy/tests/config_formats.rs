@@ -0,0 +1,116 @@
+use ai_sidecar_proxy::config::{Config, ConfigError, ConfigFormat};
+use ai_sidecar_proxy::config_env::EnvLoader;
+use std::path::Path;
+
+fn fixture(name: &str) -> Config {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    Config::from_file(&path).unwrap_or_else(|e| panic!("{}: {:#}", name, e))
+}
+
+#[test]
+fn test_yaml_and_toml_fixtures_are_the_same_config() {
+    let toml = fixture("config.toml");
+    let yaml = fixture("config.yaml");
+    assert_eq!(serde_json::to_value(&yaml).unwrap(), serde_json::to_value(&toml).unwrap());
+
+    let orders = &yaml.upstream_services["orders"];
+    assert_eq!(orders.endpoints.len(), 2);
+    assert_eq!(orders.hosts["orders-2"][1], "fd00::12".parse::<std::net::IpAddr>().unwrap());
+    assert_eq!(orders.endpoint_weights.as_ref().unwrap()["http://orders-1:8080"], 3);
+    assert_eq!(orders.endpoint_priorities.as_ref().unwrap()["http://orders-2:8080"], 1);
+    assert_eq!(yaml.ai_config.decision_threshold, 0.6);
+    assert!(yaml.proxy_config.server_timing.always);
+    // The fixture tunes the AI engine, which builds without it refuse.
+    assert_eq!(yaml.validate().is_ok(), cfg!(feature = "ai"));
+}
+
+#[test]
+fn test_format_follows_extension() {
+    assert_eq!(ConfigFormat::from_path(Path::new("proxy.yml")), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path(Path::new("proxy.yaml")), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path(Path::new("proxy.toml")), ConfigFormat::Toml);
+    assert_eq!(ConfigFormat::from_path(Path::new("proxy.json")), ConfigFormat::Json);
+    assert_eq!(ConfigFormat::from_path(Path::new("proxy")), ConfigFormat::Json);
+}
+
+#[test]
+fn test_explicit_format_overrides_extension() {
+    let dir = std::env::temp_dir().join(format!("config-formats-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("proxy.conf");
+    std::fs::write(&path, ConfigFormat::Yaml.write(&fixture("config.toml"))).unwrap();
+
+    assert!(matches!(Config::from_file(&path), Err(ConfigError::Parse { .. })));
+    let config = Config::from_file_as(&path, ConfigFormat::Yaml).unwrap();
+    assert_eq!(serde_json::to_value(&config).unwrap(), serde_json::to_value(fixture("config.yaml")).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unreadable_file_is_an_io_error() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/missing.toml");
+    match Config::from_file(&path) {
+        Err(ConfigError::Io { kind, .. }) => assert_eq!(kind, std::io::ErrorKind::NotFound),
+        other => panic!("expected an I/O error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_every_format_round_trips() {
+    let dir = std::env::temp_dir().join(format!("config-round-trip-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = fixture("config.toml");
+    for (name, format) in [
+        ("proxy.toml", ConfigFormat::Toml),
+        ("proxy.yaml", ConfigFormat::Yaml),
+        ("proxy.json", ConfigFormat::Json),
+    ] {
+        let path = dir.join(name);
+        std::fs::write(&path, format.write(&config)).unwrap();
+        let read_back = Config::from_file(&path).unwrap_or_else(|e| panic!("{}: {:#}", name, e));
+        assert_eq!(serde_json::to_value(&read_back).unwrap(), serde_json::to_value(&config).unwrap(), "{}", name);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_env_overrides_merge_over_a_file() {
+    let file = fixture("config.toml");
+    let env = EnvLoader::empty()
+        .var("SIDECAR_PROXY_MAX_CONNECTIONS", "4096")
+        .var("SIDECAR_METRICS_PORT", "9292")
+        .overrides("SIDECAR")
+        .unwrap();
+    let config = Config::merge(file.clone(), env);
+
+    assert_eq!(config.proxy_config.max_connections, 4096);
+    assert_eq!(config.metrics_config.port, 9292);
+    // Settings the environment did not set keep the file's value, and the
+    // defaults' own services are not added.
+    assert_eq!(config.proxy_config.max_body_bytes, file.proxy_config.max_body_bytes);
+    assert_eq!(config.ai_config.decision_threshold, 0.6);
+    let mut services: Vec<&String> = config.upstream_services.keys().collect();
+    services.sort();
+    assert_eq!(services, ["orders", "payments"]);
+    assert_eq!(
+        serde_json::to_value(&config.upstream_services).unwrap(),
+        serde_json::to_value(&file.upstream_services).unwrap()
+    );
+}
+
+#[test]
+fn test_env_override_back_to_the_default_wins() {
+    let mut file = fixture("config.toml");
+    file.ai_config.enabled = false;
+    file.metrics_config.port = 9292;
+    let default_port = Config::new().metrics_config.port;
+    let env = EnvLoader::empty()
+        .var("SIDECAR_AI_ENABLED", "true")
+        .var("SIDECAR_METRICS_PORT", default_port.to_string())
+        .overrides("SIDECAR")
+        .unwrap();
+    let config = Config::merge(file, env);
+
+    assert!(config.ai_config.enabled);
+    assert_eq!(config.metrics_config.port, default_port);
+}
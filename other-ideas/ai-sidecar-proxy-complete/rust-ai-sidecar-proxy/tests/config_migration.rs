@@ -0,0 +1,63 @@
+use ai_sidecar_proxy::config_migration::{upgrade, CURRENT_CONFIG_VERSION};
+
+const V1: &str = include_str!("fixtures/config_v1.json");
+
+#[test]
+fn test_v1_fixture_migrates_cleanly() {
+    let upgraded = upgrade(V1).unwrap();
+    assert_eq!(upgraded.from_version, 1);
+    assert_eq!(upgraded.config.config_version, CURRENT_CONFIG_VERSION);
+
+    let migrated: Vec<&str> = upgraded
+        .changes
+        .iter()
+        .map(|change| change.split(':').next().unwrap())
+        .collect();
+    assert_eq!(
+        migrated,
+        vec![
+            "proxy_config.sniff_protocol",
+            "proxy_config.tls_on_plaintext",
+            "proxy_config.fd_monitor",
+            "proxy_config.max_buffered_bytes",
+            "proxy_config.supervisor",
+        ]
+    );
+
+    // Values the old file set are kept.
+    let config = &upgraded.config;
+    let storage = &config.upstream_services["storage"];
+    assert_eq!(storage.endpoints, vec!["http://storage-1:9000", "http://storage-2:9000"]);
+    assert_eq!(storage.timeout_ms, 15000);
+    assert_eq!(config.proxy_config.max_connections, 2048);
+    assert!(!config.ai_config.enabled);
+    assert!(config.proxy_config.sniff_protocol);
+}
+
+#[test]
+fn test_migrated_output_is_current() {
+    let upgraded = upgrade(V1).unwrap();
+    let written = serde_json::to_string_pretty(&upgraded.config).unwrap();
+
+    let reread = upgrade(&written).unwrap();
+    assert_eq!(reread.from_version, CURRENT_CONFIG_VERSION);
+    assert!(reread.changes.is_empty());
+}
+
+#[test]
+fn test_unknown_field_names_its_path() {
+    let mut config = serde_json::to_value(upgrade(V1).unwrap().config).unwrap();
+    config["upstream_services"]["storage"]["timeout_msec"] = 5000.into();
+    let error = upgrade(&serde_json::to_string_pretty(&config).unwrap()).unwrap_err();
+
+    let message = format!("{:#}", error);
+    assert!(message.contains("`upstream_services.storage.timeout_msec`"), "{}", message);
+    assert!(message.contains("unknown field"), "{}", message);
+}
+
+#[test]
+fn test_unknown_field_in_old_version_is_an_error() {
+    let with_typo = V1.replace("\"buffer_size\"", "\"bufer_size\"");
+    let error = upgrade(&with_typo).unwrap_err();
+    assert!(format!("{:#}", error).contains("`proxy_config.bufer_size`"), "{:#}", error);
+}
@@ -0,0 +1,191 @@
+// Shutdown with a drain window: keep-alive clients are told to leave while
+// their in-flight requests still complete.
+
+use ai_sidecar_proxy::{
+    config::Config,
+    drain::DeregistrationConfig,
+    metrics::MetricsCollector,
+    mock_upstream::{MockResponse, MockUpstream},
+    proxy::{ProxyHandle, ProxyServer},
+};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{client::conn, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+const DRAIN_DELAY: Duration = Duration::from_secs(3);
+
+async fn start(config: Config) -> (Arc<MetricsCollector>, ProxyHandle) {
+    let metrics = Arc::new(MetricsCollector::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = ProxyServer::builder()
+        .config(config)
+        .metrics(metrics.clone())
+        .build()
+        .unwrap()
+        .run(listener)
+        .unwrap();
+    (metrics, handle)
+}
+
+fn draining_config(upstream: &MockUpstream) -> Config {
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![upstream.url()];
+    config.proxy_config.drain.delay_ms = DRAIN_DELAY.as_millis() as u64;
+    config
+}
+
+fn get(path: &str) -> Request<Empty<Bytes>> {
+    Request::get(path).header("host", "proxy").body(Empty::new()).unwrap()
+}
+
+#[tokio::test]
+async fn test_http1_keep_alive_closed_after_in_flight_response() {
+    let upstream = MockUpstream::start(MockResponse {
+        latency: Duration::from_millis(500),
+        ..MockResponse::default()
+    })
+    .await
+    .unwrap();
+    let (metrics, handle) = start(draining_config(&upstream)).await;
+    let addr = handle.local_addr();
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, connection) = conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+    let (closed_tx, closed_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = closed_tx.send(connection.await);
+    });
+
+    let in_flight = tokio::spawn(sender.send_request(get("/api/a/slow")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let drain_started = Instant::now();
+    handle.shutdown();
+
+    let response = in_flight.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["connection"], "close");
+    assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "ok");
+    // Closed by the proxy well before the drain window ends.
+    tokio::time::timeout(Duration::from_secs(1), closed_rx).await.unwrap().unwrap().unwrap();
+    assert!(drain_started.elapsed() < DRAIN_DELAY);
+
+    // Still accepting, but out of rotation and closing every connection.
+    let client = reqwest::Client::new();
+    let health = client.get(format!("http://{}/health", addr)).send().await.unwrap();
+    assert_eq!(health.status(), 503);
+    assert_eq!(health.headers()["connection"], "close");
+    let status: serde_json::Value = client
+        .get(format!("http://{}/admin/drain", addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status["phase"], "draining");
+    assert_eq!(status["deregistration"], "disabled");
+    assert!(status["elapsed_ms"].as_u64().unwrap() > 0);
+    assert_eq!(metrics.draining(), 1.0);
+
+    tokio::time::timeout(DRAIN_DELAY * 2, handle.await_terminated()).await.unwrap().unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_http2_goes_away_after_in_flight_response() {
+    let upstream = MockUpstream::start(MockResponse {
+        latency: Duration::from_millis(500),
+        ..MockResponse::default()
+    })
+    .await
+    .unwrap();
+    let (_metrics, handle) = start(draining_config(&upstream)).await;
+
+    let stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let (mut sender, connection) = conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+        .await
+        .unwrap();
+    let (closed_tx, closed_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = closed_tx.send(connection.await);
+    });
+
+    // The proxy only learns the connection is HTTP/2 from a request.
+    let response = sender.send_request(get("http://proxy/api/a/first")).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let in_flight = tokio::spawn(sender.send_request(get("http://proxy/api/a/slow")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let drain_started = Instant::now();
+    handle.shutdown();
+
+    let response = in_flight.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "ok");
+    tokio::time::timeout(Duration::from_secs(1), closed_rx).await.unwrap().unwrap().unwrap();
+    assert!(drain_started.elapsed() < DRAIN_DELAY);
+
+    handle.await_terminated().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_deregistration_retried_at_drain_start() {
+    let seen = Arc::new(AtomicUsize::new(0));
+    let registry = MockUpstream::start_with(move || MockResponse {
+        status: if seen.fetch_add(1, Ordering::SeqCst) < 2 { 503 } else { 200 },
+        ..MockResponse::default()
+    })
+    .await
+    .unwrap();
+
+    let mut config = Config::new();
+    config.proxy_config.drain.delay_ms = 2000;
+    config.proxy_config.drain.deregistration = Some(DeregistrationConfig {
+        url: format!("{}/v1/instances/proxy-1", registry.url()),
+        method: "DELETE".to_string(),
+        headers: Default::default(),
+        body: None,
+        attempts: 5,
+        timeout_ms: 500,
+        backoff_ms: 10,
+    });
+    let (_metrics, handle) = start(config).await;
+    let addr = handle.local_addr();
+    handle.shutdown();
+
+    let client = reqwest::Client::new();
+    let status = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            let status: serde_json::Value = client
+                .get(format!("http://{}/admin/drain", addr))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            if !matches!(status["deregistration"].as_str(), Some("waiting" | "pending")) {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(status["deregistration"], "succeeded");
+    assert_eq!(registry.request_log(), vec!["DELETE /v1/instances/proxy-1"; 3]);
+
+    handle.await_terminated().await.unwrap();
+}
@@ -0,0 +1,158 @@
+use ai_sidecar_proxy::{
+    config::Config,
+    lifecycle::LifecycleHook,
+    middleware::{Middleware, RequestContext},
+    mock_upstream::{MockResponse, MockUpstream},
+    proxy::ProxyServer,
+};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{body::Incoming, header::HeaderValue, Request, Response};
+#[cfg(feature = "metrics-prometheus")]
+use prometheus::Registry;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+// Answers /embedded/ping itself and tags everything else on the way out.
+struct Ping;
+
+impl Middleware for Ping {
+    fn on_request(
+        &self,
+        req: &mut Request<Incoming>,
+        _context: &RequestContext,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        (req.uri().path() == "/embedded/ping").then(|| {
+            Response::new(Full::new(Bytes::from_static(b"pong")).map_err(|never| match never {}).boxed())
+        })
+    }
+
+    fn on_response(&self, response: &mut Response<BoxBody<Bytes, hyper::Error>>, _context: &RequestContext) {
+        response.headers_mut().insert("x-embedded", HeaderValue::from_static("yes"));
+    }
+}
+
+#[tokio::test]
+async fn test_embedded_proxy_serves_and_shuts_down() {
+    let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![upstream.url()];
+
+    let builder = ProxyServer::builder().config(config).middleware(Ping);
+    #[cfg(feature = "metrics-prometheus")]
+    let registry = Registry::new();
+    #[cfg(feature = "metrics-prometheus")]
+    let builder = builder.registry(registry.clone());
+    let proxy = builder.build().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = proxy.run(listener).unwrap();
+    let addr = handle.local_addr();
+
+    let response = reqwest::get(format!("http://{}/api/a/items", addr)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-embedded"], "yes");
+
+    let response = reqwest::get(format!("http://{}/embedded/ping", addr)).await.unwrap();
+    assert_eq!(response.headers()["x-embedded"], "yes");
+    assert_eq!(response.text().await.unwrap(), "pong");
+    assert!(upstream.last_request_headers("/embedded/ping").is_none());
+
+    #[cfg(feature = "metrics-prometheus")]
+    {
+        let families = registry.gather();
+        assert!(families.iter().any(|family| family.get_name() == "proxy_requests_total"));
+    }
+
+    handle.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), handle.await_terminated())
+        .await
+        .expect("proxy did not stop")
+        .unwrap();
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+}
+
+#[cfg(feature = "metrics-prometheus")]
+#[tokio::test]
+async fn test_registry_conflicts_fail_the_build() {
+    let registry = Registry::new();
+    let taken = prometheus::Counter::new("proxy_requests_total", "already registered").unwrap();
+    registry.register(Box::new(taken)).unwrap();
+
+    assert!(ProxyServer::builder().registry(registry).build().is_err());
+}
+
+#[tokio::test]
+async fn test_lifecycle_hooks_run_around_the_proxy() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let hook = |name: &'static str| {
+        let (start_log, stop_log) = (log.clone(), log.clone());
+        LifecycleHook::new(name)
+            .on_start(move || async move {
+                start_log.lock().unwrap().push(format!("start {}", name));
+                Ok(())
+            })
+            .on_stop(move || async move {
+                stop_log.lock().unwrap().push(format!("stop {}", name));
+                Ok(())
+            })
+    };
+    let proxy = ProxyServer::builder()
+        .config(Config::new())
+        .lifecycle_hook(hook("exporter").after("discovery"))
+        .lifecycle_hook(hook("discovery").after("health_checker"))
+        .lifecycle_hook(
+            LifecycleHook::new("snapshot_loader")
+                .optional()
+                .on_start(|| async { anyhow::bail!("no snapshot on disk") }),
+        )
+        .build()
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = proxy.run(listener).unwrap();
+    let addr = handle.local_addr();
+
+    let tasks: serde_json::Value = reqwest::get(format!("http://{}/admin/tasks", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let hooks = tasks["hooks"].as_array().unwrap();
+    let state = |name: &str| hooks.iter().find(|hook| hook["name"] == name).unwrap()["state"].clone();
+    assert_eq!(state("snapshot_loader"), "failed");
+    assert_eq!(state("health_checker"), "running");
+    assert_eq!(state("exporter"), "running");
+    assert_eq!(*log.lock().unwrap(), vec!["start discovery", "start exporter"]);
+
+    handle.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), handle.await_terminated())
+        .await
+        .expect("proxy did not stop")
+        .unwrap();
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec!["start discovery", "start exporter", "stop exporter", "stop discovery"]
+    );
+}
+
+#[tokio::test]
+async fn test_required_hook_failure_fails_startup() {
+    let proxy = ProxyServer::builder()
+        .config(Config::new())
+        .lifecycle_hook(LifecycleHook::new("spool").on_start(|| async { anyhow::bail!("spool dir is read-only") }))
+        .build()
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = proxy.run(listener).unwrap();
+    let error = tokio::time::timeout(Duration::from_secs(5), handle.await_terminated())
+        .await
+        .expect("proxy did not stop")
+        .unwrap_err();
+    assert!(error.to_string().contains("spool: spool dir is read-only"), "{}", error);
+}
@@ -0,0 +1,97 @@
+use ai_sidecar_proxy::{
+    config::Config,
+    egress::Direction,
+    metrics::MetricsCollector,
+    middleware::{Middleware, RequestContext},
+    mock_upstream::{MockResponse, MockUpstream},
+    overload::OverloadConfig,
+    proxy::ProxyServer,
+};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Incoming, Request, Response};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+
+// Burns the thread it runs on for requests that ask for it, the way a
+// CPU-heavy middleware would, so the proxy's timers fire late.
+struct Expensive;
+
+impl Middleware for Expensive {
+    fn on_request(
+        &self,
+        req: &mut Request<Incoming>,
+        _context: &RequestContext,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        if req.headers().contains_key("x-expensive") {
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_millis(300) {
+                std::hint::spin_loop();
+            }
+        }
+        None
+    }
+}
+
+async fn wait_for(metrics: &MetricsCollector, overloaded: f64) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics.overloaded() != overloaded {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("overload mode did not change");
+}
+
+// The test runtime has one thread, which the proxy and its monitor share
+// with the expensive middleware.
+#[tokio::test]
+async fn test_overload_engages_under_lag_and_recovers() {
+    let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![upstream.url()];
+    config.proxy_config.overload = OverloadConfig {
+        tick_ms: 20,
+        enter_lag_ms: 150,
+        exit_lag_ms: 50,
+        recovery_ticks: 25,
+        ..OverloadConfig::default()
+    };
+    let metrics = Arc::new(MetricsCollector::new());
+    let proxy = ProxyServer::builder()
+        .config(config)
+        .metrics(metrics.clone())
+        .middleware(Expensive)
+        .build()
+        .unwrap();
+    let handle = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap();
+    let url = format!("http://{}/api/a/items", handle.local_addr());
+    let client = reqwest::Client::new();
+
+    let response = client.get(&url).header("x-request-priority", "low").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(metrics.overloaded(), 0.0);
+
+    let response = client.get(&url).header("x-expensive", "1").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    wait_for(&metrics, 1.0).await;
+
+    // Half a second of calm ticks before it recovers.
+    let response = client.get(&url).header("x-request-priority", "low").send().await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "1");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "overloaded");
+    assert_eq!(client.get(&url).send().await.unwrap().status(), 200);
+    assert_eq!(metrics.overload_shed_count("/api/a"), 1);
+
+    wait_for(&metrics, 0.0).await;
+    let response = client.get(&url).header("x-request-priority", "low").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(metrics.processing_count(Direction::Ingress) >= 4);
+
+    handle.shutdown();
+}
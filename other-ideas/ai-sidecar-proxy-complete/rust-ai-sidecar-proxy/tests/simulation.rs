@@ -0,0 +1,42 @@
+// Routing-quality goldens: scripted endpoint behaviour replayed through the AI
+// engine, with the resulting traffic split checked against recorded values.
+
+use ai_sidecar_proxy::simulation::{self, Scenario};
+use std::path::Path;
+
+// Every scenario under tests/fixtures/simulation runs, so adding one only
+// takes its file.
+#[cfg(feature = "ai")]
+#[tokio::test]
+async fn test_scenarios_match_golden_expectations() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/simulation");
+    let scenarios = Scenario::load_dir(&dir).unwrap();
+    assert!(scenarios.len() >= 3);
+
+    let mut failures = Vec::new();
+    for (path, scenario) in &scenarios {
+        let report = simulation::run(scenario).await.unwrap();
+        for failure in scenario.check(&report) {
+            failures.push(format!("{}: {}", path.display(), failure));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[tokio::test]
+async fn test_auto_weights_leave_degraded_endpoint_and_settle() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/simulation/auto_weight_degrading.json");
+    let report = simulation::run(&Scenario::load(&path).unwrap()).await.unwrap();
+    let degraded: Vec<(u64, u32)> = report.weights.iter().map(|(at_ms, weights)| (*at_ms, weights[1])).collect();
+
+    // b fails from 30s on: its weight only ever goes down from there, by at
+    // most one step per round, and then stays put.
+    let after: Vec<u32> = degraded.iter().filter(|(at_ms, _)| *at_ms >= 30_000).map(|(_, weight)| *weight).collect();
+    assert!(after.windows(2).all(|pair| pair[1] <= pair[0] && pair[0] - pair[1] <= 10), "{:?}", degraded);
+    assert!(after.last().unwrap() < &10, "{:?}", degraded);
+    let settled = &after[after.len() - 10..];
+    assert!(settled.iter().all(|weight| weight == &settled[0]), "{:?}", degraded);
+    for (_, weights) in &report.weights[report.weights.len() - 10..] {
+        assert!(weights[0] >= 99 && weights[2] >= 99, "{:?}", report.weights);
+    }
+}
@@ -0,0 +1,89 @@
+use ai_sidecar_proxy::{
+    config::Config,
+    metrics::MetricsCollector,
+    mock_upstream::{MockResponse, MockUpstream},
+    proxy::ProxyServer,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+
+async fn probed_healthy(client: &reqwest::Client, base: &str) -> bool {
+    let health: serde_json::Value = client.get(format!("{}/admin/health", base)).send().await.unwrap().json().await.unwrap();
+    health["services"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|service| service["service"] == "service-a")
+        .is_some_and(|service| service["endpoints"][0]["probe"]["tier"] == "healthy")
+}
+
+#[tokio::test]
+async fn test_standby_refuses_traffic_until_promoted() {
+    let upstream = MockUpstream::start(MockResponse {
+        latency: Duration::from_millis(300),
+        ..MockResponse::default()
+    })
+    .await
+    .unwrap();
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![upstream.url()];
+    let metrics = Arc::new(MetricsCollector::new());
+    let proxy = ProxyServer::builder()
+        .config(config)
+        .metrics(metrics.clone())
+        .standby(true)
+        .build()
+        .unwrap();
+    let handle = proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap();
+    let base = format!("http://{}", handle.local_addr());
+    let client = reqwest::Client::new();
+
+    let response = client.get(format!("{}/api/a/items", base)).send().await.unwrap();
+    assert_eq!(response.status(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "standby");
+    let health = client.get(format!("{}/health", base)).send().await.unwrap();
+    assert_eq!(health.status(), 503);
+    assert_eq!(health.json::<serde_json::Value>().await.unwrap()["status"], "standby");
+    let reload = client.post(format!("{}/admin/config/reload", base)).send().await.unwrap();
+    assert_eq!(reload.status(), 503);
+    assert_eq!(metrics.standby(), 1.0);
+
+    // Health checks run in standby, so the endpoint is known good before
+    // the first request arrives.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !probed_healthy(&client, &base).await {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("endpoint was never probed");
+    assert!(upstream.request_log().iter().all(|line| line.ends_with("/health")));
+
+    let promoted: serde_json::Value =
+        client.post(format!("{}/admin/promote", base)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(promoted, serde_json::json!({"standby": false, "changed": true}));
+    assert_eq!(client.get(format!("{}/api/a/items", base)).send().await.unwrap().status(), 200);
+    assert_eq!(client.get(format!("{}/health", base)).send().await.unwrap().status(), 200);
+    assert_eq!(metrics.standby(), 0.0);
+
+    // Demotion answers only once the request already let through is done.
+    let slow = tokio::spawn({
+        let client = client.clone();
+        let url = format!("{}/api/a/items", base);
+        async move { client.get(url).send().await.unwrap().status() }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let started = Instant::now();
+    let demoted: serde_json::Value =
+        client.post(format!("{}/admin/demote", base)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(demoted, serde_json::json!({"standby": true, "changed": true}));
+    assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+    assert_eq!(slow.await.unwrap(), 200);
+    assert_eq!(client.get(format!("{}/api/a/items", base)).send().await.unwrap().status(), 503);
+
+    handle.shutdown();
+}
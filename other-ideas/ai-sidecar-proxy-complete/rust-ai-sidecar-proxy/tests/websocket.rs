@@ -0,0 +1,138 @@
+use ai_sidecar_proxy::{
+    config::Config,
+    metrics::MetricsCollector,
+    mock_upstream::{MockResponse, MockUpstream},
+    proxy::ProxyServer,
+};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request, Response};
+use hyper_util::rt::TokioIo;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// Accepts every upgrade and echoes what it is sent. The key the client
+// sent comes back in a header, to show the handshake reached it intact.
+async fn echo_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|mut req: Request<Incoming>| async move {
+                let key = req.headers()["sec-websocket-key"].clone();
+                let upgrade = hyper::upgrade::on(&mut req);
+                tokio::spawn(async move {
+                    let mut io = TokioIo::new(upgrade.await.unwrap());
+                    let mut buf = [0u8; 1024];
+                    while let Ok(read) = io.read(&mut buf).await {
+                        if read == 0 || io.write_all(&buf[..read]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                Response::builder()
+                    .status(101)
+                    .header("upgrade", "websocket")
+                    .header("connection", "upgrade")
+                    .header("x-seen-key", key)
+                    .body(Empty::<Bytes>::new())
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades());
+        }
+    });
+    format!("http://{}", addr)
+}
+
+// Sends the handshake and returns the response head.
+async fn open(stream: &mut TcpStream, path: &str) -> String {
+    let handshake = format!(
+        "GET {} HTTP/1.1\r\nHost: proxy\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path
+    );
+    stream.write_all(handshake.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "closed during handshake");
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap().to_ascii_lowercase()
+}
+
+async fn proxy_for(endpoint: String, metrics: Arc<MetricsCollector>) -> ai_sidecar_proxy::proxy::ProxyHandle {
+    let mut config = Config::new();
+    config.upstream_services.get_mut("service-a").unwrap().endpoints = vec![endpoint];
+    let proxy = ProxyServer::builder().config(config).metrics(metrics).build().unwrap();
+    proxy.run(TcpListener::bind("127.0.0.1:0").await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_upgraded_session_relays_bytes_both_ways() {
+    let metrics = Arc::new(MetricsCollector::new());
+    let handle = proxy_for(echo_upstream().await, metrics.clone()).await;
+    let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+    let head = open(&mut stream, "/api/a/chat").await;
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+    assert!(head.contains("upgrade: websocket"), "{}", head);
+    assert!(head.contains("x-seen-key: dghlihnhbxbszsbub25jzq=="), "{}", head);
+    assert_eq!(metrics.websocket_sessions("service-a"), 1.0);
+
+    for message in ["hello", "over the tunnel"] {
+        stream.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0u8; message.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+
+    // The counts land once the session ends.
+    drop(stream);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics.websocket_sessions("service-a") != 0.0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("session never ended");
+    assert_eq!(metrics.websocket_bytes("service-a", "to_upstream"), 20);
+    assert_eq!(metrics.websocket_bytes("service-a", "to_client"), 20);
+    assert_eq!(metrics.websocket_handshake_count("service-a", "upgraded"), 1);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_declined_upgrade_is_passed_back() {
+    let upstream = MockUpstream::start(MockResponse::default()).await.unwrap();
+    let metrics = Arc::new(MetricsCollector::new());
+    let handle = proxy_for(upstream.url(), metrics.clone()).await;
+    let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+    let head = open(&mut stream, "/api/a/chat").await;
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert_eq!(metrics.websocket_handshake_count("service-a", "refused"), 1);
+    assert_eq!(metrics.websocket_sessions("service-a"), 0.0);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_unreachable_upstream_answers_bad_gateway() {
+    let unused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", unused.local_addr().unwrap());
+    drop(unused);
+    let metrics = Arc::new(MetricsCollector::new());
+    let handle = proxy_for(endpoint, metrics.clone()).await;
+    let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+    let head = open(&mut stream, "/api/a/chat").await;
+    assert!(head.starts_with("http/1.1 502"), "{}", head);
+    assert_eq!(metrics.websocket_handshake_count("service-a", "failed"), 1);
+
+    handle.shutdown();
+}